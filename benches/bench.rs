@@ -19,6 +19,7 @@ use rav1e::*;
 use rav1e::cdef::cdef_filter_frame;
 use rav1e::context::*;
 use rav1e::partition::*;
+use rav1e::pool::WorkerPool;
 use rav1e::predict::*;
 use rav1e::rdo::rdo_cfl_alpha;
 use transform::transform;
@@ -63,7 +64,7 @@ fn write_b_bench(b: &mut Bencher, tx_size: TxSize, qindex: usize) {
 
   b.iter(|| {
     for &mode in RAV1E_INTRA_MODES {
-      let sbo = SuperBlockOffset { x: sbx, y: sby };
+      let sbo = SuperBlockOffset { x: sbx, y: sby, size_log2: fi.sequence.sb_size_log2() };
       for p in 1..3 {
         fs.qc.update(fi.base_q_idx, tx_size, mode.is_intra(), 8, fi.dc_delta_q[p], fi.ac_delta_q[p]);
         for by in 0..8 {
@@ -113,10 +114,11 @@ fn cdef_frame_bench(b: &mut Bencher, w: usize, h: usize) {
     EncoderConfig { quantizer: 100, speed_settings: SpeedSettings::from_preset(10), ..Default::default() };
   let sequence = Sequence::new(&Default::default());
   let fi = FrameInvariants::new(w, h, config, sequence);
-  let mut bc = BlockContext::new(fi.sb_width * 16, fi.sb_height * 16);
+  let bc = BlockContext::new(fi.sb_width * 16, fi.sb_height * 16);
   let mut fs = FrameState::new(&fi);
+  let pool = WorkerPool::new(1);
 
-  b.iter(|| cdef_filter_frame(&fi, &mut fs.rec, &mut bc));
+  b.iter(|| cdef_filter_frame(&fi, &mut fs.rec, &bc, &pool));
 }
 
 fn cfl_rdo(c: &mut Criterion) {
@@ -9,9 +9,13 @@
 
 use bitstream_io::*;
 use encoder::*;
+use film_grain::*;
 use metrics::calculate_frame_psnr;
 use partition::*;
+use qm_tables::NUM_QM_LEVELS;
 use scenechange::SceneChangeDetector;
+use superres::*;
+use util::Fixed;
 use self::EncoderStatus::*;
 
 use std::{cmp, fmt, io};
@@ -44,6 +48,42 @@ pub struct EncoderConfig {
   pub color_description: Option<ColorDescription>,
   pub speed_settings: SpeedSettings,
   pub show_psnr: bool,
+  /// Strength of the denoising pre-filter, in 8-bit sample units. The
+  /// removed noise is estimated and signaled as film grain. 0 disables it.
+  pub denoise_strength: u8,
+  pub superres_mode: SuperresMode,
+  /// Denominator (9-16) of the horizontal scaling applied in the fixed
+  /// superres mode. Frames are coded at 8/denominator of their width.
+  pub superres_denom: u8,
+  /// Superblock size in pixels, 64 or 128. 0 picks it from the frame size,
+  /// using 128 above 1080p where it saves partition and mode signaling.
+  pub superblock_size: usize,
+  /// Use of the screen content coding tools: palette mode, IntraBC and
+  /// integer motion vectors. In the auto mode they are switched on and off
+  /// at each keyframe by an analysis of its content.
+  pub screen_content_mode: ScreenContentMode,
+  /// Use of quantizer matrices, which quantize high frequencies more
+  /// coarsely. In the auto mode the level is picked from the quantizer.
+  pub qm_mode: QmMode,
+  /// Quantizer matrix level (0-15) in the fixed mode. Lower levels weight
+  /// the quantizer more steeply, 15 is flat.
+  pub qm_level: u8,
+  /// Offset (-64 to 63) of the luma DC quantizer index from the base
+  /// quantizer index.
+  pub y_dc_delta_q: i8,
+  /// Offsets (-64 to 63) of the chroma DC and AC quantizer indices. Negative
+  /// values spend more bits on chroma. U and V are signaled separately when
+  /// they differ.
+  pub u_dc_delta_q: i8,
+  pub u_ac_delta_q: i8,
+  pub v_dc_delta_q: i8,
+  pub v_ac_delta_q: i8,
+  /// Offset the deblocking levels by superblock (delta_lf), lowering them
+  /// where filtering would smear strong edges.
+  pub delta_lf: bool,
+  /// Worker threads running the loop filters, 0 for one per CPU. The
+  /// output does not depend on it.
+  pub filter_threads: usize,
 }
 
 impl Default for EncoderConfig {
@@ -64,6 +104,20 @@ impl EncoderConfig {
       color_description: None,
       speed_settings: SpeedSettings::from_preset(speed),
       show_psnr: false,
+      denoise_strength: 0,
+      superres_mode: SuperresMode::None,
+      superres_denom: 16,
+      superblock_size: 0,
+      screen_content_mode: ScreenContentMode::Auto,
+      qm_mode: QmMode::Off,
+      qm_level: 8,
+      y_dc_delta_q: 0,
+      u_dc_delta_q: 0,
+      u_ac_delta_q: 0,
+      v_dc_delta_q: 0,
+      v_ac_delta_q: 0,
+      delta_lf: false,
+      filter_threads: 0,
     }
   }
 }
@@ -73,12 +127,26 @@ pub struct SpeedSettings {
   pub min_block_size: BlockSize,
   pub multiref: bool,
   pub fast_deblock: bool,
+  pub fast_lrf: bool,
+  pub fast_cdef: bool,
   pub reduced_tx_set: bool,
   pub tx_domain_distortion: bool,
   pub encode_bottomup: bool,
   pub rdo_tx_decision: bool,
+  pub rdo_tx_size: bool,
+  pub rdoq: bool,
+  pub partition_types: PartitionTypesSetting,
   pub prediction_modes: PredictionModesSetting,
   pub include_near_mvs: bool,
+  pub global_motion: bool,
+  pub warped_motion: bool,
+  pub obmc: bool,
+  pub switchable_interp_filter: bool,
+  pub dual_filter: bool,
+  pub jnt_comp: bool,
+  pub masked_compound: bool,
+  pub interintra: bool,
+  pub ref_frame_mvs: bool,
 }
 
 impl SpeedSettings {
@@ -87,12 +155,26 @@ impl SpeedSettings {
       min_block_size: Self::min_block_size_preset(speed),
       multiref: Self::multiref_preset(speed),
       fast_deblock: Self::fast_deblock_preset(speed),
+      fast_lrf: Self::fast_lrf_preset(speed),
+      fast_cdef: Self::fast_cdef_preset(speed),
       reduced_tx_set: Self::reduced_tx_set_preset(speed),
       tx_domain_distortion: Self::tx_domain_distortion_preset(speed),
       encode_bottomup: Self::encode_bottomup_preset(speed),
       rdo_tx_decision: Self::rdo_tx_decision_preset(speed),
+      rdo_tx_size: Self::rdo_tx_size_preset(speed),
+      rdoq: Self::rdoq_preset(speed),
+      partition_types: Self::partition_types_preset(speed),
       prediction_modes: Self::prediction_modes_preset(speed),
       include_near_mvs: Self::include_near_mvs_preset(speed),
+      global_motion: Self::global_motion_preset(speed),
+      warped_motion: Self::warped_motion_preset(speed),
+      obmc: Self::obmc_preset(speed),
+      switchable_interp_filter: Self::switchable_interp_filter_preset(speed),
+      dual_filter: Self::dual_filter_preset(speed),
+      jnt_comp: Self::jnt_comp_preset(speed),
+      masked_compound: Self::masked_compound_preset(speed),
+      interintra: Self::interintra_preset(speed),
+      ref_frame_mvs: Self::ref_frame_mvs_preset(speed),
     }
   }
 
@@ -118,6 +200,14 @@ impl SpeedSettings {
     speed >= 4
   }
 
+  fn fast_lrf_preset(speed: usize) -> bool {
+    speed >= 4
+  }
+
+  fn fast_cdef_preset(speed: usize) -> bool {
+    speed >= 2
+  }
+
   fn reduced_tx_set_preset(speed: usize) -> bool {
     speed >= 2
   }
@@ -134,6 +224,24 @@ impl SpeedSettings {
     speed <= 3
   }
 
+  fn rdo_tx_size_preset(speed: usize) -> bool {
+    speed <= 2
+  }
+
+  fn rdoq_preset(speed: usize) -> bool {
+    speed <= 2
+  }
+
+  fn partition_types_preset(speed: usize) -> PartitionTypesSetting {
+    if speed == 0 {
+      PartitionTypesSetting::Extended
+    } else if speed <= 1 {
+      PartitionTypesSetting::FourWay
+    } else {
+      PartitionTypesSetting::Basic
+    }
+  }
+
   fn prediction_modes_preset(speed: usize) -> PredictionModesSetting {
     if speed <= 1 {
       PredictionModesSetting::ComplexAll
@@ -147,6 +255,50 @@ impl SpeedSettings {
   fn include_near_mvs_preset(speed: usize) -> bool {
     speed <= 2
   }
+
+  fn global_motion_preset(speed: usize) -> bool {
+    speed <= 6
+  }
+
+  fn warped_motion_preset(speed: usize) -> bool {
+    speed <= 6
+  }
+
+  fn obmc_preset(speed: usize) -> bool {
+    speed <= 4
+  }
+
+  fn switchable_interp_filter_preset(speed: usize) -> bool {
+    speed <= 3
+  }
+
+  fn dual_filter_preset(speed: usize) -> bool {
+    speed <= 1
+  }
+
+  fn jnt_comp_preset(speed: usize) -> bool {
+    speed <= 2
+  }
+
+  fn masked_compound_preset(speed: usize) -> bool {
+    speed <= 1
+  }
+
+  fn interintra_preset(speed: usize) -> bool {
+    speed <= 1
+  }
+
+  fn ref_frame_mvs_preset(speed: usize) -> bool {
+    speed <= 6
+  }
+}
+
+/// Partition types searched besides NONE, HORZ, VERT and SPLIT
+#[derive(Clone, Copy, Debug, PartialOrd, PartialEq)]
+pub enum PartitionTypesSetting {
+  Basic,
+  FourWay,
+  Extended,
 }
 
 #[derive(Clone, Copy, Debug, PartialOrd, PartialEq)]
@@ -275,6 +427,15 @@ pub struct Config {
   pub enc: EncoderConfig
 }
 
+/// Parses a quantizer index offset, which is coded in 7 bits (spec 5.9.13).
+fn parse_delta_q(value: &str) -> Result<i8, EncoderStatus> {
+  let delta: i8 = value.parse().map_err(|_e| ParseError)?;
+  if delta < -64 || delta > 63 {
+    return Err(ParseError);
+  }
+  Ok(delta)
+}
+
 impl Config {
   pub fn parse(&mut self, key: &str, value: &str) -> Result<(), EncoderStatus> {
     match key {
@@ -284,6 +445,38 @@ impl Config {
       "quantizer" => self.enc.quantizer = value.parse().map_err(|_e| ParseError)?,
       "speed" => self.enc.speed_settings = SpeedSettings::from_preset(value.parse().map_err(|_e| ParseError)?),
       "tune" => self.enc.tune = value.parse().map_err(|_e| ParseError)?,
+      "denoise_strength" => self.enc.denoise_strength = value.parse().map_err(|_e| ParseError)?,
+      "superres_mode" => self.enc.superres_mode = value.parse().map_err(|_e| ParseError)?,
+      "superres_denom" => {
+        let denom = value.parse().map_err(|_e| ParseError)?;
+        if denom < SUPERRES_DENOM_MIN || denom > SUPERRES_DENOM_MIN + (1 << SUPERRES_DENOM_BITS) - 1 {
+          return Err(ParseError);
+        }
+        self.enc.superres_denom = denom;
+      }
+      "superblock_size" => {
+        let size = value.parse().map_err(|_e| ParseError)?;
+        if size != 0 && size != 64 && size != 128 {
+          return Err(ParseError);
+        }
+        self.enc.superblock_size = size;
+      }
+      "screen_content_mode" => self.enc.screen_content_mode = value.parse().map_err(|_e| ParseError)?,
+      "qm_mode" => self.enc.qm_mode = value.parse().map_err(|_e| ParseError)?,
+      "qm_level" => {
+        let level = value.parse().map_err(|_e| ParseError)?;
+        if level as usize >= NUM_QM_LEVELS {
+          return Err(ParseError);
+        }
+        self.enc.qm_level = level;
+      }
+      "y_dc_delta_q" => self.enc.y_dc_delta_q = parse_delta_q(value)?,
+      "u_dc_delta_q" => self.enc.u_dc_delta_q = parse_delta_q(value)?,
+      "u_ac_delta_q" => self.enc.u_ac_delta_q = parse_delta_q(value)?,
+      "v_dc_delta_q" => self.enc.v_dc_delta_q = parse_delta_q(value)?,
+      "v_ac_delta_q" => self.enc.v_ac_delta_q = parse_delta_q(value)?,
+      "delta_lf" => self.enc.delta_lf = value.parse().map_err(|_e| ParseError)?,
+      "filter_threads" => self.enc.filter_threads = value.parse().map_err(|_e| ParseError)?,
       _ => return Err(InvalidKey)
     }
 
@@ -291,7 +484,26 @@ impl Config {
   }
 
   pub fn new_context(&self) -> Context {
-    let seq = Sequence::new(&self.frame_info);
+    let mut seq = Sequence::new(&self.frame_info);
+    seq.film_grain_params_present = self.enc.denoise_strength > 0;
+    seq.enable_superres = self.enc.superres_mode != SuperresMode::None;
+    seq.enable_dual_filter = self.enc.speed_settings.dual_filter;
+    seq.enable_jnt_comp = self.enc.speed_settings.jnt_comp;
+    seq.enable_masked_compound = self.enc.speed_settings.masked_compound;
+    seq.enable_interintra_compound = self.enc.speed_settings.interintra;
+    seq.enable_ref_frame_mvs = self.enc.speed_settings.ref_frame_mvs;
+    seq.separate_uv_delta_q = self.enc.u_dc_delta_q != self.enc.v_dc_delta_q
+      || self.enc.u_ac_delta_q != self.enc.v_ac_delta_q;
+    seq.force_screen_content_tools = match self.enc.screen_content_mode {
+      ScreenContentMode::Off => 0,
+      ScreenContentMode::On => 1,
+      ScreenContentMode::Auto => 2
+    };
+    seq.use_128x128_superblock = match self.enc.superblock_size {
+      64 => false,
+      128 => true,
+      _ => self.frame_info.width * self.frame_info.height > 1920 * 1080
+    };
     let fi = FrameInvariants::new(
       self.frame_info.width,
       self.frame_info.height,
@@ -307,6 +519,7 @@ impl Config {
 
     Context {
       fi,
+      timebase: self.timebase,
       frame_count: 0,
       frames_to_be_coded: 0,
       idx: 0,
@@ -316,13 +529,15 @@ impl Config {
       segment_start_frame: 0,
       frame_types: BTreeMap::new(),
       keyframe_detector: SceneChangeDetector::new(&self.frame_info),
+      film_grain_table: None,
+      frame_sizes: BTreeMap::new(),
     }
   }
 }
 
 pub struct Context {
   fi: FrameInvariants,
-  //    timebase: Rational,
+  timebase: Rational,
   frame_count: u64,
   frames_to_be_coded: u64,
   idx: u64,
@@ -332,6 +547,9 @@ pub struct Context {
   segment_start_frame: u64,
   frame_types: BTreeMap<u64, FrameType>,
   keyframe_detector: SceneChangeDetector,
+  film_grain_table: Option<Vec<GrainTableSegment>>,
+  /// Coded frame sizes requested from a given frame number on
+  frame_sizes: BTreeMap<u64, (usize, usize)>,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -353,23 +571,53 @@ pub struct Packet {
   pub frame_type: FrameType,
   /// PSNR for Y, U, and V planes
   pub psnr: Option<(f64, f64, f64)>,
+  /// Whether the frame was coded with the screen content tools
+  pub screen_content: bool,
 }
 
 impl fmt::Display for Packet {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
     write!(
       f,
-      "Frame {} - {} - {} bytes",
+      "Frame {} - {} - {} bytes{}",
       self.number,
       self.frame_type,
-      self.data.len()
+      self.data.len(),
+      if self.screen_content { " - screen content" } else { "" }
     )
   }
 }
 
 impl Context {
   pub fn new_frame(&self) -> Arc<Frame> {
-    Arc::new(Frame::new(self.fi.padded_w, self.fi.padded_h, self.fi.sequence.chroma_sampling))
+    let (width, height) = self.max_frame_size();
+    Arc::new(Frame::new(
+      width.align_power_of_two(3), height.align_power_of_two(3),
+      self.fi.sequence.chroma_sampling
+    ))
+  }
+
+  fn max_frame_size(&self) -> (usize, usize) {
+    (self.fi.sequence.max_frame_width as usize, self.fi.sequence.max_frame_height as usize)
+  }
+
+  /// Codes the frames from frame `number` on at `width`x`height`, without
+  /// starting a new keyframe. Frames are still sent at the configured size
+  /// and are scaled by the encoder. The size must not exceed the configured
+  /// one and must be at least half of it in each dimension.
+  pub fn request_frame_size(&mut self, number: u64, width: usize, height: usize) -> Result<(), EncoderStatus> {
+    let (max_width, max_height) = self.max_frame_size();
+    if width > max_width || height > max_height || 2 * width < max_width || 2 * height < max_height {
+      return Err(EncoderStatus::Failure);
+    }
+    self.frame_sizes.insert(number, (width, height));
+    Ok(())
+  }
+
+  fn apply_frame_size(&mut self) {
+    let size = self.frame_sizes.range(..=self.fi.number).next_back().map(|(_, &size)| size);
+    let (width, height) = size.unwrap_or_else(|| self.max_frame_size());
+    self.fi.set_frame_size(width, height);
   }
 
   pub fn send_frame<F>(&mut self, frame: F) -> Result<(), EncoderStatus>
@@ -383,6 +631,13 @@ impl Context {
     Ok(())
   }
 
+  /// Signals film grain from a grain table instead of estimating it. Must be
+  /// called before the sequence header is written.
+  pub fn set_film_grain_table(&mut self, table: Vec<GrainTableSegment>) {
+    self.fi.sequence.film_grain_params_present = true;
+    self.film_grain_table = Some(table);
+  }
+
   pub fn get_frame_count(&self) -> u64 {
     self.frame_count
   }
@@ -437,6 +692,12 @@ impl Context {
   }
 
   fn set_frame_properties(&mut self, idx: u64) -> Result<(), ()> {
+    self.set_frame_type(idx)?;
+    self.apply_frame_size();
+    Ok(())
+  }
+
+  fn set_frame_type(&mut self, idx: u64) -> Result<(), ()> {
     if idx == 0 {
       // The first frame will always be a key frame
       self.fi = FrameInvariants::new_key_frame(&self.fi,0);
@@ -514,18 +775,48 @@ impl Context {
         }
       }
 
-      Ok(Packet { data, rec, number: self.fi.number, frame_type: self.fi.frame_type, psnr })
+      Ok(Packet {
+        data,
+        rec,
+        number: self.fi.number,
+        frame_type: self.fi.frame_type,
+        psnr,
+        screen_content: self.fi.allow_screen_content_tools != 0
+      })
     } else {
       if let Some(f) = self.frame_q.remove(&self.fi.number) {
         self.idx += 1;
 
         if let Some(frame) = f {
-          let mut fs = FrameState::new_with_frame(&self.fi, frame.clone());
+          let input = self.apply_film_grain(&frame);
+          let (max_width, max_height) = self.max_frame_size();
+          let (width, height) = (self.fi.upscaled_width, self.fi.height);
+          let chroma_sampling = self.fi.sequence.chroma_sampling;
+          let resized = (width, height) != (max_width, max_height);
+          let scaled_input = if resized {
+            Arc::new(input.resample(max_width, max_height, width, height, chroma_sampling))
+          } else {
+            input.clone()
+          };
+          let use_superres = self.fi.superres_denom != SUPERRES_NUM;
+          let coded_input = if use_superres {
+            Arc::new(input.resample(max_width, max_height, self.fi.width, height, chroma_sampling))
+          } else {
+            scaled_input.clone()
+          };
+          let mut fs = FrameState::new_with_frame(&self.fi, coded_input);
+          if use_superres {
+            fs.input_upscaled = Some(scaled_input.clone());
+          }
 
           let data = encode_frame(&mut self.fi, &mut fs);
           self.packet_data.extend(data);
 
-          fs.rec.pad(self.fi.width, self.fi.height);
+          fs.rec.pad(width, height);
+          if use_superres {
+            // Frames referencing this one search motion at its upscaled size
+            fs.set_reference_input(&self.fi, &scaled_input);
+          }
 
           // TODO avoid the clone by having rec Arc.
           let rec = if self.fi.show_frame { Some(fs.rec.clone()) } else { None };
@@ -539,11 +830,23 @@ impl Context {
             let mut psnr = None;
             if self.fi.config.show_psnr {
               if let Some(ref rec) = rec {
-                psnr = Some(calculate_frame_psnr(&*frame, rec, self.fi.sequence.bit_depth));
+                let original = if resized {
+                  Arc::new(frame.resample(max_width, max_height, width, height, chroma_sampling))
+                } else {
+                  frame.clone()
+                };
+                psnr = Some(calculate_frame_psnr(&*original, rec, self.fi.sequence.bit_depth));
               }
             }
 
-            Ok(Packet { data, rec, number: self.fi.number, frame_type: self.fi.frame_type, psnr })
+            Ok(Packet {
+              data,
+              rec,
+              number: self.fi.number,
+              frame_type: self.fi.frame_type,
+              psnr,
+              screen_content: self.fi.allow_screen_content_tools != 0
+            })
           } else {
             Err(EncoderStatus::NeedMoreData)
           }
@@ -556,6 +859,33 @@ impl Context {
     }
   }
 
+  /// Applies the denoising pre-filter to `frame` and selects the film grain
+  /// parameters to signal with it. Returns the frame to be coded.
+  fn apply_film_grain(&mut self, frame: &Arc<Frame>) -> Arc<Frame> {
+    if !self.fi.sequence.film_grain_params_present {
+      return frame.clone();
+    }
+
+    let (width, height) = self.max_frame_size();
+    let bit_depth = self.fi.sequence.bit_depth;
+    let mut input = frame.clone();
+    let mut params = None;
+    if self.fi.config.denoise_strength > 0 {
+      let denoised =
+        denoise_frame(frame, width, height, self.fi.config.denoise_strength, bit_depth);
+      params = estimate_film_grain(frame, &denoised, width, height, bit_depth);
+      input = Arc::new(denoised);
+    }
+    if let Some(ref table) = self.film_grain_table {
+      // Grain table timestamps are in units of 1/10,000,000 s
+      let time = self.fi.number * self.timebase.num * 10_000_000 / self.timebase.den;
+      params = grain_table_lookup(table, time).and_then(|segment| segment.params);
+    }
+    self.fi.film_grain_params = params.map(|p| p.with_frame_seed(self.fi.number));
+
+    input
+  }
+
   pub fn flush(&mut self) {
     self.frame_q.insert(self.frame_count, None);
     self.frame_count = self.frame_count + 1;
@@ -599,3 +929,96 @@ impl fmt::Display for Context {
     write!(f, "Frame {} - {}", self.fi.number, self.fi.frame_type)
   }
 }
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use rand::{ChaChaRng, Rng, SeedableRng};
+
+  fn config() -> Config {
+    Config {
+      frame_info: FrameInfo { width: 64, height: 64, ..Default::default() },
+      timebase: Rational::new(1, 30),
+      enc: EncoderConfig::default()
+    }
+  }
+
+  #[test]
+  fn frame_size_range() {
+    let mut ctx = config().new_context();
+    assert!(ctx.request_frame_size(1, 64, 64).is_ok());
+    assert!(ctx.request_frame_size(2, 32, 32).is_ok());
+    assert!(ctx.request_frame_size(3, 48, 40).is_ok());
+    assert!(ctx.request_frame_size(4, 72, 64).is_err());
+    assert!(ctx.request_frame_size(4, 64, 72).is_err());
+    assert!(ctx.request_frame_size(4, 31, 64).is_err());
+    assert!(ctx.request_frame_size(4, 64, 31).is_err());
+    assert!(!ctx.frame_sizes.contains_key(&4));
+  }
+
+  #[test]
+  fn resize_mid_stream_and_back() {
+    let mut cfg = config();
+    cfg.enc = EncoderConfig::with_speed_preset(10);
+    cfg.enc.show_psnr = true;
+    let mut ctx = cfg.new_context();
+    ctx.request_frame_size(2, 48, 40).unwrap();
+    ctx.request_frame_size(4, 64, 64).unwrap();
+
+    let mut ra = ChaChaRng::from_seed([0; 32]);
+    for i in 0..6 {
+      let mut input = ctx.new_frame();
+      for p in Arc::get_mut(&mut input).unwrap().planes.iter_mut() {
+        let stride = p.cfg.stride;
+        for (j, v) in p.data.iter_mut().enumerate() {
+          *v = ((j % stride + j / stride + i * 2) % 200 + ra.gen_range(0, 8)) as u16;
+        }
+      }
+      ctx.send_frame(input).unwrap();
+    }
+    ctx.flush();
+
+    let sizes = [(64, 64), (64, 64), (48, 40), (48, 40), (64, 64), (64, 64)];
+    for (i, &size) in sizes.iter().enumerate() {
+      let pkt = ctx.receive_packet().unwrap();
+      assert_eq!(pkt.number, i as u64);
+      // Resizing doesn't start a new keyframe
+      assert_eq!(pkt.frame_type, if i == 0 { FrameType::KEY } else { FrameType::INTER });
+      let rec = pkt.rec.unwrap();
+      assert_eq!((rec.planes[0].cfg.width, rec.planes[0].cfg.height), size);
+      assert!(pkt.psnr.unwrap().0 > 25.0);
+    }
+  }
+
+  #[test]
+  fn delta_q_range() {
+    let mut cfg = config();
+    for key in &["y_dc_delta_q", "u_dc_delta_q", "u_ac_delta_q", "v_dc_delta_q", "v_ac_delta_q"] {
+      assert!(cfg.parse(key, "-64").is_ok());
+      assert!(cfg.parse(key, "63").is_ok());
+      assert!(cfg.parse(key, "-65").is_err());
+      assert!(cfg.parse(key, "64").is_err());
+      assert!(cfg.parse(key, "128").is_err());
+    }
+    assert_eq!(cfg.enc.u_ac_delta_q, 63);
+  }
+
+  #[test]
+  fn separate_uv_delta_q() {
+    let mut cfg = config();
+    cfg.parse("u_ac_delta_q", "-10").unwrap();
+    cfg.parse("v_ac_delta_q", "-10").unwrap();
+    let ctx = cfg.new_context();
+    assert!(!ctx.fi.sequence.separate_uv_delta_q);
+    assert_eq!(ctx.fi.ac_delta_q, [0, -10, -10]);
+
+    cfg.parse("v_ac_delta_q", "5").unwrap();
+    let ctx = cfg.new_context();
+    assert!(ctx.fi.sequence.separate_uv_delta_q);
+    assert_eq!(ctx.fi.ac_delta_q, [0, -10, 5]);
+
+    cfg.parse("v_ac_delta_q", "-10").unwrap();
+    cfg.parse("u_dc_delta_q", "2").unwrap();
+    assert!(cfg.new_context().fi.sequence.separate_uv_delta_q);
+  }
+}
@@ -10,6 +10,7 @@
 use clap::{App, Arg, ArgMatches};
 use {ColorPrimaries, TransferCharacteristics, MatrixCoefficients};
 use rav1e::*;
+use rav1e::film_grain::{GrainTableSegment, parse_grain_table};
 
 use std::{fmt, io, slice};
 use std::fs::File;
@@ -29,6 +30,7 @@ pub struct CliOptions {
   pub enc: EncoderConfig,
   pub limit: usize,
   pub verbose: bool,
+  pub film_grain_table: Option<Vec<GrainTableSegment>>,
 }
 
 pub fn parse_cli() -> CliOptions {
@@ -119,6 +121,101 @@ pub fn parse_cli() -> CliOptions {
       .possible_values(&MatrixCoefficients::variants())
       .default_value("unspecified")
       .case_insensitive(true)
+    ).arg(
+      Arg::with_name("DENOISE")
+        .help("Denoising strength (0-50), the removed noise is signaled as film grain")
+        .long("denoise")
+        .takes_value(true)
+        .default_value("0")
+    ).arg(
+      Arg::with_name("FILM_GRAIN_TABLE")
+        .help("Film grain table to signal instead of estimating the grain")
+        .long("film-grain-table")
+        .takes_value(true)
+    ).arg(
+      Arg::with_name("SUPERRES_MODE")
+        .help("Code frames at a reduced width and upscale them in-loop")
+        .long("superres")
+        .possible_values(&SuperresMode::variants())
+        .default_value("none")
+        .case_insensitive(true)
+    ).arg(
+      Arg::with_name("SUPERRES_DENOM")
+        .help("Superres denominator (9-16) in fixed mode, frames are coded at 8/denom of the width")
+        .long("superres-denom")
+        .takes_value(true)
+        .default_value("16")
+    ).arg(
+      Arg::with_name("SCREEN_CONTENT")
+        .help("Screen content coding tools, auto detects screen content on each keyframe")
+        .long("screen-content")
+        .possible_values(&ScreenContentMode::variants())
+        .default_value("auto")
+        .case_insensitive(true)
+    ).arg(
+      Arg::with_name("QM_MODE")
+        .help("Quantizer matrices, auto picks the level from the quantizer")
+        .long("qm")
+        .possible_values(&QmMode::variants())
+        .default_value("off")
+        .case_insensitive(true)
+    ).arg(
+      Arg::with_name("QM_LEVEL")
+        .help("Quantizer matrix level (0-15) in fixed mode, lower is steeper")
+        .long("qm-level")
+        .takes_value(true)
+        .default_value("8")
+    ).arg(
+      Arg::with_name("Y_DC_DELTA_Q")
+        .help("Offset of the luma DC quantizer index (-64 to 63)")
+        .long("y-dc-delta-q")
+        .takes_value(true)
+        .allow_hyphen_values(true)
+        .default_value("0")
+    ).arg(
+      Arg::with_name("U_DC_DELTA_Q")
+        .help("Offset of the U DC quantizer index (-64 to 63), negative spends more bits")
+        .long("u-dc-delta-q")
+        .takes_value(true)
+        .allow_hyphen_values(true)
+        .default_value("0")
+    ).arg(
+      Arg::with_name("U_AC_DELTA_Q")
+        .help("Offset of the U AC quantizer index (-64 to 63), negative spends more bits")
+        .long("u-ac-delta-q")
+        .takes_value(true)
+        .allow_hyphen_values(true)
+        .default_value("0")
+    ).arg(
+      Arg::with_name("V_DC_DELTA_Q")
+        .help("Offset of the V DC quantizer index (-64 to 63), negative spends more bits")
+        .long("v-dc-delta-q")
+        .takes_value(true)
+        .allow_hyphen_values(true)
+        .default_value("0")
+    ).arg(
+      Arg::with_name("V_AC_DELTA_Q")
+        .help("Offset of the V AC quantizer index (-64 to 63), negative spends more bits")
+        .long("v-ac-delta-q")
+        .takes_value(true)
+        .allow_hyphen_values(true)
+        .default_value("0")
+    ).arg(
+      Arg::with_name("SUPERBLOCK_SIZE")
+        .help("Superblock size in pixels, 0 picks it from the resolution")
+        .long("superblock-size")
+        .possible_values(&["0", "64", "128"])
+        .default_value("0")
+    ).arg(
+      Arg::with_name("DELTA_LF")
+        .help("Offset the deblocking levels by superblock")
+        .long("delta-lf")
+    ).arg(
+      Arg::with_name("FILTER_THREADS")
+        .help("Worker threads of the loop filters, 0 for one per CPU")
+        .long("filter-threads")
+        .takes_value(true)
+        .default_value("0")
     ).arg(
       Arg::with_name("VERBOSE")
         .help("verbose logging, output info for every frame")
@@ -149,19 +246,42 @@ pub fn parse_cli() -> CliOptions {
     enc: parse_config(&matches),
     limit: matches.value_of("LIMIT").unwrap().parse().unwrap(),
     verbose: matches.is_present("VERBOSE"),
+    film_grain_table: matches.value_of("FILM_GRAIN_TABLE").map(|f| {
+      read_grain_table(f).unwrap_or_else(|e| {
+        clap::Error::with_description(&e, clap::ErrorKind::InvalidValue).exit()
+      })
+    }),
   }
 }
 
+fn read_grain_table(path: &str) -> Result<Vec<GrainTableSegment>, String> {
+  let mut table = String::new();
+  File::open(path)
+    .and_then(|mut f| f.read_to_string(&mut table))
+    .map_err(|e| format!("Cannot read film grain table '{}': {}", path, e))?;
+  parse_grain_table(&table)
+    .map_err(|e| format!("Invalid film grain table '{}': {}", path, e))
+}
+
 fn parse_config(matches: &ArgMatches) -> EncoderConfig {
   let speed = matches.value_of("SPEED").unwrap().parse().unwrap();
   let quantizer = matches.value_of("QP").unwrap().parse().unwrap();
   let min_interval = matches.value_of("MIN_KEYFRAME_INTERVAL").unwrap().parse().unwrap();
   let max_interval = matches.value_of("KEYFRAME_INTERVAL").unwrap().parse().unwrap();
+  let denoise_strength = matches.value_of("DENOISE").unwrap().parse().unwrap();
+  let superres_denom = matches.value_of("SUPERRES_DENOM").unwrap().parse().unwrap();
+  let qm_level = matches.value_of("QM_LEVEL").unwrap().parse().unwrap();
+  let delta_q: Vec<i8> = ["Y_DC_DELTA_Q", "U_DC_DELTA_Q", "U_AC_DELTA_Q", "V_DC_DELTA_Q", "V_AC_DELTA_Q"]
+    .iter()
+    .map(|name| matches.value_of(name).unwrap().parse().unwrap())
+    .collect();
 
   // Validate arguments
   if quantizer == 0 {
     unimplemented!("Lossless encoding not yet implemented");
-  } else if quantizer > 255 || speed > 10 {
+  } else if quantizer > 255 || speed > 10 || denoise_strength > 50
+    || superres_denom < 9 || superres_denom > 16 || qm_level > 15
+    || delta_q.iter().any(|&d| d < -64 || d > 63) {
     panic!("argument out of range");
   } else if min_interval > max_interval {
     panic!("Maximum keyframe interval must be greater than or equal to minimum keyframe interval");
@@ -190,6 +310,20 @@ fn parse_config(matches: &ArgMatches) -> EncoderConfig {
     };
   cfg.quantizer = quantizer;
   cfg.show_psnr = matches.is_present("PSNR");
+  cfg.denoise_strength = denoise_strength;
+  cfg.superres_mode = matches.value_of("SUPERRES_MODE").unwrap().parse().unwrap();
+  cfg.superres_denom = superres_denom;
+  cfg.superblock_size = matches.value_of("SUPERBLOCK_SIZE").unwrap().parse().unwrap();
+  cfg.screen_content_mode = matches.value_of("SCREEN_CONTENT").unwrap().parse().unwrap();
+  cfg.qm_mode = matches.value_of("QM_MODE").unwrap().parse().unwrap();
+  cfg.qm_level = qm_level;
+  cfg.y_dc_delta_q = delta_q[0];
+  cfg.u_dc_delta_q = delta_q[1];
+  cfg.u_ac_delta_q = delta_q[2];
+  cfg.v_dc_delta_q = delta_q[3];
+  cfg.v_ac_delta_q = delta_q[4];
+  cfg.delta_lf = matches.is_present("DELTA_LF");
+  cfg.filter_threads = matches.value_of("FILTER_THREADS").unwrap().parse().unwrap();
 
   cfg
 }
@@ -220,6 +354,7 @@ pub struct FrameSummary {
   pub frame_type: FrameType,
   // PSNR for Y, U, and V planes
   pub psnr: Option<(f64, f64, f64)>,
+  pub screen_content: bool,
 }
 
 impl From<Packet> for FrameSummary {
@@ -229,6 +364,7 @@ impl From<Packet> for FrameSummary {
       number: packet.number,
       frame_type: packet.frame_type,
       psnr: packet.psnr,
+      screen_content: packet.screen_content,
     }
   }
 }
@@ -237,10 +373,11 @@ impl fmt::Display for FrameSummary {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
     write!(
       f,
-      "Frame {} - {} - {} bytes{}",
+      "Frame {} - {} - {} bytes{}{}",
       self.number,
       self.frame_type,
       self.size,
+      if self.screen_content { " - screen content" } else { "" },
       if let Some(psnr) = self.psnr {
         format!(" - PSNR: Y: {:.4}  Cb: {:.4}  Cr: {:.4}", psnr.0, psnr.1, psnr.2)
       } else { String::new() }
@@ -47,6 +47,9 @@ fn main() {
   };
 
   let mut ctx = cfg.new_context();
+  if let Some(table) = cli.film_grain_table.take() {
+    ctx.set_film_grain_table(table);
+  }
 
   let stderr = io::stderr();
   let mut err = stderr.lock();
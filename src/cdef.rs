@@ -13,6 +13,7 @@ use context::*;
 use Frame;
 use FrameInvariants;
 use plane::*;
+use pool::WorkerPool;
 use util::{clamp, msb};
 
 use std::cmp;
@@ -24,6 +25,18 @@ pub struct CdefDirections {
 
 pub const CDEF_VERY_LARGE: u16 = 30000;
 const CDEF_SEC_STRENGTHS: u8 = 4;
+/// Number of coded strengths, the primary one times 4 plus the secondary
+pub const CDEF_STRENGTHS: usize = 64;
+pub const CDEF_STRENGTH_BITS: usize = 6;
+pub const CDEF_MAX_BITS: usize = 3;
+pub const CDEF_MAX_STRENGTH_PAIRS: usize = 1 << CDEF_MAX_BITS;
+/// The strengths searched with `fast_cdef`
+pub const CDEF_FAST_STRENGTHS: [usize; 16] = [
+    0*4+0, 0*4+1, 1*4+0, 1*4+1, 2*4+0, 2*4+1, 3*4+0, 3*4+1,
+    4*4+1, 5*4+2, 6*4+2, 7*4+3, 8*4+2, 10*4+3, 12*4+3, 15*4+3
+];
+pub const CDEF_MIN_DAMPING: u8 = 3;
+pub const CDEF_MAX_DAMPING: u8 = 6;
 
 // Instead of dividing by n between 2 and 8, we multiply by 3*5*7*8/n.
 // The output is then 840 times larger, but we don't care for finding
@@ -193,14 +206,27 @@ fn adjust_strength(strength: i32, var: i32) -> i32 {
     if var!=0 {strength * (4 + i) + 8 >> 4} else {0}
 }
 
+/// Whether 8x8 block (bx, by) of filter block `fbo` is left unfiltered,
+/// being outside the frame or made of skip blocks only
+pub fn cdef_block_skipped(bc: &BlockContext, fbo: &FilterBlockOffset, bx: usize, by: usize) -> bool {
+    let bo = fbo.block_offset(bx<<1, by<<1);
+    if bo.x >= bc.cols || bo.y >= bc.rows {
+        return true;
+    }
+    bc.at(&bo).skip
+        & bc.at(&fbo.block_offset(2*bx+1, 2*by)).skip
+        & bc.at(&fbo.block_offset(2*bx, 2*by+1)).skip
+        & bc.at(&fbo.block_offset(2*bx+1, 2*by+1)).skip
+}
+
 // For convenience of use alongside cdef_filter_superblock, we assume
 // in_frame is padded.  Blocks are not scanned outside the block
 // boundaries (padding is untouched here).
 
-pub fn cdef_analyze_superblock(in_frame: &mut Frame,
-                               bc_global: &mut BlockContext,
-                               sbo: &SuperBlockOffset,
-                               sbo_global: &SuperBlockOffset,
+pub fn cdef_analyze_superblock(in_frame: &Frame,
+                               bc_global: &BlockContext,
+                               sbo: &FilterBlockOffset,
+                               sbo_global: &FilterBlockOffset,
                                bit_depth: usize) -> CdefDirections {
     let coeff_shift = bit_depth as i32 - 8;
     let mut dir: CdefDirections = CdefDirections {dir: [[0; 8]; 8], var: [[0; 8]; 8]};
@@ -211,23 +237,15 @@ pub fn cdef_analyze_superblock(in_frame: &mut Frame,
             // boundaries and skips in the event we're passing in a
             // single-SB copy 'frame' that represents some superblock
             // in the main frame.
-            let global_block_offset = sbo_global.block_offset(bx<<1, by<<1);
-            if global_block_offset.x < bc_global.cols && global_block_offset.y < bc_global.rows {
-                let skip = bc_global.at(&global_block_offset).skip
-                         & bc_global.at(&sbo_global.block_offset(2*bx+1, 2*by)).skip
-                         & bc_global.at(&sbo_global.block_offset(2*bx, 2*by+1)).skip
-                         & bc_global.at(&sbo_global.block_offset(2*bx+1, 2*by+1)).skip;
-
-                if !skip {
-                    let mut var: i32 = 0;
-                    let mut in_plane = &mut in_frame.planes[0];
-                    let in_po = sbo.plane_offset(&in_plane.cfg);
-                    let in_stride = in_plane.cfg.stride;
-                    let in_slice = &in_plane.mut_slice(&in_po);
-                    dir.dir[bx][by] = cdef_find_dir(in_slice.offset(8*bx+2,8*by+2),
-                                                    in_stride, &mut var, coeff_shift) as u8;
-                    dir.var[bx][by] = var;
-                }
+            if !cdef_block_skipped(bc_global, sbo_global, bx, by) {
+                let mut var: i32 = 0;
+                let in_plane = &in_frame.planes[0];
+                let in_po = sbo.plane_offset(&in_plane.cfg);
+                let in_stride = in_plane.cfg.stride;
+                let in_slice = &in_plane.slice(&in_po);
+                dir.dir[bx][by] = cdef_find_dir(in_slice.subslice(8*bx+2,8*by+2).as_slice(),
+                                                in_stride, &mut var, coeff_shift) as u8;
+                dir.var[bx][by] = var;
             }
         }
     }
@@ -235,156 +253,247 @@ pub fn cdef_analyze_superblock(in_frame: &mut Frame,
 }
 
 // We assume in is padded, and the area we'll write out is at least as
-// large as the unpadded area of in
-// cdef_index is taken from the block context
+// large as the unpadded area of in. The output is written at the position
+// of out_sbo in out_frame.
 pub fn cdef_filter_superblock(fi: &FrameInvariants,
-                              in_frame: &mut Frame,
+                              in_frame: &Frame,
                               out_frame: &mut Frame,
-                              bc_global: &mut BlockContext,
-                              sbo: &SuperBlockOffset,
-                              sbo_global: &SuperBlockOffset,
+                              bc_global: &BlockContext,
+                              sbo: &FilterBlockOffset,
+                              sbo_global: &FilterBlockOffset,
+                              out_sbo: &FilterBlockOffset,
                               cdef_index: u8,
                               cdef_dirs: &CdefDirections) {
-    let coeff_shift = fi.sequence.bit_depth as i32 - 8;
-    let cdef_damping = fi.cdef_damping as i32;
-    let cdef_y_strength = fi.cdef_y_strengths[cdef_index as usize];
-    let cdef_uv_strength = fi.cdef_uv_strengths[cdef_index as usize];
-    let cdef_pri_y_strength = (cdef_y_strength / CDEF_SEC_STRENGTHS) as i32;
-    let mut cdef_sec_y_strength = (cdef_y_strength % CDEF_SEC_STRENGTHS) as i32;
-    let cdef_pri_uv_strength = (cdef_uv_strength / CDEF_SEC_STRENGTHS) as i32;
-    let mut cdef_sec_uv_strength = (cdef_uv_strength % CDEF_SEC_STRENGTHS) as i32;
-    if cdef_sec_y_strength == 3 {
-        cdef_sec_y_strength += 1;
+    for p in 0..3 {
+        let strength = if p == 0 {
+            fi.cdef_y_strengths[cdef_index as usize]
+        } else {
+            fi.cdef_uv_strengths[cdef_index as usize]
+        };
+        cdef_filter_superblock_plane(in_frame, out_frame, bc_global, sbo, sbo_global, out_sbo,
+                                     p, strength, fi.cdef_damping, fi.sequence.bit_depth,
+                                     cdef_dirs);
     }
-    if cdef_sec_uv_strength == 3 {
-        cdef_sec_uv_strength += 1;
+}
+
+/// Filters plane `p` of a filter block with `strength`, the primary
+/// strength times CDEF_SEC_STRENGTHS plus the coded secondary strength,
+/// from position `sbo` in `in_frame` to `out_sbo` in `out_frame`
+pub fn cdef_filter_superblock_plane(in_frame: &Frame,
+                                    out_frame: &mut Frame,
+                                    bc_global: &BlockContext,
+                                    sbo: &FilterBlockOffset,
+                                    sbo_global: &FilterBlockOffset,
+                                    out_sbo: &FilterBlockOffset,
+                                    p: usize,
+                                    strength: u8,
+                                    damping: u8,
+                                    bit_depth: usize,
+                                    cdef_dirs: &CdefDirections) {
+    let coeff_shift = bit_depth as i32 - 8;
+    let pri_strength = (strength / CDEF_SEC_STRENGTHS) as i32;
+    let mut sec_strength = (strength % CDEF_SEC_STRENGTHS) as i32;
+    if sec_strength == 3 {
+        sec_strength += 1;
     }
 
+    let out_plane = &mut out_frame.planes[p];
+    let out_po = out_sbo.plane_offset(&out_plane.cfg);
+    let in_plane = &in_frame.planes[p];
+    let in_po = sbo.plane_offset(&in_plane.cfg);
+    let xdec = in_plane.cfg.xdec;
+    let ydec = in_plane.cfg.ydec;
+
+    let in_stride = in_plane.cfg.stride;
+    let in_slice = &in_plane.slice(&in_po);
+    let out_stride = out_plane.cfg.stride;
+    let out_slice = &mut out_plane.mut_slice(&out_po);
+
     // Each direction block is 8x8 in y, potentially smaller if subsampled in chroma
     for by in 0..8 {
         for bx in 0..8 {
-            let global_block_offset = sbo_global.block_offset(bx<<1, by<<1);
-            if global_block_offset.x < bc_global.cols && global_block_offset.y < bc_global.rows {
-                let skip = bc_global.at(&global_block_offset).skip
-                         & bc_global.at(&sbo_global.block_offset(2*bx+1, 2*by)).skip
-                         & bc_global.at(&sbo_global.block_offset(2*bx, 2*by+1)).skip
-                         & bc_global.at(&sbo_global.block_offset(2*bx+1, 2*by+1)).skip;
-                if !skip {
-                    let dir = cdef_dirs.dir[bx][by];
-                    let var = cdef_dirs.var[bx][by];
-                    for p in 0..3 {
-                        let mut out_plane = &mut out_frame.planes[p];
-                        let out_po = sbo.plane_offset(&out_plane.cfg);
-                        let mut in_plane = &mut in_frame.planes[p];
-                        let in_po = sbo.plane_offset(&in_plane.cfg);
-                        let xdec = in_plane.cfg.xdec;
-                        let ydec = in_plane.cfg.ydec;
-
-                        let in_stride = in_plane.cfg.stride;
-                        let in_slice = &in_plane.mut_slice(&in_po);
-                        let out_stride = out_plane.cfg.stride;
-                        let mut out_slice = &mut out_plane.mut_slice(&out_po);
+            if !cdef_block_skipped(bc_global, sbo_global, bx, by) {
+                let dir = cdef_dirs.dir[bx][by];
+                let var = cdef_dirs.var[bx][by];
 
-                        let mut local_pri_strength;
-                        let mut local_sec_strength;
-                        let mut local_damping: i32 = cdef_damping + coeff_shift;
-                        let mut local_dir: usize;
+                let local_pri_strength;
+                let local_sec_strength = sec_strength << coeff_shift;
+                let mut local_damping: i32 = damping as i32 + coeff_shift;
+                let local_dir: usize = if pri_strength != 0 {dir as usize} else {0};
 
-                        if p==0 {
-                            local_pri_strength = adjust_strength(cdef_pri_y_strength << coeff_shift, var);
-                            local_sec_strength = cdef_sec_y_strength << coeff_shift;
-                            local_dir = if cdef_pri_y_strength != 0 {dir as usize} else {0};
-                        } else {
-                            local_pri_strength = cdef_pri_uv_strength << coeff_shift;
-                            local_sec_strength = cdef_sec_uv_strength << coeff_shift;
-                            local_damping -= 1;
-                            local_dir = if cdef_pri_uv_strength != 0 {dir as usize} else {0};
-                        }
+                if p==0 {
+                    local_pri_strength = adjust_strength(pri_strength << coeff_shift, var);
+                } else {
+                    local_pri_strength = pri_strength << coeff_shift;
+                    local_damping -= 1;
+                }
 
-                        unsafe {
-                            cdef_filter_block(out_slice.offset_as_mutable(8*bx>>xdec,8*by>>ydec),
-                                              out_stride as isize,
-                                              in_slice.offset(8*bx>>xdec,8*by>>ydec),
-                                              in_stride as isize,
-                                              local_pri_strength, local_sec_strength, local_dir,
-                                              local_damping, local_damping,
-                                              8 >> xdec, 8 >> ydec, coeff_shift as i32);
-                        }
-                    }
+                unsafe {
+                    cdef_filter_block(out_slice.offset_as_mutable(8*bx>>xdec,8*by>>ydec),
+                                      out_stride as isize,
+                                      in_slice.subslice(8*bx>>xdec,8*by>>ydec).as_slice(),
+                                      in_stride as isize,
+                                      local_pri_strength, local_sec_strength, local_dir,
+                                      local_damping, local_damping,
+                                      8 >> xdec, 8 >> ydec, coeff_shift as i32);
                 }
             }
         }
     }
 }
 
-// Input to this process is the array CurrFrame of reconstructed samples.
-// Output from this process is the array CdefFrame containing deringed samples.
-// The purpose of CDEF is to perform deringing based on the detected direction of blocks.
-// CDEF parameters are stored for each 64 by 64 block of pixels.
-// The CDEF filter is applied on each 8 by 8 block of pixels.
-// Reference: http://av1-spec.argondesign.com/av1-spec/av1-spec.html#cdef-process
-pub fn cdef_filter_frame(fi: &FrameInvariants, rec: &mut Frame, bc: &mut BlockContext) {
-
-    // Each filter block is 64x64, except right and/or bottom for non-multiple-of-64 sizes.
-    // FIXME: 128x128 SB support will break this, we need FilterBlockOffset etc.
-    let fb_height = (fi.padded_h + 63) / 64;
+/// Allocates the strip of one row of filter blocks, padded by 2 pixels on
+/// each side, from which CDEF reads
+pub fn cdef_strip(fi: &FrameInvariants, rec: &Frame) -> Frame {
     let fb_width = (fi.padded_w + 63) / 64;
+    let mut planes = rec.planes.iter().map(|p| {
+        let PlaneConfig { xdec, ydec, .. } = p.cfg;
+        Plane::new((fb_width*64 >> xdec) + 4, (64 >> ydec) + 4, xdec, ydec, 0, 0)
+    });
+    Frame { planes: [planes.next().unwrap(), planes.next().unwrap(), planes.next().unwrap()] }
+}
 
-    // Construct a padded copy of the reconstructed frame.
-    let mut padded_px: [[usize; 2]; 3] = [[0; 2]; 3];
+/// Copies filter block row `fby` of the reconstructed frame into `strip`
+/// with 2 rows above and below, the pixels outside the frame being flagged
+/// as CDEF_VERY_LARGE. The rows above are taken from the previous row of
+/// `strip`, as CDEF may have overwritten them in `rec` already, so the
+/// rows must be filled in order.
+pub fn cdef_fill_strip(fi: &FrameInvariants, rec: &Frame, fby: usize, strip: &mut Frame) {
     for p in 0..3 {
-        padded_px[p][0] =  (fb_width*64 >> rec.planes[p].cfg.xdec) + 4;
-        padded_px[p][1] =  (fb_height*64 >> rec.planes[p].cfg.ydec) + 4;
-    }
-    let mut cdef_frame = Frame {
-        planes: [
-            Plane::new(padded_px[0][0], padded_px[0][1], rec.planes[0].cfg.xdec, rec.planes[0].cfg.ydec, 0, 0),
-            Plane::new(padded_px[1][0], padded_px[1][1], rec.planes[1].cfg.xdec, rec.planes[1].cfg.ydec, 0, 0),
-            Plane::new(padded_px[2][0], padded_px[2][1], rec.planes[2].cfg.xdec, rec.planes[2].cfg.ydec, 0, 0)
-        ]
-    };
-    for p in 0..3 {
-        let rec_w = fi.padded_w >> rec.planes[p].cfg.xdec;
-        let rec_h = fi.padded_h >> rec.planes[p].cfg.ydec;
-        for row in 0..padded_px[p][1] {
-            // pad first two elements of current row
-            {
-                let mut cdef_slice = cdef_frame.planes[p].mut_slice(&PlaneOffset { x: 0, y: row as isize });
-                let mut cdef_row = &mut cdef_slice.as_mut_slice()[..2];
-                cdef_row[0] = CDEF_VERY_LARGE;
-                cdef_row[1] = CDEF_VERY_LARGE;
+        let PlaneConfig { xdec, ydec, .. } = rec.planes[p].cfg;
+        let rec_w = fi.padded_w >> xdec;
+        let rec_h = fi.padded_h >> ydec;
+        let fb_h = 64 >> ydec;
+        let strip_plane = &mut strip.planes[p];
+        let stride = strip_plane.cfg.stride;
+        let strip_w = strip_plane.cfg.width;
+        let data = strip_plane.data_origin_mut();
+        if fby > 0 {
+            let (above, below) = data.split_at_mut(fb_h * stride);
+            above[..2 * stride].copy_from_slice(&below[..2 * stride]);
+        }
+        let rec_stride = rec.planes[p].cfg.stride;
+        let rec_data = rec.planes[p].data_origin();
+        for row in 0..fb_h + 4 {
+            let y = (fby * fb_h + row) as isize - 2;
+            if row < 2 && fby > 0 {
+                continue;
             }
-            // pad out end of current row
-            {
-                let mut cdef_slice = cdef_frame.planes[p].mut_slice(&PlaneOffset { x: rec_w as isize + 2, y: row as isize });
-                let mut cdef_row = &mut cdef_slice.as_mut_slice()[..padded_px[p][0]-rec_w-2];
-                for x in cdef_row {
+            let strip_row = &mut data[row * stride..row * stride + strip_w];
+            if y < 0 || y >= rec_h as isize {
+                for x in strip_row.iter_mut() {
                     *x = CDEF_VERY_LARGE;
                 }
-            }
-            // copy current row from rec if we're in data, or pad if we're in first two rows/last N rows
-            {
-                let mut cdef_slice = cdef_frame.planes[p].mut_slice(&PlaneOffset { x: 2, y: row as isize });
-                let mut cdef_row = &mut cdef_slice.as_mut_slice()[..rec_w];
-                if row < 2 || row >= rec_h+2 {
-                    for x in cdef_row {
-                        *x = CDEF_VERY_LARGE;
-                    }
-                } else {
-                    let rec_stride = rec.planes[p].cfg.stride;
-                    cdef_row.copy_from_slice(&rec.planes[p].data_origin()[(row-2)*rec_stride..(row-1)*rec_stride][..rec_w]);
+            } else {
+                let y = y as usize;
+                strip_row[..2].copy_from_slice(&[CDEF_VERY_LARGE; 2]);
+                strip_row[2..rec_w + 2].copy_from_slice(&rec_data[y * rec_stride..][..rec_w]);
+                for x in strip_row[rec_w + 2..].iter_mut() {
+                    *x = CDEF_VERY_LARGE;
                 }
             }
         }
     }
+}
 
-    // Perform actual CDEF, using the padded copy as source, and the input rec vector as destination.
+/// Finds the directions of the filter blocks of row `fby` from its strip
+pub fn cdef_analyze_strip(fi: &FrameInvariants, strip: &Frame, bc: &BlockContext,
+                          fby: usize) -> Vec<CdefDirections> {
+    let fb_width = (fi.padded_w + 63) / 64;
+    (0..fb_width).map(|fbx| {
+        cdef_analyze_superblock(strip, bc, &FilterBlockOffset { x: fbx, y: 0 },
+                                &FilterBlockOffset { x: fbx, y: fby }, fi.sequence.bit_depth)
+    }).collect()
+}
+
+/// Finds the directions of all filter blocks of the frame, in raster order,
+/// so that they are computed once for both the strength search and the
+/// filter
+pub fn cdef_analyze_frame(fi: &FrameInvariants, rec: &Frame,
+                          bc: &BlockContext) -> Vec<CdefDirections> {
+    let fb_height = (fi.padded_h + 63) / 64;
+    let mut strip = cdef_strip(fi, rec);
+    let mut dirs = Vec::new();
     for fby in 0..fb_height {
-        for fbx in 0..fb_width {
-            let sbo = SuperBlockOffset { x: fbx, y: fby };
-            let cdef_index = bc.at(&sbo.block_offset(0, 0)).cdef_index;
-            let cdef_dirs = cdef_analyze_superblock(&mut cdef_frame, bc, &sbo, &sbo, fi.sequence.bit_depth);
-            cdef_filter_superblock(fi, &mut cdef_frame, rec, bc, &sbo, &sbo, cdef_index, &cdef_dirs);
+        cdef_fill_strip(fi, rec, fby, &mut strip);
+        dirs.extend(cdef_analyze_strip(fi, &strip, bc, fby));
+    }
+    dirs
+}
+
+/// Filters filter block row `fby` of `rec` from its strip, given the
+/// directions of its filter blocks. The filter blocks are spread over the
+/// workers of `pool`, each filtered into a block of its own, starting as a
+/// copy of the unfiltered pixels so that the skipped blocks are kept.
+pub fn cdef_filter_strip(fi: &FrameInvariants, strip: &Frame, dirs: &[CdefDirections],
+                         rec: &mut Frame, bc: &BlockContext, fby: usize,
+                         pool: &WorkerPool) {
+    let blocks = pool.map(dirs.iter().enumerate().collect(), |(fbx, cdef_dirs)| {
+        let fbo = FilterBlockOffset { x: fbx, y: fby };
+        let strip_fbo = FilterBlockOffset { x: fbx, y: 0 };
+        let cdef_index = bc.at(&fbo.block_offset(0, 0)).cdef_index;
+        let mut out = cdef_block(strip, fbx);
+        cdef_filter_superblock(fi, strip, &mut out, bc, &strip_fbo, &fbo,
+                               &FilterBlockOffset { x: 0, y: 0 }, cdef_index, cdef_dirs);
+        out
+    });
+    for (fbx, out) in blocks.iter().enumerate() {
+        cdef_store_block(fi, out, rec, fbx, fby);
+    }
+}
+
+// Copies filter block `fbx` of a strip, without its border
+fn cdef_block(strip: &Frame, fbx: usize) -> Frame {
+    let mut planes = strip.planes.iter().map(|strip_plane| {
+        let PlaneConfig { stride, xdec, ydec, .. } = strip_plane.cfg;
+        let (fb_w, fb_h) = (64 >> xdec, 64 >> ydec);
+        let mut block = Plane::new(fb_w, fb_h, xdec, ydec, 0, 0);
+        let block_stride = block.cfg.stride;
+        let data = &strip_plane.data_origin()[2 * stride + fbx * fb_w + 2..];
+        for (y, row) in block.data_origin_mut().chunks_mut(block_stride).take(fb_h).enumerate() {
+            row[..fb_w].copy_from_slice(&data[y * stride..][..fb_w]);
+        }
+        block
+    });
+    Frame { planes: [planes.next().unwrap(), planes.next().unwrap(), planes.next().unwrap()] }
+}
+
+// Copies filter block (`fbx`, `fby`), filtered into `out`, back into the
+// frame
+fn cdef_store_block(fi: &FrameInvariants, out: &Frame, rec: &mut Frame, fbx: usize,
+                    fby: usize) {
+    for (out_plane, rec_plane) in out.planes.iter().zip(rec.planes.iter_mut()) {
+        let PlaneConfig { xdec, ydec, .. } = rec_plane.cfg;
+        let (fb_w, fb_h) = (64 >> xdec, 64 >> ydec);
+        let cols = cmp::min(fb_w, (fi.padded_w >> xdec) - fbx * fb_w);
+        let rows = cmp::min(fb_h, (fi.padded_h >> ydec) - fby * fb_h);
+        let out_stride = out_plane.cfg.stride;
+        let rec_stride = rec_plane.cfg.stride;
+        let out_data = out_plane.data_origin();
+        let rec_data = &mut rec_plane.data_origin_mut()[fby * fb_h * rec_stride + fbx * fb_w..];
+        for y in 0..rows {
+            rec_data[y * rec_stride..][..cols].copy_from_slice(&out_data[y * out_stride..][..cols]);
         }
     }
 }
+
+// Input to this process is the array CurrFrame of reconstructed samples.
+// Output from this process is the array CdefFrame containing deringed samples.
+// The purpose of CDEF is to perform deringing based on the detected direction of blocks.
+// CDEF parameters are stored for each 64 by 64 block of pixels.
+// The CDEF filter is applied on each 8 by 8 block of pixels.
+// Reference: http://av1-spec.argondesign.com/av1-spec/av1-spec.html#cdef-process
+pub fn cdef_filter_frame(fi: &FrameInvariants, rec: &mut Frame, bc: &BlockContext,
+                         pool: &WorkerPool) {
+    let fb_height = (fi.padded_h + 63) / 64;
+    let fb_width = (fi.padded_w + 63) / 64;
+    let dirs = cdef_analyze_frame(fi, rec, bc);
+    // Each row is filtered in place from a strip, which keeps the unfiltered
+    // rows above
+    let mut strip = cdef_strip(fi, rec);
+    for fby in 0..fb_height {
+        cdef_fill_strip(fi, rec, fby, &mut strip);
+        cdef_filter_strip(fi, &strip, &dirs[fby * fb_width..(fby + 1) * fb_width], rec, bc, fby,
+                          pool);
+    }
+}
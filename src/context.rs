@@ -16,7 +16,7 @@
 #![cfg_attr(feature = "cargo-clippy", allow(needless_range_loop))]
 #![cfg_attr(feature = "cargo-clippy", allow(collapsible_if))]
 
-use ec::Writer;
+use ec::{OD_BITRES, Writer, WriterCheckpoint, WriterCounter};
 use encoder::{FrameInvariants, ReferenceMode};
 use entropymode::*;
 use partition::BlockSize::*;
@@ -25,10 +25,13 @@ use partition::TxSize::*;
 use partition::TxType::*;
 use partition::*;
 use lrf::*;
+use mc::FilterMode;
+use palette::*;
 use plane::*;
 use scan_order::*;
 use token_cdfs::*;
 use util::{clamp, msb};
+use warp::{global_mv, lower_mv_precision, ref_is_scaled, WarpSample, LEAST_SQUARES_SAMPLES_MAX};
 
 use std::*;
 
@@ -46,7 +49,7 @@ const MAX_MIB_SIZE_LOG2: usize = (MAX_SB_SIZE_LOG2 - MI_SIZE_LOG2);
 pub const MAX_MIB_SIZE: usize = (1 << MAX_MIB_SIZE_LOG2);
 pub const MAX_MIB_MASK: usize = (MAX_MIB_SIZE - 1);
 
-const MAX_SB_SIZE_LOG2: usize = 6;
+const MAX_SB_SIZE_LOG2: usize = 7;
 pub const MAX_SB_SIZE: usize = (1 << MAX_SB_SIZE_LOG2);
 const MAX_SB_SQUARE: usize = (MAX_SB_SIZE * MAX_SB_SIZE);
 
@@ -60,6 +63,10 @@ pub const CFL_JOINT_SIGNS: usize = 8;
 pub const CFL_ALPHA_CONTEXTS: usize = 6;
 pub const CFL_ALPHABET_SIZE: usize = 16;
 pub const SKIP_MODE_CONTEXTS: usize = 3;
+pub const PALETTE_BSIZE_CTXS: usize = 7;
+pub const PALETTE_Y_MODE_CONTEXTS: usize = 3;
+pub const PALETTE_UV_MODE_CONTEXTS: usize = 2;
+pub const PALETTE_COLOR_INDEX_CONTEXTS: usize = 5;
 pub const COMP_INDEX_CONTEXTS: usize = 6;
 pub const COMP_GROUP_IDX_CONTEXTS: usize = 6;
 
@@ -140,8 +147,8 @@ static ss_size_lookup: [[[BlockSize; 2]; 2]; BlockSize::BLOCK_SIZES_ALL] = [
   [  [ BLOCK_64X128, BLOCK_64X64 ], [ BLOCK_INVALID, BLOCK_32X64 ] ],
   [  [ BLOCK_128X64, BLOCK_INVALID ], [ BLOCK_64X64, BLOCK_64X32 ] ],
   [  [ BLOCK_128X128, BLOCK_128X64 ], [ BLOCK_64X128, BLOCK_64X64 ] ],
-  [  [ BLOCK_4X16, BLOCK_4X8 ], [BLOCK_4X16, BLOCK_4X8 ] ],
-  [  [ BLOCK_16X4, BLOCK_16X4 ], [BLOCK_8X4, BLOCK_8X4 ] ],
+  [  [ BLOCK_4X16, BLOCK_4X8 ], [BLOCK_INVALID, BLOCK_4X8 ] ],
+  [  [ BLOCK_16X4, BLOCK_INVALID ], [BLOCK_8X4, BLOCK_8X4 ] ],
   [  [ BLOCK_8X32, BLOCK_8X16 ], [BLOCK_INVALID, BLOCK_4X16 ] ],
   [  [ BLOCK_32X8, BLOCK_INVALID ], [BLOCK_16X8, BLOCK_16X4 ] ],
   [  [ BLOCK_16X64, BLOCK_16X32 ], [BLOCK_INVALID, BLOCK_8X32 ] ],
@@ -236,16 +243,16 @@ const MAX_BASE_BR_RANGE: usize = COEFF_BASE_RANGE + NUM_BASE_LEVELS + 1;
 const BASE_CONTEXT_POSITION_NUM: usize = 12;
 
 // Pad 4 extra columns to remove horizontal availability check.
-const TX_PAD_HOR_LOG2: usize = 2;
-const TX_PAD_HOR: usize = 4;
+pub const TX_PAD_HOR_LOG2: usize = 2;
+pub const TX_PAD_HOR: usize = 4;
 // Pad 6 extra rows (2 on top and 4 on bottom) to remove vertical availability
 // check.
-const TX_PAD_TOP: usize = 2;
+pub const TX_PAD_TOP: usize = 2;
 const TX_PAD_BOTTOM: usize = 4;
 const TX_PAD_VER: usize = (TX_PAD_TOP + TX_PAD_BOTTOM);
 // Pad 16 extra bytes to avoid reading overflow in SIMD optimization.
 const TX_PAD_END: usize = 16;
-const TX_PAD_2D: usize =
+pub const TX_PAD_2D: usize =
   ((MAX_TX_SIZE + TX_PAD_HOR) * (MAX_TX_SIZE + TX_PAD_VER) + TX_PAD_END);
 
 const TX_CLASSES: usize = 3;
@@ -278,7 +285,7 @@ pub const seg_feature_is_signed: [bool; SegLvl::SEG_LVL_MAX as usize] =
 
 use context::TxClass::*;
 
-static tx_type_to_class: [TxClass; TX_TYPES] = [
+pub static tx_type_to_class: [TxClass; TX_TYPES] = [
   TX_CLASS_2D,    // DCT_DCT
   TX_CLASS_2D,    // ADST_DCT
   TX_CLASS_2D,    // DCT_ADST
@@ -319,6 +326,16 @@ static k_eob_group_start: [u16; 12] = [ 0, 1, 2, 3, 5, 9,
                                         17, 33, 65, 129, 257, 513 ];
 static k_eob_offset_bits: [u16; 12] = [ 0, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9 ];
 
+/// Estimated cost of coding symbol `s` with `cdf`, in 1/8 bits. The
+/// fractional part of the logarithm is linearly approximated.
+pub fn symbol_cost(s: u32, cdf: &[u16]) -> u32 {
+  let fl = if s > 0 { cdf[s as usize - 1] as u32 } else { 32768 };
+  let p = cmp::max(fl - cdf[s as usize] as u32, 1);
+  let l = msb(p as i32) as u32;
+
+  ((15 - l) << OD_BITRES) - (((p << OD_BITRES) >> l) & ((1 << OD_BITRES) - 1))
+}
+
 fn clip_max3(x: u8) -> u8 {
   if x > 3 {
     3
@@ -688,6 +705,149 @@ pub struct CandidateMV {
   pub weight: u32
 }
 
+const MFMV_STACK_SIZE: usize = 3;
+const REFMVS_LIMIT: i16 = (1 << 12) - 1;
+const MAX_OFFSET_WIDTH: isize = 64;
+const MAX_OFFSET_HEIGHT: isize = 0;
+
+static DIV_MULT: [i32; 32] = [
+  0, 16384, 8192, 5461, 4096, 3276, 2730, 2340, 2048, 1820, 1638, 1489, 1365,
+  1260, 1170, 1092, 1024, 963, 910, 862, 819, 780, 744, 712, 682, 655, 630,
+  606, 585, 564, 546, 528
+];
+
+/// Motion vector of an 8x8 block and the reference it points to.
+#[derive(Clone, Copy, Debug)]
+pub struct SavedMotionVector {
+  pub ref_frame: usize,
+  pub mv: MotionVector
+}
+
+/// Motion field of a coded frame at 8x8 granularity, kept with the
+/// reference frame for the temporal motion vector prediction of later
+/// frames (spec 7.19).
+#[derive(Clone, Debug)]
+pub struct FrameMotionVectors {
+  pub cols: usize,
+  pub rows: usize,
+  pub mvs: Vec<Option<SavedMotionVector>>
+}
+
+/// Motion vector projected onto an 8x8 block of the current frame, with
+/// the distance it spans in the frame it was saved with.
+#[derive(Clone, Copy, Debug)]
+pub struct ProjectedMotionVector {
+  pub mv: MotionVector,
+  pub ref_offset: i32
+}
+
+/// Scales `mv` by `num / den` (spec 7.9.3).
+fn get_mv_projection(mv: MotionVector, num: i32, den: i32) -> MotionVector {
+  let den = den.min(MAX_FRAME_DISTANCE);
+  let num = num.max(-MAX_FRAME_DISTANCE).min(MAX_FRAME_DISTANCE);
+  let project = |v: i16| {
+    let p = v as i32 * num * DIV_MULT[den as usize];
+    let p = if p < 0 { -((-p + (1 << 13)) >> 14) } else { (p + (1 << 13)) >> 14 };
+    clamp(p, -(1 << 14) + 1, (1 << 14) - 1) as i16
+  };
+  MotionVector { row: project(mv.row), col: project(mv.col) }
+}
+
+/// Projects the motion field of reference `src` onto the current frame
+/// (spec 7.9.2). Returns false if the reference has no usable motion field.
+fn project_motion_field_from(
+  fi: &FrameInvariants, field: &mut [Option<ProjectedMotionVector>], src: usize
+) -> bool {
+  let rec = match fi.rec_buffer.frames[fi.ref_frames[src - LAST_FRAME] as usize] {
+    Some(ref rec) => rec,
+    None => return false
+  };
+  let cols = fi.w_in_b >> 1;
+  let rows = fi.h_in_b >> 1;
+  let saved = match rec.frame_mvs {
+    Some(ref saved) if saved.cols == cols && saved.rows == rows => saved,
+    _ => return false
+  };
+  // Motion is followed backwards from references in the past
+  let backwards = src < BWDREF_FRAME;
+  let mut src_offset = fi.sequence.get_relative_dist(rec.order_hint, fi.order_hint);
+  if backwards {
+    src_offset = -src_offset;
+  }
+  if src_offset.abs() > MAX_FRAME_DISTANCE {
+    return true;
+  }
+  for y8 in 0..rows {
+    for x8 in 0..cols {
+      let smv = match saved.mvs[y8 * cols + x8] {
+        Some(smv) => smv,
+        None => continue
+      };
+      let ref_offset = fi.sequence.get_relative_dist(
+        rec.order_hint, rec.ref_order_hints[smv.ref_frame - LAST_FRAME]
+      );
+      if ref_offset <= 0 || ref_offset > MAX_FRAME_DISTANCE {
+        continue;
+      }
+      let proj_mv = get_mv_projection(smv.mv, src_offset, ref_offset);
+      // Offset in 8x8 blocks, rounded towards zero
+      let offset = |v: i16| {
+        let o = (v.abs() >> 6) as isize;
+        if (v < 0) != backwards { -o } else { o }
+      };
+      let y = y8 as isize + offset(proj_mv.row);
+      let x = x8 as isize + offset(proj_mv.col);
+      // Projections stay within the same 64 pixel row and close to the
+      // same 64 pixel column
+      let base_y = (y8 & !7) as isize;
+      let base_x = (x8 & !7) as isize;
+      if y < 0 || y >= rows as isize || x < 0 || x >= cols as isize
+        || y < base_y - (MAX_OFFSET_HEIGHT >> 3)
+        || y >= base_y + 8 + (MAX_OFFSET_HEIGHT >> 3)
+        || x < base_x - (MAX_OFFSET_WIDTH >> 3)
+        || x >= base_x + 8 + (MAX_OFFSET_WIDTH >> 3) {
+        continue;
+      }
+      field[y as usize * cols + x as usize] =
+        Some(ProjectedMotionVector { mv: smv.mv, ref_offset });
+    }
+  }
+  true
+}
+
+/// Motion field estimation process (spec 7.9): the motion vectors of the
+/// references projected onto each 8x8 block of the current frame.
+pub fn project_motion_field(fi: &FrameInvariants) -> Vec<Option<ProjectedMotionVector>> {
+  let mut field = vec![None; (fi.w_in_b >> 1) * (fi.h_in_b >> 1)];
+  let is_future = |r: usize| {
+    fi.sequence.get_relative_dist(fi.ref_order_hint(r), fi.order_hint) > 0
+  };
+  let mut ref_stamp = MFMV_STACK_SIZE as isize - 1;
+  if let Some(ref last) = fi.rec_buffer.frames[fi.ref_frames[0] as usize] {
+    // An overlay of the golden frame has little motion to project
+    let is_overlay =
+      last.ref_order_hints[ALTREF_FRAME - LAST_FRAME] == fi.ref_order_hint(GOLDEN_FRAME);
+    if !is_overlay {
+      project_motion_field_from(fi, &mut field, LAST_FRAME);
+    }
+    ref_stamp -= 1;
+  }
+  if is_future(BWDREF_FRAME) && project_motion_field_from(fi, &mut field, BWDREF_FRAME) {
+    ref_stamp -= 1;
+  }
+  if is_future(ALTREF2_FRAME) && project_motion_field_from(fi, &mut field, ALTREF2_FRAME) {
+    ref_stamp -= 1;
+  }
+  if is_future(ALTREF_FRAME) && ref_stamp >= 0
+    && project_motion_field_from(fi, &mut field, ALTREF_FRAME) {
+    ref_stamp -= 1;
+  }
+  if ref_stamp >= 0 {
+    project_motion_field_from(fi, &mut field, LAST2_FRAME);
+  }
+  field
+}
+
 #[derive(Clone,Copy)]
 pub struct CDFContext {
   partition_cdf: [[u16; EXT_PARTITION_TYPES + 1]; PARTITION_CONTEXTS],
@@ -702,10 +862,22 @@ pub struct CDFContext {
   intra_tx_cdf:
     [[[[u16; TX_TYPES + 1]; INTRA_MODES]; TX_SIZE_SQR_CONTEXTS]; TX_SETS_INTRA],
   inter_tx_cdf: [[[u16; TX_TYPES + 1]; TX_SIZE_SQR_CONTEXTS]; TX_SETS_INTER],
+  tx_size_cdf: [[[u16; MAX_TX_DEPTH + 1 + 1]; TX_SIZE_CONTEXTS]; MAX_TX_CATS],
+  txfm_partition_cdf: [[u16; 2 + 1]; TXFM_PARTITION_CONTEXTS],
   skip_cdfs: [[u16; 3]; SKIP_CONTEXTS],
+  skip_mode_cdfs: [[u16; 3]; SKIP_MODE_CONTEXTS],
   intra_inter_cdfs: [[u16; 3]; INTRA_INTER_CONTEXTS],
   angle_delta_cdf: [[u16; 2 * MAX_ANGLE_DELTA + 1 + 1]; DIRECTIONAL_MODES],
   filter_intra_cdfs: [[u16; 3]; BlockSize::BLOCK_SIZES_ALL],
+  intrabc_cdf: [u16; 3],
+  palette_y_mode_cdfs: [[[u16; 3]; PALETTE_Y_MODE_CONTEXTS]; PALETTE_BSIZE_CTXS],
+  palette_uv_mode_cdfs: [[u16; 3]; PALETTE_UV_MODE_CONTEXTS],
+  palette_y_size_cdfs: [[u16; PaletteSize::PALETTE_SIZES as usize + 1]; PALETTE_BSIZE_CTXS],
+  palette_uv_size_cdfs: [[u16; PaletteSize::PALETTE_SIZES as usize + 1]; PALETTE_BSIZE_CTXS],
+  palette_y_color_index_cdfs: [[[u16; PaletteColor::PALETTE_COLORS as usize + 1];
+    PALETTE_COLOR_INDEX_CONTEXTS]; PaletteSize::PALETTE_SIZES as usize],
+  palette_uv_color_index_cdfs: [[[u16; PaletteColor::PALETTE_COLORS as usize + 1];
+    PALETTE_COLOR_INDEX_CONTEXTS]; PaletteSize::PALETTE_SIZES as usize],
   comp_mode_cdf: [[u16; 3]; COMP_INTER_CONTEXTS],
   comp_ref_type_cdf: [[u16; 3]; COMP_REF_TYPE_CONTEXTS],
   comp_ref_cdf: [[[u16; 3]; FWD_REFS - 1]; REF_CONTEXTS],
@@ -713,7 +885,20 @@ pub struct CDFContext {
   single_ref_cdfs: [[[u16; 2 + 1]; SINGLE_REFS - 1]; REF_CONTEXTS],
   drl_cdfs: [[u16; 2 + 1]; DRL_MODE_CONTEXTS],
   compound_mode_cdf: [[u16; INTER_COMPOUND_MODES + 1]; INTER_MODE_CONTEXTS],
+  motion_mode_cdf: [[u16; MotionMode::MOTION_MODES as usize + 1]; BlockSize::BLOCK_SIZES_ALL],
+  obmc_cdf: [[u16; 3]; BlockSize::BLOCK_SIZES_ALL],
+  interintra_cdf: [[u16; 3]; BLOCK_SIZE_GROUPS],
+  interintra_mode_cdf:
+    [[u16; InterIntraMode::INTERINTRA_MODES as usize + 1]; BLOCK_SIZE_GROUPS],
+  wedge_interintra_cdf: [[u16; 3]; BlockSize::BLOCK_SIZES_ALL],
+  comp_group_idx_cdf: [[u16; 3]; COMP_GROUP_IDX_CONTEXTS],
+  compound_idx_cdf: [[u16; 3]; COMP_INDEX_CONTEXTS],
+  compound_type_cdf: [[u16; MASKED_COMPOUND_TYPES + 1]; BlockSize::BLOCK_SIZES_ALL],
+  wedge_idx_cdf: [[u16; 16 + 1]; BlockSize::BLOCK_SIZES_ALL],
+  switchable_interp_cdf: [[u16; SWITCHABLE_FILTERS + 1]; SWITCHABLE_FILTER_CONTEXTS],
   nmv_context: NMVContext,
+  dv_context: NMVContext,
+  delta_q_cdf: [u16; DELTA_Q_PROBS + 1 + 1],
   deblock_delta_multi_cdf: [[u16; DELTA_LF_PROBS + 1 + 1]; FRAME_LF_COUNT],
   deblock_delta_cdf: [u16; DELTA_LF_PROBS + 1 + 1],
   spatial_segmentation_cdfs: [[u16; 8 + 1]; 3],
@@ -763,10 +948,20 @@ impl CDFContext {
       refmv_cdf: default_refmv_cdf,
       intra_tx_cdf: default_intra_ext_tx_cdf,
       inter_tx_cdf: default_inter_ext_tx_cdf,
+      tx_size_cdf: default_tx_size_cdf,
+      txfm_partition_cdf: default_txfm_partition_cdf,
       skip_cdfs: default_skip_cdfs,
+      skip_mode_cdfs: default_skip_mode_cdfs,
       intra_inter_cdfs: default_intra_inter_cdf,
       angle_delta_cdf: default_angle_delta_cdf,
       filter_intra_cdfs: default_filter_intra_cdfs,
+      intrabc_cdf: default_intrabc_cdf,
+      palette_y_mode_cdfs: default_palette_y_mode_cdf,
+      palette_uv_mode_cdfs: default_palette_uv_mode_cdf,
+      palette_y_size_cdfs: default_palette_y_size_cdf,
+      palette_uv_size_cdfs: default_palette_uv_size_cdf,
+      palette_y_color_index_cdfs: default_palette_y_color_index_cdf,
+      palette_uv_color_index_cdfs: default_palette_uv_color_index_cdf,
       comp_mode_cdf: default_comp_mode_cdf,
       comp_ref_type_cdf: default_comp_ref_type_cdf,
       comp_ref_cdf: default_comp_ref_cdf,
@@ -774,7 +969,19 @@ impl CDFContext {
       single_ref_cdfs: default_single_ref_cdf,
       drl_cdfs: default_drl_cdf,
       compound_mode_cdf: default_compound_mode_cdf,
+      motion_mode_cdf: default_motion_mode_cdf,
+      obmc_cdf: default_obmc_cdf,
+      interintra_cdf: default_interintra_cdf,
+      interintra_mode_cdf: default_interintra_mode_cdf,
+      wedge_interintra_cdf: default_wedge_interintra_cdf,
+      comp_group_idx_cdf: default_comp_group_idx_cdfs,
+      compound_idx_cdf: default_compound_idx_cdfs,
+      compound_type_cdf: default_compound_type_cdf,
+      wedge_idx_cdf: default_wedge_idx_cdf,
+      switchable_interp_cdf: default_switchable_interp_cdf,
       nmv_context: default_nmv_context,
+      dv_context: default_nmv_context,
+      delta_q_cdf: default_delta_q_cdf,
       deblock_delta_multi_cdf: default_delta_lf_multi_cdf,
       deblock_delta_cdf: default_delta_lf_cdf,
       spatial_segmentation_cdfs: default_spatial_pred_seg_tree_cdf,
@@ -801,6 +1008,13 @@ impl CDFContext {
     }
   }
 
+  /// Restores the delta_lf CDFs of `fc`, for the block-level deblocking
+  /// deltas to be coded again once they are decided
+  pub fn restore_delta_lf(&mut self, fc: &CDFContext) {
+    self.deblock_delta_multi_cdf = fc.deblock_delta_multi_cdf;
+    self.deblock_delta_cdf = fc.deblock_delta_cdf;
+  }
+
   pub fn reset_counts(&mut self) {
     macro_rules! reset_1d {
       ($field:expr) => (let r = $field.last_mut().unwrap(); *r = 0;)
@@ -841,11 +1055,32 @@ impl CDFContext {
       self.inter_tx_cdf[2][i][12] = 0;
       self.inter_tx_cdf[3][i][2] = 0;
     }
+    // Transforms of the smallest category have two depths to choose from
+    for i in 0..TX_SIZE_CONTEXTS {
+      self.tx_size_cdf[0][i][MAX_TX_DEPTH] = 0;
+    }
+    for i in 1..MAX_TX_CATS {
+      reset_2d!(self.tx_size_cdf[i]);
+    }
+    reset_2d!(self.txfm_partition_cdf);
 
     reset_2d!(self.skip_cdfs);
+    reset_2d!(self.skip_mode_cdfs);
     reset_2d!(self.intra_inter_cdfs);
     reset_2d!(self.angle_delta_cdf);
     reset_2d!(self.filter_intra_cdfs);
+    reset_1d!(self.intrabc_cdf);
+    reset_3d!(self.palette_y_mode_cdfs);
+    reset_2d!(self.palette_uv_mode_cdfs);
+    reset_2d!(self.palette_y_size_cdfs);
+    reset_2d!(self.palette_uv_size_cdfs);
+    // The colour index CDFs of a palette size n have n symbols
+    for i in 0..PaletteSize::PALETTE_SIZES as usize {
+      for j in 0..PALETTE_COLOR_INDEX_CONTEXTS {
+        self.palette_y_color_index_cdfs[i][j][i + PALETTE_MIN_SIZE] = 0;
+        self.palette_uv_color_index_cdfs[i][j][i + PALETTE_MIN_SIZE] = 0;
+      }
+    }
     reset_2d!(self.comp_mode_cdf);
     reset_2d!(self.comp_ref_type_cdf);
     reset_3d!(self.comp_ref_cdf);
@@ -853,6 +1088,17 @@ impl CDFContext {
     reset_3d!(self.single_ref_cdfs);
     reset_2d!(self.drl_cdfs);
     reset_2d!(self.compound_mode_cdf);
+    reset_2d!(self.motion_mode_cdf);
+    reset_2d!(self.obmc_cdf);
+    reset_2d!(self.interintra_cdf);
+    reset_2d!(self.interintra_mode_cdf);
+    reset_2d!(self.wedge_interintra_cdf);
+    reset_2d!(self.comp_group_idx_cdf);
+    reset_2d!(self.compound_idx_cdf);
+    reset_2d!(self.compound_type_cdf);
+    reset_2d!(self.wedge_idx_cdf);
+    reset_2d!(self.switchable_interp_cdf);
+    reset_1d!(self.delta_q_cdf);
     reset_2d!(self.deblock_delta_multi_cdf);
     reset_1d!(self.deblock_delta_cdf);
     reset_2d!(self.spatial_segmentation_cdfs);
@@ -860,16 +1106,18 @@ impl CDFContext {
     reset_1d!(self.lrf_sgrproj_cdf);
     reset_1d!(self.lrf_wiener_cdf);
 
-    reset_1d!(self.nmv_context.joints_cdf);
-    for i in 0..2 {
-      reset_1d!(self.nmv_context.comps[i].classes_cdf);
-      reset_2d!(self.nmv_context.comps[i].class0_fp_cdf);
-      reset_1d!(self.nmv_context.comps[i].fp_cdf);
-      reset_1d!(self.nmv_context.comps[i].sign_cdf);
-      reset_1d!(self.nmv_context.comps[i].class0_hp_cdf);
-      reset_1d!(self.nmv_context.comps[i].hp_cdf);
-      reset_1d!(self.nmv_context.comps[i].class0_cdf);
-      reset_2d!(self.nmv_context.comps[i].bits_cdf);
+    for nmvc in [&mut self.nmv_context, &mut self.dv_context].iter_mut() {
+      reset_1d!(nmvc.joints_cdf);
+      for i in 0..2 {
+        reset_1d!(nmvc.comps[i].classes_cdf);
+        reset_2d!(nmvc.comps[i].class0_fp_cdf);
+        reset_1d!(nmvc.comps[i].fp_cdf);
+        reset_1d!(nmvc.comps[i].sign_cdf);
+        reset_1d!(nmvc.comps[i].class0_hp_cdf);
+        reset_1d!(nmvc.comps[i].hp_cdf);
+        reset_1d!(nmvc.comps[i].class0_cdf);
+        reset_2d!(nmvc.comps[i].bits_cdf);
+      }
     }
 
     // lv_map
@@ -948,6 +1196,10 @@ impl CDFContext {
       self.comp_bwd_ref_cdf.first().unwrap().as_ptr() as usize;
     let comp_bwd_ref_cdf_end =
       comp_bwd_ref_cdf_start + size_of_val(&self.comp_bwd_ref_cdf);
+    let delta_q_cdf_start =
+      self.delta_q_cdf.as_ptr() as usize;
+    let delta_q_cdf_end =
+      delta_q_cdf_start + size_of_val(&self.delta_q_cdf);
     let deblock_delta_multi_cdf_start =
       self.deblock_delta_multi_cdf.first().unwrap().as_ptr() as usize;
     let deblock_delta_multi_cdf_end =
@@ -1042,6 +1294,7 @@ impl CDFContext {
       ("comp_ref_type_cdf", comp_ref_type_cdf_start, comp_ref_type_cdf_end),
       ("comp_ref_cdf", comp_ref_cdf_start, comp_ref_cdf_end),
       ("comp_bwd_ref_cdf", comp_bwd_ref_cdf_start, comp_bwd_ref_cdf_end),
+      ("delta_q_cdf", delta_q_cdf_start, delta_q_cdf_end),
       ("deblock_delta_multi_cdf", deblock_delta_multi_cdf_start, deblock_delta_multi_cdf_end),
       ("deblock_delta_cdf", deblock_delta_cdf_start, deblock_delta_cdf_end),
       ("spatial_segmentation_cdfs", spatial_segmentation_cdfs_start, spatial_segmentation_cdfs_end),
@@ -1119,35 +1372,333 @@ mod test {
       }
     }
   }
+
+  #[test]
+  fn superblock_filter_blocks() {
+    use super::*;
+
+    // A 128x128 superblock holds four 64x64 filter blocks in raster order
+    let sbo = SuperBlockOffset { x: 1, y: 2, size_log2: 7 };
+    let fbos = sbo.filter_blocks();
+    let coords: Vec<_> = fbos.iter().map(|fbo| (fbo.x, fbo.y)).collect();
+    assert!(coords == vec![(2, 4), (3, 4), (2, 5), (3, 5)]);
+
+    let mut bc = BlockContext::new(128, 128);
+    for (i, fbo) in fbos.iter().enumerate() {
+      assert!(fbo.block_offset(0, 0).fb_index_in_sb() == i);
+      assert!(fbo.block_offset(15, 15).fb_index_in_sb() == i);
+      bc.set_cdef(fbo, i as u8 + 1);
+    }
+    let bo = sbo.block_offset(0, 0);
+    for (x, y, idx) in [(0, 0, 1), (15, 15, 1), (16, 0, 2), (31, 15, 2),
+                        (0, 16, 3), (15, 31, 3), (16, 16, 4), (31, 31, 4)].iter() {
+      assert!(bc.blocks[bo.y + y][bo.x + x].cdef_index == *idx);
+    }
+    assert!(bc.blocks[bo.y][bo.x - 1].cdef_index == 0);
+
+    let sbo = SuperBlockOffset { x: 1, y: 2, size_log2: 6 };
+    let fbos = sbo.filter_blocks();
+    assert!(fbos.len() == 1);
+    assert!((fbos[0].x, fbos[0].y) == (1, 2));
+  }
+
+  #[test]
+  fn obmc_signaling() {
+    use super::*;
+    use api::*;
+    use ec::WriterRecorder;
+    use encoder::{FrameInvariants, Sequence};
+
+    let seq = Sequence::new(&FrameInfo { width: 64, height: 64, ..Default::default() });
+    let mut fi = FrameInvariants::new(64, 64, EncoderConfig::default(), seq);
+    fi.is_motion_mode_switchable = true;
+    let mut cw = ContextWriter::new(CDFContext::new(0), BlockContext::new(16, 16));
+    let bo = BlockOffset { x: 4, y: 4 };
+    let mode = PredictionMode::NEWMV;
+    let last = [LAST_FRAME, NONE_FRAME];
+    let switchable = |cw: &ContextWriter, fi: &FrameInvariants, bsize, refs| {
+      cw.motion_mode_switchable(fi, &bo, bsize, mode, refs)
+    };
+
+    // Without inter neighbours there is nothing to overlap with
+    assert!(!switchable(&cw, &fi, BLOCK_16X16, last));
+    let default_cdf = cw.fc.obmc_cdf[BLOCK_16X16 as usize];
+    let mut w = WriterRecorder::new();
+    cw.write_motion_mode(&mut w, &fi, &bo, BLOCK_16X16, mode, last, MotionVector { row: 0, col: 0 }, MotionMode::SIMPLE_TRANSLATION, false);
+    assert!(cw.fc.obmc_cdf[BLOCK_16X16 as usize] == default_cdf);
+
+    cw.bc.blocks[3][5].ref_frames = last;
+    assert!(switchable(&cw, &fi, BLOCK_16X16, last));
+    assert!(switchable(&cw, &fi, BLOCK_8X8, last));
+    assert!(!switchable(&cw, &fi, BLOCK_8X4, last));
+    assert!(!switchable(&cw, &fi, BLOCK_16X16, [LAST_FRAME, BWDREF_FRAME]));
+    cw.write_motion_mode(&mut w, &fi, &bo, BLOCK_16X16, mode, last, MotionVector { row: 0, col: 0 }, MotionMode::OBMC_CAUSAL, false);
+    assert!(cw.fc.obmc_cdf[BLOCK_16X16 as usize] != default_cdf);
+
+    fi.is_motion_mode_switchable = false;
+    assert!(!switchable(&cw, &fi, BLOCK_16X16, last));
+  }
+
+  #[test]
+  fn interintra_allowed() {
+    use super::*;
+    use api::*;
+    use encoder::{FrameInvariants, Sequence};
+
+    let mut seq = Sequence::new(&FrameInfo { width: 64, height: 64, ..Default::default() });
+    seq.enable_interintra_compound = true;
+    let mut fi = FrameInvariants::new(64, 64, EncoderConfig::default(), seq);
+    let cw = ContextWriter::new(CDFContext::new(0), BlockContext::new(16, 16));
+    let last = [LAST_FRAME, NONE_FRAME];
+
+    for &bsize in [BLOCK_8X8, BLOCK_8X16, BLOCK_16X8, BLOCK_16X16, BLOCK_16X32,
+                   BLOCK_32X16, BLOCK_32X32].iter() {
+      assert!(cw.interintra_allowed(&fi, bsize, last));
+      assert!(!cw.interintra_allowed(&fi, bsize, [LAST_FRAME, BWDREF_FRAME]));
+    }
+    for &bsize in [BLOCK_4X4, BLOCK_4X8, BLOCK_8X4, BLOCK_32X64, BLOCK_64X64,
+                   BLOCK_4X16, BLOCK_16X4, BLOCK_8X32, BLOCK_32X8].iter() {
+      assert!(!cw.interintra_allowed(&fi, bsize, last));
+    }
+    fi.sequence.enable_interintra_compound = false;
+    assert!(!cw.interintra_allowed(&fi, BLOCK_16X16, last));
+  }
+
+  #[test]
+  fn skip_mode_context() {
+    use super::*;
+    use ec::WriterRecorder;
+
+    let mut cw = ContextWriter::new(CDFContext::new(0), BlockContext::new(16, 16));
+    let default_cdfs = cw.fc.skip_mode_cdfs;
+    let mut w = WriterRecorder::new();
+    // The context counts the skip mode blocks above and to the left
+    let mut context = |cw: &mut ContextWriter, bo: &BlockOffset| {
+      let cdfs = cw.fc.skip_mode_cdfs;
+      cw.write_skip_mode(&mut w, bo, true);
+      let updated: Vec<_> =
+        (0..SKIP_MODE_CONTEXTS).filter(|&i| cw.fc.skip_mode_cdfs[i] != cdfs[i]).collect();
+      assert!(updated.len() == 1);
+      cw.fc.skip_mode_cdfs = default_cdfs;
+      updated[0]
+    };
+
+    let bo = BlockOffset { x: 4, y: 4 };
+    assert!(context(&mut cw, &bo) == 0);
+    cw.bc.set_skip_mode(&BlockOffset { x: 4, y: 2 }, BLOCK_8X8, true);
+    assert!(context(&mut cw, &bo) == 1);
+    cw.bc.set_skip_mode(&BlockOffset { x: 2, y: 4 }, BLOCK_8X8, true);
+    assert!(context(&mut cw, &bo) == 2);
+    cw.bc.set_skip_mode(&BlockOffset { x: 4, y: 2 }, BLOCK_8X8, false);
+    assert!(context(&mut cw, &bo) == 1);
+    // Blocks on the frame edges have no neighbour there
+    cw.bc.set_skip_mode(&BlockOffset { x: 0, y: 0 }, BLOCK_16X16, true);
+    assert!(context(&mut cw, &BlockOffset { x: 0, y: 4 }) == 1);
+    assert!(context(&mut cw, &BlockOffset { x: 4, y: 0 }) == 1);
+    assert!(context(&mut cw, &BlockOffset { x: 0, y: 0 }) == 0);
+  }
+
+  #[test]
+  fn motion_field_projection() {
+    use super::*;
+    use encoder::test::inter_frame;
+    use std::sync::Arc;
+
+    // 32x16 8x8 blocks
+    let mut fi = inter_frame(256, 128);
+    let (cols, rows) = (32, 16);
+    fi.order_hint = 4;
+    // Reference `ref_frame` at `order_hint`, whose blocks all moved by `mv`
+    // from its own LAST_FRAME at `last_hint`
+    let set_ref = |fi: &mut FrameInvariants, ref_frame: usize, order_hint, last_hint, mv| {
+      let rf = Arc::make_mut(fi.rec_buffer.frames[ref_frame - LAST_FRAME].as_mut().unwrap());
+      rf.order_hint = order_hint;
+      rf.ref_order_hints[0] = last_hint;
+      let smv = SavedMotionVector { ref_frame: LAST_FRAME, mv };
+      rf.frame_mvs = Some(FrameMotionVectors { cols, rows, mvs: vec![Some(smv); cols * rows] });
+    };
+    let project = |fi: &FrameInvariants, ref_frame| {
+      let mut field = vec![None; cols * rows];
+      assert!(project_motion_field_from(fi, &mut field, ref_frame));
+      field
+    };
+    let projected = |field: &[Option<ProjectedMotionVector>], x: usize, y: usize| {
+      field[y * cols + x].map(|p| (p.mv.row, p.mv.col, p.ref_offset))
+    };
+
+    // Motion of 2 blocks to the right over the 2 frames to LAST is followed
+    // back 2 blocks to the left
+    set_ref(&mut fi, LAST_FRAME, 2, 0, MotionVector { row: 0, col: 128 });
+    let field = project(&fi, LAST_FRAME);
+    for y in 0..rows {
+      for x in 0..cols {
+        let expected = if x < cols - 2 { Some((0, 128, 2)) } else { None };
+        assert!(projected(&field, x, y) == expected);
+      }
+    }
+
+    // Motion of a future frame over 4 frames is halved for the 2 frames to
+    // it, and followed forwards
+    set_ref(&mut fi, BWDREF_FRAME, 6, 2, MotionVector { row: 0, col: 256 });
+    let field = project(&fi, BWDREF_FRAME);
+    for y in 0..rows {
+      for x in 0..cols {
+        let expected = if x >= 2 { Some((0, 256, 4)) } else { None };
+        assert!(projected(&field, x, y) == expected);
+      }
+    }
+
+    // Projections leaving their 64 pixel row are rejected
+    set_ref(&mut fi, LAST_FRAME, 2, 0, MotionVector { row: 64, col: 0 });
+    let field = project(&fi, LAST_FRAME);
+    for y in 0..rows {
+      let expected = if y % 8 != 7 { Some((64, 0, 2)) } else { None };
+      assert!((0..cols).all(|x| projected(&field, x, y) == expected));
+    }
+    // as are those landing more than 64 pixels past their 64 pixel column
+    set_ref(&mut fi, LAST_FRAME, 2, 0, MotionVector { row: 0, col: -64 * 9 });
+    let field = project(&fi, LAST_FRAME);
+    for x in 0..cols {
+      let expected = if x >= 9 && x % 8 != 0 { Some((0, -64 * 9, 2)) } else { None };
+      assert!((0..rows).all(|y| projected(&field, x, y) == expected));
+    }
+    // and motion to a reference in the future of the saved frame
+    set_ref(&mut fi, LAST_FRAME, 2, 3, MotionVector { row: 0, col: 0 });
+    assert!(project(&fi, LAST_FRAME).iter().all(|p| p.is_none()));
+  }
+
+  #[test]
+  fn tx_partition_blocks() {
+    use super::TxPartition;
+    use partition::TxSize::*;
+
+    let mut tx_partition = TxPartition::default();
+    assert_eq!(tx_partition.tx_blocks(1, TX_32X32), vec![(0, 0, TX_32X32)]);
+
+    tx_partition.set_split(1, 0, true);
+    tx_partition.set_split(1, 2, true);
+    assert_eq!(tx_partition.tx_blocks(1, TX_32X32), vec![
+      (0, 0, TX_16X16),
+      (4, 0, TX_8X8), (6, 0, TX_8X8), (4, 2, TX_8X8), (6, 2, TX_8X8),
+      (0, 4, TX_16X16),
+      (4, 4, TX_16X16)
+    ]);
+    assert!(!tx_partition.is_split(0, 0));
+
+    tx_partition.set_split(0, 0, true);
+    assert_eq!(tx_partition.tx_blocks(0, TX_16X64).len(), 2);
+  }
+
+  #[test]
+  fn patched_block_deblock_deltas() {
+    use super::*;
+    use ec::{WriterEncoder, WriterRecorder};
+
+    // Coded changes of two superblocks, with a magnitude needing literals
+    let deltas = [[3, -2, 0, 1], [-5, 0, 2, 0]];
+    for &multi in &[false, true] {
+      let fc = CDFContext::new(100);
+      let mut cw = ContextWriter::new(fc, BlockContext::new(128, 128));
+      let mut w = WriterRecorder::new();
+      for d in deltas.iter() {
+        cw.write_block_deblock_deltas(&mut w, *d, multi);
+        w.bool(true, 16384);
+      }
+
+      // Zero placeholders, coded again from the frame CDFs once decided
+      let mut cw = ContextWriter::new(fc, BlockContext::new(128, 128));
+      let mut w_patched = WriterRecorder::new();
+      let mut checkpoints = Vec::new();
+      for _ in deltas.iter() {
+        checkpoints.push(Writer::checkpoint(&mut w_patched));
+        cw.write_block_deblock_deltas(&mut w_patched, [0; FRAME_LF_COUNT], multi);
+        w_patched.bool(true, 16384);
+      }
+      cw.fc.restore_delta_lf(&fc);
+      let len = if multi { FRAME_LF_COUNT + PLANES - 3 } else { 1 };
+      let patches = checkpoints.into_iter().zip(deltas.iter()).map(|(c, d)| {
+        let mut w_deltas = WriterRecorder::new();
+        cw.write_block_deblock_deltas(&mut w_deltas, *d, multi);
+        (c, len, w_deltas)
+      }).collect();
+      w_patched.patch(patches);
+
+      let mut e = WriterEncoder::new();
+      w.replay(&mut e);
+      let mut e_patched = WriterEncoder::new();
+      w_patched.replay(&mut e_patched);
+      assert!(e.done() == e_patched.done());
+    }
+  }
 }
 
-const SUPERBLOCK_TO_PLANE_SHIFT: usize = MAX_SB_SIZE_LOG2;
-const SUPERBLOCK_TO_BLOCK_SHIFT: usize = MAX_MIB_SIZE_LOG2;
+const FILTER_BLOCK_TO_PLANE_SHIFT: usize = 6;
+const FILTER_BLOCK_TO_BLOCK_SHIFT: usize = FILTER_BLOCK_TO_PLANE_SHIFT - MI_SIZE_LOG2;
 pub const BLOCK_TO_PLANE_SHIFT: usize = MI_SIZE_LOG2;
-pub const LOCAL_BLOCK_MASK: usize = (1 << SUPERBLOCK_TO_BLOCK_SHIFT) - 1;
 
 /// Absolute offset in superblocks inside a plane, where a superblock is defined
-/// to be an N*N square where N = (1 << SUPERBLOCK_TO_PLANE_SHIFT).
+/// to be an N*N square where N = (1 << size_log2), 64 or 128 as signaled in
+/// the sequence header.
 #[derive(Clone)]
 pub struct SuperBlockOffset {
   pub x: usize,
-  pub y: usize
+  pub y: usize,
+  pub size_log2: usize
 }
 
 impl SuperBlockOffset {
   /// Offset of a block inside the current superblock.
   pub fn block_offset(&self, block_x: usize, block_y: usize) -> BlockOffset {
+    let shift = self.size_log2 - MI_SIZE_LOG2;
     BlockOffset {
-      x: (self.x << SUPERBLOCK_TO_BLOCK_SHIFT) + block_x,
-      y: (self.y << SUPERBLOCK_TO_BLOCK_SHIFT) + block_y
+      x: (self.x << shift) + block_x,
+      y: (self.y << shift) + block_y
     }
   }
 
-  /// Offset of the top-left pixel of this block.
+  /// Offset of the top-left pixel of this superblock.
+  pub fn plane_offset(&self, plane: &PlaneConfig) -> PlaneOffset {
+    PlaneOffset {
+      x: (self.x as isize) << (self.size_log2 - plane.xdec),
+      y: (self.y as isize) << (self.size_log2 - plane.ydec)
+    }
+  }
+
+  /// The 64x64 filter blocks covered by this superblock, in raster order.
+  pub fn filter_blocks(&self) -> Vec<FilterBlockOffset> {
+    let n = 1 << (self.size_log2 - FILTER_BLOCK_TO_PLANE_SHIFT);
+    let mut fbos = Vec::with_capacity(n * n);
+    for y in 0..n {
+      for x in 0..n {
+        fbos.push(FilterBlockOffset { x: (self.x * n) + x, y: (self.y * n) + y });
+      }
+    }
+    fbos
+  }
+}
+
+/// Absolute offset in 64x64 filter blocks inside a plane. CDEF strengths
+/// are signaled per filter block, so a 128x128 superblock holds 2x2 of them.
+#[derive(Clone)]
+pub struct FilterBlockOffset {
+  pub x: usize,
+  pub y: usize
+}
+
+impl FilterBlockOffset {
+  /// Offset of a block inside the current filter block.
+  pub fn block_offset(&self, block_x: usize, block_y: usize) -> BlockOffset {
+    BlockOffset {
+      x: (self.x << FILTER_BLOCK_TO_BLOCK_SHIFT) + block_x,
+      y: (self.y << FILTER_BLOCK_TO_BLOCK_SHIFT) + block_y
+    }
+  }
+
+  /// Offset of the top-left pixel of this filter block.
   pub fn plane_offset(&self, plane: &PlaneConfig) -> PlaneOffset {
     PlaneOffset {
-      x: (self.x as isize) << (SUPERBLOCK_TO_PLANE_SHIFT - plane.xdec),
-      y: (self.y as isize) << (SUPERBLOCK_TO_PLANE_SHIFT - plane.ydec)
+      x: (self.x as isize) << (FILTER_BLOCK_TO_PLANE_SHIFT - plane.xdec),
+      y: (self.y as isize) << (FILTER_BLOCK_TO_PLANE_SHIFT - plane.ydec)
     }
   }
 }
@@ -1161,20 +1712,20 @@ pub struct BlockOffset {
 }
 
 impl BlockOffset {
-  /// Offset of the superblock in which this block is located.
-  pub fn sb_offset(&self) -> SuperBlockOffset {
-    SuperBlockOffset {
-      x: self.x >> SUPERBLOCK_TO_BLOCK_SHIFT,
-      y: self.y >> SUPERBLOCK_TO_BLOCK_SHIFT
+  /// Offset of the filter block in which this block is located.
+  pub fn fb_offset(&self) -> FilterBlockOffset {
+    FilterBlockOffset {
+      x: self.x >> FILTER_BLOCK_TO_BLOCK_SHIFT,
+      y: self.y >> FILTER_BLOCK_TO_BLOCK_SHIFT
     }
   }
 
   /// Offset of the top-left pixel of this block.
   pub fn plane_offset(&self, plane: &PlaneConfig) -> PlaneOffset {
-    let po = self.sb_offset().plane_offset(plane);
+    let po = self.fb_offset().plane_offset(plane);
 
-    let x_offset = self.x & LOCAL_BLOCK_MASK;
-    let y_offset = self.y & LOCAL_BLOCK_MASK;
+    let x_offset = self.x & ((1 << FILTER_BLOCK_TO_BLOCK_SHIFT) - 1);
+    let y_offset = self.y & ((1 << FILTER_BLOCK_TO_BLOCK_SHIFT) - 1);
 
     PlaneOffset {
         x: po.x + (x_offset as isize >> plane.xdec << BLOCK_TO_PLANE_SHIFT),
@@ -1186,6 +1737,13 @@ impl BlockOffset {
     self.y % MAX_MIB_SIZE
   }
 
+  /// Index of the containing 64x64 filter block within the superblock,
+  /// in raster order.
+  pub fn fb_index_in_sb(&self) -> usize {
+    let fb = self.fb_offset();
+    (fb.x & 1) + ((fb.y & 1) << 1)
+  }
+
   pub fn with_offset(&self, col_offset: isize, row_offset: isize) -> BlockOffset {
     let x = self.x as isize + col_offset;
     let y = self.y as isize + row_offset;
@@ -1202,17 +1760,22 @@ pub struct Block {
   pub mode: PredictionMode,
   pub partition: PartitionType,
   pub skip: bool,
+  pub skip_mode: bool,
   pub ref_frames: [usize; 2],
   pub mv: [MotionVector; 2],
+  /// Interpolation filters in the vertical, horizontal order of the syntax
+  pub interp_filter: [FilterMode; 2],
+  pub compound: CompoundParams,
+  pub palette: PaletteParams,
   pub neighbors_ref_counts: [usize; TOTAL_REFS_PER_FRAME],
   pub cdef_index: u8,
   pub n4_w: usize, /* block width in the unit of mode_info */
   pub n4_h: usize, /* block height in the unit of mode_info */
   pub tx_w: usize, /* transform width in the unit of mode_info */
   pub tx_h: usize, /* transform height in the unit of mode_info */
-  // The block-level deblock_deltas are left-shifted by
-  // fi.deblock.block_delta_shift and added to the frame-configured
-  // deltas
+  // The block-level deblock_deltas (DeltaLF in the spec, already scaled
+  // by fi.deblock.block_delta_shift) are added to the frame-configured
+  // levels
   pub deblock_deltas: [i8; FRAME_LF_COUNT],
   pub segmentation_idx: u8
 }
@@ -1223,8 +1786,12 @@ impl Block {
       mode: PredictionMode::DC_PRED,
       partition: PartitionType::PARTITION_NONE,
       skip: false,
+      skip_mode: false,
       ref_frames: [INTRA_FRAME; 2],
       mv: [ MotionVector { row:0, col: 0 }; 2],
+      interp_filter: [FilterMode::REGULAR; 2],
+      compound: CompoundParams::new(),
+      palette: PaletteParams::new(),
       neighbors_ref_counts: [0; TOTAL_REFS_PER_FRAME],
       cdef_index: 0,
       n4_w: BLOCK_64X64.width_mi(),
@@ -1252,8 +1819,12 @@ pub struct TXB_CTX {
 pub struct BlockContext {
   pub cols: usize,
   pub rows: usize,
-  pub cdef_coded: bool,
+  /// Position of the cdef_idx coded for each 64x64 filter block of the
+  /// current superblock, in raster order
+  pub cdef_coded: [Option<WriterCheckpoint>; 4],
   pub code_deltas: bool,
+  /// Position of the delta_lf coded in the current superblock
+  pub deltas_coded: Option<WriterCheckpoint>,
   pub update_seg: bool,
   pub preskip_segid: bool,
   above_partition_context: Vec<u8>,
@@ -1274,8 +1845,9 @@ impl BlockContext {
     BlockContext {
       cols,
       rows,
-      cdef_coded: false,
+      cdef_coded: Default::default(),
       code_deltas: false,
+      deltas_coded: None,
       update_seg: false,
       preskip_segid: true,
       above_partition_context: vec![0; aligned_cols],
@@ -1294,8 +1866,9 @@ impl BlockContext {
     BlockContext {
       cols: self.cols,
       rows: self.rows,
-      cdef_coded: self.cdef_coded,
+      cdef_coded: self.cdef_coded.clone(),
       code_deltas: self.code_deltas,
+      deltas_coded: self.deltas_coded.clone(),
       update_seg: self.update_seg,
       preskip_segid: self.preskip_segid,
       above_partition_context: self.above_partition_context.clone(),
@@ -1309,7 +1882,9 @@ impl BlockContext {
   pub fn rollback(&mut self, checkpoint: &BlockContext) {
     self.cols = checkpoint.cols;
     self.rows = checkpoint.rows;
-    self.cdef_coded = checkpoint.cdef_coded;
+    self.cdef_coded = checkpoint.cdef_coded.clone();
+    self.code_deltas = checkpoint.code_deltas;
+    self.deltas_coded = checkpoint.deltas_coded.clone();
     self.above_partition_context = checkpoint.above_partition_context.clone();
     self.left_partition_context = checkpoint.left_partition_context;
     self.above_coeff_context = checkpoint.above_coeff_context.clone();
@@ -1348,6 +1923,158 @@ impl BlockContext {
     }
   }
 
+  /// Whether any inter block borders the top or left edge of a block, so
+  /// that it may use a causal motion mode (spec 5.11.27).
+  pub fn has_overlappable_candidates(
+    &self, bo: &BlockOffset, bsize: BlockSize
+  ) -> bool {
+    let is_inter = |b: &Block| b.ref_frames[0] > INTRA_FRAME;
+    if bo.y > 0 {
+      let end = self.cols.min(bo.x + bsize.width_mi());
+      for x in (bo.x..end).step_by(2) {
+        if is_inter(&self.blocks[bo.y - 1][(x | 1).min(self.cols - 1)]) {
+          return true;
+        }
+      }
+    }
+    if bo.x > 0 {
+      let end = self.rows.min(bo.y + bsize.height_mi());
+      for y in (bo.y..end).step_by(2) {
+        if is_inter(&self.blocks[(y | 1).min(self.rows - 1)][bo.x - 1]) {
+          return true;
+        }
+      }
+    }
+    false
+  }
+
+  /// The inter neighbours in the row above (or the column to the left) that
+  /// an `OBMC_CAUSAL` block is blended with (spec 7.11.3.10), each with its
+  /// offset and overlap along the block edge in mode info units.
+  pub fn overlappable_candidates(
+    &self, bo: &BlockOffset, bsize: BlockSize, above: bool
+  ) -> Vec<(usize, usize, &Block)> {
+    let mut candidates = Vec::new();
+    let (n4, end) = if above {
+      if bo.y == 0 {
+        return candidates;
+      }
+      (bsize.width_mi(), self.cols.min(bo.x + bsize.width_mi()) - bo.x)
+    } else {
+      if bo.x == 0 {
+        return candidates;
+      }
+      (bsize.height_mi(), self.rows.min(bo.y + bsize.height_mi()) - bo.y)
+    };
+    let limit = n4.trailing_zeros().min(4) as usize;
+    let mut i = 0;
+    while candidates.len() < limit && i < end {
+      let cand = if above {
+        &self.blocks[bo.y - 1][(bo.x + i) | 1]
+      } else {
+        &self.blocks[(bo.y + i) | 1][bo.x - 1]
+      };
+      let step = (if above { cand.n4_w } else { cand.n4_h }).max(2).min(16);
+      if cand.ref_frames[0] > INTRA_FRAME {
+        candidates.push((i, step.min(n4), cand));
+      }
+      i += step;
+    }
+    candidates
+  }
+
+  /// Collects the neighbouring blocks predicted from `ref_frame` alone, to
+  /// which the local warp model of a block moved by `mv` is fitted (spec
+  /// 7.10.4). No samples are returned only if there is no such neighbour.
+  pub fn find_warp_samples(
+    &self, bo: &BlockOffset, bsize: BlockSize, sb_size: BlockSize,
+    ref_frame: usize, mv: MotionVector
+  ) -> Vec<WarpSample> {
+    let w4 = bsize.width_mi();
+    let h4 = bsize.height_mi();
+    let mut do_top_left = bo.x > 0 && bo.y > 0;
+    let mut do_top_right = bo.y > 0
+      && w4.max(h4) <= BLOCK_64X64.width_mi()
+      && bo.x + w4 < self.cols
+      && has_tr(bo, bsize, sb_size, self.at(bo).partition);
+
+    let mut candidates = Vec::new();
+    if bo.y > 0 {
+      let src_w = self.blocks[bo.y - 1][bo.x].n4_w;
+      if w4 <= src_w {
+        let col_offset = bo.x & (src_w - 1);
+        if col_offset > 0 {
+          do_top_left = false;
+        }
+        if src_w - col_offset > w4 {
+          do_top_right = false;
+        }
+        candidates.push((bo.y - 1, bo.x));
+      } else {
+        let mut x = bo.x;
+        while x < self.cols.min(bo.x + w4) {
+          candidates.push((bo.y - 1, x));
+          x += self.blocks[bo.y - 1][x].n4_w;
+        }
+      }
+    }
+    if bo.x > 0 {
+      let src_h = self.blocks[bo.y][bo.x - 1].n4_h;
+      if h4 <= src_h {
+        if bo.y & (src_h - 1) > 0 {
+          do_top_left = false;
+        }
+        candidates.push((bo.y, bo.x - 1));
+      } else {
+        let mut y = bo.y;
+        while y < self.rows.min(bo.y + h4) {
+          candidates.push((y, bo.x - 1));
+          y += self.blocks[y][bo.x - 1].n4_h;
+        }
+      }
+    }
+    if do_top_left {
+      candidates.push((bo.y - 1, bo.x - 1));
+    }
+    if do_top_right {
+      candidates.push((bo.y - 1, bo.x + w4));
+    }
+
+    let samples: Vec<(WarpSample, MotionVector)> = candidates
+      .iter()
+      .map(|&(y, x)| (y, x, &self.blocks[y][x]))
+      // Inter-intra neighbours are not single reference blocks either, their
+      // RefFrame[1] being INTRA_FRAME
+      .filter(|&(_, _, b)| {
+        b.ref_frames == [ref_frame, NONE_FRAME] && !b.compound.interintra
+      })
+      .take(LEAST_SQUARES_SAMPLES_MAX)
+      .map(|(y, x, b)| {
+        let mid_y = ((y & !(b.n4_h - 1)) * MI_SIZE + b.n4_h * MI_SIZE / 2) as i32 - 1;
+        let mid_x = ((x & !(b.n4_w - 1)) * MI_SIZE + b.n4_w * MI_SIZE / 2) as i32 - 1;
+        let mv = b.mv[0];
+        ([mid_y * 8, mid_x * 8, mid_y * 8 + mv.row as i32, mid_x * 8 + mv.col as i32], mv)
+      })
+      .collect();
+
+    // Neighbours moving too differently from the block are left out,
+    // unless none remain
+    let thresh = clamp(bsize.width().max(bsize.height()), 16, 112) as i32;
+    let mut valid: Vec<WarpSample> = samples
+      .iter()
+      .filter(|&&(_, cand_mv)| {
+        (cand_mv.row as i32 - mv.row as i32).abs()
+          + (cand_mv.col as i32 - mv.col as i32).abs()
+          <= thresh
+      })
+      .map(|&(sample, _)| sample)
+      .collect();
+    if valid.is_empty() && !samples.is_empty() {
+      valid.push(samples[0].0);
+    }
+    valid
+  }
+
   pub fn for_each<F>(&mut self, bo: &BlockOffset, bsize: BlockSize, f: F)
   where
     F: Fn(&mut Block) -> ()
@@ -1399,11 +2126,8 @@ impl BlockContext {
     &mut self, bo: &BlockOffset, bsize: BlockSize, xdec: usize, ydec: usize
   ) {
     const num_planes: usize = 3;
-    let nplanes = if bsize >= BLOCK_8X8 {
-      3
-    } else {
-      1 + (num_planes - 1) * has_chroma(bo, bsize, xdec, ydec) as usize
-    };
+    let nplanes =
+      1 + (num_planes - 1) * has_chroma(bo, bsize, xdec, ydec) as usize;
 
     for plane in 0..nplanes {
       let xdec2 = if plane == 0 {
@@ -1457,10 +2181,94 @@ impl BlockContext {
     self.for_each(bo, bsize, |block| { block.n4_w = n4_w; block.n4_h = n4_h } );
   }
 
-  pub fn set_tx_size(&mut self, bo: &BlockOffset, txsize: TxSize) {
+  /// Records the partition a `bsize` block at `bo` is coded in, which must
+  /// be known before predicting it
+  pub fn set_partition(&mut self, bo: &BlockOffset, bsize: BlockSize, partition: PartitionType) {
+    self.for_each(bo, bsize, |block| block.partition = partition);
+  }
+
+  /// Updates the partition context after coding a `bsize` block at `bo`
+  /// with an extended partition, from the sizes of its blocks
+  pub fn update_ext_partition_context(
+    &mut self, bo: &BlockOffset, bsize: BlockSize, partition: PartitionType
+  ) {
+    for (sub_bo, subsize) in partition.sub_blocks(bo, bsize) {
+      self.update_partition_context(&sub_bo, subsize, subsize);
+    }
+  }
+
+  /// Records `txsize` as the transform size over `bsize` from `bo`
+  pub fn set_tx_size(&mut self, bo: &BlockOffset, bsize: BlockSize, txsize: TxSize) {
     let tx_w = txsize.width_mi();
     let tx_h = txsize.height_mi();
-    self.for_each(bo, txsize.block_size(), |block| { block.tx_w = tx_w; block.tx_h = tx_h } );
+    self.for_each(bo, bsize, |block| { block.tx_w = tx_w; block.tx_h = tx_h } );
+  }
+
+  /// Width of the transform above `bo` in the block at `block_bo`, in
+  /// pixels (spec get_above_tx_width)
+  fn above_tx_width(&self, block_bo: &BlockOffset, bo: &BlockOffset) -> usize {
+    if bo.y == block_bo.y {
+      if bo.y == 0 {
+        return 64;
+      }
+      let above = self.above_of(bo);
+      if above.skip && above.is_inter() {
+        return above.n4_w << MI_SIZE_LOG2;
+      }
+    }
+    self.above_of(bo).tx_w << MI_SIZE_LOG2
+  }
+
+  /// Height of the transform left of `bo` in the block at `block_bo`, in
+  /// pixels (spec get_left_tx_height)
+  fn left_tx_height(&self, block_bo: &BlockOffset, bo: &BlockOffset) -> usize {
+    if bo.x == block_bo.x {
+      if bo.x == 0 {
+        return 64;
+      }
+      let left = self.left_of(bo);
+      if left.skip && left.is_inter() {
+        return left.n4_h << MI_SIZE_LOG2;
+      }
+    }
+    self.left_of(bo).tx_h << MI_SIZE_LOG2
+  }
+
+  /// Context of the tx_depth of a block (spec 8.3.2 for tx_depth)
+  fn tx_size_context(&self, bo: &BlockOffset, bsize: BlockSize) -> usize {
+    let max_tx_size = bsize.tx_size();
+    let above = if bo.y == 0 {
+      0
+    } else if self.above_of(bo).is_inter() {
+      self.above_of(bo).n4_w << MI_SIZE_LOG2
+    } else {
+      self.above_tx_width(bo, bo)
+    };
+    let left = if bo.x == 0 {
+      0
+    } else if self.left_of(bo).is_inter() {
+      self.left_of(bo).n4_h << MI_SIZE_LOG2
+    } else {
+      self.left_tx_height(bo, bo)
+    };
+    (above >= max_tx_size.width()) as usize
+      + (left >= max_tx_size.height()) as usize
+  }
+
+  /// Context of the txfm_split of the `tx_size` transform at `bo` in the
+  /// block at `block_bo` (spec 8.3.2 for txfm_split)
+  fn txfm_split_context(
+    &self, block_bo: &BlockOffset, bsize: BlockSize, bo: &BlockOffset,
+    tx_size: TxSize
+  ) -> usize {
+    let above = (self.above_tx_width(block_bo, bo) < tx_size.width()) as usize;
+    let left = (self.left_tx_height(block_bo, bo) < tx_size.height()) as usize;
+    let size = bsize.width().max(bsize.height()).min(64);
+    let max_tx_size = TxSize::by_dims(size, size);
+    (tx_size.sqr_up() != max_tx_size) as usize * 3
+      + (TxSize::TX_SIZES - 1 - max_tx_size as usize) * 6
+      + above
+      + left
   }
 
   pub fn get_mode(&mut self, bo: &BlockOffset) -> PredictionMode {
@@ -1524,6 +2332,52 @@ impl BlockContext {
     self.for_each(bo, bsize, |block| block.skip = skip);
   }
 
+  fn skip_mode_context(&mut self, bo: &BlockOffset) -> usize {
+    let above_skip_mode = if bo.y > 0 {
+      self.above_of(bo).skip_mode as usize
+    } else {
+      0
+    };
+    let left_skip_mode = if bo.x > 0 {
+      self.left_of(bo).skip_mode as usize
+    } else {
+      0
+    };
+    above_skip_mode + left_skip_mode
+  }
+
+  pub fn set_skip_mode(&mut self, bo: &BlockOffset, bsize: BlockSize, skip_mode: bool) {
+    self.for_each(bo, bsize, |block| block.skip_mode = skip_mode);
+  }
+
+  /// Motion field motion vector storage process (spec 7.19): keeps the
+  /// motion vector of the bottom-right 4x4 block of each 8x8 block, if it
+  /// points to a reference in the past and is not too long.
+  pub fn save_motion_vectors(&self, fi: &FrameInvariants) -> FrameMotionVectors {
+    let cols = self.cols >> 1;
+    let rows = self.rows >> 1;
+    let is_past = |r: usize| {
+      fi.sequence.get_relative_dist(fi.ref_order_hint(r), fi.order_hint) < 0
+    };
+    let mut mvs = Vec::with_capacity(cols * rows);
+    for y8 in 0..rows {
+      for x8 in 0..cols {
+        let b = &self.blocks[2 * y8 + 1][2 * x8 + 1];
+        let mut saved = None;
+        for list in 0..2 {
+          let r = b.ref_frames[list];
+          let mv = b.mv[list];
+          if r > INTRA_FRAME && r != NONE_FRAME && is_past(r)
+            && mv.row.abs() <= REFMVS_LIMIT && mv.col.abs() <= REFMVS_LIMIT {
+            saved = Some(SavedMotionVector { ref_frame: r, mv });
+          }
+        }
+        mvs.push(saved);
+      }
+    }
+    FrameMotionVectors { cols, rows, mvs }
+  }
+
   pub fn set_segmentation_idx(&mut self, bo: &BlockOffset, bsize: BlockSize, idx: u8) {
     self.for_each(bo, bsize, |block| block.segmentation_idx = idx);
   }
@@ -1539,6 +2393,36 @@ impl BlockContext {
     }
   }
 
+  pub fn set_interp_filter(&mut self, bo: &BlockOffset, bsize: BlockSize, interp_filter: [FilterMode; 2]) {
+    self.for_each(bo, bsize, |block| { block.interp_filter = interp_filter });
+  }
+
+  pub fn set_compound(&mut self, bo: &BlockOffset, bsize: BlockSize, compound: CompoundParams) {
+    self.for_each(bo, bsize, |block| { block.compound = compound });
+  }
+
+  pub fn set_palette(&mut self, bo: &BlockOffset, bsize: BlockSize, palette: PaletteParams) {
+    self.for_each(bo, bsize, |block| { block.palette = palette });
+  }
+
+  /// Context of has_palette_y: the number of neighbours with a luma palette
+  pub fn palette_y_mode_context(&self, bo: &BlockOffset) -> usize {
+    let above = bo.y > 0 && self.above_of(bo).palette.size[0] > 0;
+    let left = bo.x > 0 && self.left_of(bo).palette.size[0] > 0;
+    above as usize + left as usize
+  }
+
+  /// Colours of the above and left palettes of plane type `plane` (0 for
+  /// luma, 1 for U), which can be reused without coding them. The above
+  /// palette is not used across 64x64 block rows.
+  pub fn palette_cache(&self, bo: &BlockOffset, plane: usize) -> Vec<u16> {
+    let (above, left) = (self.above_of(bo), self.left_of(bo));
+    merge_palette_cache(
+      if bo.y & 15 != 0 { above.palette.plane_colors(plane) } else { &[] },
+      if bo.x > 0 { left.palette.plane_colors(plane) } else { &[] }
+    )
+  }
+
   pub fn set_motion_vectors(&mut self, bo: &BlockOffset, bsize: BlockSize, mvs: [MotionVector; 2]) {
     let bw = bsize.width_mi();
     let bh = bsize.height_mi();
@@ -1550,11 +2434,25 @@ impl BlockContext {
     }
   }
 
-  pub fn set_cdef(&mut self, sbo: &SuperBlockOffset, cdef_index: u8) {
+  pub fn set_deblock_deltas(
+    &mut self, sbo: &SuperBlockOffset, deltas: [i8; FRAME_LF_COUNT]
+  ) {
     let bo = sbo.block_offset(0, 0);
-    // Checkme: Is 16 still the right block unit for 128x128 superblocks?
-    let bw = cmp::min (bo.x + MAX_MIB_SIZE, self.blocks[bo.y as usize].len());
-    let bh = cmp::min (bo.y + MAX_MIB_SIZE, self.blocks.len());
+    let sb_mi_size = 1 << (sbo.size_log2 - MI_SIZE_LOG2);
+    let bw = cmp::min(bo.x + sb_mi_size, self.cols);
+    let bh = cmp::min(bo.y + sb_mi_size, self.rows);
+    for y in bo.y..bh {
+      for x in bo.x..bw {
+        self.blocks[y][x].deblock_deltas = deltas;
+      }
+    }
+  }
+
+  pub fn set_cdef(&mut self, fbo: &FilterBlockOffset, cdef_index: u8) {
+    let bo = fbo.block_offset(0, 0);
+    let fb_mi_size = 1 << FILTER_BLOCK_TO_BLOCK_SHIFT;
+    let bw = cmp::min (bo.x + fb_mi_size, self.blocks[bo.y as usize].len());
+    let bh = cmp::min (bo.y + fb_mi_size, self.blocks.len());
     for y in bo.y..bh {
       for x in bo.x..bw {
         self.blocks[y as usize][x as usize].cdef_index = cdef_index;
@@ -1838,7 +2736,7 @@ impl ContextWriter {
   }
 
   fn partition_gather_horz_alike(
-    out: &mut [u16; 2], cdf_in: &[u16], _bsize: BlockSize
+    out: &mut [u16; 2], cdf_in: &[u16], bsize: BlockSize
   ) {
     out[0] = 32768;
     out[0] -= ContextWriter::cdf_element_prob(
@@ -1861,16 +2759,18 @@ impl ContextWriter {
       cdf_in,
       PartitionType::PARTITION_VERT_A as usize
     );
-    out[0] -= ContextWriter::cdf_element_prob(
-      cdf_in,
-      PartitionType::PARTITION_HORZ_4 as usize
-    );
+    if bsize != BlockSize::BLOCK_128X128 {
+      out[0] -= ContextWriter::cdf_element_prob(
+        cdf_in,
+        PartitionType::PARTITION_HORZ_4 as usize
+      );
+    }
     out[0] = 32768 - out[0];
     out[1] = 0;
   }
 
   fn partition_gather_vert_alike(
-    out: &mut [u16; 2], cdf_in: &[u16], _bsize: BlockSize
+    out: &mut [u16; 2], cdf_in: &[u16], bsize: BlockSize
   ) {
     out[0] = 32768;
     out[0] -= ContextWriter::cdf_element_prob(
@@ -1893,10 +2793,12 @@ impl ContextWriter {
       cdf_in,
       PartitionType::PARTITION_VERT_B as usize
     );
-    out[0] -= ContextWriter::cdf_element_prob(
-      cdf_in,
-      PartitionType::PARTITION_VERT_4 as usize
-    );
+    if bsize != BlockSize::BLOCK_128X128 {
+      out[0] -= ContextWriter::cdf_element_prob(
+        cdf_in,
+        PartitionType::PARTITION_VERT_4 as usize
+      );
+    }
     out[0] = 32768 - out[0];
     out[1] = 0;
   }
@@ -1912,6 +2814,9 @@ impl ContextWriter {
     assert!(ctx < PARTITION_CONTEXTS);
     let partition_cdf = if bsize <= BlockSize::BLOCK_8X8 {
       &mut self.fc.partition_cdf[ctx][..PARTITION_TYPES+1]
+    } else if bsize == BlockSize::BLOCK_128X128 {
+      // 4-way partitions are not allowed for 128x128 blocks
+      &mut self.fc.partition_cdf[ctx][..EXT_PARTITION_TYPES-1]
     } else {
       &mut self.fc.partition_cdf[ctx]
     };
@@ -1947,10 +2852,10 @@ impl ContextWriter {
   pub fn get_cdf_intra_mode_kf(&self, bo: &BlockOffset) -> &[u16; INTRA_MODES + 1] {
     static intra_mode_context: [usize; INTRA_MODES] =
       [0, 1, 2, 3, 4, 4, 4, 4, 3, 0, 1, 2, 0];
-    let above_mode = self.bc.above_of(bo).mode as usize;
-    let left_mode = self.bc.left_of(bo).mode as usize;
-    let above_ctx = intra_mode_context[above_mode];
-    let left_ctx = intra_mode_context[left_mode];
+    // IntraBC blocks count as DC_PRED
+    let mode_ctx = |b: &Block| if b.is_inter() { 0 } else { intra_mode_context[b.mode as usize] };
+    let above_ctx = mode_ctx(&self.bc.above_of(bo));
+    let left_ctx = mode_ctx(&self.bc.left_of(bo));
     &self.fc.kf_y_cdf[above_ctx][left_ctx]
   }
   pub fn write_intra_mode_kf(
@@ -1958,10 +2863,10 @@ impl ContextWriter {
   ) {
     static intra_mode_context: [usize; INTRA_MODES] =
       [0, 1, 2, 3, 4, 4, 4, 4, 3, 0, 1, 2, 0];
-    let above_mode = self.bc.above_of(bo).mode as usize;
-    let left_mode = self.bc.left_of(bo).mode as usize;
-    let above_ctx = intra_mode_context[above_mode];
-    let left_ctx = intra_mode_context[left_mode];
+    // IntraBC blocks count as DC_PRED
+    let mode_ctx = |b: &Block| if b.is_inter() { 0 } else { intra_mode_context[b.mode as usize] };
+    let above_ctx = mode_ctx(&self.bc.above_of(bo));
+    let left_ctx = mode_ctx(&self.bc.left_of(bo));
     let cdf = &mut self.fc.kf_y_cdf[above_ctx][left_ctx];
     symbol_with_update!(self, w, mode as u32, cdf);
   }
@@ -2005,16 +2910,79 @@ impl ContextWriter {
     symbol_with_update!(self, w, enable as u32, &mut self.fc.filter_intra_cdfs[block_size as usize]);
   }
 
-  fn get_mvref_ref_frames(&mut self, ref_frame: usize) -> ([usize; 2], usize) {
-    let ref_frame_map: [[usize; 2]; TOTAL_COMP_REFS] = [
-      [ LAST_FRAME,  BWDREF_FRAME  ], [ LAST2_FRAME,  BWDREF_FRAME  ],
-      [ LAST3_FRAME, BWDREF_FRAME  ], [ GOLDEN_FRAME, BWDREF_FRAME  ],
-      [ LAST_FRAME,  ALTREF2_FRAME ], [ LAST2_FRAME,  ALTREF2_FRAME ],
-      [ LAST3_FRAME, ALTREF2_FRAME ], [ GOLDEN_FRAME, ALTREF2_FRAME ],
-      [ LAST_FRAME,  ALTREF_FRAME  ], [ LAST2_FRAME,  ALTREF_FRAME  ],
-      [ LAST3_FRAME, ALTREF_FRAME  ], [ GOLDEN_FRAME, ALTREF_FRAME  ],
-      [ LAST_FRAME,  LAST2_FRAME   ], [ LAST_FRAME,   LAST3_FRAME   ],
-      [ LAST_FRAME,  GOLDEN_FRAME  ], [ BWDREF_FRAME, ALTREF_FRAME  ],
+  /// Whether palettes can be used in the block (spec 5.11.7)
+  pub fn palette_allowed(&self, fi: &FrameInvariants, bsize: BlockSize) -> bool {
+    fi.allow_screen_content_tools != 0 && bsize >= BLOCK_8X8
+      && bsize.width() <= 64 && bsize.height() <= 64
+  }
+
+  pub fn write_palette_mode_info(
+    &mut self, w: &mut dyn Writer, bo: &BlockOffset, bsize: BlockSize, luma_mode: PredictionMode,
+    chroma_mode: PredictionMode, has_chroma: bool, palette: &PaletteParams, bit_depth: usize
+  ) {
+    let bsize_ctx = bsize.width_log2() + bsize.height_log2() - 6;
+    if luma_mode == PredictionMode::DC_PRED {
+      let ctx = self.bc.palette_y_mode_context(bo);
+      symbol_with_update!(self, w, palette.has_palette(0) as u32,
+                          &mut self.fc.palette_y_mode_cdfs[bsize_ctx][ctx]);
+      if palette.has_palette(0) {
+        symbol_with_update!(self, w, (palette.size[0] - PALETTE_MIN_SIZE) as u32,
+                            &mut self.fc.palette_y_size_cdfs[bsize_ctx]);
+        let cache = self.bc.palette_cache(bo, 0);
+        write_palette_colors(w, &cache, palette.plane_colors(0), 1, bit_depth);
+      }
+    } else {
+      assert!(!palette.has_palette(0));
+    }
+
+    if has_chroma && chroma_mode == PredictionMode::DC_PRED {
+      let ctx = palette.has_palette(0) as usize;
+      symbol_with_update!(self, w, palette.has_palette(1) as u32,
+                          &mut self.fc.palette_uv_mode_cdfs[ctx]);
+      if palette.has_palette(1) {
+        symbol_with_update!(self, w, (palette.size[1] - PALETTE_MIN_SIZE) as u32,
+                            &mut self.fc.palette_uv_size_cdfs[bsize_ctx]);
+        let cache = self.bc.palette_cache(bo, 1);
+        write_palette_colors(w, &cache, palette.plane_colors(1), 0, bit_depth);
+        write_palette_colors_v(w, palette.plane_colors(2), bit_depth);
+      }
+    } else {
+      assert!(!palette.has_palette(1));
+    }
+  }
+
+  /// Colour index map of a palette plane (spec 5.11.49), coded along
+  /// anti-diagonals so each index has its left and above neighbours
+  pub fn write_palette_color_map(
+    &mut self, w: &mut dyn Writer, plane_type: usize, map: &ColorMap, n: usize
+  ) {
+    let (width, height) = (map.onscreen_width, map.onscreen_height);
+    write_uniform(w, n, map.index(0, 0));
+    for i in 1..(width + height - 1) {
+      for j in ((i + 1).saturating_sub(height)..=cmp::min(i, width - 1)).rev() {
+        let (ctx, order) = map.color_context(i - j, j, n);
+        let index = map.index(i - j, j);
+        let s = order.iter().position(|&o| o as usize == index).unwrap() as u32;
+        let cdfs = if plane_type == 0 {
+          &mut self.fc.palette_y_color_index_cdfs
+        } else {
+          &mut self.fc.palette_uv_color_index_cdfs
+        };
+        symbol_with_update!(self, w, s, &mut cdfs[n - PALETTE_MIN_SIZE][ctx][..n + 1]);
+      }
+    }
+  }
+
+  fn get_mvref_ref_frames(&mut self, ref_frame: usize) -> ([usize; 2], usize) {
+    let ref_frame_map: [[usize; 2]; TOTAL_COMP_REFS] = [
+      [ LAST_FRAME,  BWDREF_FRAME  ], [ LAST2_FRAME,  BWDREF_FRAME  ],
+      [ LAST3_FRAME, BWDREF_FRAME  ], [ GOLDEN_FRAME, BWDREF_FRAME  ],
+      [ LAST_FRAME,  ALTREF2_FRAME ], [ LAST2_FRAME,  ALTREF2_FRAME ],
+      [ LAST3_FRAME, ALTREF2_FRAME ], [ GOLDEN_FRAME, ALTREF2_FRAME ],
+      [ LAST_FRAME,  ALTREF_FRAME  ], [ LAST2_FRAME,  ALTREF_FRAME  ],
+      [ LAST3_FRAME, ALTREF_FRAME  ], [ GOLDEN_FRAME, ALTREF_FRAME  ],
+      [ LAST_FRAME,  LAST2_FRAME   ], [ LAST_FRAME,   LAST3_FRAME   ],
+      [ LAST_FRAME,  GOLDEN_FRAME  ], [ BWDREF_FRAME, ALTREF_FRAME  ],
 
       // NOTE: Following reference frame pairs are not supported to be explicitly
       //       signalled, but they are possibly chosen by the use of skip_mode,
@@ -2075,17 +3043,28 @@ impl ContextWriter {
   }
 
   fn add_ref_mv_candidate(&self, ref_frames: [usize; 2], blk: &Block, mv_stack: &mut Vec<CandidateMV>,
-                          weight: u32, newmv_count: &mut usize, is_compound: bool) -> bool {
+                          weight: u32, newmv_count: &mut usize, is_compound: bool,
+                          gm_mv_candidates: &[Option<MotionVector>; 2]) -> bool {
+    // Warped global motion blocks contribute the global motion vector at the
+    // current block position instead of their own.
+    let is_global = (blk.mode == PredictionMode::GLOBALMV || blk.mode == PredictionMode::GLOBAL_GLOBALMV)
+      && blk.n4_w.min(blk.n4_h) >= BLOCK_8X8.width_mi();
+    let cand_mv = |list: usize, i: usize| match gm_mv_candidates[list] {
+      Some(mv) if is_global => mv,
+      _ => blk.mv[i]
+    };
+
     if !blk.is_inter() { /* For intrabc */
       false
     } else if is_compound {
       if blk.ref_frames[0] == ref_frames[0] && blk.ref_frames[1] == ref_frames[1] {
-        let found_match = self.find_matching_comp_mv_and_update_weight(blk.mv, mv_stack, weight);
+        let mvs = [cand_mv(0, 0), cand_mv(1, 1)];
+        let found_match = self.find_matching_comp_mv_and_update_weight(mvs, mv_stack, weight);
 
         if !found_match && mv_stack.len() < MAX_REF_MV_STACK_SIZE {
           let mv_cand = CandidateMV {
-            this_mv: blk.mv[0],
-            comp_mv: blk.mv[1],
+            this_mv: mvs[0],
+            comp_mv: mvs[1],
             weight: weight
           };
 
@@ -2108,11 +3087,12 @@ impl ContextWriter {
       let mut found = false;
       for i in 0..2 {
         if blk.ref_frames[i] == ref_frames[0] {
-          let found_match = self.find_matching_mv_and_update_weight(blk.mv[i], mv_stack, weight);
+          let mv = cand_mv(0, i);
+          let found_match = self.find_matching_mv_and_update_weight(mv, mv_stack, weight);
 
           if !found_match && mv_stack.len() < MAX_REF_MV_STACK_SIZE {
             let mv_cand = CandidateMV {
-              this_mv: blk.mv[i],
+              this_mv: mv,
               comp_mv: MotionVector { row: 0, col: 0 },
               weight: weight
             };
@@ -2196,7 +3176,7 @@ impl ContextWriter {
   fn scan_row_mbmi(&mut self, bo: &BlockOffset, row_offset: isize, max_row_offs: isize,
                    processed_rows: &mut isize, ref_frames: [usize; 2],
                    mv_stack: &mut Vec<CandidateMV>, newmv_count: &mut usize, bsize: BlockSize,
-                   is_compound: bool) -> bool {
+                   is_compound: bool, gm_mv_candidates: &[Option<MotionVector>; 2]) -> bool {
     let bc = &self.bc;
     let target_n4_w = bsize.width_mi();
 
@@ -2237,7 +3217,8 @@ impl ContextWriter {
         *processed_rows = (inc as isize) - row_offset - 1;
       }
 
-      if self.add_ref_mv_candidate(ref_frames, cand, mv_stack, len as u32 * weight, newmv_count, is_compound) {
+      if self.add_ref_mv_candidate(ref_frames, cand, mv_stack, len as u32 * weight, newmv_count, is_compound,
+                                   gm_mv_candidates) {
         found_match = true;
       }
 
@@ -2250,7 +3231,7 @@ impl ContextWriter {
   fn scan_col_mbmi(&mut self, bo: &BlockOffset, col_offset: isize, max_col_offs: isize,
                    processed_cols: &mut isize, ref_frames: [usize; 2],
                    mv_stack: &mut Vec<CandidateMV>, newmv_count: &mut usize, bsize: BlockSize,
-                   is_compound: bool) -> bool {
+                   is_compound: bool, gm_mv_candidates: &[Option<MotionVector>; 2]) -> bool {
     let bc = &self.bc;
 
     let target_n4_h = bsize.height_mi();
@@ -2291,7 +3272,8 @@ impl ContextWriter {
         *processed_cols = (inc as isize) - col_offset - 1;
       }
 
-      if self.add_ref_mv_candidate(ref_frames, cand, mv_stack, len as u32 * weight, newmv_count, is_compound) {
+      if self.add_ref_mv_candidate(ref_frames, cand, mv_stack, len as u32 * weight, newmv_count, is_compound,
+                                   gm_mv_candidates) {
         found_match = true;
       }
 
@@ -2303,14 +3285,15 @@ impl ContextWriter {
 
   fn scan_blk_mbmi(&mut self, bo: &BlockOffset, ref_frames: [usize; 2],
                    mv_stack: &mut Vec<CandidateMV>, newmv_count: &mut usize,
-                   is_compound: bool) -> bool {
+                   is_compound: bool, gm_mv_candidates: &[Option<MotionVector>; 2]) -> bool {
     if bo.x >= self.bc.cols || bo.y >= self.bc.rows {
       return false;
     }
 
     let weight = 2 * BLOCK_8X8.width_mi() as u32;
     /* Always assume its within a tile, probably wrong */
-    self.add_ref_mv_candidate(ref_frames, self.bc.at(bo), mv_stack, weight, newmv_count, is_compound)
+    self.add_ref_mv_candidate(ref_frames, self.bc.at(bo), mv_stack, weight, newmv_count, is_compound,
+                              gm_mv_candidates)
   }
 
   fn add_offset(&mut self, mv_stack: &mut Vec<CandidateMV>) {
@@ -2319,10 +3302,106 @@ impl ContextWriter {
     }
   }
 
+  /// Temporal sample process (spec 7.10.2.6): adds the motion vector
+  /// projected onto the 8x8 block at the given offset of the current block.
+  /// Returns whether it is far from the global motion vector, or `None` if
+  /// there is no projection.
+  fn add_tpl_ref_mv(&mut self, bo: &BlockOffset, delta_row: isize, delta_col: isize,
+                    ref_frames: [usize; 2], mv_stack: &mut Vec<CandidateMV>,
+                    fi: &FrameInvariants, is_compound: bool,
+                    global_mvs: [MotionVector; 2]) -> Option<bool> {
+    let mv_row = (bo.y as isize + delta_row) | 1;
+    let mv_col = (bo.x as isize + delta_col) | 1;
+    if mv_row < 0 || mv_row >= self.bc.rows as isize || mv_col < 0 || mv_col >= self.bc.cols as isize {
+      return None;
+    }
+    let (y8, x8) = (mv_row as usize >> 1, mv_col as usize >> 1);
+    let pmv = fi.motion_field_mvs[y8 * (fi.w_in_b >> 1) + x8]?;
+    let project = |r: usize| {
+      let cur_offset = fi.sequence.get_relative_dist(fi.order_hint, fi.ref_order_hint(r));
+      let mv = get_mv_projection(pmv.mv, cur_offset, pmv.ref_offset);
+      MotionVector {
+        row: lower_mv_precision(fi, mv.row as i64),
+        col: lower_mv_precision(fi, mv.col as i64)
+      }
+    };
+    let is_far = |mv: MotionVector, global_mv: MotionVector| {
+      (mv.row - global_mv.row).abs() >= 16 || (mv.col - global_mv.col).abs() >= 16
+    };
+
+    let this_mv = project(ref_frames[0]);
+    if is_compound {
+      let comp_mv = project(ref_frames[1]);
+      let mvs = [this_mv, comp_mv];
+      if !self.find_matching_comp_mv_and_update_weight(mvs, mv_stack, 2)
+        && mv_stack.len() < MAX_REF_MV_STACK_SIZE {
+        mv_stack.push(CandidateMV { this_mv, comp_mv, weight: 2 });
+      }
+      Some(is_far(this_mv, global_mvs[0]) || is_far(comp_mv, global_mvs[1]))
+    } else {
+      if !self.find_matching_mv_and_update_weight(this_mv, mv_stack, 2)
+        && mv_stack.len() < MAX_REF_MV_STACK_SIZE {
+        mv_stack.push(CandidateMV {
+          this_mv,
+          comp_mv: MotionVector { row: 0, col: 0 },
+          weight: 2
+        });
+      }
+      Some(is_far(this_mv, global_mvs[0]))
+    }
+  }
+
+  /// Temporal scan process (spec 7.10.2.5). Returns the context of the
+  /// `GLOBALMV` flag, set when the block has no projected motion or it is
+  /// far from the global motion.
+  fn scan_temporal(&mut self, bo: &BlockOffset, ref_frames: [usize; 2],
+                   mv_stack: &mut Vec<CandidateMV>, bsize: BlockSize,
+                   fi: &FrameInvariants, is_compound: bool,
+                   global_mvs: [MotionVector; 2]) -> usize {
+    let bw4 = bsize.width_mi();
+    let bh4 = bsize.height_mi();
+    let step_w4 = if bw4 >= 16 { 4 } else { 2 };
+    let step_h4 = if bh4 >= 16 { 4 } else { 2 };
+    let mut zeromv_ctx = 0;
+    for delta_row in (0..bh4.min(16)).step_by(step_h4) {
+      for delta_col in (0..bw4.min(16)).step_by(step_w4) {
+        let is_far = self.add_tpl_ref_mv(bo, delta_row as isize, delta_col as isize,
+                                         ref_frames, mv_stack, fi, is_compound, global_mvs);
+        if delta_row == 0 && delta_col == 0 {
+          zeromv_ctx = is_far.unwrap_or(true) as usize;
+        }
+      }
+    }
+
+    let allow_extension = bh4 >= BLOCK_8X8.height_mi() && bh4 < BLOCK_64X64.height_mi()
+      && bw4 >= BLOCK_8X8.width_mi() && bw4 < BLOCK_64X64.width_mi();
+    if allow_extension {
+      let (bw4, bh4) = (bw4 as isize, bh4 as isize);
+      for &(delta_row, delta_col) in [(bh4, -2), (bh4, bw4), (bh4 - 2, bw4)].iter() {
+        // The samples must lie in the same 64x64 block
+        let row = (bo.y & 15) as isize + delta_row;
+        let col = (bo.x & 15) as isize + delta_col;
+        if row >= 0 && row < 16 && col >= 0 && col < 16 {
+          self.add_tpl_ref_mv(bo, delta_row, delta_col, ref_frames, mv_stack, fi,
+                              is_compound, global_mvs);
+        }
+      }
+    }
+    zeromv_ctx
+  }
+
   fn setup_mvref_list(&mut self, bo: &BlockOffset, ref_frames: [usize; 2], mv_stack: &mut Vec<CandidateMV>,
-                      bsize: BlockSize, fi: &FrameInvariants, is_compound: bool) -> usize {
+                      bsize: BlockSize, fi: &FrameInvariants, is_compound: bool,
+                      global_mvs: [MotionVector; 2]) -> usize {
     let (_rf, _rf_num) = self.get_mvref_ref_frames(INTRA_FRAME);
 
+    let mut gm_mv_candidates = [None; 2];
+    for list in 0..(1 + is_compound as usize) {
+      if fi.globalmv_transformation_type[ref_frames[list]] > GlobalMVMode::TRANSLATION {
+        gm_mv_candidates[list] = Some(global_mvs[list]);
+      }
+    }
+
     let target_n4_h = bsize.height_mi();
     let target_n4_w = bsize.width_mi();
 
@@ -2367,17 +3446,17 @@ impl ContextWriter {
 
     if max_row_offs.abs() >= 1 {
       let found_match = self.scan_row_mbmi(bo, -1, max_row_offs, &mut processed_rows, ref_frames, mv_stack,
-                                           &mut newmv_count, bsize, is_compound);
+                                           &mut newmv_count, bsize, is_compound, &gm_mv_candidates);
       row_match |= found_match;
     }
     if max_col_offs.abs() >= 1 {
       let found_match = self.scan_col_mbmi(bo, -1, max_col_offs, &mut processed_cols, ref_frames, mv_stack,
-                                           &mut newmv_count, bsize, is_compound);
+                                           &mut newmv_count, bsize, is_compound, &gm_mv_candidates);
       col_match |= found_match;
     }
-    if has_tr(bo, bsize) {
+    if has_tr(bo, bsize, fi.sequence.sb_size(), self.bc.at(bo).partition) {
       let found_match = self.scan_blk_mbmi(&bo.with_offset(target_n4_w as isize, -1), ref_frames, mv_stack,
-                                           &mut newmv_count, is_compound);
+                                           &mut newmv_count, is_compound, &gm_mv_candidates);
       row_match |= found_match;
     }
 
@@ -2385,11 +3464,17 @@ impl ContextWriter {
 
     self.add_offset(mv_stack);
 
+    let zeromv_ctx = if fi.use_ref_frame_mvs {
+      self.scan_temporal(bo, ref_frames, mv_stack, bsize, fi, is_compound, global_mvs)
+    } else {
+      0
+    };
+
     /* Scan the second outer area. */
     let mut far_newmv_count: usize = 0; // won't be used
 
     let found_match = self.scan_blk_mbmi(
-      &bo.with_offset(-1, -1), ref_frames, mv_stack, &mut far_newmv_count, is_compound
+      &bo.with_offset(-1, -1), ref_frames, mv_stack, &mut far_newmv_count, is_compound, &gm_mv_candidates
     );
     row_match |= found_match;
 
@@ -2399,13 +3484,13 @@ impl ContextWriter {
 
       if row_offset.abs() <= max_row_offs.abs() && row_offset.abs() > processed_rows {
         let found_match = self.scan_row_mbmi(bo, row_offset, max_row_offs, &mut processed_rows, ref_frames, mv_stack,
-                                             &mut far_newmv_count, bsize, is_compound);
+                                             &mut far_newmv_count, bsize, is_compound, &gm_mv_candidates);
         row_match |= found_match;
       }
 
       if col_offset.abs() <= max_col_offs.abs() && col_offset.abs() > processed_cols {
         let found_match = self.scan_col_mbmi(bo, col_offset, max_col_offs, &mut processed_cols, ref_frames, mv_stack,
-                                             &mut far_newmv_count, bsize, is_compound);
+                                             &mut far_newmv_count, bsize, is_compound, &gm_mv_candidates);
         col_match |= found_match;
       }
     }
@@ -2420,7 +3505,7 @@ impl ContextWriter {
       0 =>  cmp::min(total_match, 1) + (total_match << REFMV_OFFSET),
       1 =>  3 - cmp::min(newmv_count, 1) + ((2 + total_match) << REFMV_OFFSET),
       _ =>  5 - cmp::min(newmv_count, 1) + (5 << REFMV_OFFSET)
-    };
+    } + (zeromv_ctx << GLOBALMV_OFFSET);
 
     /* TODO: Find nearest match and assign nearest and near mvs */
 
@@ -2465,7 +3550,7 @@ impl ContextWriter {
       }
 
       if is_compound {
-        let mut combined_mvs = [[MotionVector { row: 0, col: 0}; 2]; 2];
+        let mut combined_mvs = [global_mvs; 2];
 
         for list in 0..2 {
           let mut comp_count = 0;
@@ -2539,25 +3624,22 @@ impl ContextWriter {
                      mv_stack: &mut Vec<CandidateMV>, bsize: BlockSize,
                      fi: &FrameInvariants, is_compound: bool) -> usize {
     assert!(ref_frames[0] != NONE_FRAME);
-    if ref_frames[0] < REF_FRAMES {
-      if ref_frames[0] != INTRA_FRAME {
-        /* TODO: convert global mv to an mv here */
-      } else {
-        /* TODO: set the global mv ref to invalid here */
-      }
-    }
-
-    if ref_frames[0] != INTRA_FRAME {
-      /* TODO: Set zeromv ref to the converted global motion vector */
-    } else {
-      /* TODO: Set the zeromv ref to 0 */
-    }
-
-    if ref_frames[0] <= INTRA_FRAME {
+    // IntraBC displacement vectors are predicted like motion vectors of an
+    // INTRA_FRAME reference
+    if ref_frames[0] == INTRA_FRAME && !fi.allow_intrabc {
       return 0;
     }
 
-    self.setup_mvref_list(bo, ref_frames, mv_stack, bsize, fi, is_compound)
+    let global_mvs = [
+      global_mv(fi, ref_frames[0], bsize, bo),
+      if is_compound {
+        global_mv(fi, ref_frames[1], bsize, bo)
+      } else {
+        MotionVector { row: 0, col: 0 }
+      }
+    ];
+
+    self.setup_mvref_list(bo, ref_frames, mv_stack, bsize, fi, is_compound, global_mvs)
   }
 
   pub fn fill_neighbours_ref_counts(&mut self, bo: &BlockOffset) {
@@ -2867,20 +3949,315 @@ impl ContextWriter {
     symbol_with_update!(self, w, drl_mode as u32, &mut self.fc.drl_cdfs[ctx]);
   }
 
+  /// Whether the motion mode of an inter block is coded at all (spec
+  /// 5.11.27), as opposed to inferred to be `SIMPLE_TRANSLATION`.
+  pub fn motion_mode_switchable(
+    &self, fi: &FrameInvariants, bo: &BlockOffset, bsize: BlockSize,
+    mode: PredictionMode, ref_frames: [usize; 2]
+  ) -> bool {
+    let is_compound =
+      ref_frames[1] > INTRA_FRAME && ref_frames[1] != NONE_FRAME;
+    fi.is_motion_mode_switchable
+      && bsize.width_mi().min(bsize.height_mi()) >= 2
+      && !(fi.force_integer_mv == 0
+        && mode == PredictionMode::GLOBALMV
+        && fi.globalmv_transformation_type[ref_frames[0]]
+          > GlobalMVMode::TRANSLATION)
+      && !is_compound
+      && self.bc.has_overlappable_candidates(bo, bsize)
+  }
+
+  /// Whether an inter block can signal `WARPED_CAUSAL` motion.
+  pub fn warped_causal_allowed(
+    &self, fi: &FrameInvariants, bo: &BlockOffset, bsize: BlockSize,
+    mode: PredictionMode, ref_frames: [usize; 2], mv: MotionVector
+  ) -> bool {
+    self.motion_mode_switchable(fi, bo, bsize, mode, ref_frames)
+      && self.warp_signaled(fi, bo, bsize, ref_frames[0], mv)
+  }
+
+  fn warp_signaled(
+    &self, fi: &FrameInvariants, bo: &BlockOffset, bsize: BlockSize,
+    ref_frame: usize, mv: MotionVector
+  ) -> bool {
+    fi.allow_warped_motion
+      && fi.force_integer_mv == 0
+      && !ref_is_scaled(fi, ref_frame)
+      && !self
+        .bc
+        .find_warp_samples(bo, bsize, fi.sequence.sb_size(), ref_frame, mv)
+        .is_empty()
+  }
+
+  /// Whether inter-intra prediction can be signaled for an inter block
+  /// (spec 5.11.28)
+  pub fn interintra_allowed(
+    &self, fi: &FrameInvariants, bsize: BlockSize, ref_frames: [usize; 2]
+  ) -> bool {
+    let is_compound =
+      ref_frames[1] > INTRA_FRAME && ref_frames[1] != NONE_FRAME;
+    fi.sequence.enable_interintra_compound
+      && !is_compound
+      && bsize.interintra_allowed()
+  }
+
+  pub fn write_interintra_mode(
+    &mut self, w: &mut dyn Writer, fi: &FrameInvariants, bsize: BlockSize,
+    ref_frames: [usize; 2], compound: CompoundParams
+  ) {
+    if !self.interintra_allowed(fi, bsize, ref_frames) {
+      assert!(!compound.interintra);
+      return;
+    }
+    let ctx = size_group_lookup[bsize as usize] as usize;
+    symbol_with_update!(
+      self,
+      w,
+      compound.interintra as u32,
+      &mut self.fc.interintra_cdf[ctx]
+    );
+    if compound.interintra {
+      symbol_with_update!(
+        self,
+        w,
+        compound.interintra_mode as u32,
+        &mut self.fc.interintra_mode_cdf[ctx]
+      );
+      let wedge_interintra =
+        compound.compound_type == CompoundType::COMPOUND_WEDGE;
+      symbol_with_update!(
+        self,
+        w,
+        wedge_interintra as u32,
+        &mut self.fc.wedge_interintra_cdf[bsize as usize]
+      );
+      if wedge_interintra {
+        symbol_with_update!(
+          self,
+          w,
+          compound.wedge_index as u32,
+          &mut self.fc.wedge_idx_cdf[bsize as usize]
+        );
+      }
+    }
+  }
+
+  pub fn write_motion_mode(
+    &mut self, w: &mut dyn Writer, fi: &FrameInvariants, bo: &BlockOffset,
+    bsize: BlockSize, mode: PredictionMode, ref_frames: [usize; 2],
+    mv: MotionVector, motion_mode: MotionMode, interintra: bool
+  ) {
+    // Inter-intra blocks always use simple translation
+    if interintra
+      || !self.motion_mode_switchable(fi, bo, bsize, mode, ref_frames)
+    {
+      assert!(motion_mode == MotionMode::SIMPLE_TRANSLATION);
+      return;
+    }
+    if self.warp_signaled(fi, bo, bsize, ref_frames[0], mv) {
+      symbol_with_update!(self, w, motion_mode as u32, &mut self.fc.motion_mode_cdf[bsize as usize]);
+    } else {
+      assert!(motion_mode != MotionMode::WARPED_CAUSAL);
+      let use_obmc = motion_mode == MotionMode::OBMC_CAUSAL;
+      symbol_with_update!(self, w, use_obmc as u32, &mut self.fc.obmc_cdf[bsize as usize]);
+    }
+  }
+
+  /// Whether the compound prediction of a block can be masked or distance
+  /// weighted, as opposed to always averaged.
+  pub fn compound_type_switchable(
+    &self, fi: &FrameInvariants, ref_frames: [usize; 2]
+  ) -> bool {
+    ref_frames[1] > INTRA_FRAME
+      && ref_frames[1] != NONE_FRAME
+      && (fi.sequence.enable_masked_compound || fi.sequence.enable_jnt_comp)
+  }
+
+  // Context of a compound syntax element from the value of compound
+  // neighbours, or `altref_value` for neighbours predicted from ALTREF_FRAME
+  // only
+  fn get_compound_neighbour_ctx(
+    &self, bo: &BlockOffset, value: &dyn Fn(&Block) -> usize, altref_value: usize
+  ) -> usize {
+    let neighbour_ctx = |b: &Block| {
+      if b.ref_frames[1] > INTRA_FRAME && b.ref_frames[1] != NONE_FRAME {
+        value(b)
+      } else if b.ref_frames[0] == ALTREF_FRAME {
+        altref_value
+      } else {
+        0
+      }
+    };
+    let above_ctx =
+      if bo.y > 0 { neighbour_ctx(&self.bc.above_of(bo)) } else { 0 };
+    let left_ctx =
+      if bo.x > 0 { neighbour_ctx(&self.bc.left_of(bo)) } else { 0 };
+    above_ctx + left_ctx
+  }
+
+  pub fn write_compound_type(
+    &mut self, w: &mut dyn Writer, fi: &FrameInvariants, bo: &BlockOffset,
+    bsize: BlockSize, ref_frames: [usize; 2], compound: CompoundParams
+  ) {
+    if !self.compound_type_switchable(fi, ref_frames) {
+      assert!(
+        compound.interintra
+          || compound.compound_type == CompoundType::COMPOUND_AVERAGE
+      );
+      return;
+    }
+    let comp_group_idx = compound.comp_group_idx();
+    if fi.sequence.enable_masked_compound {
+      let ctx = self
+        .get_compound_neighbour_ctx(bo, &|b| b.compound.comp_group_idx(), 3)
+        .min(5);
+      symbol_with_update!(
+        self,
+        w,
+        comp_group_idx as u32,
+        &mut self.fc.comp_group_idx_cdf[ctx]
+      );
+    } else {
+      assert!(comp_group_idx == 0);
+    }
+    if comp_group_idx == 0 {
+      if fi.sequence.enable_jnt_comp {
+        let fwd = fi.ref_frame_dist(ref_frames[0]);
+        let bck = fi.ref_frame_dist(ref_frames[1]);
+        let ctx = if fwd == bck { 3 } else { 0 }
+          + self.get_compound_neighbour_ctx(bo, &|b| b.compound.compound_idx(), 1);
+        symbol_with_update!(
+          self,
+          w,
+          compound.compound_idx() as u32,
+          &mut self.fc.compound_idx_cdf[ctx]
+        );
+      } else {
+        assert!(compound.compound_type == CompoundType::COMPOUND_AVERAGE);
+      }
+      return;
+    }
+    if bsize.wedge_allowed() {
+      symbol_with_update!(
+        self,
+        w,
+        compound.compound_type as u32,
+        &mut self.fc.compound_type_cdf[bsize as usize]
+      );
+    } else {
+      assert!(compound.compound_type == CompoundType::COMPOUND_DIFFWTD);
+    }
+    if compound.compound_type == CompoundType::COMPOUND_WEDGE {
+      symbol_with_update!(
+        self,
+        w,
+        compound.wedge_index as u32,
+        &mut self.fc.wedge_idx_cdf[bsize as usize]
+      );
+      w.bit(compound.wedge_sign as u16);
+    } else {
+      w.bit(compound.mask_type as u16);
+    }
+  }
+
+  /// Whether the interpolation filters of an inter block are coded in a
+  /// frame with switchable filters (spec 5.11.26), as opposed to inferred to
+  /// be `REGULAR`.
+  pub fn interp_filter_coded(
+    &self, fi: &FrameInvariants, bsize: BlockSize, mode: PredictionMode,
+    ref_frames: [usize; 2], motion_mode: MotionMode, skip_mode: bool
+  ) -> bool {
+    let large = bsize.width().min(bsize.height()) >= 8;
+    let is_translation = |r: usize| {
+      fi.globalmv_transformation_type[r] == GlobalMVMode::TRANSLATION
+    };
+    if skip_mode || motion_mode == MotionMode::WARPED_CAUSAL {
+      false
+    } else if large && mode == PredictionMode::GLOBALMV {
+      is_translation(ref_frames[0])
+    } else if large && mode == PredictionMode::GLOBAL_GLOBALMV {
+      is_translation(ref_frames[0]) || is_translation(ref_frames[1])
+    } else {
+      true
+    }
+  }
+
+  fn get_interp_filter_ctx(
+    &self, bo: &BlockOffset, ref_frames: [usize; 2], dir: usize
+  ) -> usize {
+    let is_compound =
+      ref_frames[1] > INTRA_FRAME && ref_frames[1] != NONE_FRAME;
+    let filter_type = |b: &Block| {
+      if b.ref_frames[0] == ref_frames[0] || b.ref_frames[1] == ref_frames[0] {
+        b.interp_filter[dir] as usize
+      } else {
+        SWITCHABLE_FILTERS
+      }
+    };
+    let left_type = if bo.x > 0 {
+      filter_type(self.bc.at(&bo.with_offset(-1, 0)))
+    } else {
+      SWITCHABLE_FILTERS
+    };
+    let above_type = if bo.y > 0 {
+      filter_type(self.bc.at(&bo.with_offset(0, -1)))
+    } else {
+      SWITCHABLE_FILTERS
+    };
+    let ctx = ((dir & 1) * 2 + is_compound as usize) * 4;
+    ctx + if left_type == above_type {
+      left_type
+    } else if left_type == SWITCHABLE_FILTERS {
+      above_type
+    } else if above_type == SWITCHABLE_FILTERS {
+      left_type
+    } else {
+      SWITCHABLE_FILTERS
+    }
+  }
+
+  pub fn write_interp_filter(
+    &mut self, w: &mut dyn Writer, fi: &FrameInvariants, bo: &BlockOffset,
+    bsize: BlockSize, mode: PredictionMode, ref_frames: [usize; 2],
+    motion_mode: MotionMode, interp_filter: [FilterMode; 2]
+  ) {
+    // Skip mode blocks code no inter mode info and never get here
+    if !fi.is_filter_switchable
+      || !self.interp_filter_coded(fi, bsize, mode, ref_frames, motion_mode, false)
+    {
+      assert!(interp_filter == fi.default_interp_filter());
+      return;
+    }
+    let dirs = if fi.sequence.enable_dual_filter { 2 } else { 1 };
+    if dirs == 1 {
+      assert!(interp_filter[1] == interp_filter[0]);
+    }
+    for dir in 0..dirs {
+      let ctx = self.get_interp_filter_ctx(bo, ref_frames, dir);
+      symbol_with_update!(
+        self,
+        w,
+        interp_filter[dir] as u32,
+        &mut self.fc.switchable_interp_cdf[ctx]
+      );
+    }
+  }
+
   pub fn write_mv(&mut self, w: &mut dyn Writer,
                   mv: MotionVector, ref_mv: MotionVector,
                   mv_precision: MvSubpelPrecision) {
-    let diff = MotionVector { row: mv.row - ref_mv.row, col: mv.col - ref_mv.col };
-    let j: MvJointType = av1_get_mv_joint(diff);
+    encode_mv(w, mv, ref_mv, &mut self.fc.nmv_context, mv_precision);
+  }
 
-    w.symbol_with_update(j as u32, &mut self.fc.nmv_context.joints_cdf);
+  /// Writes an IntraBC displacement vector, in whole pixels with its own
+  /// contexts (spec MV_INTRABC_CONTEXT).
+  pub fn write_dv(&mut self, w: &mut dyn Writer,
+                  dv: MotionVector, ref_dv: MotionVector) {
+    encode_mv(w, dv, ref_dv, &mut self.fc.dv_context, MvSubpelPrecision::MV_SUBPEL_NONE);
+  }
 
-    if mv_joint_vertical(j) {
-      encode_mv_component(w, diff.row as i32, &mut self.fc.nmv_context.comps[0], mv_precision);
-    }
-    if mv_joint_horizontal(j) {
-      encode_mv_component(w, diff.col as i32, &mut self.fc.nmv_context.comps[1], mv_precision);
-    }
+  pub fn write_intrabc(&mut self, w: &mut dyn Writer, use_intrabc: bool) {
+    symbol_with_update!(self, w, use_intrabc as u32, &mut self.fc.intrabc_cdf);
   }
 
   pub fn write_tx_type(
@@ -2923,11 +4300,101 @@ impl ContextWriter {
       }
     }
   }
+  /// Writes the transform size of a block whose transforms all have the
+  /// same size (spec 5.11.16), when the frame uses TX_MODE_SELECT
+  pub fn write_tx_size(
+    &mut self, w: &mut dyn Writer, bo: &BlockOffset, bsize: BlockSize,
+    tx_size: TxSize
+  ) {
+    if bsize == BlockSize::BLOCK_4X4 {
+      return;
+    }
+    let max_depth = bsize.max_tx_depth();
+    let mut depth = 0;
+    let mut depth_size = bsize.tx_size();
+    while depth_size != tx_size {
+      depth_size = depth_size.split();
+      depth += 1;
+    }
+    assert!(depth <= MAX_TX_DEPTH);
+    let ctx = self.bc.tx_size_context(bo, bsize);
+    let nsymbs = max_depth.min(MAX_TX_DEPTH) + 1;
+    symbol_with_update!(
+      self,
+      w,
+      depth as u32,
+      &mut self.fc.tx_size_cdf[max_depth - 1][ctx][..nsymbs + 1]
+    );
+  }
+
+  /// Writes the transform partitioning of a non-skipped inter block larger
+  /// than 4x4 (spec 5.11.17) and records its transform sizes
+  pub fn write_tx_partition(
+    &mut self, w: &mut dyn Writer, bo: &BlockOffset, bsize: BlockSize,
+    tx_partition: TxPartition
+  ) {
+    let tx_size = bsize.tx_size();
+    let mut root = 0;
+    for y in (0..bsize.height_mi()).step_by(tx_size.height_mi()) {
+      for x in (0..bsize.width_mi()).step_by(tx_size.width_mi()) {
+        let root_bo = BlockOffset { x: bo.x + x, y: bo.y + y };
+        self.write_var_tx_size(w, bo, bsize, &root_bo, tx_size, 0, tx_partition, root, 0);
+        root += 1;
+      }
+    }
+  }
+
+  fn write_var_tx_size(
+    &mut self, w: &mut dyn Writer, block_bo: &BlockOffset, bsize: BlockSize,
+    bo: &BlockOffset, tx_size: TxSize, depth: usize,
+    tx_partition: TxPartition, root: usize, node: usize
+  ) {
+    let split = if depth < MAX_VARTX_DEPTH && tx_size != TxSize::TX_4X4 {
+      let split = tx_partition.is_split(root, node);
+      self.write_txfm_split(w, block_bo, bsize, bo, tx_size, split);
+      split
+    } else {
+      false
+    };
+    if split {
+      let sub = tx_size.split();
+      for (i, (x, y)) in tx_size.split_offsets().into_iter().enumerate() {
+        let sub_bo = BlockOffset { x: bo.x + x, y: bo.y + y };
+        self.write_var_tx_size(
+          w, block_bo, bsize, &sub_bo, sub, depth + 1, tx_partition, root, i + 1
+        );
+      }
+    } else {
+      self.bc.set_tx_size(bo, tx_size.block_size(), tx_size);
+    }
+  }
+
+  /// Writes whether the `tx_size` transform at `bo` in the block at
+  /// `block_bo` is split
+  pub fn write_txfm_split(
+    &mut self, w: &mut dyn Writer, block_bo: &BlockOffset, bsize: BlockSize,
+    bo: &BlockOffset, tx_size: TxSize, split: bool
+  ) {
+    let ctx = self.bc.txfm_split_context(block_bo, bsize, bo, tx_size);
+    symbol_with_update!(self, w, split as u32, &mut self.fc.txfm_partition_cdf[ctx]);
+  }
+
   pub fn write_skip(&mut self, w: &mut dyn Writer, bo: &BlockOffset, skip: bool) {
     let ctx = self.bc.skip_context(bo);
     symbol_with_update!(self, w, skip as u32, &mut self.fc.skip_cdfs[ctx]);
   }
 
+  /// Whether skip_mode is coded for a block (spec 5.11.10). The segment
+  /// features that would disable it are never enabled by the encoder.
+  pub fn skip_mode_allowed(&self, fi: &FrameInvariants, bsize: BlockSize) -> bool {
+    fi.skip_mode_present && bsize.width() >= 8 && bsize.height() >= 8
+  }
+
+  pub fn write_skip_mode(&mut self, w: &mut dyn Writer, bo: &BlockOffset, skip_mode: bool) {
+    let ctx = self.bc.skip_mode_context(bo);
+    symbol_with_update!(self, w, skip_mode as u32, &mut self.fc.skip_mode_cdfs[ctx]);
+  }
+
   fn get_segment_pred(&mut self, bo: &BlockOffset) -> ( u8, u8 ) {
     let mut prev_ul = -1;
     let mut prev_u  = -1;
@@ -3011,99 +4478,145 @@ impl ContextWriter {
                    sbo: &SuperBlockOffset) {
     if !fi.allow_intrabc { // TODO: also disallow if lossless
       for pli in 0..PLANES {
-        let code;
         let rp = &mut rs.plane[pli];
-        {
-          let ru = &mut rp.restoration_unit_as_mut(sbo);
-          code = !ru.coded;
-          ru.coded = true;
-        }
-        if code {
-          match rp.restoration_unit_as_mut(sbo).filter {
-            RestorationFilter::None => {
-              match rp.lrf_type {
-                RESTORE_WIENER => {
-                  symbol_with_update!(self, w, 0, &mut self.fc.lrf_wiener_cdf);
-                }
-                RESTORE_SGRPROJ => {
-                  symbol_with_update!(self, w, 0, &mut self.fc.lrf_sgrproj_cdf);
-                }
-                RESTORE_SWITCHABLE => {
-                  symbol_with_update!(self, w, 0, &mut self.fc.lrf_switchable_cdf);
-                }
-                RESTORE_NONE => {}
-                _ => unreachable!()
+        let (rows, cols) = rp.restoration_units_in_sb(sbo, fi.superres_denom);
+        for row in rows {
+          for col in cols.clone() {
+            let filter = {
+              let ru = &mut rp.units[row][col];
+              if ru.coded {
+                continue;
               }
-            }
-            RestorationFilter::Sgrproj{set, xqd} => {
-              match rs.plane[pli].lrf_type {
-                RESTORE_SGRPROJ => {
-                  symbol_with_update!(self, w, 1, &mut self.fc.lrf_sgrproj_cdf);
-                }
-                RESTORE_SWITCHABLE => {
-                  // Does *not* write 'RESTORE_SGRPROJ'
-                  symbol_with_update!(self, w, 2 as u32, &mut self.fc.lrf_switchable_cdf);
-                }
-                _ => unreachable!()
-              }
-              w.literal(SGRPROJ_PARAMS_BITS, set as u32);
-              for i in 0..2 {
-                let r = SGRPROJ_PARAMS_RADIUS[set as usize][i];
-                let min = SGRPROJ_XQD_MIN[i] as i32;
-                let max = SGRPROJ_XQD_MAX[i] as i32;
-                if r>0 {
-                  w.write_signed_subexp_with_ref(xqd[i] as i32, min, max+1, SGRPROJ_PRJ_SUBEXP_K,
-                                                 rp.sgrproj_ref[i] as i32);
-                  rp.sgrproj_ref[i] = xqd[i];
-                } else {
-                  // Nothing written, just update the reference
-                  if i==0 {
-                    rp.sgrproj_ref[0] = 0;
-                  } else {
-                    rp.sgrproj_ref[1] =
-                      clamp((1 << SGRPROJ_PRJ_BITS) - rp.sgrproj_ref[0], min as i8, max as i8);
+              ru.coded = true;
+              ru.filter
+            };
+            match filter {
+              RestorationFilter::None => {
+                match rp.lrf_type {
+                  RESTORE_WIENER => {
+                    symbol_with_update!(self, w, 0, &mut self.fc.lrf_wiener_cdf);
                   }
+                  RESTORE_SGRPROJ => {
+                    symbol_with_update!(self, w, 0, &mut self.fc.lrf_sgrproj_cdf);
+                  }
+                  RESTORE_SWITCHABLE => {
+                    symbol_with_update!(self, w, 0, &mut self.fc.lrf_switchable_cdf);
+                  }
+                  RESTORE_NONE => {}
+                  _ => unreachable!()
                 }
               }
-            }
-            RestorationFilter::Wiener{coeffs} => {
-              match rs.plane[pli].lrf_type {
-                RESTORE_WIENER => {
-                  symbol_with_update!(self, w, 1, &mut self.fc.lrf_wiener_cdf);
-                }
-                RESTORE_SWITCHABLE => {
-                  // Does *not* write 'RESTORE_WIENER'
-                  symbol_with_update!(self, w, 1, &mut self.fc.lrf_switchable_cdf);
+              RestorationFilter::Sgrproj{..} => {
+                match rp.lrf_type {
+                  RESTORE_SGRPROJ => {
+                    symbol_with_update!(self, w, 1, &mut self.fc.lrf_sgrproj_cdf);
+                  }
+                  RESTORE_SWITCHABLE => {
+                    // Does *not* write 'RESTORE_SGRPROJ'
+                    symbol_with_update!(self, w, 2 as u32, &mut self.fc.lrf_switchable_cdf);
+                  }
+                  _ => unreachable!()
                 }
-                _ => unreachable!()
               }
-              for pass in 0..2 {
-                let first_coeff = if pli==0 {0} else {1};
-                for i in first_coeff..3 {
-                  let min = WIENER_TAPS_MIN[i] as i32;
-                  let max = WIENER_TAPS_MAX[i] as i32;
-                  w.write_signed_subexp_with_ref(coeffs[pass][i] as i32, min, max+1, (i+1) as u8,
-                                                 rp.wiener_ref[pass][i] as i32);
-                  rp.wiener_ref[pass][i] = coeffs[pass][i];
+              RestorationFilter::Wiener{..} => {
+                match rp.lrf_type {
+                  RESTORE_WIENER => {
+                    symbol_with_update!(self, w, 1, &mut self.fc.lrf_wiener_cdf);
+                  }
+                  RESTORE_SWITCHABLE => {
+                    // Does *not* write 'RESTORE_WIENER'
+                    symbol_with_update!(self, w, 1, &mut self.fc.lrf_switchable_cdf);
+                  }
+                  _ => unreachable!()
                 }
               }
             }
+            Self::write_lrf_coeffs(w, pli, filter, &rp.wiener_ref, &rp.sgrproj_ref);
+            update_lrf_refs(filter, &mut rp.wiener_ref, &mut rp.sgrproj_ref);
           }
         }
       }
     }
   }
 
+  /// Writes the coefficients of the restoration filter of a unit of plane
+  /// `pli`, coded against the references of the previous unit
+  fn write_lrf_coeffs(w: &mut dyn Writer, pli: usize, filter: RestorationFilter,
+                      wiener_ref: &[[i8; 3]; 2], sgrproj_ref: &[i8; 2]) {
+    match filter {
+      RestorationFilter::None => {}
+      RestorationFilter::Sgrproj{set, xqd} => {
+        w.literal(SGRPROJ_PARAMS_BITS, set as u32);
+        for i in 0..2 {
+          let r = SGRPROJ_PARAMS_RADIUS[set as usize][i];
+          let min = SGRPROJ_XQD_MIN[i] as i32;
+          let max = SGRPROJ_XQD_MAX[i] as i32;
+          // Nothing is written for a filter of radius 0
+          if r>0 {
+            w.write_signed_subexp_with_ref(xqd[i] as i32, min, max+1, SGRPROJ_PRJ_SUBEXP_K,
+                                           sgrproj_ref[i] as i32);
+          }
+        }
+      }
+      RestorationFilter::Wiener{coeffs} => {
+        for pass in 0..2 {
+          let first_coeff = if pli==0 {0} else {1};
+          for i in first_coeff..3 {
+            let min = WIENER_TAPS_MIN[i] as i32;
+            let max = WIENER_TAPS_MAX[i] as i32;
+            w.write_signed_subexp_with_ref(coeffs[pass][i] as i32, min, max+1, (i+1) as u8,
+                                           wiener_ref[pass][i] as i32);
+          }
+        }
+      }
+    }
+  }
+
+  /// Cost in 1/8 bits of coding `filter` for a restoration unit of plane
+  /// `pli` of restoration type `lrf_type`, given the coefficient references
+  pub fn lrf_cost(&self, pli: usize, lrf_type: u8, filter: RestorationFilter,
+                  wiener_ref: &[[i8; 3]; 2], sgrproj_ref: &[i8; 2]) -> u32 {
+    let s = match filter {
+      RestorationFilter::None => 0,
+      RestorationFilter::Wiener{..} => 1,
+      RestorationFilter::Sgrproj{..} => if lrf_type == RESTORE_SWITCHABLE { 2 } else { 1 }
+    };
+    let type_cost = match lrf_type {
+      RESTORE_WIENER => symbol_cost(s, &self.fc.lrf_wiener_cdf),
+      RESTORE_SGRPROJ => symbol_cost(s, &self.fc.lrf_sgrproj_cdf),
+      RESTORE_SWITCHABLE => symbol_cost(s, &self.fc.lrf_switchable_cdf),
+      _ => 0
+    };
+    let mut wc = WriterCounter::new();
+    let tell = wc.tell_frac();
+    Self::write_lrf_coeffs(&mut wc, pli, filter, wiener_ref, sgrproj_ref);
+    type_cost + wc.tell_frac() - tell
+  }
+
   pub fn write_cdef(&mut self, w: &mut dyn Writer, strength_index: u8, bits: u8) {
     w.literal(bits, strength_index as u32);
   }
 
+  pub fn write_delta_qindex(&mut self, w: &mut dyn Writer, delta_q_abs: u32,
+                            negative: bool) {
+      symbol_with_update!(self, w, cmp::min(delta_q_abs, DELTA_Q_SMALL),
+                          &mut self.fc.delta_q_cdf);
+      if delta_q_abs >= DELTA_Q_SMALL {
+          let bits = msb(delta_q_abs as i32 - 1) as u32;
+          w.literal(3, bits - 1);
+          w.literal(bits as u8, delta_q_abs - (1<<bits) - 1);
+      }
+      if delta_q_abs > 0 {
+          w.bool(negative, 16384);
+      }
+  }
+
+  /// Writes the change of the block-level deblocking deltas from those of
+  /// the previous superblock, in units of the delta_lf resolution
   pub fn write_block_deblock_deltas(&mut self, w: &mut dyn Writer,
-                                    bo: &BlockOffset, multi: bool) {
-      let block = self.bc.at(bo);
-      let deltas = if multi { FRAME_LF_COUNT + PLANES - 3 } else { 1 };
-      for i in 0..deltas {
-          let delta = block.deblock_deltas[i];
+                                    deltas: [i8; FRAME_LF_COUNT], multi: bool) {
+      let deltas = if multi { &deltas[..FRAME_LF_COUNT + PLANES - 3] } else { &deltas[..1] };
+      for (i, &delta) in deltas.iter().enumerate() {
           let abs:u32 = delta.abs() as u32;
 
           if multi {
@@ -3355,6 +4868,106 @@ impl ContextWriter {
     }
   }
 
+  /// Estimated cost in 1/8 bits of signalling `eob` for a transform block,
+  /// including the all-zero flag
+  pub fn eob_cost(
+    &mut self, eob: usize, plane: usize, tx_size: TxSize, tx_type: TxType,
+    txb_ctx: &TXB_CTX
+  ) -> u32 {
+    let txs_ctx = self.get_txsize_entropy_ctx(tx_size);
+    let skip_cost = symbol_cost(
+      (eob == 0) as u32,
+      &self.fc.txb_skip_cdf[txs_ctx][txb_ctx.txb_skip_ctx]
+    );
+    if eob == 0 {
+      return skip_cost;
+    }
+
+    let plane_type = (plane > 0) as usize;
+    let eob_multi_ctx = (tx_type_to_class[tx_type as usize] != TX_CLASS_2D) as usize;
+    let mut eob_extra = 0 as u32;
+    let eob_pt = self.get_eob_pos_token(eob, &mut eob_extra);
+    let eob_cdf: &[u16] = match av1_get_coded_tx_size(tx_size).area_log2() - 4 {
+      0 => &self.fc.eob_flag_cdf16[plane_type][eob_multi_ctx],
+      1 => &self.fc.eob_flag_cdf32[plane_type][eob_multi_ctx],
+      2 => &self.fc.eob_flag_cdf64[plane_type][eob_multi_ctx],
+      3 => &self.fc.eob_flag_cdf128[plane_type][eob_multi_ctx],
+      4 => &self.fc.eob_flag_cdf256[plane_type][eob_multi_ctx],
+      5 => &self.fc.eob_flag_cdf512[plane_type][eob_multi_ctx],
+      _ => &self.fc.eob_flag_cdf1024[plane_type][eob_multi_ctx],
+    };
+    let mut cost = skip_cost + symbol_cost(eob_pt - 1, eob_cdf);
+
+    let eob_offset_bits = k_eob_offset_bits[eob_pt as usize] as u32;
+    if eob_offset_bits > 0 {
+      let bit = (eob_extra >> (eob_offset_bits - 1)) & 1;
+      cost += symbol_cost(
+        bit,
+        &self.fc.eob_extra_cdf[txs_ctx][plane_type][(eob_pt - 3) as usize]
+      );
+      cost += (eob_offset_bits - 1) << OD_BITRES;
+    }
+
+    cost
+  }
+
+  /// Estimated cost in 1/8 bits of coding coefficient `v` at scan index `c`,
+  /// given its base and range contexts
+  pub fn coeff_cost(
+    &mut self, v: i32, c: usize, is_eob: bool, coeff_ctx: usize, br_ctx: usize,
+    plane: usize, tx_size: TxSize, txb_ctx: &TXB_CTX
+  ) -> u32 {
+    let txs_ctx = self.get_txsize_entropy_ctx(tx_size);
+    let plane_type = (plane > 0) as usize;
+    let level = v.abs() as u32;
+
+    let mut cost = if is_eob {
+      symbol_cost(
+        cmp::min(level, 3) - 1,
+        &self.fc.coeff_base_eob_cdf[txs_ctx][plane_type][coeff_ctx]
+      )
+    } else {
+      symbol_cost(
+        cmp::min(level, 3),
+        &self.fc.coeff_base_cdf[txs_ctx][plane_type][coeff_ctx]
+      )
+    };
+    if level == 0 {
+      return cost;
+    }
+
+    if level > NUM_BASE_LEVELS as u32 {
+      let br_cdf = &self.fc.coeff_br_cdf
+        [cmp::min(txs_ctx, TxSize::TX_32X32 as usize)][plane_type][br_ctx];
+      let base_range = level - 1 - NUM_BASE_LEVELS as u32;
+      let mut idx = 0;
+      while idx < COEFF_BASE_RANGE as u32 {
+        let k = cmp::min(base_range - idx, BR_CDF_SIZE as u32 - 1);
+        cost += symbol_cost(k, br_cdf);
+        if k < BR_CDF_SIZE as u32 - 1 {
+          break;
+        }
+        idx += BR_CDF_SIZE as u32 - 1;
+      }
+    }
+
+    cost += if c == 0 {
+      symbol_cost(
+        (v < 0) as u32,
+        &self.fc.dc_sign_cdf[plane_type][txb_ctx.dc_sign_ctx]
+      )
+    } else {
+      1 << OD_BITRES
+    };
+
+    if level > (COEFF_BASE_RANGE + NUM_BASE_LEVELS) as u32 {
+      let x = level - (COEFF_BASE_RANGE + NUM_BASE_LEVELS) as u32;
+      cost += (2 * msb(x as i32) as u32 + 1) << OD_BITRES;
+    }
+
+    cost
+  }
+
   pub fn write_coeffs_lv_map(
     &mut self, w: &mut dyn Writer, plane: usize, bo: &BlockOffset, coeffs_in: &[i32],
     pred_mode: PredictionMode,
@@ -3427,7 +5040,7 @@ impl ContextWriter {
     // Encode EOB
     let mut eob_extra = 0 as u32;
     let eob_pt = self.get_eob_pos_token(eob, &mut eob_extra);
-    let eob_multi_size: usize = tx_size.area_log2() - 4;
+    let eob_multi_size: usize = av1_get_coded_tx_size(tx_size).area_log2() - 4;
     let eob_multi_ctx: usize = if tx_class == TX_CLASS_2D {
       0
     } else {
@@ -3638,8 +5251,8 @@ const MV_MAX: usize = ((1 << MV_MAX_BITS) - 1);
 const MV_VALS: usize = ((MV_MAX << 1) + 1);
 
 const MV_IN_USE_BITS: usize = 14;
-const MV_UPP: i32 = (1 << MV_IN_USE_BITS);
-const MV_LOW: i32 = (-(1 << MV_IN_USE_BITS));
+pub const MV_UPP: i32 = (1 << MV_IN_USE_BITS);
+pub const MV_LOW: i32 = (-(1 << MV_IN_USE_BITS));
 
 
 #[inline(always)]
@@ -3679,6 +5292,21 @@ pub fn get_mv_class(z: u32, offset: &mut u32) -> usize {
   c
 }
 
+fn encode_mv(w: &mut dyn Writer, mv: MotionVector, ref_mv: MotionVector,
+  nmvc: &mut NMVContext, precision: MvSubpelPrecision) {
+  let diff = MotionVector { row: mv.row - ref_mv.row, col: mv.col - ref_mv.col };
+  let j: MvJointType = av1_get_mv_joint(diff);
+
+  w.symbol_with_update(j as u32, &mut nmvc.joints_cdf);
+
+  if mv_joint_vertical(j) {
+    encode_mv_component(w, diff.row as i32, &mut nmvc.comps[0], precision);
+  }
+  if mv_joint_horizontal(j) {
+    encode_mv_component(w, diff.col as i32, &mut nmvc.comps[1], precision);
+  }
+}
+
 pub fn encode_mv_component(w: &mut Writer, comp: i32,
   mvcomp: &mut NMVComponent, precision: MvSubpelPrecision) {
   assert!(comp != 0);
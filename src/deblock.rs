@@ -11,15 +11,65 @@
 
 use context::*;
 use DeblockState;
+use encoder::ChromaSampling;
+use Frame;
 use FrameInvariants;
 use FrameState;
 use FrameType;
 use partition::*;
 use partition::PredictionMode::*;
 use plane::*;
+use pool::WorkerPool;
 use quantize::*;
+use rdo::get_lambda;
 use std::cmp;
-use util::{clamp, ILog};
+use std::ops::Range;
+use util::{clamp, Fixed, ILog};
+
+// Blocks sharing the same reference and mode deltas: intra, then each
+// reference with the two mode types
+const DEBLOCK_CLASSES: usize = 1 + INTER_REFS_PER_FRAME * 2;
+
+fn deblock_mode_type(mode: PredictionMode) -> usize {
+  if mode >= NEARESTMV && mode != GLOBALMV && mode != GLOBAL_GLOBALMV {
+    1
+  } else {
+    0
+  }
+}
+
+fn deblock_class(block: &Block) -> usize {
+  let reference = block.ref_frames[0];
+  if reference == INTRA_FRAME {
+    0
+  } else {
+    1 + (reference - LAST_FRAME) * 2 + deblock_mode_type(block.mode)
+  }
+}
+
+// Applies the reference and mode deltas to a level, if they are enabled
+fn class_level(deblock: &DeblockState, level: usize, class: usize) -> usize {
+  if !deblock.deltas_enabled {
+    return level;
+  }
+  let (reference, mode_type) = if class == 0 {
+    (INTRA_FRAME, 0)
+  } else {
+    (LAST_FRAME + (class - 1) / 2, (class - 1) % 2)
+  };
+  let l5 = level >> 5;
+  clamp(
+    level as i32
+      + ((deblock.ref_deltas[reference] as i32) << l5)
+      + if reference == INTRA_FRAME {
+        0
+      } else {
+        (deblock.mode_deltas[mode_type] as i32) << l5
+      },
+    0,
+    MAX_LOOP_FILTER as i32
+  ) as usize
+}
 
 fn deblock_adjusted_level(
   deblock: &DeblockState, block: &Block, pli: usize, vertical: bool
@@ -37,9 +87,9 @@ fn deblock_adjusted_level(
   let level = if deblock.block_deltas_enabled {
     // By-block filter strength delta, if the feature is active.
     let block_delta = if deblock.block_delta_multi {
-      block.deblock_deltas[idx] << deblock.block_delta_shift
+      block.deblock_deltas[idx]
     } else {
-      block.deblock_deltas[0] << deblock.block_delta_shift
+      block.deblock_deltas[0]
     };
 
     // Add to frame-specified filter strength (Y-vertical, Y-horizontal, U, V)
@@ -54,30 +104,7 @@ fn deblock_adjusted_level(
   // }
 
   // Are delta modifiers for specific references and modes active?  If so, add them too.
-  if deblock.deltas_enabled {
-    let mode = block.mode;
-    let reference = block.ref_frames[0];
-    let mode_type =
-      if mode >= NEARESTMV && mode != GLOBALMV && mode != GLOBAL_GLOBALMV {
-        1
-      } else {
-        0
-      };
-    let l5 = level >> 5;
-    clamp(
-      level as i32
-        + ((deblock.ref_deltas[reference] as i32) << l5)
-        + if reference == INTRA_FRAME {
-          0
-        } else {
-          (deblock.mode_deltas[mode_type] as i32) << l5
-        },
-      0,
-      MAX_LOOP_FILTER as i32
-    ) as usize
-  } else {
-    level as usize
-  }
+  class_level(deblock, level as usize, deblock_class(block))
 }
 
 fn deblock_left<'a>(
@@ -108,6 +135,29 @@ fn deblock_up<'a>(
   bc.at(&bo.with_offset(0, -1 << ydec))
 }
 
+// Width and height in luma mi units of the transforms of a block in a
+// plane.  Chroma always uses the largest transforms of the block, however
+// luma is split.
+fn tx_size_mi(block: &Block, p: &Plane, pli: usize) -> (usize, usize) {
+  if pli == 0 {
+    (block.tx_w, block.tx_h)
+  } else {
+    let xdec = p.cfg.xdec;
+    let ydec = p.cfg.ydec;
+    let chroma_sampling = match (xdec, ydec) {
+      (1, 1) => ChromaSampling::Cs420,
+      (1, 0) => ChromaSampling::Cs422,
+      _ => ChromaSampling::Cs444
+    };
+    let bsize = BlockSize::from_width_and_height(
+      block.n4_w << MI_SIZE_LOG2,
+      block.n4_h << MI_SIZE_LOG2
+    );
+    let tx_size = bsize.largest_uv_tx_size(chroma_sampling);
+    (tx_size.width_mi() << xdec, tx_size.height_mi() << ydec)
+  }
+}
+
 // Must be called on a tx edge, and not on a frame edge.  This is enforced above the call.
 fn deblock_size(
   block: &Block, prev_block: &Block, p: &Plane, pli: usize, vertical: bool,
@@ -125,10 +175,12 @@ fn deblock_size(
   {
     0
   } else {
+    let (tx_w, tx_h) = tx_size_mi(block, p, pli);
+    let (prev_tx_w, prev_tx_h) = tx_size_mi(prev_block, p, pli);
     let (tx_size, prev_tx_size) = if vertical {
-      (cmp::max(block.tx_w >> xdec, 1), cmp::max(prev_block.tx_w >> xdec, 1))
+      (tx_w >> xdec, prev_tx_w >> xdec)
     } else {
-      (cmp::max(block.tx_h >> ydec, 1), cmp::max(prev_block.tx_h >> ydec, 1))
+      (tx_h >> ydec, prev_tx_h >> ydec)
     };
 
     cmp::min(
@@ -318,20 +370,55 @@ fn stride_sse(a: &[u16], b: &[i32], pitch: usize) -> i64 {
   acc as i64
 }
 
-fn _level_to_limit(level: i32, shift: usize) -> i32 {
-  level << shift
+// Limits are scaled down with the sharpness (spec 7.14.4)
+fn sharpness_shift(sharpness: usize) -> usize {
+  if sharpness > 4 {
+    2
+  } else if sharpness > 0 {
+    1
+  } else {
+    0
+  }
 }
 
-fn limit_to_level(limit: i32, shift: usize) -> i32 {
-  limit + (1 << shift) - 1 >> shift
+fn level_to_limit(level: i32, shift: usize, sharpness: usize) -> i32 {
+  let limit = if sharpness > 0 {
+    clamp(level >> sharpness_shift(sharpness), 1, 9 - sharpness as i32)
+  } else {
+    cmp::max(1, level)
+  };
+  limit << shift
+}
+
+// Lowest level with a limit of at least `limit`
+fn limit_to_level(limit: i32, shift: usize, sharpness: usize) -> i32 {
+  let limit = limit + (1 << shift) - 1 >> shift;
+  if sharpness == 0 || limit <= 1 {
+    limit
+  } else if limit > 9 - sharpness as i32 {
+    MAX_LOOP_FILTER as i32 + 1
+  } else {
+    limit << sharpness_shift(sharpness)
+  }
 }
 
-fn _level_to_blimit(level: i32, shift: usize) -> i32 {
-  3 * level + 4 << shift
+fn level_to_blimit(level: i32, shift: usize, sharpness: usize) -> i32 {
+  2 * (level + 2) + level_to_limit(level, 0, sharpness) << shift
 }
 
-fn blimit_to_level(blimit: i32, shift: usize) -> i32 {
-  ((blimit + (1 << shift) - 1 >> shift) - 2) / 3
+// Lowest level with a blimit of at least `blimit`
+fn blimit_to_level(blimit: i32, shift: usize, sharpness: usize) -> i32 {
+  let blimit = blimit + (1 << shift) - 1 >> shift;
+  if sharpness == 0 {
+    (blimit - 2) / 3
+  } else {
+    // The limit term is at most 9 - sharpness
+    let mut level = cmp::max(0, blimit - 4 - (9 - sharpness as i32) + 1 >> 1);
+    while level_to_blimit(level, 0, sharpness) < blimit {
+      level += 1;
+    }
+    level
+  }
 }
 
 fn _level_to_thresh(level: i32, shift: usize) -> i32 {
@@ -346,16 +433,26 @@ fn nhev4(p1: i32, p0: i32, q0: i32, q1: i32, shift: usize) -> usize {
   thresh_to_level(cmp::max((p1 - p0).abs(), (q1 - q0).abs()), shift) as usize
 }
 
-fn mask4(p1: i32, p0: i32, q0: i32, q1: i32, shift: usize) -> usize {
+// Lowest level at which a line of pixels is filtered, from the largest
+// step between neighbors and the step across the edge
+fn mask_to_level(mask: (i32, i32), shift: usize, sharpness: usize) -> usize {
   cmp::max(
-    limit_to_level(cmp::max((p1 - p0).abs(), (q1 - q0).abs()), shift),
-    blimit_to_level((p0 - q0).abs() * 2 + (p1 - q1).abs() / 2, shift)
+    limit_to_level(mask.0, shift, sharpness),
+    blimit_to_level(mask.1, shift, sharpness)
   ) as usize
 }
 
+fn mask4(p1: i32, p0: i32, q0: i32, q1: i32) -> (i32, i32) {
+  (
+    cmp::max((p1 - p0).abs(), (q1 - q0).abs()),
+    (p0 - q0).abs() * 2 + (p1 - q1).abs() / 2
+  )
+}
+
 // Assumes rec[0] is set 2 taps back from the edge
 fn deblock_size4(
-  rec: &mut [u16], pitch: usize, stride: usize, level: usize, bd: usize
+  rec: &mut [u16], pitch: usize, stride: usize, level: usize,
+  sharpness: usize, bd: usize
 ) {
   let mut s = 0;
   for _i in 0..4 {
@@ -364,7 +461,7 @@ fn deblock_size4(
     let p0 = p[pitch] as i32;
     let q0 = p[pitch * 2] as i32;
     let q1 = p[pitch * 3] as i32;
-    if mask4(p1, p0, q0, q1, bd - 8) <= level {
+    if mask_to_level(mask4(p1, p0, q0, q1), bd - 8, sharpness) <= level {
       let x;
       if nhev4(p1, p0, q0, q1, bd - 8) <= level {
         x = filter_narrow4_4(p1, p0, q0, q1, bd - 8);
@@ -377,16 +474,48 @@ fn deblock_size4(
   }
 }
 
+// Squared errors of the outcomes of filtering one line of pixels across
+// an edge, with the masks deciding between them
+#[derive(Clone, Copy, Default)]
+struct LineSse {
+  mask: (i32, i32),
+  // Level from which the narrow filter uses 4 taps instead of 2
+  hev: usize,
+  none: i64,
+  // Filtered below and from the hev level; the flat filters ignore it
+  low: i64,
+  high: i64
+}
+
+impl LineSse {
+  // Accumulates into a tally by level, where `base_level` maps each
+  // filter level to the lowest frame level producing it
+  fn tally(
+    &self, tally: &mut LevelTally, base_level: &[usize; MAX_LOOP_FILTER + 2],
+    sharpness: usize, bd: usize
+  ) {
+    let mask = clamp(
+      mask_to_level(self.mask, bd - 8, sharpness),
+      1,
+      MAX_LOOP_FILTER + 1
+    );
+    let hev = clamp(self.hev, mask, MAX_LOOP_FILTER + 1);
+    tally[0] += self.none;
+    tally[base_level[mask]] += self.low - self.none;
+    tally[base_level[hev]] += self.high - self.low;
+  }
+}
+
 // Assumes rec[0] and src[0] are set 2 taps back from the edge.
-// Accesses four taps, accumulates four pixels into the tally
+// Accesses four taps, compares four pixels per line
 fn sse_size4(
-  rec: &[u16], src: &[u16], tally: &mut [i64; MAX_LOOP_FILTER + 2],
-  rec_pitch: usize, src_pitch: usize, rec_stride: usize, src_stride: usize,
-  bd: usize
-) {
+  rec: &[u16], src: &[u16], rec_pitch: usize, src_pitch: usize,
+  rec_stride: usize, src_stride: usize, bd: usize
+) -> [LineSse; 4] {
+  let mut lines = [LineSse::default(); 4];
   let mut rec_s = 0;
   let mut src_s = 0;
-  for _i in 0..4 {
+  for line in lines.iter_mut() {
     let p = &rec[rec_s..]; // four taps
     let a = &src[src_s..]; // four pixels to compare
     let p1 = p[0] as i32;
@@ -402,47 +531,30 @@ fn sse_size4(
 
     // mask4 sets the dividing line for filter vs no filter
     // nhev4 sets the dividing line between narrow2 and narrow4
-    let mask =
-      clamp(mask4(p1, p0, q0, q1, bd - 8), 1, MAX_LOOP_FILTER + 1) as usize;
-    let nhev =
-      clamp(nhev4(p1, p0, q0, q1, bd - 8), mask, MAX_LOOP_FILTER + 1) as usize;
-
-    // sse for each; short-circuit the 'special' no-op cases.
-    let sse_none = stride_sse(a, &none, src_pitch);
-    let sse_narrow2 =
-      if nhev != mask { stride_sse(a, &narrow2, src_pitch) } else { sse_none };
-    let sse_narrow4 = if nhev <= MAX_LOOP_FILTER {
-      stride_sse(a, &narrow4, src_pitch)
-    } else {
-      sse_none
+    *line = LineSse {
+      mask: mask4(p1, p0, q0, q1),
+      hev: nhev4(p1, p0, q0, q1, bd - 8),
+      none: stride_sse(a, &none, src_pitch),
+      low: stride_sse(a, &narrow2, src_pitch),
+      high: stride_sse(a, &narrow4, src_pitch)
     };
 
-    // accumulate possible filter values into the tally
-    // level 0 is a special case
-    tally[0] += sse_none;
-    tally[mask] -= sse_none;
-    tally[mask] += sse_narrow2;
-    tally[nhev] -= sse_narrow2;
-    tally[nhev] += sse_narrow4;
-
     rec_s += rec_stride;
     src_s += src_stride;
   }
+  lines
 }
 
 fn mask6(
-  p2: i32, p1: i32, p0: i32, q0: i32, q1: i32, q2: i32, shift: usize
-) -> usize {
-  cmp::max(
-    limit_to_level(
-      cmp::max(
-        (p2 - p1).abs(),
-        cmp::max((p1 - p0).abs(), cmp::max((q2 - q1).abs(), (q1 - q0).abs()))
-      ),
-      shift
+  p2: i32, p1: i32, p0: i32, q0: i32, q1: i32, q2: i32
+) -> (i32, i32) {
+  (
+    cmp::max(
+      (p2 - p1).abs(),
+      cmp::max((p1 - p0).abs(), cmp::max((q2 - q1).abs(), (q1 - q0).abs()))
     ),
-    blimit_to_level((p0 - q0).abs() * 2 + (p1 - q1).abs() / 2, shift)
-  ) as usize
+    (p0 - q0).abs() * 2 + (p1 - q1).abs() / 2
+  )
 }
 
 fn flat6(p2: i32, p1: i32, p0: i32, q0: i32, q1: i32, q2: i32) -> usize {
@@ -454,7 +566,8 @@ fn flat6(p2: i32, p1: i32, p0: i32, q0: i32, q1: i32, q2: i32) -> usize {
 
 // Assumes slice[0] is set 3 taps back from the edge
 fn deblock_size6(
-  rec: &mut [u16], pitch: usize, stride: usize, level: usize, bd: usize
+  rec: &mut [u16], pitch: usize, stride: usize, level: usize,
+  sharpness: usize, bd: usize
 ) {
   let mut s = 0;
   let flat = 1 << bd - 8;
@@ -466,7 +579,8 @@ fn deblock_size6(
     let q0 = p[pitch * 3] as i32;
     let q1 = p[pitch * 4] as i32;
     let q2 = p[pitch * 5] as i32;
-    if mask6(p2, p1, p0, q0, q1, q2, bd - 8) <= level {
+    let mask = mask6(p2, p1, p0, q0, q1, q2);
+    if mask_to_level(mask, bd - 8, sharpness) <= level {
       let x;
       if flat6(p2, p1, p0, q0, q1, q2) <= flat {
         x = filter_wide6_4(p2, p1, p0, q0, q1, q2);
@@ -482,16 +596,16 @@ fn deblock_size6(
 }
 
 // Assumes rec[0] and src[0] are set 3 taps back from the edge.
-// Accesses six taps, accumulates four pixels into the tally
+// Accesses six taps, compares four pixels per line
 fn sse_size6(
-  rec: &[u16], src: &[u16], tally: &mut [i64; MAX_LOOP_FILTER + 2],
-  rec_pitch: usize, src_pitch: usize, rec_stride: usize, src_stride: usize,
-  bd: usize
-) {
+  rec: &[u16], src: &[u16], rec_pitch: usize, src_pitch: usize,
+  rec_stride: usize, src_stride: usize, bd: usize
+) -> [LineSse; 4] {
+  let mut lines = [LineSse::default(); 4];
   let mut rec_s = 0;
   let mut src_s = 0;
   let flat = 1 << bd - 8;
-  for _i in 0..4 {
+  for line in lines.iter_mut() {
     let p = &rec[rec_s..]; // six taps
     let a = &src[src_s + src_pitch..]; // four pixels to compare so offset one forward
     let p2 = p[0] as i32;
@@ -504,77 +618,53 @@ fn sse_size6(
     // Four possibilities: no filter, wide6, narrow2 and narrow4
     // All possibilities produce four outputs
     let none: [_; 4] = [p1, p0, q0, q1];
-    let wide6 = filter_wide6_4(p2, p1, p0, q0, q1, q2);
-    let narrow2 = filter_narrow2_4(p1, p0, q0, q1, bd - 8);
-    let narrow4 = filter_narrow4_4(p1, p0, q0, q1, bd - 8);
+    let sse_none = stride_sse(a, &none, src_pitch);
 
     // mask6 sets the dividing line for filter vs no filter
     // flat6 decides between wide and narrow filters (unrelated to level)
     // nhev4 sets the dividing line between narrow2 and narrow4
-    let mask =
-      clamp(mask6(p2, p1, p0, q0, q1, q2, bd - 8), 1, MAX_LOOP_FILTER + 1)
-        as usize;
-    let flatp = flat6(p2, p1, p0, q0, q1, q2) <= flat;
-    let nhev =
-      clamp(nhev4(p1, p0, q0, q1, bd - 8), mask, MAX_LOOP_FILTER + 1) as usize;
-
-    // sse for each; short-circuit the 'special' no-op cases.
-    let sse_none = stride_sse(a, &none, src_pitch);
-    let sse_wide6 = if flatp && mask <= MAX_LOOP_FILTER {
-      stride_sse(a, &wide6, src_pitch)
-    } else {
-      sse_none
-    };
-    let sse_narrow2 = if !flatp && nhev != mask {
-      stride_sse(a, &narrow2, src_pitch)
-    } else {
-      sse_none
-    };
-    let sse_narrow4 = if !flatp && nhev <= MAX_LOOP_FILTER {
-      stride_sse(a, &narrow4, src_pitch)
+    let mask = mask6(p2, p1, p0, q0, q1, q2);
+    *line = if flat6(p2, p1, p0, q0, q1, q2) <= flat {
+      let wide6 = filter_wide6_4(p2, p1, p0, q0, q1, q2);
+      let sse_wide6 = stride_sse(a, &wide6, src_pitch);
+      LineSse { mask, hev: 0, none: sse_none, low: sse_wide6, high: sse_wide6 }
     } else {
-      sse_none
+      let narrow2 = filter_narrow2_4(p1, p0, q0, q1, bd - 8);
+      let narrow4 = filter_narrow4_4(p1, p0, q0, q1, bd - 8);
+      LineSse {
+        mask,
+        hev: nhev4(p1, p0, q0, q1, bd - 8),
+        none: sse_none,
+        low: stride_sse(a, &narrow2, src_pitch),
+        high: stride_sse(a, &narrow4, src_pitch)
+      }
     };
 
-    // accumulate possible filter values into the tally
-    tally[0] += sse_none;
-    tally[mask] -= sse_none;
-    if flatp {
-      tally[mask] += sse_wide6;
-    } else {
-      tally[mask] += sse_narrow2;
-      tally[nhev] -= sse_narrow2;
-      tally[nhev] += sse_narrow4;
-    }
-
     rec_s += rec_stride;
     src_s += src_stride;
   }
+  lines
 }
 
 fn mask8(
-  p3: i32, p2: i32, p1: i32, p0: i32, q0: i32, q1: i32, q2: i32, q3: i32,
-  shift: usize
-) -> usize {
-  cmp::max(
-    limit_to_level(
+  p3: i32, p2: i32, p1: i32, p0: i32, q0: i32, q1: i32, q2: i32, q3: i32
+) -> (i32, i32) {
+  (
+    cmp::max(
+      (p3 - p2).abs(),
       cmp::max(
-        (p3 - p2).abs(),
+        (p2 - p1).abs(),
         cmp::max(
-          (p2 - p1).abs(),
+          (p1 - p0).abs(),
           cmp::max(
-            (p1 - p0).abs(),
-            cmp::max(
-              (q3 - q2).abs(),
-              cmp::max((q2 - q1).abs(), (q1 - q0).abs())
-            )
+            (q3 - q2).abs(),
+            cmp::max((q2 - q1).abs(), (q1 - q0).abs())
           )
         )
-      ),
-      shift
+      )
     ),
-    blimit_to_level((p0 - q0).abs() * 2 + (p1 - q1).abs() / 2, shift)
-  ) as usize
+    (p0 - q0).abs() * 2 + (p1 - q1).abs() / 2
+  )
 }
 
 fn flat8(
@@ -594,7 +684,8 @@ fn flat8(
 
 // Assumes rec[0] is set 4 taps back from the edge
 fn deblock_size8(
-  rec: &mut [u16], pitch: usize, stride: usize, level: usize, bd: usize
+  rec: &mut [u16], pitch: usize, stride: usize, level: usize,
+  sharpness: usize, bd: usize
 ) {
   let mut s = 0;
   let flat = 1 << bd - 8;
@@ -608,7 +699,8 @@ fn deblock_size8(
     let q1 = p[pitch * 5] as i32;
     let q2 = p[pitch * 6] as i32;
     let q3 = p[pitch * 7] as i32;
-    if mask8(p3, p2, p1, p0, q0, q1, q2, q3, bd - 8) <= level {
+    let mask = mask8(p3, p2, p1, p0, q0, q1, q2, q3);
+    if mask_to_level(mask, bd - 8, sharpness) <= level {
       let x: [i32; 6];
       if flat8(p3, p2, p1, p0, q0, q1, q2, q3) <= flat {
         x = filter_wide8_6(p3, p2, p1, p0, q0, q1, q2, q3);
@@ -626,16 +718,16 @@ fn deblock_size8(
 }
 
 // Assumes rec[0] and src[0] are set 4 taps back from the edge.
-// Accesses eight taps, accumulates six pixels into the tally
+// Accesses eight taps, compares six pixels per line
 fn sse_size8(
-  rec: &[u16], src: &[u16], tally: &mut [i64; MAX_LOOP_FILTER + 2],
-  rec_pitch: usize, src_pitch: usize, rec_stride: usize, src_stride: usize,
-  bd: usize
-) {
+  rec: &[u16], src: &[u16], rec_pitch: usize, src_pitch: usize,
+  rec_stride: usize, src_stride: usize, bd: usize
+) -> [LineSse; 4] {
+  let mut lines = [LineSse::default(); 4];
   let mut rec_s = 0;
   let mut src_s = 0;
   let flat = 1 << bd - 8;
-  for _i in 0..4 {
+  for line in lines.iter_mut() {
     let p = &rec[rec_s..]; // eight taps
     let a = &src[src_s + src_pitch..]; // six pixels to compare so offset one forward
     let p3 = p[0] as i32;
@@ -649,54 +741,32 @@ fn sse_size8(
 
     // Four possibilities: no filter, wide8, narrow2 and narrow4
     let none: [_; 6] = [p2, p1, p0, q0, q1, q2];
-    let wide8: [_; 6] = filter_wide8_6(p3, p2, p1, p0, q0, q1, q2, q3);
-    let narrow2: [_; 6] = filter_narrow2_6(p2, p1, p0, q0, q1, q2, bd - 8);
-    let narrow4: [_; 6] = filter_narrow4_6(p2, p1, p0, q0, q1, q2, bd - 8);
+    let sse_none = stride_sse(a, &none, src_pitch);
 
     // mask8 sets the dividing line for filter vs no filter
     // flat8 decides between wide and narrow filters (unrelated to level)
     // nhev4 sets the dividing line between narrow2 and narrow4
-    let mask = clamp(
-      mask8(p3, p2, p1, p0, q0, q1, q2, q3, bd - 8),
-      1,
-      MAX_LOOP_FILTER + 1
-    ) as usize;
-    let flatp = flat8(p3, p2, p1, p0, q0, q1, q2, q3) <= flat;
-    let nhev =
-      clamp(nhev4(p1, p0, q0, q1, bd - 8), mask, MAX_LOOP_FILTER + 1) as usize;
-
-    // sse for each; short-circuit the 'special' no-op cases.
-    let sse_none = stride_sse(a, &none, src_pitch);
-    let sse_wide8 = if flatp && mask <= MAX_LOOP_FILTER {
-      stride_sse(a, &wide8, src_pitch)
+    let mask = mask8(p3, p2, p1, p0, q0, q1, q2, q3);
+    *line = if flat8(p3, p2, p1, p0, q0, q1, q2, q3) <= flat {
+      let wide8: [_; 6] = filter_wide8_6(p3, p2, p1, p0, q0, q1, q2, q3);
+      let sse_wide8 = stride_sse(a, &wide8, src_pitch);
+      LineSse { mask, hev: 0, none: sse_none, low: sse_wide8, high: sse_wide8 }
     } else {
-      sse_none
-    };
-    let sse_narrow2 = if !flatp && nhev != mask {
-      stride_sse(a, &narrow2, src_pitch)
-    } else {
-      sse_none
-    };
-    let sse_narrow4 = if !flatp && nhev <= MAX_LOOP_FILTER {
-      stride_sse(a, &narrow4, src_pitch)
-    } else {
-      sse_none
+      let narrow2: [_; 6] = filter_narrow2_6(p2, p1, p0, q0, q1, q2, bd - 8);
+      let narrow4: [_; 6] = filter_narrow4_6(p2, p1, p0, q0, q1, q2, bd - 8);
+      LineSse {
+        mask,
+        hev: nhev4(p1, p0, q0, q1, bd - 8),
+        none: sse_none,
+        low: stride_sse(a, &narrow2, src_pitch),
+        high: stride_sse(a, &narrow4, src_pitch)
+      }
     };
 
-    // accumulate possible filter values into the tally
-    tally[0] += sse_none;
-    tally[mask] -= sse_none;
-    if flatp {
-      tally[mask] += sse_wide8;
-    } else {
-      tally[mask] += sse_narrow2;
-      tally[nhev] -= sse_narrow2;
-      tally[nhev] += sse_narrow4;
-    }
-
     src_s += src_stride;
     rec_s += rec_stride;
   }
+  lines
 }
 
 fn flat14_outer(
@@ -716,7 +786,8 @@ fn flat14_outer(
 
 // Assumes rec[0] is set 7 taps back from the edge
 fn deblock_size14(
-  rec: &mut [u16], pitch: usize, stride: usize, level: usize, bd: usize
+  rec: &mut [u16], pitch: usize, stride: usize, level: usize,
+  sharpness: usize, bd: usize
 ) {
   let mut s = 0;
   let flat = 1 << bd - 8;
@@ -737,7 +808,8 @@ fn deblock_size14(
     let q5 = p[pitch * 12] as i32;
     let q6 = p[pitch * 13] as i32;
     // 'mask' test
-    if mask8(p3, p2, p1, p0, q0, q1, q2, q3, bd - 8) <= level {
+    let mask = mask8(p3, p2, p1, p0, q0, q1, q2, q3);
+    if mask_to_level(mask, bd - 8, sharpness) <= level {
       let x: [i32; 12];
       // inner flatness test
       if flat8(p3, p2, p1, p0, q0, q1, q2, q3) <= flat {
@@ -794,16 +866,16 @@ fn deblock_size14(
 }
 
 // Assumes rec[0] and src[0] are set 7 taps back from the edge.
-// Accesses fourteen taps, accumulates twelve pixels into the tally
+// Accesses fourteen taps, compares twelve pixels per line
 fn sse_size14(
-  rec: &[u16], src: &[u16], tally: &mut [i64; MAX_LOOP_FILTER + 2],
-  rec_pitch: usize, src_pitch: usize, rec_stride: usize, src_stride: usize,
-  bd: usize
-) {
+  rec: &[u16], src: &[u16], rec_pitch: usize, src_pitch: usize,
+  rec_stride: usize, src_stride: usize, bd: usize
+) -> [LineSse; 4] {
+  let mut lines = [LineSse::default(); 4];
   let mut rec_s = 0;
   let mut src_s = 0;
   let flat = 1 << bd - 8;
-  for _i in 0..4 {
+  for line in lines.iter_mut() {
     let p = &rec[rec_s..]; // 14 taps
     let a = &src[src_s + src_pitch..]; // 12 pixels to compare so offset one forward
     let p6 = p[0] as i32;
@@ -823,104 +895,74 @@ fn sse_size14(
 
     // Five possibilities: no filter, wide14, wide8, narrow2 and narrow4
     let none: [i32; 12] = [p5, p4, p3, p2, p1, p0, q0, q1, q2, q3, q4, q5];
-    let wide14 =
-      filter_wide14_12(p6, p5, p4, p3, p2, p1, p0, q0, q1, q2, q3, q4, q5, q6);
-    let wide8 =
-      filter_wide8_12(p5, p4, p3, p2, p1, p0, q0, q1, q2, q3, q4, q5);
-    let narrow2 = filter_narrow2_12(
-      p5,
-      p4,
-      p3,
-      p2,
-      p1,
-      p0,
-      q0,
-      q1,
-      q2,
-      q3,
-      q4,
-      q5,
-      bd - 8
-    );
-    let narrow4 = filter_narrow4_12(
-      p5,
-      p4,
-      p3,
-      p2,
-      p1,
-      p0,
-      q0,
-      q1,
-      q2,
-      q3,
-      q4,
-      q5,
-      bd - 8
-    );
+    let sse_none = stride_sse(a, &none, src_pitch);
 
     // mask8 sets the dividing line for filter vs no filter
     // flat8 decides between wide and narrow filters (unrelated to level)
     // flat14 decides between wide14 and wide8 filters
     // nhev4 sets the dividing line between narrow2 and narrow4
-    let mask = clamp(
-      mask8(p3, p2, p1, p0, q0, q1, q2, q3, bd - 8),
-      1,
-      MAX_LOOP_FILTER + 1
-    ) as usize;
-    let flat8p = flat8(p3, p2, p1, p0, q0, q1, q2, q3) <= flat;
-    let flat14p = flat14_outer(p6, p5, p4, p0, q0, q4, q5, q6) <= flat;
-    let nhev =
-      clamp(nhev4(p1, p0, q0, q1, bd - 8), mask, MAX_LOOP_FILTER + 1) as usize;
-
-    // sse for each; short-circuit the 'special' no-op cases.
-    let sse_none = stride_sse(a, &none, src_pitch);
-    let sse_wide8 = if flat8p && !flat14p && mask <= MAX_LOOP_FILTER {
-      stride_sse(a, &wide8, src_pitch)
-    } else {
-      sse_none
-    };
-    let sse_wide14 = if flat8p && flat14p && mask <= MAX_LOOP_FILTER {
-      stride_sse(a, &wide14, src_pitch)
-    } else {
-      sse_none
-    };
-    let sse_narrow2 = if !flat8p && nhev != mask {
-      stride_sse(a, &narrow2, src_pitch)
-    } else {
-      sse_none
-    };
-    let sse_narrow4 = if !flat8p && nhev <= MAX_LOOP_FILTER {
-      stride_sse(a, &narrow4, src_pitch)
-    } else {
-      sse_none
-    };
-
-    // accumulate possible filter values into the tally
-    tally[0] += sse_none;
-    tally[mask] -= sse_none;
-    if flat8p {
-      if flat14p {
-        tally[mask] += sse_wide14;
+    let mask = mask8(p3, p2, p1, p0, q0, q1, q2, q3);
+    *line = if flat8(p3, p2, p1, p0, q0, q1, q2, q3) <= flat {
+      let wide = if flat14_outer(p6, p5, p4, p0, q0, q4, q5, q6) <= flat {
+        filter_wide14_12(p6, p5, p4, p3, p2, p1, p0, q0, q1, q2, q3, q4, q5, q6)
       } else {
-        tally[mask] += sse_wide8;
-      }
+        filter_wide8_12(p5, p4, p3, p2, p1, p0, q0, q1, q2, q3, q4, q5)
+      };
+      let sse_wide = stride_sse(a, &wide, src_pitch);
+      LineSse { mask, hev: 0, none: sse_none, low: sse_wide, high: sse_wide }
     } else {
-      tally[mask] += sse_narrow2;
-      tally[nhev] -= sse_narrow2;
-      tally[nhev] += sse_narrow4;
-    }
+      let narrow2 = filter_narrow2_12(
+        p5,
+        p4,
+        p3,
+        p2,
+        p1,
+        p0,
+        q0,
+        q1,
+        q2,
+        q3,
+        q4,
+        q5,
+        bd - 8
+      );
+      let narrow4 = filter_narrow4_12(
+        p5,
+        p4,
+        p3,
+        p2,
+        p1,
+        p0,
+        q0,
+        q1,
+        q2,
+        q3,
+        q4,
+        q5,
+        bd - 8
+      );
+      LineSse {
+        mask,
+        hev: nhev4(p1, p0, q0, q1, bd - 8),
+        none: sse_none,
+        low: stride_sse(a, &narrow2, src_pitch),
+        high: stride_sse(a, &narrow4, src_pitch)
+      }
+    };
 
     rec_s += rec_stride;
     src_s += src_stride;
   }
+  lines
 }
 
+// `p` holds the rows of the plane from `row0`
 fn filter_v_edge(
   deblock: &DeblockState, bc: &BlockContext, bo: &BlockOffset, p: &mut Plane,
-  pli: usize, bd: usize
+  row0: usize, pli: usize, bd: usize
 ) {
   let block = bc.at(&bo);
-  let tx_edge = bo.x & (block.tx_w - 1) == 0;
+  let tx_edge = bo.x & (tx_size_mi(block, p, pli).0 - 1) == 0;
   if tx_edge {
     let prev_block = deblock_left(bc, bo, p);
     let block_edge = bo.x & (block.n4_w - 1) == 0;
@@ -929,23 +971,25 @@ fn filter_v_edge(
     if filter_size > 0 {
       let level = deblock_level(deblock, block, prev_block, pli, true);
       if level > 0 {
-        let po = bo.plane_offset(&p.cfg);
+        let sharpness = deblock.sharpness as usize;
+        let mut po = bo.plane_offset(&p.cfg);
+        po.y -= row0 as isize;
         let stride = p.cfg.stride;
         let mut plane_slice = p.mut_slice(&po);
         plane_slice.x -= (filter_size >> 1) as isize;
         let slice = plane_slice.as_mut_slice();
         match filter_size {
           4 => {
-            deblock_size4(slice, 1, stride, level, bd);
+            deblock_size4(slice, 1, stride, level, sharpness, bd);
           }
           6 => {
-            deblock_size6(slice, 1, stride, level, bd);
+            deblock_size6(slice, 1, stride, level, sharpness, bd);
           }
           8 => {
-            deblock_size8(slice, 1, stride, level, bd);
+            deblock_size8(slice, 1, stride, level, sharpness, bd);
           }
           14 => {
-            deblock_size14(slice, 1, stride, level, bd);
+            deblock_size14(slice, 1, stride, level, sharpness, bd);
           }
           _ => unreachable!()
         }
@@ -956,84 +1000,45 @@ fn filter_v_edge(
 
 fn sse_v_edge(
   bc: &BlockContext, bo: &BlockOffset, rec_plane: &Plane, src_plane: &Plane,
-  tally: &mut [i64; MAX_LOOP_FILTER + 2], pli: usize, bd: usize
-) {
+  pli: usize, bd: usize
+) -> Option<[LineSse; 4]> {
   let block = bc.at(&bo);
-  let tx_edge = bo.x & (block.tx_w - 1) == 0;
-  if tx_edge {
-    let prev_block = deblock_left(bc, bo, rec_plane);
-    let block_edge = bo.x & (block.n4_w - 1) == 0;
-    let filter_size =
-      deblock_size(block, prev_block, rec_plane, pli, true, block_edge);
-    if filter_size > 0 {
-      let po = bo.plane_offset(&rec_plane.cfg); // rec and src have identical subsampling
-      let rec_slice = rec_plane.slice(&po);
-      let src_slice = src_plane.slice(&po);
-      let rec_tmp = rec_slice.go_left(filter_size >> 1);
-      let src_tmp = src_slice.go_left(filter_size >> 1);
-      let rec = rec_tmp.as_slice();
-      let src = src_tmp.as_slice();
-      match filter_size {
-        4 => {
-          sse_size4(
-            rec,
-            src,
-            tally,
-            1,
-            1,
-            rec_plane.cfg.stride,
-            src_plane.cfg.stride,
-            bd
-          );
-        }
-        6 => {
-          sse_size6(
-            rec,
-            src,
-            tally,
-            1,
-            1,
-            rec_plane.cfg.stride,
-            src_plane.cfg.stride,
-            bd
-          );
-        }
-        8 => {
-          sse_size8(
-            rec,
-            src,
-            tally,
-            1,
-            1,
-            rec_plane.cfg.stride,
-            src_plane.cfg.stride,
-            bd
-          );
-        }
-        14 => {
-          sse_size14(
-            rec,
-            src,
-            tally,
-            1,
-            1,
-            rec_plane.cfg.stride,
-            src_plane.cfg.stride,
-            bd
-          );
-        }
-        _ => unreachable!()
-      }
-    }
+  let tx_edge = bo.x & (tx_size_mi(block, rec_plane, pli).0 - 1) == 0;
+  if !tx_edge {
+    return None;
+  }
+  let prev_block = deblock_left(bc, bo, rec_plane);
+  let block_edge = bo.x & (block.n4_w - 1) == 0;
+  let filter_size =
+    deblock_size(block, prev_block, rec_plane, pli, true, block_edge);
+  if filter_size == 0 {
+    return None;
   }
+  let po = bo.plane_offset(&rec_plane.cfg); // rec and src have identical subsampling
+  let rec_slice = rec_plane.slice(&po);
+  let src_slice = src_plane.slice(&po);
+  let rec_tmp = rec_slice.go_left(filter_size >> 1);
+  let src_tmp = src_slice.go_left(filter_size >> 1);
+  let rec = rec_tmp.as_slice();
+  let src = src_tmp.as_slice();
+  let rec_stride = rec_plane.cfg.stride;
+  let src_stride = src_plane.cfg.stride;
+  Some(match filter_size {
+    4 => sse_size4(rec, src, 1, 1, rec_stride, src_stride, bd),
+    6 => sse_size6(rec, src, 1, 1, rec_stride, src_stride, bd),
+    8 => sse_size8(rec, src, 1, 1, rec_stride, src_stride, bd),
+    14 => sse_size14(rec, src, 1, 1, rec_stride, src_stride, bd),
+    _ => unreachable!()
+  })
 }
 
+// `p` holds the rows of the plane from `row0`
 fn filter_h_edge(
   deblock: &DeblockState, bc: &BlockContext, bo: &BlockOffset, p: &mut Plane,
-  pli: usize, bd: usize
+  row0: usize, pli: usize, bd: usize
 ) {
   let block = bc.at(&bo);
-  let tx_edge = bo.y & (block.tx_h - 1) == 0;
+  let tx_edge = bo.y & (tx_size_mi(block, p, pli).1 - 1) == 0;
   if tx_edge {
     let prev_block = deblock_up(bc, bo, p);
     let block_edge = bo.y & (block.n4_h - 1) == 0;
@@ -1042,23 +1047,25 @@ fn filter_h_edge(
     if filter_size > 0 {
       let level = deblock_level(deblock, block, prev_block, pli, false);
       if level > 0 {
-        let po = bo.plane_offset(&p.cfg);
+        let sharpness = deblock.sharpness as usize;
+        let mut po = bo.plane_offset(&p.cfg);
+        po.y -= row0 as isize;
         let stride = p.cfg.stride;
         let mut plane_slice = p.mut_slice(&po);
         plane_slice.y -= (filter_size >> 1) as isize;
         let slice = plane_slice.as_mut_slice();
         match filter_size {
           4 => {
-            deblock_size4(slice, stride, 1, level, bd);
+            deblock_size4(slice, stride, 1, level, sharpness, bd);
           }
           6 => {
-            deblock_size6(slice, stride, 1, level, bd);
+            deblock_size6(slice, stride, 1, level, sharpness, bd);
           }
           8 => {
-            deblock_size8(slice, stride, 1, level, bd);
+            deblock_size8(slice, stride, 1, level, sharpness, bd);
           }
           14 => {
-            deblock_size14(slice, stride, 1, level, bd);
+            deblock_size14(slice, stride, 1, level, sharpness, bd);
           }
           _ => unreachable!()
         }
@@ -1069,218 +1076,423 @@ fn filter_h_edge(
 
 fn sse_h_edge(
   bc: &BlockContext, bo: &BlockOffset, rec_plane: &Plane, src_plane: &Plane,
-  tally: &mut [i64; MAX_LOOP_FILTER + 2], pli: usize, bd: usize
-) {
+  pli: usize, bd: usize
+) -> Option<[LineSse; 4]> {
   let block = bc.at(&bo);
-  let tx_edge = bo.y & (block.tx_h - 1) == 0;
-  if tx_edge {
-    let prev_block = deblock_up(bc, bo, rec_plane);
-    let block_edge = bo.y & (block.n4_h - 1) == 0;
-    let filter_size =
-      deblock_size(block, prev_block, rec_plane, pli, true, block_edge);
-    if filter_size > 0 {
-      let po = bo.plane_offset(&rec_plane.cfg); // rec and src have identical subsampling
-      let rec_slice = rec_plane.slice(&po);
-      let src_slice = src_plane.slice(&po);
-      let rec_tmp = rec_slice.go_up(filter_size >> 1);
-      let src_tmp = src_slice.go_up(filter_size >> 1);
-      let rec = rec_tmp.as_slice();
-      let src = src_tmp.as_slice();
-      match filter_size {
-        4 => {
-          sse_size4(
-            rec,
-            src,
-            tally,
-            rec_plane.cfg.stride,
-            src_plane.cfg.stride,
-            1,
-            1,
-            bd
-          );
-        }
-        6 => {
-          sse_size6(
-            rec,
-            src,
-            tally,
-            rec_plane.cfg.stride,
-            src_plane.cfg.stride,
-            1,
-            1,
-            bd
-          );
-        }
-        8 => {
-          sse_size8(
-            rec,
-            src,
-            tally,
-            rec_plane.cfg.stride,
-            src_plane.cfg.stride,
-            1,
-            1,
-            bd
-          );
-        }
-        14 => {
-          sse_size14(
-            rec,
-            src,
-            tally,
-            rec_plane.cfg.stride,
-            src_plane.cfg.stride,
-            1,
-            1,
-            bd
-          );
-        }
-        _ => unreachable!()
-      }
-    }
+  let tx_edge = bo.y & (tx_size_mi(block, rec_plane, pli).1 - 1) == 0;
+  if !tx_edge {
+    return None;
   }
+  let prev_block = deblock_up(bc, bo, rec_plane);
+  let block_edge = bo.y & (block.n4_h - 1) == 0;
+  let filter_size =
+    deblock_size(block, prev_block, rec_plane, pli, false, block_edge);
+  if filter_size == 0 {
+    return None;
+  }
+  let po = bo.plane_offset(&rec_plane.cfg); // rec and src have identical subsampling
+  let rec_slice = rec_plane.slice(&po);
+  let src_slice = src_plane.slice(&po);
+  let rec_tmp = rec_slice.go_up(filter_size >> 1);
+  let src_tmp = src_slice.go_up(filter_size >> 1);
+  let rec = rec_tmp.as_slice();
+  let src = src_tmp.as_slice();
+  let rec_stride = rec_plane.cfg.stride;
+  let src_stride = src_plane.cfg.stride;
+  Some(match filter_size {
+    4 => sse_size4(rec, src, rec_stride, src_stride, 1, 1, bd),
+    6 => sse_size6(rec, src, rec_stride, src_stride, 1, 1, bd),
+    8 => sse_size8(rec, src, rec_stride, src_stride, 1, 1, bd),
+    14 => sse_size14(rec, src, rec_stride, src_stride, 1, 1, bd),
+    _ => unreachable!()
+  })
 }
 
-// Deblocks all edges, vertical and horizontal, in a single plane
-pub fn deblock_plane(
-  deblock: &DeblockState, p: &mut Plane, pli: usize, bc: &mut BlockContext,
-  bd: usize
-) {
-  let xdec = p.cfg.xdec;
-  let ydec = p.cfg.ydec;
+// Edges starting outside of the visible frame area are not filtered, even
+// within the last partially visible MI. Rounded up to whole chroma MIs.
+fn visible_mi_size(
+  fi: &FrameInvariants, bc: &BlockContext, xdec: usize, ydec: usize
+) -> (usize, usize) {
+  let cols = ((fi.width + MI_SIZE - 1) >> MI_SIZE_LOG2).align_power_of_two(xdec);
+  let rows = ((fi.height + MI_SIZE - 1) >> MI_SIZE_LOG2).align_power_of_two(ydec);
+  (cmp::min(bc.cols, cols), cmp::min(bc.rows, rows))
+}
 
+// Whether any edge of plane `pli` is filtered
+fn plane_deblocked(deblock: &DeblockState, pli: usize) -> bool {
   match pli {
-    0 =>
-      if deblock.levels[0] == 0 && deblock.levels[1] == 0 {
-        return;
-      },
-    1 =>
-      if deblock.levels[2] == 0 {
-        return;
-      },
-    2 =>
-      if deblock.levels[3] == 0 {
-        return;
-      },
-    _ => return
+    0 => deblock.levels[0] != 0 || deblock.levels[1] != 0,
+    1 => deblock.levels[2] != 0,
+    _ => deblock.levels[3] != 0
   }
+}
 
-  // vertical edge filtering leads horizonal by one full MI-sized
-  // row (and horizontal filtering doesn't happen along the upper
-  // edge).  Unroll to avoid corner-cases.
-  if bc.rows > 0 {
-    for x in (1 << xdec..bc.cols).step_by(1 << xdec) {
-      filter_v_edge(deblock, bc, &BlockOffset { x, y: 0 }, p, pli, bd);
-    }
-    if bc.rows > 1 << ydec {
-      for x in (1 << xdec..bc.cols).step_by(1 << xdec) {
-        filter_v_edge(
-          deblock,
-          bc,
-          &BlockOffset { x, y: 1 << ydec },
-          p,
-          pli,
-          bd
-        );
+// Splits the MI rows or columns `mis`, by steps of `step`, into up to `n`
+// bands
+fn mi_bands(mis: Range<usize>, step: usize, n: usize) -> Vec<Range<usize>> {
+  let steps = (mis.end - mis.start + step - 1) / step;
+  let band = (steps + n - 1) / n * step;
+  (mis.start..mis.end).step_by(band).map(|y| y..cmp::min(y + band, mis.end)).collect()
+}
+
+// Copy of the rows `rows` of a plane, in which edges are filtered apart
+fn copy_rows(p: &Plane, rows: Range<usize>) -> Plane {
+  let stride = p.cfg.stride;
+  let start = (p.cfg.yorigin + rows.start) * stride;
+  let height = rows.end - rows.start;
+  Plane {
+    data: p.data[start..start + height * stride].to_vec(),
+    cfg: PlaneConfig { alloc_height: height, height, yorigin: 0, ..p.cfg.clone() }
+  }
+}
+
+// Deblocks the edges in the MI rows of filter block row `fby` of a single
+// plane, all vertical then all horizontal ones. The filters of a row only
+// reach into the previous one, so deblocking the rows in order gives the
+// same result as each direction over the whole plane. Within a row, the
+// filters of an edge only reach half of the transforms on each side, so
+// the edges of a direction are independent: the vertical ones are spread
+// over the workers of `pool` by bands of rows, the horizontal ones by bands
+// of columns, each band being filtered in a copy of its rows.
+fn deblock_plane_rows(
+  fi: &FrameInvariants, deblock: &DeblockState, p: &mut Plane, pli: usize,
+  bc: &BlockContext, bd: usize, fby: usize, pool: &WorkerPool
+) {
+  if !plane_deblocked(deblock, pli) {
+    return;
+  }
+  let xdec = p.cfg.xdec;
+  let ydec = p.cfg.ydec;
+  let (cols, rows) = visible_mi_size(fi, bc, xdec, ydec);
+  let y0 = FilterBlockOffset { x: 0, y: fby }.block_offset(0, 0).y;
+  let y1 = cmp::min(rows, FilterBlockOffset { x: 0, y: fby + 1 }.block_offset(0, 0).y);
+  if y0 >= y1 {
+    return;
+  }
+  let plane_row = |y: usize| y << MI_SIZE_LOG2 >> ydec;
+  let plane_col = |x: usize| x << MI_SIZE_LOG2 >> xdec;
+  let stride = p.cfg.stride;
+
+  // No filtering along the left and top edges of the frame
+  let bands = mi_bands(y0..y1, 1 << ydec, pool.threads());
+  let filtered = pool.map(bands.clone(), |band| {
+    let row0 = plane_row(band.start);
+    let mut rows = copy_rows(p, row0..plane_row(band.end));
+    for y in band.step_by(1 << ydec) {
+      for x in (1 << xdec..cols).step_by(1 << xdec) {
+        filter_v_edge(deblock, bc, &BlockOffset { x, y }, &mut rows, row0, pli, bd);
       }
     }
+    rows
+  });
+  for (band, rows) in bands.iter().zip(filtered) {
+    let start = (p.cfg.yorigin + plane_row(band.start)) * stride;
+    p.data[start..start + rows.data.len()].copy_from_slice(&rows.data);
   }
 
-  // filter rows where vertical and horizontal edge filtering both
-  // happen (horizontal edge filtering lags vertical by one row).
-  for y in ((2 << ydec)..bc.rows).step_by(1 << ydec) {
-    // Check for vertical edge at first MI block boundary on this row
-    if 1 << xdec < bc.cols {
-      filter_v_edge(deblock, bc, &BlockOffset { x: 1 << xdec, y }, p, pli, bd);
+  // The horizontal edges of the row read up to 7 rows above it
+  let h_y0 = cmp::max(y0, 1 << ydec);
+  if h_y0 >= y1 {
+    return;
+  }
+  let row0 = plane_row(h_y0).saturating_sub(8);
+  let row1 = plane_row(y1);
+  let bands = mi_bands(0..cols, 1 << xdec, pool.threads());
+  let filtered = pool.map(bands.clone(), |band| {
+    let mut rows = copy_rows(p, row0..row1);
+    for y in (h_y0..y1).step_by(1 << ydec) {
+      for x in band.clone().step_by(1 << xdec) {
+        filter_h_edge(deblock, bc, &BlockOffset { x, y }, &mut rows, row0, pli, bd);
+      }
     }
-    // run the rest of the row with both vertical and horizontal edge filtering.
-    // Horizontal lags vertical edge by one row and two columns.
-    for x in (2 << xdec..bc.cols).step_by(1 << xdec) {
-      filter_v_edge(deblock, bc, &BlockOffset { x, y }, p, pli, bd);
-      filter_h_edge(
-        deblock,
-        bc,
-        &BlockOffset { x: x - (2 << xdec), y: y - (1 << ydec) },
-        p,
-        pli,
-        bd
-      );
+    rows
+  });
+  let xorigin = p.cfg.xorigin;
+  let yorigin = p.cfg.yorigin;
+  for (band, rows) in bands.iter().zip(filtered) {
+    let band_cols = xorigin + plane_col(band.start)..xorigin + plane_col(band.end);
+    for y in 0..row1 - row0 {
+      let start = (yorigin + row0 + y) * stride;
+      p.data[start..][band_cols.clone()]
+        .copy_from_slice(&rows.data[y * stride..][band_cols.clone()]);
     }
-    // ..and the last two horizontal edges for the row
-    if bc.cols - (2 << xdec) > 0 {
-      filter_h_edge(
-        deblock,
-        bc,
-        &BlockOffset { x: bc.cols - (2 << xdec), y: y - (1 << ydec) },
-        p,
-        pli,
-        bd
-      );
-      if bc.cols - (1 << xdec) > 0 {
-        filter_h_edge(
-          deblock,
-          bc,
-          &BlockOffset { x: bc.cols - (1 << xdec), y: y - (1 << ydec) },
-          p,
-          pli,
-          bd
-        );
+  }
+}
+
+// Deblocks all edges in all planes of filter block row `fby`. Its pixels
+// are final once the next row is deblocked.
+pub fn deblock_filter_rows(
+  fi: &FrameInvariants, deblock: &DeblockState, rec: &mut Frame,
+  bc: &BlockContext, fby: usize, pool: &WorkerPool
+) {
+  for pli in 0..PLANES {
+    deblock_plane_rows(
+      fi,
+      deblock,
+      &mut rec.planes[pli],
+      pli,
+      bc,
+      fi.sequence.bit_depth,
+      fby,
+      pool
+    );
+  }
+}
+
+// Filters the edges of one direction in the MI rows `rows` of a plane, in
+// the order of the separate passes of the spec
+pub fn deblock_plane_pass(
+  fi: &FrameInvariants, deblock: &DeblockState, p: &mut Plane, pli: usize,
+  bc: &BlockContext, bd: usize, vertical: bool, rows: Range<usize>
+) {
+  let xdec = p.cfg.xdec;
+  let ydec = p.cfg.ydec;
+  let (cols, visible_rows) = visible_mi_size(fi, bc, xdec, ydec);
+
+  // No filtering along the left and top edges of the frame
+  let (x0, y0) = if vertical { (1 << xdec, 0) } else { (0, 1 << ydec) };
+  let y1 = cmp::min(visible_rows, rows.end);
+  for y in (cmp::max(y0, rows.start)..y1).step_by(1 << ydec) {
+    for x in (x0..cols).step_by(1 << xdec) {
+      if vertical {
+        filter_v_edge(deblock, bc, &BlockOffset { x, y }, p, 0, pli, bd);
+      } else {
+        filter_h_edge(deblock, bc, &BlockOffset { x, y }, p, 0, pli, bd);
       }
     }
   }
+}
 
-  // Last horizontal row, vertical is already complete
-  if bc.rows > 1 << ydec {
-    for x in (0..bc.cols).step_by(1 << xdec) {
-      filter_h_edge(
-        deblock,
-        bc,
-        &BlockOffset { x, y: bc.rows - (1 << ydec) },
-        p,
-        pli,
-        bd
-      );
-    }
+// Luma vertical pass over the MI rows `rows`, which is undone by
+// `restore_rows` with the pixel rows it returns
+fn vertical_trial(
+  fi: &FrameInvariants, deblock: &DeblockState, p: &mut Plane,
+  bc: &BlockContext, rows: Range<usize>
+) -> (Range<usize>, Vec<u16>) {
+  let stride = p.cfg.stride;
+  let y0 = cmp::min(rows.start << MI_SIZE_LOG2, p.cfg.height);
+  let y1 = cmp::min(rows.end << MI_SIZE_LOG2, p.cfg.height);
+  let saved = p.data_origin()[y0 * stride..y1 * stride].to_vec();
+  deblock_plane_pass(fi, deblock, p, 0, bc, fi.sequence.bit_depth, true, rows);
+  (y0..y1, saved)
+}
+
+fn restore_rows(p: &mut Plane, (rows, saved): (Range<usize>, Vec<u16>)) {
+  let stride = p.cfg.stride;
+  p.data_origin_mut()[rows.start * stride..rows.end * stride].copy_from_slice(&saved);
+}
+
+// Deblocks all edges in all planes of a frame
+pub fn deblock_filter_frame(
+  fi: &FrameInvariants, deblock: &DeblockState, rec: &mut Frame,
+  bc: &BlockContext, pool: &WorkerPool
+) {
+  for fby in 0..(fi.padded_h + 63) / 64 {
+    deblock_filter_rows(fi, deblock, rec, bc, fby, pool);
+  }
+}
+
+// Filtering error by level, each line of pixels being counted from the
+// level at which its filtering changes
+type LevelTally = [i64; MAX_LOOP_FILTER + 2];
+
+// Tallies of the levels signaled for Y vertical, Y horizontal, U and V
+// edges, by block class
+type ClassTallies = [[LevelTally; PLANES + 1]; DEBLOCK_CLASSES];
+
+// Index of the level applying to the edges of a plane and direction
+fn level_index(pli: usize, vertical: bool) -> usize {
+  if pli > 0 {
+    pli + 1
+  } else if vertical {
+    0
+  } else {
+    1
+  }
+}
+
+fn identity_levels() -> [usize; MAX_LOOP_FILTER + 2] {
+  let mut levels = [0; MAX_LOOP_FILTER + 2];
+  for (i, level) in levels.iter_mut().enumerate() {
+    *level = i;
+  }
+  levels
+}
+
+// Turns counts at each level into the error at each level
+fn accumulate_tally(tally: &mut LevelTally) {
+  for i in 1..=MAX_LOOP_FILTER {
+    tally[i] += tally[i - 1];
   }
 }
 
-// sse count of all edges in a single plane, accumulates into vertical and horizontal counts
-fn sse_plane(
-  rec: &Plane, src: &Plane, v_sse: &mut [i64; MAX_LOOP_FILTER + 2],
-  h_sse: &mut [i64; MAX_LOOP_FILTER + 2], pli: usize, bc: &mut BlockContext,
-  bd: usize
+// Visits the filtered edges of one direction in the MI rows `rows` of a
+// plane with the squared errors of their filtering outcomes
+fn sse_plane_edges<F: FnMut(&BlockOffset, &[LineSse; 4])>(
+  fi: &FrameInvariants, rec: &Plane, src: &Plane, pli: usize,
+  bc: &BlockContext, bd: usize, vertical: bool, rows: Range<usize>, mut f: F
 ) {
   let xdec = rec.cfg.xdec;
   let ydec = rec.cfg.ydec;
+  let (cols, visible_rows) = visible_mi_size(fi, bc, xdec, ydec);
+
+  // No filtering along the left and top edges of the frame
+  let (x0, y0) = if vertical { (1 << xdec, 0) } else { (0, 1 << ydec) };
+  let y1 = cmp::min(visible_rows, rows.end);
+  for y in (cmp::max(y0, rows.start)..y1).step_by(1 << ydec) {
+    for x in (x0..cols).step_by(1 << xdec) {
+      let bo = BlockOffset { x, y };
+      let lines = if vertical {
+        sse_v_edge(bc, &bo, rec, src, pli, bd)
+      } else {
+        sse_h_edge(bc, &bo, rec, src, pli, bd)
+      };
+      if let Some(lines) = lines {
+        f(&bo, &lines);
+      }
+    }
+  }
+}
 
-  // No horizontal edge filtering along top of frame
-  for x in (1 << xdec..bc.cols).step_by(1 << xdec) {
-    sse_v_edge(bc, &BlockOffset { x, y: 0 }, rec, src, v_sse, pli, bd);
+// Tallies the edges of one direction in the MI rows `rows` of a plane by
+// block class for each sharpness.  Horizontal edges are measured on `rec`
+// as given, so they are only exact once it has been filtered vertically.
+fn class_tallies(
+  fi: &FrameInvariants, rec: &Plane, src: &Plane, pli: usize,
+  bc: &BlockContext, vertical: bool, rows: Range<usize>,
+  tallies: &mut [ClassTallies], sharpness: &[usize]
+) {
+  let bd = fi.sequence.bit_depth;
+  let identity = identity_levels();
+  let idx = level_index(pli, vertical);
+  sse_plane_edges(fi, rec, src, pli, bc, bd, vertical, rows, |bo, lines| {
+    let class = deblock_class(bc.at(bo));
+    for (t, &s) in tallies.iter_mut().zip(sharpness) {
+      for line in lines {
+        line.tally(&mut t[class][idx], &identity, s, bd);
+      }
+    }
+  });
+  for t in tallies.iter_mut() {
+    for class in t.iter_mut() {
+      accumulate_tally(&mut class[idx]);
+    }
   }
+}
+
+// Distortion weights of the Y vertical, Y horizontal, U and V levels
+fn level_weights(fi: &FrameInvariants) -> [f64; PLANES + 1] {
+  [1.0, 1.0, fi.dist_scale[1], fi.dist_scale[2]]
+}
 
-  // Unlike actual filtering, we're counting horizontal and vertical
-  // as separable cases.  No need to lag the horizontal processing
-  // behind vertical.
-  for y in (1 << ydec..bc.rows).step_by(1 << ydec) {
-    // No vertical filtering along left edge of frame
-    sse_h_edge(bc, &BlockOffset { x: 0, y }, rec, src, h_sse, pli, bd);
-    for x in (1 << xdec..bc.cols).step_by(1 << xdec) {
-      sse_v_edge(bc, &BlockOffset { x, y }, rec, src, v_sse, pli, bd);
-      sse_h_edge(bc, &BlockOffset { x, y }, rec, src, h_sse, pli, bd);
+// Whether the edges under each level are filtered at all: the whole loop
+// filter is off without luma levels, and each chroma plane without its own
+fn levels_active(deblock: &DeblockState) -> [bool; PLANES + 1] {
+  let luma = deblock.levels[0] != 0 || deblock.levels[1] != 0;
+  [luma, luma, luma && deblock.levels[2] != 0, luma && deblock.levels[3] != 0]
+}
+
+// Weighted filtering error of the frame with the levels and deltas of
+// `deblock`, ignoring block-level deltas
+fn deblock_dist(
+  tallies: &ClassTallies, deblock: &DeblockState,
+  weights: &[f64; PLANES + 1]
+) -> f64 {
+  let active = levels_active(deblock);
+  let mut dist = 0.0;
+  for i in 0..PLANES + 1 {
+    let mut sse = 0;
+    for (class, tally) in tallies.iter().enumerate() {
+      let level = if active[i] {
+        class_level(deblock, deblock.levels[i] as usize, class)
+      } else {
+        0
+      };
+      sse += tally[i][level];
     }
+    dist += weights[i] * sse as f64;
   }
+  dist
+}
+// Header bits of the reference and mode deltas (spec 5.9.11)
+fn deltas_bits(
+  deblock: &DeblockState, prev: &([i8; REF_FRAMES], [i8; 2])
+) -> u32 {
+  if !deblock.deltas_enabled || !deblock.delta_updates_enabled {
+    return 0;
+  }
+  let changed = deblock.ref_deltas.iter().zip(prev.0.iter())
+    .chain(deblock.mode_deltas.iter().zip(prev.1.iter()))
+    .filter(|&(a, b)| a != b)
+    .count() as u32;
+  (REF_FRAMES + 2) as u32 + 7 * changed
 }
 
-// Deblocks all edges in all planes of a frame
-pub fn deblock_filter_frame(
-  fs: &mut FrameState, bc: &mut BlockContext, bit_depth: usize
-) {
-  for pli in 0..PLANES {
-    deblock_plane(&fs.deblock, &mut fs.rec.planes[pli], pli, bc, bit_depth);
+fn deblock_cost(
+  tallies: &ClassTallies, deblock: &DeblockState,
+  weights: &[f64; PLANES + 1], prev: &([i8; REF_FRAMES], [i8; 2]),
+  lambda: f64
+) -> f64 {
+  let mut deblock = *deblock;
+  deblock.delta_updates_enabled =
+    deblock.ref_deltas != prev.0 || deblock.mode_deltas != prev.1;
+  deblock_dist(tallies, &deblock, weights)
+    + lambda * deltas_bits(&deblock, prev) as f64
+}
+
+// Coordinate descent on the levels, then on the reference and mode deltas
+// when they are enabled
+fn deblock_descent(
+  tallies: &ClassTallies, deblock: &mut DeblockState,
+  weights: &[f64; PLANES + 1], prev: &([i8; REF_FRAMES], [i8; 2]),
+  lambda: f64, levels: &[usize]
+) -> f64 {
+  let mut best = deblock_cost(tallies, deblock, weights, prev, lambda);
+  for _pass in 0..4 {
+    let start = best;
+    for &i in levels {
+      for level in 0..=MAX_LOOP_FILTER as u8 {
+        let mut candidate = *deblock;
+        candidate.levels[i] = level;
+        let cost = deblock_cost(tallies, &candidate, weights, prev, lambda);
+        if cost < best {
+          best = cost;
+          *deblock = candidate;
+        }
+      }
+    }
+    if deblock.deltas_enabled {
+      for r in 0..REF_FRAMES + 2 {
+        for delta in -(MAX_LOOP_FILTER as i8)..=MAX_LOOP_FILTER as i8 {
+          let mut candidate = *deblock;
+          if r < REF_FRAMES {
+            candidate.ref_deltas[r] = delta;
+          } else {
+            candidate.mode_deltas[r - REF_FRAMES] = delta;
+          }
+          let cost =
+            deblock_cost(tallies, &candidate, weights, prev, lambda);
+          if cost < best {
+            best = cost;
+            *deblock = candidate;
+          }
+        }
+      }
+    }
+    if best >= start {
+      break;
+    }
   }
+  best
 }
 
-fn sse_optimize(fs: &mut FrameState, bc: &mut BlockContext, bit_depth: usize) {
+// Searches the sharpness, the level of each plane and direction, and the
+// reference and mode deltas on the MI rows `rows`.  Deltas that do not
+// change from the primary reference frame are not signaled again.
+fn sse_optimize(
+  fi: &FrameInvariants, fs: &mut FrameState, bc: &BlockContext,
+  rows: Range<usize>
+) {
   assert!(MAX_LOOP_FILTER < 999);
   // i64 allows us to accumulate a total of ~ 35 bits worth of pixels
   assert!(
@@ -1288,58 +1500,81 @@ fn sse_optimize(fs: &mut FrameState, bc: &mut BlockContext, bit_depth: usize) {
       < 35
   );
 
+  let sharpness: Vec<usize> = (0..8).collect();
+  let mut tallies =
+    vec![[[[0; MAX_LOOP_FILTER + 2]; PLANES + 1]; DEBLOCK_CLASSES]; sharpness.len()];
   for pli in 0..PLANES {
-    let mut v_tally: [i64; MAX_LOOP_FILTER + 2] = [0; MAX_LOOP_FILTER + 2];
-    let mut h_tally: [i64; MAX_LOOP_FILTER + 2] = [0; MAX_LOOP_FILTER + 2];
-
-    sse_plane(
-      &fs.rec.planes[pli],
-      &fs.input.planes[pli],
-      &mut v_tally,
-      &mut h_tally,
-      pli,
-      bc,
-      bit_depth
-    );
-
-    for i in 1..=MAX_LOOP_FILTER {
-      v_tally[i] += v_tally[i - 1];
-      h_tally[i] += h_tally[i - 1];
+    for &vertical in &[true, false] {
+      class_tallies(
+        fi,
+        &fs.rec.planes[pli],
+        &fs.input.planes[pli],
+        pli,
+        bc,
+        vertical,
+        rows.clone(),
+        &mut tallies,
+        &sharpness
+      );
     }
+  }
 
-    match pli {
-      0 => {
-        let mut best_v = 999;
-        let mut best_h = 999;
-        for i in 0..=MAX_LOOP_FILTER {
-          if best_v == 999 || v_tally[best_v] > v_tally[i] {
-            best_v = i;
-          };
-          if best_h == 999 || h_tally[best_h] > h_tally[i] {
-            best_h = i;
-          };
-        }
-        fs.deblock.levels[0] = best_v as u8;
-        fs.deblock.levels[1] = best_h as u8;
+  let weights = level_weights(fi);
+  let prev = fi.prev_deblock_deltas();
+  let lambda = get_lambda(fi);
+  let mut best = (std::f64::MAX, fs.deblock);
+  for (t, &s) in tallies.iter().zip(&sharpness) {
+    let mut deblock = fs.deblock;
+    deblock.sharpness = s as u8;
+    deblock.ref_deltas = prev.0;
+    deblock.mode_deltas = prev.1;
+    for &deltas_enabled in &[false, true] {
+      deblock.deltas_enabled = deltas_enabled;
+      let cost =
+        deblock_descent(t, &mut deblock, &weights, &prev, lambda, &[0, 1, 2, 3]);
+      if cost < best.0 {
+        best = (cost, deblock);
       }
-      1 | 2 => {
-        let mut best = 999;
-        for i in 0..=MAX_LOOP_FILTER {
-          if best == 999
-            || v_tally[best] + h_tally[best] > v_tally[i] + h_tally[i]
-          {
-            best = i;
-          };
-        }
-        fs.deblock.levels[pli + 1] = best as u8;
-      }
-      _ => unreachable!()
     }
   }
+  let mut deblock = best.1;
+
+  // Horizontal edges are filtered after the vertical ones: measure them
+  // again once luma is filtered at the chosen vertical level
+  let mut tallies = [tallies[deblock.sharpness as usize]];
+  for class in tallies[0].iter_mut() {
+    class[1] = [0; MAX_LOOP_FILTER + 2];
+  }
+  let trial = vertical_trial(fi, &deblock, &mut fs.rec.planes[0], bc, rows.clone());
+  class_tallies(
+    fi,
+    &fs.rec.planes[0],
+    &fs.input.planes[0],
+    0,
+    bc,
+    false,
+    rows,
+    &mut tallies,
+    &[deblock.sharpness as usize]
+  );
+  restore_rows(&mut fs.rec.planes[0], trial);
+  deblock_descent(&tallies[0], &mut deblock, &weights, &prev, lambda, &[1]);
+
+  if !deblock.deltas_enabled {
+    deblock.ref_deltas = prev.0;
+    deblock.mode_deltas = prev.1;
+  }
+  deblock.delta_updates_enabled =
+    deblock.ref_deltas != prev.0 || deblock.mode_deltas != prev.1;
+  fs.deblock = deblock;
 }
 
+/// Picks the deblocking levels of the frame from the MI rows `rows`, which
+/// are not deblocked yet
 pub fn deblock_filter_optimize(
-  fi: &FrameInvariants, fs: &mut FrameState, bc: &mut BlockContext) {
+  fi: &FrameInvariants, fs: &mut FrameState, bc: &BlockContext,
+  rows: Range<usize>
+) {
   if fi.config.speed_settings.fast_deblock {
     let q = ac_q(fi.base_q_idx, 0, fi.sequence.bit_depth) as i32;
     let level = clamp(
@@ -1376,6 +1611,213 @@ pub fn deblock_filter_optimize(
     fs.deblock.levels[2] = level;
     fs.deblock.levels[3] = level;
   } else {
-    sse_optimize(fs, bc, fi.sequence.bit_depth);
+    sse_optimize(fi, fs, bc, rows);
+  }
+}
+
+// Approximate cost in bits of a coded delta_lf magnitude, with its sign
+fn delta_lf_bits(abs: usize) -> u32 {
+  match abs {
+    0 => 1,
+    1 | 2 => 3 + abs as u32,
+    _ => 8 + 2 * (abs - 1).ilog() as u32
+  }
+}
+
+/// Picks the deblocking level offsets (delta_lf) coded in superblock row
+/// `sby` once the frame levels are known, before the row is deblocked.
+/// Only the superblocks flagged in `coded` code one, the others keep the
+/// offset of the previous superblock, `prev` before the first one.  With
+/// `pick_shift` the delta_lf resolution is picked on this row, otherwise
+/// the one in `fs.deblock` is kept.  Returns the offset of each superblock
+/// of the row.
+pub fn deblock_block_deltas_optimize(
+  fi: &FrameInvariants, fs: &mut FrameState, bc: &BlockContext, sby: usize,
+  coded: &[bool], prev: i8, pick_shift: bool
+) -> Vec<i8> {
+  let deblock = fs.deblock;
+  let active = levels_active(&deblock);
+  if !active[0] {
+    return vec![prev; coded.len()];
+  }
+
+  // Tally each superblock at the base levels before the reference and
+  // mode deltas
+  let mut base_levels = [[0; MAX_LOOP_FILTER + 2]; DEBLOCK_CLASSES];
+  for (class, base) in base_levels.iter_mut().enumerate() {
+    let mut level = 0;
+    for (target, base) in base.iter_mut().enumerate() {
+      while level <= MAX_LOOP_FILTER
+        && class_level(&deblock, level, class) < target
+      {
+        level += 1;
+      }
+      *base = level;
+    }
+  }
+  let bd = fi.sequence.bit_depth;
+  let sharpness = deblock.sharpness as usize;
+  let sb_mi_log2 = fi.sequence.sb_size_log2() - MI_SIZE_LOG2;
+  let rows = sby << sb_mi_log2..(sby + 1) << sb_mi_log2;
+  let mut tallies =
+    vec![[[0; MAX_LOOP_FILTER + 2]; PLANES + 1]; coded.len()];
+  {
+    let input = &fs.input;
+    let mut tally_edges = |rec: &Plane, pli: usize, vertical: bool| {
+      let idx = level_index(pli, vertical);
+      let src = &input.planes[pli];
+      sse_plane_edges(fi, rec, src, pli, bc, bd, vertical, rows.clone(), |bo, lines| {
+        let base = &base_levels[deblock_class(bc.at(bo))];
+        for line in lines {
+          line.tally(&mut tallies[bo.x >> sb_mi_log2][idx], base, sharpness, bd);
+        }
+      });
+    };
+    for pli in 0..PLANES {
+      for &vertical in &[true, false] {
+        if pli > 0 || vertical {
+          tally_edges(&fs.rec.planes[pli], pli, vertical);
+        }
+      }
+    }
+    // Luma horizontal edges are measured after the vertical ones
+    let trial = vertical_trial(fi, &deblock, &mut fs.rec.planes[0], bc, rows.clone());
+    tally_edges(&fs.rec.planes[0], 0, false);
+    restore_rows(&mut fs.rec.planes[0], trial);
+  }
+  for sb in tallies.iter_mut() {
+    for tally in sb.iter_mut() {
+      accumulate_tally(tally);
+    }
+  }
+
+  let weights = level_weights(fi);
+  let lambda = get_lambda(fi);
+  let sb_dist = |tally: &[LevelTally; PLANES + 1], delta: i32| -> f64 {
+    let mut dist = 0.0;
+    for i in 0..PLANES + 1 {
+      let level = if active[i] {
+        clamp(deblock.levels[i] as i32 + delta, 0, MAX_LOOP_FILTER as i32)
+          as usize
+      } else {
+        0
+      };
+      dist += weights[i] * tally[i][level] as f64;
+    }
+    dist
+  };
+
+  let shifts = if pick_shift {
+    0..4
+  } else {
+    deblock.block_delta_shift..deblock.block_delta_shift + 1
+  };
+  let mut best = (std::f64::MAX, 0, Vec::new());
+  for shift in shifts {
+    let max = MAX_LOOP_FILTER as i32 >> shift;
+    let mut prev = prev as i32 >> shift;
+    let mut cost = 0.0;
+    let mut deltas = Vec::with_capacity(coded.len());
+    for (tally, &coded) in tallies.iter().zip(coded) {
+      if coded {
+        let mut sb_best = (std::f64::MAX, 0);
+        for delta in -max..=max {
+          let bits = delta_lf_bits((delta - prev).abs() as usize);
+          let sb_cost = sb_dist(tally, delta << shift) + lambda * bits as f64;
+          if sb_cost < sb_best.0 {
+            sb_best = (sb_cost, delta);
+          }
+        }
+        cost += sb_best.0;
+        prev = sb_best.1;
+      } else {
+        cost += sb_dist(tally, prev << shift);
+      }
+      deltas.push((prev << shift) as i8);
+    }
+    if cost < best.0 {
+      best = (cost, shift, deltas);
+    }
+  }
+  fs.deblock.block_delta_shift = best.1;
+  best.2
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use encoder::test::loop_filter_frame;
+  use rand::{ChaChaRng, SeedableRng};
+
+  #[test]
+  fn mask_levels_invert_limits() {
+    for sharpness in 0..8 {
+      for shift in 0..3 {
+        for mask in 0..(200 << shift) {
+          let level = limit_to_level(mask, shift, sharpness);
+          for l in 1..=MAX_LOOP_FILTER as i32 {
+            assert_eq!(l >= level, level_to_limit(l, shift, sharpness) >= mask);
+          }
+          let level = blimit_to_level(mask, shift, sharpness);
+          for l in 1..=MAX_LOOP_FILTER as i32 {
+            assert_eq!(l >= level, level_to_blimit(l, shift, sharpness) >= mask);
+          }
+        }
+      }
+    }
+  }
+  #[test]
+  fn sharpness_limits() {
+    // Spec 7.14.4, with the limits scaled up to the bit depth
+    for sharpness in 0..8 {
+      let shift = if sharpness > 4 { 2 } else if sharpness > 0 { 1 } else { 0 };
+      for level in 1..=MAX_LOOP_FILTER as i32 {
+        let limit = if sharpness > 0 {
+          clamp(level >> shift, 1, 9 - sharpness as i32)
+        } else {
+          cmp::max(1, level >> shift)
+        };
+        for bd_shift in 0..3 {
+          assert_eq!(level_to_limit(level, bd_shift, sharpness), limit << bd_shift);
+          assert_eq!(level_to_blimit(level, bd_shift, sharpness),
+                     2 * (level + 2) + limit << bd_shift);
+        }
+      }
+    }
+    assert_eq!(level_to_limit(40, 0, 3), 6);
+    assert_eq!(level_to_blimit(63, 2, 7), 528);
+
+    // A step of 4 inside each side is filtered at level 20 unless the
+    // sharpness caps the limit below it
+    let line = [100, 104, 112, 112];
+    let rec: Vec<u16> = line.iter().cycle().take(16).cloned().collect();
+    let mut smooth = rec.clone();
+    deblock_size4(&mut smooth, 1, 4, 20, 0, 8);
+    assert!(smooth != rec);
+    let mut sharp = rec.clone();
+    deblock_size4(&mut sharp, 1, 4, 20, 7, 8);
+    assert!(sharp == rec);
+  }
+
+  #[test]
+  fn deblock_pool_matches_passes() {
+    let (fi, bc, rec) = loop_filter_frame(&mut ChaChaRng::from_seed([0; 32]));
+    let deblock = DeblockState { levels: [40, 32, 24, 20], ..Default::default() };
+
+    let mut expected = rec.clone();
+    for pli in 0..PLANES {
+      for &vertical in &[true, false] {
+        deblock_plane_pass(&fi, &deblock, &mut expected.planes[pli], pli, &bc, 8, vertical,
+                           0..bc.rows);
+      }
+    }
+    for &threads in &[1, 3] {
+      let mut filtered = rec.clone();
+      deblock_filter_frame(&fi, &deblock, &mut filtered, &bc, &WorkerPool::new(threads));
+      for pli in 0..PLANES {
+        assert!(filtered.planes[pli].data == expected.planes[pli].data);
+      }
+    }
+    assert!(expected.planes[0].data != rec.planes[0].data);
   }
 }
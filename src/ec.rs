@@ -114,7 +114,7 @@ pub struct WriterEncoder {
   low: ec_window
 }
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 pub struct WriterCheckpoint {
   /// Byte length coded/recorded to date
   stream_bytes: usize,
@@ -396,6 +396,21 @@ impl WriterBase<WriterRecorder> {
     self.s.storage.truncate(0);
     self.s.bytes = 0;
   }
+
+  /// Replaces tokens recorded after the given checkpoints.  Each patch
+  /// gives the number of tokens replaced and a Recorder holding their
+  /// replacement.  Recorded tokens do not depend on the coder state, so
+  /// the tokens that follow are unaffected once replayed; the Recorder's
+  /// own bit count becomes approximate.  Checkpoints taken after a resized
+  /// patch no longer point to their token, so all the patches are applied
+  /// at once.
+  pub fn patch(&mut self, mut patches: Vec<(WriterCheckpoint, usize, WriterBase<WriterRecorder>)>) {
+    patches.sort_by_key(|p| p.0.backend_var);
+    for (checkpoint, len, patch) in patches.into_iter().rev() {
+      let start = checkpoint.backend_var;
+      self.s.storage.splice(start..start + len, patch.s.storage);
+    }
+  }
 }
 
 /// Done implementation specific to the Encoder
@@ -667,7 +682,7 @@ impl<W: io::Write> BCodeWriter for BitWriter<W, BigEndian> {
     if n <= 1 {
       return Ok(());
     };
-    let l = 31 ^ ((n - 1) + 1).leading_zeros();
+    let l = 16 - n.leading_zeros();
     let m = (1 << l) - n;
     if v < m {
       self.write(l - 1, v)
@@ -913,4 +928,41 @@ mod test {
     assert_eq!(r.symbol(&cdf), 2);
     assert_eq!(r.symbol(&cdf), 2);
   }
+
+  #[test]
+  fn patched_tokens() {
+    let cdf = [7296, 3819, 1716, 0];
+
+    let mut w = WriterRecorder::new();
+
+    w.symbol(1, &cdf);
+    let c0 = Writer::checkpoint(&mut w);
+    w.literal(3, 0);
+    w.symbol(2, &cdf);
+    let c1 = Writer::checkpoint(&mut w);
+    w.symbol(0, &cdf);
+    w.symbol(0, &cdf);
+
+    // Patched out of order, with a shorter literal and a longer symbol run
+    let mut p0 = WriterRecorder::new();
+    p0.literal(2, 2);
+    let mut p1 = WriterRecorder::new();
+    p1.symbol(1, &cdf);
+    p1.bool(true, 16384);
+    w.patch(vec![(c1, 1, p1), (c0, 3, p0)]);
+
+    let mut e = WriterEncoder::new();
+    w.replay(&mut e);
+    let b = e.done();
+
+    let mut r = Reader::new(&b);
+
+    assert_eq!(r.symbol(&cdf), 1);
+    assert_eq!(r.bool(16384), true);
+    assert_eq!(r.bool(16384), false);
+    assert_eq!(r.symbol(&cdf), 2);
+    assert_eq!(r.symbol(&cdf), 1);
+    assert_eq!(r.bool(16384), true);
+    assert_eq!(r.symbol(&cdf), 0);
+  }
 }
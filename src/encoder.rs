@@ -12,23 +12,32 @@ use cdef::*;
 use context::*;
 use deblock::*;
 use ec::*;
+use film_grain::*;
+use intrabc::*;
 use lrf::*;
 use mc::*;
 use me::*;
+use palette::*;
 use partition::*;
 use plane::*;
+use pool::WorkerPool;
 use quantize::*;
+use qm_tables::*;
 use rdo::*;
+use screen_content::*;
 use segmentation::*;
+use superres::*;
 use transform::*;
 use util::*;
+use warp::*;
 use partition::PartitionType::*;
 
 use bitstream_io::{BitWriter, BigEndian, LittleEndian};
 use std;
-use std::{fmt, io};
+use std::{cmp, fmt, io, mem};
+use std::collections::VecDeque;
 use std::io::Write;
-use std::rc::Rc;
+use std::ops::Range;
 use std::sync::Arc;
 
 extern {
@@ -81,6 +90,28 @@ impl Frame {
         }
     }
 
+    /// Resamples the `src_w`x`src_h` area of this frame to a new frame of
+    /// `width`x`height`.
+    pub fn resample(
+        &self, src_w: usize, src_h: usize, width: usize, height: usize,
+        chroma_sampling: ChromaSampling
+    ) -> Frame {
+        let mut dst = Frame::new(
+            width.align_power_of_two(3), height.align_power_of_two(3), chroma_sampling
+        );
+        for p in 0..3 {
+            let xdec = self.planes[p].cfg.xdec;
+            let ydec = self.planes[p].cfg.ydec;
+            dst.planes[p].resample_from(
+                &self.planes[p],
+                (src_w + xdec) >> xdec, (src_h + ydec) >> ydec,
+                (width + xdec) >> xdec, (height + ydec) >> ydec
+            );
+        }
+        dst.pad(width, height);
+        dst
+    }
+
     /// Returns a `PixelIter` containing the data of this frame's planes in YUV format.
     /// Each point in the `PixelIter` is a triple consisting of a Y, U, and V component.
     /// The `PixelIter` is laid out as contiguous rows, e.g. to get a given 0-indexed row
@@ -144,23 +175,34 @@ impl<'a> Iterator for PixelIter<'a> {
 #[derive(Debug, Clone)]
 pub struct ReferenceFrame {
   pub order_hint: u32,
+  /// Upscaled width and height the frame was coded at
+  pub width: usize,
+  pub height: usize,
   pub frame: Frame,
   pub input_hres: Plane,
   pub input_qres: Plane,
-  pub cdfs: CDFContext
+  pub cdfs: CDFContext,
+  /// Order hints of the references the frame was coded with
+  pub ref_order_hints: [u32; INTER_REFS_PER_FRAME],
+  /// Motion vectors saved for projection, `None` for intra frames
+  pub frame_mvs: Option<FrameMotionVectors>
 }
 
 #[derive(Debug, Clone)]
 pub struct ReferenceFramesSet {
-    pub frames: [Option<Rc<ReferenceFrame>>; (REF_FRAMES as usize)],
-    pub deblock: [DeblockState; (REF_FRAMES as usize)]
+    pub frames: [Option<Arc<ReferenceFrame>>; (REF_FRAMES as usize)],
+    pub deblock: [DeblockState; (REF_FRAMES as usize)],
+    /// Global motion models of each slot, the reference for coding the
+    /// models of frames that use the slot as their primary reference
+    pub gm_params: [[[i32; 6]; ALTREF_FRAME + 1]; (REF_FRAMES as usize)]
 }
 
 impl ReferenceFramesSet {
     pub fn new() -> ReferenceFramesSet {
         ReferenceFramesSet {
             frames: Default::default(),
-            deblock: Default::default()
+            deblock: Default::default(),
+            gm_params: [[DEFAULT_WARP_PARAMS; ALTREF_FRAME + 1]; (REF_FRAMES as usize)]
         }
     }
 }
@@ -187,6 +229,54 @@ impl Default for Tune {
     }
 }
 
+arg_enum!{
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    #[repr(C)]
+    pub enum SuperresMode {
+        None,
+        Fixed,
+        Auto
+    }
+}
+
+impl Default for SuperresMode {
+    fn default() -> Self {
+        SuperresMode::None
+    }
+}
+
+arg_enum!{
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    #[repr(C)]
+    pub enum ScreenContentMode {
+        Off,
+        On,
+        Auto
+    }
+}
+
+impl Default for ScreenContentMode {
+    fn default() -> Self {
+        ScreenContentMode::Auto
+    }
+}
+
+arg_enum!{
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    #[repr(C)]
+    pub enum QmMode {
+        Off,
+        Fixed,
+        Auto
+    }
+}
+
+impl Default for QmMode {
+    fn default() -> Self {
+        QmMode::Off
+    }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 #[repr(C)]
 pub enum ChromaSampling {
@@ -338,7 +428,7 @@ impl Sequence {
             enable_order_hint: true,
             enable_jnt_comp: false,
             enable_ref_frame_mvs: false,
-            enable_warped_motion: false,
+            enable_warped_motion: true,
             enable_superres: false,
             enable_cdef: true,
             enable_restoration: true,
@@ -353,15 +443,29 @@ impl Sequence {
         }
     }
 
+    pub fn sb_size_log2(&self) -> usize {
+        if self.use_128x128_superblock { 7 } else { 6 }
+    }
+
+    pub fn sb_size(&self) -> BlockSize {
+        if self.use_128x128_superblock {
+            BlockSize::BLOCK_128X128
+        } else {
+            BlockSize::BLOCK_64X64
+        }
+    }
+
     pub fn get_relative_dist(&self, a: u32, b: u32) -> i32 {
         let diff = a as i32 - b as i32;
         let m = 1 << self.order_hint_bits_minus_1;
         (diff & (m - 1)) - (diff & m)
     }
 
-    pub fn get_skip_mode_allowed(&self, fi: &FrameInvariants, reference_select: bool) -> bool {
+    /// The pair of references used by skip mode blocks, if skip mode is
+    /// allowed (spec 5.9.22)
+    pub fn get_skip_mode_frames(&self, fi: &FrameInvariants, reference_select: bool) -> Option<[usize; 2]> {
       if fi.intra_only || !reference_select || !self.enable_order_hint {
-        false
+        None
       } else {
         let mut forward_idx: isize = -1;
         let mut backward_idx: isize = -1;
@@ -376,7 +480,7 @@ impl Sequence {
                 forward_hint = ref_hint;
               }
             } else if self.get_relative_dist(ref_hint, fi.order_hint) > 0 {
-              if backward_idx < 0 || self.get_relative_dist(ref_hint, backward_hint) > 0 {
+              if backward_idx < 0 || self.get_relative_dist(ref_hint, backward_hint) < 0 {
                 backward_idx = i as isize;
                 backward_hint = ref_hint;
               }
@@ -384,10 +488,12 @@ impl Sequence {
           }
         }
         if forward_idx < 0 {
-          false
+          None
         } else if backward_idx >= 0 {
-          // set skip_mode_frame
-          true
+          Some([
+            LAST_FRAME + forward_idx.min(backward_idx) as usize,
+            LAST_FRAME + forward_idx.max(backward_idx) as usize
+          ])
         } else {
           let mut second_forward_idx: isize = -1;
           let mut second_forward_hint = 0;
@@ -403,10 +509,12 @@ impl Sequence {
             }
           }
           if second_forward_idx < 0 {
-            false
+            None
           } else {
-            // set skip_mode_frame
-            true
+            Some([
+              LAST_FRAME + forward_idx.min(second_forward_idx) as usize,
+              LAST_FRAME + forward_idx.max(second_forward_idx) as usize
+            ])
           }
         }
       }
@@ -424,6 +532,15 @@ pub struct FrameState {
     pub deblock: DeblockState,
     pub segmentation: SegmentationState,
     pub restoration: RestorationState,
+    /// References coded at a different size, scaled to the size of this
+    /// frame for motion search
+    pub scaled_refs: [Option<ReferenceFrame>; (REF_FRAMES as usize)],
+    pub frame_mvs: Option<FrameMotionVectors>,
+    /// Source blocks by hash for the IntraBC search
+    pub intrabc_hashes: Option<IntraBCHashTable>,
+    /// Source at the upscaled frame size when superres is used, which
+    /// loop restoration is fitted to
+    pub input_upscaled: Option<Arc<Frame>>,
 }
 
 impl FrameState {
@@ -452,6 +569,75 @@ impl FrameState {
             deblock: Default::default(),
             segmentation: Default::default(),
             restoration: rs,
+            scaled_refs: Default::default(),
+            frame_mvs: None,
+            intrabc_hashes: None,
+            input_upscaled: None,
+        }
+    }
+
+    /// Recomputes the downsampled luma used by motion estimation from the
+    /// input at the upscaled frame size, for storage in the reference buffer.
+    pub fn set_reference_input(&mut self, fi: &FrameInvariants, input: &Frame) {
+        let padded_w = fi.upscaled_width.align_power_of_two(3);
+        self.input_hres = Plane::new(
+            padded_w/2, fi.padded_h/2,
+            1, 1,
+            (MAX_SB_SIZE + FRAME_MARGIN) / 2, (MAX_SB_SIZE + FRAME_MARGIN) / 2
+        );
+        self.input_qres = Plane::new(
+            padded_w/4, fi.padded_h/4,
+            2, 2,
+            (MAX_SB_SIZE + FRAME_MARGIN) / 4, (MAX_SB_SIZE + FRAME_MARGIN) / 4
+        );
+        self.input_hres.downsample_from(&input.planes[0]);
+        self.input_hres.pad(fi.upscaled_width, fi.height);
+        self.input_qres.downsample_from(&self.input_hres);
+        self.input_qres.pad(fi.upscaled_width, fi.height);
+    }
+
+    /// Prepares the references whose size differs from the coded frame
+    /// size for motion search. Prediction still uses the original frames.
+    pub fn scale_references(&mut self, fi: &FrameInvariants) {
+        for i in 0..INTER_REFS_PER_FRAME {
+            let r = fi.ref_frames[i] as usize;
+            if let Some(ref rec) = fi.rec_buffer.frames[r] {
+                if self.scaled_refs[r].is_some()
+                    || (rec.width == fi.width && rec.height == fi.height) {
+                    continue;
+                }
+                let mut input_hres = Plane::new(
+                    fi.padded_w/2, fi.padded_h/2,
+                    1, 1,
+                    (MAX_SB_SIZE + FRAME_MARGIN) / 2, (MAX_SB_SIZE + FRAME_MARGIN) / 2
+                );
+                let mut input_qres = Plane::new(
+                    fi.padded_w/4, fi.padded_h/4,
+                    2, 2,
+                    (MAX_SB_SIZE + FRAME_MARGIN) / 4, (MAX_SB_SIZE + FRAME_MARGIN) / 4
+                );
+                input_hres.resample_from(
+                    &rec.input_hres, rec.width / 2, rec.height / 2, fi.width / 2, fi.height / 2
+                );
+                input_hres.pad(fi.width, fi.height);
+                input_qres.resample_from(
+                    &rec.input_qres, rec.width / 4, rec.height / 4, fi.width / 4, fi.height / 4
+                );
+                input_qres.pad(fi.width, fi.height);
+                self.scaled_refs[r] = Some(ReferenceFrame {
+                    order_hint: rec.order_hint,
+                    width: fi.width,
+                    height: fi.height,
+                    frame: rec.frame.resample(
+                        rec.width, rec.height, fi.width, fi.height, fi.sequence.chroma_sampling
+                    ),
+                    input_hres,
+                    input_qres,
+                    cdfs: rec.cdfs,
+                    ref_order_hints: rec.ref_order_hints,
+                    frame_mvs: None
+                });
+            }
         }
     }
 }
@@ -474,9 +660,9 @@ impl Default for DeblockState {
         DeblockState {
             levels: [8,8,4,4],
             sharpness: 0,
-            deltas_enabled: false, // requires delta_q_enabled
+            deltas_enabled: false,
             delta_updates_enabled: false,
-            ref_deltas: [1, 0, 0, 0, 0, -1, -1, -1],
+            ref_deltas: [1, 0, 0, 0, -1, 0, -1, -1],
             mode_deltas: [0, 0],
             block_deltas_enabled: false,
             block_delta_shift: 0,
@@ -534,10 +720,18 @@ pub struct FrameInvariants {
     pub show_existing_frame: bool,
     pub frame_to_show_map_idx: u32,
     pub use_reduced_tx_set: bool,
+    /// Whether blocks choose their transform sizes (TX_MODE_SELECT) rather
+    /// than using the largest ones (TX_MODE_LARGEST)
+    pub tx_mode_select: bool,
     pub reference_mode: ReferenceMode,
+    pub skip_mode_present: bool,
+    /// References of the blocks coded with skip mode
+    pub skip_mode_frames: [usize; 2],
     pub use_prev_frame_mvs: bool,
     pub min_partition_size: BlockSize,
     pub globalmv_transformation_type: [GlobalMVMode; ALTREF_FRAME + 1],
+    /// Global motion model of each reference, in the warp model format
+    pub gm_params: [[i32; 6]; ALTREF_FRAME + 1],
     pub num_tg: usize,
     pub large_scale_tile: bool,
     pub disable_cdf_update: bool,
@@ -549,7 +743,11 @@ pub struct FrameInvariants {
     // after it is decoded.
     pub allow_intrabc: bool,
     pub use_ref_frame_mvs: bool,
+    /// Motion of the references projected onto each 8x8 block, used for
+    /// temporal motion vector candidates when `use_ref_frame_mvs` is set
+    pub motion_field_mvs: Vec<Option<ProjectedMotionVector>>,
     pub is_filter_switchable: bool,
+    pub interpolation_filter: FilterMode, // used when !is_filter_switchable
     pub is_motion_mode_switchable: bool,
     pub disable_frame_end_update_cdf: bool,
     pub allow_warped_motion: bool,
@@ -565,9 +763,20 @@ pub struct FrameInvariants {
     pub base_q_idx: u8,
     pub dc_delta_q: [i8; 3],
     pub ac_delta_q: [i8; 3],
+    /// Weight of the distortion of each plane in RD decisions
+    pub dist_scale: [f64; 3],
+    pub using_qmatrix: bool,
+    pub qm_y: u8,
+    pub qm_u: u8,
+    pub qm_v: u8,
     pub me_range_scale: u8,
     pub use_tx_domain_distortion: bool,
     pub inter_cfg: Option<InterPropsConfig>,
+    pub film_grain_params: Option<FilmGrainParams>,
+    pub superres_denom: u8,
+    pub upscaled_width: usize,
+    /// Workers of the loop filters, shared by the frames of a sequence
+    pub filter_pool: Arc<WorkerPool>,
 }
 
 impl FrameInvariants {
@@ -594,8 +803,8 @@ impl FrameInvariants {
             height,
             padded_w: width.align_power_of_two(3),
             padded_h: height.align_power_of_two(3),
-            sb_width: width.align_power_of_two_and_shift(6),
-            sb_height: height.align_power_of_two_and_shift(6),
+            sb_width: width.align_power_of_two_and_shift(sequence.sb_size_log2()),
+            sb_height: height.align_power_of_two_and_shift(sequence.sb_size_log2()),
             w_in_b: 2 * width.align_power_of_two_and_shift(3), // MiCols, ((width+7)/8)<<3 >> MI_SIZE_LOG2
             h_in_b: 2 * height.align_power_of_two_and_shift(3), // MiRows, ((height+7)/8)<<3 >> MI_SIZE_LOG2
             number: 0,
@@ -609,10 +818,14 @@ impl FrameInvariants {
             show_existing_frame: false,
             frame_to_show_map_idx: 0,
             use_reduced_tx_set,
+            tx_mode_select: config.speed_settings.rdo_tx_size,
             reference_mode: ReferenceMode::SINGLE,
+            skip_mode_present: false,
+            skip_mode_frames: [NONE_FRAME; 2],
             use_prev_frame_mvs: false,
             min_partition_size,
             globalmv_transformation_type: [GlobalMVMode::IDENTITY; ALTREF_FRAME + 1],
+            gm_params: [DEFAULT_WARP_PARAMS; ALTREF_FRAME + 1],
             num_tg: 1,
             large_scale_tile: false,
             disable_cdf_update: false,
@@ -622,7 +835,9 @@ impl FrameInvariants {
             refresh_frame_flags: 0,
             allow_intrabc: false,
             use_ref_frame_mvs: false,
+            motion_field_mvs: Vec::new(),
             is_filter_switchable: false,
+            interpolation_filter: FilterMode::REGULAR,
             is_motion_mode_switchable: false, // 0: only the SIMPLE motion mode will be used.
             disable_frame_end_update_cdf: false,
             allow_warped_motion: false,
@@ -636,13 +851,170 @@ impl FrameInvariants {
             ref_frame_sign_bias: [false; INTER_REFS_PER_FRAME],
             rec_buffer: ReferenceFramesSet::new(),
             base_q_idx: config.quantizer as u8,
-            dc_delta_q: [0; 3],
-            ac_delta_q: [0; 3],
+            dc_delta_q: [config.y_dc_delta_q, config.u_dc_delta_q, config.v_dc_delta_q],
+            ac_delta_q: [0, config.u_ac_delta_q, config.v_ac_delta_q],
+            dist_scale: [1.0; 3],
+            using_qmatrix: false,
+            qm_y: (NUM_QM_LEVELS - 1) as u8,
+            qm_u: (NUM_QM_LEVELS - 1) as u8,
+            qm_v: (NUM_QM_LEVELS - 1) as u8,
             me_range_scale: 1,
             use_tx_domain_distortion: use_tx_domain_distortion,
             inter_cfg: None,
+            film_grain_params: None,
+            superres_denom: SUPERRES_NUM,
+            upscaled_width: width,
+            filter_pool: Arc::new(WorkerPool::new(config.filter_threads)),
+        }
+    }
+
+  /// Sets the coded frame size and the values derived from it.
+  fn set_coded_size(&mut self, width: usize, height: usize) {
+    self.width = width;
+    self.height = height;
+    self.padded_w = width.align_power_of_two(3);
+    self.padded_h = height.align_power_of_two(3);
+    self.sb_width = width.align_power_of_two_and_shift(self.sequence.sb_size_log2());
+    self.sb_height = height.align_power_of_two_and_shift(self.sequence.sb_size_log2());
+    self.w_in_b = 2 * width.align_power_of_two_and_shift(3);
+    self.h_in_b = 2 * height.align_power_of_two_and_shift(3);
+  }
+
+  /// Sets the frame size before superres downscaling. The coded size is
+  /// derived from it.
+  pub fn set_frame_size(&mut self, width: usize, height: usize) {
+    self.upscaled_width = width;
+    self.height = height;
+    self.apply_superres_cfg();
+  }
+
+  /// Picks the quantizer matrix levels of the frame. In the auto mode the
+  /// weighting is flattened as the AC quantizer of each plane increases, as
+  /// in libaom.
+  fn apply_qm_cfg(&mut self) {
+    let levels: Vec<u8> = self.ac_delta_q.iter().map(|&delta_q| {
+      let qindex = (self.base_q_idx as i32 + delta_q as i32).max(0).min(255);
+      let level = match self.config.qm_mode {
+        QmMode::Off => NUM_QM_LEVELS - 1,
+        QmMode::Fixed => self.config.qm_level as usize,
+        QmMode::Auto => {
+          QM_AUTO_LEVEL_MIN
+            + qindex as usize * (NUM_QM_LEVELS - QM_AUTO_LEVEL_MIN) / 256
+        }
+      };
+      level as u8
+    }).collect();
+    self.qm_y = levels[0];
+    self.qm_u = levels[1];
+    self.qm_v = levels[2];
+    self.using_qmatrix =
+      levels.iter().any(|&level| (level as usize) < NUM_QM_LEVELS - 1);
+  }
+
+  /// Weights the chroma distortion by the squared ratio of the luma and
+  /// chroma AC quantizers, so that the RD search follows the chroma offsets.
+  fn set_dist_scale(&mut self) {
+    let q_y = ac_q(self.base_q_idx, 0, self.sequence.bit_depth) as f64;
+    for p in 1..3 {
+      let q = ac_q(self.base_q_idx, self.ac_delta_q[p], self.sequence.bit_depth) as f64;
+      self.dist_scale[p] = (q_y / q) * (q_y / q);
+    }
+  }
+
+  /// Quantizer matrix of a transform block in plane `p`, if any. Only 2D
+  /// transforms are weighted (spec 7.12.3).
+  pub fn qm_tbl(&self, p: usize, tx_size: TxSize, tx_type: TxType) -> Option<&'static [u8]> {
+    let level = [self.qm_y, self.qm_u, self.qm_v][p];
+    if self.using_qmatrix && (level as usize) < NUM_QM_LEVELS - 1 && tx_type < TxType::IDTX {
+      Some(get_qm_tbl(level, (p > 0) as usize, tx_size))
+    } else {
+      None
+    }
+  }
+
+  /// Picks the superres denominator for the frame and derives the coded
+  /// width from it. Inter frames are kept within the 2:1 downscaling limit
+  /// of prediction from their references.
+  fn apply_superres_cfg(&mut self) {
+    let upscaled_width = self.upscaled_width;
+    let mut denom = match self.config.superres_mode {
+      SuperresMode::None => SUPERRES_NUM,
+      SuperresMode::Fixed => self.config.superres_denom,
+      SuperresMode::Auto => {
+        if self.base_q_idx > SUPERRES_AUTO_QTHRESH {
+          cmp::min(SUPERRES_NUM + 1 + ((self.base_q_idx - SUPERRES_AUTO_QTHRESH) >> 3),
+                   SUPERRES_DENOM_MIN + (1 << SUPERRES_DENOM_BITS) - 1)
+        } else {
+          SUPERRES_NUM
         }
+      }
+    };
+    if !self.intra_only {
+      let max_ref_width = self.ref_frames.iter()
+        .filter_map(|&r| self.rec_buffer.frames[r as usize].as_ref())
+        .map(|rec| rec.width)
+        .max()
+        .unwrap_or(0);
+      while denom > SUPERRES_NUM
+        && 2 * superres_coded_width(upscaled_width, denom) < max_ref_width {
+        denom -= 1;
+      }
+    }
+    self.superres_denom = denom;
+    let height = self.height;
+    self.set_coded_size(superres_coded_width(upscaled_width, denom), height);
+  }
+
+  /// Global motion models the models of this frame are coded against.
+  pub fn prev_gm_params(&self) -> [[i32; 6]; ALTREF_FRAME + 1] {
+    if self.primary_ref_frame == PRIMARY_REF_NONE {
+      [DEFAULT_WARP_PARAMS; ALTREF_FRAME + 1]
+    } else {
+      self.rec_buffer.gm_params[self.ref_frames[self.primary_ref_frame as usize] as usize]
+    }
+  }
+
+  /// Deblocking reference and mode deltas inherited from the primary
+  /// reference frame, which are kept when they are not updated.
+  pub fn prev_deblock_deltas(&self) -> ([i8; REF_FRAMES], [i8; 2]) {
+    if self.primary_ref_frame == PRIMARY_REF_NONE {
+      let deblock = DeblockState::default();
+      (deblock.ref_deltas, deblock.mode_deltas)
+    } else {
+      let deblock = self.rec_buffer.deblock[self.ref_frames[self.primary_ref_frame as usize] as usize];
+      (deblock.ref_deltas, deblock.mode_deltas)
+    }
+  }
+
+  /// Interpolation filters of the inter blocks that do not code them.
+  pub fn default_interp_filter(&self) -> [FilterMode; 2] {
+    if self.is_filter_switchable {
+      [FilterMode::REGULAR; 2]
+    } else {
+      [self.interpolation_filter; 2]
+    }
+  }
+
+  /// Absolute distance in display order between the frame and a reference.
+  pub fn ref_frame_dist(&self, ref_frame: usize) -> i32 {
+    match self.rec_buffer.frames[self.ref_frames[ref_frame - LAST_FRAME] as usize] {
+      Some(ref rec) => self.sequence.get_relative_dist(rec.order_hint, self.order_hint).abs(),
+      None => 0
+    }
+  }
+
+  /// Order hint of a reference, 0 if the slot is empty.
+  pub fn ref_order_hint(&self, ref_frame: usize) -> u32 {
+    match self.rec_buffer.frames[self.ref_frames[ref_frame - LAST_FRAME] as usize] {
+      Some(ref rec) => rec.order_hint,
+      None => 0
     }
+  }
+
+  fn reset_global_motion(&mut self) {
+    self.globalmv_transformation_type = [GlobalMVMode::IDENTITY; ALTREF_FRAME + 1];
+    self.gm_params = [DEFAULT_WARP_PARAMS; ALTREF_FRAME + 1];
+  }
 
   pub fn new_key_frame(previous_fi: &Self, segment_start_frame: u64) -> Self {
     let mut fi = previous_fi.clone();
@@ -656,8 +1028,18 @@ impl FrameInvariants {
     fi.frame_to_show_map_idx = 0;
     let q_boost = 15;
     fi.base_q_idx = (fi.config.quantizer.max(1 + q_boost).min(255 + q_boost) - q_boost) as u8;
+    fi.apply_qm_cfg();
+    fi.set_dist_scale();
+    fi.apply_superres_cfg();
+    if fi.sequence.force_screen_content_tools != 2 {
+      fi.set_screen_content_tools(fi.sequence.force_screen_content_tools != 0);
+    }
     fi.cdef_bits = 3;
     fi.primary_ref_frame = PRIMARY_REF_NONE;
+    fi.reset_global_motion();
+    fi.allow_warped_motion = false;
+    fi.is_motion_mode_switchable = false;
+    fi.use_ref_frame_mvs = false;
     fi.number = segment_start_frame;
     for i in 0..INTER_REFS_PER_FRAME {
       fi.ref_frames[i] = 0;
@@ -665,6 +1047,16 @@ impl FrameInvariants {
     fi
   }
 
+  /// Turns the screen content tools on or off up to the next keyframe:
+  /// palettes, IntraBC on the keyframe and integer motion vectors on the
+  /// inter frames. IntraBC turns off the loop filters, so it is only used
+  /// for screen content.
+  pub fn set_screen_content_tools(&mut self, enable: bool) {
+    self.allow_screen_content_tools = enable as u32;
+    self.force_integer_mv = enable as u32;
+    self.allow_intrabc = enable && self.intra_only && self.upscaled_width == self.width;
+  }
+
   fn apply_inter_props_cfg(&mut self, idx_in_segment: u64) {
     let reorder = !self.config.low_latency;
     let multiref = reorder || self.config.speed_settings.multiref;
@@ -694,6 +1086,17 @@ impl FrameInvariants {
     let mut fi = previous_fi.clone();
     fi.frame_type = FrameType::INTER;
     fi.intra_only = false;
+    fi.allow_intrabc = false;
+    fi.reset_global_motion();
+    fi.allow_warped_motion = fi.sequence.enable_warped_motion && !fi.error_resilient
+      && fi.config.speed_settings.warped_motion && fi.force_integer_mv == 0;
+    fi.is_motion_mode_switchable =
+      fi.allow_warped_motion || fi.config.speed_settings.obmc;
+    // Integer motion vectors are not interpolated
+    fi.is_filter_switchable = fi.config.speed_settings.switchable_interp_filter
+      && fi.force_integer_mv == 0;
+    fi.interpolation_filter = FilterMode::REGULAR;
+    fi.use_ref_frame_mvs = fi.sequence.enable_ref_frame_mvs && !fi.error_resilient;
     fi.apply_inter_props_cfg(idx_in_segment);
     let inter_cfg = fi.inter_cfg.unwrap();
 
@@ -749,6 +1152,8 @@ impl FrameInvariants {
 
     let q_drop = 15 * lvl as usize;
     fi.base_q_idx = (fi.config.quantizer.min(255 - q_drop) + q_drop) as u8;
+    fi.apply_qm_cfg();
+    fi.set_dist_scale();
     fi.cdef_bits = 3 - ((fi.base_q_idx.max(128) - 128) >> 5);
     let second_ref_frame = if !inter_cfg.multiref {
       NONE_FRAME
@@ -803,6 +1208,7 @@ impl FrameInvariants {
     };
     fi.number = number;
     fi.me_range_scale = (inter_cfg.group_src_len >> lvl) as u8;
+    fi.apply_superres_cfg();
     (fi, true)
   }
 }
@@ -892,12 +1298,18 @@ trait UncompressedHeader {
     // End of OBU Headers
 
     fn write_frame_size(&mut self, fi: &FrameInvariants) -> io::Result<()>;
+    fn write_frame_size_params(&mut self, fi: &FrameInvariants, frame_size_override_flag: bool) -> io::Result<()>;
+    fn write_frame_size_with_refs(&mut self, fi: &FrameInvariants) -> io::Result<()>;
+    fn write_render_size(&mut self, fi: &FrameInvariants) -> io::Result<()>;
+    fn write_superres_params(&mut self, fi: &FrameInvariants) -> io::Result<()>;
     fn write_deblock_filter_a(&mut self, fi: &FrameInvariants, deblock: &DeblockState) -> io::Result<()>;
     fn write_deblock_filter_b(&mut self, fi: &FrameInvariants, deblock: &DeblockState) -> io::Result<()>;
     fn write_frame_cdef(&mut self, fi: &FrameInvariants) -> io::Result<()>;
     fn write_frame_lrf(&mut self, fi: &FrameInvariants, rs: &RestorationState) -> io::Result<()>;
     fn write_segment_data(&mut self, fi: &FrameInvariants, segmentation: &SegmentationState) -> io::Result<()>;
+    fn write_quantization_params(&mut self, fi: &FrameInvariants) -> io::Result<()>;
     fn write_delta_q(&mut self, delta_q: i8) -> io::Result<()>;
+    fn write_film_grain_params(&mut self, fi: &FrameInvariants) -> io::Result<()>;
 }
 #[allow(unused)]
 const OP_POINTS_IDC_BITS:usize = 12;
@@ -1131,15 +1543,14 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
                 fi.sequence.force_screen_content_tools);
       }
 
-      if fi.allow_screen_content_tools == 2 {
+      if fi.allow_screen_content_tools != 0 {
         if fi.sequence.force_integer_mv == 2 {
           self.write_bit(fi.force_integer_mv != 0)?;
         } else {
           assert!(fi.force_integer_mv == fi.sequence.force_integer_mv);
         }
       } else {
-        assert!(fi.allow_screen_content_tools ==
-                fi.sequence.force_screen_content_tools);
+        assert!(fi.force_integer_mv == 0);
       }
 
       if fi.sequence.frame_id_numbers_present_flag {
@@ -1150,14 +1561,17 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
         //self.write(frame_id_len, fi.current_frame_id);
       }
 
-      let mut frame_size_override_flag = false;
-      if fi.frame_type == FrameType::SWITCH {
-        frame_size_override_flag = true;
+      let frame_size_override_flag = if fi.frame_type == FrameType::SWITCH {
+        true
       } else if fi.sequence.reduced_still_picture_hdr {
-        frame_size_override_flag = false;
+        false
       } else {
+        let frame_size_override_flag =
+          fi.upscaled_width != fi.sequence.max_frame_width as usize
+          || fi.height != fi.sequence.max_frame_height as usize;
         self.write_bit(frame_size_override_flag)?; // frame size overhead flag
-      }
+        frame_size_override_flag
+      };
 
       if fi.sequence.enable_order_hint {
         let n = fi.sequence.order_hint_bits_minus_1 + 1;
@@ -1205,15 +1619,9 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
       // if KEY or INTRA_ONLY frame
       // FIXME: Not sure whether putting frame/render size here is good idea
       if fi.intra_only {
-        if frame_size_override_flag {
-          unimplemented!();
-        }
-        if fi.sequence.enable_superres {
-          unimplemented!();
-        }
-        self.write_bit(false)?; // render_and_frame_size_different
-        //if render_and_frame_size_different { }
-        if fi.allow_screen_content_tools != 0 && true /* UpscaledWidth == FrameWidth */ {
+        self.write_frame_size_params(fi, frame_size_override_flag)?;
+        self.write_render_size(fi)?;
+        if fi.allow_screen_content_tools != 0 && fi.upscaled_width == fi.width {
           self.write_bit(fi.allow_intrabc)?;
         }
       }
@@ -1240,24 +1648,21 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
               unimplemented!();
             }
           }
-          if fi.error_resilient && frame_size_override_flag {
-            unimplemented!();
+          if frame_size_override_flag && !fi.error_resilient {
+            self.write_frame_size_with_refs(fi)?;
           } else {
-            if frame_size_override_flag {
-               unimplemented!();
-            }
-            if fi.sequence.enable_superres {
-              unimplemented!();
-            }
-            self.write_bit(false)?; // render_and_frame_size_different
+            self.write_frame_size_params(fi, frame_size_override_flag)?;
+            self.write_render_size(fi)?;
           }
           if fi.force_integer_mv != 0 {
           } else {
             self.write_bit(fi.allow_high_precision_mv);
           }
           self.write_bit(fi.is_filter_switchable)?;
+          if !fi.is_filter_switchable {
+            self.write(2, fi.interpolation_filter as u8)?;
+          }
           self.write_bit(fi.is_motion_mode_switchable)?;
-          self.write(2,0)?; // EIGHTTAP_REGULAR
           if fi.error_resilient || !fi.sequence.enable_ref_frame_mvs {
           } else {
             self.write_bit(fi.use_ref_frame_mvs)?;
@@ -1271,11 +1676,11 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
 
       // tile
       self.write_bit(true)?; // uniform_tile_spacing_flag
-      if fi.width > 64 {
+      if fi.sb_width > 1 {
         // TODO: if tile_cols > 1, write more increment_tile_cols_log2 bits
         self.write_bit(false)?; // tile cols
       }
-      if fi.height > 64 {
+      if fi.sb_height > 1 {
         // TODO: if tile_rows > 1, write increment_tile_rows_log2 bits
         self.write_bit(false)?; // tile rows
       }
@@ -1283,32 +1688,19 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
       // write context_update_tile_id and tile_size_bytes_minus_1 }
 
       // quantization
-      assert!(fi.base_q_idx > 0);
-      self.write(8, fi.base_q_idx)?; // base_q_idx
-      self.write_delta_q(fi.dc_delta_q[0])?;
-      assert!(fi.ac_delta_q[0] == 0);
-      let diff_uv_delta = fi.sequence.separate_uv_delta_q
-        && (fi.dc_delta_q[1] != fi.dc_delta_q[2]
-          || fi.ac_delta_q[1] != fi.ac_delta_q[2]);
-      if fi.sequence.separate_uv_delta_q {
-        self.write_bit(diff_uv_delta)?;
-      } else {
-        assert!(fi.dc_delta_q[1] == fi.dc_delta_q[2]);
-        assert!(fi.ac_delta_q[1] == fi.ac_delta_q[2]);
-      }
-      self.write_delta_q(fi.dc_delta_q[1])?;
-      self.write_delta_q(fi.ac_delta_q[1])?;
-      if diff_uv_delta {
-        self.write_delta_q(fi.dc_delta_q[2])?;
-        self.write_delta_q(fi.ac_delta_q[2])?;
-      }
-      self.write_bit(false)?; // no qm
+      self.write_quantization_params(fi)?;
 
       // segmentation
       self.write_segment_data(fi, &fs.segmentation)?;
 
       // delta_q
-      self.write_bit(false)?; // delta_q_present_flag: no delta q
+      if fi.base_q_idx > 0 {
+        self.write_bit(fi.delta_q_present)?;
+      }
+      if fi.delta_q_present {
+        // The quantizer deltas are all zero
+        self.write(2, 0)?; // delta_q_res
+      }
 
       // delta_lf_params in the spec
       self.write_deblock_filter_a(fi, &fs.deblock)?;
@@ -1324,7 +1716,7 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
       // loop restoration
       self.write_frame_lrf(fi, &fs.restoration)?;
 
-      self.write_bit(false)?; // tx mode == TX_MODE_SELECT ?
+      self.write_bit(fi.tx_mode_select)?; // tx mode == TX_MODE_SELECT ?
 
       let mut reference_select = false;
       if !fi.intra_only {
@@ -1332,9 +1724,11 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
         self.write_bit(reference_select)?;
       }
 
-      let skip_mode_allowed = fi.sequence.get_skip_mode_allowed(fi, reference_select);
+      let skip_mode_allowed = fi.sequence.get_skip_mode_frames(fi, reference_select).is_some();
       if skip_mode_allowed {
-        self.write_bit(false)?; // skip_mode_present
+        self.write_bit(fi.skip_mode_present)?;
+      } else {
+        assert!(!fi.skip_mode_present);
       }
 
       if fi.intra_only || fi.error_resilient || !fi.sequence.enable_warped_motion {
@@ -1346,6 +1740,7 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
 
       // global motion
       if !fi.intra_only {
+          let prev_gm_params = fi.prev_gm_params();
           for i in LAST_FRAME..ALTREF_FRAME+1 {
               let mode = fi.globalmv_transformation_type[i];
               self.write_bit(mode != GlobalMVMode::IDENTITY)?;
@@ -1355,30 +1750,24 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
                       self.write_bit(mode == GlobalMVMode::TRANSLATION)?;
                   }
               }
-              match mode {
-                  GlobalMVMode::IDENTITY => { /* Nothing to do */ }
-                  GlobalMVMode::TRANSLATION => {
-                      let mv_x = 0;
-                      let mv_x_ref = 0;
-                      let mv_y = 0;
-                      let mv_y_ref = 0;
-                      let bits = 12 - 6 + 3 - !fi.allow_high_precision_mv as u8;
-                      let bits_diff = 12 - 3 + fi.allow_high_precision_mv as u8;
-                      BCodeWriter::write_s_refsubexpfin(self, (1 << bits) + 1,
-                                                        3, mv_x_ref >> bits_diff,
-                                                        mv_x >> bits_diff)?;
-                      BCodeWriter::write_s_refsubexpfin(self, (1 << bits) + 1,
-                                                        3, mv_y_ref >> bits_diff,
-                                                        mv_y >> bits_diff)?;
-                  }
-                  GlobalMVMode::ROTZOOM => unimplemented!(),
-                  GlobalMVMode::AFFINE => unimplemented!(),
+              let params: &[usize] = match mode {
+                  GlobalMVMode::IDENTITY => &[],
+                  GlobalMVMode::TRANSLATION => &[0, 1],
+                  GlobalMVMode::ROTZOOM => &[2, 3, 0, 1],
+                  GlobalMVMode::AFFINE => &[2, 3, 4, 5, 0, 1]
               };
+              for &j in params {
+                  let hp = fi.allow_high_precision_mv;
+                  let (abs_bits, _) = gm_param_bits(mode, j, hp);
+                  BCodeWriter::write_s_refsubexpfin(self, (1 << abs_bits) + 1, 3,
+                      gm_param_to_coded(prev_gm_params[i][j], mode, j, hp) as i16,
+                      gm_param_to_coded(fi.gm_params[i][j], mode, j, hp) as i16)?;
+              }
           }
       }
 
-      if fi.sequence.film_grain_params_present && fi.show_frame {
-          unimplemented!();
+      if fi.sequence.film_grain_params_present && (fi.show_frame || fi.showable_frame) {
+          self.write_film_grain_params(fi)?;
       }
 
       if fi.large_scale_tile {
@@ -1394,14 +1783,57 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
     fn write_frame_size(&mut self, fi: &FrameInvariants) -> io::Result<()> {
         // width_bits and height_bits will have to be moved to the sequence header OBU
         // when we add support for it.
-        let width_bits = 32 - (fi.width as u32).leading_zeros();
-        let height_bits = 32 - (fi.height as u32).leading_zeros();
+        let width_bits = 32 - fi.sequence.max_frame_width.leading_zeros();
+        let height_bits = 32 - fi.sequence.max_frame_height.leading_zeros();
         assert!(width_bits <= 16);
         assert!(height_bits <= 16);
         self.write(4, width_bits - 1)?;
         self.write(4, height_bits - 1)?;
-        self.write(width_bits, (fi.width - 1) as u16)?;
-        self.write(height_bits, (fi.height - 1) as u16)?;
+        self.write(width_bits, (fi.sequence.max_frame_width - 1) as u16)?;
+        self.write(height_bits, (fi.sequence.max_frame_height - 1) as u16)?;
+        Ok(())
+    }
+
+    fn write_frame_size_params(&mut self, fi: &FrameInvariants, frame_size_override_flag: bool) -> io::Result<()> {
+        if frame_size_override_flag {
+            let width_bits = 32 - fi.sequence.max_frame_width.leading_zeros();
+            let height_bits = 32 - fi.sequence.max_frame_height.leading_zeros();
+            self.write(width_bits, (fi.upscaled_width - 1) as u16)?;
+            self.write(height_bits, (fi.height - 1) as u16)?;
+        }
+        self.write_superres_params(fi)
+    }
+
+    fn write_frame_size_with_refs(&mut self, fi: &FrameInvariants) -> io::Result<()> {
+        // Render sizes always match frame sizes, so a reference of the same
+        // size also carries the right render size.
+        for i in 0..INTER_REFS_PER_FRAME {
+            let found_ref = match fi.rec_buffer.frames[fi.ref_frames[i] as usize] {
+                Some(ref rec) => rec.width == fi.upscaled_width && rec.height == fi.height,
+                None => false
+            };
+            self.write_bit(found_ref)?;
+            if found_ref {
+                return self.write_superres_params(fi);
+            }
+        }
+        self.write_frame_size_params(fi, true)?;
+        self.write_render_size(fi)
+    }
+
+    fn write_render_size(&mut self, _fi: &FrameInvariants) -> io::Result<()> {
+        self.write_bit(false)?; // render_and_frame_size_different
+        Ok(())
+    }
+
+    fn write_superres_params(&mut self, fi: &FrameInvariants) -> io::Result<()> {
+        if fi.sequence.enable_superres {
+            let use_superres = fi.superres_denom != SUPERRES_NUM;
+            self.write_bit(use_superres)?;
+            if use_superres {
+                self.write(SUPERRES_DENOM_BITS, fi.superres_denom - SUPERRES_DENOM_MIN)?;
+            }
+        }
         Ok(())
     }
 
@@ -1419,6 +1851,10 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
     }
 
     fn write_deblock_filter_b(&mut self, fi: &FrameInvariants, deblock: &DeblockState) -> io::Result<()> {
+        if fi.allow_intrabc {
+            assert!(deblock.levels[0] == 0 && deblock.levels[1] == 0);
+            return Ok(());
+        }
         assert!(deblock.levels[0] < 64);
         self.write(6, deblock.levels[0])?; // loop deblocking filter level 0
         assert!(deblock.levels[1] < 64);
@@ -1435,11 +1871,7 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
             self.write_bit(deblock.delta_updates_enabled)?; // deltas updates enabled
             if deblock.delta_updates_enabled {
                 // conditionally write ref delta updates
-                let prev_ref_deltas = if fi.primary_ref_frame == PRIMARY_REF_NONE {
-                    [1, 0, 0, 0, 0, -1, -1, -1]
-                } else {
-                    fi.rec_buffer.deblock[fi.ref_frames[fi.primary_ref_frame as usize] as usize].ref_deltas
-                };
+                let (prev_ref_deltas, prev_mode_deltas) = fi.prev_deblock_deltas();
                 for i in 0..REF_FRAMES {
                     let update = deblock.ref_deltas[i] != prev_ref_deltas[i];
                     self.write_bit(update)?;
@@ -1448,11 +1880,6 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
                     }
                 }
                 // conditionally write mode delta updates
-                let prev_mode_deltas = if fi.primary_ref_frame == PRIMARY_REF_NONE {
-                    [0, 0]
-                } else {
-                    fi.rec_buffer.deblock[fi.ref_frames[fi.primary_ref_frame as usize] as usize].mode_deltas
-                };
                 for i in 0..2 {
                     let update = deblock.mode_deltas[i] != prev_mode_deltas[i];
                     self.write_bit(update)?;
@@ -1466,18 +1893,17 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
     }
 
     fn write_frame_cdef(&mut self, fi: &FrameInvariants) -> io::Result<()> {
-        if fi.sequence.enable_cdef {
+        if fi.sequence.enable_cdef && !fi.allow_intrabc {
             assert!(fi.cdef_damping >= 3);
             assert!(fi.cdef_damping <= 6);
             self.write(2, fi.cdef_damping - 3)?;
             assert!(fi.cdef_bits < 4);
             self.write(2,fi.cdef_bits)?; // cdef bits
             for i in 0..(1<<fi.cdef_bits) {
-                let j = i << (3 - fi.cdef_bits);
-                assert!(fi.cdef_y_strengths[j]<64);
-                assert!(fi.cdef_uv_strengths[j]<64);
-                self.write(6,fi.cdef_y_strengths[j])?; // cdef y strength
-                self.write(6,fi.cdef_uv_strengths[j])?; // cdef uv strength
+                assert!(fi.cdef_y_strengths[i]<64);
+                assert!(fi.cdef_uv_strengths[i]<64);
+                self.write(6,fi.cdef_y_strengths[i])?; // cdef y strength
+                self.write(6,fi.cdef_uv_strengths[i])?; // cdef uv strength
             }
         }
         Ok(())
@@ -1547,6 +1973,39 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
         Ok(())
     }
 
+    fn write_quantization_params(&mut self, fi: &FrameInvariants) -> io::Result<()> {
+        assert!(fi.base_q_idx > 0);
+        self.write(8, fi.base_q_idx)?; // base_q_idx
+        self.write_delta_q(fi.dc_delta_q[0])?;
+        assert!(fi.ac_delta_q[0] == 0);
+        let diff_uv_delta = fi.sequence.separate_uv_delta_q
+            && (fi.dc_delta_q[1] != fi.dc_delta_q[2]
+                || fi.ac_delta_q[1] != fi.ac_delta_q[2]);
+        if fi.sequence.separate_uv_delta_q {
+            self.write_bit(diff_uv_delta)?;
+        } else {
+            assert!(fi.dc_delta_q[1] == fi.dc_delta_q[2]);
+            assert!(fi.ac_delta_q[1] == fi.ac_delta_q[2]);
+        }
+        self.write_delta_q(fi.dc_delta_q[1])?;
+        self.write_delta_q(fi.ac_delta_q[1])?;
+        if diff_uv_delta {
+            self.write_delta_q(fi.dc_delta_q[2])?;
+            self.write_delta_q(fi.ac_delta_q[2])?;
+        }
+        self.write_bit(fi.using_qmatrix)?;
+        if fi.using_qmatrix {
+            self.write(4, fi.qm_y)?;
+            self.write(4, fi.qm_u)?;
+            if fi.sequence.separate_uv_delta_q {
+                self.write(4, fi.qm_v)?;
+            } else {
+                assert!(fi.qm_u == fi.qm_v);
+            }
+        }
+        Ok(())
+    }
+
     fn write_delta_q(&mut self, delta_q: i8) -> io::Result<()> {
         self.write_bit(delta_q != 0)?;
         if delta_q != 0 {
@@ -1554,6 +2013,84 @@ impl<W: io::Write> UncompressedHeader for BitWriter<W, BigEndian> {
         }
         Ok(())
     }
+
+    fn write_film_grain_params(&mut self, fi: &FrameInvariants) -> io::Result<()> {
+        let params = match fi.film_grain_params {
+            Some(ref params) => params,
+            None => {
+                self.write_bit(false)?; // apply_grain
+                return Ok(());
+            }
+        };
+        self.write_bit(true)?; // apply_grain
+        self.write(16, params.random_seed)?;
+        if fi.frame_type == FrameType::INTER {
+            self.write_bit(true)?; // update_grain
+        }
+
+        self.write(4, params.num_y_points as u8)?;
+        for point in &params.scaling_points_y[..params.num_y_points] {
+            self.write(8, point[0])?;
+            self.write(8, point[1])?;
+        }
+
+        let subsampled = fi.sequence.chroma_sampling == ChromaSampling::Cs420;
+        self.write_bit(params.chroma_scaling_from_luma)?;
+        let chroma_points_coded = !params.chroma_scaling_from_luma
+            && !(subsampled && params.num_y_points == 0);
+        let (num_cb_points, num_cr_points) = if chroma_points_coded {
+            (params.num_cb_points, params.num_cr_points)
+        } else {
+            (0, 0)
+        };
+        if chroma_points_coded {
+            self.write(4, num_cb_points as u8)?;
+            for point in &params.scaling_points_cb[..num_cb_points] {
+                self.write(8, point[0])?;
+                self.write(8, point[1])?;
+            }
+            self.write(4, num_cr_points as u8)?;
+            for point in &params.scaling_points_cr[..num_cr_points] {
+                self.write(8, point[0])?;
+                self.write(8, point[1])?;
+            }
+        }
+
+        self.write(2, params.scaling_shift - 8)?; // grain_scaling_minus_8
+        self.write(2, params.ar_coeff_lag)?;
+        let num_pos_luma = params.num_pos_luma();
+        if params.num_y_points > 0 {
+            for &coeff in &params.ar_coeffs_y[..num_pos_luma] {
+                self.write(8, (coeff as i16 + 128) as u8)?;
+            }
+        }
+        let num_pos_chroma = params.num_pos_chroma();
+        if params.chroma_scaling_from_luma || num_cb_points > 0 {
+            for &coeff in &params.ar_coeffs_cb[..num_pos_chroma] {
+                self.write(8, (coeff as i16 + 128) as u8)?;
+            }
+        }
+        if params.chroma_scaling_from_luma || num_cr_points > 0 {
+            for &coeff in &params.ar_coeffs_cr[..num_pos_chroma] {
+                self.write(8, (coeff as i16 + 128) as u8)?;
+            }
+        }
+        self.write(2, params.ar_coeff_shift - 6)?; // ar_coeff_shift_minus_6
+        self.write(2, params.grain_scale_shift)?;
+        if num_cb_points > 0 {
+            self.write(8, params.cb_mult)?;
+            self.write(8, params.cb_luma_mult)?;
+            self.write(9, params.cb_offset)?;
+        }
+        if num_cr_points > 0 {
+            self.write(8, params.cr_mult)?;
+            self.write(8, params.cr_luma_mult)?;
+            self.write(9, params.cr_offset)?;
+        }
+        self.write_bit(params.overlap_flag)?;
+        self.write_bit(params.clip_to_restricted_range)?;
+        Ok(())
+    }
 }
 
 #[allow(non_camel_case_types)]
@@ -1689,7 +2226,7 @@ fn diff(dst: &mut [i16], src1: &PlaneSlice<'_>, src2: &PlaneSlice<'_>, width: us
   }
 }
 
-fn get_qidx(fi: &FrameInvariants, fs: &FrameState, cw: &ContextWriter, bo: &BlockOffset) -> u8 {
+pub fn get_qidx(fi: &FrameInvariants, fs: &FrameState, cw: &ContextWriter, bo: &BlockOffset) -> u8 {
     let mut qidx = fi.base_q_idx;
     let sidx = cw.bc.at(bo).segmentation_idx as usize;
     if fs.segmentation.features[sidx][SegLvl::SEG_LVL_ALT_Q as usize] {
@@ -1706,7 +2243,7 @@ pub fn encode_tx_block(
   fi: &FrameInvariants, fs: &mut FrameState, cw: &mut ContextWriter,
   w: &mut dyn Writer, p: usize, bo: &BlockOffset, mode: PredictionMode,
   tx_size: TxSize, tx_type: TxType, plane_bsize: BlockSize, po: &PlaneOffset,
-  skip: bool, ac: &[i16], alpha: i16, for_rdo_use: bool
+  skip: bool, ac: &[i16], alpha: i16, palette: Option<&ColorMap>, for_rdo_use: bool
 ) -> (bool, i64) {
     let qidx = get_qidx(fi, fs, cw, bo);
     let rec = &mut fs.rec.planes[p];
@@ -1714,9 +2251,14 @@ pub fn encode_tx_block(
 
     assert!(tx_size.sqr() <= TxSize::TX_32X32 || tx_type == TxType::DCT_DCT);
 
-    if mode.is_intra() {
+    if let Some(map) = palette {
+      map.predict(p, &mut rec.mut_slice(po), tx_size.width(), tx_size.height());
+    } else if mode.is_intra() {
       let bit_depth = fi.sequence.bit_depth;
-      let edge_buf = get_intra_edges(&rec.slice(po), tx_size, bit_depth, p, fi.w_in_b, fi.h_in_b, Some(mode));
+      let edge_buf = get_intra_edges(
+        &rec.slice(po), tx_size, bit_depth, p, fi.w_in_b, fi.h_in_b, fi.sequence.sb_size(),
+        plane_bsize, cw.bc.at(bo).partition, Some(mode)
+      );
       mode.predict_intra(&mut rec.mut_slice(po), tx_size, bit_depth, &ac, alpha, &edge_buf);
     }
 
@@ -1740,13 +2282,18 @@ pub fn encode_tx_block(
     forward_transform(residual, coeffs, tx_size.width(), tx_size, tx_type, fi.sequence.bit_depth);
 
     let coded_tx_size = av1_get_coded_tx_size(tx_size).area();
-    fs.qc.quantize(coeffs, qcoeffs, coded_tx_size);
+    let qm = fi.qm_tbl(p, tx_size, tx_type);
+    if fi.config.speed_settings.rdoq {
+      fs.qc.rdoq(cw, coeffs, qcoeffs, p, bo, tx_size, tx_type, plane_bsize, xdec, ydec, get_plane_lambda(fi, p), qm);
+    } else {
+      fs.qc.quantize(coeffs, qcoeffs, coded_tx_size, qm);
+    }
 
     let has_coeff = cw.write_coeffs_lv_map(w, p, bo, &qcoeffs, mode, tx_size, tx_type, plane_bsize, xdec, ydec,
                             fi.use_reduced_tx_set);
 
     // Reconstruct
-    dequantize(qidx, qcoeffs, rcoeffs, tx_size, fi.sequence.bit_depth, fi.dc_delta_q[p], fi.ac_delta_q[p]);
+    dequantize(qidx, qcoeffs, rcoeffs, tx_size, fi.sequence.bit_depth, fi.dc_delta_q[p], fi.ac_delta_q[p], qm);
 
     let mut tx_dist: i64 = -1;
 
@@ -1765,20 +2312,33 @@ pub fn encode_tx_block(
         let tx_dist_scale_bits = 2*(3 - get_log_tx_scale(tx_size));
         let tx_dist_scale_rounding_offset = 1 << (tx_dist_scale_bits - 1);
         tx_dist = (tx_dist + tx_dist_scale_rounding_offset) >> tx_dist_scale_bits;
+        if p > 0 {
+            tx_dist = weight_plane_dist(fi, p, tx_dist as u64) as i64;
+        }
     }
     (has_coeff, tx_dist)
 }
 
 pub fn motion_compensate(fi: &FrameInvariants, fs: &mut FrameState, cw: &mut ContextWriter,
                          luma_mode: PredictionMode, ref_frames: [usize; 2], mvs: [MotionVector; 2],
-                         bsize: BlockSize, bo: &BlockOffset, luma_only: bool) {
+                         motion_mode: MotionMode, interp_filter: [FilterMode; 2],
+                         compound: CompoundParams, bsize: BlockSize, bo: &BlockOffset,
+                         luma_only: bool) {
   debug_assert!(!luma_mode.is_intra());
 
   let PlaneConfig { xdec, ydec, .. } = fs.input.planes[1].cfg;
 
+  let warp = if motion_mode == MotionMode::WARPED_CAUSAL {
+    let samples = cw.bc.find_warp_samples(bo, bsize, fi.sequence.sb_size(), ref_frames[0], mvs[0]);
+    local_warp(bo, bsize, mvs[0], &samples)
+  } else {
+    None
+  };
+
   // Inter mode prediction can take place once for a whole partition,
   // instead of each tx-block.
   let num_planes = 1 + if !luma_only && has_chroma(bo, bsize, xdec, ydec) { 2 } else { 0 };
+  let mut seg_mask = [0u8; 128 * 128];
 
   for p in 0..num_planes {
     let plane_bsize = if p == 0 { bsize }
@@ -1787,90 +2347,132 @@ pub fn motion_compensate(fi: &FrameInvariants, fs: &mut FrameState, cw: &mut Con
     let po = bo.plane_offset(&fs.input.planes[p].cfg);
     let rec = &mut fs.rec.planes[p];
 
-    if p > 0 && bsize < BlockSize::BLOCK_8X8 {
+    if p > 0 && bsize.is_sub8x8() {
       let mut some_use_intra = false;
-      if bsize == BlockSize::BLOCK_4X4 || bsize == BlockSize::BLOCK_4X8 {
+      if bsize.width() == 4 {
           some_use_intra |= cw.bc.at(&bo.with_offset(-1,0)).mode.is_intra(); };
-      if !some_use_intra && bsize == BlockSize::BLOCK_4X4 || bsize == BlockSize::BLOCK_8X4 {
+      if !some_use_intra && bsize.height() == 4 {
           some_use_intra |= cw.bc.at(&bo.with_offset(0,-1)).mode.is_intra(); };
       if !some_use_intra && bsize == BlockSize::BLOCK_4X4 {
           some_use_intra |= cw.bc.at(&bo.with_offset(-1,-1)).mode.is_intra(); };
 
       if some_use_intra {
         luma_mode.predict_inter(fi, p, &po, &mut rec.mut_slice(&po), plane_bsize.width(),
-          plane_bsize.height(), ref_frames, mvs);
+          plane_bsize.height(), ref_frames, mvs, interp_filter, compound, &mut seg_mask, warp);
       } else {
         assert!(xdec == 1 && ydec == 1);
         // TODO: these are absolutely only valid for 4:2:0
         if bsize == BlockSize::BLOCK_4X4 {
             let mv0 = cw.bc.at(&bo.with_offset(-1,-1)).mv;
             let rf0 = cw.bc.at(&bo.with_offset(-1,-1)).ref_frames;
+            let if0 = cw.bc.at(&bo.with_offset(-1,-1)).interp_filter;
             let mv1 = cw.bc.at(&bo.with_offset(0,-1)).mv;
             let rf1 = cw.bc.at(&bo.with_offset(0,-1)).ref_frames;
+            let if1 = cw.bc.at(&bo.with_offset(0,-1)).interp_filter;
             let po1 = PlaneOffset { x: po.x+2, y: po.y };
             let mv2 = cw.bc.at(&bo.with_offset(-1,0)).mv;
             let rf2 = cw.bc.at(&bo.with_offset(-1,0)).ref_frames;
+            let if2 = cw.bc.at(&bo.with_offset(-1,0)).interp_filter;
             let po2 = PlaneOffset { x: po.x, y: po.y+2 };
             let po3 = PlaneOffset { x: po.x+2, y: po.y+2 };
-            luma_mode.predict_inter(fi, p, &po, &mut rec.mut_slice(&po), 2, 2, rf0, mv0);
-            luma_mode.predict_inter(fi, p, &po1, &mut rec.mut_slice(&po1), 2, 2, rf1, mv1);
-            luma_mode.predict_inter(fi, p, &po2, &mut rec.mut_slice(&po2), 2, 2, rf2, mv2);
-            luma_mode.predict_inter(fi, p, &po3, &mut rec.mut_slice(&po3), 2, 2, ref_frames, mvs);
+            luma_mode.predict_inter(fi, p, &po, &mut rec.mut_slice(&po), 2, 2, rf0, mv0, if0,
+              CompoundParams::new(), &mut [], None);
+            luma_mode.predict_inter(fi, p, &po1, &mut rec.mut_slice(&po1), 2, 2, rf1, mv1, if1,
+              CompoundParams::new(), &mut [], None);
+            luma_mode.predict_inter(fi, p, &po2, &mut rec.mut_slice(&po2), 2, 2, rf2, mv2, if2,
+              CompoundParams::new(), &mut [], None);
+            luma_mode.predict_inter(fi, p, &po3, &mut rec.mut_slice(&po3), 2, 2, ref_frames, mvs, interp_filter,
+              CompoundParams::new(), &mut [], None);
         }
-        if bsize == BlockSize::BLOCK_8X4 {
+        if bsize.height() == 4 && bsize.width() > 4 {
+            let w = plane_bsize.width();
             let mv1 = cw.bc.at(&bo.with_offset(0,-1)).mv;
             let rf1 = cw.bc.at(&bo.with_offset(0,-1)).ref_frames;
-            luma_mode.predict_inter(fi, p, &po, &mut rec.mut_slice(&po), 4, 2, rf1, mv1);
+            let if1 = cw.bc.at(&bo.with_offset(0,-1)).interp_filter;
+            luma_mode.predict_inter(fi, p, &po, &mut rec.mut_slice(&po), w, 2, rf1, mv1, if1,
+              CompoundParams::new(), &mut [], None);
             let po3 = PlaneOffset { x: po.x, y: po.y+2 };
-            luma_mode.predict_inter(fi, p, &po3, &mut rec.mut_slice(&po3), 4, 2, ref_frames, mvs);
+            luma_mode.predict_inter(fi, p, &po3, &mut rec.mut_slice(&po3), w, 2, ref_frames, mvs, interp_filter,
+              CompoundParams::new(), &mut [], None);
         }
-        if bsize == BlockSize::BLOCK_4X8 {
+        if bsize.width() == 4 && bsize.height() > 4 {
+            let h = plane_bsize.height();
             let mv2 = cw.bc.at(&bo.with_offset(-1,0)).mv;
             let rf2 = cw.bc.at(&bo.with_offset(-1,0)).ref_frames;
-            luma_mode.predict_inter(fi, p, &po, &mut rec.mut_slice(&po), 2, 4, rf2, mv2);
+            let if2 = cw.bc.at(&bo.with_offset(-1,0)).interp_filter;
+            luma_mode.predict_inter(fi, p, &po, &mut rec.mut_slice(&po), 2, h, rf2, mv2, if2,
+              CompoundParams::new(), &mut [], None);
             let po3 = PlaneOffset { x: po.x+2, y: po.y };
-            luma_mode.predict_inter(fi, p, &po3, &mut rec.mut_slice(&po3), 2, 4, ref_frames, mvs);
+            luma_mode.predict_inter(fi, p, &po3, &mut rec.mut_slice(&po3), 2, h, ref_frames, mvs, interp_filter,
+              CompoundParams::new(), &mut [], None);
         }
       }
     } else {
       luma_mode.predict_inter(fi, p, &po, &mut rec.mut_slice(&po), plane_bsize.width(),
-        plane_bsize.height(), ref_frames, mvs);
+        plane_bsize.height(), ref_frames, mvs, interp_filter, compound, &mut seg_mask, warp);
+      if motion_mode == MotionMode::OBMC_CAUSAL {
+        let above = cw.bc.overlappable_candidates(bo, bsize, true);
+        let left = cw.bc.overlappable_candidates(bo, bsize, false);
+        predict_overlapped(fi, p, &po, rec, plane_bsize, &above, &left);
+      }
+      if compound.interintra {
+        predict_interintra(fi, p, &po, rec, bsize, compound);
+      }
     }
   }
 }
 
-pub fn encode_block_a(seq: &Sequence, fs: &FrameState,
+pub fn encode_block_a(fi: &FrameInvariants, fs: &FrameState,
                  cw: &mut ContextWriter, w: &mut dyn Writer,
-                 bsize: BlockSize, bo: &BlockOffset, skip: bool) -> bool {
+                 bsize: BlockSize, bo: &BlockOffset, skip: bool, skip_mode: bool) {
+    // Skip mode blocks have no residual
+    assert!(skip || !skip_mode);
     cw.bc.set_skip(bo, bsize, skip);
+    cw.bc.set_skip_mode(bo, bsize, skip_mode);
     if fs.segmentation.enabled && fs.segmentation.update_map && fs.segmentation.preskip {
         cw.write_segmentation(w, bo, bsize, false, fs.segmentation.last_active_segid);
     }
-    cw.write_skip(w, bo, skip);
+    if cw.skip_mode_allowed(fi, bsize) {
+        cw.write_skip_mode(w, bo, skip_mode);
+    } else {
+        assert!(!skip_mode);
+    }
+    if !skip_mode {
+        cw.write_skip(w, bo, skip);
+    }
     if fs.segmentation.enabled && fs.segmentation.update_map && !fs.segmentation.preskip {
         cw.write_segmentation(w, bo, bsize, skip, fs.segmentation.last_active_segid);
     }
-    if !skip && seq.enable_cdef {
-        cw.bc.cdef_coded = true;
+    if !skip && fi.sequence.enable_cdef && !fi.allow_intrabc {
+        // cdef_idx is coded once per 64x64 filter block, after the first
+        // non-skip block.  The strengths are only known once the frame is
+        // deblocked, so a placeholder is written and patched later.
+        if cw.bc.cdef_coded[bo.fb_index_in_sb()].is_none() {
+            let checkpoint = w.checkpoint();
+            cw.write_cdef(w, 0, fi.cdef_bits);
+            for y in (bo.y..bo.y + bsize.height_mi()).step_by(16) {
+                for x in (bo.x..bo.x + bsize.width_mi()).step_by(16) {
+                    let unit_bo = BlockOffset { x, y };
+                    cw.bc.cdef_coded[unit_bo.fb_index_in_sb()] = Some(checkpoint.clone());
+                }
+            }
+        }
     }
-    cw.bc.cdef_coded
 }
 
 pub fn encode_block_b(fi: &FrameInvariants, fs: &mut FrameState,
                  cw: &mut ContextWriter, w: &mut dyn Writer,
                  luma_mode: PredictionMode, chroma_mode: PredictionMode,
                  ref_frames: [usize; 2], mvs: [MotionVector; 2],
-                 bsize: BlockSize, bo: &BlockOffset, skip: bool,
-                 cfl: CFLParams, tx_size: TxSize, tx_type: TxType,
+                 motion_mode: MotionMode, interp_filter: [FilterMode; 2],
+                 compound: CompoundParams, palette: PaletteParams, bsize: BlockSize,
+                 bo: &BlockOffset, skip: bool, skip_mode: bool, cfl: CFLParams,
+                 tx_size: TxSize, tx_type: TxType, tx_partition: TxPartition,
                  mode_context: usize, mv_stack: &[CandidateMV], for_rdo_use: bool)
                  -> i64 {
     let is_inter = !luma_mode.is_intra();
     if is_inter { assert!(luma_mode == chroma_mode); };
-    let sb_size = if fi.sequence.use_128x128_superblock {
-        BlockSize::BLOCK_128X128
-    } else {
-        BlockSize::BLOCK_64X64
-    };
+    let sb_size = fi.sequence.sb_size();
     let PlaneConfig { xdec, ydec, .. } = fs.input.planes[1].cfg;
     if skip {
         cw.bc.reset_skip_context(bo, bsize, xdec, ydec);
@@ -1879,14 +2481,32 @@ pub fn encode_block_b(fi: &FrameInvariants, fs: &mut FrameState,
     cw.bc.set_mode(bo, bsize, luma_mode);
     cw.bc.set_ref_frames(bo, bsize, ref_frames);
     cw.bc.set_motion_vectors(bo, bsize, mvs);
-
-    //write_q_deltas();
-    if cw.bc.code_deltas && fs.deblock.block_deltas_enabled && (bsize < sb_size || !skip) {
-        cw.write_block_deblock_deltas(w, bo, fs.deblock.block_delta_multi);
+    cw.bc.set_interp_filter(bo, bsize, interp_filter);
+    cw.bc.set_compound(bo, bsize, compound);
+    cw.bc.set_palette(bo, bsize, palette);
+
+    if cw.bc.code_deltas && (bsize != sb_size || !skip) {
+        cw.write_delta_qindex(w, 0, false);
+        if fs.deblock.block_deltas_enabled {
+            // The deltas are decided once the frame is coded, so zero
+            // deltas are written and patched later.
+            cw.bc.deltas_coded = Some(w.checkpoint());
+            cw.write_block_deblock_deltas(w, [0; FRAME_LF_COUNT], fs.deblock.block_delta_multi);
+        }
     }
     cw.bc.code_deltas = false;
 
-    if fi.frame_type == FrameType::INTER {
+    if skip_mode {
+        // The block is coded as NEAREST_NEARESTMV from the skip mode
+        // references, with none of its mode info signaled
+        assert!(luma_mode == PredictionMode::NEAREST_NEARESTMV);
+        assert!(ref_frames == fi.skip_mode_frames);
+        assert!(mvs[0].row == mv_stack[0].this_mv.row && mvs[0].col == mv_stack[0].this_mv.col);
+        assert!(mvs[1].row == mv_stack[0].comp_mv.row && mvs[1].col == mv_stack[0].comp_mv.col);
+        assert!(motion_mode == MotionMode::SIMPLE_TRANSLATION);
+        assert!(interp_filter == fi.default_interp_filter());
+        assert!(compound.compound_type == CompoundType::COMPOUND_AVERAGE && !compound.interintra);
+    } else if fi.frame_type == FrameType::INTER {
         cw.write_is_inter(w, bo, is_inter);
         if is_inter {
             cw.fill_neighbours_ref_counts(bo);
@@ -1915,10 +2535,14 @@ pub fn encode_block_b(fi: &FrameInvariants, fs: &mut FrameState,
               }
             }
 
+            let global_mvs = [
+              global_mv(fi, ref_frames[0], bsize, bo),
+              global_mv(fi, ref_frames[1], bsize, bo)
+            ];
             let ref_mvs = if num_mv_found > 0 {
               [mv_stack[ref_mv_idx].this_mv, mv_stack[ref_mv_idx].comp_mv]
             } else {
-              [MotionVector{ row: 0, col: 0 }; 2]
+              global_mvs
             };
 
             let mv_precision = if fi.force_integer_mv != 0 {
@@ -1957,23 +2581,43 @@ pub fn encode_block_b(fi: &FrameInvariants, fs: &mut FrameState,
                 assert!(mv_stack[ref_mv_idx].this_mv.row == mvs[0].row);
                 assert!(mv_stack[ref_mv_idx].this_mv.col == mvs[0].col);
               } else {
-                assert!(0 == mvs[0].row);
-                assert!(0 == mvs[0].col);
+                assert!(global_mvs[0].row == mvs[0].row);
+                assert!(global_mvs[0].col == mvs[0].col);
               }
             } else if luma_mode == PredictionMode::NEARESTMV {
               if mv_stack.len() > 0 {
                 assert!(mv_stack[0].this_mv.row == mvs[0].row);
                 assert!(mv_stack[0].this_mv.col == mvs[0].col);
               } else {
-                assert!(0 == mvs[0].row);
-                assert!(0 == mvs[0].col);
+                assert!(global_mvs[0].row == mvs[0].row);
+                assert!(global_mvs[0].col == mvs[0].col);
               }
+            } else if luma_mode == PredictionMode::GLOBALMV {
+              assert!(global_mvs[0].row == mvs[0].row);
+              assert!(global_mvs[0].col == mvs[0].col);
             }
+
+            cw.write_interintra_mode(w, fi, bsize, ref_frames, compound);
+            cw.write_motion_mode(w, fi, bo, bsize, luma_mode, ref_frames, mvs[0], motion_mode,
+                                 compound.interintra);
+            cw.write_compound_type(w, fi, bo, bsize, ref_frames, compound);
+            cw.write_interp_filter(w, fi, bo, bsize, luma_mode, ref_frames, motion_mode, interp_filter);
         } else {
             cw.write_intra_mode(w, bsize, luma_mode);
         }
     } else {
-        cw.write_intra_mode_kf(w, bo, luma_mode);
+        if fi.allow_intrabc {
+            cw.write_intrabc(w, is_inter);
+        }
+        if is_inter {
+            // IntraBC copies from the current frame with a whole pixel
+            // displacement
+            assert!(ref_frames == [INTRA_FRAME, NONE_FRAME] && luma_mode == PredictionMode::NEWMV);
+            assert!(motion_mode == MotionMode::SIMPLE_TRANSLATION && !palette.has_palette(0));
+            cw.write_dv(w, mvs[0], ref_dv(fi, bo, mv_stack));
+        } else {
+            cw.write_intra_mode_kf(w, bo, luma_mode);
+        }
     }
 
     if !is_inter {
@@ -1990,17 +2634,26 @@ pub fn encode_block_b(fi: &FrameInvariants, fs: &mut FrameState,
                 cw.write_angle_delta(w, 0, chroma_mode);
             }
         }
-        // TODO: Extra condition related to palette mode, see `read_filter_intra_mode_info` in decodemv.c
-        if luma_mode == PredictionMode::DC_PRED && bsize.width() <= 32 && bsize.height() <= 32 {
+        if cw.palette_allowed(fi, bsize) {
+            cw.write_palette_mode_info(w, bo, bsize, luma_mode, chroma_mode,
+                                       has_chroma(bo, bsize, xdec, ydec), &palette,
+                                       fi.sequence.bit_depth);
+        } else {
+            assert!(!palette.has_palette(0) && !palette.has_palette(1));
+        }
+        if luma_mode == PredictionMode::DC_PRED && !palette.has_palette(0)
+            && bsize.width() <= 32 && bsize.height() <= 32 {
             cw.write_use_filter_intra(w,false, bsize); // Always turn off FILTER_INTRA
         }
     }
 
     if is_inter {
-      motion_compensate(fi, fs, cw, luma_mode, ref_frames, mvs, bsize, bo, false);
-      write_tx_tree(fi, fs, cw, w, luma_mode, bo, bsize, tx_size, tx_type, skip, false, for_rdo_use)
+      motion_compensate(fi, fs, cw, luma_mode, ref_frames, mvs, motion_mode, interp_filter, compound, bsize, bo, false);
+      write_tx_tree(fi, fs, cw, w, luma_mode, bo, bsize, tx_size, tx_type, tx_partition, skip,
+                    false, for_rdo_use)
     } else {
-      write_tx_blocks(fi, fs, cw, w, luma_mode, chroma_mode, bo, bsize, tx_size, tx_type, skip, cfl, false, for_rdo_use)
+      write_tx_blocks(fi, fs, cw, w, luma_mode, chroma_mode, bo, bsize, tx_size, tx_type, skip, cfl,
+                      palette, false, for_rdo_use)
     }
 }
 
@@ -2045,9 +2698,34 @@ pub fn write_tx_blocks(fi: &FrameInvariants, fs: &mut FrameState,
                        cw: &mut ContextWriter, w: &mut dyn Writer,
                        luma_mode: PredictionMode, chroma_mode: PredictionMode, bo: &BlockOffset,
                        bsize: BlockSize, tx_size: TxSize, tx_type: TxType, skip: bool,
-                       cfl: CFLParams, luma_only: bool, for_rdo_use: bool) -> i64 {
-    let bw = bsize.width_mi() / tx_size.width_mi();
-    let bh = bsize.height_mi() / tx_size.height_mi();
+                       cfl: CFLParams, palette: PaletteParams, luma_only: bool,
+                       for_rdo_use: bool) -> i64 {
+    // The colour index maps of palettes are coded ahead of the residual
+    let luma_map = if palette.has_palette(0) {
+        let map = ColorMap::new(fi, fs, bo, bsize, &palette, 0);
+        cw.write_palette_color_map(w, 0, &map, palette.size[0]);
+        Some(map)
+    } else {
+        None
+    };
+    let chroma_map = if palette.has_palette(1) && !luma_only {
+        let map = ColorMap::new(fi, fs, bo, bsize, &palette, 1);
+        cw.write_palette_color_map(w, 1, &map, palette.size[1]);
+        Some(map)
+    } else {
+        None
+    };
+
+    if fi.tx_mode_select {
+        cw.write_tx_size(w, bo, bsize, tx_size);
+    }
+    cw.bc.set_tx_size(bo, bsize, tx_size);
+
+    // Blocks larger than 64x64 are coded in 64x64 chunks, luma then chroma
+    let chunk_w = bsize.width_mi().min(BlockSize::BLOCK_64X64.width_mi());
+    let chunk_h = bsize.height_mi().min(BlockSize::BLOCK_64X64.height_mi());
+    let bw = chunk_w / tx_size.width_mi();
+    let bh = chunk_h / tx_size.height_mi();
     let qidx = get_qidx(fi, fs, cw, bo);
 
     let PlaneConfig { xdec, ydec, .. } = fs.input.planes[1].cfg;
@@ -2055,36 +2733,15 @@ pub fn write_tx_blocks(fi: &FrameInvariants, fs: &mut FrameState,
     let mut tx_dist: i64 = 0;
     let do_chroma = has_chroma(bo, bsize, xdec, ydec);
 
-    fs.qc.update(qidx, tx_size, luma_mode.is_intra(), fi.sequence.bit_depth, fi.dc_delta_q[0], 0);
-
-    for by in 0..bh {
-        for bx in 0..bw {
-            let tx_bo = BlockOffset {
-                x: bo.x + bx * tx_size.width_mi(),
-                y: bo.y + by * tx_size.height_mi()
-            };
-
-            let po = tx_bo.plane_offset(&fs.input.planes[0].cfg);
-            let (_, dist) =
-            encode_tx_block(
-              fi, fs, cw, w, 0, &tx_bo, luma_mode, tx_size, tx_type, bsize, &po,
-              skip, ac, 0, for_rdo_use
-            );
-            assert!(!fi.use_tx_domain_distortion || !for_rdo_use || skip || dist >= 0);
-            tx_dist += dist;
-        }
-    }
-
-    if luma_only { return tx_dist };
-
     let uv_tx_size = bsize.largest_uv_tx_size(fi.sequence.chroma_sampling);
 
-    let mut bw_uv = (bw * tx_size.width_mi()) >> xdec;
-    let mut bh_uv = (bh * tx_size.height_mi()) >> ydec;
+    let mut bw_uv = chunk_w >> xdec;
+    let mut bh_uv = chunk_h >> ydec;
 
-    if (bw_uv == 0 || bh_uv == 0) && do_chroma {
-        bw_uv = 1;
-        bh_uv = 1;
+    // Chroma of sub8x8 blocks is coded with their last block
+    if do_chroma {
+        bw_uv = bw_uv.max(1);
+        bh_uv = bh_uv.max(1);
     }
 
     bw_uv /= uv_tx_size.width_mi();
@@ -2092,79 +2749,106 @@ pub fn write_tx_blocks(fi: &FrameInvariants, fs: &mut FrameState,
 
     let plane_bsize = get_plane_block_size(bsize, xdec, ydec);
 
-    if chroma_mode.is_cfl() {
-      luma_ac(ac, fs, bo, bsize);
-    }
+    for chunk_y in (0..bsize.height_mi()).step_by(chunk_h) {
+        for chunk_x in (0..bsize.width_mi()).step_by(chunk_w) {
+            let chunk_bo = BlockOffset { x: bo.x + chunk_x, y: bo.y + chunk_y };
 
-    if bw_uv > 0 && bh_uv > 0 {
-        let uv_tx_type = if uv_tx_size.width() >= 32 || uv_tx_size.height() >= 32 {
-            TxType::DCT_DCT
-        } else {
-            uv_intra_mode_to_tx_type_context(chroma_mode)
-        };
+            fs.qc.update(qidx, tx_size, luma_mode.is_intra(), fi.sequence.bit_depth, fi.dc_delta_q[0], 0);
 
-        for p in 1..3 {
-            fs.qc.update(fi.base_q_idx, uv_tx_size, true, fi.sequence.bit_depth, fi.dc_delta_q[p], fi.ac_delta_q[p]);
-            let alpha = cfl.alpha(p - 1);
-            for by in 0..bh_uv {
-                for bx in 0..bw_uv {
-                    let tx_bo =
-                        BlockOffset {
-                            x: bo.x + ((bx * uv_tx_size.width_mi()) << xdec) -
-                                ((bw * tx_size.width_mi() == 1) as usize),
-                            y: bo.y + ((by * uv_tx_size.height_mi()) << ydec) -
-                                ((bh * tx_size.height_mi() == 1) as usize)
-                        };
+            for by in 0..bh {
+                for bx in 0..bw {
+                    let tx_bo = BlockOffset {
+                        x: chunk_bo.x + bx * tx_size.width_mi(),
+                        y: chunk_bo.y + by * tx_size.height_mi()
+                    };
 
-                    let mut po = bo.plane_offset(&fs.input.planes[p].cfg);
-                    po.x += (bx * uv_tx_size.width()) as isize;
-                    po.y += (by * uv_tx_size.height()) as isize;
+                    let po = tx_bo.plane_offset(&fs.input.planes[0].cfg);
                     let (_, dist) =
-                    encode_tx_block(fi, fs, cw, w, p, &tx_bo, chroma_mode, uv_tx_size, uv_tx_type,
-                                    plane_bsize, &po, skip, ac, alpha, for_rdo_use);
+                    encode_tx_block(
+                      fi, fs, cw, w, 0, &tx_bo, luma_mode, tx_size, tx_type, bsize, &po,
+                      skip, ac, 0, luma_map.as_ref(), for_rdo_use
+                    );
                     assert!(!fi.use_tx_domain_distortion || !for_rdo_use || skip || dist >= 0);
                     tx_dist += dist;
                 }
             }
+
+            if luma_only { continue };
+
+            if chroma_mode.is_cfl() {
+              luma_ac(ac, fs, bo, bsize);
+            }
+
+            if bw_uv == 0 || bh_uv == 0 { continue };
+
+            let uv_tx_type = if uv_tx_size.width() >= 32 || uv_tx_size.height() >= 32 {
+                TxType::DCT_DCT
+            } else {
+                uv_intra_mode_to_tx_type_context(chroma_mode)
+            };
+
+            for p in 1..3 {
+                fs.qc.update(fi.base_q_idx, uv_tx_size, true, fi.sequence.bit_depth, fi.dc_delta_q[p], fi.ac_delta_q[p]);
+                let alpha = cfl.alpha(p - 1);
+                for by in 0..bh_uv {
+                    for bx in 0..bw_uv {
+                        let tx_bo =
+                            BlockOffset {
+                                x: chunk_bo.x + ((bx * uv_tx_size.width_mi()) << xdec) -
+                                    ((chunk_w == 1) as usize),
+                                y: chunk_bo.y + ((by * uv_tx_size.height_mi()) << ydec) -
+                                    ((chunk_h == 1) as usize)
+                            };
+
+                        let mut po = chunk_bo.plane_offset(&fs.input.planes[p].cfg);
+                        po.x += (bx * uv_tx_size.width()) as isize;
+                        po.y += (by * uv_tx_size.height()) as isize;
+                        let (_, dist) =
+                        encode_tx_block(fi, fs, cw, w, p, &tx_bo, chroma_mode, uv_tx_size, uv_tx_type,
+                                        plane_bsize, &po, skip, ac, alpha, chroma_map.as_ref(),
+                                        for_rdo_use);
+                        assert!(!fi.use_tx_domain_distortion || !for_rdo_use || skip || dist >= 0);
+                        tx_dist += dist;
+                    }
+                }
+            }
         }
     }
 
     tx_dist
 }
 
-// FIXME: For now, assume tx_mode is LARGEST_TX, so var-tx is not implemented yet
-// but only one tx block exist for a inter mode partition.
 pub fn write_tx_tree(fi: &FrameInvariants, fs: &mut FrameState, cw: &mut ContextWriter, w: &mut dyn Writer,
                        luma_mode: PredictionMode, bo: &BlockOffset,
-                       bsize: BlockSize, tx_size: TxSize, tx_type: TxType, skip: bool,
-                       luma_only: bool, for_rdo_use: bool) -> i64 {
-    let bw = bsize.width_mi() / tx_size.width_mi();
-    let bh = bsize.height_mi() / tx_size.height_mi();
+                       bsize: BlockSize, tx_size: TxSize, tx_type: TxType, tx_partition: TxPartition,
+                       skip: bool, luma_only: bool, for_rdo_use: bool) -> i64 {
+    // Skipped blocks keep their largest transforms
+    let tx_partition = if skip { TxPartition::default() } else { tx_partition };
+    if fi.tx_mode_select && !skip && bsize > BlockSize::BLOCK_4X4 {
+        cw.write_tx_partition(w, bo, bsize, tx_partition);
+    } else {
+        cw.bc.set_tx_size(bo, bsize, tx_size);
+    }
+
+    // Blocks larger than 64x64 are coded in 64x64 chunks, luma then chroma.
+    // The chunks are also the roots of the transform trees.
+    let chunk_w = bsize.width_mi().min(BlockSize::BLOCK_64X64.width_mi());
+    let chunk_h = bsize.height_mi().min(BlockSize::BLOCK_64X64.height_mi());
     let qidx = get_qidx(fi, fs, cw, bo);
 
     let PlaneConfig { xdec, ydec, .. } = fs.input.planes[1].cfg;
     let ac = &[0i16; 32 * 32];
     let mut tx_dist: i64 = 0;
 
-    fs.qc.update(qidx, tx_size, luma_mode.is_intra(), fi.sequence.bit_depth, fi.dc_delta_q[0], 0);
-
-    let po = bo.plane_offset(&fs.input.planes[0].cfg);
-    let (has_coeff, dist) = encode_tx_block(
-      fi, fs, cw, w, 0, &bo, luma_mode, tx_size, tx_type, bsize, &po, skip, ac, 0, for_rdo_use
-    );
-    assert!(!fi.use_tx_domain_distortion || !for_rdo_use || skip || dist >= 0);
-    tx_dist += dist;
-
-    if luma_only { return tx_dist };
-
     let uv_tx_size = bsize.largest_uv_tx_size(fi.sequence.chroma_sampling);
+    let uv_tx_set = get_tx_set(uv_tx_size, true, fi.use_reduced_tx_set);
 
-    let mut bw_uv = (bw * tx_size.width_mi()) >> xdec;
-    let mut bh_uv = (bh * tx_size.height_mi()) >> ydec;
+    let mut bw_uv = chunk_w >> xdec;
+    let mut bh_uv = chunk_h >> ydec;
 
-    if (bw_uv == 0 || bh_uv == 0) && has_chroma(bo, bsize, xdec, ydec) {
-        bw_uv = 1;
-        bh_uv = 1;
+    if has_chroma(bo, bsize, xdec, ydec) {
+        bw_uv = bw_uv.max(1);
+        bh_uv = bh_uv.max(1);
     }
 
     bw_uv /= uv_tx_size.width_mi();
@@ -2172,57 +2856,124 @@ pub fn write_tx_tree(fi: &FrameInvariants, fs: &mut FrameState, cw: &mut Context
 
     let plane_bsize = get_plane_block_size(bsize, xdec, ydec);
 
-    if bw_uv > 0 && bh_uv > 0 {
-        let uv_tx_type = if has_coeff {tx_type} else {TxType::DCT_DCT}; // if inter mode, uv_tx_type == tx_type
+    let mut root = 0;
+    for chunk_y in (0..bsize.height_mi()).step_by(chunk_h) {
+        for chunk_x in (0..bsize.width_mi()).step_by(chunk_w) {
+            let chunk_bo = BlockOffset { x: bo.x + chunk_x, y: bo.y + chunk_y };
+
+            // Transform types of the luma transform blocks, which chroma
+            // transform blocks inherit
+            let mut luma_tx_types = Vec::new();
+            for (x, y, leaf_tx_size) in tx_partition.tx_blocks(root, tx_size) {
+                let tx_bo = BlockOffset { x: chunk_bo.x + x, y: chunk_bo.y + y };
+                let leaf_tx_type = inter_tx_type(fi, leaf_tx_size, tx_type);
+
+                fs.qc.update(qidx, leaf_tx_size, luma_mode.is_intra(), fi.sequence.bit_depth, fi.dc_delta_q[0], 0);
+
+                let po = tx_bo.plane_offset(&fs.input.planes[0].cfg);
+                let (has_coeff, dist) = encode_tx_block(
+                  fi, fs, cw, w, 0, &tx_bo, luma_mode, leaf_tx_size, leaf_tx_type, bsize, &po, skip, ac,
+                  0, None, for_rdo_use
+                );
+                assert!(!fi.use_tx_domain_distortion || !for_rdo_use || skip || dist >= 0);
+                tx_dist += dist;
+                luma_tx_types.push((
+                    tx_bo, leaf_tx_size, if has_coeff { leaf_tx_type } else { TxType::DCT_DCT }
+                ));
+            }
+            root += 1;
+
+            if luma_only || bw_uv == 0 || bh_uv == 0 { continue };
+
+            for p in 1..3 {
+                fs.qc.update(qidx, uv_tx_size, false, fi.sequence.bit_depth, fi.dc_delta_q[p], fi.ac_delta_q[p]);
+                for by in 0..bh_uv {
+                    for bx in 0..bw_uv {
+                        let tx_bo = BlockOffset {
+                            x: chunk_bo.x + ((bx * uv_tx_size.width_mi()) << xdec) -
+                                ((chunk_w == 1) as usize),
+                            y: chunk_bo.y + ((by * uv_tx_size.height_mi()) << ydec) -
+                                ((chunk_h == 1) as usize)
+                        };
 
-        for p in 1..3 {
-            fs.qc.update(qidx, uv_tx_size, false, fi.sequence.bit_depth, fi.dc_delta_q[p], fi.ac_delta_q[p]);
-            let tx_bo = BlockOffset {
-                x: bo.x  - ((bw * tx_size.width_mi() == 1) as usize),
-                y: bo.y  - ((bh * tx_size.height_mi() == 1) as usize)
-            };
+                        // The type of the luma transform block at the top
+                        // left of the chroma one, if in the chroma set
+                        // (spec 7.13.3 compute_tx_type)
+                        let luma_x = tx_bo.x.max(bo.x);
+                        let luma_y = tx_bo.y.max(bo.y);
+                        let uv_tx_type = luma_tx_types.iter().find(|&&(ref luma_bo, luma_tx_size, _)| {
+                            luma_x >= luma_bo.x && luma_x < luma_bo.x + luma_tx_size.width_mi() &&
+                            luma_y >= luma_bo.y && luma_y < luma_bo.y + luma_tx_size.height_mi()
+                        }).map_or(TxType::DCT_DCT, |&(_, _, luma_tx_type)| luma_tx_type);
+                        let uv_tx_type = if av1_tx_used[uv_tx_set as usize][uv_tx_type as usize] == 0 {
+                            TxType::DCT_DCT
+                        } else {
+                            uv_tx_type
+                        };
 
-            let po = bo.plane_offset(&fs.input.planes[p].cfg);
-            let (_, dist) =
-            encode_tx_block(fi, fs, cw, w, p, &tx_bo, luma_mode, uv_tx_size, uv_tx_type,
-                            plane_bsize, &po, skip, ac, 0, for_rdo_use);
-            assert!(!fi.use_tx_domain_distortion || !for_rdo_use || skip || dist >= 0);
-            tx_dist += dist;
+                        let mut po = chunk_bo.plane_offset(&fs.input.planes[p].cfg);
+                        po.x += (bx * uv_tx_size.width()) as isize;
+                        po.y += (by * uv_tx_size.height()) as isize;
+                        let (_, dist) =
+                        encode_tx_block(fi, fs, cw, w, p, &tx_bo, luma_mode, uv_tx_size, uv_tx_type,
+                                        plane_bsize, &po, skip, ac, 0, None, for_rdo_use);
+                        assert!(!fi.use_tx_domain_distortion || !for_rdo_use || skip || dist >= 0);
+                        tx_dist += dist;
+                    }
+                }
+            }
         }
     }
 
     tx_dist
 }
 
+/// Transform type of an inter transform block of a block coded with
+/// `tx_type`, which is replaced with DCT_DCT when not in the set of the
+/// transform size
+pub fn inter_tx_type(fi: &FrameInvariants, tx_size: TxSize, tx_type: TxType) -> TxType {
+    let tx_set = get_tx_set(tx_size, true, fi.use_reduced_tx_set);
+    if av1_tx_used[tx_set as usize][tx_type as usize] == 0 {
+        TxType::DCT_DCT
+    } else {
+        tx_type
+    }
+}
+
 pub fn encode_block_with_modes(fi: &FrameInvariants, fs: &mut FrameState,
-    cw: &mut ContextWriter, w_pre_cdef: &mut dyn Writer, w_post_cdef: &mut dyn Writer,
+    cw: &mut ContextWriter, w: &mut dyn Writer,
     bsize: BlockSize, bo: &BlockOffset, mode_decision: &RDOPartitionOutput) {
     let (mode_luma, mode_chroma) =
         (mode_decision.pred_mode_luma, mode_decision.pred_mode_chroma);
     let cfl = mode_decision.pred_cfl_params;
     let ref_frames = mode_decision.ref_frames;
     let mvs = mode_decision.mvs;
+    let motion_mode = mode_decision.motion_mode;
+    let interp_filter = mode_decision.interp_filter;
+    let compound = mode_decision.compound;
+    let palette = mode_decision.palette;
     let skip = mode_decision.skip;
-    let mut cdef_coded = cw.bc.cdef_coded;
-    let (tx_size, tx_type) = (mode_decision.tx_size, mode_decision.tx_type);
+    let skip_mode = mode_decision.skip_mode;
+    let (tx_size, tx_type, tx_partition) =
+        (mode_decision.tx_size, mode_decision.tx_type, mode_decision.tx_partition);
 
-    debug_assert!((tx_size, tx_type) ==
-        rdo_tx_size_type(fi, fs, cw, bsize, bo, mode_luma, ref_frames, mvs, skip));
-    cw.bc.set_tx_size(bo, tx_size);
+    debug_assert!((tx_size, tx_partition, tx_type) ==
+        rdo_tx_size_type(fi, fs, cw, bsize, bo, mode_luma, ref_frames, mvs, motion_mode,
+                         interp_filter, compound, palette, skip));
 
     let mut mv_stack = Vec::new();
     let is_compound = ref_frames[1] != NONE_FRAME;
     let mode_context = cw.find_mvrefs(bo, ref_frames, &mut mv_stack, bsize, fi, is_compound);
 
-    cdef_coded = encode_block_a(&fi.sequence, fs, cw, if cdef_coded  {w_post_cdef} else {w_pre_cdef},
-                                bsize, bo, skip);
-    encode_block_b(fi, fs, cw, if cdef_coded  {w_post_cdef} else {w_pre_cdef},
-                    mode_luma, mode_chroma, ref_frames, mvs, bsize, bo, skip, cfl,
-                    tx_size, tx_type, mode_context, &mv_stack, false);
+    encode_block_a(fi, fs, cw, w, bsize, bo, skip, skip_mode);
+    encode_block_b(fi, fs, cw, w,
+                    mode_luma, mode_chroma, ref_frames, mvs, motion_mode, interp_filter,
+                    compound, palette, bsize, bo, skip, skip_mode, cfl, tx_size, tx_type,
+                    tx_partition, mode_context, &mv_stack, false);
 }
 
 fn encode_partition_bottomup(fi: &FrameInvariants, fs: &mut FrameState,
-                             cw: &mut ContextWriter, w_pre_cdef: &mut dyn Writer, w_post_cdef: &mut dyn Writer,
+                             cw: &mut ContextWriter, w: &mut dyn Writer,
                              bsize: BlockSize, bo: &BlockOffset, pmvs: &[[Option<MotionVector>; REF_FRAMES]; 5]
 ) -> (f64, Option<RDOPartitionOutput>) {
     let mut rd_cost = std::f64::MAX;
@@ -2239,8 +2990,7 @@ fn encode_partition_bottomup(fi: &FrameInvariants, fs: &mut FrameState,
 
     // Always split if the current partition is too large
     let must_split = (bo.x + bsw as usize > fi.w_in_b ||
-        bo.y + bsh as usize > fi.h_in_b ||
-        bsize.greater_than(BlockSize::BLOCK_64X64)) && is_square;
+        bo.y + bsh as usize > fi.h_in_b) && is_square;
 
     // must_split overrides the minimum partition size when applicable
     let can_split = (bsize > fi.min_partition_size && is_square) || must_split;
@@ -2255,14 +3005,19 @@ fn encode_partition_bottomup(fi: &FrameInvariants, fs: &mut FrameState,
         pred_cfl_params: CFLParams::new(),
         ref_frames: [INTRA_FRAME, NONE_FRAME],
         mvs: [MotionVector { row: 0, col: 0}; 2],
+        motion_mode: MotionMode::SIMPLE_TRANSLATION,
+        interp_filter: fi.default_interp_filter(),
+        compound: CompoundParams::new(),
+        palette: PaletteParams::new(),
         skip: false,
+        skip_mode: false,
         tx_size: TxSize::TX_4X4,
         tx_type: TxType::DCT_DCT,
+        tx_partition: TxPartition::default(),
     }; // Best decision that is not PARTITION_SPLIT
 
     let cw_checkpoint = cw.checkpoint();
-    let w_pre_checkpoint = w_pre_cdef.checkpoint();
-    let w_post_checkpoint = w_post_cdef.checkpoint();
+    let w_checkpoint = w.checkpoint();
 
     // Code the whole block
     // TODO(yushin): Try move PARTITION_NONE to below partition loop
@@ -2272,25 +3027,21 @@ fn encode_partition_bottomup(fi: &FrameInvariants, fs: &mut FrameState,
         let mut cost: f64 = 0.0;
 
         if bsize.gte(BlockSize::BLOCK_8X8) && is_square {
-            let w: &mut dyn Writer = if cw.bc.cdef_coded {w_post_cdef} else {w_pre_cdef};
             let tell = w.tell_frac();
             cw.write_partition(w, bo, best_partition, bsize);
             cost = (w.tell_frac() - tell) as f64 * get_lambda(fi)/ ((1 << OD_BITRES) as f64);
         }
 
-        let pmv_idx = if bsize.greater_than(BlockSize::BLOCK_32X32) {
-            0
-        } else {
-            ((bo.x & 32) >> 5) + ((bo.y & 32) >> 4) + 1
-        };
+        let pmv_idx = get_pmv_idx(fi, bsize, bo);
         let spmvs = &pmvs[pmv_idx];
 
+        cw.bc.set_partition(bo, bsize, best_partition);
         let mode_decision = rdo_mode_decision(fi, fs, cw, bsize, bo, spmvs, false).part_modes[0].clone();
 
         rd_cost = mode_decision.rd_cost + cost;
         best_rd = rd_cost;
 
-        encode_block_with_modes(fi, fs, cw, w_pre_cdef, w_post_cdef, bsize, bo,
+        encode_block_with_modes(fi, fs, cw, w, bsize, bo,
                             &mode_decision);
 
         best_decision = mode_decision;
@@ -2314,9 +3065,11 @@ fn encode_partition_bottomup(fi: &FrameInvariants, fs: &mut FrameState,
                 if !split_horz && partition == PartitionType::PARTITION_HORZ { continue; };
                 if !split_vert && partition == PartitionType::PARTITION_VERT { continue; };
             }
+            if partition.is_ext() && (must_split || !ext_partition_allowed(fi, fs, bsize, partition)) {
+                continue;
+            }
             cw.rollback(&cw_checkpoint);
-            w_pre_cdef.rollback(&w_pre_checkpoint);
-            w_post_cdef.rollback(&w_post_checkpoint);
+            w.rollback(&w_checkpoint);
 
             let subsize = bsize.subsize(partition);
             let hbsw = subsize.width_mi(); // Half the block size width in blocks
@@ -2325,12 +3078,24 @@ fn encode_partition_bottomup(fi: &FrameInvariants, fs: &mut FrameState,
             rd_cost = 0.0;
 
             if bsize.gte(BlockSize::BLOCK_8X8) {
-                let w: &mut dyn Writer = if cw.bc.cdef_coded {w_post_cdef} else {w_pre_cdef};
                 let tell = w.tell_frac();
                 cw.write_partition(w, bo, partition, bsize);
                 rd_cost = (w.tell_frac() - tell) as f64 * get_lambda(fi)/ ((1 << OD_BITRES) as f64);
             }
 
+            // The blocks of extended partitions are not split any further
+            if partition.is_ext() {
+                child_modes = rdo_ext_partition(fi, fs, cw, w, bsize, bo, partition, pmvs);
+                rd_cost += child_modes.iter().map(|mode| mode.rd_cost).sum::<f64>();
+
+                if rd_cost < best_rd {
+                    best_rd = rd_cost;
+                    best_partition = partition;
+                    best_pred_modes = child_modes.clone();
+                }
+                continue;
+            }
+
             let four_partitions = [
                 bo,
                 &BlockOffset{ x: bo.x + hbsw as usize, y: bo.y },
@@ -2347,8 +3112,7 @@ fn encode_partition_bottomup(fi: &FrameInvariants, fs: &mut FrameState,
                     fi,
                     fs,
                     cw,
-                    w_pre_cdef,
-                    w_post_cdef,
+                    w,
                     subsize,
                     offset,
                     pmvs//&best_decision.mvs[0]
@@ -2368,21 +3132,20 @@ fn encode_partition_bottomup(fi: &FrameInvariants, fs: &mut FrameState,
         // If the best partition is not PARTITION_SPLIT, recode it
         if best_partition != PartitionType::PARTITION_SPLIT {
             cw.rollback(&cw_checkpoint);
-            w_pre_cdef.rollback(&w_pre_checkpoint);
-            w_post_cdef.rollback(&w_post_checkpoint);
+            w.rollback(&w_checkpoint);
 
             assert!(best_partition != PartitionType::PARTITION_NONE || !must_split);
             let subsize = bsize.subsize(best_partition);
 
             if bsize.gte(BlockSize::BLOCK_8X8) {
-                let w: &mut dyn Writer = if cw.bc.cdef_coded {w_post_cdef} else {w_pre_cdef};
                 cw.write_partition(w, bo, best_partition, bsize);
             }
             for mode in best_pred_modes {
-                assert!(subsize == mode.bsize);
+                assert!(best_partition.is_ext() || subsize == mode.bsize);
                 let offset = mode.bo.clone();
+                cw.bc.set_partition(&offset, mode.bsize, best_partition);
                 // FIXME: redundant block re-encode
-                encode_block_with_modes(fi, fs, cw, w_pre_cdef, w_post_cdef,
+                encode_block_with_modes(fi, fs, cw, w,
                                         mode.bsize, &offset, &mode);
             }
         }
@@ -2392,14 +3155,18 @@ fn encode_partition_bottomup(fi: &FrameInvariants, fs: &mut FrameState,
 
     if bsize.gte(BlockSize::BLOCK_8X8) &&
         (bsize == BlockSize::BLOCK_8X8 || best_partition != PartitionType::PARTITION_SPLIT) {
-        cw.bc.update_partition_context(bo, subsize, bsize);
+        if best_partition.is_ext() {
+            cw.bc.update_ext_partition_context(bo, bsize, best_partition);
+        } else {
+            cw.bc.update_partition_context(bo, subsize, bsize);
+        }
     }
 
     (best_rd, Some(best_decision))
 }
 
 fn encode_partition_topdown(fi: &FrameInvariants, fs: &mut FrameState,
-            cw: &mut ContextWriter, w_pre_cdef: &mut dyn Writer, w_post_cdef: &mut dyn Writer,
+            cw: &mut ContextWriter, w: &mut dyn Writer,
             bsize: BlockSize, bo: &BlockOffset, block_output: &Option<RDOOutput>,
             pmvs: &[[Option<MotionVector>; REF_FRAMES]; 5]
 ) {
@@ -2413,8 +3180,7 @@ fn encode_partition_topdown(fi: &FrameInvariants, fs: &mut FrameState,
 
     // Always split if the current partition is too large
     let must_split = (bo.x + bsw as usize > fi.w_in_b ||
-        bo.y + bsh as usize > fi.h_in_b ||
-        bsize.greater_than(BlockSize::BLOCK_64X64)) && is_square;
+        bo.y + bsh as usize > fi.h_in_b) && is_square;
 
     let mut rdo_output = block_output.clone().unwrap_or(RDOOutput {
         part_type: PartitionType::PARTITION_INVALID,
@@ -2447,9 +3213,12 @@ fn encode_partition_topdown(fi: &FrameInvariants, fs: &mut FrameState,
             //partition_types.append(&mut RAV1E_PARTITION_TYPES.to_vec());
             partition_types.push(PartitionType::PARTITION_NONE);
             partition_types.push(PartitionType::PARTITION_SPLIT);
+            partition_types.extend(RAV1E_PARTITION_TYPES.iter().filter(|&&partition| {
+                partition.is_ext() && ext_partition_allowed(fi, fs, bsize, partition)
+            }));
         }
         rdo_output = rdo_partition_decision(fi, fs, cw,
-            w_pre_cdef, w_post_cdef, bsize, bo, &rdo_output, pmvs, &partition_types);
+            w, bsize, bo, &rdo_output, pmvs, &partition_types);
         partition = rdo_output.part_type;
     } else {
         // Blocks of sizes below the supported range are encoded directly
@@ -2462,7 +3231,6 @@ fn encode_partition_topdown(fi: &FrameInvariants, fs: &mut FrameState,
     let subsize = bsize.subsize(partition);
 
     if bsize.gte(BlockSize::BLOCK_8X8) && is_square {
-        let w: &mut dyn Writer = if cw.bc.cdef_coded {w_post_cdef} else {w_pre_cdef};
         cw.write_partition(w, bo, partition, bsize);
     }
 
@@ -2472,38 +3240,49 @@ fn encode_partition_topdown(fi: &FrameInvariants, fs: &mut FrameState,
                     // The optimal prediction mode is known from a previous iteration
                     rdo_output.part_modes[0].clone()
                 } else {
-                    let pmv_idx = if bsize.greater_than(BlockSize::BLOCK_32X32) {
-                        0
-                    } else {
-                        ((bo.x & 32) >> 5) + ((bo.y & 32) >> 4) + 1
-                    };
+                    let pmv_idx = get_pmv_idx(fi, bsize, bo);
                     let spmvs = &pmvs[pmv_idx];
 
                     // Make a prediction mode decision for blocks encoded with no rdo_partition_decision call (e.g. edges)
+                    cw.bc.set_partition(bo, bsize, partition);
                     rdo_mode_decision(fi, fs, cw, bsize, bo, spmvs, false).part_modes[0].clone()
                 };
+            cw.bc.set_partition(bo, bsize, partition);
 
             let mut mode_luma = part_decision.pred_mode_luma;
             let mut mode_chroma = part_decision.pred_mode_chroma;
 
             let cfl = part_decision.pred_cfl_params;
             let skip = part_decision.skip;
+            let skip_mode = part_decision.skip_mode;
             let ref_frames = part_decision.ref_frames;
-            let mvs = part_decision.mvs;
-            let mut cdef_coded = cw.bc.cdef_coded;
-
-            // NOTE: Cannot avoid calling rdo_tx_size_type() here again,
-            // because, with top-down partition RDO, the neighnoring contexts
-            // of current partition can change, i.e. neighboring partitions can split down more.
-            let (tx_size, tx_type) =
-                rdo_tx_size_type(fi, fs, cw, bsize, bo, mode_luma, ref_frames, mvs, skip);
+            let mut mvs = part_decision.mvs;
+            let mut motion_mode = part_decision.motion_mode;
+            let mut interp_filter = part_decision.interp_filter;
+            let compound = part_decision.compound;
+            let palette = part_decision.palette;
 
             let mut mv_stack = Vec::new();
             let is_compound = ref_frames[1] != NONE_FRAME;
             let mode_context = cw.find_mvrefs(bo, ref_frames, &mut mv_stack, bsize, fi, is_compound);
 
+            // Skip mode always predicts from the nearest candidate
+            if skip_mode {
+                mvs = [mv_stack[0].this_mv, mv_stack[0].comp_mv];
+            }
+
             // TODO proper remap when is_compound is true
-            if !mode_luma.is_intra() {
+            // IntraBC always codes a new displacement vector
+            if !mode_luma.is_intra() && ref_frames[0] != INTRA_FRAME {
+                let global_mvs = [
+                    global_mv(fi, ref_frames[0], bsize, bo),
+                    global_mv(fi, ref_frames[1], bsize, bo)
+                ];
+                // Global motion modes can replace translational ones with the
+                // same motion vectors unless they would warp the prediction
+                let global_is_translation = ref_frames.iter().all(|&r| {
+                    r == NONE_FRAME || global_warp(fi, r, bsize.width(), bsize.height()).is_none()
+                });
                 if is_compound && mode_luma != PredictionMode::GLOBAL_GLOBALMV {
                     let match0 = mv_stack[0].this_mv.row == mvs[0].row && mv_stack[0].this_mv.col == mvs[0].col;
                     let match1 = mv_stack[0].comp_mv.row == mvs[1].row && mv_stack[0].comp_mv.col == mvs[1].col;
@@ -2517,8 +3296,9 @@ fn encode_partition_topdown(fi: &FrameInvariants, fs: &mut FrameState,
                     } else {
                         PredictionMode::NEW_NEWMV
                     };
-                    if mode_luma != PredictionMode::NEAREST_NEARESTMV && mvs[0].row == 0 && mvs[0].col == 0 &&
-                        mvs[1].row == 0 && mvs[1].col == 0 {
+                    if mode_luma != PredictionMode::NEAREST_NEARESTMV && global_is_translation &&
+                        mvs[0].row == global_mvs[0].row && mvs[0].col == global_mvs[0].col &&
+                        mvs[1].row == global_mvs[1].row && mvs[1].col == global_mvs[1].col {
                         mode_luma = PredictionMode::GLOBAL_GLOBALMV;
                     }
                     mode_chroma = mode_luma;
@@ -2531,22 +3311,44 @@ fn encode_partition_topdown(fi: &FrameInvariants, fs: &mut FrameState,
                             mode_luma = *m;
                         }
                     }
-                    if mode_luma == PredictionMode::NEWMV && mvs[0].row == 0 && mvs[0].col == 0 {
+                    if mode_luma == PredictionMode::NEWMV &&
+                        mvs[0].row == global_mvs[0].row && mvs[0].col == global_mvs[0].col {
                         mode_luma =
                             if mv_stack.len() == 0 { PredictionMode::NEARESTMV }
                             else if mv_stack.len() == 1 { PredictionMode::NEAR0MV }
-                            else { PredictionMode::GLOBALMV };
+                            else if global_is_translation { PredictionMode::GLOBALMV }
+                            else { PredictionMode::NEWMV };
                     }
                     mode_chroma = mode_luma;
                 }
             }
 
+            // The neighbours a local warp is fitted to, or that OBMC blends
+            // with, may have changed too
+            if motion_mode == MotionMode::WARPED_CAUSAL &&
+                !cw.warped_causal_allowed(fi, bo, bsize, mode_luma, ref_frames, mvs[0]) ||
+                motion_mode == MotionMode::OBMC_CAUSAL &&
+                !cw.motion_mode_switchable(fi, bo, bsize, mode_luma, ref_frames) {
+                motion_mode = MotionMode::SIMPLE_TRANSLATION;
+            }
+            if !mode_luma.is_intra() && fi.is_filter_switchable &&
+                !cw.interp_filter_coded(fi, bsize, mode_luma, ref_frames, motion_mode, skip_mode) {
+                interp_filter = fi.default_interp_filter();
+            }
+
+            // NOTE: Cannot avoid calling rdo_tx_size_type() here again,
+            // because, with top-down partition RDO, the neighnoring contexts
+            // of current partition can change, i.e. neighboring partitions can split down more.
+            let (tx_size, tx_partition, tx_type) =
+                rdo_tx_size_type(fi, fs, cw, bsize, bo, mode_luma, ref_frames, mvs, motion_mode,
+                                 interp_filter, compound, palette, skip);
+
             // FIXME: every final block that has gone through the RDO decision process is encoded twice
-            cdef_coded = encode_block_a(&fi.sequence, fs, cw, if cdef_coded  {w_post_cdef} else {w_pre_cdef},
-                         bsize, bo, skip);
-            encode_block_b(fi, fs, cw, if cdef_coded  {w_post_cdef} else {w_pre_cdef},
-                          mode_luma, mode_chroma, ref_frames, mvs, bsize, bo, skip, cfl,
-                          tx_size, tx_type, mode_context, &mv_stack, false);
+            encode_block_a(fi, fs, cw, w, bsize, bo, skip, skip_mode);
+            encode_block_b(fi, fs, cw, w,
+                          mode_luma, mode_chroma, ref_frames, mvs, motion_mode, interp_filter,
+                          compound, palette, bsize, bo, skip, skip_mode, cfl, tx_size, tx_type,
+                          tx_partition, mode_context, &mv_stack, false);
         },
         PARTITION_SPLIT |
         PARTITION_HORZ |
@@ -2562,7 +3364,7 @@ fn encode_partition_topdown(fi: &FrameInvariants, fs: &mut FrameState,
                     let offset = mode.bo.clone();
 
                     // Each block is subjected to a new splitting decision
-                    encode_partition_topdown(fi, fs, cw, w_pre_cdef, w_post_cdef, subsize, &offset,
+                    encode_partition_topdown(fi, fs, cw, w, subsize, &offset,
                         &Some(RDOOutput {
                             rd_cost: mode.rd_cost,
                             part_type: PartitionType::PARTITION_NONE,
@@ -2585,8 +3387,7 @@ fn encode_partition_topdown(fi: &FrameInvariants, fs: &mut FrameState,
                             fi,
                             fs,
                             cw,
-                            w_pre_cdef,
-                            w_post_cdef,
+                            w,
                             subsize,
                             offset,
                             &None,
@@ -2595,16 +3396,309 @@ fn encode_partition_topdown(fi: &FrameInvariants, fs: &mut FrameState,
                     });
             }
         },
+        PARTITION_HORZ_A |
+        PARTITION_HORZ_B |
+        PARTITION_VERT_A |
+        PARTITION_VERT_B |
+        PARTITION_HORZ_4 |
+        PARTITION_VERT_4 => {
+            // Extended partitions are only chosen by rdo_partition_decision()
+            assert!(!rdo_output.part_modes.is_empty());
+
+            for mode in rdo_output.part_modes {
+                let offset = mode.bo.clone();
+                cw.bc.set_partition(&offset, mode.bsize, partition);
+                encode_block_with_modes(fi, fs, cw, w, mode.bsize, &offset, &mode);
+            }
+        },
         _ => { assert!(false); },
     }
 
     if bsize.gte(BlockSize::BLOCK_8X8) &&
         (bsize == BlockSize::BLOCK_8X8 || partition != PartitionType::PARTITION_SPLIT) {
+        if partition.is_ext() {
+            cw.bc.update_ext_partition_context(bo, bsize, partition);
+        } else {
             cw.bc.update_partition_context(bo, subsize, bsize);
+        }
+    }
+}
+
+/// A superblock coded ahead of the loop filters, recorded until the
+/// deblocking deltas, CDEF strengths and restoration filters it codes are
+/// decided
+struct RecordedSuperBlock {
+  w: WriterBase<WriterRecorder>,
+  // cdef_bits of the cdef_idx coded as a placeholder
+  cdef_bits: u8,
+  // Filter blocks sharing each coded cdef_idx
+  cdef_groups: Vec<(WriterCheckpoint, Vec<FilterBlockOffset>)>,
+  // Position of the coded delta_lf
+  deltas_coded: Option<WriterCheckpoint>,
+  patches: Vec<(WriterCheckpoint, usize, WriterBase<WriterRecorder>)>
+}
+
+/// Runs the loop filters on rows of filter blocks, 64 luma rows, as soon
+/// as the superblock rows they read are coded, keeping only the rows of
+/// each stage that the next one reads. Each stage lags the one before, as
+/// its filters reach into the next row, and the deblocking lags the coding
+/// by a row, as intra prediction reads the unfiltered row above. The frame
+/// levels, CDEF strengths and restoration types are decided on the first
+/// rows, the block deltas, cdef_idx and restoration filters on each row.
+/// Superblocks are written once all they code is decided.
+struct LoopFilterPipeline {
+  // Superblock rows coded but not written yet
+  rows: VecDeque<Vec<RecordedSuperBlock>>,
+  written: usize,
+  coded: usize,
+  // Filter block rows deblocked and CDEF filtered
+  deblocked: usize,
+  cdeffed: usize,
+  // Stripes gathered for the restoration search and filtered
+  gathered: usize,
+  restored: usize,
+  levels_decided: bool,
+  cdef_decided: bool,
+  // The delta_lf CDFs, adapting as the final deltas are coded
+  delta_lf_fc: CDFContext,
+  delta_prev: i8,
+  cdef: bool,
+  restoration: bool,
+  cdef_strip: Frame,
+  boundaries: Option<StripeBoundaries>,
+  upscaled: Option<Frame>,
+  // Workers over which each stage spreads its rows
+  pool: Arc<WorkerPool>
+}
+
+impl LoopFilterPipeline {
+  fn new(fi: &FrameInvariants, fs: &FrameState, fc: CDFContext) -> LoopFilterPipeline {
+    /* TODO: Don't apply if lossless */
+    // The loop filters are all disabled with IntraBC
+    let cdef = fi.sequence.enable_cdef && !fi.allow_intrabc;
+    let restoration = fi.sequence.enable_restoration && !fi.allow_intrabc;
+    LoopFilterPipeline {
+      rows: VecDeque::new(),
+      written: 0,
+      coded: 0,
+      deblocked: 0,
+      cdeffed: 0,
+      gathered: 0,
+      restored: 0,
+      levels_decided: false,
+      cdef_decided: false,
+      delta_lf_fc: fc,
+      delta_prev: 0,
+      cdef,
+      restoration,
+      cdef_strip: cdef_strip(fi, &fs.rec),
+      boundaries: if restoration { Some(StripeBoundaries::new(fi, &fs.rec)) } else { None },
+      // Loop restoration and the reference buffer operate at the upscaled
+      // resolution
+      upscaled: if fi.superres_denom != SUPERRES_NUM { Some(upscaled_frame(fi)) } else { None },
+      pool: Arc::clone(&fi.filter_pool)
+    }
+  }
+
+  /// Adds the next coded superblock row and runs the stages it unblocks
+  fn push_row(&mut self, fi: &mut FrameInvariants, fs: &mut FrameState, cw: &mut ContextWriter,
+              w: &mut WriterBase<WriterEncoder>, row: Vec<RecordedSuperBlock>) {
+    self.rows.push_back(row);
+    self.coded += 1;
+    self.advance(fi, fs, cw, w);
+  }
+
+  /// Completes the frame once all its superblock rows are pushed
+  fn finish(mut self, fi: &mut FrameInvariants, fs: &mut FrameState, cw: &mut ContextWriter) {
+    assert!(self.coded == fi.sb_height && self.rows.is_empty());
+    if self.cdef && !self.cdef_decided {
+      rdo_cdef_frame(fi, &fs.input, &[], 0, &[], &cw.bc, &[]);
+    }
+    if fs.deblock.block_deltas_enabled {
+      cw.fc.restore_delta_lf(&self.delta_lf_fc);
+    }
+    if let Some(upscaled) = self.upscaled.take() {
+      fs.rec = upscaled;
+    }
+  }
+
+  fn advance(&mut self, fi: &mut FrameInvariants, fs: &mut FrameState, cw: &mut ContextWriter,
+             w: &mut WriterBase<WriterEncoder>) {
+    let fb_rows = (fi.padded_h + 63) / 64;
+    let k_log2 = fi.sequence.sb_size_log2() - 6;
+    let sb_fb_rows = |sby: usize| sby << k_log2..cmp::min((sby + 1) << k_log2, fb_rows);
+
+    let ready = if self.coded == fi.sb_height {
+      fb_rows
+    } else {
+      cmp::min(self.coded << k_log2, fb_rows) - 1
+    };
+    while self.deblocked < ready {
+      let fby = self.deblocked;
+      if fby & ((1 << k_log2) - 1) == 0 {
+        self.decide_deblock(fi, fs, cw, fby >> k_log2);
+      }
+      if fs.deblock.levels[0] != 0 || fs.deblock.levels[1] != 0 {
+        deblock_filter_rows(fi, &fs.deblock, &mut fs.rec, &cw.bc, fby, &self.pool);
+      }
+      self.deblocked += 1;
+    }
+
+    let ready = if self.deblocked == fb_rows { fb_rows } else { self.deblocked.saturating_sub(1) };
+    while self.cdeffed < fb_rows && sb_fb_rows(self.cdeffed >> k_log2).end <= ready {
+      let sby = self.cdeffed >> k_log2;
+      self.cdef_rows(fi, fs, cw, sby, sb_fb_rows(sby));
+      self.cdeffed = sb_fb_rows(sby).end;
+    }
+
+    if let Some(ref boundaries) = self.boundaries {
+      let stripe_n = (fi.height + 7) / 64 + 1;
+      let source = fs.input_upscaled.as_ref().unwrap_or(&fs.input);
+      let cdeffed = self.upscaled.as_ref().unwrap_or(&fs.rec);
+      while self.gathered < stripe_n && cmp::min(self.gathered + 1, fb_rows) <= self.cdeffed {
+        fs.restoration.lrf_gather_stripe(fi, cw, source, cdeffed, boundaries, self.gathered);
+        self.gathered += 1;
+      }
+    }
+
+    while let Some(row) = self.rows.pop_front() {
+      let sby = self.written;
+      let ready = (self.deblocked > sby << k_log2 || self.deblocked == fb_rows)
+        && self.cdeffed >= sb_fb_rows(sby).end
+        && (!self.restoration || (0..fi.sb_width).all(|sbx| {
+          let sbo = SuperBlockOffset { x: sbx, y: sby, size_log2: fi.sequence.sb_size_log2() };
+          fs.restoration.lrf_fitted(&sbo, fi)
+        }));
+      if !ready {
+        self.rows.push_front(row);
+        break;
+      }
+      // Loop restoration is decided last but coded before each superblock
+      for (sbx, mut sb) in row.into_iter().enumerate() {
+        if self.restoration {
+          let sbo = SuperBlockOffset { x: sbx, y: sby, size_log2: fi.sequence.sb_size_log2() };
+          fs.restoration.lrf_optimize_superblock(&sbo, fi, cw);
+          cw.write_lrf(w, fi, &mut fs.restoration, &sbo);
+        }
+        sb.w.patch(sb.patches);
+        sb.w.replay(w);
+      }
+      self.written += 1;
     }
+
+    if let Some(ref boundaries) = self.boundaries {
+      let out = self.upscaled.as_mut().unwrap_or(&mut fs.rec);
+      let mut end = self.restored;
+      while end < self.gathered && fs.restoration.lrf_stripe_coded(end) {
+        end += 1;
+      }
+      fs.restoration.lrf_filter_stripes(out, boundaries, fi.sequence.bit_depth,
+                                        self.restored..end, &self.pool);
+      self.restored = end;
+    }
+  }
+
+  // Picks the deblocking levels of the frame on its first rows, then the
+  // delta_lf of the superblocks of row `sby`, before it is deblocked
+  fn decide_deblock(&mut self, fi: &FrameInvariants, fs: &mut FrameState,
+                    cw: &mut ContextWriter, sby: usize) {
+    if !self.levels_decided {
+      self.levels_decided = true;
+      if fi.allow_intrabc {
+        fs.deblock.levels = [0; 4];
+      } else {
+        let rows = cmp::min(self.coded << fi.sequence.sb_size_log2() >> MI_SIZE_LOG2, cw.bc.rows);
+        deblock_filter_optimize(fi, fs, &cw.bc, 0..rows);
+      }
+    }
+    if !fs.deblock.block_deltas_enabled {
+      return;
+    }
+
+    // Recorded superblocks are patched with the deltas once they are
+    // decided, coded with the delta_lf CDFs of the final deltas
+    let row = &mut self.rows[sby - self.written];
+    let coded: Vec<bool> = row.iter().map(|sb| sb.deltas_coded.is_some()).collect();
+    let deltas =
+      deblock_block_deltas_optimize(fi, fs, &cw.bc, sby, &coded, self.delta_prev, sby == 0);
+    let multi = fs.deblock.block_delta_multi;
+    mem::swap(&mut cw.fc, &mut self.delta_lf_fc);
+    for (sbx, (sb, &delta)) in row.iter_mut().zip(&deltas).enumerate() {
+      let sbo = SuperBlockOffset { x: sbx, y: sby, size_log2: fi.sequence.sb_size_log2() };
+      cw.bc.set_deblock_deltas(&sbo, [delta; FRAME_LF_COUNT]);
+      if let Some(ref checkpoint) = sb.deltas_coded {
+        let coded_delta = (delta - self.delta_prev) >> fs.deblock.block_delta_shift;
+        let mut w_deltas = WriterRecorder::new();
+        cw.write_block_deblock_deltas(&mut w_deltas, [coded_delta; FRAME_LF_COUNT], multi);
+        let len = if multi { FRAME_LF_COUNT + PLANES - 3 } else { 1 };
+        sb.patches.push((checkpoint.clone(), len, w_deltas));
+        self.delta_prev = delta;
+      }
+    }
+    mem::swap(&mut cw.fc, &mut self.delta_lf_fc);
+  }
+
+  // Saves the stripe boundaries of the filter block rows `fbys` of
+  // superblock row `sby`, picks the cdef_idx of its superblocks, the frame
+  // strengths on the first row coding any, and filters them with CDEF.
+  // Loop restoration reads the deblocked rows around the stripe
+  // boundaries, so they are saved before CDEF overwrites them.
+  fn cdef_rows(&mut self, fi: &mut FrameInvariants, fs: &mut FrameState, cw: &mut ContextWriter,
+               sby: usize, fbys: Range<usize>) {
+    let fb_width = (fi.padded_w + 63) / 64;
+    let mut strips = Vec::with_capacity(fbys.len());
+    let mut dirs = Vec::with_capacity(fbys.len() * fb_width);
+    for fby in fbys.clone() {
+      if let Some(ref mut boundaries) = self.boundaries {
+        boundaries.save_rows(fi, &fs.rec, fby);
+      }
+      if self.cdef {
+        cdef_fill_strip(fi, &fs.rec, fby, &mut self.cdef_strip);
+        dirs.extend(cdef_analyze_strip(fi, &self.cdef_strip, &cw.bc, fby));
+        strips.push(self.cdef_strip.clone());
+      }
+    }
+
+    if self.cdef {
+      let row = &mut self.rows[sby - self.written];
+      let groups: Vec<Vec<FilterBlockOffset>> = row.iter()
+        .flat_map(|sb| sb.cdef_groups.iter().map(|g| g.1.clone())).collect();
+      let cdef_indices = if groups.is_empty() {
+        Vec::new()
+      } else if self.cdef_decided {
+        rdo_cdef_rows(fi, &fs.input, &strips, fbys.start, &dirs, &cw.bc, &groups)
+      } else {
+        self.cdef_decided = true;
+        rdo_cdef_frame(fi, &fs.input, &strips, fbys.start, &dirs, &cw.bc, &groups)
+      };
+
+      let mut cdef_indices = cdef_indices.iter();
+      for sb in row.iter_mut() {
+        for g in &sb.cdef_groups {
+          let cdef_index = *cdef_indices.next().unwrap();
+          for fbo in &g.1 {
+            cw.bc.set_cdef(fbo, cdef_index);
+          }
+          let mut w_cdef = WriterRecorder::new();
+          cw.write_cdef(&mut w_cdef, cdef_index, fi.cdef_bits);
+          sb.patches.push((g.0.clone(), sb.cdef_bits as usize, w_cdef));
+        }
+      }
+      for (i, (strip, fby)) in strips.iter().zip(fbys.clone()).enumerate() {
+        let dirs = &dirs[i * fb_width..(i + 1) * fb_width];
+        cdef_filter_strip(fi, strip, dirs, &mut fs.rec, &cw.bc, fby, &self.pool);
+      }
+    }
+
+    if let Some(ref mut upscaled) = self.upscaled {
+      for fby in fbys {
+        upscale_frame_rows(fi, &fs.rec, upscaled, fby);
+      }
+    }
+  }
 }
 
-fn encode_tile(fi: &FrameInvariants, fs: &mut FrameState) -> Vec<u8> {
+fn encode_tile(fi: &mut FrameInvariants, fs: &mut FrameState) -> Vec<u8> {
     let mut w = WriterEncoder::new();
 
     let fc = if fi.primary_ref_frame == PRIMARY_REF_NONE {
@@ -2617,40 +3711,52 @@ fn encode_tile(fi: &FrameInvariants, fs: &mut FrameState) -> Vec<u8> {
     };
 
     let bc = BlockContext::new(fi.w_in_b, fi.h_in_b);
-    // For now, restoration unit size is locked to superblock size.
     let mut cw = ContextWriter::new(fc, bc);
 
+    let sb_size = fi.sequence.sb_size();
+    let sb_size_log2 = fi.sequence.sb_size_log2();
+    // Quadrant size and offset used by the half-resolution ME
+    let qsize = sb_size.subsize(PartitionType::PARTITION_SPLIT);
+    let qoff = qsize.width_mi();
+
+    fs.intrabc_hashes = if fi.allow_intrabc {
+        Some(IntraBCHashTable::new(&fs.input.planes[0], fi.width, fi.height))
+    } else {
+        None
+    };
+
     // initial coarse ME loop
     let mut frame_pmvs = Vec::new();
 
     for sby in 0..fi.sb_height {
         for sbx in 0..fi.sb_width {
-            let sbo = SuperBlockOffset { x: sbx, y: sby };
+            let sbo = SuperBlockOffset { x: sbx, y: sby, size_log2: sb_size_log2 };
             let bo = sbo.block_offset(0, 0);
             let mut pmvs: [Option<MotionVector>; REF_FRAMES] = [None; REF_FRAMES];
             for i in 0..INTER_REFS_PER_FRAME {
                 let r = fi.ref_frames[i] as usize;
                 if pmvs[r].is_none() {
-                    assert!(!fi.sequence.use_128x128_superblock);
-                    pmvs[r] = estimate_motion_ss4(fi, fs, BlockSize::BLOCK_64X64, r, &bo);
+                    pmvs[r] = estimate_motion_ss4(fi, fs, sb_size, r, &bo);
                 }
             }
             frame_pmvs.push(pmvs);
         }
     }
 
+    let mut pipeline = LoopFilterPipeline::new(fi, fs, fc);
+
     // main loop
     for sby in 0..fi.sb_height {
         cw.bc.reset_left_contexts();
+        let mut row = Vec::with_capacity(fi.sb_width);
 
         for sbx in 0..fi.sb_width {
-            let mut w_pre_cdef = WriterRecorder::new();
-            let mut w_post_cdef = WriterRecorder::new();
-            let mut cdef_index = 0;
-            let sbo = SuperBlockOffset { x: sbx, y: sby };
+            let mut w_sb = WriterRecorder::new();
+            let sbo = SuperBlockOffset { x: sbx, y: sby, size_log2: sb_size_log2 };
             let bo = sbo.block_offset(0, 0);
-            cw.bc.cdef_coded = false;
+            cw.bc.cdef_coded = Default::default();
             cw.bc.code_deltas = fi.delta_q_present;
+            cw.bc.deltas_coded = None;
 
             // Do subsampled ME
             let mut pmvs: [[Option<MotionVector>; REF_FRAMES]; 5] = [[None; REF_FRAMES]; 5];
@@ -2680,18 +3786,17 @@ fn encode_tile(fi: &FrameInvariants, fs: &mut FrameState) -> Vec<u8> {
                             None
                         };
 
-                        assert!(!fi.sequence.use_128x128_superblock);
                         pmvs[1][r] = estimate_motion_ss2(
-                            fi, fs, BlockSize::BLOCK_32X32, r, &sbo.block_offset(0, 0), &[Some(pmv), pmv_w, pmv_n]
+                            fi, fs, qsize, r, &sbo.block_offset(0, 0), &[Some(pmv), pmv_w, pmv_n]
                         );
                         pmvs[2][r] = estimate_motion_ss2(
-                            fi, fs, BlockSize::BLOCK_32X32, r, &sbo.block_offset(8, 0), &[Some(pmv), pmv_e, pmv_n]
+                            fi, fs, qsize, r, &sbo.block_offset(qoff, 0), &[Some(pmv), pmv_e, pmv_n]
                         );
                         pmvs[3][r] = estimate_motion_ss2(
-                            fi, fs, BlockSize::BLOCK_32X32, r, &sbo.block_offset(0, 8), &[Some(pmv), pmv_w, pmv_s]
+                            fi, fs, qsize, r, &sbo.block_offset(0, qoff), &[Some(pmv), pmv_w, pmv_s]
                         );
                         pmvs[4][r] = estimate_motion_ss2(
-                            fi, fs, BlockSize::BLOCK_32X32, r, &sbo.block_offset(8, 8), &[Some(pmv), pmv_e, pmv_s]
+                            fi, fs, qsize, r, &sbo.block_offset(qoff, qoff), &[Some(pmv), pmv_e, pmv_s]
                         );
                     }
                 }
@@ -2699,61 +3804,52 @@ fn encode_tile(fi: &FrameInvariants, fs: &mut FrameState) -> Vec<u8> {
 
             // Encode SuperBlock
             if fi.config.speed_settings.encode_bottomup {
-                encode_partition_bottomup(fi, fs, &mut cw,
-                                          &mut w_pre_cdef, &mut w_post_cdef,
-                                          BlockSize::BLOCK_64X64, &bo, &pmvs);
+                encode_partition_bottomup(fi, fs, &mut cw, &mut w_sb,
+                                          sb_size, &bo, &pmvs);
             }
             else {
-                encode_partition_topdown(fi, fs, &mut cw,
-                                         &mut w_pre_cdef, &mut w_post_cdef,
-                                         BlockSize::BLOCK_64X64, &bo, &None, &pmvs);
+                encode_partition_topdown(fi, fs, &mut cw, &mut w_sb,
+                                         sb_size, &bo, &None, &pmvs);
             }
 
-            // CDEF has to be decisded before loop restoration, but coded after
-            if cw.bc.cdef_coded {
-                cdef_index = rdo_cdef_decision(&sbo, fi, fs, &mut cw);
-                cw.bc.set_cdef(&sbo, cdef_index);
-            }
-
-            // loop restoration must be decided last but coded before anything else
-            if fi.sequence.enable_restoration {
-                fs.restoration.lrf_optimize_superblock(&sbo, fi, &mut cw);
-                cw.write_lrf(&mut w, fi, &mut fs.restoration, &sbo);
+            // CDEF is decided once the row is deblocked. Filter blocks
+            // covered by a single coded cdef_idx share it.
+            let fbos = sbo.filter_blocks();
+            let cdef_coded: Vec<_> = fbos.iter().map(|fbo| {
+                cw.bc.cdef_coded[fbo.block_offset(0, 0).fb_index_in_sb()].clone()
+            }).collect();
+            let mut cdef_groups = Vec::new();
+            for (i, coded) in cdef_coded.iter().enumerate() {
+                if let Some(ref checkpoint) = *coded {
+                    if cdef_coded[..i].contains(coded) {
+                        continue;
+                    }
+                    let group: Vec<FilterBlockOffset> = fbos.iter().zip(&cdef_coded)
+                        .filter(|&(_, c)| c == coded)
+                        .map(|(fbo, _)| fbo.clone())
+                        .collect();
+                    cdef_groups.push((checkpoint.clone(), group));
+                }
             }
 
-            // Once loop restoration is coded, we can replay the initial block bits
-            w_pre_cdef.replay(&mut w);
-
-            if cw.bc.cdef_coded {
-                // CDEF index must be written in the middle, we can code it now
-                cw.write_cdef(&mut w, cdef_index, fi.cdef_bits);
-                // ...and then finally code what comes after the CDEF index
-                w_post_cdef.replay(&mut w);
-            }
+            row.push(RecordedSuperBlock {
+                w: w_sb,
+                cdef_bits: fi.cdef_bits,
+                cdef_groups,
+                deltas_coded: cw.bc.deltas_coded.clone(),
+                patches: Vec::new()
+            });
         }
+        pipeline.push_row(fi, fs, &mut cw, &mut w, row);
     }
-    /* TODO: Don't apply if lossless */
-    deblock_filter_optimize(fi, fs, &mut cw.bc);
-    if fs.deblock.levels[0] != 0 || fs.deblock.levels[1] != 0 {
-        deblock_filter_frame(fs, &mut cw.bc, fi.sequence.bit_depth);
-    }
-    {
-      // Until the loop filters are pipelined, we'll need to keep
-      // around a copy of both the pre- and post-cdef frame.
-      let pre_cdef_frame = fs.rec.clone();
-
-      /* TODO: Don't apply if lossless */
-      if fi.sequence.enable_cdef {
-        cdef_filter_frame(fi, &mut fs.rec, &mut cw.bc);
-      }
-      /* TODO: Don't apply if lossless */
-      if fi.sequence.enable_restoration {
-        fs.restoration.lrf_filter_frame(&mut fs.rec, &pre_cdef_frame, fi.sequence.bit_depth);
-      }
-    }
+    fs.intrabc_hashes = None;
+    pipeline.finish(fi, fs, &mut cw);
 
     fs.cdfs = cw.fc;
     fs.cdfs.reset_counts();
+    if !fi.intra_only {
+      fs.frame_mvs = Some(cw.bc.save_motion_vectors(fi));
+    }
 
     let mut h = w.done();
     h.push(0); // superframe anti emulation
@@ -2777,9 +3873,7 @@ pub fn encode_frame(fi: &mut FrameInvariants, fs: &mut FrameState) -> Vec<u8> {
     if fi.show_existing_frame {
         write_obus(&mut packet, fi, fs).unwrap();
         match fi.rec_buffer.frames[fi.frame_to_show_map_idx as usize] {
-            Some(ref rec) => for p in 0..3 {
-                fs.rec.planes[p].data.copy_from_slice(rec.frame.planes[p].data.as_slice());
-            },
+            Some(ref rec) => fs.rec = rec.frame.clone(),
             None => (),
         }
     } else {
@@ -2797,13 +3891,67 @@ pub fn encode_frame(fi: &mut FrameInvariants, fs: &mut FrameState) -> Vec<u8> {
             }
         }
 
+        let reference_select = fi.reference_mode != ReferenceMode::SINGLE;
+        match fi.sequence.get_skip_mode_frames(fi, reference_select) {
+            Some(skip_mode_frames) => {
+                fi.skip_mode_present = true;
+                fi.skip_mode_frames = skip_mode_frames;
+            },
+            None => fi.skip_mode_present = false
+        }
+
+        if fi.frame_type == FrameType::KEY && fi.sequence.force_screen_content_tools == 2 {
+            let stats = ScreenContentStats::new(
+                &fs.input.planes[0], fi.width, fi.height, fi.sequence.bit_depth
+            );
+            fi.set_screen_content_tools(stats.is_screen_content());
+        }
+
+        if fi.use_ref_frame_mvs {
+            fi.motion_field_mvs = project_motion_field(fi);
+        }
+
         fs.input_hres.downsample_from(&fs.input.planes[0]);
         fs.input_hres.pad(fi.width, fi.height);
         fs.input_qres.downsample_from(&fs.input_hres);
         fs.input_qres.pad(fi.width, fi.height);
 
+        if !fi.intra_only {
+            fs.scale_references(fi);
+        }
+
+        // Warped global motion is not used with integer motion vectors
+        if !fi.intra_only && fi.config.speed_settings.global_motion && fi.force_integer_mv == 0 {
+            for i in LAST_FRAME..ALTREF_FRAME+1 {
+                let slot = fi.ref_frames[i - LAST_FRAME];
+                // References sharing a slot share the same motion
+                let (mode, params) =
+                    match (LAST_FRAME..i).find(|&j| fi.ref_frames[j - LAST_FRAME] == slot) {
+                        Some(j) => (fi.globalmv_transformation_type[j], fi.gm_params[j]),
+                        None => estimate_global_motion(fi, fs, i)
+                    };
+                fi.globalmv_transformation_type[i] = mode;
+                fi.gm_params[i] = params;
+            }
+        }
+
+        if !fi.intra_only && !fi.is_filter_switchable {
+            let luma = &fs.input.planes[0];
+            fi.interpolation_filter = select_interp_filter(
+                luma, luma.cfg.width, luma.cfg.height, fi.sequence.bit_depth
+            );
+        }
+
         segmentation_optimize(fi, fs);
 
+        // Deblocking levels are offset by superblock through delta_lf,
+        // which is only signaled along with quantizer deltas
+        fi.delta_q_present = fi.config.delta_lf && fi.base_q_idx > 0 && !fi.allow_intrabc;
+        let (ref_deltas, mode_deltas) = fi.prev_deblock_deltas();
+        fs.deblock.ref_deltas = ref_deltas;
+        fs.deblock.mode_deltas = mode_deltas;
+        fs.deblock.block_deltas_enabled = fi.delta_q_present;
+
         let tile = encode_tile(fi, fs); // actually tile group
 
         write_obus(&mut packet, fi, fs).unwrap();
@@ -2834,30 +3982,613 @@ pub fn encode_frame(fi: &mut FrameInvariants, fs: &mut FrameState) -> Vec<u8> {
 }
 
 pub fn update_rec_buffer(fi: &mut FrameInvariants, fs: FrameState) {
-  let rfs = Rc::new(
+  let mut ref_order_hints = [0; INTER_REFS_PER_FRAME];
+  if !fi.intra_only {
+    for i in 0..INTER_REFS_PER_FRAME {
+      ref_order_hints[i] = fi.ref_order_hint(LAST_FRAME + i);
+    }
+  }
+  let rfs = Arc::new(
     ReferenceFrame {
       order_hint: fi.order_hint,
+      width: fi.upscaled_width,
+      height: fi.height,
       frame: fs.rec,
       input_hres: fs.input_hres,
       input_qres: fs.input_qres,
-      cdfs: fs.cdfs
+      cdfs: fs.cdfs,
+      ref_order_hints,
+      frame_mvs: fs.frame_mvs
     }
   );
   for i in 0..(REF_FRAMES as usize) {
     if (fi.refresh_frame_flags & (1 << i)) != 0 {
-      fi.rec_buffer.frames[i] = Some(Rc::clone(&rfs));
+      fi.rec_buffer.frames[i] = Some(Arc::clone(&rfs));
       fi.rec_buffer.deblock[i] = fs.deblock;
+      fi.rec_buffer.gm_params[i] = fi.gm_params;
     }
   }
 }
 
 #[cfg(test)]
-mod test {
+pub mod test {
   use super::*;
+  use bitstream_io::BitReader;
+  use rand::{ChaChaRng, Rng, SeedableRng};
+
+  /// The first inter frame of a sequence, whose references LAST..ALTREF are
+  /// in slots 0..7, all holding the coded key frame
+  pub fn inter_frame(width: usize, height: usize) -> FrameInvariants {
+    let seq = Sequence::new(&FrameInfo { width, height, ..Default::default() });
+    let mut fi = FrameInvariants::new(width, height, EncoderConfig::with_speed_preset(10), seq);
+    fi = FrameInvariants::new_key_frame(&fi, 0);
+    let mut fs = FrameState::new(&fi);
+    encode_frame(&mut fi, &mut fs);
+    update_rec_buffer(&mut fi, fs);
+    fi = FrameInvariants::new_inter_frame(&fi, 0, 1, 10).0;
+    for i in 0..INTER_REFS_PER_FRAME {
+      fi.ref_frames[i] = i as u8;
+    }
+    fi
+  }
 
   #[test]
   fn check_partition_types_order() {
       assert_eq!(RAV1E_PARTITION_TYPES[RAV1E_PARTITION_TYPES.len() - 1],
                 PartitionType::PARTITION_SPLIT);
   }
+
+  #[test]
+  fn film_grain_params_syntax() {
+    let seq = Sequence::new(&FrameInfo { width: 64, height: 64, ..Default::default() });
+    let mut fi = FrameInvariants::new(64, 64, EncoderConfig::default(), seq);
+    let mut params = FilmGrainParams { random_seed: 1234, ar_coeff_lag: 1, ..Default::default() };
+    params.num_y_points = 2;
+    params.scaling_points_y[0] = [0, 20];
+    params.scaling_points_y[1] = [255, 40];
+    params.num_cb_points = 1;
+    params.scaling_points_cb[0] = [128, 10];
+    params.num_cr_points = 1;
+    params.scaling_points_cr[0] = [64, 30];
+    params.ar_coeffs_y[..4].copy_from_slice(&[1, -4, 1, 4]);
+    params.ar_coeffs_cb[..5].copy_from_slice(&[-3, 1, -4, 6, -1]);
+    fi.frame_type = FrameType::INTER;
+    fi.film_grain_params = Some(params);
+
+    let mut buf = Vec::new();
+    {
+      let mut bw = BitWriter::endian(&mut buf, BigEndian);
+      bw.write_film_grain_params(&fi).unwrap();
+      bw.byte_align().unwrap();
+    }
+    let mut br = BitReader::endian(io::Cursor::new(&buf), BigEndian);
+    let mut read = |bits: u32| br.read::<u32>(bits).unwrap();
+    assert_eq!(read(1), 1); // apply_grain
+    assert_eq!(read(16), 1234);
+    assert_eq!(read(1), 1); // update_grain
+    assert_eq!(read(4), 2);
+    assert_eq!((read(8), read(8), read(8), read(8)), (0, 20, 255, 40));
+    assert_eq!(read(1), 0); // chroma_scaling_from_luma
+    assert_eq!((read(4), read(8), read(8)), (1, 128, 10));
+    assert_eq!((read(4), read(8), read(8)), (1, 64, 30));
+    assert_eq!(read(2), 0); // grain_scaling_minus_8
+    assert_eq!(read(2), 1); // ar_coeff_lag
+    let coeffs_y: Vec<u32> = (0..4).map(|_| read(8)).collect();
+    assert_eq!(coeffs_y, [129, 124, 129, 132]);
+    let coeffs_cb: Vec<u32> = (0..5).map(|_| read(8)).collect();
+    assert_eq!(coeffs_cb, [125, 129, 124, 134, 127]);
+    let coeffs_cr: Vec<u32> = (0..5).map(|_| read(8)).collect();
+    assert_eq!(coeffs_cr, [128; 5]);
+    assert_eq!(read(2), 0); // ar_coeff_shift_minus_6
+    assert_eq!(read(2), 0); // grain_scale_shift
+    assert_eq!((read(8), read(8), read(9)), (192, 128, 256));
+    assert_eq!((read(8), read(8), read(9)), (192, 128, 256));
+    assert_eq!(read(1), 1); // overlap_flag
+    assert_eq!(read(1), 0); // clip_to_restricted_range
+
+    fi.film_grain_params = None;
+    let mut buf = Vec::new();
+    {
+      let mut bw = BitWriter::endian(&mut buf, BigEndian);
+      bw.write_film_grain_params(&fi).unwrap();
+      bw.byte_align().unwrap();
+    }
+    assert_eq!(buf, [0]);
+  }
+
+  #[test]
+  fn superres_fixed_denom_frame_sizes() {
+    let info = FrameInfo { width: 200, height: 64, ..Default::default() };
+    let mut enc = EncoderConfig::with_speed_preset(10);
+    enc.superres_mode = SuperresMode::Fixed;
+    enc.superres_denom = 12;
+    let mut seq = Sequence::new(&info);
+    seq.enable_superres = true;
+    let mut fi = FrameInvariants::new(200, 64, enc, seq);
+    let input = Arc::new(Frame::new(200, 64, info.chroma_sampling));
+
+    for idx in 0..3 {
+      fi = if idx == 0 {
+        FrameInvariants::new_key_frame(&fi, 0)
+      } else {
+        FrameInvariants::new_inter_frame(&fi, 0, idx, 10).0
+      };
+      assert_eq!((fi.upscaled_width, fi.width, fi.superres_denom), (200, 133, 12));
+
+      let mut buf = Vec::new();
+      {
+        let mut bw = BitWriter::endian(&mut buf, BigEndian);
+        bw.write_superres_params(&fi).unwrap();
+        bw.byte_align().unwrap();
+      }
+      // use_superres, then coded_denom = 12 - SUPERRES_DENOM_MIN
+      assert_eq!(buf, [0b1011_0000]);
+
+      let mut fs = FrameState::new(&fi);
+      encode_frame(&mut fi, &mut fs);
+      // References are stored at the upscaled size
+      assert_eq!(fs.rec.planes[0].cfg.width, 200);
+      fs.set_reference_input(&fi, &input);
+      update_rec_buffer(&mut fi, fs);
+    }
+    let rec = fi.rec_buffer.frames[fi.ref_frames[0] as usize].as_ref().unwrap();
+    assert_eq!((rec.width, rec.height), (200, 64));
+  }
+
+  #[test]
+  fn resized_inter_frame_size_syntax() {
+    let info = FrameInfo { width: 64, height: 64, ..Default::default() };
+    let seq = Sequence::new(&info);
+    let mut fi = FrameInvariants::new(64, 64, EncoderConfig::with_speed_preset(10), seq);
+
+    // Frame header of an inter frame, read up to its frame size syntax,
+    // with frame_size_override_flag
+    let header = |fi: &FrameInvariants| {
+      let mut buf = Vec::new();
+      {
+        let mut bw = BitWriter::endian(&mut buf, BigEndian);
+        bw.write_frame_header_obu(fi, &FrameState::new(fi)).unwrap();
+      }
+      let mut br = BitReader::endian(io::Cursor::new(buf), BigEndian);
+      assert_eq!(br.read::<u32>(1).unwrap(), 0); // show_existing_frame
+      assert_eq!(br.read::<u32>(2).unwrap(), FrameType::INTER as u32);
+      assert_eq!(br.read::<u32>(1).unwrap(), 1); // show_frame
+      assert_eq!(br.read::<u32>(2).unwrap(), 0); // error_resilient_mode, disable_cdf_update
+      let frame_size_override_flag = br.read_bit().unwrap();
+      // order_hint, primary_ref_frame, refresh_frame_flags,
+      // frame_refs_short_signaling and ref_frame_idx
+      br.read::<u64>(6 + 3 + 8 + 1 + 3 * INTER_REFS_PER_FRAME as u32).unwrap();
+      (frame_size_override_flag, br)
+    };
+    let sizes = [(64, 64), (48, 40), (48, 40), (64, 64)];
+    for (idx, &(width, height)) in sizes.iter().enumerate() {
+      fi = if idx == 0 {
+        FrameInvariants::new_key_frame(&fi, 0)
+      } else {
+        FrameInvariants::new_inter_frame(&fi, 0, idx as u64, 10).0
+      };
+      fi.set_frame_size(width, height);
+
+      if idx > 0 {
+        let (frame_size_override_flag, mut br) = header(&fi);
+        assert_eq!(frame_size_override_flag, (width, height) != (64, 64));
+        if frame_size_override_flag {
+          // found_ref for each reference until one has the frame size
+          let found = fi.ref_frames.iter().position(|&r| {
+            let rec = fi.rec_buffer.frames[r as usize].as_ref().unwrap();
+            (rec.width, rec.height) == (width, height)
+          });
+          assert_eq!(found.is_some(), idx == 2);
+          for i in 0..INTER_REFS_PER_FRAME {
+            assert_eq!(br.read_bit().unwrap(), found == Some(i));
+            if found == Some(i) {
+              break;
+            }
+          }
+          if found.is_none() {
+            assert_eq!(br.read::<u32>(7).unwrap(), width as u32 - 1);
+            assert_eq!(br.read::<u32>(7).unwrap(), height as u32 - 1);
+            assert!(!br.read_bit().unwrap()); // render_and_frame_size_different
+          }
+        } else {
+          assert!(!br.read_bit().unwrap()); // render_and_frame_size_different
+        }
+      }
+
+      let mut fs = FrameState::new(&fi);
+      encode_frame(&mut fi, &mut fs);
+      assert_eq!((fs.rec.planes[0].cfg.width, fs.rec.planes[0].cfg.height), (width, height));
+      update_rec_buffer(&mut fi, fs);
+    }
+  }
+
+  #[test]
+  fn superblock_128_restoration_units() {
+    let mut seq = Sequence::new(&FrameInfo { width: 320, height: 192, ..Default::default() });
+    assert_eq!((seq.sb_size(), seq.sb_size_log2()), (BlockSize::BLOCK_64X64, 6));
+    seq.use_128x128_superblock = true;
+    assert_eq!((seq.sb_size(), seq.sb_size_log2()), (BlockSize::BLOCK_128X128, 7));
+
+    let cfg = Plane::new(320, 192, 0, 0, 0, 0).cfg;
+    let sbo = |x, y| SuperBlockOffset { x, y, size_log2: seq.sb_size_log2() };
+    // 5x3 units of 64: each 128x128 superblock codes up to 2x2 of them
+    let rp = RestorationPlane::new(&cfg, RESTORE_WIENER, 64);
+    assert_eq!(rp.restoration_units_in_sb(&sbo(0, 0), 8), (0..2, 0..2));
+    assert_eq!(rp.restoration_units_in_sb(&sbo(1, 0), 8), (0..2, 2..4));
+    assert_eq!(rp.restoration_units_in_sb(&sbo(2, 1), 8), (2..3, 4..5));
+    // Twice as many upscaled columns with superres at 2:1
+    assert_eq!(rp.restoration_units_in_sb(&sbo(0, 0), 16), (0..2, 0..4));
+    assert_eq!(rp.restoration_units_in_sb(&sbo(1, 0), 16), (0..2, 4..5));
+    // 3x2 units of 128 map one to one
+    let rp = RestorationPlane::new(&cfg, RESTORE_WIENER, 128);
+    assert_eq!(rp.restoration_units_in_sb(&sbo(1, 1), 8), (1..2, 1..2));
+    assert_eq!(rp.restoration_units_in_sb(&sbo(2, 0), 8), (0..1, 2..3));
+  }
+  #[test]
+  fn quantizer_matrix_levels_and_tables() {
+    let seq = Sequence::new(&FrameInfo { width: 64, height: 64, ..Default::default() });
+    let mut enc = EncoderConfig::default();
+    enc.qm_mode = QmMode::Fixed;
+    enc.qm_level = 0;
+    let mut fi = FrameInvariants::new(64, 64, enc, seq);
+    fi.apply_qm_cfg();
+    assert!(fi.using_qmatrix);
+
+    // Spot checks against the spec Quantizer_Matrix at level 0
+    let qm = fi.qm_tbl(0, TxSize::TX_4X4, TxType::DCT_DCT).unwrap();
+    assert_eq!(qm, &[32, 43, 73, 97, 43, 67, 94, 110, 73, 94, 137, 150, 97, 110, 150, 200][..]);
+    let qm = fi.qm_tbl(1, TxSize::TX_4X4, TxType::ADST_DCT).unwrap();
+    assert_eq!(qm, &[35, 46, 57, 66, 46, 60, 69, 71, 57, 69, 90, 90, 66, 71, 90, 109][..]);
+    let qm = fi.qm_tbl(0, TxSize::TX_8X8, TxType::DCT_DCT).unwrap();
+    assert_eq!(&qm[..8], &[32, 32, 38, 51, 68, 84, 95, 109]);
+    assert_eq!(qm[63], 220);
+    // 64-point transforms use the 32x32 weights, 1D transforms none
+    assert_eq!(fi.qm_tbl(0, TxSize::TX_64X64, TxType::DCT_DCT).unwrap().len(), 1024);
+    assert!(fi.qm_tbl(0, TxSize::TX_8X8, TxType::IDTX).is_none());
+    assert!(fi.qm_tbl(0, TxSize::TX_8X8, TxType::V_DCT).is_none());
+
+    // The automatic levels follow the AC quantizer of each plane
+    fi.config.qm_mode = QmMode::Auto;
+    fi.base_q_idx = 200;
+    fi.ac_delta_q = [0, -64, -64];
+    fi.apply_qm_cfg();
+    assert_eq!((fi.qm_y, fi.qm_u, fi.qm_v), (14, 12, 12));
+    fi.base_q_idx = 255;
+    fi.ac_delta_q = [0; 3];
+    fi.apply_qm_cfg();
+    assert_eq!((fi.qm_y, fi.qm_u, fi.qm_v), (15, 15, 15));
+    assert!(!fi.using_qmatrix);
+    assert!(fi.qm_tbl(0, TxSize::TX_4X4, TxType::DCT_DCT).is_none());
+  }
+  #[test]
+  fn quantization_params_syntax() {
+    let mut seq = Sequence::new(&FrameInfo { width: 64, height: 64, ..Default::default() });
+    let write = |seq: &Sequence, dc_delta_q: [i8; 3], ac_delta_q: [i8; 3]| {
+      let mut fi = FrameInvariants::new(64, 64, EncoderConfig::default(), *seq);
+      fi.base_q_idx = 100;
+      fi.dc_delta_q = dc_delta_q;
+      fi.ac_delta_q = ac_delta_q;
+      let mut buf = Vec::new();
+      {
+        let mut bw = BitWriter::endian(&mut buf, BigEndian);
+        bw.write_quantization_params(&fi).unwrap();
+        bw.byte_align().unwrap();
+      }
+      buf
+    };
+
+    // U and V share their offsets without separate_uv_delta_q
+    let buf = write(&seq, [0, -64, -64], [0, 63, 63]);
+    let mut br = BitReader::endian(io::Cursor::new(&buf), BigEndian);
+    assert_eq!(br.read::<u32>(8).unwrap(), 100);
+    assert_eq!(br.read::<u32>(1).unwrap(), 0); // DeltaQYDc
+    assert_eq!(br.read::<u32>(1).unwrap(), 1);
+    assert_eq!(br.read_signed::<i32>(7).unwrap(), -64);
+    assert_eq!(br.read::<u32>(1).unwrap(), 1);
+    assert_eq!(br.read_signed::<i32>(7).unwrap(), 63);
+    assert_eq!(br.read::<u32>(1).unwrap(), 0); // using_qmatrix
+
+    seq.separate_uv_delta_q = true;
+    let buf = write(&seq, [3, 0, 0], [0, -10, 5]);
+    let mut br = BitReader::endian(io::Cursor::new(&buf), BigEndian);
+    assert_eq!(br.read::<u32>(8).unwrap(), 100);
+    assert_eq!(br.read::<u32>(1).unwrap(), 1);
+    assert_eq!(br.read_signed::<i32>(7).unwrap(), 3);
+    assert_eq!(br.read::<u32>(1).unwrap(), 1); // diff_uv_delta
+    assert_eq!(br.read::<u32>(2).unwrap(), 0b01); // no DeltaQUDc, DeltaQUAc
+    assert_eq!(br.read_signed::<i32>(7).unwrap(), -10);
+    assert_eq!(br.read::<u32>(2).unwrap(), 0b01); // no DeltaQVDc, DeltaQVAc
+    assert_eq!(br.read_signed::<i32>(7).unwrap(), 5);
+    assert_eq!(br.read::<u32>(1).unwrap(), 0);
+
+    // Equal offsets are not repeated for V
+    let buf = write(&seq, [0, 0, 0], [0, 0, 0]);
+    assert_eq!(buf, [100, 0]);
+  }
+
+  #[test]
+  fn plane_lambda_follows_chroma_delta_q() {
+    let seq = Sequence::new(&FrameInfo { width: 64, height: 64, ..Default::default() });
+    let mut fi = FrameInvariants::new(64, 64, EncoderConfig::default(), seq);
+    fi.base_q_idx = 120;
+    fi.set_dist_scale();
+    for p in 0..3 {
+      assert_eq!(get_plane_lambda(&fi, p), get_lambda(&fi));
+      assert_eq!(weight_plane_dist(&fi, p, 1000), 1000);
+    }
+
+    // A finer chroma quantizer weights its distortion up and its lambda down
+    fi.ac_delta_q = [0, -20, 20];
+    fi.set_dist_scale();
+    assert_eq!(fi.dist_scale[0], 1.0);
+    assert!(fi.dist_scale[1] > 1.0 && fi.dist_scale[2] < 1.0);
+    for p in 1..3 {
+      let q = ac_q(fi.base_q_idx, fi.ac_delta_q[p], 8) as f64;
+      let q_y = ac_q(fi.base_q_idx, 0, 8) as f64;
+      assert!((get_plane_lambda(&fi, p) * (q_y / q).powi(2) - get_lambda(&fi)).abs() < 1e-6);
+      assert_eq!(weight_plane_dist(&fi, p, 1000), (1000.0 * fi.dist_scale[p]) as u64);
+    }
+    assert!(weight_plane_dist(&fi, 1, 1000) > 1000);
+  }
+
+
+
+  #[test]
+  fn skip_mode_frames() {
+    let mut fi = inter_frame(64, 64);
+    // The order hints of the frame and its references LAST..ALTREF
+    let with_refs = |fi: &mut FrameInvariants, order_hint, hints: [u32; INTER_REFS_PER_FRAME]| {
+      fi.order_hint = order_hint;
+      for (i, &hint) in hints.iter().enumerate() {
+        Arc::make_mut(fi.rec_buffer.frames[i].as_mut().unwrap()).order_hint = hint;
+      }
+    };
+
+    // Frame 2 of a pyramid over frames 0..4: the nearest past and future
+    // frames, even if farther ones come later in the reference list
+    with_refs(&mut fi, 2, [1, 0, 0, 0, 3, 4, 4]);
+    assert_eq!(fi.sequence.get_skip_mode_frames(&fi, true), Some([LAST_FRAME, BWDREF_FRAME]));
+    with_refs(&mut fi, 2, [0, 1, 0, 0, 4, 4, 3]);
+    assert_eq!(fi.sequence.get_skip_mode_frames(&fi, true), Some([LAST2_FRAME, ALTREF_FRAME]));
+    assert_eq!(fi.sequence.get_skip_mode_frames(&fi, false), None);
+    // Without future frames, the two nearest past ones
+    with_refs(&mut fi, 4, [3, 2, 0, 0, 1, 0, 0]);
+    assert_eq!(fi.sequence.get_skip_mode_frames(&fi, true), Some([LAST_FRAME, LAST2_FRAME]));
+    // Without past frames, or a second distinct past frame, there is none
+    with_refs(&mut fi, 0, [4, 4, 4, 4, 2, 2, 4]);
+    assert_eq!(fi.sequence.get_skip_mode_frames(&fi, true), None);
+    with_refs(&mut fi, 4, [0, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(fi.sequence.get_skip_mode_frames(&fi, true), None);
+  }
+
+  #[test]
+  fn ext_partition_blocks_cover_block() {
+      let bo = BlockOffset { x: 4, y: 8 };
+      for &partition in RAV1E_PARTITION_TYPES.iter().filter(|p| p.is_ext()) {
+          let blocks = partition.sub_blocks(&bo, BlockSize::BLOCK_16X16);
+          let mut covered = [[0; 4]; 4];
+          for (sub_bo, subsize) in blocks {
+              for y in 0..subsize.height_mi() {
+                  for x in 0..subsize.width_mi() {
+                      covered[sub_bo.y - bo.y + y][sub_bo.x - bo.x + x] += 1;
+                  }
+              }
+          }
+          assert_eq!(covered, [[1; 4]; 4]);
+      }
+  }
+
+  #[test]
+  fn cdef_header_strengths() {
+    let seq = Sequence::new(&FrameInfo { width: 64, height: 64, ..Default::default() });
+    let mut fi = FrameInvariants::new(64, 64, EncoderConfig::default(), seq);
+    let mut ra = ChaChaRng::from_seed([0; 32]);
+    let mut rec = Frame::new(64, 64, ChromaSampling::Cs420);
+    for p in rec.planes.iter_mut() {
+      for v in p.data.iter_mut() {
+        *v = ra.gen_range(0, 256);
+      }
+    }
+    let bc = BlockContext::new(16, 16);
+    let mut padded = cdef_strip(&fi, &rec);
+    cdef_fill_strip(&fi, &rec, 0, &mut padded);
+    let dirs = cdef_analyze_strip(&fi, &padded, &bc, 0);
+    let fbo = FilterBlockOffset { x: 0, y: 0 };
+    fi.cdef_damping = 4;
+    for i in 0..8 {
+      fi.cdef_y_strengths[i] = 8 * i as u8 + 3;
+      fi.cdef_uv_strengths[i] = 62 - 5 * i as u8;
+    }
+
+    for bits in 0..4 {
+      fi.cdef_bits = bits;
+      let mut buf = Vec::new();
+      {
+        let mut bw = BitWriter::endian(&mut buf, BigEndian);
+        bw.write_frame_cdef(&fi).unwrap();
+        bw.byte_align().unwrap();
+      }
+      let mut br = BitReader::endian(io::Cursor::new(&buf), BigEndian);
+      assert_eq!(br.read::<u8>(2).unwrap() + 3, fi.cdef_damping);
+      assert_eq!(br.read::<u8>(2).unwrap(), bits);
+      // Each cdef_idx is filtered with the strengths the header gives it
+      for cdef_index in 0..1 << bits {
+        let y = br.read::<u8>(6).unwrap();
+        let uv = br.read::<u8>(6).unwrap();
+        let mut out = Frame::new(64, 64, ChromaSampling::Cs420);
+        cdef_filter_superblock(&fi, &padded, &mut out, &bc, &fbo, &fbo, &fbo, cdef_index, &dirs[0]);
+        let mut expected = Frame::new(64, 64, ChromaSampling::Cs420);
+        for p in 0..3 {
+          let strength = if p == 0 { y } else { uv };
+          cdef_filter_superblock_plane(&padded, &mut expected, &bc, &fbo, &fbo, &fbo, p,
+                                       strength, fi.cdef_damping, 8, &dirs[0]);
+          assert!(out.planes[p].data == expected.planes[p].data);
+        }
+      }
+    }
+  }
+
+  /// A 200x136 frame of noisy gradients, cut into superblocks of blocks of
+  /// a single size with a mix of intra, inter and skip blocks and random
+  /// CDEF strengths
+  pub fn loop_filter_frame(ra: &mut ChaChaRng) -> (FrameInvariants, BlockContext, Frame) {
+    let (w, h) = (200, 136);
+    let info = FrameInfo { width: w, height: h, ..Default::default() };
+    let fi = FrameInvariants::new(w, h, EncoderConfig::default(), Sequence::new(&info));
+    let mut bc = BlockContext::new(fi.w_in_b, fi.h_in_b);
+    for sby in 0..fi.sb_height {
+      for sbx in 0..fi.sb_width {
+        let w_log2 = ra.gen_range(2, 7);
+        let h_log2 = ra.gen_range(cmp::max(w_log2, 4) - 2, cmp::min(w_log2 + 2, 6) + 1);
+        let bsize = BlockSize::from_width_and_height(1 << w_log2, 1 << h_log2);
+        for y in (0..16).step_by(bsize.height_mi()) {
+          for x in (0..16).step_by(bsize.width_mi()) {
+            let bo = BlockOffset { x: sbx * 16 + x, y: sby * 16 + y };
+            if bo.x + bsize.width_mi() > bc.cols || bo.y + bsize.height_mi() > bc.rows {
+              continue;
+            }
+            bc.set_block_size(&bo, bsize);
+            bc.set_tx_size(&bo, bsize, bsize.tx_size());
+            bc.set_skip(&bo, bsize, ra.gen());
+            if ra.gen() {
+              bc.set_ref_frames(&bo, bsize, [LAST_FRAME, NONE_FRAME]);
+            }
+          }
+        }
+        bc.set_cdef(&FilterBlockOffset { x: sbx, y: sby }, ra.gen_range(0, 8));
+      }
+    }
+
+    let mut rec = Frame::new(fi.padded_w, fi.padded_h, ChromaSampling::Cs420);
+    for p in rec.planes.iter_mut() {
+      let stride = p.cfg.stride;
+      for (i, v) in p.data.iter_mut().enumerate() {
+        let (x, y) = (i % stride / 4, i / stride / 4);
+        *v = (100 + (x * 7 + y * 13) % 40) as u16 + ra.gen_range(0, 6);
+      }
+    }
+    (fi, bc, rec)
+  }
+
+  #[test]
+  fn loop_filter_pipeline_matches_frame() {
+    let (mut fi, bc, rec) = loop_filter_frame(&mut ChaChaRng::from_seed([0; 32]));
+    let (_, _, input) = loop_filter_frame(&mut ChaChaRng::from_seed([1; 32]));
+    fi.config.speed_settings.fast_deblock = true;
+    let (fb_width, fb_height) = ((fi.padded_w + 63) / 64, (fi.padded_h + 63) / 64);
+    let mut fs = FrameState::new_with_frame(&fi, Arc::new(input));
+    fs.rec = rec.clone();
+    let fc = CDFContext::new(fi.base_q_idx);
+    let mut cw = ContextWriter::new(fc, bc);
+    let mut w = WriterEncoder::new();
+
+    // Superblocks coding a cdef_idx each, pushed a row at a time
+    let mut pipeline = LoopFilterPipeline::new(&fi, &fs, fc);
+    for sby in 0..fi.sb_height {
+      let row = (0..fi.sb_width).map(|sbx| {
+        let mut w_sb = WriterRecorder::new();
+        let checkpoint = Writer::checkpoint(&mut w_sb);
+        cw.write_cdef(&mut w_sb, 0, fi.cdef_bits);
+        let sbo = SuperBlockOffset { x: sbx, y: sby, size_log2: fi.sequence.sb_size_log2() };
+        RecordedSuperBlock {
+          w: w_sb,
+          cdef_bits: fi.cdef_bits,
+          cdef_groups: vec![(checkpoint, sbo.filter_blocks())],
+          deltas_coded: None,
+          patches: Vec::new()
+        }
+      }).collect();
+      pipeline.push_row(&mut fi, &mut fs, &mut cw, &mut w, row);
+      // The rows are deblocked a row behind the coding
+      assert_eq!(pipeline.deblocked, if sby + 1 < fi.sb_height { sby } else { fb_height });
+    }
+    assert_eq!(pipeline.written, fi.sb_height);
+    assert_eq!(pipeline.restored, (fi.height + 7) / 64 + 1);
+    pipeline.finish(&mut fi, &mut fs, &mut cw);
+
+    // Each filter over the whole frame in turn with the same decisions,
+    // keeping the deblocked frame
+    let mut expected = rec.clone();
+    for pli in 0..PLANES {
+      for &vertical in &[true, false] {
+        deblock_plane_pass(&fi, &fs.deblock, &mut expected.planes[pli], pli, &cw.bc, 8, vertical,
+                           0..cw.bc.rows);
+      }
+    }
+    let deblocked = expected.clone();
+    let mut padded = cdef_strip(&fi, &deblocked);
+    for (dst, src) in padded.planes.iter_mut().zip(deblocked.planes.iter()) {
+      let PlaneConfig { xdec, ydec, .. } = src.cfg;
+      *dst = Plane::new(dst.cfg.width, (fb_height * 64 >> ydec) + 4, xdec, ydec, 0, 0);
+      let stride = dst.cfg.stride;
+      for v in dst.data.iter_mut() {
+        *v = CDEF_VERY_LARGE;
+      }
+      for y in 0..fi.padded_h >> ydec {
+        dst.data[(y + 2) * stride + 2..][..fi.padded_w >> xdec]
+          .copy_from_slice(&src.data_origin()[y * src.cfg.stride..][..fi.padded_w >> xdec]);
+      }
+    }
+    for fby in 0..fb_height {
+      for fbx in 0..fb_width {
+        let fbo = FilterBlockOffset { x: fbx, y: fby };
+        let cdef_index = cw.bc.at(&fbo.block_offset(0, 0)).cdef_index;
+        let dirs = cdef_analyze_superblock(&padded, &cw.bc, &fbo, &fbo, 8);
+        cdef_filter_superblock(&fi, &padded, &mut expected, &cw.bc, &fbo, &fbo, &fbo, cdef_index,
+                               &dirs);
+      }
+    }
+    let mut boundaries = StripeBoundaries::new(&fi, &deblocked);
+    for fby in 0..fb_height {
+      boundaries.save_rows(&fi, &deblocked, fby);
+    }
+    let mut rs = fs.restoration.clone();
+    rs.lrf_filter_frame(&mut expected, &boundaries, 8, &WorkerPool::new(1));
+
+    for pli in 0..PLANES {
+      assert!(fs.rec.planes[pli].data == expected.planes[pli].data);
+      assert!(deblocked.planes[pli].data != rec.planes[pli].data);
+    }
+  }
+
+  // Packets and reconstructed planes of a few frames of noisy gradients
+  fn encode_gradients(enc: EncoderConfig) -> Vec<(Vec<u8>, Vec<Vec<u16>>)> {
+    let cfg = Config {
+      frame_info: FrameInfo { width: 200, height: 136, ..Default::default() },
+      timebase: Rational::new(1, 1000),
+      enc
+    };
+    let mut ctx = cfg.new_context();
+    let mut ra = ChaChaRng::from_seed([0; 32]);
+    for i in 0..3 {
+      let mut input = ctx.new_frame();
+      for p in Arc::get_mut(&mut input).unwrap().planes.iter_mut() {
+        let stride = p.cfg.stride;
+        for (j, v) in p.data.iter_mut().enumerate() {
+          *v = ((j % stride + j / stride + i * 3) / 2 % 200) as u16 + ra.gen_range(0, 24);
+        }
+      }
+      ctx.send_frame(input).unwrap();
+    }
+    ctx.flush();
+
+    let mut packets = Vec::new();
+    while let Ok(pkt) = ctx.receive_packet() {
+      let rec = pkt.rec.unwrap();
+      packets.push((pkt.data, rec.planes.iter().map(|p| p.data.clone()).collect()));
+    }
+    packets
+  }
+
+  #[test]
+  fn loop_filter_threads_bit_exact() {
+    let encode = |threads: usize| {
+      let mut enc = EncoderConfig::with_speed_preset(6);
+      enc.filter_threads = threads;
+      encode_gradients(enc)
+    };
+    let serial = encode(1);
+    assert_eq!(serial.len(), 3);
+    assert!(encode(3) == serial);
+  }
 }
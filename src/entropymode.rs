@@ -12,14 +12,10 @@
 use context::*;
 use partition::*;
 
-const PALATTE_BSIZE_CTXS: usize = 7;
-const PALETTE_Y_MODE_CONTEXTS: usize = 3;
-const PALETTE_UV_MODE_CONTEXTS: usize = 2;
-const PALETTE_COLOR_INDEX_CONTEXTS: usize = 5;
 const CDFMAX: u16 = 32768;
 const BLOCK_SIZE_GROUPS: usize = 4;
 const RESTORE_SWITCHABLE_TYPES: usize = 3;
-const TX_SIZE_CONTEXTS: usize = 3;
+pub const TX_SIZE_CONTEXTS: usize = 3;
 
 // from seg_common.h
 const MAX_SEGMENTS: usize = 8;
@@ -29,8 +25,8 @@ const SEG_TEMPORAL_PRED_CTXS: usize = 3;
 // enums.h
 const TX_SIZE_LUMA_MIN: usize = TxSize::TX_4X4 as usize;
 const TX_SIZE_CTX_MIN: usize = (TX_SIZE_LUMA_MIN + 1);
-const MAX_TX_CATS: usize = (TxSize::TX_SIZES - TX_SIZE_CTX_MIN);
-const MAX_TX_DEPTH: usize = 2;
+pub const MAX_TX_CATS: usize = (TxSize::TX_SIZES - TX_SIZE_CTX_MIN);
+pub const MAX_TX_DEPTH: usize = 2;
 
 // LUTS ---------------------
 
@@ -849,9 +845,8 @@ pub static default_cfl_alpha_cdf: [[u16; cdf_size!(CFL_ALPHABET_SIZE)];
   )
 ];
 
-// This does not appear to be used in the rust project currently
-const SWITCHABLE_FILTERS: usize = 3;
-const SWITCHABLE_FILTER_CONTEXTS: usize = (SWITCHABLE_FILTERS + 1) * 4;
+pub const SWITCHABLE_FILTERS: usize = 3;
+pub const SWITCHABLE_FILTER_CONTEXTS: usize = (SWITCHABLE_FILTERS + 1) * 4;
 
 pub static default_switchable_interp_cdf: [[u16;
   cdf_size!(SWITCHABLE_FILTERS)];
@@ -951,7 +946,7 @@ pub static default_wedge_interintra_cdf: [[u16; cdf_size!(2)];
 ];
 
 pub static default_compound_type_cdf: [[u16;
-  cdf_size!(CompoundType::COMPOUND_TYPES as usize - 1)];
+  cdf_size!(MASKED_COMPOUND_TYPES)];
   BlockSize::BLOCK_SIZES_ALL as usize] = [
   cdf!(16384),
   cdf!(16384),
@@ -1177,7 +1172,7 @@ pub static default_comp_bwdref_cdf: [[[u16; cdf_size!(2)]; BWD_REFS - 1];
 
 pub static default_palette_y_size_cdf: [[u16;
   cdf_size!(PaletteSize::PALETTE_SIZES as usize)];
-  PALATTE_BSIZE_CTXS] = [
+  PALETTE_BSIZE_CTXS] = [
   cdf!(7952, 13000, 18149, 21478, 25527, 29241),
   cdf!(7139, 11421, 16195, 19544, 23666, 28073),
   cdf!(7788, 12741, 17325, 20500, 24315, 28530),
@@ -1189,7 +1184,7 @@ pub static default_palette_y_size_cdf: [[u16;
 
 pub static default_palette_uv_size_cdf: [[u16;
   cdf_size!(PaletteSize::PALETTE_SIZES as usize)];
-  PALATTE_BSIZE_CTXS] = [
+  PALETTE_BSIZE_CTXS] = [
   cdf!(8713, 19979, 27128, 29609, 31331, 32272),
   cdf!(5839, 15573, 23581, 26947, 29848, 31700),
   cdf!(4426, 11260, 17999, 21483, 25863, 29430),
@@ -1201,7 +1196,7 @@ pub static default_palette_uv_size_cdf: [[u16;
 
 pub static default_palette_y_mode_cdf: [[[u16; cdf_size!(2)];
   PALETTE_Y_MODE_CONTEXTS];
-  PALATTE_BSIZE_CTXS] = [
+  PALETTE_BSIZE_CTXS] = [
   [cdf!(31676), cdf!(3419), cdf!(1261)],
   [cdf!(31912), cdf!(2859), cdf!(980)],
   [cdf!(31823), cdf!(3400), cdf!(781)],
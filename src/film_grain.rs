@@ -0,0 +1,852 @@
+// Copyright (c) 2018, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+#![cfg_attr(feature = "cargo-clippy", allow(needless_range_loop))]
+
+use encoder::Frame;
+use plane::Plane;
+
+use std::cmp;
+use std::str::FromStr;
+
+pub const NUM_Y_POINTS: usize = 14;
+pub const NUM_UV_POINTS: usize = 10;
+pub const NUM_Y_COEFFS: usize = 24;
+pub const NUM_UV_COEFFS: usize = 25;
+
+pub const DEFAULT_GRAIN_SEED: u16 = 10956;
+const GRAIN_SEED_INCREMENT: u16 = 3248;
+
+/// Lag of the auto-regressive model fitted to the estimated noise.
+const ESTIMATE_AR_LAG: usize = 3;
+/// Size of the blocks used to classify the frame into flat and textured areas.
+const FLAT_BLOCK_SIZE: usize = 32;
+/// Minimum number of samples needed before a scaling point is emitted.
+const MIN_SCALING_SAMPLES: usize = 256;
+/// Standard deviation (in 8-bit units) below which noise is not synthesized.
+const MIN_NOISE_SIGMA: f64 = 0.5;
+
+/// Film grain synthesis parameters, as coded in `film_grain_params()`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct FilmGrainParams {
+  pub random_seed: u16,
+  pub num_y_points: usize,
+  pub scaling_points_y: [[u8; 2]; NUM_Y_POINTS],
+  pub chroma_scaling_from_luma: bool,
+  pub num_cb_points: usize,
+  pub scaling_points_cb: [[u8; 2]; NUM_UV_POINTS],
+  pub num_cr_points: usize,
+  pub scaling_points_cr: [[u8; 2]; NUM_UV_POINTS],
+  pub scaling_shift: u8,
+  pub ar_coeff_lag: u8,
+  pub ar_coeffs_y: [i8; NUM_Y_COEFFS],
+  pub ar_coeffs_cb: [i8; NUM_UV_COEFFS],
+  pub ar_coeffs_cr: [i8; NUM_UV_COEFFS],
+  pub ar_coeff_shift: u8,
+  pub grain_scale_shift: u8,
+  pub cb_mult: u8,
+  pub cb_luma_mult: u8,
+  pub cb_offset: u16,
+  pub cr_mult: u8,
+  pub cr_luma_mult: u8,
+  pub cr_offset: u16,
+  pub overlap_flag: bool,
+  pub clip_to_restricted_range: bool
+}
+
+impl Default for FilmGrainParams {
+  fn default() -> Self {
+    FilmGrainParams {
+      random_seed: DEFAULT_GRAIN_SEED,
+      num_y_points: 0,
+      scaling_points_y: [[0; 2]; NUM_Y_POINTS],
+      chroma_scaling_from_luma: false,
+      num_cb_points: 0,
+      scaling_points_cb: [[0; 2]; NUM_UV_POINTS],
+      num_cr_points: 0,
+      scaling_points_cr: [[0; 2]; NUM_UV_POINTS],
+      scaling_shift: 8,
+      ar_coeff_lag: 0,
+      ar_coeffs_y: [0; NUM_Y_COEFFS],
+      ar_coeffs_cb: [0; NUM_UV_COEFFS],
+      ar_coeffs_cr: [0; NUM_UV_COEFFS],
+      ar_coeff_shift: 6,
+      grain_scale_shift: 0,
+      // Index the chroma scaling functions by the chroma sample alone
+      cb_mult: 128 + 64,
+      cb_luma_mult: 128,
+      cb_offset: 256,
+      cr_mult: 128 + 64,
+      cr_luma_mult: 128,
+      cr_offset: 256,
+      overlap_flag: true,
+      clip_to_restricted_range: false
+    }
+  }
+}
+
+impl FilmGrainParams {
+  /// Number of auto-regressive coefficients for the luma plane.
+  pub fn num_pos_luma(&self) -> usize {
+    let lag = self.ar_coeff_lag as usize;
+    2 * lag * (lag + 1)
+  }
+
+  /// Number of auto-regressive coefficients for each chroma plane, which
+  /// includes the luma contribution when luma grain is present.
+  pub fn num_pos_chroma(&self) -> usize {
+    self.num_pos_luma() + if self.num_y_points > 0 { 1 } else { 0 }
+  }
+
+  /// Returns these parameters with the random seed advanced for frame
+  /// `number`, so that consecutive frames do not repeat the same pattern.
+  pub fn with_frame_seed(&self, number: u64) -> FilmGrainParams {
+    let mut params = *self;
+    params.random_seed = self.random_seed
+      .wrapping_add((number as u16).wrapping_mul(GRAIN_SEED_INCREMENT));
+    if params.random_seed == 0 {
+      params.random_seed = DEFAULT_GRAIN_SEED;
+    }
+    params
+  }
+}
+
+/// One entry of a grain table, covering the frames displayed between
+/// `start_time` (inclusive) and `end_time` (exclusive), in units of
+/// 1/10,000,000 s.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GrainTableSegment {
+  pub start_time: u64,
+  pub end_time: u64,
+  /// `None` if grain synthesis is disabled in this segment.
+  pub params: Option<FilmGrainParams>
+}
+
+/// Finds the grain table segment covering `time`.
+pub fn grain_table_lookup(
+  table: &[GrainTableSegment], time: u64
+) -> Option<&GrainTableSegment> {
+  table.iter().find(|s| time >= s.start_time && time < s.end_time)
+}
+
+fn parse_next<'a, T, I>(tokens: &mut I, what: &str) -> Result<T, String>
+where
+  T: FromStr,
+  I: Iterator<Item = &'a str>
+{
+  let token = tokens.next()
+    .ok_or_else(|| format!("Unexpected end of grain table reading {}", what))?;
+  token.parse().map_err(|_| format!("Invalid value '{}' for {}", token, what))
+}
+
+fn expect_tag<'a, I>(tokens: &mut I, tag: &str) -> Result<(), String>
+where
+  I: Iterator<Item = &'a str>
+{
+  match tokens.next() {
+    Some(t) if t == tag => Ok(()),
+    Some(t) => Err(format!("Expected '{}' in grain table, found '{}'", tag, t)),
+    None => Err(format!("Unexpected end of grain table, expected '{}'", tag))
+  }
+}
+
+fn parse_points<'a, I>(
+  tokens: &mut I, tag: &str, points: &mut [[u8; 2]]
+) -> Result<usize, String>
+where
+  I: Iterator<Item = &'a str>
+{
+  expect_tag(tokens, tag)?;
+  let num_points: usize = parse_next(tokens, tag)?;
+  if num_points > points.len() {
+    return Err(format!("Too many scaling points for {}", tag));
+  }
+  for i in 0..num_points {
+    points[i][0] = parse_next(tokens, tag)?;
+    points[i][1] = parse_next(tokens, tag)?;
+    if i > 0 && points[i][0] <= points[i - 1][0] {
+      return Err(format!("Scaling points for {} must be increasing", tag));
+    }
+  }
+  Ok(num_points)
+}
+
+fn parse_coeffs<'a, I>(
+  tokens: &mut I, tag: &str, coeffs: &mut [i8], count: usize
+) -> Result<(), String>
+where
+  I: Iterator<Item = &'a str>
+{
+  expect_tag(tokens, tag)?;
+  for c in coeffs.iter_mut().take(count) {
+    *c = parse_next(tokens, tag)?;
+  }
+  Ok(())
+}
+
+fn parse_params<'a, I>(
+  tokens: &mut I, random_seed: u16
+) -> Result<FilmGrainParams, String>
+where
+  I: Iterator<Item = &'a str>
+{
+  let mut params = FilmGrainParams { random_seed, ..Default::default() };
+  expect_tag(tokens, "p")?;
+  params.ar_coeff_lag = parse_next(tokens, "ar_coeff_lag")?;
+  params.ar_coeff_shift = parse_next(tokens, "ar_coeff_shift")?;
+  params.grain_scale_shift = parse_next(tokens, "grain_scale_shift")?;
+  params.scaling_shift = parse_next(tokens, "scaling_shift")?;
+  params.chroma_scaling_from_luma =
+    parse_next::<u8, _>(tokens, "chroma_scaling_from_luma")? != 0;
+  params.overlap_flag = parse_next::<u8, _>(tokens, "overlap_flag")? != 0;
+  params.cb_mult = parse_next(tokens, "cb_mult")?;
+  params.cb_luma_mult = parse_next(tokens, "cb_luma_mult")?;
+  params.cb_offset = parse_next(tokens, "cb_offset")?;
+  params.cr_mult = parse_next(tokens, "cr_mult")?;
+  params.cr_luma_mult = parse_next(tokens, "cr_luma_mult")?;
+  params.cr_offset = parse_next(tokens, "cr_offset")?;
+  if params.ar_coeff_lag > 3
+    || params.ar_coeff_shift < 6 || params.ar_coeff_shift > 9
+    || params.grain_scale_shift > 3
+    || params.scaling_shift < 8 || params.scaling_shift > 11
+    || params.cb_offset > 511 || params.cr_offset > 511 {
+    return Err("Grain table parameters out of range".to_string());
+  }
+
+  params.num_y_points = parse_points(tokens, "sY", &mut params.scaling_points_y)?;
+  params.num_cb_points =
+    parse_points(tokens, "sCb", &mut params.scaling_points_cb)?;
+  params.num_cr_points =
+    parse_points(tokens, "sCr", &mut params.scaling_points_cr)?;
+
+  let num_pos_luma = params.num_pos_luma();
+  parse_coeffs(tokens, "cY", &mut params.ar_coeffs_y, num_pos_luma)?;
+  parse_coeffs(tokens, "cCb", &mut params.ar_coeffs_cb, num_pos_luma + 1)?;
+  parse_coeffs(tokens, "cCr", &mut params.ar_coeffs_cr, num_pos_luma + 1)?;
+  Ok(params)
+}
+
+/// Parses a grain table in the text format written by libaom's noise model
+/// (`filmgrn1`), as used by `aomenc --film-grain-table`. Segments with
+/// `update_parameters` unset carry only a random seed and reuse the
+/// parameters of the last segment that applied grain.
+pub fn parse_grain_table(input: &str) -> Result<Vec<GrainTableSegment>, String> {
+  let mut tokens = input.split_whitespace().peekable();
+  expect_tag(&mut tokens, "filmgrn1")?;
+
+  let mut table: Vec<GrainTableSegment> = Vec::new();
+  while let Some(tag) = tokens.next() {
+    if tag != "E" {
+      return Err(format!("Expected 'E' in grain table, found '{}'", tag));
+    }
+    let start_time: u64 = parse_next(&mut tokens, "start_time")?;
+    let end_time: u64 = parse_next(&mut tokens, "end_time")?;
+    let apply_grain: u8 = parse_next(&mut tokens, "apply_grain")?;
+    let random_seed: u16 = parse_next(&mut tokens, "random_seed")?;
+    let update_parameters: u8 = parse_next(&mut tokens, "update_parameters")?;
+    if end_time < start_time {
+      return Err("Grain table segment ends before it starts".to_string());
+    }
+
+    let params = if apply_grain == 0 {
+      // libaom still writes the parameters of disabled segments
+      if update_parameters != 0 && tokens.peek() == Some(&"p") {
+        parse_params(&mut tokens, random_seed)?;
+      }
+      None
+    } else if update_parameters != 0 {
+      Some(parse_params(&mut tokens, random_seed)?)
+    } else {
+      let prev = table.iter().rev().filter_map(|s| s.params).next()
+        .ok_or_else(|| "Grain table segment has no parameters to reuse".to_string())?;
+      Some(FilmGrainParams { random_seed, ..prev })
+    };
+    table.push(GrainTableSegment { start_time, end_time, params });
+  }
+
+  Ok(table)
+}
+
+/// Removes noise from `frame` with a locally adaptive Wiener filter.
+/// `strength` approximates the standard deviation of the noise to remove,
+/// in 8-bit units.
+pub fn denoise_frame(
+  frame: &Frame, width: usize, height: usize, strength: u8, bit_depth: usize
+) -> Frame {
+  let mut denoised = frame.clone();
+  let sigma = (strength as f64) * (1 << (bit_depth - 8)) as f64;
+  for p in 0..3 {
+    let src = &frame.planes[p];
+    let w = (width + src.cfg.xdec) >> src.cfg.xdec;
+    let h = (height + src.cfg.ydec) >> src.cfg.ydec;
+    denoise_plane(src, &mut denoised.planes[p], w, h, sigma * sigma, bit_depth);
+  }
+  denoised
+}
+
+fn denoise_plane(
+  src: &Plane, dst: &mut Plane, w: usize, h: usize, noise_var: f64,
+  bit_depth: usize
+) {
+  const RADIUS: usize = 2;
+  let max = ((1 << bit_depth) - 1) as f64;
+
+  // Integral images of the samples and their squares
+  let iw = w + 1;
+  let mut sum = vec![0i64; iw * (h + 1)];
+  let mut sum_sq = vec![0i64; iw * (h + 1)];
+  for y in 0..h {
+    let mut row_sum = 0i64;
+    let mut row_sum_sq = 0i64;
+    for x in 0..w {
+      let v = src.p(x, y) as i64;
+      row_sum += v;
+      row_sum_sq += v * v;
+      sum[(y + 1) * iw + x + 1] = sum[y * iw + x + 1] + row_sum;
+      sum_sq[(y + 1) * iw + x + 1] = sum_sq[y * iw + x + 1] + row_sum_sq;
+    }
+  }
+
+  let stride = dst.cfg.stride;
+  let dst_data = dst.data_origin_mut();
+  for y in 0..h {
+    let y0 = y.saturating_sub(RADIUS);
+    let y1 = cmp::min(y + RADIUS + 1, h);
+    for x in 0..w {
+      let x0 = x.saturating_sub(RADIUS);
+      let x1 = cmp::min(x + RADIUS + 1, w);
+      let n = ((y1 - y0) * (x1 - x0)) as f64;
+      let s = (sum[y1 * iw + x1] - sum[y0 * iw + x1] - sum[y1 * iw + x0]
+        + sum[y0 * iw + x0]) as f64;
+      let ss = (sum_sq[y1 * iw + x1] - sum_sq[y0 * iw + x1]
+        - sum_sq[y1 * iw + x0] + sum_sq[y0 * iw + x0]) as f64;
+      let mean = s / n;
+      let var = (ss / n - mean * mean).max(0.0);
+      let gain = if var > 0.0 { (var - noise_var).max(0.0) / var } else { 0.0 };
+      let v = src.p(x, y) as f64;
+      let out = mean + gain * (v - mean);
+      dst_data[y * stride + x] = out.round().max(0.0).min(max) as u16;
+    }
+  }
+}
+
+/// Positions of the auto-regressive taps, in the order used by the
+/// bitstream: all rows above the current sample, then the samples to the
+/// left of it.
+fn ar_positions(lag: usize) -> Vec<(isize, isize)> {
+  let lag = lag as isize;
+  let mut pos = Vec::new();
+  for dy in -lag..=0 {
+    for dx in -lag..=lag {
+      if dy == 0 && dx == 0 {
+        break;
+      }
+      pos.push((dy, dx));
+    }
+  }
+  pos
+}
+
+/// Solves `a * x = b` in place with Gaussian elimination, returning `None`
+/// for a (numerically) singular system.
+fn solve(a: &mut [f64], b: &mut [f64], n: usize) -> Option<Vec<f64>> {
+  for col in 0..n {
+    let pivot = (col..n)
+      .max_by(|&i, &j| a[i * n + col].abs().partial_cmp(&a[j * n + col].abs()).unwrap())
+      .unwrap();
+    if a[pivot * n + col].abs() < 1e-9 {
+      return None;
+    }
+    if pivot != col {
+      for k in 0..n {
+        a.swap(pivot * n + k, col * n + k);
+      }
+      b.swap(pivot, col);
+    }
+    for row in col + 1..n {
+      let f = a[row * n + col] / a[col * n + col];
+      for k in col..n {
+        a[row * n + k] -= f * a[col * n + k];
+      }
+      b[row] -= f * b[col];
+    }
+  }
+  let mut x = vec![0.0; n];
+  for row in (0..n).rev() {
+    let mut s = b[row];
+    for k in row + 1..n {
+      s -= a[row * n + k] * x[k];
+    }
+    x[row] = s / a[row * n + row];
+  }
+  Some(x)
+}
+
+/// Noise removed by the denoiser for one plane, along with the denoised
+/// samples used to index the scaling function.
+struct NoisePlane {
+  noise: Vec<i32>,
+  denoised: Vec<u16>,
+  w: usize,
+  h: usize,
+  xdec: usize,
+  ydec: usize
+}
+
+impl NoisePlane {
+  fn new(source: &Plane, denoised: &Plane, width: usize, height: usize) -> NoisePlane {
+    let (xdec, ydec) = (source.cfg.xdec, source.cfg.ydec);
+    let w = (width + xdec) >> xdec;
+    let h = (height + ydec) >> ydec;
+    let mut noise = Vec::with_capacity(w * h);
+    let mut den = Vec::with_capacity(w * h);
+    for y in 0..h {
+      for x in 0..w {
+        let d = denoised.p(x, y);
+        noise.push(source.p(x, y) as i32 - d as i32);
+        den.push(d);
+      }
+    }
+    NoisePlane { noise, denoised: den, w, h, xdec, ydec }
+  }
+
+  fn n(&self, x: usize, y: usize) -> f64 {
+    self.noise[y * self.w + x] as f64
+  }
+}
+
+/// Marks the luma blocks flat enough for their noise to be measured, i.e.
+/// the half of the blocks with the lowest variance after denoising.
+fn flat_blocks(luma: &NoisePlane) -> (Vec<bool>, usize) {
+  let cols = (luma.w + FLAT_BLOCK_SIZE - 1) / FLAT_BLOCK_SIZE;
+  let rows = (luma.h + FLAT_BLOCK_SIZE - 1) / FLAT_BLOCK_SIZE;
+  let mut variances = Vec::with_capacity(cols * rows);
+  for by in 0..rows {
+    for bx in 0..cols {
+      let (mut s, mut ss, mut n) = (0f64, 0f64, 0f64);
+      for y in by * FLAT_BLOCK_SIZE..cmp::min((by + 1) * FLAT_BLOCK_SIZE, luma.h) {
+        for x in bx * FLAT_BLOCK_SIZE..cmp::min((bx + 1) * FLAT_BLOCK_SIZE, luma.w) {
+          let v = luma.denoised[y * luma.w + x] as f64;
+          s += v;
+          ss += v * v;
+          n += 1.0;
+        }
+      }
+      variances.push(ss / n - (s / n) * (s / n));
+    }
+  }
+  let mut sorted = variances.clone();
+  sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+  let threshold = sorted[sorted.len() / 2];
+  (variances.iter().map(|&v| v <= threshold).collect(), cols)
+}
+
+fn is_flat(mask: &(Vec<bool>, usize), plane: &NoisePlane, x: usize, y: usize) -> bool {
+  let bx = (x << plane.xdec) / FLAT_BLOCK_SIZE;
+  let by = (y << plane.ydec) / FLAT_BLOCK_SIZE;
+  mask.0[by * mask.1 + bx]
+}
+
+/// Average luma noise co-located with chroma sample (x, y).
+fn luma_average(luma: &NoisePlane, chroma: &NoisePlane, x: usize, y: usize) -> f64 {
+  let lx = x << chroma.xdec;
+  let ly = y << chroma.ydec;
+  let mut s = 0.0;
+  let mut n = 0.0;
+  for dy in 0..=chroma.ydec {
+    for dx in 0..=chroma.xdec {
+      if lx + dx < luma.w && ly + dy < luma.h {
+        s += luma.n(lx + dx, ly + dy);
+        n += 1.0;
+      }
+    }
+  }
+  if n > 0.0 { s / n } else { 0.0 }
+}
+
+/// Least-squares fit of the auto-regressive model over the flat areas of
+/// a plane. For chroma, the co-located luma noise is an extra regressor.
+fn fit_ar_model(
+  plane: &NoisePlane, luma: Option<&NoisePlane>, mask: &(Vec<bool>, usize)
+) -> Vec<f64> {
+  let lag = ESTIMATE_AR_LAG;
+  let pos = ar_positions(lag);
+  let n = pos.len() + luma.is_some() as usize;
+  let mut a = vec![0f64; n * n];
+  let mut b = vec![0f64; n];
+  let mut reg = vec![0f64; n];
+  // Subsample the fit, the statistics are stable enough
+  let step = 2;
+  let mut y = lag;
+  while y < plane.h {
+    let mut x = lag;
+    while x + lag < plane.w {
+      if is_flat(mask, plane, x, y) {
+        for (r, &(dy, dx)) in reg.iter_mut().zip(pos.iter()) {
+          *r = plane.n((x as isize + dx) as usize, (y as isize + dy) as usize);
+        }
+        if let Some(luma) = luma {
+          reg[n - 1] = luma_average(luma, plane, x, y);
+        }
+        let target = plane.n(x, y);
+        for i in 0..n {
+          b[i] += reg[i] * target;
+          for j in 0..n {
+            a[i * n + j] += reg[i] * reg[j];
+          }
+        }
+      }
+      x += step;
+    }
+    y += step;
+  }
+  solve(&mut a, &mut b, n).unwrap_or_else(|| vec![0.0; n])
+}
+
+/// Simple deterministic generator of normally distributed samples.
+struct GaussianSource {
+  state: u32
+}
+
+impl GaussianSource {
+  fn next_uniform(&mut self) -> f64 {
+    self.state ^= self.state << 13;
+    self.state ^= self.state >> 17;
+    self.state ^= self.state << 5;
+    (self.state as f64 + 1.0) / (u32::max_value() as f64 + 2.0)
+  }
+
+  fn next(&mut self) -> f64 {
+    let u1 = self.next_uniform();
+    let u2 = self.next_uniform();
+    (-2.0 * u1.ln()).sqrt() * (2.0 * ::std::f64::consts::PI * u2).cos()
+  }
+}
+
+/// Applies the auto-regressive filter to a field of unit-variance white
+/// noise, the same way the decoder generates its grain template.
+fn synthesize_grain(
+  coeffs: &[i8], lag: usize, shift: u8, w: usize, h: usize,
+  luma: Option<(&[f64], usize, usize, usize)>, source: &mut GaussianSource
+) -> Vec<f64> {
+  let pos = ar_positions(lag);
+  let mut grain: Vec<f64> = (0..w * h).map(|_| source.next()).collect();
+  let scale = 1.0 / (1 << shift) as f64;
+  for y in lag..h {
+    for x in lag..w - lag {
+      let mut sum = 0.0;
+      for (&(dy, dx), &c) in pos.iter().zip(coeffs.iter()) {
+        let v = grain[(y as isize + dy) as usize * w + (x as isize + dx) as usize];
+        sum += c as f64 * v;
+      }
+      if let Some((luma, luma_w, xdec, ydec)) = luma {
+        let mut l = 0.0;
+        for dy in 0..=ydec {
+          for dx in 0..=xdec {
+            l += luma[((y << ydec) + dy) * luma_w + (x << xdec) + dx];
+          }
+        }
+        l /= ((xdec + 1) * (ydec + 1)) as f64;
+        sum += coeffs[pos.len()] as f64 * l;
+      }
+      grain[y * w + x] += sum * scale;
+    }
+  }
+  grain
+}
+
+/// Standard deviation of the interior of a synthesized grain field.
+fn grain_sigma(grain: &[f64], w: usize, h: usize, border: usize) -> f64 {
+  let mut ss = 0.0;
+  let mut n = 0.0;
+  for y in h / 2..h {
+    for x in border..w - border {
+      ss += grain[y * w + x] * grain[y * w + x];
+      n += 1.0;
+    }
+  }
+  (ss / n).sqrt()
+}
+
+/// Standard deviation of the noise in the flat areas of a plane, binned by
+/// the (8-bit) intensity of the denoised sample.
+fn noise_sigmas(
+  plane: &NoisePlane, mask: &(Vec<bool>, usize), bins: usize, bit_depth: usize
+) -> Vec<Option<f64>> {
+  let mut ss = vec![0f64; bins];
+  let mut n = vec![0usize; bins];
+  for y in 0..plane.h {
+    for x in 0..plane.w {
+      if is_flat(mask, plane, x, y) {
+        let v = (plane.denoised[y * plane.w + x] >> (bit_depth - 8)) as usize;
+        let bin = cmp::min(v * bins / 256, bins - 1);
+        ss[bin] += plane.n(x, y) * plane.n(x, y);
+        n[bin] += 1;
+      }
+    }
+  }
+  let scale = (1 << (bit_depth - 8)) as f64;
+  (0..bins).map(|i| {
+    if n[i] >= MIN_SCALING_SAMPLES {
+      Some((ss[i] / n[i] as f64).sqrt() / scale)
+    } else {
+      None
+    }
+  }).collect()
+}
+
+/// Converts the binned noise levels to scaling points, given the standard
+/// deviation (in 8-bit units) of the synthesized grain.
+fn scaling_points(
+  sigmas: &[Option<f64>], grain_sigma: f64, scaling_shift: u8,
+  points: &mut [[u8; 2]]
+) -> usize {
+  let bins = sigmas.len();
+  let mut num_points = 0;
+  for (i, s) in sigmas.iter().enumerate() {
+    if let Some(s) = *s {
+      let x = ((2 * i + 1) * 256 / (2 * bins)) as u8;
+      let y = s * (1 << scaling_shift) as f64 / grain_sigma;
+      points[num_points] = [x, y.round().max(0.0).min(255.0) as u8];
+      num_points += 1;
+    }
+  }
+  num_points
+}
+
+/// Estimates the film grain of `source` from the noise removed by the
+/// denoiser, fitting an auto-regressive model and piecewise-linear
+/// scaling functions. Returns `None` if there is no noticeable grain.
+pub fn estimate_film_grain(
+  source: &Frame, denoised: &Frame, width: usize, height: usize,
+  bit_depth: usize
+) -> Option<FilmGrainParams> {
+  let planes: Vec<NoisePlane> = (0..3)
+    .map(|p| NoisePlane::new(&source.planes[p], &denoised.planes[p], width, height))
+    .collect();
+  if planes[0].w <= 2 * ESTIMATE_AR_LAG || planes[0].h <= 2 * ESTIMATE_AR_LAG {
+    return None;
+  }
+  let mask = flat_blocks(&planes[0]);
+
+  let y_sigmas = noise_sigmas(&planes[0], &mask, NUM_Y_POINTS, bit_depth);
+  let max_sigma = y_sigmas.iter().filter_map(|&s| s).fold(0.0, f64::max);
+  if max_sigma < MIN_NOISE_SIGMA {
+    return None;
+  }
+
+  let mut params = FilmGrainParams {
+    ar_coeff_lag: ESTIMATE_AR_LAG as u8,
+    ..Default::default()
+  };
+  let fits: Vec<Vec<f64>> = vec![
+    fit_ar_model(&planes[0], None, &mask),
+    fit_ar_model(&planes[1], Some(&planes[0]), &mask),
+    fit_ar_model(&planes[2], Some(&planes[0]), &mask)
+  ];
+
+  // All planes share one coefficient precision, pick the finest that fits
+  let max_coeff = fits.iter().flat_map(|f| f.iter()).fold(0.0, |m: f64, c| m.max(c.abs()));
+  params.ar_coeff_shift = (6..=9u8).rev()
+    .find(|&s| (max_coeff * (1 << s) as f64).round() <= 127.0)
+    .unwrap_or(6);
+  let coeff_scale = (1 << params.ar_coeff_shift) as f64;
+  let quantize = |c: &f64| (c * coeff_scale).round().max(-128.0).min(127.0) as i8;
+  for (d, c) in params.ar_coeffs_y.iter_mut().zip(fits[0].iter()) {
+    *d = quantize(c);
+  }
+  for (d, c) in params.ar_coeffs_cb.iter_mut().zip(fits[1].iter()) {
+    *d = quantize(c);
+  }
+  for (d, c) in params.ar_coeffs_cr.iter_mut().zip(fits[2].iter()) {
+    *d = quantize(c);
+  }
+
+  // The decoder's white noise has a standard deviation of about 512 in 12-bit
+  // units before the auto-regressive filter, i.e. 32 in 8-bit units.
+  const WHITE_NOISE_SIGMA: f64 = 32.0;
+  const SIM_SIZE: usize = 64;
+  let mut rng = GaussianSource { state: 0x2545_f491 };
+  let (xdec, ydec) = (planes[1].xdec, planes[1].ydec);
+  let luma_w = SIM_SIZE << xdec;
+  let luma_h = SIM_SIZE << ydec;
+  let mut luma_grain = synthesize_grain(
+    &params.ar_coeffs_y, ESTIMATE_AR_LAG, params.ar_coeff_shift, luma_w, luma_h,
+    None, &mut rng
+  );
+  let mut y_gain = grain_sigma(&luma_grain, luma_w, luma_h, ESTIMATE_AR_LAG);
+  if !y_gain.is_finite() || y_gain > 8.0 {
+    // The quantized model is unstable, fall back to white noise
+    params.ar_coeffs_y = [0; NUM_Y_COEFFS];
+    luma_grain = synthesize_grain(
+      &params.ar_coeffs_y, ESTIMATE_AR_LAG, params.ar_coeff_shift, luma_w,
+      luma_h, None, &mut rng
+    );
+    y_gain = 1.0;
+  }
+
+  let mut uv_gains = [1.0; 2];
+  for (i, coeffs) in [&mut params.ar_coeffs_cb, &mut params.ar_coeffs_cr].iter_mut().enumerate() {
+    let grain = synthesize_grain(
+      &coeffs[..], ESTIMATE_AR_LAG, params.ar_coeff_shift, SIM_SIZE, SIM_SIZE,
+      Some((&luma_grain, luma_w, xdec, ydec)), &mut rng
+    );
+    let gain = grain_sigma(&grain, SIM_SIZE, SIM_SIZE, ESTIMATE_AR_LAG);
+    uv_gains[i] = if !gain.is_finite() || gain > 8.0 {
+      **coeffs = [0; NUM_UV_COEFFS];
+      1.0
+    } else {
+      gain
+    };
+  }
+
+  let uv_sigmas = [
+    noise_sigmas(&planes[1], &mask, NUM_UV_POINTS, bit_depth),
+    noise_sigmas(&planes[2], &mask, NUM_UV_POINTS, bit_depth)
+  ];
+
+  // Use the largest scaling shift (finest precision) that keeps every
+  // scaling value within 8 bits
+  let max_ratio = y_sigmas.iter().filter_map(|&s| s)
+    .map(|s| s / (WHITE_NOISE_SIGMA * y_gain))
+    .chain(uv_sigmas[0].iter().filter_map(|&s| s)
+      .map(|s| s / (WHITE_NOISE_SIGMA * uv_gains[0])))
+    .chain(uv_sigmas[1].iter().filter_map(|&s| s)
+      .map(|s| s / (WHITE_NOISE_SIGMA * uv_gains[1])))
+    .fold(0.0, f64::max);
+  params.scaling_shift = (8..=11u8).rev()
+    .find(|&s| (max_ratio * (1 << s) as f64).round() <= 255.0)
+    .unwrap_or(8);
+
+  params.num_y_points = scaling_points(
+    &y_sigmas, WHITE_NOISE_SIGMA * y_gain, params.scaling_shift,
+    &mut params.scaling_points_y
+  );
+  params.num_cb_points = scaling_points(
+    &uv_sigmas[0], WHITE_NOISE_SIGMA * uv_gains[0], params.scaling_shift,
+    &mut params.scaling_points_cb
+  );
+  params.num_cr_points = scaling_points(
+    &uv_sigmas[1], WHITE_NOISE_SIGMA * uv_gains[1], params.scaling_shift,
+    &mut params.scaling_points_cr
+  );
+  // With subsampled chroma, Cb and Cr must either both or neither have
+  // scaling points
+  if xdec == 1
+    && (params.num_cb_points == 0) != (params.num_cr_points == 0) {
+    params.num_cb_points = 0;
+    params.num_cr_points = 0;
+  }
+
+  Some(params)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use encoder::ChromaSampling;
+
+  #[test]
+  fn parse_luma_chroma_table() {
+    let input = "filmgrn1
+E 0 9223372036854775807 1 7391 1
+  p 1 7 0 11 0 1 128 192 256 128 192 256
+  sY 3  0 20 128 4 255 5
+  sCb 2 0 0 255 1
+  sCr 0
+  cY 1 -4 1 4
+  cCb -3 1 -4 6 -1
+  cCr 0 0 0 0 0
+E 9223372036854775807 9223372036854775807 0 0 1
+";
+    let table = parse_grain_table(input).unwrap();
+    assert_eq!(table.len(), 2);
+    let params = table[0].params.unwrap();
+    assert_eq!(params.random_seed, 7391);
+    assert_eq!(params.ar_coeff_lag, 1);
+    assert_eq!(params.scaling_shift, 11);
+    assert_eq!(params.num_y_points, 3);
+    assert_eq!(params.scaling_points_y[1], [128, 4]);
+    assert_eq!(params.num_cb_points, 2);
+    assert_eq!(params.num_cr_points, 0);
+    assert_eq!(&params.ar_coeffs_y[..4], &[1, -4, 1, 4]);
+    assert_eq!(&params.ar_coeffs_cb[..5], &[-3, 1, -4, 6, -1]);
+    assert!(table[1].params.is_none());
+    assert!(parse_grain_table("filmgrn1\nE 0 1 1 0 1\n p 4").is_err());
+  }
+
+  #[test]
+  fn parse_reused_parameters() {
+    let input = "filmgrn1
+E 0 100 1 7391 1
+  p 0 6 0 8 0 1 128 192 256 128 192 256
+  sY 2  0 20 255 40
+  sCb 0
+  sCr 0
+  cY
+  cCb 0
+  cCr 0
+E 100 200 0 0 1
+  p 0 6 0 8 0 1 128 192 256 128 192 256
+  sY 1  0 10
+  sCb 0
+  sCr 0
+  cY
+  cCb 0
+  cCr 0
+E 200 300 1 1234 0
+";
+    let table = parse_grain_table(input).unwrap();
+    assert_eq!(table.len(), 3);
+    assert!(table[1].params.is_none());
+    let first = table[0].params.unwrap();
+    let reused = table[2].params.unwrap();
+    assert_eq!(reused.random_seed, 1234);
+    assert_eq!(FilmGrainParams { random_seed: first.random_seed, ..reused }, first);
+    assert!(parse_grain_table("filmgrn1\nE 0 1 1 0 0\n").is_err());
+  }
+
+  fn noisy_frame(size: usize, sigma: f64) -> Frame {
+    let mut frame = Frame::new(size, size, ChromaSampling::Cs420);
+    let mut rng = GaussianSource { state: 0x1234_5678 };
+    for p in 0..3 {
+      let stride = frame.planes[p].cfg.stride;
+      let w = size >> frame.planes[p].cfg.xdec;
+      let h = size >> frame.planes[p].cfg.ydec;
+      let data = frame.planes[p].data_origin_mut();
+      for y in 0..h {
+        for x in 0..w {
+          let noise = if p == 0 { rng.next() * sigma } else { 0.0 };
+          data[y * stride + x] = (128.0 + noise).round() as u16;
+        }
+      }
+    }
+    frame
+  }
+
+  #[test]
+  fn estimate_white_noise() {
+    let size = 128;
+    let clean = noisy_frame(size, 0.0);
+    assert!(estimate_film_grain(&clean, &clean, size, size, 8).is_none());
+
+    let sigma = 4.0;
+    let source = noisy_frame(size, sigma);
+    let params = estimate_film_grain(&source, &clean, size, size, 8).unwrap();
+    let coeff_limit = (1 << params.ar_coeff_shift) / 8;
+    assert!(params.ar_coeffs_y[..params.num_pos_luma()]
+      .iter()
+      .all(|&c| (c as i32).abs() <= coeff_limit));
+    // Flat mid-grey content falls in a single intensity bin
+    assert_eq!(params.num_y_points, 1);
+    let scaling = params.scaling_points_y[0][1] as f64;
+    let estimate = scaling * 32.0 / (1 << params.scaling_shift) as f64;
+    assert!((estimate - sigma).abs() < 0.15 * sigma, "estimate {}", estimate);
+  }
+}
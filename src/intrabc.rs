@@ -0,0 +1,346 @@
+// Copyright (c) 2019, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+use context::{BlockOffset, CandidateMV, MI_SIZE, MV_LOW, MV_UPP};
+use encoder::{FrameInvariants, FrameState};
+use partition::*;
+use plane::*;
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+/// Distance, in pixels and in 64x64 units, kept between an IntraBC block
+/// and the area it can copy from, so that decoders may delay loop filtering
+pub const INTRABC_DELAY_PIXELS: usize = 256;
+pub const INTRABC_DELAY_SB64: usize = INTRABC_DELAY_PIXELS / 64;
+
+/// Square block sizes hashed for the IntraBC search
+const INTRABC_MIN_HASH_SIZE: usize = 8;
+const INTRABC_MAX_HASH_SIZE: usize = 64;
+/// Maximum number of valid copies compared to a block
+const INTRABC_MAX_CANDIDATES: usize = 64;
+
+/// Whether a displacement vector points to an area of the frame that is
+/// already reconstructed and may be referenced (libaom av1_is_dv_valid).
+pub fn is_dv_valid(
+  fi: &FrameInvariants, bo: &BlockOffset, bsize: BlockSize,
+  dv: MotionVector, xdec: usize, ydec: usize
+) -> bool {
+  // Only whole pixel displacements are allowed
+  if dv.row & 7 != 0 || dv.col & 7 != 0 {
+    return false;
+  }
+  let (row, col) = (dv.row as i32, dv.col as i32);
+  if row <= MV_LOW || row >= MV_UPP || col <= MV_LOW || col >= MV_UPP {
+    return false;
+  }
+
+  // The source must lie within the tile
+  let src_top = (bo.y * MI_SIZE) as isize + (row >> 3) as isize;
+  let src_left = (bo.x * MI_SIZE) as isize + (col >> 3) as isize;
+  let src_bottom = src_top + bsize.height() as isize;
+  let src_right = src_left + bsize.width() as isize;
+  if src_top < 0 || src_left < 0
+    || src_bottom > (fi.h_in_b * MI_SIZE) as isize
+    || src_right > (fi.w_in_b * MI_SIZE) as isize {
+    return false;
+  }
+  // Sub-8x8 chroma is predicted from the neighbouring luma blocks too
+  if bsize.width() < 8 && xdec == 1 && src_left < 4 {
+    return false;
+  }
+  if bsize.height() < 8 && ydec == 1 && src_top < 4 {
+    return false;
+  }
+
+  // The source must be in a superblock coded at least INTRABC_DELAY_SB64
+  // 64x64 units earlier, and within the wavefront of the previous rows
+  let sb_size_log2 = fi.sequence.sb_size_log2();
+  let active_sb_row = (bo.y * MI_SIZE) >> sb_size_log2;
+  let active_sb64_col = (bo.x * MI_SIZE) >> 6;
+  let src_sb_row = (src_bottom as usize - 1) >> sb_size_log2;
+  let src_sb64_col = (src_right as usize - 1) >> 6;
+  let sb64_per_row = ((fi.w_in_b - 1) >> 4) + 1;
+  let active_sb64 = active_sb_row * sb64_per_row + active_sb64_col;
+  let src_sb64 = src_sb_row * sb64_per_row + src_sb64_col;
+  if src_sb64 + INTRABC_DELAY_SB64 >= active_sb64 || src_sb_row > active_sb_row {
+    return false;
+  }
+  let gradient = 1 + INTRABC_DELAY_SB64 + (sb_size_log2 > 6) as usize;
+  let wf_offset = gradient * (active_sb_row - src_sb_row);
+  src_sb64_col + INTRABC_DELAY_SB64 < active_sb64_col + wf_offset
+}
+
+/// Displacement vector prediction (spec 5.11.26): the first non-zero of the
+/// two nearest candidates, or else one superblock up, or left past the
+/// delay in the first superblock row.
+pub fn ref_dv(
+  fi: &FrameInvariants, bo: &BlockOffset, mv_stack: &[CandidateMV]
+) -> MotionVector {
+  for c in mv_stack.iter().take(2) {
+    if c.this_mv.row != 0 || c.this_mv.col != 0 {
+      return c.this_mv;
+    }
+  }
+  let sb_mi_size = fi.sequence.sb_size().height_mi();
+  if bo.y < sb_mi_size {
+    MotionVector {
+      row: 0,
+      col: -(((sb_mi_size * MI_SIZE + INTRABC_DELAY_PIXELS) * 8) as i16)
+    }
+  } else {
+    MotionVector { row: -((sb_mi_size * MI_SIZE * 8) as i16), col: 0 }
+  }
+}
+
+fn hash_combine(hashes: [u32; 4]) -> u32 {
+  let mut h = 0x811c_9dc5u32;
+  for &v in hashes.iter() {
+    h = (h ^ v).wrapping_mul(0x0100_0193);
+    h ^= h >> 15;
+  }
+  h
+}
+
+/// Hash of a square block built from the hashes of its quadrants, and its
+/// sample value if it is flat.
+fn block_hash(
+  plane: &Plane, x: usize, y: usize, size: usize
+) -> (u32, Option<u16>) {
+  if size == 1 {
+    let v = plane.p(x, y);
+    return (v as u32, Some(v));
+  }
+  let half = size / 2;
+  let q = [
+    block_hash(plane, x, y, half),
+    block_hash(plane, x + half, y, half),
+    block_hash(plane, x, y + half, half),
+    block_hash(plane, x + half, y + half, half)
+  ];
+  let flat = if q.iter().all(|b| b.1.is_some() && b.1 == q[0].1) {
+    q[0].1
+  } else {
+    None
+  };
+  (hash_combine([q[0].0, q[1].0, q[2].0, q[3].0]), flat)
+}
+
+/// Positions of the luma source blocks of a frame by hash, for each square
+/// size searched by IntraBC. Flat blocks, better coded by intra prediction,
+/// are left out.
+#[derive(Debug)]
+pub struct IntraBCHashTable {
+  tables: Vec<HashMap<u32, Vec<(u16, u16)>>>
+}
+
+impl IntraBCHashTable {
+  pub fn new(plane: &Plane, width: usize, height: usize) -> IntraBCHashTable {
+    let mut hashes = Vec::with_capacity(width * height);
+    for y in 0..height {
+      for x in 0..width {
+        hashes.push(plane.p(x, y) as u32);
+      }
+    }
+    let mut flat = vec![true; width * height];
+    let mut tables = Vec::new();
+
+    // Hash all blocks of twice the previous size in place; each position
+    // only depends on itself and later ones in raster order
+    let mut size = 1;
+    while size < INTRABC_MAX_HASH_SIZE {
+      for y in 0..(height + 1).saturating_sub(2 * size) {
+        for x in 0..(width + 1).saturating_sub(2 * size) {
+          let i = y * width + x;
+          let q = [i, i + size, i + size * width, i + size * width + size];
+          let v = plane.p(x, y);
+          flat[i] = q.iter().all(|&j| flat[j])
+            && plane.p(x + size, y) == v
+            && plane.p(x, y + size) == v
+            && plane.p(x + size, y + size) == v;
+          hashes[i] =
+            hash_combine([hashes[q[0]], hashes[q[1]], hashes[q[2]], hashes[q[3]]]);
+        }
+      }
+      size *= 2;
+
+      if size >= INTRABC_MIN_HASH_SIZE {
+        let mut table = HashMap::new();
+        for y in 0..(height + 1).saturating_sub(size) {
+          for x in 0..(width + 1).saturating_sub(size) {
+            let i = y * width + x;
+            if !flat[i] {
+              table.entry(hashes[i]).or_insert_with(Vec::new)
+                .push((x as u16, y as u16));
+            }
+          }
+        }
+        tables.push(table);
+      }
+    }
+
+    IntraBCHashTable { tables }
+  }
+}
+
+/// Rough cost in bits of coding a displacement vector.
+fn dv_cost(dv: MotionVector, ref_dv: MotionVector) -> u32 {
+  let bits = |d: i32| 32 - ((d.abs() >> 3) as u32).leading_zeros();
+  bits(dv.row as i32 - ref_dv.row as i32) + bits(dv.col as i32 - ref_dv.col as i32)
+}
+
+/// Searches the hash table of the frame for a valid exact copy of a block,
+/// returning the displacement vector that is cheapest to code.
+pub fn intrabc_search(
+  fi: &FrameInvariants, fs: &FrameState, bo: &BlockOffset, bsize: BlockSize,
+  ref_dv: MotionVector
+) -> Option<MotionVector> {
+  let size = bsize.width();
+  if !bsize.is_sqr() || size < INTRABC_MIN_HASH_SIZE || size > INTRABC_MAX_HASH_SIZE {
+    return None;
+  }
+  let hashes = fs.intrabc_hashes.as_ref()?;
+  let PlaneConfig { xdec, ydec, .. } = fs.input.planes[1].cfg;
+  let plane = &fs.input.planes[0];
+  let (x, y) = (bo.x * MI_SIZE, bo.y * MI_SIZE);
+  if x + size > fi.width || y + size > fi.height {
+    return None;
+  }
+  let (hash, flat) = block_hash(plane, x, y, size);
+  if flat.is_some() {
+    return None;
+  }
+  let positions = hashes.tables[bsize.width_log2() - 3].get(&hash)?;
+
+  // Only blocks ending in the current superblock row or above may be valid
+  let sb_size_log2 = fi.sequence.sb_size_log2();
+  let sb_row_end = ((y >> sb_size_log2) + 1) << sb_size_log2;
+  let end = positions.binary_search_by(|&(_, py)| {
+    if py as usize + size <= sb_row_end { Ordering::Less } else { Ordering::Greater }
+  }).unwrap_err();
+
+  let same_block = |px: usize, py: usize| {
+    (0..size).all(|r| {
+      let org = plane.slice(&PlaneOffset { x: x as isize, y: (y + r) as isize });
+      let src = plane.slice(&PlaneOffset { x: px as isize, y: (py + r) as isize });
+      org.as_slice_w_width(size) == src.as_slice_w_width(size)
+    })
+  };
+
+  positions[..end].iter().rev().map(|&(px, py)| {
+    MotionVector {
+      row: ((py as isize - y as isize) * 8) as i16,
+      col: ((px as isize - x as isize) * 8) as i16
+    }
+  }).filter(|&dv| {
+    let (drow, dcol) = (dv.row as i32 - ref_dv.row as i32, dv.col as i32 - ref_dv.col as i32);
+    drow > MV_LOW && drow < MV_UPP && dcol > MV_LOW && dcol < MV_UPP
+      && is_dv_valid(fi, bo, bsize, dv, xdec, ydec)
+  }).take(INTRABC_MAX_CANDIDATES).filter(|&dv| {
+    same_block((x as isize + (dv.col >> 3) as isize) as usize,
+               (y as isize + (dv.row >> 3) as isize) as usize)
+  }).min_by_key(|&dv| dv_cost(dv, ref_dv))
+}
+
+/// IntraBC prediction of a block from the pre-filter reconstruction of the
+/// current frame (spec 7.11.3), with the bilinear filter for the half-sample
+/// positions of subsampled chroma.
+pub fn predict_intrabc(
+  dst: &mut PlaneMutSlice, width: usize, height: usize, frame_width: usize,
+  frame_height: usize, dv: MotionVector, bit_depth: usize
+) {
+  let PlaneConfig { xdec, ydec, stride, .. } = dst.plane.cfg;
+  let last_x = ((frame_width + xdec) >> xdec) as isize - 1;
+  let last_y = ((frame_height + ydec) >> ydec) as isize - 1;
+  // Source position in 1/16 sample units
+  let pos_x = (dst.x << 4) + ((2 * dv.col as isize) >> xdec);
+  let pos_y = (dst.y << 4) + ((2 * dv.row as isize) >> ydec);
+  let (x0, frac_x) = (pos_x >> 4, (pos_x & 15) as i32);
+  let (y0, frac_y) = (pos_y >> 4, (pos_y & 15) as i32);
+  let (round0, round1) = if bit_depth == 12 { (5, 9) } else { (3, 11) };
+  let round2 = |v: i32, n: i32| (v + (1 << (n - 1))) >> n;
+
+  let pred = {
+    let sample = |x: isize, y: isize| {
+      dst.plane.p(x.max(0).min(last_x) as usize, y.max(0).min(last_y) as usize)
+        as i32
+    };
+    let mut tmp = vec![0i32; width * (height + 1)];
+    for r in 0..=height {
+      for c in 0..width {
+        let (sx, sy) = (x0 + c as isize, y0 + r as isize);
+        tmp[r * width + c] = round2(
+          (128 - 8 * frac_x) * sample(sx, sy) + 8 * frac_x * sample(sx + 1, sy),
+          round0
+        );
+      }
+    }
+    let max = (1 << bit_depth) - 1;
+    (0..height * width).map(|i| {
+      round2(
+        (128 - 8 * frac_y) * tmp[i] + 8 * frac_y * tmp[i + width],
+        round1
+      ).max(0).min(max) as u16
+    }).collect::<Vec<_>>()
+  };
+
+  let slice = dst.as_mut_slice();
+  for r in 0..height {
+    slice[r * stride..r * stride + width]
+      .copy_from_slice(&pred[r * width..(r + 1) * width]);
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use api::*;
+  use encoder::Sequence;
+
+  fn frame_invariants(width: usize, height: usize) -> FrameInvariants {
+    let info = FrameInfo { width, height, ..Default::default() };
+    FrameInvariants::new(width, height, EncoderConfig::default(), Sequence::new(&info))
+  }
+
+  #[test]
+  fn dv_validity() {
+    let fi = frame_invariants(640, 256);
+    let bsize = BlockSize::BLOCK_16X16;
+    let dv = |row: i16, col: i16| MotionVector { row: row * 8, col: col * 8 };
+    // Block in the sixth 64x64 unit of the first row
+    let bo = BlockOffset { x: 80, y: 0 };
+    assert!(is_dv_valid(&fi, &bo, bsize, dv(0, -320), 0, 0));
+    // Within the delay
+    assert!(!is_dv_valid(&fi, &bo, bsize, dv(0, -64), 0, 0));
+    // Sub-pixel and outside the frame
+    assert!(!is_dv_valid(&fi, &bo, bsize, MotionVector { row: 0, col: -2564 }, 0, 0));
+    assert!(!is_dv_valid(&fi, &bo, bsize, dv(0, -336), 0, 0));
+    // Second superblock row: the row above is usable up to the wavefront
+    let bo = BlockOffset { x: 0, y: 16 };
+    assert!(is_dv_valid(&fi, &bo, bsize, dv(-64, 0), 0, 0));
+    assert!(is_dv_valid(&fi, &bo, bsize, dv(-64, 48), 0, 0));
+    assert!(!is_dv_valid(&fi, &bo, bsize, dv(-64, 64), 0, 0));
+  }
+
+  #[test]
+  fn dv_prediction() {
+    let fi = frame_invariants(640, 256);
+    let stack = |row, col| vec![CandidateMV {
+      this_mv: MotionVector { row, col },
+      comp_mv: MotionVector { row: 0, col: 0 },
+      weight: 2
+    }];
+    let mv = ref_dv(&fi, &BlockOffset { x: 40, y: 4 }, &[]);
+    assert_eq!((mv.row, mv.col), (0, -(64 + 256) * 8));
+    let mv = ref_dv(&fi, &BlockOffset { x: 40, y: 20 }, &stack(0, 0));
+    assert_eq!((mv.row, mv.col), (-64 * 8, 0));
+    let mv = ref_dv(&fi, &BlockOffset { x: 40, y: 20 }, &stack(-16, 8));
+    assert_eq!((mv.row, mv.col), (-16, 8));
+  }
+}
@@ -14,6 +14,8 @@ extern crate bitstream_io;
 extern crate backtrace;
 extern crate libc;
 extern crate rand;
+extern crate num_cpus;
+extern crate scoped_threadpool;
 
 extern crate num_traits;
 extern crate paste;
@@ -30,6 +32,7 @@ pub mod partition;
 pub mod plane;
 pub mod transform;
 pub mod quantize;
+pub mod qm_tables;
 pub mod predict;
 pub mod rdo;
 #[macro_use]
@@ -38,6 +41,7 @@ pub mod context;
 pub mod entropymode;
 pub mod token_cdfs;
 pub mod deblock;
+pub mod pool;
 pub mod segmentation;
 pub mod cdef;
 pub mod lrf;
@@ -47,6 +51,12 @@ pub mod me;
 pub mod metrics;
 pub mod scan_order;
 pub mod scenechange;
+pub mod film_grain;
+pub mod superres;
+pub mod palette;
+pub mod intrabc;
+pub mod screen_content;
+pub mod warp;
 
 mod api;
 
@@ -18,8 +18,14 @@ use context::MAX_SB_SIZE;
 use plane::Plane;
 use plane::PlaneConfig;
 use plane::PlaneOffset;
+use pool::WorkerPool;
+use rdo::get_plane_lambda;
+use superres::{SUPERRES_NUM, upscale_rows};
+use ec::OD_BITRES;
 use std::cmp;
-use util::clamp;
+use std::f64;
+use std::ops::Range;
+use util::{clamp, Fixed};
 
 pub const RESTORATION_TILESIZE_MAX: usize = 256;
 
@@ -71,13 +77,101 @@ impl RestorationFilter {
   }
 }
 
+/// Plane and row read by the restoration filters at row `y` of the stripe
+/// starting at `stripe_y`: the CDEF output within the stripe and the
+/// deblocked frame up to 2 rows beyond it (spec 7.17.6), kept in
+/// `boundaries`.
+fn stripe_source_row<'a>(y: isize, stripe_y: isize, stripe_h: isize, height: usize,
+                         cdeffed: &'a Plane, boundaries: &'a Plane) -> (&'a Plane, usize) {
+  let y = clamp(y, 0, height as isize - 1);
+  if y < stripe_y {
+    (boundaries, boundary_row(stripe_y, stripe_h, cmp::max(y, stripe_y - 2)))
+  } else if y >= stripe_y + stripe_h {
+    (boundaries, boundary_row(stripe_y + stripe_h, stripe_h, cmp::min(y, stripe_y + stripe_h + 1)))
+  } else {
+    (cdeffed, y as usize)
+  }
+}
+
+/// Row of the stripe boundaries plane holding row `y`, within 2 rows of the
+/// boundary at `boundary_y` between stripes of height `stripe_h`
+fn boundary_row(boundary_y: isize, stripe_h: isize, y: isize) -> usize {
+  // The boundaries are 8 luma rows above multiples of 64
+  let k = (boundary_y + stripe_h / 8) / stripe_h;
+  ((k - 1) * 4 + y - boundary_y + 2) as usize
+}
+
+/// The deblocked rows on both sides of the boundaries between stripes,
+/// which are saved before CDEF overwrites them in the frame. With superres
+/// they are saved upscaled, as restoration runs on the upscaled frame.
+#[derive(Clone, Debug)]
+pub struct StripeBoundaries {
+  pub planes: [Plane; PLANES]
+}
+
+impl StripeBoundaries {
+  pub fn new(fi: &FrameInvariants, rec: &Frame) -> StripeBoundaries {
+    let boundaries = (fi.height + 7) / 64;
+    let mut planes = rec.planes.iter().map(|p| {
+      let PlaneConfig { xdec, ydec, .. } = p.cfg;
+      let width = if fi.superres_denom == SUPERRES_NUM {
+        p.cfg.width
+      } else {
+        (fi.upscaled_width.align_power_of_two(3) + xdec) >> xdec
+      };
+      Plane::new(width, cmp::max(boundaries, 1) * 4, xdec, ydec, 0, 0)
+    });
+    StripeBoundaries {
+      planes: [planes.next().unwrap(), planes.next().unwrap(), planes.next().unwrap()]
+    }
+  }
+
+  /// Saves the rows of the boundaries within filter block row `fby`
+  pub fn save_rows(&mut self, fi: &FrameInvariants, rec: &Frame, fby: usize) {
+    for (dst, src) in self.planes.iter_mut().zip(rec.planes.iter()) {
+      let PlaneConfig { width, height, ydec, .. } = src.cfg;
+      let stripe_h = 64 >> ydec;
+      let rows = cmp::min(fby * stripe_h, height)..cmp::min((fby + 1) * stripe_h, height);
+      for k in 1..=(fi.height + 7) / 64 {
+        let boundary_y = (k * 64 - 8 >> ydec) as isize;
+        for y in boundary_y - 2..boundary_y + 2 {
+          if !rows.contains(&(y as usize)) {
+            continue;
+          }
+          let row = boundary_row(boundary_y, stripe_h as isize, y);
+          if fi.superres_denom != SUPERRES_NUM {
+            upscale_rows(fi, src, dst, y as usize, row, 1);
+            continue;
+          }
+          let dst_stride = dst.cfg.stride;
+          dst.data_origin_mut()[row * dst_stride..][..width]
+            .copy_from_slice(&src.data_origin()[y as usize * src.cfg.stride..][..width]);
+        }
+      }
+    }
+  }
+}
+
+/// First row and number of rows of a stripe within the frame
+fn stripe_rows(stripe_y: isize, stripe_h: isize, height: usize) -> (usize, usize) {
+  // unlike x, our y can be negative to start as the first stripe
+  // starts off the top of the frame by 8 pixels, and can also run off the end of the frame
+  let clipped_y = if stripe_y < 0 {0} else {stripe_y} as usize;
+  let clipped_h = cmp::max(0, if stripe_y + stripe_h > height as isize {
+    height - clipped_y
+  } else {
+    (stripe_y + stripe_h - clipped_y as isize) as usize
+  });
+  (clipped_y, clipped_h)
+}
+
 fn sgrproj_box_ab(af: &mut[i32; 64+2],
                   bf: &mut[i32; 64+2],
                   r: isize, eps: isize,
                   clipped_cfg: &PlaneConfig,
                   x: isize,
                   stripe_y: isize, stripe_h: isize, y: isize, h: isize,
-                  cdeffed: &Plane, deblocked: &Plane,
+                  cdeffed: &Plane, boundaries: &Plane,
                   bit_depth: usize) {
 
 
@@ -91,18 +185,8 @@ fn sgrproj_box_ab(af: &mut[i32; 64+2],
     let mut b:i32 = 0;
 
     for yi in y+row-r..=y+row+r {
-      let mut src_plane: &Plane;
-      let ly;
-      if yi < stripe_y {
-        ly = cmp::max(clamp(yi, 0, clipped_cfg.height as isize - 1), y - 2) as usize;
-        src_plane = deblocked;
-      } else if yi < stripe_y + stripe_h {
-        ly = clamp(yi, 0, clipped_cfg.height as isize - 1) as usize;
-        src_plane = cdeffed;
-      } else {
-        ly = cmp::min(clamp(yi, 0, clipped_cfg.height as isize - 1), y+h+1) as usize;
-        src_plane = deblocked;
-      }
+      let (src_plane, ly) = stripe_source_row(yi, stripe_y, stripe_h, clipped_cfg.height,
+                                              cdeffed, boundaries);
 
       for _xi in -r..-x {
         let c = src_plane.p(0, ly) as i32;
@@ -172,18 +256,16 @@ fn sgrproj_box_f(af: &[&[i32; 64+2]; 3], bf: &[&[i32; 64+2]; 3], f: &mut[i32; 64
   }
 }
 
-fn sgrproj_stripe_rdu(set: u8, xqd: [i8; 2], clipped_cfg: &PlaneConfig,
-                      x: usize, w: usize, stripe_y: isize, stripe_h: isize,
-                      cdeffed: &Plane, deblocked: &Plane, out: &mut Plane, bit_depth: usize){
+/// Runs the self-guided filters of parameter set `set` over the columns
+/// `x..x+w` of a stripe and passes each column of their outputs, at
+/// SGRPROJ_RST_BITS precision, to `emit`. The output of a filter of
+/// radius 0 is left at zero.
+fn sgrproj_stripe_filter<F>(set: u8, clipped_cfg: &PlaneConfig,
+                            x: usize, w: usize, stripe_y: isize, stripe_h: isize,
+                            cdeffed: &Plane, boundaries: &Plane, bit_depth: usize, mut emit: F)
+  where F: FnMut(usize, &[i32; 64], &[i32; 64]) {
 
-  // unlike x, our y can be negative to start as the first stripe
-  // starts off the top of the frame by 8 pixels, and can also run off the end of the frame
-  let clipped_y = if stripe_y < 0 {0} else {stripe_y} as usize;
-  let clipped_h = cmp::max(0, if stripe_y + stripe_h > clipped_cfg.height as isize {
-    clipped_cfg.height - clipped_y
-  } else {
-    (stripe_y + stripe_h - clipped_y as isize) as usize
-  });
+  let (clipped_y, clipped_h) = stripe_rows(stripe_y, stripe_h, clipped_cfg.height);
 
   assert!(clipped_h <= 64);
 
@@ -203,31 +285,27 @@ fn sgrproj_stripe_rdu(set: u8, xqd: [i8; 2], clipped_cfg: &PlaneConfig,
   if r0 > 0 {
     sgrproj_box_ab(&mut a0[0], &mut b0[0], r0 as isize, eps0 as isize, clipped_cfg,
                    x as isize - 1, stripe_y, stripe_h, clipped_y as isize, clipped_h as isize,
-                   cdeffed, deblocked, bit_depth);
+                   cdeffed, boundaries, bit_depth);
     sgrproj_box_ab(&mut a0[1], &mut b0[1], r0 as isize, eps0 as isize, clipped_cfg,
                    x as isize, stripe_y, stripe_h, clipped_y as isize, clipped_h as isize,
-                   cdeffed, deblocked, bit_depth);
+                   cdeffed, boundaries, bit_depth);
   }
   if r1 > 0 {
     sgrproj_box_ab(&mut a1[0], &mut b1[0], r1 as isize, eps1 as isize, clipped_cfg,
                    x as isize - 1, stripe_y, stripe_h, clipped_y as isize, clipped_h as isize,
-                   cdeffed, deblocked, bit_depth);
+                   cdeffed, boundaries, bit_depth);
     sgrproj_box_ab(&mut a1[1], &mut b1[1], r1 as isize, eps1 as isize, clipped_cfg,
                    x as isize, stripe_y, stripe_h, clipped_y as isize, clipped_h as isize,
-                   cdeffed, deblocked, bit_depth);
+                   cdeffed, boundaries, bit_depth);
   }
-  
+
   /* iterate by column */
-  let cdeffed_slice = cdeffed.slice(&PlaneOffset{x: x as isize, y: clipped_y as isize});
-  let outstride = out.cfg.stride;
-  let mut out_slice = out.mut_slice(&PlaneOffset{x: x as isize, y: clipped_y as isize});
-  let out_data = out_slice.as_mut_slice();
   for xi in 0..w {
     /* build intermediate array columns */
     if r0 > 0 {
       sgrproj_box_ab(&mut a0[(xi+2)%3], &mut b0[(xi+2)%3], r0 as isize, eps0 as isize, clipped_cfg,
                      (x + xi + 1) as isize, stripe_y, stripe_h, clipped_y as isize, clipped_h as isize,
-                     cdeffed, deblocked, bit_depth);
+                     cdeffed, boundaries, bit_depth);
       let ap0: [&[i32; 64+2]; 3] = [&a0[xi%3], &a0[(xi+1)%3], &a0[(xi+2)%3]];
       let bp0: [&[i32; 64+2]; 3] = [&b0[xi%3], &b0[(xi+1)%3], &b0[(xi+2)%3]];
       sgrproj_box_f(&ap0, &bp0, &mut f0, x + xi, clipped_y, clipped_h, cdeffed, 0);
@@ -235,12 +313,32 @@ fn sgrproj_stripe_rdu(set: u8, xqd: [i8; 2], clipped_cfg: &PlaneConfig,
     if r1 > 0 {
       sgrproj_box_ab(&mut a1[(xi+2)%3], &mut b1[(xi+2)%3], r1 as isize, eps1 as isize, clipped_cfg,
                      (x + xi + 1) as isize, stripe_y, stripe_h, clipped_y as isize, clipped_h as isize,
-                     cdeffed, deblocked, bit_depth);
+                     cdeffed, boundaries, bit_depth);
       let ap1: [&[i32; 64+2]; 3] = [&a1[xi%3], &a1[(xi+1)%3], &a1[(xi+2)%3]];
       let bp1: [&[i32; 64+2]; 3] = [&b1[xi%3], &b1[(xi+1)%3], &b1[(xi+2)%3]];
 
       sgrproj_box_f(&ap1, &bp1, &mut f1, x + xi, clipped_y, clipped_h, cdeffed, 1);
     }
+    emit(xi, &f0, &f1);
+  }
+}
+
+/// Filters the columns `x..x+w` of a stripe into `out`, which holds the rows
+/// of the stripe within the frame
+fn sgrproj_stripe_rdu(set: u8, xqd: [i8; 2], clipped_cfg: &PlaneConfig,
+                      x: usize, w: usize, stripe_y: isize, stripe_h: isize,
+                      cdeffed: &Plane, boundaries: &Plane, out: &mut Plane, bit_depth: usize){
+
+  let (clipped_y, clipped_h) = stripe_rows(stripe_y, stripe_h, clipped_cfg.height);
+  let r0: u8 = SGRPROJ_PARAMS_RADIUS[set as usize][0];
+  let r1: u8 = SGRPROJ_PARAMS_RADIUS[set as usize][1];
+
+  let cdeffed_slice = cdeffed.slice(&PlaneOffset{x: x as isize, y: clipped_y as isize});
+  let outstride = out.cfg.stride;
+  let mut out_slice = out.mut_slice(&PlaneOffset{x: x as isize, y: 0});
+  let out_data = out_slice.as_mut_slice();
+  sgrproj_stripe_filter(set, clipped_cfg, x, w, stripe_y, stripe_h, cdeffed, boundaries, bit_depth,
+                        |xi, f0, f1| {
     if r0 > 0 {
       if r1 > 0 {
         let w0 = xqd[0] as i32;
@@ -250,7 +348,7 @@ fn sgrproj_stripe_rdu(set: u8, xqd: [i8; 2], clipped_cfg: &PlaneConfig,
           let u = (cdeffed_slice.p(xi,yi) as i32) << SGRPROJ_RST_BITS;
           let v = w0*f0[yi] + w1*u + w2*f1[yi];
           let s = v + (1 << SGRPROJ_RST_BITS + SGRPROJ_PRJ_BITS >> 1) >> SGRPROJ_RST_BITS + SGRPROJ_PRJ_BITS;
-          out_data[xi + yi*outstride] = clamp(s as u16, 0, (1 << bit_depth) - 1);
+          out_data[xi + yi*outstride] = clamp(s, 0, (1 << bit_depth) - 1) as u16;
         }
       } else {
         let w0 = xqd[0] as i32;
@@ -259,7 +357,7 @@ fn sgrproj_stripe_rdu(set: u8, xqd: [i8; 2], clipped_cfg: &PlaneConfig,
           let u = (cdeffed_slice.p(xi,yi) as i32) << SGRPROJ_RST_BITS;
           let v = w0*f0[yi] + w*u;
           let s = v + (1 << SGRPROJ_RST_BITS + SGRPROJ_PRJ_BITS >> 1) >> SGRPROJ_RST_BITS + SGRPROJ_PRJ_BITS;
-          out_data[xi + yi*outstride] = clamp(s as u16, 0, (1 << bit_depth) - 1);
+          out_data[xi + yi*outstride] = clamp(s, 0, (1 << bit_depth) - 1) as u16;
         }
       }
     } else {
@@ -270,15 +368,17 @@ fn sgrproj_stripe_rdu(set: u8, xqd: [i8; 2], clipped_cfg: &PlaneConfig,
         let u = (cdeffed_slice.p(xi,yi) as i32) << SGRPROJ_RST_BITS;
         let v = w*u + w2*f1[yi];
         let s = v + (1 << SGRPROJ_RST_BITS + SGRPROJ_PRJ_BITS >> 1) >> SGRPROJ_RST_BITS + SGRPROJ_PRJ_BITS;
-        out_data[xi + yi*outstride] = clamp(s as u16, 0, (1 << bit_depth) - 1);
-      }      
+        out_data[xi + yi*outstride] = clamp(s, 0, (1 << bit_depth) - 1) as u16;
+      }
     }
-  }
+  });
 }
 
+/// Filters the columns `x..x+w` of a stripe into `out`, like
+/// `sgrproj_stripe_rdu`
 fn wiener_stripe_rdu(coeffs: [[i8; 3]; 2], clipped_cfg: &PlaneConfig,
                      x: usize, w: usize, y: isize, h: isize,
-                     cdeffed: &Plane, deblocked: &Plane, out: &mut Plane, bit_depth: usize){
+                     cdeffed: &Plane, boundaries: &Plane, out: &mut Plane, bit_depth: usize){
   let round_h = if bit_depth == 12 {5} else {3};
   let round_v = if bit_depth == 12 {9} else {11};
   let offset = 1 << bit_depth + WIENER_BITS - round_h - 1;
@@ -307,7 +407,6 @@ fn wiener_stripe_rdu(coeffs: [[i8; 3]; 2], clipped_cfg: &PlaneConfig,
   // unlike x, our y can be negative to start as the first stripe
   // starts off the top of the frame by 8 pixels, and can also run off the end of the frame
   let start_wi = if y < 0 {-y} else {0} as usize; 
-  let start_yi = if y < 0 {0} else {y} as usize; 
   let end_i = cmp::max(0, if y+h > clipped_cfg.height as isize {
     clipped_cfg.height as isize - y - start_wi as isize
   } else {
@@ -315,26 +414,15 @@ fn wiener_stripe_rdu(coeffs: [[i8; 3]; 2], clipped_cfg: &PlaneConfig,
   }) as usize;
   
   let stride = out.cfg.stride;
-  let mut out_slice = out.mut_slice(&PlaneOffset{x: 0, y: start_yi as isize});
+  let mut out_slice = out.mut_slice(&PlaneOffset{x: 0, y: 0});
   let out_data = out_slice.as_mut_slice();
 
   for xi in x..x+w {
     let n = cmp::min(7, clipped_cfg.width as isize + 3 - xi as isize);
     for yi in y-3..y+h+4 {
-      let mut src_plane: &Plane;
+      let (src_plane, ly) = stripe_source_row(yi, y, h, clipped_cfg.height, cdeffed, boundaries);
       let mut acc = 0;
-      let ly;
-      if yi < y {
-        ly = cmp::max(clamp(yi, 0, clipped_cfg.height as isize - 1), y - 2) as usize;
-        src_plane = deblocked;
-      } else if yi < y+h {
-        ly = clamp(yi, 0, clipped_cfg.height as isize - 1) as usize;
-        src_plane = cdeffed;
-      } else {
-        ly = cmp::min(clamp(yi, 0, clipped_cfg.height as isize - 1), y+h+1) as usize;
-        src_plane = deblocked;
-      }
-      
+
       for i in 0..3 - xi as isize {
         acc += hfilter[i as usize] * src_plane.p(0, ly) as i32;
       }
@@ -359,6 +447,468 @@ fn wiener_stripe_rdu(coeffs: [[i8; 3]; 2], clipped_cfg: &PlaneConfig,
   }
 }
 
+/// The self-guided filter sets searched with `fast_lrf`, one for each pair
+/// of radii
+const FAST_SGRPROJ_SETS: [u8; 3] = [3, 11, 14];
+
+const WIENER_WIN: usize = 7;
+const WIENER_WIN2: usize = WIENER_WIN * WIENER_WIN;
+
+/// Sums over a region of the products of the 7x7 windows of the Wiener
+/// filter input with each other and with the source, from which the least
+/// squares filter of any union of regions is fitted
+#[derive(Clone, Debug)]
+struct WienerStats {
+  n: i64,
+  sum_x: i64,
+  sum_xx: i64,
+  sum_y: Vec<i64>,
+  sum_xy: Vec<i64>,
+  // Upper triangle of the window autocorrelation matrix
+  sum_yy: Vec<i64>
+}
+
+impl WienerStats {
+  fn new() -> WienerStats {
+    WienerStats {
+      n: 0,
+      sum_x: 0,
+      sum_xx: 0,
+      sum_y: vec![0; WIENER_WIN2],
+      sum_xy: vec![0; WIENER_WIN2],
+      sum_yy: vec![0; WIENER_WIN2 * WIENER_WIN2]
+    }
+  }
+
+  fn add(&mut self, other: &WienerStats) {
+    self.n += other.n;
+    self.sum_x += other.sum_x;
+    self.sum_xx += other.sum_xx;
+    for (a, b) in self.sum_y.iter_mut().zip(&other.sum_y) { *a += *b; }
+    for (a, b) in self.sum_xy.iter_mut().zip(&other.sum_xy) { *a += *b; }
+    for (a, b) in self.sum_yy.iter_mut().zip(&other.sum_yy) { *a += *b; }
+  }
+}
+
+/// Sums of the products of the self-guided filter corrections f0 - u and
+/// f1 - u with each other and with the source correction x - u, for each
+/// parameter set
+type SgrprojStats = [[i64; 5]; 1 << SGRPROJ_PARAMS_BITS];
+
+/// Filters fitted to a restoration unit, None, Wiener and Sgrproj, each
+/// with the estimated SSE of its output
+type RestorationCandidates = [(RestorationFilter, f64); 3];
+
+/// Least squares Wiener fit of a unit, with the window statistics centered
+/// on the mean of the filter input so that they are well conditioned
+struct WienerFit {
+  h: Vec<f64>,
+  m: Vec<f64>,
+  sxx: f64
+}
+
+impl WienerFit {
+  fn new(stats: &WienerStats) -> WienerFit {
+    let n = stats.n as f64;
+    let mu = stats.sum_y[WIENER_WIN2 / 2] as f64 / n;
+    let mut h = vec![0.0; WIENER_WIN2 * WIENER_WIN2];
+    for k in 0..WIENER_WIN2 {
+      for l in k..WIENER_WIN2 {
+        let v = stats.sum_yy[k * WIENER_WIN2 + l] as f64
+          - mu * (stats.sum_y[k] + stats.sum_y[l]) as f64 + n * mu * mu;
+        h[k * WIENER_WIN2 + l] = v;
+        h[l * WIENER_WIN2 + k] = v;
+      }
+    }
+    let m = (0..WIENER_WIN2).map(|k| {
+      stats.sum_xy[k] as f64 - mu * (stats.sum_y[k] + stats.sum_x) as f64 + n * mu * mu
+    }).collect();
+    let sxx = stats.sum_xx as f64 - 2.0 * mu * stats.sum_x as f64 + n * mu * mu;
+    WienerFit { h, m, sxx }
+  }
+
+  fn taps(coeffs: [i8; 3]) -> [f64; WIENER_WIN] {
+    let c = [coeffs[0] as f64, coeffs[1] as f64, coeffs[2] as f64];
+    let mid = 128.0 - 2.0 * (c[0] + c[1] + c[2]);
+    [c[0] / 128.0, c[1] / 128.0, c[2] / 128.0, mid / 128.0, c[2] / 128.0, c[1] / 128.0,
+     c[0] / 128.0]
+  }
+
+  /// SSE of the output of the separable filter with vertical taps `a` and
+  /// horizontal taps `b`
+  fn sse(&self, a: &[f64; WIENER_WIN], b: &[f64; WIENER_WIN]) -> f64 {
+    let mut f = [0.0; WIENER_WIN2];
+    for i in 0..WIENER_WIN {
+      for j in 0..WIENER_WIN {
+        f[i * WIENER_WIN + j] = a[i] * b[j];
+      }
+    }
+    let mut sse = self.sxx;
+    for k in 0..WIENER_WIN2 {
+      let hf: f64 = self.h[k * WIENER_WIN2..(k + 1) * WIENER_WIN2].iter().zip(f.iter())
+        .map(|(h, f)| h * f).sum();
+      sse += f[k] * (hf - 2.0 * self.m[k]);
+    }
+    sse
+  }
+
+  /// Solves for the taps of one direction with those of the other fixed,
+  /// keeping them symmetric and summing to 1. Chroma filters have 5 taps.
+  fn solve_pass(&self, fixed: &[f64; WIENER_WIN], vertical: bool, first_tap: usize,
+                taps: &mut [f64; WIENER_WIN]) {
+    let idx = |i: usize, j: usize| if vertical { i * WIENER_WIN + j } else { j * WIENER_WIN + i };
+    let mut a = [[0.0; WIENER_WIN]; WIENER_WIN];
+    let mut c = [0.0; WIENER_WIN];
+    for i in 0..WIENER_WIN {
+      for j in 0..WIENER_WIN {
+        c[i] += fixed[j] * self.m[idx(i, j)];
+        for k in 0..WIENER_WIN {
+          for l in 0..WIENER_WIN {
+            a[i][k] += fixed[j] * fixed[l] * self.h[idx(i, j) * WIENER_WIN2 + idx(k, l)];
+          }
+        }
+      }
+    }
+    // taps = e3 + sum_t x_t g_t with g_t = e_t + e_(6-t) - 2 e_3
+    let g = |t: usize, i: usize| -> f64 {
+      (i == t) as i32 as f64 + (i == WIENER_WIN - 1 - t) as i32 as f64 - 2.0 * (i == 3) as i32 as f64
+    };
+    let n = 3 - first_tap;
+    let mut q = [[0.0; 3]; 3];
+    let mut r = [0.0; 3];
+    for p in 0..n {
+      for i in 0..WIENER_WIN {
+        let gi = g(p + first_tap, i);
+        if gi == 0.0 { continue; }
+        r[p] += gi * (c[i] - a[i][3]);
+        for u in 0..n {
+          for k in 0..WIENER_WIN {
+            q[p][u] += gi * a[i][k] * g(u + first_tap, k);
+          }
+        }
+      }
+    }
+    if let Some(x) = solve_linear(q, r, n) {
+      let mut sum = 0.0;
+      for p in 0..n {
+        taps[p + first_tap] = x[p];
+        taps[WIENER_WIN - 1 - p - first_tap] = x[p];
+        sum += x[p];
+      }
+      taps[3] = 1.0 - 2.0 * sum;
+    }
+  }
+
+  /// Fits the quantized filter of minimal SSE, returning its coefficients
+  /// and SSE
+  fn fit(&self, pli: usize) -> ([[i8; 3]; 2], f64) {
+    let first_tap = if pli == 0 { 0 } else { 1 };
+    let mut init = WIENER_TAPS_MID;
+    if first_tap > 0 {
+      init[0] = 0;
+    }
+    let mut a = WienerFit::taps(init);
+    let mut b = a;
+    // Alternate between the directions until the SSE settles
+    let mut prev = self.sse(&a, &b);
+    for _ in 0..16 {
+      self.solve_pass(&b, true, first_tap, &mut a);
+      self.solve_pass(&a, false, first_tap, &mut b);
+      let e = self.sse(&a, &b);
+      if e > prev * 0.999 {
+        break;
+      }
+      prev = e;
+    }
+
+    let mut coeffs = [[0i8; 3]; 2];
+    for (pass, taps) in [a, b].iter().enumerate() {
+      for i in first_tap..3 {
+        let v = (taps[i] * 128.0).round() as i32;
+        coeffs[pass][i] = clamp(v, WIENER_TAPS_MIN[i] as i32, WIENER_TAPS_MAX[i] as i32) as i8;
+      }
+    }
+
+    // Refine the rounded taps one step at a time
+    let sse = |c: &[[i8; 3]; 2]| self.sse(&WienerFit::taps(c[0]), &WienerFit::taps(c[1]));
+    let mut best = sse(&coeffs);
+    for _ in 0..2 {
+      let mut improved = false;
+      for pass in 0..2 {
+        for i in first_tap..3 {
+          for &d in &[-1i8, 1] {
+            let v = coeffs[pass][i] + d;
+            if v < WIENER_TAPS_MIN[i] || v > WIENER_TAPS_MAX[i] {
+              continue;
+            }
+            let mut c = coeffs;
+            c[pass][i] = v;
+            let e = sse(&c);
+            if e < best {
+              best = e;
+              coeffs = c;
+              improved = true;
+            }
+          }
+        }
+      }
+      if !improved {
+        break;
+      }
+    }
+    (coeffs, best)
+  }
+}
+
+/// Solves the n x n linear system `a x = b` by Gaussian elimination
+fn solve_linear(mut a: [[f64; 3]; 3], mut b: [f64; 3], n: usize) -> Option<[f64; 3]> {
+  for k in 0..n {
+    let pivot = (k..n).max_by(|&i, &j| a[i][k].abs().partial_cmp(&a[j][k].abs()).unwrap()).unwrap();
+    if a[pivot][k].abs() < 1e-10 {
+      return None;
+    }
+    a.swap(k, pivot);
+    b.swap(k, pivot);
+    for i in k + 1..n {
+      let f = a[i][k] / a[k][k];
+      for j in k..n {
+        a[i][j] -= f * a[k][j];
+      }
+      b[i] -= f * b[k];
+    }
+  }
+  let mut x = [0.0; 3];
+  for k in (0..n).rev() {
+    let s: f64 = (k + 1..n).map(|j| a[k][j] * x[j]).sum();
+    x[k] = (b[k] - s) / a[k][k];
+  }
+  Some(x)
+}
+
+/// Picks the projection of self-guided parameter set `set` of minimal SSE,
+/// returning it with the SSE. `sse_none` is the SSE of the filter input.
+fn sgrproj_fit(set: u8, stats: &[i64; 5], sse_none: f64) -> Option<([i8; 2], f64)> {
+  let r0 = SGRPROJ_PARAMS_RADIUS[set as usize][0];
+  let r1 = SGRPROJ_PARAMS_RADIUS[set as usize][1];
+  let (h00, h01, h11) = (stats[0] as f64, stats[1] as f64, stats[2] as f64);
+  let (c0, c1) = (stats[3] as f64, stats[4] as f64);
+  let scale = (1 << SGRPROJ_PRJ_BITS) as f64;
+  let (x0, x1) = if r0 > 0 && r1 > 0 {
+    let det = h00 * h11 - h01 * h01;
+    if det <= 0.0 {
+      return None;
+    }
+    ((h11 * c0 - h01 * c1) / det, (h00 * c1 - h01 * c0) / det)
+  } else if r0 > 0 {
+    if h00 <= 0.0 {
+      return None;
+    }
+    (c0 / h00, 0.0)
+  } else {
+    if h11 <= 0.0 {
+      return None;
+    }
+    (0.0, c1 / h11)
+  };
+
+  // The weights of f0 and f1 are xqd[0] and 128 - xqd[0] - xqd[1]
+  let prj = 1 << SGRPROJ_PRJ_BITS;
+  let xqd0 = if r0 > 0 {
+    clamp((x0 * scale).round() as i32, SGRPROJ_XQD_MIN[0] as i32, SGRPROJ_XQD_MAX[0] as i32)
+  } else {
+    0
+  };
+  let xqd1 = if r1 > 0 {
+    let w2 = (x1 * scale).round() as i32;
+    clamp(prj - xqd0 - w2, SGRPROJ_XQD_MIN[1] as i32, SGRPROJ_XQD_MAX[1] as i32)
+  } else {
+    clamp(prj - xqd0, SGRPROJ_XQD_MIN[1] as i32, SGRPROJ_XQD_MAX[1] as i32)
+  };
+  let w0 = xqd0 as f64 / scale;
+  let w2 = if r1 > 0 { (prj - xqd0 - xqd1) as f64 / scale } else { 0.0 };
+  let rst = (1 << (2 * SGRPROJ_RST_BITS)) as f64;
+  let sse = sse_none
+    + (w0 * w0 * h00 + 2.0 * w0 * w2 * h01 + w2 * w2 * h11 - 2.0 * (w0 * c0 + w2 * c1)) / rst;
+  Some(([xqd0 as i8, xqd1 as i8], sse))
+}
+
+/// Statistics of the restoration units of a row of a plane, gathered a
+/// stripe at a time, from which their filters are fitted
+#[derive(Clone, Debug)]
+struct RestorationStats {
+  wiener: Vec<WienerStats>,
+  sgrproj: Vec<SgrprojStats>
+}
+
+impl RestorationStats {
+  fn new(cols: usize) -> RestorationStats {
+    RestorationStats {
+      wiener: vec![WienerStats::new(); cols],
+      sgrproj: vec![[[0i64; 5]; 1 << SGRPROJ_PARAMS_BITS]; cols]
+    }
+  }
+
+  /// Adds stripe `si` of a plane divided in units of `unit_size`
+  fn add_stripe(&mut self, cfg: &PlaneConfig, unit_size: usize, si: usize, source: &Plane,
+                cdeffed: &Plane, boundaries: &Plane, bit_depth: usize, sgrproj_sets: &[u8]) {
+    let PlaneConfig { width, height, ydec, .. } = *cfg;
+    let cols = self.wiener.len();
+    let unit_col = |x: usize| cmp::min(x / unit_size, cols - 1);
+    let stripe_y = si as isize * 64 - 8 >> ydec;
+    let stripe_h = 64 >> ydec;
+    let (y0, h) = stripe_rows(stripe_y, stripe_h, height);
+    if y0 >= height || h == 0 {
+      return;
+    }
+
+    // Input of the Wiener filter around the stripe
+    let buf_w = width + WIENER_WIN - 1;
+    let mut buf = vec![0i64; (h + WIENER_WIN - 1) * buf_w];
+    for (i, yi) in (y0 as isize - 3..(y0 + h) as isize + 3).enumerate() {
+      let (plane, ly) = stripe_source_row(yi, stripe_y, stripe_h, height, cdeffed, boundaries);
+      for xi in 0..buf_w {
+        let x = clamp(xi as isize - 3, 0, width as isize - 1) as usize;
+        buf[i * buf_w + xi] = plane.p(x, ly) as i64;
+      }
+    }
+    let mut win = [0i64; WIENER_WIN2];
+    for y in 0..h {
+      for x in 0..width {
+        let stats = &mut self.wiener[unit_col(x)];
+        for i in 0..WIENER_WIN {
+          let row = (y + i) * buf_w + x;
+          win[i * WIENER_WIN..(i + 1) * WIENER_WIN].copy_from_slice(&buf[row..row + WIENER_WIN]);
+        }
+        let v = source.p(x, y0 + y) as i64;
+        stats.n += 1;
+        stats.sum_x += v;
+        stats.sum_xx += v * v;
+        for k in 0..WIENER_WIN2 {
+          let wk = win[k];
+          stats.sum_y[k] += wk;
+          stats.sum_xy[k] += v * wk;
+          let sum_yy = &mut stats.sum_yy[k * WIENER_WIN2..(k + 1) * WIENER_WIN2];
+          for (s, &wl) in sum_yy[k..].iter_mut().zip(&win[k..]) {
+            *s += wk * wl;
+          }
+        }
+      }
+    }
+
+    for &set in sgrproj_sets {
+      let r0 = SGRPROJ_PARAMS_RADIUS[set as usize][0];
+      let r1 = SGRPROJ_PARAMS_RADIUS[set as usize][1];
+      let sgrproj = &mut self.sgrproj;
+      sgrproj_stripe_filter(set, cfg, 0, width, stripe_y, stripe_h, cdeffed, boundaries,
+                            bit_depth, |xi, f0, f1| {
+        let stats = &mut sgrproj[unit_col(xi)][set as usize];
+        for yi in 0..h {
+          let u = (cdeffed.p(xi, y0 + yi) as i64) << SGRPROJ_RST_BITS;
+          let d = ((source.p(xi, y0 + yi) as i64) << SGRPROJ_RST_BITS) - u;
+          let e0 = if r0 > 0 { f0[yi] as i64 - u } else { 0 };
+          let e1 = if r1 > 0 { f1[yi] as i64 - u } else { 0 };
+          stats[0] += e0 * e0;
+          stats[1] += e0 * e1;
+          stats[2] += e1 * e1;
+          stats[3] += e0 * d;
+          stats[4] += e1 * d;
+        }
+      });
+    }
+  }
+
+  /// Fits the filters of each unit of the row
+  fn fit(&self, pli: usize) -> Vec<RestorationCandidates> {
+    self.wiener.iter().zip(self.sgrproj.iter()).map(|(wiener, sgrproj)| {
+      let center = WIENER_WIN2 / 2;
+      let sse_none = (wiener.sum_xx - 2 * wiener.sum_xy[center]
+        + wiener.sum_yy[center * WIENER_WIN2 + center]) as f64;
+      let mut candidates = [(RestorationFilter::None, sse_none),
+                            (RestorationFilter::None, f64::MAX),
+                            (RestorationFilter::None, f64::MAX)];
+      if wiener.n == 0 {
+        return candidates;
+      }
+      let (coeffs, sse) = WienerFit::new(wiener).fit(pli);
+      candidates[1] = (RestorationFilter::Wiener { coeffs }, sse);
+      for set in 0..(1 << SGRPROJ_PARAMS_BITS) as u8 {
+        if let Some((xqd, sse)) = sgrproj_fit(set, &sgrproj[set as usize], sse_none) {
+          if sse < candidates[2].1 {
+            candidates[2] = (RestorationFilter::Sgrproj { set, xqd }, sse);
+          }
+        }
+      }
+      candidates
+    }).collect()
+  }
+}
+
+/// Updates the coefficient references of a plane after coding `filter`
+/// (spec 5.11.57, 5.11.58)
+pub fn update_lrf_refs(filter: RestorationFilter, wiener_ref: &mut [[i8; 3]; 2],
+                       sgrproj_ref: &mut [i8; 2]) {
+  match filter {
+    RestorationFilter::None => {}
+    RestorationFilter::Wiener { coeffs } => *wiener_ref = coeffs,
+    RestorationFilter::Sgrproj { set, xqd } => {
+      for i in 0..2 {
+        sgrproj_ref[i] = if SGRPROJ_PARAMS_RADIUS[set as usize][i] > 0 {
+          xqd[i]
+        } else if i == 0 {
+          0
+        } else {
+          clamp((1 << SGRPROJ_PRJ_BITS) - sgrproj_ref[0] as i32,
+                SGRPROJ_XQD_MIN[1] as i32, SGRPROJ_XQD_MAX[1] as i32) as i8
+        };
+      }
+    }
+  }
+}
+
+/// Picks the filter of a unit among its candidates by RD cost, given the
+/// coefficient references. Returns the filter with its cost.
+fn rdo_unit_filter(candidates: &RestorationCandidates, pli: usize, lrf_type: u8,
+                   cw: &ContextWriter, wiener_ref: &[[i8; 3]; 2], sgrproj_ref: &[i8; 2],
+                   lambda: f64) -> (RestorationFilter, f64) {
+  let allowed = match lrf_type {
+    RESTORE_NONE => [true, false, false],
+    RESTORE_WIENER => [true, true, false],
+    RESTORE_SGRPROJ => [true, false, true],
+    _ => [true, true, true]
+  };
+  candidates.iter().zip(allowed.iter())
+    .filter(|&(&(_, sse), &allowed)| allowed && sse < f64::MAX)
+    .map(|(&(filter, sse), _)| {
+      let rate = cw.lrf_cost(pli, lrf_type, filter, wiener_ref, sgrproj_ref);
+      (filter, sse + lambda * rate as f64 / (1 << OD_BITRES) as f64)
+    })
+    .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+    .unwrap()
+}
+
+/// Picks the restoration type of a plane from the units fitted so far,
+/// simulating their coding in order
+fn rdo_plane_type(fi: &FrameInvariants, cw: &ContextWriter, rp: &RestorationPlane,
+                  pli: usize) -> u8 {
+  let lambda = get_plane_lambda(fi, pli);
+  let mut best = (RESTORE_NONE, f64::MAX);
+  for &lrf_type in &[RESTORE_NONE, RESTORE_WIENER, RESTORE_SGRPROJ, RESTORE_SWITCHABLE] {
+    let mut wiener_ref = rp.wiener_ref;
+    let mut sgrproj_ref = rp.sgrproj_ref;
+    let mut cost = 0.0;
+    for candidates in rp.candidates.iter().flat_map(|row| row.iter()) {
+      let (filter, c) = rdo_unit_filter(candidates, pli, lrf_type, cw, &wiener_ref,
+                                        &sgrproj_ref, lambda);
+      update_lrf_refs(filter, &mut wiener_ref, &mut sgrproj_ref);
+      cost += c;
+    }
+    if cost < best.1 {
+      best = (lrf_type, cost);
+    }
+  }
+  best.0
+}
+
 #[derive(Copy, Clone, Debug)]
 pub struct RestorationUnit {
   pub filter: RestorationFilter,
@@ -384,7 +934,11 @@ pub struct RestorationPlane {
   pub rows: usize,
   pub wiener_ref: [[i8; 3]; 2],
   pub sgrproj_ref: [i8; 2],
-  pub units: Vec<Vec<RestorationUnit>>
+  pub units: Vec<Vec<RestorationUnit>>,
+  // Statistics of the unit row being gathered, and the filters fitted to
+  // the rows before it
+  stats: RestorationStats,
+  candidates: Vec<Vec<RestorationCandidates>>
 }
 
 #[derive(Clone, Default)]
@@ -407,7 +961,9 @@ impl RestorationPlane {
       rows,
       wiener_ref: [WIENER_TAPS_MID; 2],
       sgrproj_ref: SGRPROJ_XQD_MID,
-      units: vec![vec![RestorationUnit::default(); cols]; rows]
+      units: vec![vec![RestorationUnit::default(); cols]; rows],
+      stats: RestorationStats::new(cols),
+      candidates: Vec::new()
     }
   }
 
@@ -431,9 +987,73 @@ impl RestorationPlane {
     &mut self.units[rpo.row][rpo.col]
   }
 
+  /// Ranges of unit rows and columns coded in superblock `sbo`: the units
+  /// whose top-left corner lies in the superblock. With superres, a
+  /// superblock covers `superres_denom / SUPERRES_NUM` times as many
+  /// (upscaled) columns.
+  pub fn restoration_units_in_sb(&self, sbo: &SuperBlockOffset, superres_denom: u8)
+    -> (Range<usize>, Range<usize>) {
+    let sb_h = (1 << sbo.size_log2) >> self.clipped_cfg.ydec;
+    let sb_w = (1 << sbo.size_log2) >> self.clipped_cfg.xdec;
+    let y = sbo.y * sb_h;
+    let row_start = (y + self.unit_size - 1) / self.unit_size;
+    let row_end = cmp::min(self.rows, (y + sb_h + self.unit_size - 1) / self.unit_size);
+    let numerator = sb_w * superres_denom as usize;
+    let denominator = self.unit_size * SUPERRES_NUM as usize;
+    let col_start = (sbo.x * numerator + denominator - 1) / denominator;
+    let col_end = cmp::min(self.cols, ((sbo.x + 1) * numerator + denominator - 1) / denominator);
+    (row_start..row_end, col_start..col_end)
+  }
+
   pub fn restoration_unit_by_stripe(&self, stripenum: usize, rux: usize) -> &RestorationUnit {
-    &self.units[cmp::min((stripenum * 64 >> self.clipped_cfg.ydec) / self.unit_size, self.rows - 1)]
-      [cmp::min(rux, self.cols - 1)]
+    &self.units[self.unit_row_of_stripe(stripenum)][cmp::min(rux, self.cols - 1)]
+  }
+
+  /// Filters stripe `si` into a buffer of its rows within the frame,
+  /// returned with the column ranges of its filtered units
+  fn filter_stripe(&self, si: usize, cdeffed: &Plane, boundaries: &Plane,
+                   bit_depth: usize) -> (Plane, Vec<Range<usize>>) {
+    let PlaneConfig { xdec, ydec, .. } = self.clipped_cfg;
+    let mut stripe = Plane::new(cdeffed.cfg.width, 64 >> ydec, xdec, ydec, 0, 0);
+    // stripe y pixel locations must be able to overspan the frame
+    let stripe_start_y = si as isize * 64 - 8 >> ydec;
+    let stripe_size = 64 >> ydec; // one past, unlike spec
+
+    // horizontally, go rdu-by-rdu
+    let mut filtered = Vec::new();
+    for rux in 0..self.cols {
+      // stripe x pixel locations must be clipped to frame, last may need to stretch
+      let ru_start_x = rux * self.unit_size;
+      let ru_size = if rux == self.cols - 1 {
+        self.clipped_cfg.width - ru_start_x
+      } else {
+        self.unit_size
+      };
+      let ru = self.restoration_unit_by_stripe(si, rux);
+      match ru.filter {
+        RestorationFilter::Wiener{coeffs} => {
+          wiener_stripe_rdu(coeffs, &self.clipped_cfg,
+                            ru_start_x, ru_size, stripe_start_y, stripe_size,
+                            cdeffed, boundaries, &mut stripe, bit_depth);
+        },
+        RestorationFilter::Sgrproj{set, xqd} => {
+          sgrproj_stripe_rdu(set, xqd, &self.clipped_cfg,
+                             ru_start_x, ru_size, stripe_start_y, stripe_size,
+                             cdeffed, boundaries, &mut stripe, bit_depth);
+        },
+        RestorationFilter::None => {
+          continue;
+        }
+      }
+      filtered.push(ru_start_x..ru_start_x + ru_size);
+    }
+    (stripe, filtered)
+  }
+
+
+  /// Row of the units filtering stripe `si`
+  fn unit_row_of_stripe(&self, si: usize) -> usize {
+    cmp::min((si * 64 >> self.clipped_cfg.ydec) / self.unit_size, self.rows - 1)
   }
 }
 
@@ -450,17 +1070,19 @@ impl RestorationState {
     let mut clipped_cfg:[PlaneConfig; 3] = [input.planes[0].cfg.clone(),
                                     input.planes[1].cfg.clone(),
                                     input.planes[2].cfg.clone()];
-    clipped_cfg[0].width = fi.width;
+    // with superres, restoration operates on the upscaled frame
+    clipped_cfg[0].width = fi.upscaled_width;
     clipped_cfg[0].height = fi.height;
     
     let PlaneConfig { xdec, ydec, .. } = clipped_cfg[1];
 
-    clipped_cfg[1].width = fi.width + (1 << xdec >> 1) >> xdec;
+    clipped_cfg[1].width = fi.upscaled_width + (1 << xdec >> 1) >> xdec;
     clipped_cfg[1].height = fi.height + (1 << ydec >> 1) >> ydec;
-    clipped_cfg[2].width = fi.width + (1 << xdec >> 1) >> xdec;
+    clipped_cfg[2].width = fi.upscaled_width + (1 << xdec >> 1) >> xdec;
     clipped_cfg[2].height = fi.height + (1 << ydec >> 1) >> ydec;
 
-    // Currrently opt for smallest possible restoration unit size
+    // The smallest unit size, so that each row of units is decided as soon
+    // as the rows of its superblocks are filtered
     let lrf_y_shift = if fi.sequence.use_128x128_superblock {1} else {2};
     let lrf_uv_shift = lrf_y_shift + if xdec>0 && ydec>0 {1} else {0};
     let lrf_type: [u8; PLANES] = [RESTORE_SWITCHABLE, RESTORE_SWITCHABLE, RESTORE_SWITCHABLE];
@@ -485,55 +1107,160 @@ impl RestorationState {
     &mut self.plane[pli].units[rpo.row][rpo.col]
   }  
 
-  pub fn lrf_optimize_superblock(&mut self, _sbo: &SuperBlockOffset, _fi: &FrameInvariants,
-                                 _cw: &mut ContextWriter) {
+  /// Gathers the statistics of stripe `si` of the restoration units from
+  /// the CDEF filtered frame and the deblocked stripe boundaries, and fits
+  /// the filters of the units of each plane once all their stripes are
+  /// gathered. The restoration type of each plane is picked on its first
+  /// row of units.
+  pub fn lrf_gather_stripe(&mut self, fi: &FrameInvariants, cw: &ContextWriter, source: &Frame,
+                           cdeffed: &Frame, boundaries: &StripeBoundaries, si: usize) {
+    let stripe_n = (self.plane[0].clipped_cfg.height + 7) / 64 + 1;
+    let all_sets: Vec<u8> = (0..1 << SGRPROJ_PARAMS_BITS).collect();
+    let sgrproj_sets = if fi.config.speed_settings.fast_lrf {
+      &FAST_SGRPROJ_SETS[..]
+    } else {
+      &all_sets[..]
+    };
+    for pli in 0..PLANES {
+      let rp = &mut self.plane[pli];
+      rp.stats.add_stripe(&rp.clipped_cfg, rp.unit_size, si, &source.planes[pli],
+                          &cdeffed.planes[pli], &boundaries.planes[pli], fi.sequence.bit_depth,
+                          sgrproj_sets);
+      let row = rp.unit_row_of_stripe(si);
+      if si + 1 < stripe_n && rp.unit_row_of_stripe(si + 1) == row {
+        continue;
+      }
+      let candidates = rp.stats.fit(pli);
+      rp.candidates.push(candidates);
+      rp.stats = RestorationStats::new(rp.cols);
+      if row == 0 {
+        rp.lrf_type = rdo_plane_type(fi, cw, rp, pli);
+      }
+    }
+  }
+
+  /// Whether the filters of the restoration units coded in superblock
+  /// `sbo` are fitted
+  pub fn lrf_fitted(&self, sbo: &SuperBlockOffset, fi: &FrameInvariants) -> bool {
+    self.plane.iter().all(|rp| {
+      rp.restoration_units_in_sb(sbo, fi.superres_denom).0.end <= rp.candidates.len()
+    })
   }
 
-  pub fn lrf_filter_frame(&mut self, out: &mut Frame, pre_cdef: &Frame,
-                          bit_depth: usize) {
-    let cdeffed = out.clone();
-    
+  /// Picks the filters of the restoration units coded in superblock `sbo`
+  /// among those fitted by `lrf_gather_stripe`, with the coefficient
+  /// references at this point of the coding
+  pub fn lrf_optimize_superblock(&mut self, sbo: &SuperBlockOffset, fi: &FrameInvariants,
+                                 cw: &ContextWriter) {
+    for pli in 0..PLANES {
+      let lambda = get_plane_lambda(fi, pli);
+      let rp = &mut self.plane[pli];
+      let mut wiener_ref = rp.wiener_ref;
+      let mut sgrproj_ref = rp.sgrproj_ref;
+      let (rows, cols) = rp.restoration_units_in_sb(sbo, fi.superres_denom);
+      for row in rows {
+        for col in cols.clone() {
+          let (filter, _) = rdo_unit_filter(&rp.candidates[row][col], pli, rp.lrf_type, cw,
+                                            &wiener_ref, &sgrproj_ref, lambda);
+          rp.units[row][col].filter = filter;
+          update_lrf_refs(filter, &mut wiener_ref, &mut sgrproj_ref);
+        }
+      }
+    }
+  }
+
+  /// Whether the filters of the restoration units of stripe `si` are coded
+  pub fn lrf_stripe_coded(&self, si: usize) -> bool {
+    self.plane.iter().all(|rp| rp.units[rp.unit_row_of_stripe(si)].iter().all(|ru| ru.coded))
+  }
+
+  /// Filters the frame a stripe at a time, the stripes being spread over
+  /// the workers of `pool`
+  pub fn lrf_filter_frame(&mut self, out: &mut Frame, boundaries: &StripeBoundaries,
+                          bit_depth: usize, pool: &WorkerPool) {
     // number of stripes (counted according to colocated Y luma position)
     let stripe_n = (self.plane[0].clipped_cfg.height + 7) / 64 + 1;
-    
-    for pli in 0..PLANES {
-      let rp = &self.plane[pli];
-      let ydec = self.plane[pli].clipped_cfg.ydec;
-
-      for si in 0..stripe_n {
-        // stripe y pixel locations must be able to overspan the frame
-        let stripe_start_y = si as isize * 64 - 8 >> ydec;
-        let stripe_size = 64 >> ydec; // one past, unlike spec
-      
-        // horizontally, go rdu-by-rdu
-        for rux in 0..rp.cols {
-          // stripe x pixel locations must be clipped to frame, last may need to stretch
-          let ru_start_x = rux * rp.unit_size;
-          let ru_size = if rux == rp.cols - 1 {
-            self.plane[pli].clipped_cfg.width - ru_start_x
-          } else {
-            rp.unit_size
-          };
-          let ru = rp.restoration_unit_by_stripe(si, rux);
-          match ru.filter {
-            RestorationFilter::Wiener{coeffs} => {          
-              wiener_stripe_rdu(coeffs, &self.plane[pli].clipped_cfg,
-                                ru_start_x, ru_size, stripe_start_y, stripe_size,
-                                &cdeffed.planes[pli], &pre_cdef.planes[pli],
-                                &mut out.planes[pli], bit_depth);
-            },
-            RestorationFilter::Sgrproj{set, xqd} => {
-              sgrproj_stripe_rdu(set, xqd, &self.plane[pli].clipped_cfg,
-                                 ru_start_x, ru_size, stripe_start_y, stripe_size,
-                                 &cdeffed.planes[pli], &pre_cdef.planes[pli],
-                                 &mut out.planes[pli], bit_depth);
-            },
-            RestorationFilter::None => {
-              // do nothing
-            }
+    self.lrf_filter_stripes(out, boundaries, bit_depth, 0..stripe_n, pool);
+  }
+
+  /// Filters the stripes `stripes`, each through a buffer of its rows so
+  /// that it is read unfiltered. A stripe only reads its own rows of the
+  /// frame, so the stripes above may be filtered already and those below
+  /// not yet, and the stripes are spread over the workers of `pool`.
+  pub fn lrf_filter_stripes(&mut self, out: &mut Frame, boundaries: &StripeBoundaries,
+                            bit_depth: usize, stripes: Range<usize>, pool: &WorkerPool) {
+    let clipped_rows = |rp: &RestorationPlane, si: usize| {
+      let ydec = rp.clipped_cfg.ydec;
+      // stripe y pixel locations must be able to overspan the frame
+      stripe_rows(si as isize * 64 - 8 >> ydec, 64 >> ydec, rp.clipped_cfg.height)
+    };
+    let jobs: Vec<(usize, usize)> = (0..PLANES).flat_map(|pli| {
+      stripes.clone().map(move |si| (pli, si))
+    }).filter(|&(pli, si)| clipped_rows(&self.plane[pli], si).1 > 0).collect();
+    let filtered = {
+      let out = &*out;
+      pool.map(jobs.clone(), |(pli, si)| {
+        self.plane[pli].filter_stripe(si, &out.planes[pli], &boundaries.planes[pli], bit_depth)
+      })
+    };
+
+    for (&(pli, si), (stripe, filtered)) in jobs.iter().zip(filtered) {
+      let (clipped_y, clipped_h) = clipped_rows(&self.plane[pli], si);
+      let cdeffed = &mut out.planes[pli];
+      let stride = cdeffed.cfg.stride;
+      let stripe_stride = stripe.cfg.stride;
+      for cols in filtered {
+        for y in 0..clipped_h {
+          cdeffed.data_origin_mut()[(clipped_y + y) * stride..][cols.clone()]
+            .copy_from_slice(&stripe.data_origin()[y * stripe_stride..][cols.clone()]);
+        }
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use rand::{ChaChaRng, Rng, SeedableRng};
+
+  #[test]
+  fn wiener_fit_recovers_filter() {
+    let coeffs = [[-2i8, 7, -15], [3, -9, 30]];
+    let (w, h) = (64, 64);
+    let mut ra = ChaChaRng::from_seed([0; 32]);
+    let input: Vec<i64> = (0..(w + 6) * (h + 6)).map(|_| ra.gen_range(0, 256)).collect();
+
+    let vert = WienerFit::taps(coeffs[0]);
+    let horz = WienerFit::taps(coeffs[1]);
+    let mut stats = WienerStats::new();
+    let mut win = [0i64; WIENER_WIN2];
+    for y in 0..h {
+      for x in 0..w {
+        let mut v = 0.0;
+        for i in 0..WIENER_WIN {
+          for j in 0..WIENER_WIN {
+            win[i * WIENER_WIN + j] = input[(y + i) * (w + 6) + x + j];
+            v += vert[i] * horz[j] * win[i * WIENER_WIN + j] as f64;
+          }
+        }
+        let v = v.round() as i64;
+        stats.n += 1;
+        stats.sum_x += v;
+        stats.sum_xx += v * v;
+        for k in 0..WIENER_WIN2 {
+          stats.sum_y[k] += win[k];
+          stats.sum_xy[k] += v * win[k];
+          for l in k..WIENER_WIN2 {
+            stats.sum_yy[k * WIENER_WIN2 + l] += win[k] * win[l];
           }
         }
       }
-    }    
+    }
+
+    let (fitted, sse) = WienerFit::new(&stats).fit(0);
+    assert_eq!(fitted, coeffs);
+    // Only the rounding of the source is left
+    assert!(sse < (w * h) as f64 / 4.0);
   }
 }
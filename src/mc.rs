@@ -10,6 +10,7 @@
 use num_traits::*;
 use plane::*;
 use util::*;
+use warp::*;
 
 #[derive(Copy, Clone, Debug, PartialEq, PartialOrd)]
 pub enum FilterMode {
@@ -145,10 +146,12 @@ fn run_filter<T: AsPrimitive<i32>>(
 fn get_filter(
   mode: FilterMode, frac: i32, length: usize
 ) -> [i32; SUBPEL_FILTER_SIZE] {
-  let filter_idx = if mode == FilterMode::BILINEAR || length > 4 {
-    mode as usize
-  } else {
-    (mode as usize).min(1) + 4
+  // Blocks of 4 samples or fewer use 4-tap kernels, sharp ones falling back
+  // to the regular kernel
+  let filter_idx = match mode {
+    FilterMode::REGULAR | FilterMode::SHARP if length <= 4 => 4,
+    FilterMode::SMOOTH if length <= 4 => 5,
+    _ => mode as usize
   };
   SUBPEL_FILTERS[filter_idx][frac as usize]
 }
@@ -156,13 +159,37 @@ fn get_filter(
 pub fn put_8tap<'a>(
   dst: &'a mut PlaneMutSlice<'a>, src: PlaneSlice, width: usize,
   height: usize, col_frac: i32, row_frac: i32, bit_depth: usize,
-  mode: FilterMode
+  mode_x: FilterMode, mode_y: FilterMode
+) {
+  #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+  {
+    if is_x86_feature_detected!("avx2")
+      && width % 8 == 0
+      && (col_frac != 0 || row_frac != 0)
+    {
+      return unsafe {
+        avx2::put_8tap(
+          dst, src, width, height, col_frac, row_frac, bit_depth, mode_x,
+          mode_y
+        )
+      };
+    }
+  }
+  put_8tap_native(
+    dst, src, width, height, col_frac, row_frac, bit_depth, mode_x, mode_y
+  )
+}
+
+fn put_8tap_native<'a>(
+  dst: &'a mut PlaneMutSlice<'a>, src: PlaneSlice, width: usize,
+  height: usize, col_frac: i32, row_frac: i32, bit_depth: usize,
+  mode_x: FilterMode, mode_y: FilterMode
 ) {
   let dst_stride = dst.plane.cfg.stride;
   let dst_slice = dst.as_mut_slice();
   let ref_stride = src.plane.cfg.stride;
-  let y_filter = get_filter(mode, row_frac, height);
-  let x_filter = get_filter(mode, col_frac, width);
+  let y_filter = get_filter(mode_y, row_frac, height);
+  let x_filter = get_filter(mode_x, col_frac, width);
   let max_sample_val = ((1 << bit_depth) - 1) as i32;
   let intermediate_bits = 4 - if bit_depth == 12 { 2 } else { 0 };
   match (col_frac, row_frac) {
@@ -235,13 +262,38 @@ pub fn put_8tap<'a>(
   }
 }
 
-pub fn prep_8tap<'a>(
+pub fn prep_8tap(
   tmp: &mut [i16], src: PlaneSlice, width: usize, height: usize,
-  col_frac: i32, row_frac: i32, bit_depth: usize, mode: FilterMode
+  col_frac: i32, row_frac: i32, bit_depth: usize, mode_x: FilterMode,
+  mode_y: FilterMode
+) {
+  #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+  {
+    if is_x86_feature_detected!("avx2")
+      && width % 8 == 0
+      && (col_frac != 0 || row_frac != 0)
+    {
+      return unsafe {
+        avx2::prep_8tap(
+          tmp, src, width, height, col_frac, row_frac, bit_depth, mode_x,
+          mode_y
+        )
+      };
+    }
+  }
+  prep_8tap_native(
+    tmp, src, width, height, col_frac, row_frac, bit_depth, mode_x, mode_y
+  )
+}
+
+fn prep_8tap_native(
+  tmp: &mut [i16], src: PlaneSlice, width: usize, height: usize,
+  col_frac: i32, row_frac: i32, bit_depth: usize, mode_x: FilterMode,
+  mode_y: FilterMode
 ) {
   let ref_stride = src.plane.cfg.stride;
-  let y_filter = get_filter(mode, row_frac, height);
-  let x_filter = get_filter(mode, col_frac, width);
+  let y_filter = get_filter(mode_y, row_frac, height);
+  let x_filter = get_filter(mode_x, col_frac, width);
   let intermediate_bits = 4 - if bit_depth == 12 { 2 } else { 0 };
   match (col_frac, row_frac) {
     (0, 0) => {
@@ -302,6 +354,518 @@ pub fn prep_8tap<'a>(
   }
 }
 
+/// AVX2 versions of `put_8tap` and `prep_8tap` for blocks a multiple of 8
+/// samples wide, filtering 8 columns at a time with 32-bit sums.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod avx2 {
+  use super::*;
+  #[cfg(target_arch = "x86")]
+  use std::arch::x86::*;
+  #[cfg(target_arch = "x86_64")]
+  use std::arch::x86_64::*;
+
+  #[inline]
+  #[target_feature(enable = "avx2")]
+  unsafe fn taps(filter: [i32; SUBPEL_FILTER_SIZE]) -> [__m256i; 8] {
+    let mut taps = [_mm256_setzero_si256(); 8];
+    for (t, &f) in taps.iter_mut().zip(filter.iter()) {
+      *t = _mm256_set1_epi32(f);
+    }
+    taps
+  }
+
+  #[inline]
+  #[target_feature(enable = "avx2")]
+  unsafe fn run_filter_u16(
+    src: *const u16, stride: usize, taps: &[__m256i; 8]
+  ) -> __m256i {
+    let mut sum = _mm256_setzero_si256();
+    for (k, t) in taps.iter().enumerate() {
+      let s = _mm_loadu_si128(src.add(k * stride) as *const _);
+      let s = _mm256_cvtepu16_epi32(s);
+      sum = _mm256_add_epi32(sum, _mm256_mullo_epi32(s, *t));
+    }
+    sum
+  }
+
+  #[inline]
+  #[target_feature(enable = "avx2")]
+  unsafe fn run_filter_i32(
+    src: *const i32, stride: usize, taps: &[__m256i; 8]
+  ) -> __m256i {
+    let mut sum = _mm256_setzero_si256();
+    for (k, t) in taps.iter().enumerate() {
+      let s = _mm256_loadu_si256(src.add(k * stride) as *const _);
+      sum = _mm256_add_epi32(sum, _mm256_mullo_epi32(s, *t));
+    }
+    sum
+  }
+
+  #[inline]
+  #[target_feature(enable = "avx2")]
+  unsafe fn round_shift(value: __m256i, bit: usize) -> __m256i {
+    _mm256_sra_epi32(
+      _mm256_add_epi32(value, _mm256_set1_epi32(1 << bit >> 1)),
+      _mm_cvtsi32_si128(bit as i32)
+    )
+  }
+
+  #[inline]
+  #[target_feature(enable = "avx2")]
+  unsafe fn store_u16(dst: *mut u16, value: __m256i, max: __m256i) {
+    let v = _mm256_max_epi32(value, _mm256_setzero_si256());
+    let v = _mm256_min_epi32(v, max);
+    let packed = _mm_packus_epi32(
+      _mm256_castsi256_si128(v),
+      _mm256_extracti128_si256(v, 1)
+    );
+    _mm_storeu_si128(dst as *mut _, packed);
+  }
+
+  #[inline]
+  #[target_feature(enable = "avx2")]
+  unsafe fn store_i16(dst: *mut i16, value: __m256i) {
+    let packed = _mm_packs_epi32(
+      _mm256_castsi256_si128(value),
+      _mm256_extracti128_si256(value, 1)
+    );
+    _mm_storeu_si128(dst as *mut _, packed);
+  }
+
+  /// Runs the horizontal pass of a 2-D filter over 8 columns, for `height`
+  /// rows plus the 7 rows of context the vertical pass needs.
+  #[inline]
+  #[target_feature(enable = "avx2")]
+  unsafe fn filter_h_8(
+    intermediate: &mut [i32], src: *const u16, ref_stride: usize,
+    height: usize, x_taps: &[__m256i; 8], intermediate_bits: usize
+  ) {
+    assert!(intermediate.len() >= 8 * (height + 7));
+    for r in 0..height + 7 {
+      let v = round_shift(
+        run_filter_u16(src.add(r * ref_stride), 1, x_taps),
+        7 - intermediate_bits
+      );
+      _mm256_storeu_si256(intermediate.as_mut_ptr().add(8 * r) as *mut _, v);
+    }
+  }
+
+  #[target_feature(enable = "avx2")]
+  pub unsafe fn put_8tap<'a>(
+    dst: &'a mut PlaneMutSlice<'a>, src: PlaneSlice, width: usize,
+    height: usize, col_frac: i32, row_frac: i32, bit_depth: usize,
+    mode_x: FilterMode, mode_y: FilterMode
+  ) {
+    let dst_stride = dst.plane.cfg.stride;
+    let dst_slice = dst.as_mut_slice();
+    assert!(dst_slice.len() >= (height - 1) * dst_stride + width);
+    let dst_ptr = dst_slice.as_mut_ptr();
+    let ref_stride = src.plane.cfg.stride;
+    let y_taps = taps(get_filter(mode_y, row_frac, height));
+    let x_taps = taps(get_filter(mode_x, col_frac, width));
+    let max = _mm256_set1_epi32((1 << bit_depth) - 1);
+    let intermediate_bits = 4 - if bit_depth == 12 { 2 } else { 0 };
+    match (col_frac, row_frac) {
+      (0, _) => {
+        let src_ptr = src.go_up(3).as_slice().as_ptr();
+        for r in 0..height {
+          for c in (0..width).step_by(8) {
+            let src = src_ptr.add(r * ref_stride + c);
+            let v = run_filter_u16(src, ref_stride, &y_taps);
+            store_u16(dst_ptr.add(r * dst_stride + c), round_shift(v, 7), max);
+          }
+        }
+      }
+      (_, 0) => {
+        let src_ptr = src.go_left(3).as_slice().as_ptr();
+        for r in 0..height {
+          for c in (0..width).step_by(8) {
+            let v = run_filter_u16(src_ptr.add(r * ref_stride + c), 1, &x_taps);
+            let v = round_shift(v, 7 - intermediate_bits);
+            let v = round_shift(v, intermediate_bits);
+            store_u16(dst_ptr.add(r * dst_stride + c), v, max);
+          }
+        }
+      }
+      (_, _) => {
+        let mut intermediate = [0i32; 8 * (128 + 7)];
+        let src_ptr = src.go_left(3).go_up(3).as_slice().as_ptr();
+        for cg in (0..width).step_by(8) {
+          filter_h_8(
+            &mut intermediate, src_ptr.add(cg), ref_stride, height, &x_taps,
+            intermediate_bits
+          );
+          for r in 0..height {
+            let v =
+              run_filter_i32(intermediate.as_ptr().add(8 * r), 8, &y_taps);
+            let v = round_shift(v, 7 + intermediate_bits);
+            store_u16(dst_ptr.add(r * dst_stride + cg), v, max);
+          }
+        }
+      }
+    }
+  }
+
+  #[target_feature(enable = "avx2")]
+  pub unsafe fn prep_8tap(
+    tmp: &mut [i16], src: PlaneSlice, width: usize, height: usize,
+    col_frac: i32, row_frac: i32, bit_depth: usize, mode_x: FilterMode,
+    mode_y: FilterMode
+  ) {
+    assert!(tmp.len() >= width * height);
+    let tmp_ptr = tmp.as_mut_ptr();
+    let ref_stride = src.plane.cfg.stride;
+    let y_taps = taps(get_filter(mode_y, row_frac, height));
+    let x_taps = taps(get_filter(mode_x, col_frac, width));
+    let intermediate_bits = 4 - if bit_depth == 12 { 2 } else { 0 };
+    match (col_frac, row_frac) {
+      (0, _) => {
+        let src_ptr = src.go_up(3).as_slice().as_ptr();
+        for r in 0..height {
+          for c in (0..width).step_by(8) {
+            let src = src_ptr.add(r * ref_stride + c);
+            let v = run_filter_u16(src, ref_stride, &y_taps);
+            let v = round_shift(v, 7 - intermediate_bits);
+            store_i16(tmp_ptr.add(r * width + c), v);
+          }
+        }
+      }
+      (_, 0) => {
+        let src_ptr = src.go_left(3).as_slice().as_ptr();
+        for r in 0..height {
+          for c in (0..width).step_by(8) {
+            let v = run_filter_u16(src_ptr.add(r * ref_stride + c), 1, &x_taps);
+            let v = round_shift(v, 7 - intermediate_bits);
+            store_i16(tmp_ptr.add(r * width + c), v);
+          }
+        }
+      }
+      (_, _) => {
+        let mut intermediate = [0i32; 8 * (128 + 7)];
+        let src_ptr = src.go_left(3).go_up(3).as_slice().as_ptr();
+        for cg in (0..width).step_by(8) {
+          filter_h_8(
+            &mut intermediate, src_ptr.add(cg), ref_stride, height, &x_taps,
+            intermediate_bits
+          );
+          for r in 0..height {
+            let v =
+              run_filter_i32(intermediate.as_ptr().add(8 * r), 8, &y_taps);
+            store_i16(tmp_ptr.add(r * width + cg), round_shift(v, 7));
+          }
+        }
+      }
+    }
+  }
+}
+
+/// Precision of reference sample positions in scaled motion compensation.
+pub const SCALE_SUBPEL_BITS: usize = 10;
+const SCALE_SUBPEL_MASK: i32 = (1 << SCALE_SUBPEL_BITS) - 1;
+
+/// Position of a block inside a reference frame of a different size, as
+/// derived by the motion vector scaling process (spec 7.11.3.3).
+#[derive(Copy, Clone, Debug)]
+pub struct ScaledPosition {
+  pub start_x: i32,
+  pub start_y: i32,
+  pub x_step: i32,
+  pub y_step: i32,
+  /// Last valid sample coordinates of the reference plane
+  pub last_x: usize,
+  pub last_y: usize
+}
+
+/// Filters a block from a scaled reference, returning the unrounded output
+/// of the vertical pass.
+fn scaled_8tap(
+  src: &Plane, pos: &ScaledPosition, width: usize, height: usize,
+  bit_depth: usize, mode_x: FilterMode, mode_y: FilterMode
+) -> Vec<i32> {
+  let intermediate_bits = 4 - if bit_depth == 12 { 2 } else { 0 };
+  let intermediate_height = (((height as i32 - 1) * pos.y_step
+    + SCALE_SUBPEL_MASK)
+    >> SCALE_SUBPEL_BITS) as usize
+    + SUBPEL_FILTER_SIZE;
+  let mut intermediate = vec![0i16; width * intermediate_height];
+
+  let y0 = (pos.start_y >> SCALE_SUBPEL_BITS) - 3;
+  for r in 0..intermediate_height {
+    let y = clamp(y0 + r as i32, 0, pos.last_y as i32) as usize;
+    for c in 0..width {
+      let p = pos.start_x + pos.x_step * c as i32;
+      let x_filter = get_filter(mode_x, (p >> 6) & 15, width);
+      let x0 = (p >> SCALE_SUBPEL_BITS) - 3;
+      let sum: i32 = x_filter
+        .iter()
+        .enumerate()
+        .map(|(t, f)| {
+          let x = clamp(x0 + t as i32, 0, pos.last_x as i32) as usize;
+          f * src.p(x, y) as i32
+        })
+        .sum();
+      intermediate[r * width + c] =
+        round_shift(sum, 7 - intermediate_bits) as i16;
+    }
+  }
+
+  let mut out = vec![0i32; width * height];
+  for r in 0..height {
+    let p = (pos.start_y & SCALE_SUBPEL_MASK) + pos.y_step * r as i32;
+    let y_filter = get_filter(mode_y, (p >> 6) & 15, height);
+    let y0 = (p >> SCALE_SUBPEL_BITS) as usize;
+    for c in 0..width {
+      out[r * width + c] = run_filter(
+        &intermediate[y0 * width + c..],
+        width,
+        y_filter
+      );
+    }
+  }
+  out
+}
+
+/// Predicts a block from a reference frame of a different size.
+pub fn put_8tap_scaled<'a>(
+  dst: &'a mut PlaneMutSlice<'a>, src: &Plane, pos: &ScaledPosition,
+  width: usize, height: usize, bit_depth: usize, mode_x: FilterMode,
+  mode_y: FilterMode
+) {
+  let dst_stride = dst.plane.cfg.stride;
+  let dst_slice = dst.as_mut_slice();
+  let max_sample_val = ((1 << bit_depth) - 1) as i32;
+  let intermediate_bits = 4 - if bit_depth == 12 { 2 } else { 0 };
+  let sums =
+    scaled_8tap(src, pos, width, height, bit_depth, mode_x, mode_y);
+  for r in 0..height {
+    for c in 0..width {
+      dst_slice[r * dst_stride + c] =
+        round_shift(sums[r * width + c], 7 + intermediate_bits)
+          .max(0)
+          .min(max_sample_val) as u16;
+    }
+  }
+}
+
+/// Compound prediction counterpart of `put_8tap_scaled`.
+pub fn prep_8tap_scaled(
+  tmp: &mut [i16], src: &Plane, pos: &ScaledPosition, width: usize,
+  height: usize, bit_depth: usize, mode_x: FilterMode, mode_y: FilterMode
+) {
+  let sums =
+    scaled_8tap(src, pos, width, height, bit_depth, mode_x, mode_y);
+  for (t, &s) in tmp[..width * height].iter_mut().zip(sums.iter()) {
+    *t = round_shift(s, 7) as i16;
+  }
+}
+
+/// Taps of the warp filter, indexed by the sample position in 1/64 units
+/// offset by 64 (spec Warped_Filters).
+const WARPED_FILTERS: [[i32; 8]; 193] = [
+  [0, 0, 127, 1, 0, 0, 0, 0], [0, -1, 127, 2, 0, 0, 0, 0],
+  [1, -3, 127, 4, -1, 0, 0, 0], [1, -4, 126, 6, -2, 1, 0, 0],
+  [1, -5, 126, 8, -3, 1, 0, 0], [1, -6, 125, 11, -4, 1, 0, 0],
+  [1, -7, 124, 13, -4, 1, 0, 0], [2, -8, 123, 15, -5, 1, 0, 0],
+  [2, -9, 122, 18, -6, 1, 0, 0], [2, -10, 121, 20, -6, 1, 0, 0],
+  [2, -11, 120, 22, -7, 2, 0, 0], [2, -12, 119, 25, -8, 2, 0, 0],
+  [3, -13, 117, 27, -8, 2, 0, 0], [3, -13, 116, 29, -9, 2, 0, 0],
+  [3, -14, 114, 32, -10, 3, 0, 0], [3, -15, 113, 35, -10, 2, 0, 0],
+  [3, -15, 111, 37, -11, 3, 0, 0], [3, -16, 109, 40, -11, 3, 0, 0],
+  [3, -16, 108, 42, -12, 3, 0, 0], [4, -17, 106, 45, -13, 3, 0, 0],
+  [4, -17, 104, 47, -13, 3, 0, 0], [4, -17, 102, 50, -14, 3, 0, 0],
+  [4, -17, 100, 52, -14, 3, 0, 0], [4, -18, 98, 55, -15, 4, 0, 0],
+  [4, -18, 96, 58, -15, 3, 0, 0], [4, -18, 94, 60, -16, 4, 0, 0],
+  [4, -18, 91, 63, -16, 4, 0, 0], [4, -18, 89, 65, -16, 4, 0, 0],
+  [4, -18, 87, 68, -17, 4, 0, 0], [4, -18, 85, 70, -17, 4, 0, 0],
+  [4, -18, 82, 73, -17, 4, 0, 0], [4, -18, 80, 75, -17, 4, 0, 0],
+  [4, -18, 78, 78, -18, 4, 0, 0], [4, -17, 75, 80, -18, 4, 0, 0],
+  [4, -17, 73, 82, -18, 4, 0, 0], [4, -17, 70, 85, -18, 4, 0, 0],
+  [4, -17, 68, 87, -18, 4, 0, 0], [4, -16, 65, 89, -18, 4, 0, 0],
+  [4, -16, 63, 91, -18, 4, 0, 0], [4, -16, 60, 94, -18, 4, 0, 0],
+  [3, -15, 58, 96, -18, 4, 0, 0], [4, -15, 55, 98, -18, 4, 0, 0],
+  [3, -14, 52, 100, -17, 4, 0, 0], [3, -14, 50, 102, -17, 4, 0, 0],
+  [3, -13, 47, 104, -17, 4, 0, 0], [3, -13, 45, 106, -17, 4, 0, 0],
+  [3, -12, 42, 108, -16, 3, 0, 0], [3, -11, 40, 109, -16, 3, 0, 0],
+  [3, -11, 37, 111, -15, 3, 0, 0], [2, -10, 35, 113, -15, 3, 0, 0],
+  [3, -10, 32, 114, -14, 3, 0, 0], [2, -9, 29, 116, -13, 3, 0, 0],
+  [2, -8, 27, 117, -13, 3, 0, 0], [2, -8, 25, 119, -12, 2, 0, 0],
+  [2, -7, 22, 120, -11, 2, 0, 0], [1, -6, 20, 121, -10, 2, 0, 0],
+  [1, -6, 18, 122, -9, 2, 0, 0], [1, -5, 15, 123, -8, 2, 0, 0],
+  [1, -4, 13, 124, -7, 1, 0, 0], [1, -4, 11, 125, -6, 1, 0, 0],
+  [1, -3, 8, 126, -5, 1, 0, 0], [1, -2, 6, 126, -4, 1, 0, 0],
+  [0, -1, 4, 127, -3, 1, 0, 0], [0, 0, 2, 127, -1, 0, 0, 0],
+  [0, 0, 0, 127, 1, 0, 0, 0], [0, 0, -1, 127, 2, 0, 0, 0],
+  [0, 1, -3, 127, 4, -2, 1, 0], [0, 1, -5, 127, 6, -2, 1, 0],
+  [0, 2, -6, 126, 8, -3, 1, 0], [-1, 2, -7, 126, 11, -4, 2, -1],
+  [-1, 3, -8, 125, 13, -5, 2, -1], [-1, 3, -10, 124, 16, -6, 3, -1],
+  [-1, 4, -11, 123, 18, -7, 3, -1], [-1, 4, -12, 122, 20, -7, 3, -1],
+  [-1, 4, -13, 121, 23, -8, 3, -1], [-2, 5, -14, 120, 25, -9, 4, -1],
+  [-1, 5, -15, 119, 27, -10, 4, -1], [-1, 5, -16, 118, 30, -11, 4, -1],
+  [-2, 6, -17, 116, 33, -12, 5, -1], [-2, 6, -17, 114, 35, -12, 5, -1],
+  [-2, 6, -18, 113, 38, -13, 5, -1], [-2, 7, -19, 111, 41, -14, 6, -2],
+  [-2, 7, -19, 110, 43, -15, 6, -2], [-2, 7, -20, 108, 46, -15, 6, -2],
+  [-2, 7, -20, 106, 49, -16, 6, -2], [-2, 7, -21, 104, 51, -16, 7, -2],
+  [-2, 7, -21, 102, 54, -17, 7, -2], [-2, 8, -21, 100, 56, -18, 7, -2],
+  [-2, 8, -22, 98, 59, -18, 7, -2], [-2, 8, -22, 96, 62, -19, 7, -2],
+  [-2, 8, -22, 94, 64, -19, 7, -2], [-2, 8, -22, 91, 67, -20, 8, -2],
+  [-2, 8, -22, 89, 69, -20, 8, -2], [-2, 8, -22, 87, 72, -21, 8, -2],
+  [-2, 8, -21, 84, 74, -21, 8, -2], [-2, 8, -22, 82, 77, -21, 8, -2],
+  [-2, 8, -21, 79, 79, -21, 8, -2], [-2, 8, -21, 77, 82, -22, 8, -2],
+  [-2, 8, -21, 74, 84, -21, 8, -2], [-2, 8, -21, 72, 87, -22, 8, -2],
+  [-2, 8, -20, 69, 89, -22, 8, -2], [-2, 8, -20, 67, 91, -22, 8, -2],
+  [-2, 7, -19, 64, 94, -22, 8, -2], [-2, 7, -19, 62, 96, -22, 8, -2],
+  [-2, 7, -18, 59, 98, -22, 8, -2], [-2, 7, -18, 56, 100, -21, 8, -2],
+  [-2, 7, -17, 54, 102, -21, 7, -2], [-2, 7, -16, 51, 104, -21, 7, -2],
+  [-2, 6, -16, 49, 106, -20, 7, -2], [-2, 6, -15, 46, 108, -20, 7, -2],
+  [-2, 6, -15, 43, 110, -19, 7, -2], [-2, 6, -14, 41, 111, -19, 7, -2],
+  [-1, 5, -13, 38, 113, -18, 6, -2], [-1, 5, -12, 35, 114, -17, 6, -2],
+  [-1, 5, -12, 33, 116, -17, 6, -2], [-1, 4, -11, 30, 118, -16, 5, -1],
+  [-1, 4, -10, 27, 119, -15, 5, -1], [-1, 4, -9, 25, 120, -14, 5, -2],
+  [-1, 3, -8, 23, 121, -13, 4, -1], [-1, 3, -7, 20, 122, -12, 4, -1],
+  [-1, 3, -7, 18, 123, -11, 4, -1], [-1, 3, -6, 16, 124, -10, 3, -1],
+  [-1, 2, -5, 13, 125, -8, 3, -1], [-1, 2, -4, 11, 126, -7, 2, -1],
+  [0, 1, -3, 8, 126, -6, 2, 0], [0, 1, -2, 6, 127, -5, 1, 0],
+  [0, 1, -2, 4, 127, -3, 1, 0], [0, 0, 0, 2, 127, -1, 0, 0],
+  [0, 0, 0, 1, 127, 0, 0, 0], [0, 0, 0, -1, 127, 2, 0, 0],
+  [0, 0, 1, -3, 127, 4, -1, 0], [0, 0, 1, -4, 126, 6, -2, 1],
+  [0, 0, 1, -5, 126, 8, -3, 1], [0, 0, 1, -6, 125, 11, -4, 1],
+  [0, 0, 1, -7, 124, 13, -4, 1], [0, 0, 2, -8, 123, 15, -5, 1],
+  [0, 0, 2, -9, 122, 18, -6, 1], [0, 0, 2, -10, 121, 20, -6, 1],
+  [0, 0, 2, -11, 120, 22, -7, 2], [0, 0, 2, -12, 119, 25, -8, 2],
+  [0, 0, 3, -13, 117, 27, -8, 2], [0, 0, 3, -13, 116, 29, -9, 2],
+  [0, 0, 3, -14, 114, 32, -10, 3], [0, 0, 3, -15, 113, 35, -10, 2],
+  [0, 0, 3, -15, 111, 37, -11, 3], [0, 0, 3, -16, 109, 40, -11, 3],
+  [0, 0, 3, -16, 108, 42, -12, 3], [0, 0, 4, -17, 106, 45, -13, 3],
+  [0, 0, 4, -17, 104, 47, -13, 3], [0, 0, 4, -17, 102, 50, -14, 3],
+  [0, 0, 4, -17, 100, 52, -14, 3], [0, 0, 4, -18, 98, 55, -15, 4],
+  [0, 0, 4, -18, 96, 58, -15, 3], [0, 0, 4, -18, 94, 60, -16, 4],
+  [0, 0, 4, -18, 91, 63, -16, 4], [0, 0, 4, -18, 89, 65, -16, 4],
+  [0, 0, 4, -18, 87, 68, -17, 4], [0, 0, 4, -18, 85, 70, -17, 4],
+  [0, 0, 4, -18, 82, 73, -17, 4], [0, 0, 4, -18, 80, 75, -17, 4],
+  [0, 0, 4, -18, 78, 78, -18, 4], [0, 0, 4, -17, 75, 80, -18, 4],
+  [0, 0, 4, -17, 73, 82, -18, 4], [0, 0, 4, -17, 70, 85, -18, 4],
+  [0, 0, 4, -17, 68, 87, -18, 4], [0, 0, 4, -16, 65, 89, -18, 4],
+  [0, 0, 4, -16, 63, 91, -18, 4], [0, 0, 4, -16, 60, 94, -18, 4],
+  [0, 0, 3, -15, 58, 96, -18, 4], [0, 0, 4, -15, 55, 98, -18, 4],
+  [0, 0, 3, -14, 52, 100, -17, 4], [0, 0, 3, -14, 50, 102, -17, 4],
+  [0, 0, 3, -13, 47, 104, -17, 4], [0, 0, 3, -13, 45, 106, -17, 4],
+  [0, 0, 3, -12, 42, 108, -16, 3], [0, 0, 3, -11, 40, 109, -16, 3],
+  [0, 0, 3, -11, 37, 111, -15, 3], [0, 0, 2, -10, 35, 113, -15, 3],
+  [0, 0, 3, -10, 32, 114, -14, 3], [0, 0, 2, -9, 29, 116, -13, 3],
+  [0, 0, 2, -8, 27, 117, -13, 3], [0, 0, 2, -8, 25, 119, -12, 2],
+  [0, 0, 2, -7, 22, 120, -11, 2], [0, 0, 1, -6, 20, 121, -10, 2],
+  [0, 0, 1, -6, 18, 122, -9, 2], [0, 0, 1, -5, 15, 123, -8, 2],
+  [0, 0, 1, -4, 13, 124, -7, 1], [0, 0, 1, -4, 11, 125, -6, 1],
+  [0, 0, 1, -3, 8, 126, -5, 1], [0, 0, 1, -2, 6, 126, -4, 1],
+  [0, 0, 0, -1, 4, 127, -3, 1], [0, 0, 0, 0, 2, 127, -1, 0],
+  [0, 0, 0, 0, 2, 127, -1, 0]
+];
+
+/// Filters a block with the block warp process (spec 7.11.3.5), returning
+/// the unrounded output of the vertical pass. The block is at `po` in a
+/// plane whose last valid sample is at (`last_x`, `last_y`).
+fn warp_8tap(
+  src: &Plane, po: &PlaneOffset, width: usize, height: usize, last_x: usize,
+  last_y: usize, wp: &WarpParams, bit_depth: usize
+) -> Vec<i32> {
+  let xdec = src.cfg.xdec;
+  let ydec = src.cfg.ydec;
+  let intermediate_bits = 4 - if bit_depth == 12 { 2 } else { 0 };
+  let mat = &wp.mat;
+  let mut out = vec![0i32; width * height];
+  let mut intermediate = [[0i32; 8]; 15];
+
+  for i in (0..height).step_by(8) {
+    for j in (0..width).step_by(8) {
+      let src_x = ((po.x + j as isize + 4) << xdec) as i64;
+      let src_y = ((po.y + i as isize + 4) << ydec) as i64;
+      let dst_x =
+        mat[2] as i64 * src_x + mat[3] as i64 * src_y + mat[0] as i64;
+      let dst_y =
+        mat[4] as i64 * src_x + mat[5] as i64 * src_y + mat[1] as i64;
+      let x4 = dst_x >> xdec;
+      let y4 = dst_y >> ydec;
+      // Filter positions keep only the precision of the shear parameters
+      let precision_mask =
+        ((1 << WARPEDMODEL_PREC_BITS) - 1) & !((1 << WARP_PARAM_REDUCE_BITS) - 1);
+      let ix4 = (x4 >> WARPEDMODEL_PREC_BITS) as i32;
+      let sx4 = (x4 & precision_mask) as i32;
+      let iy4 = (y4 >> WARPEDMODEL_PREC_BITS) as i32;
+      let sy4 = (y4 & precision_mask) as i32;
+
+      for i1 in -7..8i32 {
+        let y = clamp(iy4 + i1, 0, last_y as i32) as usize;
+        for i2 in -4..4i32 {
+          let sx = sx4 + wp.alpha * i2 + wp.beta * i1;
+          let filter = WARPED_FILTERS[(round_shift(sx, 10) + 64) as usize];
+          let sum: i32 = filter
+            .iter()
+            .enumerate()
+            .map(|(t, f)| {
+              let x = clamp(ix4 + i2 - 3 + t as i32, 0, last_x as i32);
+              f * src.p(x as usize, y) as i32
+            })
+            .sum();
+          intermediate[(i1 + 7) as usize][(i2 + 4) as usize] =
+            round_shift(sum, 7 - intermediate_bits);
+        }
+      }
+
+      for i1 in -4..(8.min(height - i) as i32 - 4) {
+        for i2 in -4..(8.min(width - j) as i32 - 4) {
+          let sy = sy4 + wp.gamma * i2 + wp.delta * i1;
+          let filter = WARPED_FILTERS[(round_shift(sy, 10) + 64) as usize];
+          let sum: i32 = filter
+            .iter()
+            .enumerate()
+            .map(|(t, f)| {
+              f * intermediate[(i1 + t as i32 + 4) as usize][(i2 + 4) as usize]
+            })
+            .sum();
+          out[(i as i32 + i1 + 4) as usize * width
+            + (j as i32 + i2 + 4) as usize] = sum;
+        }
+      }
+    }
+  }
+  out
+}
+
+/// Predicts a block with a warp model.
+pub fn put_warp<'a>(
+  dst: &'a mut PlaneMutSlice<'a>, src: &Plane, po: &PlaneOffset,
+  width: usize, height: usize, last_x: usize, last_y: usize,
+  wp: &WarpParams, bit_depth: usize
+) {
+  let dst_stride = dst.plane.cfg.stride;
+  let dst_slice = dst.as_mut_slice();
+  let max_sample_val = ((1 << bit_depth) - 1) as i32;
+  let intermediate_bits = 4 - if bit_depth == 12 { 2 } else { 0 };
+  let sums =
+    warp_8tap(src, po, width, height, last_x, last_y, wp, bit_depth);
+  for r in 0..height {
+    for c in 0..width {
+      dst_slice[r * dst_stride + c] =
+        round_shift(sums[r * width + c], 7 + intermediate_bits)
+          .max(0)
+          .min(max_sample_val) as u16;
+    }
+  }
+}
+
+/// Compound prediction counterpart of `put_warp`.
+pub fn prep_warp(
+  tmp: &mut [i16], src: &Plane, po: &PlaneOffset, width: usize,
+  height: usize, last_x: usize, last_y: usize, wp: &WarpParams,
+  bit_depth: usize
+) {
+  let sums =
+    warp_8tap(src, po, width, height, last_x, last_y, wp, bit_depth);
+  for (t, &s) in tmp[..width * height].iter_mut().zip(sums.iter()) {
+    *t = round_shift(s, 7) as i16;
+  }
+}
+
 pub fn mc_avg<'a>(
   dst: &'a mut PlaneMutSlice<'a>, tmp1: &[i16], tmp2: &[i16], width: usize,
   height: usize, bit_depth: usize
@@ -321,3 +885,207 @@ pub fn mc_avg<'a>(
     }
   }
 }
+
+/// Blends two intermediate predictions with a weight for the first one
+/// out of 16
+pub fn mc_w_avg<'a>(
+  dst: &'a mut PlaneMutSlice<'a>, tmp1: &[i16], tmp2: &[i16], width: usize,
+  height: usize, bit_depth: usize, weight: i32
+) {
+  let dst_stride = dst.plane.cfg.stride;
+  let dst_slice = dst.as_mut_slice();
+  let max_sample_val = ((1 << bit_depth) - 1) as i32;
+  let intermediate_bits = 4 - if bit_depth == 12 { 2 } else { 0 };
+  for r in 0..height {
+    for c in 0..width {
+      let i = r * width + c;
+      dst_slice[r * dst_stride + c] = round_shift(
+        tmp1[i] as i32 * weight + tmp2[i] as i32 * (16 - weight),
+        intermediate_bits + 4
+      )
+      .max(0)
+      .min(max_sample_val) as u16;
+    }
+  }
+}
+
+/// Blends two intermediate predictions with a per-sample weight for the
+/// first one out of 64
+pub fn mc_mask<'a>(
+  dst: &'a mut PlaneMutSlice<'a>, tmp1: &[i16], tmp2: &[i16], width: usize,
+  height: usize, bit_depth: usize, mask: &[u8]
+) {
+  let dst_stride = dst.plane.cfg.stride;
+  let dst_slice = dst.as_mut_slice();
+  let max_sample_val = ((1 << bit_depth) - 1) as i32;
+  let intermediate_bits = 4 - if bit_depth == 12 { 2 } else { 0 };
+  for r in 0..height {
+    for c in 0..width {
+      let i = r * width + c;
+      let m = mask[i] as i32;
+      dst_slice[r * dst_stride + c] = round_shift(
+        tmp1[i] as i32 * m + tmp2[i] as i32 * (64 - m),
+        intermediate_bits + 6
+      )
+      .max(0)
+      .min(max_sample_val) as u16;
+    }
+  }
+}
+
+/// Difference weight mask (spec 7.11.3.12) of two intermediate
+/// predictions, favouring the first one where they differ unless
+/// `mask_type` is set
+pub fn diffwtd_mask(
+  mask: &mut [u8], tmp1: &[i16], tmp2: &[i16], width: usize, height: usize,
+  bit_depth: usize, mask_type: bool
+) {
+  let intermediate_bits = 4 - if bit_depth == 12 { 2 } else { 0 };
+  for i in 0..width * height {
+    let diff = (tmp1[i] as i32 - tmp2[i] as i32).abs();
+    let diff = round_shift(diff, bit_depth - 8 + intermediate_bits);
+    let m = (38 + diff / 16).min(64) as u8;
+    mask[i] = if mask_type { 64 - m } else { m };
+  }
+}
+
+/// Picks the frame-level interpolation filter whose half-sample kernel best
+/// predicts every other sample of the source from its even-position
+/// neighbours, both horizontally and vertically. REGULAR is kept unless
+/// another kernel does noticeably better.
+pub fn select_interp_filter(
+  plane: &Plane, width: usize, height: usize, bit_depth: usize
+) -> FilterMode {
+  const MODES: [FilterMode; 4] = [
+    FilterMode::REGULAR,
+    FilterMode::SMOOTH,
+    FilterMode::SHARP,
+    FilterMode::BILINEAR
+  ];
+  let stride = plane.cfg.stride;
+  let data = plane.data_origin();
+  let max_sample_val = ((1 << bit_depth) - 1) as i32;
+  let mut sse = [0u64; 4];
+
+  if width < 16 || height < 16 {
+    return FilterMode::REGULAR;
+  }
+
+  for (mode, err) in MODES.iter().zip(sse.iter_mut()) {
+    let filter = SUBPEL_FILTERS[*mode as usize][8];
+    let mut predict = |src: &[u16], step: usize, orig: u16| {
+      let pred = round_shift(run_filter(src, step, filter), 7)
+        .max(0)
+        .min(max_sample_val);
+      let diff = (pred - orig as i32) as i64;
+      *err += (diff * diff) as u64;
+    };
+    // Horizontal half-sample predictions on every fourth row
+    for y in (0..height).step_by(4) {
+      let row = &data[y * stride..];
+      for x in (7..width - 7).step_by(2) {
+        predict(&row[x - 7..], 2, row[x]);
+      }
+    }
+    // Vertical half-sample predictions on every fourth column
+    for y in (7..height - 7).step_by(2) {
+      for x in (0..width).step_by(4) {
+        predict(&data[(y - 7) * stride + x..], 2 * stride, data[y * stride + x]);
+      }
+    }
+  }
+
+  let best = (0..MODES.len()).min_by_key(|&i| sse[i]).unwrap();
+  if sse[best] + (sse[0] >> 5) < sse[0] {
+    MODES[best]
+  } else {
+    FilterMode::REGULAR
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use rand::{ChaChaRng, Rng, SeedableRng};
+
+  #[test]
+  fn simd_8tap_matches_native() {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+      if !is_x86_feature_detected!("avx2") {
+        return;
+      }
+      let modes = [
+        FilterMode::REGULAR,
+        FilterMode::SMOOTH,
+        FilterMode::SHARP,
+        FilterMode::BILINEAR
+      ];
+      let mut ra = ChaChaRng::from_seed([0; 32]);
+      for &bit_depth in &[8, 10, 12] {
+        let mut src = Plane::new(160, 160, 0, 0, 0, 0);
+        for v in src.data.iter_mut() {
+          *v = ra.gen_range(0, 1 << bit_depth);
+        }
+        let po = PlaneOffset { x: 8, y: 8 };
+        for &(w, h) in &[(8, 4), (8, 8), (16, 32), (64, 16), (128, 128)] {
+          for _ in 0..8 {
+            let col_frac = ra.gen_range(0, 16);
+            let row_frac = ra.gen_range(0, 16);
+            let mode_x = modes[ra.gen_range(0, 4)];
+            let mode_y = modes[ra.gen_range(0, 4)];
+
+            let mut dst1 = Plane::new(w, h, 0, 0, 0, 0);
+            let mut dst2 = Plane::new(w, h, 0, 0, 0, 0);
+            put_8tap_native(
+              &mut dst1.mut_slice(&PlaneOffset { x: 0, y: 0 }),
+              src.slice(&po), w, h, col_frac, row_frac, bit_depth, mode_x,
+              mode_y
+            );
+            put_8tap(
+              &mut dst2.mut_slice(&PlaneOffset { x: 0, y: 0 }),
+              src.slice(&po), w, h, col_frac, row_frac, bit_depth, mode_x,
+              mode_y
+            );
+            assert_eq!(dst1.data, dst2.data);
+
+            let mut tmp1 = vec![0i16; w * h];
+            let mut tmp2 = vec![0i16; w * h];
+            prep_8tap_native(
+              &mut tmp1, src.slice(&po), w, h, col_frac, row_frac, bit_depth,
+              mode_x, mode_y
+            );
+            prep_8tap(
+              &mut tmp2, src.slice(&po), w, h, col_frac, row_frac, bit_depth,
+              mode_x, mode_y
+            );
+            assert_eq!(tmp1, tmp2);
+          }
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn diffwtd_mask_weights() {
+    // Sample differences, in the intermediate precision of prep_8tap, and
+    // the weight of the first prediction
+    let diffs = [(0, 38), (15, 38), (16, 39), (100, 44), (415, 63), (416, 64), (4000, 64)];
+    for &bit_depth in &[8, 10, 12] {
+      let intermediate_bits = if bit_depth == 12 { 2 } else { 4 };
+      let scale = 1 << (bit_depth - 8 + intermediate_bits);
+      let tmp1: Vec<i16> = diffs.iter().map(|&(d, _)| (d * scale).min(i16::MAX as i32) as i16).collect();
+      let tmp2 = vec![0i16; diffs.len()];
+      let mut mask = vec![0u8; diffs.len()];
+      for &mask_type in &[false, true] {
+        // The mask only depends on the absolute difference
+        for &(a, b) in &[(&tmp1, &tmp2), (&tmp2, &tmp1)] {
+          diffwtd_mask(&mut mask, a, b, diffs.len(), 1, bit_depth, mask_type);
+          for (&m, &(_, weight)) in mask.iter().zip(diffs.iter()) {
+            assert_eq!(m, if mask_type { 64 - weight } else { weight });
+          }
+        }
+      }
+    }
+  }
+}
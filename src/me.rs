@@ -14,9 +14,12 @@ pub use self::native::get_sad;
 use context::{BlockOffset, BLOCK_TO_PLANE_SHIFT, MI_SIZE};
 use FrameInvariants;
 use FrameState;
+use encoder::ReferenceFrame;
 use partition::*;
 use plane::*;
-use rdo::get_lambda_sqrt;
+use rdo::{get_lambda, get_lambda_sqrt};
+use util::clamp;
+use warp::*;
 
 #[cfg(all(target_arch = "x86_64", not(windows), feature = "nasm"))]
 mod nasm {
@@ -136,19 +139,30 @@ fn get_mv_range(fi: &FrameInvariants, bo: &BlockOffset, blk_w: usize, blk_h: usi
   let border_w = 128 + blk_w as isize * 8;
   let border_h = 128 + blk_h as isize * 8;
   let mvx_min = -(bo.x as isize) * (8 * MI_SIZE) as isize - border_w;
-  let mvx_max = (fi.w_in_b - bo.x - blk_w / MI_SIZE) as isize * (8 * MI_SIZE) as isize + border_w;
+  // Blocks may reach past the end of frames smaller than a superblock
+  let mvx_max = (fi.w_in_b as isize - (bo.x + blk_w / MI_SIZE) as isize) * (8 * MI_SIZE) as isize + border_w;
   let mvy_min = -(bo.y as isize) * (8 * MI_SIZE) as isize - border_h;
-  let mvy_max = (fi.h_in_b - bo.y - blk_h / MI_SIZE) as isize * (8 * MI_SIZE) as isize + border_h;
+  let mvy_max = (fi.h_in_b as isize - (bo.y + blk_h / MI_SIZE) as isize) * (8 * MI_SIZE) as isize + border_h;
 
   (mvx_min, mvx_max, mvy_min, mvy_max)
 }
 
+// Reference data to search, scaled to the frame size if needed
+fn get_search_ref<'a>(
+  fi: &'a FrameInvariants, fs: &'a FrameState, ref_idx: usize
+) -> Option<&'a ReferenceFrame> {
+  match fs.scaled_refs[ref_idx] {
+    Some(ref rec) => Some(rec),
+    None => fi.rec_buffer.frames[ref_idx].as_ref().map(|rec| &**rec)
+  }
+}
+
 pub fn motion_estimation(
   fi: &FrameInvariants, fs: &FrameState, bsize: BlockSize, bo: &BlockOffset,
   ref_frame: usize, cmv: MotionVector, pmv: &[MotionVector; 2]
 ) -> MotionVector {
-  match fi.rec_buffer.frames[fi.ref_frames[ref_frame - LAST_FRAME] as usize] {
-    Some(ref rec) => {
+  match get_search_ref(fi, fs, fi.ref_frames[ref_frame - LAST_FRAME] as usize) {
+    Some(rec) => {
       let po = PlaneOffset {
         x: (bo.x as isize) << BLOCK_TO_PLANE_SHIFT,
         y: (bo.y as isize) << BLOCK_TO_PLANE_SHIFT
@@ -190,7 +204,7 @@ pub fn motion_estimation(
       let mode = PredictionMode::NEWMV;
       let mut tmp_plane = Plane::new(blk_w, blk_h, 0, 0, 0, 0);
 
-      let mut steps = vec![8, 4, 2];
+      let mut steps = if fi.force_integer_mv != 0 { vec![8] } else { vec![8, 4, 2] };
       if fi.allow_high_precision_mv {
         steps.push(1);
       }
@@ -228,7 +242,11 @@ pub fn motion_estimation(
                 blk_w,
                 blk_h,
                 [ref_frame, NONE_FRAME],
-                [cand_mv, MotionVector { row: 0, col: 0 }]                
+                [cand_mv, MotionVector { row: 0, col: 0 }],
+                fi.default_interp_filter(),
+                CompoundParams::new(),
+                &mut [],
+                None
               );
             }
 
@@ -313,7 +331,7 @@ pub fn estimate_motion_ss4(
   fi: &FrameInvariants, fs: &FrameState, bsize: BlockSize, ref_idx: usize,
   bo: &BlockOffset
 ) -> Option<MotionVector> {
-  if let Some(ref rec) = fi.rec_buffer.frames[ref_idx] {
+  if let Some(rec) = get_search_ref(fi, fs, ref_idx) {
     let blk_w = bsize.width();
     let blk_h = bsize.height();
     let bo_adj = adjust_bo(bo, fi, blk_w, blk_h);
@@ -364,7 +382,7 @@ pub fn estimate_motion_ss2(
   fi: &FrameInvariants, fs: &FrameState, bsize: BlockSize, ref_idx: usize,
   bo: &BlockOffset, pmvs: &[Option<MotionVector>; 3]
 ) -> Option<MotionVector> {
-  if let Some(ref rec) = fi.rec_buffer.frames[ref_idx] {
+  if let Some(rec) = get_search_ref(fi, fs, ref_idx) {
     let blk_w = bsize.width();
     let blk_h = bsize.height();
     let bo_adj = adjust_bo(bo, fi, blk_w, blk_h);
@@ -415,6 +433,374 @@ pub fn estimate_motion_ss2(
   }
 }
 
+/// A point in the current frame and its match in the reference, in
+/// full-resolution pixels.
+#[derive(Copy, Clone)]
+struct MotionPoint {
+  x: f64,
+  y: f64,
+  ref_x: f64,
+  ref_y: f64
+}
+
+// Sum of absolute differences of a w x h block at the given offsets, or
+// None if the reference block extends outside the frame
+fn block_sad(
+  org: &Plane, org_po: &PlaneOffset, rec: &Plane, x: isize, y: isize,
+  w: usize, h: usize, frame_w: usize, frame_h: usize, bit_depth: usize
+) -> Option<u32> {
+  if x < 0 || y < 0 || x as usize + w > frame_w || y as usize + h > frame_h {
+    return None;
+  }
+  Some(get_sad(
+    &org.slice(org_po),
+    &rec.slice(&PlaneOffset { x, y }),
+    h,
+    w,
+    bit_depth
+  ))
+}
+
+// Matches textured blocks of the current frame in the reference with a
+// coarse search on the quarter resolution planes, refined with subpixel
+// accuracy on the half resolution planes
+fn find_motion_points(
+  fi: &FrameInvariants, fs: &FrameState, rec: &ReferenceFrame
+) -> Vec<MotionPoint> {
+  const QRES_BLOCK: usize = 8;
+  const QRES_RANGE: isize = 16;
+  const HRES_BLOCK: usize = 2 * QRES_BLOCK;
+  const HRES_RANGE: isize = 3;
+
+  let bit_depth = fi.sequence.bit_depth;
+  let (qw, qh) = (fi.width >> 2, fi.height >> 2);
+  let (hw, hh) = (fi.width >> 1, fi.height >> 1);
+  let mut points = Vec::new();
+
+  for qy in (0..qh.saturating_sub(QRES_BLOCK - 1)).step_by(QRES_BLOCK) {
+    for qx in (0..qw.saturating_sub(QRES_BLOCK - 1)).step_by(QRES_BLOCK) {
+      let qpo = PlaneOffset { x: qx as isize, y: qy as isize };
+
+      // Flat blocks cannot be matched reliably
+      let org = fs.input_qres.slice(&qpo);
+      let sum: u32 = org.iter_width(QRES_BLOCK).take(QRES_BLOCK)
+        .map(|row| row.iter().map(|&p| p as u32).sum::<u32>()).sum();
+      let mean = (sum / (QRES_BLOCK * QRES_BLOCK) as u32) as i32;
+      let deviation: u32 = org.iter_width(QRES_BLOCK).take(QRES_BLOCK)
+        .map(|row| row.iter().map(|&p| (p as i32 - mean).abs() as u32).sum::<u32>())
+        .sum();
+      if deviation < (2 * QRES_BLOCK * QRES_BLOCK << (bit_depth - 8)) as u32 {
+        continue;
+      }
+
+      let mut best = (std::u32::MAX, 0, 0);
+      for dy in (-QRES_RANGE..=QRES_RANGE).step_by(2) {
+        for dx in (-QRES_RANGE..=QRES_RANGE).step_by(2) {
+          if let Some(sad) = block_sad(
+            &fs.input_qres, &qpo, &rec.input_qres, qpo.x + dx, qpo.y + dy,
+            QRES_BLOCK, QRES_BLOCK, qw, qh, bit_depth
+          ) {
+            if sad < best.0 {
+              best = (sad, dx, dy);
+            }
+          }
+        }
+      }
+      if best.0 == std::u32::MAX {
+        continue;
+      }
+
+      let hpo = PlaneOffset { x: 2 * qpo.x, y: 2 * qpo.y };
+      let (cx, cy) = (2 * best.1, 2 * best.2);
+      let sad_at = |dx: isize, dy: isize| {
+        block_sad(
+          &fs.input_hres, &hpo, &rec.input_hres, hpo.x + dx, hpo.y + dy,
+          HRES_BLOCK, HRES_BLOCK, hw, hh, bit_depth
+        )
+      };
+      let mut best = (std::u32::MAX, 0, 0);
+      for dy in cy - HRES_RANGE..=cy + HRES_RANGE {
+        for dx in cx - HRES_RANGE..=cx + HRES_RANGE {
+          if let Some(sad) = sad_at(dx, dy) {
+            if sad < best.0 {
+              best = (sad, dx, dy);
+            }
+          }
+        }
+      }
+      if best.0 == std::u32::MAX {
+        continue;
+      }
+
+      // Fit a parabola through the costs around the minimum
+      let subpel = |lo: Option<u32>, hi: Option<u32>| match (lo, hi) {
+        (Some(lo), Some(hi)) => {
+          let curvature = lo as f64 + hi as f64 - 2.0 * best.0 as f64;
+          if curvature > 0.0 {
+            ((lo as f64 - hi as f64) / (2.0 * curvature)).max(-0.5).min(0.5)
+          } else {
+            0.0
+          }
+        }
+        _ => 0.0
+      };
+      let (_, dx, dy) = best;
+      let sx = subpel(sad_at(dx - 1, dy), sad_at(dx + 1, dy));
+      let sy = subpel(sad_at(dx, dy - 1), sad_at(dx, dy + 1));
+
+      // Centers of the blocks in full resolution pixels
+      let x = (2 * hpo.x) as f64 + HRES_BLOCK as f64 - 0.5;
+      let y = (2 * hpo.y) as f64 + HRES_BLOCK as f64 - 0.5;
+      points.push(MotionPoint {
+        x,
+        y,
+        ref_x: x + 2.0 * (dx as f64 + sx),
+        ref_y: y + 2.0 * (dy as f64 + sy)
+      });
+    }
+  }
+  points
+}
+
+// Least squares fit of a ROTZOOM or AFFINE model to the inlier points, as
+// [x translation, y translation, a, b, c, d] in pixels
+fn fit_motion_model(
+  points: &[MotionPoint], inliers: &[bool], mode: GlobalMVMode
+) -> Option<[f64; 6]> {
+  let n = inliers.iter().filter(|&&i| i).count() as f64;
+  if n < 2.0 {
+    return None;
+  }
+  let inlier_points = || points.iter().zip(inliers).filter(|p| *p.1).map(|p| p.0);
+  let (mut mx, mut my, mut mrx, mut mry) = (0.0, 0.0, 0.0, 0.0);
+  for p in inlier_points() {
+    mx += p.x / n;
+    my += p.y / n;
+    mrx += p.ref_x / n;
+    mry += p.ref_y / n;
+  }
+  let (mut sxx, mut sxy, mut syy) = (0.0, 0.0, 0.0);
+  let (mut sxrx, mut syrx, mut sxry, mut syry) = (0.0, 0.0, 0.0, 0.0);
+  for p in inlier_points() {
+    let (x, y) = (p.x - mx, p.y - my);
+    let (rx, ry) = (p.ref_x - mrx, p.ref_y - mry);
+    sxx += x * x;
+    sxy += x * y;
+    syy += y * y;
+    sxrx += x * rx;
+    syrx += y * rx;
+    sxry += x * ry;
+    syry += y * ry;
+  }
+  let (a, b, c, d) = match mode {
+    GlobalMVMode::ROTZOOM => {
+      let s = sxx + syy;
+      if s <= 0.0 {
+        return None;
+      }
+      let a = (sxrx + syry) / s;
+      let b = (syrx - sxry) / s;
+      (a, b, -b, a)
+    }
+    GlobalMVMode::AFFINE => {
+      let det = sxx * syy - sxy * sxy;
+      if det <= 1e-6 * sxx * syy {
+        return None;
+      }
+      (
+        (sxrx * syy - syrx * sxy) / det,
+        (syrx * sxx - sxrx * sxy) / det,
+        (sxry * syy - syry * sxy) / det,
+        (syry * sxx - sxry * sxy) / det
+      )
+    }
+    _ => unreachable!()
+  };
+  Some([mrx - a * mx - b * my, mry - c * mx - d * my, a, b, c, d])
+}
+
+// Distance between where a model maps a point and its actual match
+fn model_residual(m: &[f64; 6], p: &MotionPoint) -> f64 {
+  let ex = m[2] * p.x + m[3] * p.y + m[0] - p.ref_x;
+  let ey = m[4] * p.x + m[5] * p.y + m[1] - p.ref_y;
+  (ex * ex + ey * ey).sqrt()
+}
+
+// Robustly fits a model with RANSAC over minimal sets of points, refines it
+// on the points it explains, then quantizes it to the precision it is
+// coded with
+fn estimate_motion_model(
+  fi: &FrameInvariants, points: &[MotionPoint], mode: GlobalMVMode
+) -> Option<[i32; 6]> {
+  const RANSAC_TRIALS: usize = 256;
+  const INLIER_THRESHOLD: f64 = 1.5;
+
+  let min_points = if mode == GlobalMVMode::ROTZOOM { 2 } else { 3 };
+  if points.len() < 8 {
+    return None;
+  }
+  let classify = |m: &[f64; 6]| -> Vec<bool> {
+    points.iter().map(|p| model_residual(m, p) < INLIER_THRESHOLD).collect()
+  };
+  let count = |inliers: &[bool]| inliers.iter().filter(|&&i| i).count();
+
+  // A fixed seed keeps the estimate deterministic
+  let mut seed = 0x9e37_79b9u32;
+  let mut inliers = vec![false; points.len()];
+  for _ in 0..RANSAC_TRIALS {
+    let mut sample = vec![false; points.len()];
+    let mut picked = 0;
+    while picked < min_points {
+      seed ^= seed << 13;
+      seed ^= seed >> 17;
+      seed ^= seed << 5;
+      let i = seed as usize % points.len();
+      if !sample[i] {
+        sample[i] = true;
+        picked += 1;
+      }
+    }
+    if let Some(m) = fit_motion_model(points, &sample, mode) {
+      let candidate = classify(&m);
+      if count(&candidate) > count(&inliers) {
+        inliers = candidate;
+      }
+    }
+  }
+
+  let mut model = None;
+  for _ in 0..4 {
+    let m = fit_motion_model(points, &inliers, mode)?;
+    let next = classify(&m);
+    model = Some(m);
+    if next == inliers || count(&next) < min_points {
+      break;
+    }
+    inliers = next;
+  }
+  let inlier_count = count(&inliers);
+  if inlier_count < 8 || inlier_count * 3 < points.len() {
+    return None;
+  }
+  let m = model?;
+
+  let hp = fi.allow_high_precision_mv;
+  let quantize = |value: f64, idx: usize| {
+    let (abs_bits, prec_bits) = gm_param_bits(mode, idx, hp);
+    let identity = if idx % 3 == 2 { 1.0 } else { 0.0 };
+    let limit = 1 << abs_bits;
+    let coded = ((value - identity) * (1 << prec_bits) as f64).round() as i32;
+    gm_param_from_coded(clamp(coded, -limit, limit), mode, idx, hp)
+  };
+  let mut mat = DEFAULT_WARP_PARAMS;
+  mat[2] = quantize(m[2], 2);
+  mat[3] = quantize(m[3], 3);
+  if mode == GlobalMVMode::AFFINE {
+    mat[4] = quantize(m[4], 4);
+    mat[5] = quantize(m[5], 5);
+  } else {
+    mat[4] = -mat[3];
+    mat[5] = mat[2];
+  }
+
+  // Recompute the translation around the centroid of the inliers so it
+  // absorbs the error of the quantized linear part
+  let scale = (1 << WARPEDMODEL_PREC_BITS) as f64;
+  let (mut tx, mut ty) = (0.0, 0.0);
+  for (p, _) in points.iter().zip(&inliers).filter(|p| *p.1) {
+    tx += p.ref_x - (mat[2] as f64 * p.x + mat[3] as f64 * p.y) / scale;
+    ty += p.ref_y - (mat[4] as f64 * p.x + mat[5] as f64 * p.y) / scale;
+  }
+  mat[0] = quantize(tx / inlier_count as f64, 0);
+  mat[1] = quantize(ty / inlier_count as f64, 1);
+
+  WarpParams::new(&mat).map(|_| mat)
+}
+
+// Squared error of predicting the half resolution input from the reference
+// warped by mat, sampling every other pixel in each direction
+fn warp_error(fi: &FrameInvariants, fs: &FrameState, rec: &ReferenceFrame, mat: &[i32; 6]) -> u64 {
+  let (hw, hh) = (fi.width >> 1, fi.height >> 1);
+  let scale = (1 << WARPEDMODEL_PREC_BITS) as f64;
+  let sample = |x: isize, y: isize| {
+    rec.input_hres.p(clamp(x, 0, hw as isize - 1) as usize, clamp(y, 0, hh as isize - 1) as usize)
+      as f64
+  };
+  let mut sse = 0;
+  for y in (0..hh).step_by(2) {
+    for x in (0..hw).step_by(2) {
+      let (fx, fy) = (2.0 * x as f64 + 0.5, 2.0 * y as f64 + 0.5);
+      let rx = (mat[2] as f64 * fx + mat[3] as f64 * fy + mat[0] as f64) / scale;
+      let ry = (mat[4] as f64 * fx + mat[5] as f64 * fy + mat[1] as f64) / scale;
+      let (hx, hy) = ((rx - 0.5) / 2.0, (ry - 0.5) / 2.0);
+      let (ix, iy) = (hx.floor() as isize, hy.floor() as isize);
+      let (ax, ay) = (hx - ix as f64, hy - iy as f64);
+      let top = sample(ix, iy) * (1.0 - ax) + sample(ix + 1, iy) * ax;
+      let bottom = sample(ix, iy + 1) * (1.0 - ax) + sample(ix + 1, iy + 1) * ax;
+      let pred = (top * (1.0 - ay) + bottom * ay).round() as i32;
+      let diff = fs.input_hres.p(x, y) as i32 - pred;
+      sse += (diff * diff) as u64;
+    }
+  }
+  sse
+}
+
+// Approximate cost in bits of signaling a global motion model
+fn global_motion_bits(
+  fi: &FrameInvariants, ref_frame: usize, mode: GlobalMVMode, mat: &[i32; 6]
+) -> u32 {
+  let params: &[usize] = match mode {
+    GlobalMVMode::IDENTITY => return 1,
+    GlobalMVMode::TRANSLATION => &[0, 1],
+    GlobalMVMode::ROTZOOM => &[2, 3, 0, 1],
+    GlobalMVMode::AFFINE => &[2, 3, 4, 5, 0, 1]
+  };
+  let hp = fi.allow_high_precision_mv;
+  let prev = fi.prev_gm_params()[ref_frame];
+  let type_bits = if mode == GlobalMVMode::ROTZOOM { 2 } else { 3 };
+  type_bits + params.iter().map(|&i| {
+    let diff = (gm_param_to_coded(mat[i], mode, i, hp)
+      - gm_param_to_coded(prev[i], mode, i, hp)).abs() as u32;
+    if diff < 8 { 4 } else { 2 * (32 - diff.leading_zeros()) - 2 }
+  }).sum::<u32>()
+}
+
+/// Estimates the global motion of the frame relative to `ref_frame` and
+/// selects the cheapest model in the rate-distortion sense. Only unscaled
+/// references are considered, since the decoder never warps scaled ones.
+pub fn estimate_global_motion(
+  fi: &FrameInvariants, fs: &FrameState, ref_frame: usize
+) -> (GlobalMVMode, [i32; 6]) {
+  let identity = (GlobalMVMode::IDENTITY, DEFAULT_WARP_PARAMS);
+  let rec = match fi.rec_buffer.frames[fi.ref_frames[ref_frame - LAST_FRAME] as usize] {
+    Some(ref rec) if rec.width == fi.width && rec.height == fi.height => rec,
+    _ => return identity
+  };
+  let points = find_motion_points(fi, fs, rec);
+
+  let lambda = get_lambda(fi);
+  // Every sample stands for 4 half resolution or 16 full resolution pixels
+  let cost = |mode: GlobalMVMode, mat: &[i32; 6]| {
+    16.0 * warp_error(fi, fs, rec, mat) as f64
+      + lambda * global_motion_bits(fi, ref_frame, mode, mat) as f64
+  };
+  let mut best = identity;
+  let mut best_cost = cost(best.0, &best.1);
+  for &mode in &[GlobalMVMode::ROTZOOM, GlobalMVMode::AFFINE] {
+    if let Some(mat) = estimate_motion_model(fi, &points, mode) {
+      if mat == DEFAULT_WARP_PARAMS {
+        continue;
+      }
+      let c = cost(mode, &mat);
+      if c < best_cost {
+        best = (mode, mat);
+        best_cost = c;
+      }
+    }
+  }
+  best
+}
+
 #[cfg(test)]
 pub mod test {
   use super::*;
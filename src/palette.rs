@@ -0,0 +1,499 @@
+// Copyright (c) 2019, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+use context::{BlockOffset, MI_SIZE};
+use ec::Writer;
+use encoder::{FrameInvariants, FrameState};
+use partition::*;
+use plane::*;
+
+use std::cmp;
+
+pub const PALETTE_MIN_SIZE: usize = 2;
+pub const PALETTE_MAX_SIZE: usize = 8;
+const PALETTE_NUM_NEIGHBORS: usize = 3;
+const PALETTE_COLOR_HASH_MULTIPLIERS: [usize; PALETTE_NUM_NEIGHBORS] = [1, 2, 2];
+/// Colour context of each neighbour score hash, -1 for impossible hashes
+const PALETTE_COLOR_CONTEXT: [i8; 9] = [-1, -1, 0, -1, -1, 4, 3, 2, 1];
+
+/// Blocks with more distinct colours are not coded with a palette
+const PALETTE_MAX_BLOCK_COLORS: usize = 64;
+const K_MEANS_MAX_ITERS: usize = 50;
+
+/// Palettes of a block (spec 5.11.46). `size` is 0 for the plane types
+/// without a palette; the chroma palette pairs `colors[1]` and `colors[2]`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PaletteParams {
+  pub size: [usize; 2],
+  pub colors: [[u16; PALETTE_MAX_SIZE]; 3]
+}
+
+impl PaletteParams {
+  pub fn new() -> PaletteParams {
+    PaletteParams { size: [0; 2], colors: [[0; PALETTE_MAX_SIZE]; 3] }
+  }
+
+  /// The palette colours of plane `p`
+  pub fn plane_colors(&self, p: usize) -> &[u16] {
+    &self.colors[p][..self.size[(p > 0) as usize]]
+  }
+
+  pub fn has_palette(&self, p: usize) -> bool {
+    self.size[(p > 0) as usize] > 0
+  }
+}
+
+/// Size of plane `p` of a block and of its part inside the frame
+fn plane_block_dims(
+  fi: &FrameInvariants, bo: &BlockOffset, bsize: BlockSize, xdec: usize, ydec: usize
+) -> (usize, usize, usize, usize) {
+  let onscreen_w = cmp::min(bsize.width(), (fi.w_in_b - bo.x) * MI_SIZE);
+  let onscreen_h = cmp::min(bsize.height(), (fi.h_in_b - bo.y) * MI_SIZE);
+  // Palettes are only picked for blocks with chroma of at least 4x4
+  debug_assert!(bsize.width() >> xdec >= 4 && bsize.height() >> ydec >= 4);
+  (bsize.width() >> xdec, bsize.height() >> ydec, onscreen_w >> xdec, onscreen_h >> ydec)
+}
+
+/// Colour index map of a plane type of a palette block, with the colours
+/// of the palette. The chroma map indexes both U and V colours.
+pub struct ColorMap {
+  /// Position of the block in the plane
+  x: isize,
+  y: isize,
+  width: usize,
+  pub onscreen_width: usize,
+  pub onscreen_height: usize,
+  colors: [[u16; PALETTE_MAX_SIZE]; 3],
+  indices: Vec<u8>
+}
+
+impl ColorMap {
+  /// Maps each sample of plane `p` inside the frame to the nearest colour
+  /// of the palette, and extends the map to the rest of the block by
+  /// repeating its last column and row (spec 5.11.49).
+  pub fn new(
+    fi: &FrameInvariants, fs: &FrameState, bo: &BlockOffset, bsize: BlockSize,
+    palette: &PaletteParams, p: usize
+  ) -> ColorMap {
+    let PlaneConfig { xdec, ydec, .. } = fs.input.planes[p].cfg;
+    let (width, height, onscreen_width, onscreen_height) =
+      plane_block_dims(fi, bo, bsize, xdec, ydec);
+    let po = bo.plane_offset(&fs.input.planes[p].cfg);
+    let mut indices = vec![0u8; width * height];
+
+    if p == 0 {
+      let colors = palette.plane_colors(0);
+      let src = fs.input.planes[0].slice(&po);
+      for r in 0..onscreen_height {
+        for c in 0..onscreen_width {
+          let v = src.p(c, r) as i32;
+          indices[r * width + c] = nearest_color(colors.len(), |i| {
+            (v - colors[i] as i32).abs() as u32
+          });
+        }
+      }
+    } else {
+      let (colors_u, colors_v) = (palette.plane_colors(1), palette.plane_colors(2));
+      let src_u = fs.input.planes[1].slice(&po);
+      let src_v = fs.input.planes[2].slice(&po);
+      for r in 0..onscreen_height {
+        for c in 0..onscreen_width {
+          let u = src_u.p(c, r) as i32;
+          let v = src_v.p(c, r) as i32;
+          indices[r * width + c] = nearest_color(colors_u.len(), |i| {
+            let du = u - colors_u[i] as i32;
+            let dv = v - colors_v[i] as i32;
+            (du * du + dv * dv) as u32
+          });
+        }
+      }
+    }
+
+    for r in 0..onscreen_height {
+      for c in onscreen_width..width {
+        indices[r * width + c] = indices[r * width + onscreen_width - 1];
+      }
+    }
+    for r in onscreen_height..height {
+      for c in 0..width {
+        indices[r * width + c] = indices[(onscreen_height - 1) * width + c];
+      }
+    }
+
+    ColorMap {
+      x: po.x,
+      y: po.y,
+      width,
+      onscreen_width,
+      onscreen_height,
+      colors: palette.colors,
+      indices
+    }
+  }
+
+  pub fn index(&self, row: usize, col: usize) -> usize {
+    self.indices[row * self.width + col] as usize
+  }
+
+  /// Palette prediction process (spec 7.11.4) of the `w`x`h` transform
+  /// block at `dst` in plane `p`.
+  pub fn predict(&self, p: usize, dst: &mut PlaneMutSlice, w: usize, h: usize) {
+    let x0 = (dst.x - self.x) as usize;
+    let y0 = (dst.y - self.y) as usize;
+    let stride = dst.plane.cfg.stride;
+    let buf = dst.as_mut_slice();
+    for r in 0..h {
+      for c in 0..w {
+        buf[r * stride + c] = self.colors[p][self.index(y0 + r, x0 + c)];
+      }
+    }
+  }
+
+  /// Colour context of a sample and the order of the colour indices from
+  /// the most to the least common in its coded neighbourhood (spec 5.11.50).
+  pub fn color_context(
+    &self, r: usize, c: usize, n: usize
+  ) -> (usize, [u8; PALETTE_MAX_SIZE]) {
+    let mut scores = [0; PALETTE_MAX_SIZE];
+    let mut order = [0, 1, 2, 3, 4, 5, 6, 7];
+    if c > 0 {
+      scores[self.index(r, c - 1)] += 2;
+    }
+    if r > 0 && c > 0 {
+      scores[self.index(r - 1, c - 1)] += 1;
+    }
+    if r > 0 {
+      scores[self.index(r - 1, c)] += 2;
+    }
+    for i in 0..PALETTE_NUM_NEIGHBORS {
+      let mut max_score = scores[i];
+      let mut max_idx = i;
+      for j in (i + 1)..n {
+        if scores[j] > max_score {
+          max_score = scores[j];
+          max_idx = j;
+        }
+      }
+      if max_idx != i {
+        let max_color_order = order[max_idx];
+        for k in (i + 1..=max_idx).rev() {
+          scores[k] = scores[k - 1];
+          order[k] = order[k - 1];
+        }
+        scores[i] = max_score;
+        order[i] = max_color_order;
+      }
+    }
+    let hash: usize = (0..PALETTE_NUM_NEIGHBORS)
+      .map(|i| scores[i] * PALETTE_COLOR_HASH_MULTIPLIERS[i])
+      .sum();
+    let ctx = PALETTE_COLOR_CONTEXT[hash];
+    debug_assert!(ctx >= 0);
+    (ctx as usize, order)
+  }
+}
+
+fn nearest_color<F: Fn(usize) -> u32>(n: usize, dist: F) -> u8 {
+  (0..n).min_by_key(|&i| dist(i)).unwrap() as u8
+}
+
+/// Merges the sorted colours of the above and left palettes, dropping
+/// duplicates (get_palette_cache in the spec).
+pub fn merge_palette_cache(above: &[u16], left: &[u16]) -> Vec<u16> {
+  let mut cache: Vec<u16> = Vec::with_capacity(above.len() + left.len());
+  let (mut a, mut l) = (0, 0);
+  while a < above.len() || l < left.len() {
+    let color = if l == left.len() || (a < above.len() && above[a] <= left[l]) {
+      if l < left.len() && left[l] == above[a] {
+        l += 1;
+      }
+      a += 1;
+      above[a - 1]
+    } else {
+      l += 1;
+      left[l - 1]
+    };
+    if cache.last() != Some(&color) {
+      cache.push(color);
+    }
+  }
+  cache
+}
+
+fn ceil_log2(x: u32) -> u32 {
+  if x < 2 { 0 } else { 32 - (x - 1).leading_zeros() }
+}
+
+/// Codes the colours of a luma or U palette: those found in `cache` are
+/// flagged, the others are coded in ascending order as deltas of at least
+/// `min_delta` (spec 5.11.46).
+pub fn write_palette_colors(
+  w: &mut dyn Writer, cache: &[u16], colors: &[u16], min_delta: u32, bit_depth: usize
+) {
+  let n = colors.len();
+  let mut in_cache = [false; PALETTE_MAX_SIZE];
+  let mut n_in_cache = 0;
+  for &cached in cache.iter() {
+    if n_in_cache >= n {
+      break;
+    }
+    let found = colors.iter().enumerate()
+      .position(|(i, &color)| !in_cache[i] && color == cached);
+    if let Some(i) = found {
+      in_cache[i] = true;
+      n_in_cache += 1;
+    }
+    w.bit(found.is_some() as u16);
+  }
+
+  let literals: Vec<u32> = colors.iter().enumerate()
+    .filter(|&(i, _)| !in_cache[i]).map(|(_, &color)| color as u32).collect();
+  if literals.is_empty() {
+    return;
+  }
+  w.literal(bit_depth as u8, literals[0]);
+  if literals.len() == 1 {
+    return;
+  }
+  let min_bits = bit_depth as u32 - 3;
+  let max_delta = literals.windows(2).map(|c| c[1] - c[0]).max().unwrap();
+  let mut bits = cmp::max(ceil_log2(max_delta + 1 - min_delta), min_bits);
+  w.literal(2, bits - min_bits);
+  let mut range = (1 << bit_depth) - literals[0] - min_delta;
+  for c in literals.windows(2) {
+    let delta = c[1] - c[0];
+    w.literal(bits as u8, delta - min_delta);
+    range -= delta;
+    bits = cmp::min(bits, ceil_log2(range));
+  }
+}
+
+/// Codes the colours of a V palette, as wrapping deltas when that takes
+/// fewer bits than literals (spec 5.11.46).
+pub fn write_palette_colors_v(w: &mut dyn Writer, colors: &[u16], bit_depth: usize) {
+  let n = colors.len();
+  let max_val = 1 << bit_depth;
+  let min_bits = bit_depth - 4;
+  let deltas: Vec<(i32, bool)> = colors.windows(2).map(|c| {
+    let delta = (c[1] as i32 - c[0] as i32).abs();
+    let sign = c[1] < c[0];
+    if delta <= max_val - delta { (delta, sign) } else { (max_val - delta, !sign) }
+  }).collect();
+  let max_delta = deltas.iter().map(|&(d, _)| d).max().unwrap();
+  let zero_count = deltas.iter().filter(|&&(d, _)| d == 0).count();
+  let bits = cmp::max(ceil_log2(max_delta as u32 + 1) as usize, min_bits);
+  let rate_using_delta = 2 + bit_depth + (bits + 1) * (n - 1) - zero_count;
+  let rate_using_raw = bit_depth * n;
+  if rate_using_delta < rate_using_raw {
+    w.bit(1);
+    w.literal(2, (bits - min_bits) as u32);
+    w.literal(bit_depth as u8, colors[0] as u32);
+    for &(delta, sign) in deltas.iter() {
+      w.literal(bits as u8, delta as u32);
+      if delta != 0 {
+        w.bit(sign as u16);
+      }
+    }
+  } else {
+    w.bit(0);
+    for &color in colors.iter() {
+      w.literal(bit_depth as u8, color as u32);
+    }
+  }
+}
+
+/// Codes `v` in `[0, n)` with the non-symmetric unsigned code (spec 4.10.7).
+pub fn write_uniform(w: &mut dyn Writer, n: usize, v: usize) {
+  let l = 32 - (n as u32).leading_zeros();
+  let m = (1 << l) - n as u32;
+  let v = v as u32;
+  if v < m {
+    w.literal(l as u8 - 1, v);
+  } else {
+    w.literal(l as u8 - 1, m + ((v - m) >> 1));
+    w.bit(((v - m) & 1) as u16);
+  }
+}
+
+/// Lloyd's k-means clustering of the `dim`-dimensional points of `data`,
+/// refining the initial `centroids` until the total distance stops
+/// decreasing.
+fn k_means(data: &[i32], dim: usize, centroids: &mut [i32]) {
+  let k = centroids.len() / dim;
+  let n = data.len() / dim;
+  let assign = |centroids: &[i32], indices: &mut [usize]| -> i64 {
+    let mut total = 0;
+    for i in 0..n {
+      let point = &data[i * dim..(i + 1) * dim];
+      let (best, dist) = (0..k).map(|j| {
+        let d: i64 = point.iter().zip(&centroids[j * dim..(j + 1) * dim])
+          .map(|(&a, &b)| ((a - b) as i64) * ((a - b) as i64)).sum();
+        (j, d)
+      }).min_by_key(|&(_, d)| d).unwrap();
+      indices[i] = best;
+      total += dist;
+    }
+    total
+  };
+
+  let mut indices = vec![0; n];
+  let mut dist = assign(centroids, &mut indices);
+  for _ in 0..K_MEANS_MAX_ITERS {
+    let prev_centroids = centroids.to_vec();
+    let mut sums = vec![0i64; k * dim];
+    let mut counts = vec![0i64; k];
+    for i in 0..n {
+      counts[indices[i]] += 1;
+      for d in 0..dim {
+        sums[indices[i] * dim + d] += data[i * dim + d] as i64;
+      }
+    }
+    for j in 0..k {
+      // Empty clusters keep their centroid
+      if counts[j] > 0 {
+        for d in 0..dim {
+          centroids[j * dim + d] =
+            ((sums[j * dim + d] + counts[j] / 2) / counts[j]) as i32;
+        }
+      }
+    }
+    let prev_dist = dist;
+    dist = assign(centroids, &mut indices);
+    if dist > prev_dist {
+      centroids.copy_from_slice(&prev_centroids);
+      break;
+    }
+    if centroids[..] == prev_centroids[..] {
+      break;
+    }
+  }
+}
+
+/// Centroids spread evenly over the range of each dimension of `data`
+fn initial_centroids(data: &[i32], dim: usize, k: usize) -> Vec<i32> {
+  let mut centroids = vec![0; k * dim];
+  for d in 0..dim {
+    let values = data.iter().skip(d).step_by(dim);
+    let lb = *values.clone().min().unwrap();
+    let ub = *values.max().unwrap();
+    for i in 0..k {
+      centroids[i * dim + d] = lb + ((2 * i as i32 + 1) * (ub - lb)) / (2 * k as i32);
+    }
+  }
+  centroids
+}
+
+/// Luma palette candidate of a block: its colours if there are few enough,
+/// otherwise the centres of their k-means clusters. `None` if the block
+/// has a single colour or too many to benefit from a palette.
+pub fn luma_palette(
+  fi: &FrameInvariants, fs: &FrameState, bo: &BlockOffset, bsize: BlockSize
+) -> Option<PaletteParams> {
+  let cfg = &fs.input.planes[0].cfg;
+  let (_, _, w, h) = plane_block_dims(fi, bo, bsize, 0, 0);
+  let src = fs.input.planes[0].slice(&bo.plane_offset(cfg));
+  let data: Vec<i32> =
+    (0..h).flat_map(|r| (0..w).map(move |c| (r, c))).map(|(r, c)| src.p(c, r) as i32).collect();
+
+  let mut distinct = data.clone();
+  distinct.sort();
+  distinct.dedup();
+  if distinct.len() < PALETTE_MIN_SIZE || distinct.len() > PALETTE_MAX_BLOCK_COLORS {
+    return None;
+  }
+
+  let mut colors = if distinct.len() <= PALETTE_MAX_SIZE {
+    distinct
+  } else {
+    let mut centroids = initial_centroids(&data, 1, PALETTE_MAX_SIZE);
+    k_means(&data, 1, &mut centroids);
+    centroids
+  };
+  colors.sort();
+  colors.dedup();
+  if colors.len() < PALETTE_MIN_SIZE {
+    return None;
+  }
+
+  let mut palette = PaletteParams::new();
+  palette.size[0] = colors.len();
+  for (i, &color) in colors.iter().enumerate() {
+    palette.colors[0][i] = color as u16;
+  }
+  Some(palette)
+}
+
+/// Chroma palette candidate of a block, of pairs of U and V colours found
+/// as for luma and sorted by U, added to `palette`.
+pub fn chroma_palette(
+  fi: &FrameInvariants, fs: &FrameState, bo: &BlockOffset, bsize: BlockSize,
+  palette: PaletteParams
+) -> Option<PaletteParams> {
+  let PlaneConfig { xdec, ydec, .. } = fs.input.planes[1].cfg;
+  let (_, _, w, h) = plane_block_dims(fi, bo, bsize, xdec, ydec);
+  let po = bo.plane_offset(&fs.input.planes[1].cfg);
+  let src_u = fs.input.planes[1].slice(&po);
+  let src_v = fs.input.planes[2].slice(&po);
+  let mut data = Vec::with_capacity(2 * w * h);
+  for r in 0..h {
+    for c in 0..w {
+      data.push(src_u.p(c, r) as i32);
+      data.push(src_v.p(c, r) as i32);
+    }
+  }
+
+  let mut distinct: Vec<(i32, i32)> = data.chunks(2).map(|uv| (uv[0], uv[1])).collect();
+  distinct.sort();
+  distinct.dedup();
+  if distinct.len() < PALETTE_MIN_SIZE || distinct.len() > PALETTE_MAX_BLOCK_COLORS {
+    return None;
+  }
+
+  let mut colors = if distinct.len() <= PALETTE_MAX_SIZE {
+    distinct
+  } else {
+    let mut centroids = initial_centroids(&data, 2, PALETTE_MAX_SIZE);
+    k_means(&data, 2, &mut centroids);
+    centroids.chunks(2).map(|uv| (uv[0], uv[1])).collect()
+  };
+  colors.sort();
+  colors.dedup();
+  if colors.len() < PALETTE_MIN_SIZE {
+    return None;
+  }
+
+  let mut palette = palette;
+  palette.size[1] = colors.len();
+  for (i, &(u, v)) in colors.iter().enumerate() {
+    palette.colors[1][i] = u as u16;
+    palette.colors[2][i] = v as u16;
+  }
+  Some(palette)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn palette_cache_merge() {
+    assert_eq!(merge_palette_cache(&[10, 20, 30], &[5, 20, 40]), vec![5, 10, 20, 30, 40]);
+    assert_eq!(merge_palette_cache(&[], &[7, 7, 9]), vec![7, 9]);
+    assert!(merge_palette_cache(&[], &[]).is_empty());
+  }
+
+  #[test]
+  fn k_means_clusters() {
+    let data = [10, 12, 11, 100, 102, 98, 200, 201];
+    let mut centroids = initial_centroids(&data, 1, 3);
+    k_means(&data, 1, &mut centroids);
+    centroids.sort();
+    assert_eq!(centroids, vec![11, 100, 201]);
+  }
+}
@@ -13,11 +13,13 @@
 use self::BlockSize::*;
 use self::TxSize::*;
 use context::*;
-use encoder::{ChromaSampling, FrameInvariants};
+use encoder::{ChromaSampling, FrameInvariants, ReferenceFrame};
+use intrabc::predict_intrabc;
 use mc::*;
 use plane::*;
 use predict::*;
 use util::*;
+use warp::*;
 
 pub const NONE_FRAME: usize = 8;
 pub const INTRA_FRAME: usize = 0;
@@ -73,6 +75,56 @@ pub enum PartitionType {
   PARTITION_INVALID
 }
 
+impl PartitionType {
+  /// Whether the partition is one of the extended (three or four way) ones,
+  /// whose blocks are not partitioned any further
+  pub fn is_ext(self) -> bool {
+    self >= PartitionType::PARTITION_HORZ_A
+      && self < PartitionType::PARTITION_INVALID
+  }
+
+  /// Offsets and sizes of the blocks a `bsize` block at `bo` is partitioned
+  /// into, in coding order (spec 5.11.4)
+  pub fn sub_blocks(
+    self, bo: &BlockOffset, bsize: BlockSize
+  ) -> Vec<(BlockOffset, BlockSize)> {
+    use self::PartitionType::*;
+
+    let subsize = bsize.subsize(self);
+    let split_size = bsize.subsize(PARTITION_SPLIT);
+    let hbs = bsize.width_mi() / 2;
+    let qbs = hbs / 2;
+    let at = |x: usize, y: usize, bsize: BlockSize| {
+      (BlockOffset { x: bo.x + x, y: bo.y + y }, bsize)
+    };
+
+    match self {
+      PARTITION_NONE => vec![at(0, 0, bsize)],
+      PARTITION_HORZ => vec![at(0, 0, subsize), at(0, hbs, subsize)],
+      PARTITION_VERT => vec![at(0, 0, subsize), at(hbs, 0, subsize)],
+      PARTITION_SPLIT => vec![
+        at(0, 0, subsize), at(hbs, 0, subsize),
+        at(0, hbs, subsize), at(hbs, hbs, subsize)
+      ],
+      PARTITION_HORZ_A => vec![
+        at(0, 0, split_size), at(hbs, 0, split_size), at(0, hbs, subsize)
+      ],
+      PARTITION_HORZ_B => vec![
+        at(0, 0, subsize), at(0, hbs, split_size), at(hbs, hbs, split_size)
+      ],
+      PARTITION_VERT_A => vec![
+        at(0, 0, split_size), at(0, hbs, split_size), at(hbs, 0, subsize)
+      ],
+      PARTITION_VERT_B => vec![
+        at(0, 0, subsize), at(hbs, 0, split_size), at(hbs, hbs, split_size)
+      ],
+      PARTITION_HORZ_4 => (0..4).map(|i| at(0, i * qbs, subsize)).collect(),
+      PARTITION_VERT_4 => (0..4).map(|i| at(i * qbs, 0, subsize)).collect(),
+      PARTITION_INVALID => unreachable!()
+    }
+  }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, PartialOrd, Ord, Eq)]
 pub enum BlockSize {
   BLOCK_4X4,
@@ -138,8 +190,21 @@ impl BlockSize {
   }
 
   pub fn cfl_allowed(self) -> bool {
-    // TODO: fix me when enabling EXT_PARTITION_TYPES
-    self <= BlockSize::BLOCK_32X32
+    self.width() <= 32 && self.height() <= 32
+  }
+
+  /// Whether inter-intra prediction can be used at the block size
+  pub fn interintra_allowed(self) -> bool {
+    self >= BLOCK_8X8 && self <= BLOCK_32X32
+  }
+
+  /// Whether wedge masks exist for the block size (Wedge_Bits > 0)
+  pub fn wedge_allowed(self) -> bool {
+    match self {
+      BLOCK_8X8 | BLOCK_8X16 | BLOCK_16X8 | BLOCK_16X16 | BLOCK_16X32
+      | BLOCK_32X16 | BLOCK_32X32 | BLOCK_8X32 | BLOCK_32X8 => true,
+      _ => false
+    }
   }
 
   pub fn width(self) -> usize {
@@ -191,6 +256,18 @@ impl BlockSize {
     }
   }
 
+  /// Number of times the largest transform of the block can be split down
+  /// to 4x4 (spec Max_Tx_Depth)
+  pub fn max_tx_depth(self) -> usize {
+    let mut tx_size = self.tx_size();
+    let mut depth = 0;
+    while tx_size != TX_4X4 {
+      tx_size = tx_size.split();
+      depth += 1;
+    }
+    depth
+  }
+
   pub fn largest_uv_tx_size(self, chroma_sampling: ChromaSampling) -> TxSize {
     match chroma_sampling {
       ChromaSampling::Cs444 => match self {
@@ -612,6 +689,89 @@ impl TxSize {
       _ => unreachable!()
     }
   }
+
+  /// Size of the transforms a transform is split into (spec Split_Tx_Size)
+  pub fn split(self) -> TxSize {
+    match self {
+      TX_4X4 | TX_8X8 | TX_4X8 | TX_8X4 => TX_4X4,
+      TX_16X16 | TX_8X16 | TX_16X8 => TX_8X8,
+      TX_32X32 | TX_16X32 | TX_32X16 => TX_16X16,
+      TX_64X64 | TX_32X64 | TX_64X32 => TX_32X32,
+      TX_4X16 => TX_4X8,
+      TX_16X4 => TX_8X4,
+      TX_8X32 => TX_8X16,
+      TX_32X8 => TX_16X8,
+      TX_16X64 => TX_16X32,
+      TX_64X16 => TX_32X16
+    }
+  }
+
+  /// Offsets in 4x4 units of the transforms this transform is split into,
+  /// in coding order
+  pub fn split_offsets(self) -> Vec<(usize, usize)> {
+    let sub = self.split();
+    let cols = self.width_mi() / sub.width_mi();
+    let rows = self.height_mi() / sub.height_mi();
+    (0..rows).flat_map(|y| {
+      (0..cols).map(move |x| (x * sub.width_mi(), y * sub.height_mi()))
+    }).collect()
+  }
+}
+
+/// Deepest split of the transform trees of inter blocks (spec
+/// MAX_VARTX_DEPTH)
+pub const MAX_VARTX_DEPTH: usize = 2;
+
+/// Transform partitioning of a non-skipped inter block when the transform
+/// size is selected per block (spec 5.11.17): the largest transforms of the
+/// block, taken in raster order, are the roots of trees whose nodes may be
+/// split into smaller transforms up to `MAX_VARTX_DEPTH` times.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct TxPartition {
+  /// Split flags of the root and its up to four children, for each root
+  splits: u32
+}
+
+impl TxPartition {
+  const NODES: usize = 5;
+
+  /// Whether `node` of the tree at `root` is split, node 0 being the root
+  /// and nodes 1 and above its children in coding order
+  pub fn is_split(self, root: usize, node: usize) -> bool {
+    self.splits & (1 << (root * Self::NODES + node)) != 0
+  }
+
+  pub fn set_split(&mut self, root: usize, node: usize, split: bool) {
+    let bit = 1 << (root * Self::NODES + node);
+    if split {
+      self.splits |= bit;
+    } else {
+      self.splits &= !bit;
+    }
+  }
+
+  /// Transform blocks of the tree at `root`, whose transform is
+  /// `root_size`, as offsets in 4x4 units from the root and sizes in coding
+  /// order
+  pub fn tx_blocks(
+    self, root: usize, root_size: TxSize
+  ) -> Vec<(usize, usize, TxSize)> {
+    if !self.is_split(root, 0) {
+      return vec![(0, 0, root_size)];
+    }
+    let sub = root_size.split();
+    let mut blocks = Vec::new();
+    for (i, (x, y)) in root_size.split_offsets().into_iter().enumerate() {
+      if self.is_split(root, i + 1) {
+        for (dx, dy) in sub.split_offsets() {
+          blocks.push((x + dx, y + dy, sub.split()));
+        }
+      } else {
+        blocks.push((x, y, sub));
+      }
+    }
+    blocks
+  }
 }
 
 pub const TX_TYPES: usize = 16;
@@ -678,14 +838,86 @@ pub enum InterIntraMode {
   II_SMOOTH_PRED,
   INTERINTRA_MODES
 }
+
+impl InterIntraMode {
+  pub fn intra_mode(self) -> PredictionMode {
+    match self {
+      InterIntraMode::II_DC_PRED => PredictionMode::DC_PRED,
+      InterIntraMode::II_V_PRED => PredictionMode::V_PRED,
+      InterIntraMode::II_H_PRED => PredictionMode::H_PRED,
+      InterIntraMode::II_SMOOTH_PRED => PredictionMode::SMOOTH_PRED,
+      InterIntraMode::INTERINTRA_MODES => unreachable!()
+    }
+  }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, PartialOrd)]
 pub enum CompoundType {
-  COMPOUND_AVERAGE,
   COMPOUND_WEDGE,
   COMPOUND_DIFFWTD,
+  COMPOUND_AVERAGE,
+  COMPOUND_INTRA,
+  COMPOUND_DISTANCE,
   COMPOUND_TYPES,
 }
 
+pub const MASKED_COMPOUND_TYPES: usize = 2;
+
+/// Compound prediction settings of a block (spec 5.11.25)
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CompoundParams {
+  pub compound_type: CompoundType,
+  pub wedge_index: usize,
+  pub wedge_sign: bool,
+  pub mask_type: bool,
+  pub interintra: bool,
+  pub interintra_mode: InterIntraMode
+}
+
+impl CompoundParams {
+  pub fn new() -> CompoundParams {
+    CompoundParams {
+      compound_type: CompoundType::COMPOUND_AVERAGE,
+      wedge_index: 0,
+      wedge_sign: false,
+      mask_type: false,
+      interintra: false,
+      interintra_mode: InterIntraMode::II_DC_PRED
+    }
+  }
+
+  /// Inter-intra prediction of a single reference block, smoothly blended
+  /// or with the wedge `wedge_index`
+  pub fn new_interintra(
+    interintra_mode: InterIntraMode, wedge_index: Option<usize>
+  ) -> CompoundParams {
+    CompoundParams {
+      compound_type: if wedge_index.is_some() {
+        CompoundType::COMPOUND_WEDGE
+      } else {
+        CompoundType::COMPOUND_INTRA
+      },
+      wedge_index: wedge_index.unwrap_or(0),
+      interintra: true,
+      interintra_mode,
+      ..CompoundParams::new()
+    }
+  }
+
+  /// comp_group_idx: whether the prediction is masked
+  pub fn comp_group_idx(self) -> usize {
+    match self.compound_type {
+      CompoundType::COMPOUND_WEDGE | CompoundType::COMPOUND_DIFFWTD => 1,
+      _ => 0
+    }
+  }
+
+  /// compound_idx: whether an unmasked prediction is a plain average
+  pub fn compound_idx(self) -> usize {
+    (self.compound_type != CompoundType::COMPOUND_DISTANCE) as usize
+  }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, PartialOrd)]
 pub enum MotionMode {
   SIMPLE_TRANSLATION,
@@ -729,7 +961,7 @@ pub enum FilterIntraMode {
   FILTER_INTRA_MODES
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug)]
 pub struct MotionVector {
   pub row: i16,
   pub col: i16
@@ -749,7 +981,10 @@ pub const REFMV_CTX_MASK: usize = ((1 << (8 - REFMV_OFFSET)) - 1);
 
 pub static RAV1E_PARTITION_TYPES: &'static [PartitionType] =
   &[PartitionType::PARTITION_NONE, PartitionType::PARTITION_HORZ,
-    PartitionType::PARTITION_VERT, PartitionType::PARTITION_SPLIT];
+    PartitionType::PARTITION_VERT, PartitionType::PARTITION_HORZ_A,
+    PartitionType::PARTITION_HORZ_B, PartitionType::PARTITION_VERT_A,
+    PartitionType::PARTITION_VERT_B, PartitionType::PARTITION_HORZ_4,
+    PartitionType::PARTITION_VERT_4, PartitionType::PARTITION_SPLIT];
 
 pub static RAV1E_TX_TYPES: &'static [TxType] = &[
   TxType::DCT_DCT,
@@ -794,6 +1029,9 @@ pub fn get_intra_edges<'a>(
   p: usize,
   frame_w_in_b: usize,
   frame_h_in_b: usize,
+  sb_size: BlockSize,
+  plane_bsize: BlockSize,
+  partition: PartitionType,
   opt_mode: Option<PredictionMode>
 ) -> AlignedArray<[u16; 4 * MAX_TX_SIZE + 1]> {
 
@@ -874,20 +1112,11 @@ pub fn get_intra_edges<'a>(
       }
     }
 
-    // Needs top right
+    // Needs top right, of which at most the width of the transform block
+    // is available (spec 7.11.2 aboveLimit)
     if needs_topright {
-      let bo = if p == 0 {
-        BlockOffset { x: x as usize / 4, y: y as usize / 4 }
-      } else {
-        BlockOffset { x: x as usize / 2, y: y as usize / 2 }
-      };
-      let bsize = if p == 0 {
-        BlockSize::from_width_and_height(tx_size.width(), tx_size.height())
-      } else {
-        BlockSize::from_width_and_height(2*tx_size.width(), 2*tx_size.height())
-      };
-      let num_avail = if y != 0 && has_tr(&bo, bsize) {
-        tx_size.height().min((if p == 0 { MI_SIZE } else { MI_SIZE / 2 }) * frame_w_in_b - x as usize - tx_size.width())
+      let num_avail = if y != 0 && tx_has_tr(dst, tx_size, plane_bsize, sb_size, partition) {
+        tx_size.width().min(tx_size.height()).min((if p == 0 { MI_SIZE } else { MI_SIZE / 2 }) * frame_w_in_b - x as usize - tx_size.width())
       } else {
         0
       };
@@ -903,20 +1132,11 @@ pub fn get_intra_edges<'a>(
       }
     }
 
-    // Needs bottom left
+    // Needs bottom left, of which at most the height of the transform block
+    // is available (spec 7.11.2 leftLimit)
     if needs_bottomleft {
-      let bo = if p == 0 {
-        BlockOffset { x: x as usize / 4, y: y as usize / 4 }
-      } else {
-        BlockOffset { x: x as usize / 2, y: y as usize / 2 }
-      };
-      let bsize = if p == 0 {
-        BlockSize::from_width_and_height(tx_size.width(), tx_size.height())
-      } else {
-        BlockSize::from_width_and_height(2*tx_size.width(), 2*tx_size.height())
-      };
-      let num_avail = if x != 0 && has_bl(&bo, bsize) {
-        tx_size.width().min((if p == 0 { MI_SIZE } else { MI_SIZE / 2 }) * frame_h_in_b - y as usize - tx_size.height())
+      let num_avail = if x != 0 && tx_has_bl(dst, tx_size, plane_bsize, sb_size, partition) {
+        tx_size.height().min(tx_size.width()).min((if p == 0 { MI_SIZE } else { MI_SIZE / 2 }) * frame_h_in_b - y as usize - tx_size.height())
       } else {
         0
       };
@@ -1101,13 +1321,25 @@ impl PredictionMode {
   pub fn predict_inter<'a>(
     self, fi: &FrameInvariants, p: usize, po: &PlaneOffset,
     dst: &'a mut PlaneMutSlice<'a>, width: usize, height: usize,
-    ref_frames: [usize; 2], mvs: [MotionVector; 2]
+    ref_frames: [usize; 2], mvs: [MotionVector; 2],
+    interp_filter: [FilterMode; 2], compound: CompoundParams,
+    seg_mask: &mut [u8], local_warp: Option<WarpParams>
   ) {
     assert!(!self.is_intra());
 
-    let mode = FilterMode::REGULAR;
+    // IntraBC copies from the current frame, reconstructed into dst
+    if ref_frames[0] == INTRA_FRAME {
+      predict_intrabc(dst, width, height, fi.width, fi.height, mvs[0], fi.sequence.bit_depth);
+      return;
+    }
+
+    // Filters are given in the vertical, horizontal order of the syntax
+    let mode_x = interp_filter[1];
+    let mode_y = interp_filter[0];
     let is_compound =
       ref_frames[1] > INTRA_FRAME && ref_frames[1] != NONE_FRAME;
+    let is_global = self == PredictionMode::GLOBALMV
+      || self == PredictionMode::GLOBAL_GLOBALMV;
 
     fn get_params<'a>(
       rec_plane: &'a Plane, po: &PlaneOffset, mv: MotionVector
@@ -1128,13 +1360,97 @@ impl PredictionMode {
       (row_frac, col_frac, rec_plane.slice(&qo).clamp().subslice(3, 3))
     };
 
+    fn get_last_sample(rec: &ReferenceFrame, rec_plane: &Plane) -> (usize, usize) {
+      let xdec = rec_plane.cfg.xdec;
+      let ydec = rec_plane.cfg.ydec;
+      (((rec.width + xdec) >> xdec) - 1, ((rec.height + ydec) >> ydec) - 1)
+    }
+
+    // Motion vector scaling process (spec 7.11.3.3), for references coded
+    // at a different size than the current frame.
+    fn get_scaled_position(
+      fi: &FrameInvariants, rec: &ReferenceFrame, rec_plane: &Plane,
+      po: &PlaneOffset, mv: MotionVector
+    ) -> Option<ScaledPosition> {
+      const REF_SCALE_SHIFT: usize = 14;
+      const SUBPEL_BITS: usize = 4;
+
+      let x_scale =
+        (((rec.width << REF_SCALE_SHIFT) + fi.width / 2) / fi.width) as i64;
+      let y_scale =
+        (((rec.height << REF_SCALE_SHIFT) + fi.height / 2) / fi.height) as i64;
+      if x_scale == 1 << REF_SCALE_SHIFT && y_scale == 1 << REF_SCALE_SHIFT {
+        return None;
+      }
+
+      let xdec = rec_plane.cfg.xdec;
+      let ydec = rec_plane.cfg.ydec;
+      let half_sample = 1 << (SUBPEL_BITS - 1);
+      let orig_x = ((po.x as i64) << SUBPEL_BITS)
+        + ((2 * mv.col as i64) >> xdec)
+        + half_sample;
+      let orig_y = ((po.y as i64) << SUBPEL_BITS)
+        + ((2 * mv.row as i64) >> ydec)
+        + half_sample;
+      let base_x = orig_x * x_scale - (half_sample << REF_SCALE_SHIFT);
+      let base_y = orig_y * y_scale - (half_sample << REF_SCALE_SHIFT);
+      let (last_x, last_y) = get_last_sample(rec, rec_plane);
+      let off = (1 << (SCALE_SUBPEL_BITS - SUBPEL_BITS)) / 2;
+      let shift = REF_SCALE_SHIFT + SUBPEL_BITS - SCALE_SUBPEL_BITS;
+      Some(ScaledPosition {
+        start_x: (round2signed(base_x, shift) + off) as i32,
+        start_y: (round2signed(base_y, shift) + off) as i32,
+        x_step: round2signed(x_scale, REF_SCALE_SHIFT - SCALE_SUBPEL_BITS)
+          as i32,
+        y_step: round2signed(y_scale, REF_SCALE_SHIFT - SCALE_SUBPEL_BITS)
+          as i32,
+        last_x,
+        last_y
+      })
+    }
+
     if !is_compound {
       match fi.rec_buffer.frames
         [fi.ref_frames[ref_frames[0] - LAST_FRAME] as usize]
       {
         Some(ref rec) => {
-          let (row_frac, col_frac, src) =
-            get_params(&rec.frame.planes[p], po, mvs[0]);
+          let rec_plane = &rec.frame.planes[p];
+          if let Some(pos) = get_scaled_position(fi, rec, rec_plane, po, mvs[0])
+          {
+            put_8tap_scaled(
+              dst,
+              rec_plane,
+              &pos,
+              width,
+              height,
+              fi.sequence.bit_depth,
+              mode_x,
+              mode_y
+            );
+            return;
+          }
+          let warp = if is_global {
+            global_warp(fi, ref_frames[0], width, height)
+          } else {
+            None
+          }
+          .or_else(|| local_warp.filter(|_| width >= 8 && height >= 8));
+          if let Some(wp) = warp {
+            let (last_x, last_y) = get_last_sample(rec, rec_plane);
+            put_warp(
+              dst,
+              rec_plane,
+              po,
+              width,
+              height,
+              last_x,
+              last_y,
+              &wp,
+              fi.sequence.bit_depth
+            );
+            return;
+          }
+          let (row_frac, col_frac, src) = get_params(rec_plane, po, mvs[0]);
           put_8tap(
             dst,
             src,
@@ -1143,7 +1459,8 @@ impl PredictionMode {
             col_frac,
             row_frac,
             fi.sequence.bit_depth,
-            mode
+            mode_x,
+            mode_y
           );
         }
         None => ()
@@ -1156,8 +1473,42 @@ impl PredictionMode {
           [fi.ref_frames[ref_frames[i] - LAST_FRAME] as usize]
         {
           Some(ref rec) => {
-            let (row_frac, col_frac, src) =
-              get_params(&rec.frame.planes[p], po, mvs[i]);
+            let rec_plane = &rec.frame.planes[p];
+            if let Some(pos) =
+              get_scaled_position(fi, rec, rec_plane, po, mvs[i])
+            {
+              prep_8tap_scaled(
+                &mut tmp[i].array,
+                rec_plane,
+                &pos,
+                width,
+                height,
+                fi.sequence.bit_depth,
+                mode_x,
+                mode_y
+              );
+              continue;
+            }
+            if let Some(wp) = if is_global {
+              global_warp(fi, ref_frames[i], width, height)
+            } else {
+              None
+            } {
+              let (last_x, last_y) = get_last_sample(rec, rec_plane);
+              prep_warp(
+                &mut tmp[i].array,
+                rec_plane,
+                po,
+                width,
+                height,
+                last_x,
+                last_y,
+                &wp,
+                fi.sequence.bit_depth
+              );
+              continue;
+            }
+            let (row_frac, col_frac, src) = get_params(rec_plane, po, mvs[i]);
             prep_8tap(
               &mut tmp[i].array,
               src,
@@ -1166,20 +1517,395 @@ impl PredictionMode {
               col_frac,
               row_frac,
               fi.sequence.bit_depth,
-              mode
+              mode_x,
+              mode_y
             );
           }
           None => ()
         }
       }
-      mc_avg(
-        dst,
-        &tmp[0].array,
-        &tmp[1].array,
-        width,
-        height,
-        fi.sequence.bit_depth
-      );
+      let bit_depth = fi.sequence.bit_depth;
+      match compound.compound_type {
+        CompoundType::COMPOUND_DISTANCE => {
+          let (fwd, bck) = get_dist_wtd_weights(fi, ref_frames);
+          debug_assert!(fwd + bck == 16);
+          mc_w_avg(
+            dst,
+            &tmp[0].array,
+            &tmp[1].array,
+            width,
+            height,
+            bit_depth,
+            fwd
+          );
+        }
+        CompoundType::COMPOUND_WEDGE | CompoundType::COMPOUND_DIFFWTD => {
+          let PlaneConfig { xdec, ydec, .. } = dst.plane.cfg;
+          let luma_mask = if compound.compound_type
+            == CompoundType::COMPOUND_WEDGE
+          {
+            let bsize =
+              BlockSize::from_width_and_height(width << xdec, height << ydec);
+            get_wedge_mask(bsize, compound.wedge_sign, compound.wedge_index)
+          } else {
+            // The difference mask is derived from the luma predictions and
+            // reused by the chroma planes
+            if p == 0 {
+              diffwtd_mask(
+                seg_mask,
+                &tmp[0].array,
+                &tmp[1].array,
+                width,
+                height,
+                bit_depth,
+                compound.mask_type
+              );
+            }
+            seg_mask[..(width << xdec) * (height << ydec)].to_vec()
+          };
+          let mask = if p == 0 {
+            luma_mask
+          } else {
+            subsample_mask(&luma_mask, width, height, xdec, ydec)
+          };
+          mc_mask(
+            dst,
+            &tmp[0].array,
+            &tmp[1].array,
+            width,
+            height,
+            bit_depth,
+            &mask
+          );
+        }
+        _ => {
+          mc_avg(
+            dst,
+            &tmp[0].array,
+            &tmp[1].array,
+            width,
+            height,
+            bit_depth
+          );
+        }
+      }
+    }
+  }
+}
+
+static OBMC_MASK_2: [u8; 2] = [45, 64];
+static OBMC_MASK_4: [u8; 4] = [39, 50, 59, 64];
+static OBMC_MASK_8: [u8; 8] = [36, 42, 48, 53, 57, 61, 64, 64];
+static OBMC_MASK_16: [u8; 16] =
+  [34, 37, 40, 43, 46, 49, 52, 54, 56, 58, 60, 61, 64, 64, 64, 64];
+static OBMC_MASK_32: [u8; 32] = [
+  33, 35, 36, 38, 40, 41, 43, 44, 45, 47, 48, 50, 51, 52, 53, 55, 56, 57, 58,
+  59, 60, 60, 61, 62, 64, 64, 64, 64, 64, 64, 64, 64
+];
+
+fn get_obmc_mask(len: usize) -> &'static [u8] {
+  match len {
+    2 => &OBMC_MASK_2,
+    4 => &OBMC_MASK_4,
+    8 => &OBMC_MASK_8,
+    16 => &OBMC_MASK_16,
+    32 => &OBMC_MASK_32,
+    _ => unreachable!()
+  }
+}
+
+const MASK_MASTER_SIZE: usize = 64;
+
+static WEDGE_MASTER_OBLIQUE_ODD: [u8; MASK_MASTER_SIZE] = [
+  0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+  0, 0, 0, 1, 2, 6, 18, 37, 53, 60, 63, 64, 64, 64, 64, 64, 64, 64, 64, 64,
+  64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64
+];
+static WEDGE_MASTER_OBLIQUE_EVEN: [u8; MASK_MASTER_SIZE] = [
+  0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+  0, 0, 0, 1, 4, 11, 27, 46, 58, 62, 63, 64, 64, 64, 64, 64, 64, 64, 64, 64,
+  64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64
+];
+static WEDGE_MASTER_VERTICAL: [u8; MASK_MASTER_SIZE] = [
+  0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+  0, 0, 0, 0, 2, 7, 21, 43, 57, 62, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64,
+  64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64
+];
+
+#[derive(Copy, Clone)]
+enum WedgeDirection {
+  Horizontal,
+  Vertical,
+  Oblique27,
+  Oblique63,
+  Oblique117,
+  Oblique153
+}
+
+use self::WedgeDirection::*;
+
+// Direction and x, y offsets in eighths of the block of each wedge, for
+// blocks taller than, wider than and as wide as they are tall
+static WEDGE_CODEBOOK_HGTW: [(WedgeDirection, usize, usize); 16] = [
+  (Oblique27, 4, 4), (Oblique63, 4, 4), (Oblique117, 4, 4),
+  (Oblique153, 4, 4), (Horizontal, 4, 2), (Horizontal, 4, 4),
+  (Horizontal, 4, 6), (Vertical, 4, 4), (Oblique27, 4, 2),
+  (Oblique27, 4, 6), (Oblique153, 4, 2), (Oblique153, 4, 6),
+  (Oblique63, 2, 4), (Oblique63, 6, 4), (Oblique117, 2, 4),
+  (Oblique117, 6, 4)
+];
+static WEDGE_CODEBOOK_HLTW: [(WedgeDirection, usize, usize); 16] = [
+  (Oblique27, 4, 4), (Oblique63, 4, 4), (Oblique117, 4, 4),
+  (Oblique153, 4, 4), (Vertical, 2, 4), (Vertical, 4, 4),
+  (Vertical, 6, 4), (Horizontal, 4, 4), (Oblique27, 4, 2),
+  (Oblique27, 4, 6), (Oblique153, 4, 2), (Oblique153, 4, 6),
+  (Oblique63, 2, 4), (Oblique63, 6, 4), (Oblique117, 2, 4),
+  (Oblique117, 6, 4)
+];
+static WEDGE_CODEBOOK_HEQW: [(WedgeDirection, usize, usize); 16] = [
+  (Oblique27, 4, 4), (Oblique63, 4, 4), (Oblique117, 4, 4),
+  (Oblique153, 4, 4), (Horizontal, 4, 2), (Horizontal, 4, 6),
+  (Vertical, 2, 4), (Vertical, 6, 4), (Oblique27, 4, 2),
+  (Oblique27, 4, 6), (Oblique153, 4, 2), (Oblique153, 4, 6),
+  (Oblique63, 2, 4), (Oblique63, 6, 4), (Oblique117, 2, 4),
+  (Oblique117, 6, 4)
+];
+
+/// Sample of the 64x64 master mask of a wedge direction (spec 7.11.3.11)
+fn wedge_master(dir: WedgeDirection, y: usize, x: usize) -> u8 {
+  let oblique63 = |y: usize, x: usize| {
+    let shift = (MASK_MASTER_SIZE / 4) as isize - ((y + 1) / 2) as isize;
+    let i = (x as isize - shift).max(0).min(MASK_MASTER_SIZE as isize - 1);
+    if y & 1 == 0 {
+      WEDGE_MASTER_OBLIQUE_EVEN[i as usize]
+    } else {
+      WEDGE_MASTER_OBLIQUE_ODD[i as usize]
+    }
+  };
+  let last = MASK_MASTER_SIZE - 1;
+  match dir {
+    Vertical => WEDGE_MASTER_VERTICAL[x],
+    Horizontal => WEDGE_MASTER_VERTICAL[y],
+    Oblique63 => oblique63(y, x),
+    Oblique27 => oblique63(x, y),
+    Oblique117 => 64 - oblique63(y, last - x),
+    Oblique153 => 64 - oblique63(x, last - y)
+  }
+}
+
+/// Wedge mask of a `bsize` block, weighting the first prediction
+pub fn get_wedge_mask(bsize: BlockSize, sign: bool, index: usize) -> Vec<u8> {
+  let w = bsize.width();
+  let h = bsize.height();
+  let codebook = if h > w {
+    &WEDGE_CODEBOOK_HGTW
+  } else if h < w {
+    &WEDGE_CODEBOOK_HLTW
+  } else {
+    &WEDGE_CODEBOOK_HEQW
+  };
+  let (dir, x_offset, y_offset) = codebook[index];
+  let xoff = MASK_MASTER_SIZE / 2 - ((x_offset * w) >> 3);
+  let yoff = MASK_MASTER_SIZE / 2 - ((y_offset * h) >> 3);
+
+  // The sign is relative to the average of the mask along its top and left
+  // edges
+  let sum = (0..w).map(|i| wedge_master(dir, yoff, xoff + i) as usize).sum::<usize>()
+    + (1..h).map(|i| wedge_master(dir, yoff + i, xoff) as usize).sum::<usize>();
+  let avg = (sum + (w + h - 1) / 2) / (w + h - 1);
+  let flip_sign = avg < 32;
+
+  let mut mask = Vec::with_capacity(w * h);
+  for y in 0..h {
+    for x in 0..w {
+      let m = wedge_master(dir, yoff + y, xoff + x);
+      mask.push(if sign == flip_sign { m } else { 64 - m });
+    }
+  }
+  mask
+}
+
+/// Subsamples a luma compound mask for a chroma plane (spec 7.11.3.14)
+fn subsample_mask(
+  mask: &[u8], width: usize, height: usize, xdec: usize, ydec: usize
+) -> Vec<u8> {
+  let luma_width = width << xdec;
+  let mut out = Vec::with_capacity(width * height);
+  for y in 0..height {
+    for x in 0..width {
+      let mut sum = 0;
+      for dy in 0..1 << ydec {
+        for dx in 0..1 << xdec {
+          sum += mask[((y << ydec) + dy) * luma_width + (x << xdec) + dx] as i32;
+        }
+      }
+      out.push(round_shift(sum, xdec + ydec) as u8);
+    }
+  }
+  out
+}
+
+static II_WEIGHTS_1D: [u8; 32] = [
+  60, 52, 45, 39, 34, 30, 26, 22, 19, 17, 15, 13, 11, 10, 8, 7, 6, 6, 5, 4, 4,
+  3, 3, 2, 2, 2, 2, 1, 1, 1, 1, 1
+];
+
+/// Intra mode variant mask process (spec 7.11.3.13): the weights of the
+/// intra prediction of a smooth inter-intra block
+fn get_interintra_mask(
+  mode: InterIntraMode, width: usize, height: usize
+) -> Vec<u8> {
+  let scale = 32 / width.max(height);
+  let mut mask = Vec::with_capacity(width * height);
+  for y in 0..height {
+    for x in 0..width {
+      mask.push(match mode {
+        InterIntraMode::II_V_PRED => II_WEIGHTS_1D[y * scale],
+        InterIntraMode::II_H_PRED => II_WEIGHTS_1D[x * scale],
+        InterIntraMode::II_SMOOTH_PRED => II_WEIGHTS_1D[x.min(y) * scale],
+        _ => 32
+      });
+    }
+  }
+  mask
+}
+
+pub const MAX_FRAME_DISTANCE: i32 = 31;
+
+/// Distance weights process (spec 7.11.3.15): the weights, out of 16, of
+/// the two predictions of a `COMPOUND_DISTANCE` block.
+fn get_dist_wtd_weights(
+  fi: &FrameInvariants, ref_frames: [usize; 2]
+) -> (i32, i32) {
+  const QUANT_DIST_WEIGHT: [[i32; 2]; 3] = [[2, 3], [2, 5], [2, 7]];
+  const QUANT_DIST_LOOKUP: [[i32; 2]; 4] = [[9, 7], [11, 5], [12, 4], [13, 3]];
+
+  let d0 = fi.ref_frame_dist(ref_frames[1]).min(MAX_FRAME_DISTANCE);
+  let d1 = fi.ref_frame_dist(ref_frames[0]).min(MAX_FRAME_DISTANCE);
+  let order = (d0 <= d1) as usize;
+  let i = if d0 == 0 || d1 == 0 {
+    3
+  } else {
+    (0..3)
+      .find(|&i| {
+        let d0_c0 = d0 * QUANT_DIST_WEIGHT[i][order];
+        let d1_c1 = d1 * QUANT_DIST_WEIGHT[i][1 - order];
+        if order == 1 { d0_c0 > d1_c1 } else { d0_c0 < d1_c1 }
+      })
+      .unwrap_or(3)
+  };
+  (QUANT_DIST_LOOKUP[i][order], QUANT_DIST_LOOKUP[i][1 - order])
+}
+
+/// Overlapped motion compensation (spec 7.11.3.10): blends the prediction
+/// of a `bsize` block at `po` with predictions made from the motion of the
+/// `above` and then the `left` neighbours found by
+/// `BlockContext::overlappable_candidates`.
+pub fn predict_overlapped(
+  fi: &FrameInvariants, p: usize, po: &PlaneOffset, rec: &mut Plane,
+  bsize: BlockSize, above: &[(usize, usize, &Block)],
+  left: &[(usize, usize, &Block)]
+) {
+  let PlaneConfig { xdec, ydec, .. } = rec.cfg;
+  let width = bsize.width();
+  let height = bsize.height();
+
+  let mut blend = |offset: usize, len: usize, cand: &Block, is_above: bool| {
+    let (opo, w, h) = if is_above {
+      let x = ((offset << MI_SIZE_LOG2) >> xdec) as isize;
+      let opo = PlaneOffset { x: po.x + x, y: po.y };
+      (opo, (len << MI_SIZE_LOG2) >> xdec, height.min(64 >> ydec) >> 1)
+    } else {
+      let y = ((offset << MI_SIZE_LOG2) >> ydec) as isize;
+      let opo = PlaneOffset { x: po.x, y: po.y + y };
+      (opo, width.min(64 >> xdec) >> 1, (len << MI_SIZE_LOG2) >> ydec)
+    };
+    let mut pred = Plane::new(w, h, xdec, ydec, 0, 0);
+    PredictionMode::NEWMV.predict_inter(
+      fi, p, &opo, &mut pred.mut_slice(&PlaneOffset { x: 0, y: 0 }), w, h,
+      [cand.ref_frames[0], NONE_FRAME],
+      [cand.mv[0], MotionVector { row: 0, col: 0 }], cand.interp_filter,
+      CompoundParams::new(), &mut [], None
+    );
+    let mask = get_obmc_mask(if is_above { h } else { w });
+    let pred_slice = pred.slice(&PlaneOffset { x: 0, y: 0 });
+    let pred_stride = pred.cfg.stride;
+    let pred_data = pred_slice.as_slice();
+    let mut dst = rec.mut_slice(&opo);
+    let dst_stride = dst.plane.cfg.stride;
+    let dst_data = dst.as_mut_slice();
+    for r in 0..h {
+      for c in 0..w {
+        let m = mask[if is_above { r } else { c }] as u32;
+        let cur = &mut dst_data[r * dst_stride + c];
+        let obmc = pred_data[r * pred_stride + c] as u32;
+        *cur = ((*cur as u32 * m + obmc * (64 - m) + 32) >> 6) as u16;
+      }
+    }
+  };
+
+  // 4x4, 4x8 and 8x4 plane blocks are not blended with the above neighbours
+  if bsize >= BlockSize::BLOCK_8X8 {
+    for &(offset, len, cand) in above {
+      blend(offset, len, cand, true);
+    }
+  }
+  for &(offset, len, cand) in left {
+    blend(offset, len, cand, false);
+  }
+}
+
+/// Inter-intra prediction (spec 7.11.3.14): blends the inter prediction of
+/// a `bsize` block already in `rec` at `po` with an intra prediction made
+/// from the `interintra_mode` of `compound`.
+pub fn predict_interintra(
+  fi: &FrameInvariants, p: usize, po: &PlaneOffset, rec: &mut Plane,
+  bsize: BlockSize, compound: CompoundParams
+) {
+  let PlaneConfig { xdec, ydec, stride, .. } = rec.cfg;
+  let plane_bsize =
+    if p == 0 { bsize } else { get_plane_block_size(bsize, xdec, ydec) };
+  let width = plane_bsize.width();
+  let height = plane_bsize.height();
+  let tx_size = plane_bsize.tx_size();
+  let bit_depth = fi.sequence.bit_depth;
+
+  let inter: Vec<u16> = {
+    let slice = rec.slice(po);
+    let data = slice.as_slice();
+    (0..height)
+      .flat_map(|r| data[r * stride..r * stride + width].to_vec())
+      .collect()
+  };
+
+  let mode = compound.interintra_mode.intra_mode();
+  let edge_buf = get_intra_edges(
+    &rec.slice(po), tx_size, bit_depth, p, fi.w_in_b, fi.h_in_b,
+    fi.sequence.sb_size(), plane_bsize, PartitionType::PARTITION_NONE, Some(mode)
+  );
+  mode.predict_intra(
+    &mut rec.mut_slice(po), tx_size, bit_depth, &[0i16; 2], 0, &edge_buf
+  );
+
+  let mask = if compound.compound_type == CompoundType::COMPOUND_WEDGE {
+    let mask = get_wedge_mask(bsize, false, compound.wedge_index);
+    if p == 0 {
+      mask
+    } else {
+      subsample_mask(&mask, width, height, xdec, ydec)
+    }
+  } else {
+    get_interintra_mask(compound.interintra_mode, width, height)
+  };
+
+  let mut dst = rec.mut_slice(po);
+  let dst_data = dst.as_mut_slice();
+  for r in 0..height {
+    for c in 0..width {
+      let m = mask[r * width + c] as u32;
+      let cur = &mut dst_data[r * stride + c];
+      let inter = inter[r * width + c] as u32;
+      *cur = ((*cur as u32 * m + inter * (64 - m) + 32) >> 6) as u16;
     }
   }
 }
@@ -1208,10 +1934,182 @@ pub enum TxSet {
   TX_SET_ALL16
 }
 
-pub fn has_tr(bo: &BlockOffset, bsize: BlockSize) -> bool {
-  let sb_mi_size = BLOCK_64X64.width_mi(); /* Assume 64x64 for now */
-  let mask_row = bo.y & LOCAL_BLOCK_MASK;
-  let mask_col = bo.x & LOCAL_BLOCK_MASK;
+/// Position of the transform block at `dst` inside its `plane_bsize` block,
+/// in samples of the plane, and the 64x64 luma chunks the block is coded in
+struct TxPosition {
+  col_off: usize,
+  row_off: usize,
+  block_w: usize,
+  block_h: usize,
+  chunk_w: usize,
+  chunk_h: usize,
+  xdec: usize,
+  ydec: usize
+}
+
+impl TxPosition {
+  fn new(dst: &PlaneSlice, plane_bsize: BlockSize) -> TxPosition {
+    let PlaneConfig { xdec, ydec, .. } = dst.plane.cfg;
+    let (x, y) = (dst.x as usize, dst.y as usize);
+    let (block_w, block_h) = (plane_bsize.width(), plane_bsize.height());
+    TxPosition {
+      col_off: x & (block_w - 1),
+      row_off: y & (block_h - 1),
+      block_w,
+      block_h,
+      chunk_w: BlockSize::BLOCK_64X64.width() >> xdec,
+      chunk_h: BlockSize::BLOCK_64X64.height() >> ydec,
+      xdec,
+      ydec
+    }
+  }
+
+  /// Whether the sample at (`col`, `row`) of the block is coded before the
+  /// transform block, the chunks of the block being coded in raster order
+  fn is_coded_before(&self, col: usize, row: usize) -> bool {
+    let chunk = |c: usize, r: usize| (r / self.chunk_h, c / self.chunk_w);
+    chunk(col, row) < chunk(self.col_off, self.row_off)
+      || (chunk(col, row) == chunk(self.col_off, self.row_off)
+        && row < self.row_off)
+  }
+
+  /// Offset and size in luma 4x4 units of the block
+  fn luma_block(&self, dst: &PlaneSlice) -> (BlockOffset, BlockSize) {
+    let x = (dst.x as usize - self.col_off) << self.xdec;
+    let y = (dst.y as usize - self.row_off) << self.ydec;
+    (
+      BlockOffset { x: x >> MI_SIZE_LOG2, y: y >> MI_SIZE_LOG2 },
+      BlockSize::from_width_and_height(
+        self.block_w << self.xdec, self.block_h << self.ydec
+      )
+    )
+  }
+}
+
+/// Whether the samples above and to the right of a transform block are
+/// available for intra prediction (spec 7.11.2 haveAboveRt).
+fn tx_has_tr(
+  dst: &PlaneSlice, tx_size: TxSize, plane_bsize: BlockSize, sb_size: BlockSize,
+  partition: PartitionType
+) -> bool {
+  let pos = TxPosition::new(dst, plane_bsize);
+  let col = pos.col_off + tx_size.width();
+  if pos.row_off > 0 {
+    col < pos.block_w && pos.is_coded_before(col, pos.row_off - 1)
+  } else if col < pos.block_w {
+    true
+  } else {
+    let (bo, bsize) = pos.luma_block(dst);
+    if bsize.width_mi().max(bsize.height_mi()) > BLOCK_64X64.width_mi() {
+      // Blocks larger than 64x64 have a top right when at the top of the
+      // superblock
+      bo.y & (sb_size.height_mi() - 1) == 0
+    } else {
+      has_tr(&bo, bsize, sb_size, partition)
+    }
+  }
+}
+
+/// Whether the samples below and to the left of a transform block are
+/// available for intra prediction (spec 7.11.2 haveBelowLft).
+fn tx_has_bl(
+  dst: &PlaneSlice, tx_size: TxSize, plane_bsize: BlockSize, sb_size: BlockSize,
+  partition: PartitionType
+) -> bool {
+  let pos = TxPosition::new(dst, plane_bsize);
+  let row = pos.row_off + tx_size.height();
+  if pos.col_off > 0 {
+    row < pos.block_h && pos.is_coded_before(pos.col_off - 1, row)
+  } else if row < pos.block_h {
+    true
+  } else {
+    let (bo, bsize) = pos.luma_block(dst);
+    has_bl(&bo, bsize, sb_size, partition)
+  }
+}
+
+/// The block split by an extended partition, given one of its blocks or
+/// the area of the blocks sharing a chroma block, along with all of its
+/// blocks and the index of the last one coded in the area
+fn ext_partition_parent(
+  bo: &BlockOffset, bsize: BlockSize, partition: PartitionType
+) -> (BlockOffset, BlockSize, Vec<(BlockOffset, BlockSize)>, usize) {
+  let w = bsize.width_mi();
+  let h = bsize.height_mi();
+  let size = match partition {
+    PartitionType::PARTITION_HORZ_4 => w,
+    PartitionType::PARTITION_VERT_4 => h,
+    _ if w == h => 2 * w,
+    _ => w.max(h)
+  };
+  let parent_bo = BlockOffset { x: bo.x & !(size - 1), y: bo.y & !(size - 1) };
+  let parent_bsize = BlockSize::from_width_and_height(
+    size << MI_SIZE_LOG2, size << MI_SIZE_LOG2
+  );
+  let blocks = partition.sub_blocks(&parent_bo, parent_bsize);
+  let last = ext_partition_index(&blocks, bo.x + w - 1, bo.y + h - 1);
+
+  (parent_bo, parent_bsize, blocks, last)
+}
+
+fn ext_partition_index(
+  blocks: &[(BlockOffset, BlockSize)], x: usize, y: usize
+) -> usize {
+  blocks.iter().position(|&(ref bo, bsize)| {
+    x >= bo.x && x < bo.x + bsize.width_mi()
+      && y >= bo.y && y < bo.y + bsize.height_mi()
+  }).unwrap()
+}
+
+/// Whether the block above and to the right of a block of an extended
+/// partition is decoded before it
+fn ext_partition_has_tr(
+  bo: &BlockOffset, bsize: BlockSize, sb_size: BlockSize,
+  partition: PartitionType
+) -> bool {
+  let (parent_bo, parent_bsize, blocks, last) =
+    ext_partition_parent(bo, bsize, partition);
+  let x = bo.x + bsize.width_mi();
+  let parent_right = parent_bo.x + parent_bsize.width_mi();
+
+  if bo.y == parent_bo.y {
+    // A 128x128 block is a whole superblock, whose top right is decoded
+    x < parent_right
+      || parent_bsize > BLOCK_64X64
+      || has_tr(&parent_bo, parent_bsize, sb_size, PartitionType::PARTITION_NONE)
+  } else {
+    x < parent_right && ext_partition_index(&blocks, x, bo.y - 1) < last
+  }
+}
+
+/// Whether the block below and to the left of a block of an extended
+/// partition is decoded before it
+fn ext_partition_has_bl(
+  bo: &BlockOffset, bsize: BlockSize, sb_size: BlockSize,
+  partition: PartitionType
+) -> bool {
+  let (parent_bo, parent_bsize, blocks, last) =
+    ext_partition_parent(bo, bsize, partition);
+  let y = bo.y + bsize.height_mi();
+  let parent_bottom = parent_bo.y + parent_bsize.height_mi();
+
+  if bo.x == parent_bo.x {
+    y < parent_bottom
+      || has_bl(&parent_bo, parent_bsize, sb_size, PartitionType::PARTITION_NONE)
+  } else {
+    y < parent_bottom && ext_partition_index(&blocks, bo.x - 1, y) < last
+  }
+}
+
+/// Whether the block above and to the right of a block is decoded before
+/// it. `partition` is the partition the block is coded in.
+pub fn has_tr(
+  bo: &BlockOffset, bsize: BlockSize, sb_size: BlockSize,
+  partition: PartitionType
+) -> bool {
+  let sb_mi_size = sb_size.width_mi();
+  let mask_row = bo.y & (sb_mi_size - 1);
+  let mask_col = bo.x & (sb_mi_size - 1);
   let target_n4_w = bsize.width_mi();
   let target_n4_h = bsize.height_mi();
 
@@ -1221,6 +2119,10 @@ pub fn has_tr(bo: &BlockOffset, bsize: BlockSize) -> bool {
     return false;
   }
 
+  if partition.is_ext() {
+    return ext_partition_has_tr(bo, bsize, sb_size, partition);
+  }
+
   let mut has_tr = !((mask_row & bs) != 0 && (mask_col & bs) != 0);
 
   /* TODO: assert its a power of two */
@@ -1249,26 +2151,18 @@ pub fn has_tr(bo: &BlockOffset, bsize: BlockSize) -> bool {
     has_tr = false;
   }
 
-  /* The bottom left square of a Vertical A (in the old format) does
-    * not have a top right as it is decoded before the right hand
-    * rectangle of the partition */
-/*
-  if blk.partition == PartitionType::PARTITION_VERT_A {
-    if blk.n4_w == blk.n4_h {
-      if (mask_row & bs) != 0 {
-        has_tr = false;
-      }
-    }
-  }
-*/
-
   has_tr
 }
 
-pub fn has_bl(bo: &BlockOffset, bsize: BlockSize) -> bool {
-  let sb_mi_size = BLOCK_64X64.width_mi(); /* Assume 64x64 for now */
-  let mask_row = bo.y & LOCAL_BLOCK_MASK;
-  let mask_col = bo.x & LOCAL_BLOCK_MASK;
+/// Whether the block below and to the left of a block is decoded before
+/// it. `partition` is the partition the block is coded in.
+pub fn has_bl(
+  bo: &BlockOffset, bsize: BlockSize, sb_size: BlockSize,
+  partition: PartitionType
+) -> bool {
+  let sb_mi_size = sb_size.width_mi();
+  let mask_row = bo.y & (sb_mi_size - 1);
+  let mask_col = bo.x & (sb_mi_size - 1);
   let target_n4_w = bsize.width_mi();
   let target_n4_h = bsize.height_mi();
 
@@ -1278,6 +2172,10 @@ pub fn has_bl(bo: &BlockOffset, bsize: BlockSize) -> bool {
     return false;
   }
 
+  if partition.is_ext() {
+    return ext_partition_has_bl(bo, bsize, sb_size, partition);
+  }
+
   let mut has_bl = (mask_row & bs) == 0 && (mask_col & bs) == 0 && bs < sb_mi_size;
 
   /* TODO: assert its a power of two */
@@ -1306,18 +2204,215 @@ pub fn has_bl(bo: &BlockOffset, bsize: BlockSize) -> bool {
     has_bl = true;
   }
 
-  /* The bottom left square of a Vertical A (in the old format) does
-    * not have a top right as it is decoded before the right hand
-    * rectangle of the partition */
-/*
-  if blk.partition == PartitionType::PARTITION_VERT_A {
-    if blk.n4_w == blk.n4_h {
-      if (mask_row & bs) != 0 {
-        has_tr = false;
+  has_bl
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use encoder::test::inter_frame;
+
+  // Top-right and bottom-left availability of a luma 64x64 transform block
+  fn avail(plane: &Plane, x: isize, y: isize, plane_bsize: BlockSize) -> (bool, bool) {
+    let dst = plane.slice(&PlaneOffset { x, y });
+    let partition = if plane_bsize == BLOCK_128X128 {
+      PartitionType::PARTITION_NONE
+    } else {
+      PartitionType::PARTITION_VERT
+    };
+    (
+      tx_has_tr(&dst, TxSize::TX_64X64, plane_bsize, BLOCK_128X128, partition),
+      tx_has_bl(&dst, TxSize::TX_64X64, plane_bsize, BLOCK_128X128, partition)
+    )
+  }
+
+  #[test]
+  fn vert_128_edge_availability() {
+    let plane = Plane::new(256, 256, 0, 0, 0, 0);
+    // Square blocks follow the quad-tree order
+    assert_eq!(avail(&plane, 0, 128, BLOCK_128X128), (true, true));
+    assert_eq!(avail(&plane, 64, 128, BLOCK_128X128), (true, false));
+    assert_eq!(avail(&plane, 0, 192, BLOCK_128X128), (true, false));
+    // The left half is coded before the right one, top to bottom
+    assert_eq!(avail(&plane, 0, 128, BLOCK_64X128), (true, true));
+    assert_eq!(avail(&plane, 0, 192, BLOCK_64X128), (false, false));
+    assert_eq!(avail(&plane, 64, 128, BLOCK_64X128), (true, true));
+    assert_eq!(avail(&plane, 64, 192, BLOCK_64X128), (false, false));
+  }
+
+  #[test]
+  fn obmc_blend_masks() {
+    let mut fi = inter_frame(64, 64);
+
+    // Give the reference a pattern so that each neighbour's motion shows
+    let slot = fi.ref_frames[0] as usize;
+    let mut rf = (**fi.rec_buffer.frames[slot].as_ref().unwrap()).clone();
+    {
+      let ref_plane = &mut rf.frame.planes[0];
+      let stride = ref_plane.cfg.stride;
+      let mut dst = ref_plane.mut_slice(&PlaneOffset { x: 0, y: 0 });
+      for (i, v) in dst.as_mut_slice()[..64 * stride].iter_mut().enumerate() {
+        *v = (40 + (i % stride) * 3 + (i / stride) * 2) as u16;
+      }
+    }
+    fi.rec_buffer.frames[slot] = Some(::std::sync::Arc::new(rf));
+    let reference = |x: isize, y: isize| {
+      let plane = &fi.rec_buffer.frames[slot].as_ref().unwrap().frame.planes[0];
+      plane.p(x as usize, y as usize) as u32
+    };
+
+    let mut above = Block::default();
+    above.ref_frames = [LAST_FRAME, NONE_FRAME];
+    above.mv[0] = MotionVector { row: 16, col: -8 };
+    let mut left = Block::default();
+    left.ref_frames = [LAST_FRAME, NONE_FRAME];
+    left.mv[0] = MotionVector { row: -24, col: 32 };
+
+    // Obmc_Mask_8 of the spec, for the 16x16 block's 8 blended rows and columns
+    let mask = [36, 42, 48, 53, 57, 61, 64, 64];
+    let blend = |cur: u32, obmc: u32, m: u32| (cur * m + obmc * (64 - m) + 32) >> 6;
+    let po = PlaneOffset { x: 16, y: 16 };
+    let mut rec = Plane::new(64, 64, 0, 0, 0, 0);
+    rec.mut_slice(&PlaneOffset { x: 0, y: 0 }).as_mut_slice().iter_mut().for_each(|v| *v = 90);
+    predict_overlapped(&fi, 0, &po, &mut rec, BLOCK_16X16, &[(0, 4, &above)], &[(0, 4, &left)]);
+    for y in 0..16 {
+      for x in 0..16 {
+        let (px, py) = (po.x + x, po.y + y);
+        let mut expected = 90;
+        if y < 8 {
+          expected = blend(expected, reference(px - 1, py + 2), mask[y as usize]);
+        }
+        if x < 8 {
+          expected = blend(expected, reference(px + 4, py - 3), mask[x as usize]);
+        }
+        assert_eq!(rec.p(px as usize, py as usize) as u32, expected);
       }
     }
+
+    // Blocks narrower or shorter than 8 are only blended with the left
+    let mut rec = Plane::new(64, 64, 0, 0, 0, 0);
+    rec.mut_slice(&PlaneOffset { x: 0, y: 0 }).as_mut_slice().iter_mut().for_each(|v| *v = 90);
+    predict_overlapped(&fi, 0, &po, &mut rec, BLOCK_8X4, &[(0, 2, &above)], &[]);
+    assert!((0..4).all(|y| (0..8).all(|x| rec.p(16 + x, 16 + y) == 90)));
   }
-*/
 
-  has_bl
+  #[test]
+  fn wedge_mask_symmetries() {
+    let sizes = [BLOCK_8X8, BLOCK_8X16, BLOCK_16X8, BLOCK_16X16, BLOCK_16X32,
+                 BLOCK_32X16, BLOCK_32X32, BLOCK_8X32, BLOCK_32X8];
+    for &bsize in sizes.iter() {
+      assert!(bsize.wedge_allowed());
+      for index in 0..16 {
+        let mask = get_wedge_mask(bsize, false, index);
+        let flipped = get_wedge_mask(bsize, true, index);
+        assert!(mask.iter().zip(flipped.iter()).all(|(&m, &f)| m <= 64 && m + f == 64));
+      }
+    }
+
+    let mask = |index| get_wedge_mask(BLOCK_16X16, false, index);
+    let transpose = |m: &[u8]| (0..256).map(|i| m[(i % 16) * 16 + i / 16]).collect::<Vec<_>>();
+    let mirror = |m: &[u8]| (0..256).map(|i| m[i - i % 16 + 15 - i % 16]).collect::<Vec<_>>();
+    let complement = |m: Vec<u8>| m.iter().map(|&v| 64 - v).collect::<Vec<_>>();
+    // Oblique27 and Oblique63, and the horizontal and vertical wedges at the
+    // same offsets, are transposes of each other
+    assert_eq!(transpose(&mask(0)), mask(1));
+    assert_eq!(transpose(&mask(4)), mask(6));
+    assert_eq!(transpose(&mask(5)), mask(7));
+    // Oblique153 mirrors Oblique27 and keeps the sign, Oblique117 mirrors
+    // Oblique63 and the top-left corner sets its sign
+    assert_eq!(mirror(&mask(0)), mask(3));
+    assert_eq!(complement(mirror(&mask(1))), mask(2));
+    assert_eq!(complement(mirror(&mask(6))), mask(7));
+    assert_eq!(mirror(&mask(4)), mask(4));
+    // The vertical edge at a quarter of the block, from the master mask
+    for row in mask(6).chunks(16) {
+      assert_eq!(row, [64, 62, 57, 43, 21, 7, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    }
+  }
+
+  #[test]
+  fn dist_wtd_weights() {
+    let mut fi = inter_frame(64, 64);
+    let key = fi.rec_buffer.frames[fi.ref_frames[0] as usize].clone().unwrap();
+    fi.order_hint = 8;
+    let set_ref = |fi: &mut FrameInvariants, ref_frame: usize, order_hint| {
+      let slot = ref_frame - LAST_FRAME;
+      let mut rf = (*key).clone();
+      rf.order_hint = order_hint;
+      fi.rec_buffer.frames[slot] = Some(::std::sync::Arc::new(rf));
+      fi.ref_frames[slot] = slot as u8;
+    };
+
+    // Distances of the first and second reference, and their weights
+    let weights = [((1, 1), (7, 9)), ((3, 1), (4, 12)), ((1, 2), (11, 5)),
+                   ((2, 0), (3, 13)), ((1, 8), (13, 3))];
+    for &((d0, d1), expected) in weights.iter() {
+      set_ref(&mut fi, LAST_FRAME, 8 - d0);
+      set_ref(&mut fi, ALTREF_FRAME, 8 + d1);
+      assert_eq!(get_dist_wtd_weights(&fi, [LAST_FRAME, ALTREF_FRAME]), expected);
+    }
+  }
+
+  // Inter-intra prediction of a 16x16 block at (16, 16) whose inter
+  // prediction is 200, surrounded by reconstructed samples of 100 from
+  // which every intra mode predicts 100
+  fn interintra_16x16(p: usize, compound: CompoundParams) -> Plane {
+    use api::*;
+    use encoder::*;
+
+    let seq = Sequence::new(&FrameInfo { width: 64, height: 64, ..Default::default() });
+    let fi = FrameInvariants::new(64, 64, EncoderConfig::default(), seq);
+    let dec = if p == 0 { 0 } else { 1 };
+    let mut rec = Plane::new(64 >> dec, 64 >> dec, dec, dec, 0, 0);
+    let po = PlaneOffset { x: 16 >> dec, y: 16 >> dec };
+    let stride = rec.cfg.stride;
+    for (i, v) in rec.data.iter_mut().enumerate() {
+      let (x, y) = ((i % stride) as isize, (i / stride) as isize);
+      let inside = |v: isize, o: isize| v >= o && v < o + (16 >> dec);
+      *v = if inside(x, po.x) && inside(y, po.y) { 200 } else { 100 };
+    }
+    predict_interintra(&fi, p, &po, &mut rec, BLOCK_16X16, compound);
+    rec
+  }
+
+  #[test]
+  fn interintra_smooth_blend() {
+    let blend = |m: u32| ((100 * m + 200 * (64 - m) + 32) >> 6) as u16;
+    // Ii_Weights_1d of the spec, at the steps of a 16x16 block
+    let weights = [60, 45, 34, 26, 19, 15, 11, 8, 6, 5, 4, 3, 2, 2, 1, 1];
+
+    let rec = interintra_16x16(0, CompoundParams::new_interintra(InterIntraMode::II_SMOOTH_PRED, None));
+    for y in 0..16 {
+      for x in 0..16 {
+        assert_eq!(rec.p(16 + x, 16 + y), blend(weights[x.min(y)]));
+      }
+    }
+    let rec = interintra_16x16(0, CompoundParams::new_interintra(InterIntraMode::II_V_PRED, None));
+    for y in 0..16 {
+      assert!((0..16).all(|x| rec.p(16 + x, 16 + y) == blend(weights[y])));
+    }
+    // DC_PRED blends evenly
+    let rec = interintra_16x16(0, CompoundParams::new_interintra(InterIntraMode::II_DC_PRED, None));
+    assert!((0..16).all(|y| (0..16).all(|x| rec.p(16 + x, 16 + y) == 150)));
+  }
+
+  #[test]
+  fn interintra_wedge_blend() {
+    let blend = |m: u32| ((100 * m + 200 * (64 - m) + 32) >> 6) as u16;
+    let compound = CompoundParams::new_interintra(InterIntraMode::II_DC_PRED, Some(6));
+
+    // The wedge of a vertical edge at a quarter of the block weights the
+    // intra prediction on its left
+    let rec = interintra_16x16(0, compound);
+    let mask = [64, 62, 57, 43, 21, 7, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    for y in 0..16 {
+      assert!((0..16).all(|x| rec.p(16 + x, 16 + y) == blend(mask[x])));
+    }
+    // and is averaged over 2x2 luma samples for 4:2:0 chroma
+    let rec = interintra_16x16(1, compound);
+    let mask = [63, 50, 14, 1, 0, 0, 0, 0];
+    for y in 0..8 {
+      assert!((0..8).all(|x| rec.p(8 + x, 8 + y) == blend(mask[x])));
+    }
+  }
 }
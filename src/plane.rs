@@ -76,8 +76,8 @@ impl Plane {
     let xorigin = self.cfg.xorigin;
     let yorigin = self.cfg.yorigin;
     let stride = self.cfg.stride;
-    let width = w >> self.cfg.xdec;
-    let height = h >> self.cfg.ydec;
+    let width = (w + self.cfg.xdec) >> self.cfg.xdec;
+    let height = (h + self.cfg.ydec) >> self.cfg.ydec;
 
     if xorigin > 0 {
       for y in 0..height {
@@ -201,6 +201,48 @@ impl Plane {
     }
   }
 
+  /// Resamples the `src_w`x`src_h` area of `src` to `width`x`height` with
+  /// a separable triangle filter spanning the scale factor.
+  pub fn resample_from(
+    &mut self, src: &Plane, src_w: usize, src_h: usize, width: usize,
+    height: usize
+  ) {
+    fn taps(src_len: usize, dst_len: usize, i: usize) -> Vec<(usize, f64)> {
+      let scale = src_len as f64 / dst_len as f64;
+      let radius = scale.max(1.0);
+      let center = (i as f64 + 0.5) * scale - 0.5;
+      let first = (center - radius).ceil() as isize;
+      let last = (center + radius).floor() as isize;
+      let taps: Vec<(usize, f64)> = (first..=last)
+        .map(|j| {
+          let w = 1.0 - (j as f64 - center).abs() / radius;
+          (j.max(0).min(src_len as isize - 1) as usize, w.max(0.0))
+        })
+        .collect();
+      let norm: f64 = taps.iter().map(|&(_, w)| w).sum();
+      taps.into_iter().map(|(j, w)| (j, w / norm)).collect()
+    }
+
+    let mut tmp = vec![0f64; width * src_h];
+    for x in 0..width {
+      let x_taps = taps(src_w, width, x);
+      for y in 0..src_h {
+        tmp[y * width + x] =
+          x_taps.iter().map(|&(i, w)| src.p(i, y) as f64 * w).sum();
+      }
+    }
+
+    let stride = self.cfg.stride;
+    let dst = self.data_origin_mut();
+    for y in 0..height {
+      let y_taps = taps(src_h, height, y);
+      for x in 0..width {
+        let sum: f64 = y_taps.iter().map(|&(j, w)| tmp[j * width + x] * w).sum();
+        dst[y * stride + x] = sum.round() as u16;
+      }
+    }
+  }
+
   /// Iterates over the pixels in the `Plane`, skipping stride data.
   pub fn iter(&self) -> PlaneIter {
     PlaneIter::new(self)
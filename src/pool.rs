@@ -0,0 +1,124 @@
+// Copyright (c) 2018, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+use num_cpus;
+use scoped_threadpool::Pool;
+
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Mutex;
+
+/// Worker threads over which independent jobs are spread. The threads are
+/// started on the first jobs and kept until the pool is dropped.
+pub struct WorkerPool {
+  threads: usize,
+  pool: Mutex<Option<Pool>>
+}
+
+impl fmt::Debug for WorkerPool {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("WorkerPool").field("threads", &self.threads).finish()
+  }
+}
+
+impl WorkerPool {
+  /// A pool of `threads` workers, or of one per CPU if 0
+  pub fn new(threads: usize) -> WorkerPool {
+    let threads = if threads == 0 { num_cpus::get() } else { threads };
+    WorkerPool { threads, pool: Mutex::new(None) }
+  }
+
+  pub fn threads(&self) -> usize {
+    self.threads
+  }
+
+  /// Runs `f` on every job, returning the results in the order of the
+  /// jobs. Each worker takes the next job left when done with one. With a
+  /// single worker the jobs run in order on the calling thread. A panic in
+  /// a job is raised again on the calling thread once all jobs are done.
+  pub fn map<T, R, F>(&self, jobs: Vec<T>, f: F) -> Vec<R>
+  where
+    T: Send,
+    R: Send,
+    F: Fn(T) -> R + Sync
+  {
+    if self.threads <= 1 || jobs.len() <= 1 {
+      return jobs.into_iter().map(f).collect();
+    }
+
+    let mut results: Vec<Option<_>> = jobs.iter().map(|_| None).collect();
+    {
+      let mut pool = self.pool.lock().unwrap();
+      let threads = self.threads as u32;
+      let pool = pool.get_or_insert_with(|| Pool::new(threads));
+      // The scope waits for all the jobs it was given before it ends, even
+      // when unwinding. The jobs catch their panics, which would otherwise
+      // stop their workers.
+      let f = &f;
+      pool.scoped(|scope| {
+        for (job, result) in jobs.into_iter().zip(results.iter_mut()) {
+          scope.execute(move || {
+            *result = Some(panic::catch_unwind(AssertUnwindSafe(|| f(job))));
+          });
+        }
+      });
+    }
+
+    let mut done = Vec::with_capacity(results.len());
+    for result in results {
+      match result.unwrap() {
+        Ok(r) => done.push(r),
+        Err(e) => panic::resume_unwind(e)
+      }
+    }
+    done
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use std::collections::HashSet;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::thread;
+
+  #[test]
+  fn workers_persist_across_maps() {
+    let pool = WorkerPool::new(3);
+    let ids = Mutex::new(HashSet::new());
+    for round in 0..4 {
+      let squares = pool.map((0..20).collect(), |i: usize| {
+        ids.lock().unwrap().insert(thread::current().id());
+        i * i + round
+      });
+      assert_eq!(squares, (0..20).map(|i| i * i + round).collect::<Vec<_>>());
+    }
+    let ids = ids.into_inner().unwrap();
+    assert!(ids.len() <= 3);
+    assert!(!ids.contains(&thread::current().id()));
+  }
+
+  #[test]
+  fn panics_reach_the_caller() {
+    let pool = WorkerPool::new(2);
+    let done = AtomicUsize::new(0);
+    let r = panic::catch_unwind(AssertUnwindSafe(|| {
+      pool.map((0..8).collect(), |i: usize| {
+        if i == 3 {
+          panic!("job 3");
+        }
+        done.fetch_add(1, Ordering::SeqCst);
+      })
+    }));
+    assert!(r.is_err());
+    assert_eq!(done.load(Ordering::SeqCst), 7);
+    // The workers survive the panic
+    assert_eq!(pool.map(vec![1, 2, 3], |i: usize| i * 2), vec![2, 4, 6]);
+  }
+}
@@ -50,6 +50,17 @@ pub static RAV1E_INTRA_MODES_MINIMAL: &'static [PredictionMode] = &[
     PredictionMode::V_PRED
 ];
 
+// Intra prediction modes tested on screen content, whose sharp edges are
+// poorly predicted by the interpolating directional modes
+#[cfg_attr(rustfmt, rustfmt_skip)]
+pub static RAV1E_INTRA_MODES_SCREEN: &'static [PredictionMode] = &[
+    PredictionMode::DC_PRED,
+    PredictionMode::H_PRED,
+    PredictionMode::V_PRED,
+    PredictionMode::PAETH_PRED,
+    PredictionMode::SMOOTH_PRED
+];
+
 pub static RAV1E_INTER_MODES_MINIMAL: &'static [PredictionMode] = &[
   PredictionMode::NEARESTMV
 ];
@@ -10,7 +10,12 @@
 #![cfg_attr(feature = "cargo-clippy", allow(cast_lossless))]
 #![allow(non_upper_case_globals)]
 
-use partition::TxSize;
+use context::*;
+use ec::OD_BITRES;
+use partition::{BlockSize, TxSize, TxType};
+use qm_tables::AOM_QM_BITS;
+use scan_order::av1_scan_orders;
+use util::{AlignedArray, UninitializedAlignedArray};
 
 use num_traits::*;
 use std::convert::Into;
@@ -59,7 +64,10 @@ pub struct QuantizationContext {
 
   ac_quant: u32,
   ac_offset: i32,
-  ac_mul_add: (u32, u32, u32)
+  ac_mul_add: (u32, u32, u32),
+
+  // Rounding offset in 1/64 of the quantizer
+  round_factor: i32
 }
 
 fn divu_gen(d: u32) -> (u32, u32, u32) {
@@ -98,7 +106,10 @@ fn divu_pair(x: i32, d: (u32, u32, u32)) -> i32 {
 #[cfg(test)]
 mod test {
   use super::*;
+  use partition::BlockSize::*;
   use partition::TxSize::*;
+  use partition::TxType::*;
+  use qm_tables::get_qm_tbl;
 
   #[test]
   fn test_divu_pair() {
@@ -116,6 +127,83 @@ mod test {
 
     println!("{:?}", b);
   }
+  #[test]
+  fn rdoq_only_lowers_levels() {
+    let mut qc = QuantizationContext::default();
+    qc.update(100, TX_8X8, true, 8, 0, 0);
+    let mut cw = ContextWriter::new(CDFContext::new(100), BlockContext::new(16, 16));
+    let bo = BlockOffset { x: 0, y: 0 };
+    let coeffs: Vec<i32> =
+      (0..64).map(|i| if i % 3 == 0 { -1 } else { 1 } * (1000 - 15 * i) / 3).collect();
+
+    // With no rate term every coefficient is rounded to the nearest level
+    let mut nearest = [0; 64];
+    qc.rdoq(&mut cw, &coeffs, &mut nearest, 0, &bo, TX_8X8, DCT_DCT, BLOCK_8X8, 0, 0, 0.0, None);
+    for (i, (&n, &c)) in nearest.iter().zip(coeffs.iter()).enumerate() {
+      let quant = if i == 0 { qc.dc_quant } else { qc.ac_quant } as f64;
+      assert_eq!(n, (c as f64 / quant).round() as i32);
+    }
+
+    let mut rdoq = [0; 64];
+    qc.rdoq(&mut cw, &coeffs, &mut rdoq, 0, &bo, TX_8X8, DCT_DCT, BLOCK_8X8, 0, 0, 10000.0, None);
+    assert!(rdoq != nearest);
+    for (&r, &n) in rdoq.iter().zip(nearest.iter()) {
+      assert!(r.abs() <= n.abs() && r * n >= 0);
+    }
+  }
+
+  #[test]
+  fn rdoq_drops_trailing_coefficient() {
+    let mut qc = QuantizationContext::default();
+    qc.update(100, TX_8X8, true, 8, 0, 0);
+    let mut cw = ContextWriter::new(CDFContext::new(100), BlockContext::new(16, 16));
+    let bo = BlockOffset { x: 0, y: 0 };
+    // A large DC and a lone level 1 at the end of the scan
+    let mut coeffs = [0; 64];
+    coeffs[0] = 20 * qc.dc_quant as i32;
+    coeffs[63] = -(qc.ac_quant as i32);
+
+    let mut qcoeffs = [0; 64];
+    qc.rdoq(&mut cw, &coeffs, &mut qcoeffs, 0, &bo, TX_8X8, DCT_DCT, BLOCK_8X8, 0, 0, 0.0, None);
+    assert_eq!((qcoeffs[0], qcoeffs[63]), (20, -1));
+
+    qc.rdoq(&mut cw, &coeffs, &mut qcoeffs, 0, &bo, TX_8X8, DCT_DCT, BLOCK_8X8, 0, 0, 500.0, None);
+    assert!(qcoeffs[0] > 0);
+    assert!(qcoeffs[1..].iter().all(|&q| q == 0));
+
+    // Coefficients below half a quantizer step are never coded
+    let coeffs = [qc.ac_quant as i32 / 3; 64];
+    qc.rdoq(&mut cw, &coeffs, &mut qcoeffs, 0, &bo, TX_8X8, DCT_DCT, BLOCK_8X8, 0, 0, 0.0, None);
+    assert!(qcoeffs.iter().all(|&q| q == 0));
+  }
+
+  #[test]
+  fn qm_quantize_dequantize() {
+    let qm = get_qm_tbl(4, 0, TX_8X8);
+    let mut qc = QuantizationContext::default();
+    qc.update(120, TX_8X8, true, 8, 0, 0);
+
+    // Multiples of the weighted quantizers are reconstructed exactly
+    let coeffs: Vec<i32> =
+      (0..64).map(|i| (i as i32 % 7 - 3) * qc.quant(i, Some(qm)) as i32).collect();
+    let mut qcoeffs = [0; 64];
+    qc.quantize(&coeffs, &mut qcoeffs, 64, Some(qm));
+    let mut rcoeffs = [0; 64];
+    dequantize(120, &qcoeffs, &mut rcoeffs, TX_8X8, 8, 0, 0, Some(qm));
+    assert_eq!(&rcoeffs[..], &coeffs[..]);
+
+    // Otherwise the error stays within a weighted quantizer step, which
+    // grows with the frequency
+    let coeffs: Vec<i32> = (0..64).map(|i| 1000 - 37 * i as i32).collect();
+    qc.quantize(&coeffs, &mut qcoeffs, 64, Some(qm));
+    dequantize(120, &qcoeffs, &mut rcoeffs, TX_8X8, 8, 0, 0, Some(qm));
+    for (i, (&r, &c)) in rcoeffs.iter().zip(coeffs.iter()).enumerate() {
+      assert!((r - c).abs() < qc.quant(i, Some(qm)) as i32);
+    }
+    assert!(qc.quant(63, Some(qm)) > qc.quant(1, Some(qm)));
+    assert_eq!(qc.quant(1, None), qc.ac_quant);
+  }
+
   #[test]
   fn test_tx_log_scale() {
     let tx_sizes = [
@@ -158,16 +246,43 @@ impl QuantizationContext {
     self.ac_quant = ac_q(qindex, ac_delta_q, bit_depth) as u32;
     self.ac_mul_add = divu_gen(self.ac_quant);
 
-    self.dc_offset =
-      self.dc_quant as i32 * (if is_intra { 21 } else { 15 }) / 64;
-    self.ac_offset =
-      self.ac_quant as i32 * (if is_intra { 21 } else { 15 }) / 64;
+    self.round_factor = if is_intra { 21 } else { 15 };
+    self.dc_offset = self.dc_quant as i32 * self.round_factor / 64;
+    self.ac_offset = self.ac_quant as i32 * self.round_factor / 64;
+  }
+
+  /// Quantizer of the coefficient at `pos`, weighted by the quantizer
+  /// matrix `qm` if any (spec 7.12.3)
+  #[inline]
+  fn quant(&self, pos: usize, qm: Option<&[u8]>) -> u32 {
+    let quant = if pos == 0 { self.dc_quant } else { self.ac_quant };
+    match qm {
+      Some(qm) => {
+        (quant * qm[pos] as u32 + (1 << (AOM_QM_BITS - 1))) >> AOM_QM_BITS
+      }
+      None => quant
+    }
   }
 
   #[inline]
-  pub fn quantize<T>(&self, coeffs: &[T], qcoeffs: &mut [T], coded_tx_size: usize)
+  pub fn quantize<T>(
+    &self, coeffs: &[T], qcoeffs: &mut [T], coded_tx_size: usize,
+    qm: Option<&[u8]>
+  )
     where T: Coefficient, i32: AsPrimitive<T>
   {
+    if qm.is_some() {
+      for (i, (qc, &c)) in qcoeffs.iter_mut().zip(coeffs).enumerate().take(coded_tx_size) {
+        let quant = self.quant(i, qm) as i32;
+        let c: i32 = c.as_() << self.log_tx_scale;
+        *qc = (c.signum() * ((c.abs() + quant * self.round_factor / 64) / quant)).as_();
+      }
+      for qc in qcoeffs[coded_tx_size..].iter_mut() {
+        *qc = 0.as_();
+      }
+      return;
+    }
+
     qcoeffs[0] = coeffs[0] << (self.log_tx_scale as usize);
     qcoeffs[0] += qcoeffs[0].signum() * self.dc_offset.as_();
     qcoeffs[0] = divu_pair(qcoeffs[0].as_(), self.dc_mul_add).as_();
@@ -184,6 +299,158 @@ impl QuantizationContext {
       }
     }
   }
+  /// Rate-distortion optimized quantization. Coefficients are rounded to
+  /// the nearest level, then greedily lowered in reverse scan order using
+  /// rate estimates from the current coefficient CDFs, and the end of block
+  /// position is chosen. The levels are then chosen again with the contexts
+  /// of the new end of block.
+  pub fn rdoq(
+    &self, cw: &mut ContextWriter, coeffs: &[i32], qcoeffs: &mut [i32],
+    plane: usize, bo: &BlockOffset, tx_size: TxSize, tx_type: TxType,
+    plane_bsize: BlockSize, xdec: usize, ydec: usize, lambda: f64,
+    qm: Option<&[u8]>
+  ) {
+    let coded_tx_size = av1_get_coded_tx_size(tx_size);
+    let width = coded_tx_size.width();
+    let height = coded_tx_size.height();
+    let bwl = coded_tx_size.width_log2();
+    let area = coded_tx_size.area();
+    let scan = &av1_scan_orders[tx_size as usize][tx_type as usize].scan[..area];
+    let tx_class = tx_type_to_class[tx_type as usize];
+
+    for (i, (qc, &c)) in qcoeffs.iter_mut().zip(coeffs).enumerate().take(area) {
+      let quant = self.quant(i, qm) as i32;
+      let c = c << self.log_tx_scale;
+      *qc = c.signum() * ((c.abs() + (quant >> 1)) / quant);
+    }
+    for qc in qcoeffs[area..].iter_mut() {
+      *qc = 0;
+    }
+
+    let eob = match scan.iter().rposition(|&pos| qcoeffs[pos as usize] != 0) {
+      Some(c) => c + 1,
+      None => return
+    };
+
+    let txb_ctx = cw.bc.get_txb_ctx(plane_bsize, tx_size, plane, bo, xdec, ydec);
+    let mut levels_buf = [0 as u8; TX_PAD_2D];
+    cw.txb_init_levels(qcoeffs, width, height, &mut levels_buf);
+    let levels = &mut levels_buf[TX_PAD_TOP * (width + TX_PAD_HOR)..];
+
+    // Scale squared errors to the pixel domain, as for tx-domain distortion
+    let dist_scale = 1.0 / (1 << (2 * (3 - self.log_tx_scale))) as f64;
+    let lambda = lambda / (1 << OD_BITRES) as f64;
+    let dist = |c: i32, level: u32, quant: u32| -> f64 {
+      let e = (c.abs() as i64) - ((level as i64 * quant as i64) >> self.log_tx_scale);
+      (e * e) as f64 * dist_scale
+    };
+
+    // Best of the rounded `level` and `level - 1` for the coefficient at
+    // scan index `c`, with its rate and distortion
+    let choose_level = |cw: &mut ContextWriter, levels: &[u8], c: usize, is_eob: bool, level: u32| {
+      let pos = scan[c] as usize;
+      let quant = self.quant(pos, qm);
+      let coeff_ctx = cw.get_nz_map_ctx(
+        levels, pos, bwl, height, c, is_eob, tx_size, tx_class
+      );
+      let br_ctx = cw.get_br_ctx(levels, pos, bwl, tx_class);
+      let sign = if coeffs[pos] < 0 { -1 } else { 1 };
+
+      let mut best_level = level;
+      let mut best_rate = cw.coeff_cost(
+        sign * level as i32, c, is_eob, coeff_ctx, br_ctx, plane, tx_size,
+        &txb_ctx
+      );
+      let mut best_dist = dist(coeffs[pos], level, quant);
+
+      if level > 1 || (level == 1 && !is_eob) {
+        let rate = cw.coeff_cost(
+          sign * (level - 1) as i32, c, is_eob, coeff_ctx, br_ctx, plane,
+          tx_size, &txb_ctx
+        );
+        let d = dist(coeffs[pos], level - 1, quant);
+        if d + lambda * (rate as f64) < best_dist + lambda * (best_rate as f64) {
+          best_level = level - 1;
+          best_rate = rate;
+          best_dist = d;
+        }
+      }
+      (sign * best_level as i32, best_rate, best_dist)
+    };
+    let level_idx = |pos: usize| pos + ((pos >> bwl) << TX_PAD_HOR_LOG2);
+
+    let mut nearest: AlignedArray<[u32; 32 * 32]> = UninitializedAlignedArray();
+    let mut rates: AlignedArray<[u32; 32 * 32]> = UninitializedAlignedArray();
+    let mut dists: AlignedArray<[f64; 32 * 32]> = UninitializedAlignedArray();
+    let mut zero_dists: AlignedArray<[f64; 32 * 32]> = UninitializedAlignedArray();
+    let nearest = &mut nearest.array[..eob];
+    let rates = &mut rates.array[..eob];
+    let dists = &mut dists.array[..eob];
+    let zero_dists = &mut zero_dists.array[..eob];
+
+    for c in (0..eob).rev() {
+      let pos = scan[c] as usize;
+      let quant = self.quant(pos, qm);
+      nearest[c] = qcoeffs[pos].abs() as u32;
+      let (v, rate, d) = choose_level(cw, levels, c, c == eob - 1, nearest[c]);
+      qcoeffs[pos] = v;
+      levels[level_idx(pos)] = v.abs().min(127) as u8;
+      rates[c] = rate;
+      dists[c] = d;
+      zero_dists[c] = dist(coeffs[pos], 0, quant);
+    }
+
+    // Choose the end of block among the nonzero positions, approximating
+    // the rate of the remaining coefficients by their contexts above
+    let mut suffix_dist: f64 = zero_dists.iter().sum();
+    let mut prefix_cost = 0.0;
+    let mut best_eob = 0;
+    let mut best_cost = suffix_dist
+      + lambda * cw.eob_cost(0, plane, tx_size, tx_type, &txb_ctx) as f64;
+
+    for c in 0..eob {
+      let pos = scan[c] as usize;
+      suffix_dist -= zero_dists[c];
+      if qcoeffs[pos] != 0 {
+        let is_eob = c == eob - 1;
+        let last_rate = if is_eob {
+          rates[c]
+        } else {
+          let coeff_ctx = cw.get_nz_map_ctx(
+            levels, pos, bwl, height, c, true, tx_size, tx_class
+          );
+          let br_ctx = cw.get_br_ctx(levels, pos, bwl, tx_class);
+          cw.coeff_cost(
+            qcoeffs[pos], c, true, coeff_ctx, br_ctx, plane, tx_size, &txb_ctx
+          )
+        };
+        let eob_rate = cw.eob_cost(c + 1, plane, tx_size, tx_type, &txb_ctx);
+        let cost = prefix_cost
+          + dists[c]
+          + suffix_dist
+          + lambda * (eob_rate + last_rate) as f64;
+        if cost < best_cost {
+          best_cost = cost;
+          best_eob = c + 1;
+        }
+      }
+      prefix_cost += dists[c] + lambda * rates[c] as f64;
+    }
+
+    for &pos in scan[best_eob..eob].iter() {
+      qcoeffs[pos as usize] = 0;
+      levels[level_idx(pos as usize)] = 0;
+    }
+
+    // The levels above were chosen with the contexts of the original end
+    // of block and of the coefficients that were cut off
+    for c in (0..best_eob).rev() {
+      let pos = scan[c] as usize;
+      let (v, _, _) = choose_level(cw, levels, c, c == best_eob - 1, nearest[c]);
+      qcoeffs[pos] = v;
+      levels[level_idx(pos)] = v.abs().min(127) as u8;
+    }
+  }
 }
 
 // quantization without using Multiplication Factor
@@ -213,7 +480,7 @@ pub fn quantize_wo_mf(
 
 pub fn dequantize(
   qindex: u8, coeffs: &[i32], rcoeffs: &mut [i32], tx_size: TxSize,
-  bit_depth: usize, dc_delta_q: i8, ac_delta_q: i8
+  bit_depth: usize, dc_delta_q: i8, ac_delta_q: i8, qm: Option<&[u8]>
 ) {
   let log_tx_scale = get_log_tx_scale(tx_size) as i32;
   let offset = (1 << log_tx_scale) - 1;
@@ -223,6 +490,13 @@ pub fn dequantize(
 
   for (i, (r, &c)) in rcoeffs.iter_mut().zip(coeffs.iter()).enumerate() {
     let quant = if i == 0 { dc_quant } else { ac_quant };
+    // Coefficients past the coded size of 64-point transforms are zero
+    let quant = match qm {
+      Some(qm) if i < qm.len() => {
+        (quant * qm[i] as i32 + (1 << (AOM_QM_BITS - 1))) >> AOM_QM_BITS
+      }
+      _ => quant
+    };
     *r = (c * quant + ((c >> 31) & offset)) >> log_tx_scale;
   }
 }
@@ -11,30 +11,38 @@
 #![allow(non_camel_case_types)]
 #![cfg_attr(feature = "cargo-clippy", allow(cast_lossless))]
 
-use api::PredictionModesSetting;
+use api::{PartitionTypesSetting, PredictionModesSetting};
 use cdef::*;
 use context::*;
 use ec::{OD_BITRES, Writer, WriterCounter};
-use encoder::{ChromaSampling, ReferenceMode};
+use entropymode::MAX_TX_DEPTH;
+use encoder::{ChromaSampling, Frame, ReferenceMode, encode_tx_block, get_qidx};
 use encode_block_a;
 use encode_block_b;
 use encode_block_with_modes;
-use Frame;
 use FrameInvariants;
 use FrameState;
 use FrameType;
+use intrabc::{intrabc_search, ref_dv};
 use luma_ac;
 use me::*;
 use motion_compensate;
+use palette::*;
 use partition::*;
 use plane::*;
-use predict::{RAV1E_INTRA_MODES, RAV1E_INTER_MODES_MINIMAL, RAV1E_INTER_COMPOUND_MODES};
+use predict::{
+  RAV1E_INTRA_MODES, RAV1E_INTRA_MODES_SCREEN, RAV1E_INTER_MODES_MINIMAL,
+  RAV1E_INTER_COMPOUND_MODES
+};
+use mc::FilterMode;
 use quantize::dc_q;
 use Tune;
 use write_tx_blocks;
 use write_tx_tree;
+use warp::{global_mv, global_warp, local_warp};
 
 use std;
+use std::cmp;
 use std::vec::Vec;
 use partition::PartitionType::*;
 
@@ -55,9 +63,15 @@ pub struct RDOPartitionOutput {
   pub pred_cfl_params: CFLParams,
   pub ref_frames: [usize; 2],
   pub mvs: [MotionVector; 2],
+  pub motion_mode: MotionMode,
+  pub interp_filter: [FilterMode; 2],
+  pub compound: CompoundParams,
+  pub palette: PaletteParams,
   pub skip: bool,
+  pub skip_mode: bool,
   pub tx_size: TxSize,
   pub tx_type: TxType,
+  pub tx_partition: TxPartition,
 }
 
 #[allow(unused)]
@@ -149,6 +163,17 @@ pub fn get_lambda(fi: &FrameInvariants) -> f64 {
   q0 * q0 * std::f64::consts::LN_2 / 6.0
 }
 
+/// Lambda for decisions that only change plane `p`, measured with an
+/// unweighted distortion. It follows the chroma quantizer offsets.
+pub fn get_plane_lambda(fi: &FrameInvariants, p: usize) -> f64 {
+  get_lambda(fi) / fi.dist_scale[p]
+}
+
+/// Distortion of plane `p` weighted for decisions that sum it over planes.
+pub fn weight_plane_dist(fi: &FrameInvariants, p: usize, dist: u64) -> u64 {
+  (dist as f64 * fi.dist_scale[p]) as u64
+}
+
 pub fn get_lambda_sqrt(fi: &FrameInvariants) -> f64 {
   let q = dc_q(fi.base_q_idx, fi.dc_delta_q[0], fi.sequence.bit_depth) as f64;
 
@@ -196,9 +221,9 @@ fn compute_rd_cost(
   let mut w_uv = (w_y >> xdec) & mask;
   let mut h_uv = (h_y >> ydec) & mask;
 
-  if (w_uv == 0 || h_uv == 0) && is_chroma_block {
-    w_uv = MI_SIZE;
-    h_uv = MI_SIZE;
+  if is_chroma_block {
+    w_uv = w_uv.max(MI_SIZE);
+    h_uv = h_uv.max(MI_SIZE);
   }
 
   // Add chroma distortion only when it is available
@@ -206,12 +231,13 @@ fn compute_rd_cost(
     for p in 1..3 {
       let po = bo.plane_offset(&fs.input.planes[p].cfg);
 
-      distortion += sse_wxh(
+      let sse = sse_wxh(
         &fs.input.planes[p].slice(&po),
         &fs.rec.planes[p].slice(&po),
         w_uv,
         h_uv
       );
+      distortion += weight_plane_dist(fi, p, sse);
     }
   };
   }
@@ -253,9 +279,9 @@ fn compute_tx_rd_cost(
     let mut w_uv = (w_y >> xdec) & mask;
     let mut h_uv = (h_y >> ydec) & mask;
 
-    if (w_uv == 0 || h_uv == 0) && is_chroma_block {
-      w_uv = MI_SIZE;
-      h_uv = MI_SIZE;
+    if is_chroma_block {
+      w_uv = w_uv.max(MI_SIZE);
+      h_uv = h_uv.max(MI_SIZE);
     }
 
     // Add chroma distortion only when it is available
@@ -263,12 +289,13 @@ fn compute_tx_rd_cost(
       for p in 1..3 {
         let po = bo.plane_offset(&fs.input.planes[p].cfg);
 
-        distortion += sse_wxh(
+        let sse = sse_wxh(
           &fs.input.planes[p].slice(&po),
           &fs.rec.planes[p].slice(&po),
           w_uv,
           h_uv
         );
+        distortion += weight_plane_dist(fi, p, sse);
       }
     }
   }
@@ -281,53 +308,197 @@ fn compute_tx_rd_cost(
 pub fn rdo_tx_size_type(
   fi: &FrameInvariants, fs: &mut FrameState,
   cw: &mut ContextWriter, bsize: BlockSize, bo: &BlockOffset,
-  luma_mode: PredictionMode, ref_frames: [usize; 2], mvs: [MotionVector; 2], skip: bool
-) -> (TxSize, TxType) {
-  // these rules follow TX_MODE_LARGEST
-  let tx_size = match bsize {
-    BlockSize::BLOCK_4X4 => TxSize::TX_4X4,
-    BlockSize::BLOCK_8X8 => TxSize::TX_8X8,
-    BlockSize::BLOCK_16X16 => TxSize::TX_16X16,
-    BlockSize::BLOCK_4X8 => TxSize::TX_4X8,
-    BlockSize::BLOCK_8X4 => TxSize::TX_8X4,
-    BlockSize::BLOCK_8X16 => TxSize::TX_8X16,
-    BlockSize::BLOCK_16X8 => TxSize::TX_16X8,
-    BlockSize::BLOCK_16X32 => TxSize::TX_16X32,
-    BlockSize::BLOCK_32X16 => TxSize::TX_32X16,
-    BlockSize::BLOCK_32X32 => TxSize::TX_32X32,
-    BlockSize::BLOCK_32X64 => TxSize::TX_32X64,
-    BlockSize::BLOCK_64X32 => TxSize::TX_64X32,
-    BlockSize::BLOCK_64X64 => TxSize::TX_64X64,
-    _ => unimplemented!()
+  luma_mode: PredictionMode, ref_frames: [usize; 2], mvs: [MotionVector; 2],
+  motion_mode: MotionMode, interp_filter: [FilterMode; 2],
+  compound: CompoundParams, palette: PaletteParams, skip: bool
+) -> (TxSize, TxPartition, TxType) {
+  let is_inter = !luma_mode.is_intra();
+  let tx_select = fi.tx_mode_select && !skip && bsize > BlockSize::BLOCK_4X4;
+
+  // Inter blocks split their largest transforms recursively, intra blocks
+  // use one transform size of up to MAX_TX_DEPTH splits
+  let mut tx_sizes = vec![bsize.tx_size()];
+  let mut tx_partition = TxPartition::default();
+  if tx_select && is_inter {
+    tx_partition = rdo_tx_partition(
+      fi, fs, cw, luma_mode, ref_frames, mvs, motion_mode, interp_filter, compound, bsize, bo
+    );
+  } else if tx_select {
+    for _ in 0..bsize.max_tx_depth().min(MAX_TX_DEPTH) {
+      let tx_size = tx_sizes.last().unwrap().split();
+      tx_sizes.push(tx_size);
+    }
+  }
+
+  let mut best = (tx_sizes[0], tx_sizes[0], TxType::DCT_DCT, std::f64::MAX);
+  for &tx_size in tx_sizes.iter() {
+    // Luma plane transform type decision, over the set of the smallest
+    // transforms of the block
+    let roots = (bsize.width() * bsize.height()) / tx_size.area();
+    let min_tx_size = (0..roots)
+      .flat_map(|root| tx_partition.tx_blocks(root, tx_size))
+      .map(|(_, _, tx_size)| tx_size)
+      .min_by_key(|tx_size| tx_size.area())
+      .unwrap();
+    let tx_set = get_tx_set(min_tx_size, is_inter, fi.use_reduced_tx_set);
+    let rdo_tx_type =
+      tx_set > TxSet::TX_SET_DCTONLY && fi.config.speed_settings.rdo_tx_decision && !skip;
+
+    if !rdo_tx_type && tx_sizes.len() == 1 {
+      best = (tx_size, min_tx_size, TxType::DCT_DCT, 0.);
+      break;
+    }
+    let (tx_type, rd) = rdo_tx_type_decision(
+      fi,
+      fs,
+      cw,
+      luma_mode,
+      ref_frames,
+      mvs,
+      motion_mode,
+      interp_filter,
+      compound,
+      palette,
+      bsize,
+      bo,
+      tx_size,
+      tx_partition,
+      if rdo_tx_type { tx_set } else { TxSet::TX_SET_DCTONLY }
+    );
+    if rd < best.3 {
+      best = (tx_size, min_tx_size, tx_type, rd);
+    }
+  }
+  let (tx_size, min_tx_size, tx_type, _) = best;
+
+  assert!(min_tx_size.sqr() <= TxSize::TX_32X32 || tx_type == TxType::DCT_DCT);
+
+  (tx_size, tx_partition, tx_type)
+}
+
+// RDO-based transform partitioning of inter blocks, coding the luma
+// residual with DCT_DCT
+fn rdo_tx_partition(
+  fi: &FrameInvariants, fs: &mut FrameState, cw: &mut ContextWriter,
+  mode: PredictionMode, ref_frames: [usize; 2], mvs: [MotionVector; 2], motion_mode: MotionMode,
+  interp_filter: [FilterMode; 2], compound: CompoundParams,
+  bsize: BlockSize, bo: &BlockOffset
+) -> TxPartition {
+  motion_compensate(fi, fs, cw, mode, ref_frames, mvs, motion_mode, interp_filter, compound, bsize, bo,
+                    true);
+
+  // Transform blocks are coded again from the prediction when split
+  let pred: Vec<u16> = {
+    let rec = &fs.rec.planes[0];
+    let stride = rec.cfg.stride;
+    let slice = rec.slice(&bo.plane_offset(&rec.cfg));
+    let data = slice.as_slice();
+    (0..bsize.height())
+      .flat_map(|r| data[r * stride..r * stride + bsize.width()].to_vec())
+      .collect()
   };
-  cw.bc.set_tx_size(bo, tx_size);
-  // Were we not hardcoded to TX_MODE_LARGEST, block tx size would be written here
 
-  // Luma plane transform type decision
-  let is_inter = !luma_mode.is_intra();
-  let tx_set = get_tx_set(tx_size, is_inter, fi.use_reduced_tx_set);
+  let cw_checkpoint = cw.checkpoint();
+  let tx_size = bsize.tx_size();
+  let mut tx_partition = TxPartition::default();
+  let mut root = 0;
+  for y in (0..bsize.height_mi()).step_by(tx_size.height_mi()) {
+    for x in (0..bsize.width_mi()).step_by(tx_size.width_mi()) {
+      let tx_bo = BlockOffset { x: bo.x + x, y: bo.y + y };
+      rdo_tx_split(
+        fi, fs, cw, mode, bsize, bo, &pred, &tx_bo, tx_size, 0, &mut tx_partition, root, 0
+      );
+      root += 1;
+    }
+  }
+  cw.rollback(&cw_checkpoint);
 
-  let tx_type =
-    if tx_set > TxSet::TX_SET_DCTONLY && fi.config.speed_settings.rdo_tx_decision && !skip {
-      rdo_tx_type_decision(
-        fi,
-        fs,
-        cw,
-        luma_mode,
-        ref_frames,
-        mvs,
-        bsize,
-        bo,
-        tx_size,
-        tx_set,
-      )
-    } else {
-      TxType::DCT_DCT
-    };
+  tx_partition
+}
 
-  assert!(tx_size.sqr() <= TxSize::TX_32X32 || tx_type == TxType::DCT_DCT);
+// Codes the transform at `tx_bo` whole or split, whichever has the lower RD
+// cost, and returns that cost
+fn rdo_tx_split(
+  fi: &FrameInvariants, fs: &mut FrameState, cw: &mut ContextWriter,
+  mode: PredictionMode, bsize: BlockSize, bo: &BlockOffset, pred: &[u16],
+  tx_bo: &BlockOffset, tx_size: TxSize, depth: usize,
+  tx_partition: &mut TxPartition, root: usize, node: usize
+) -> f64 {
+  let can_split = depth < MAX_VARTX_DEPTH && tx_size != TxSize::TX_4X4;
+  let cw_checkpoint = cw.checkpoint();
+  let rd = rdo_tx_leaf(fi, fs, cw, mode, bsize, bo, pred, tx_bo, tx_size, can_split);
+  if !can_split {
+    return rd;
+  }
+  cw.rollback(&cw_checkpoint);
+
+  let wr: &mut dyn Writer = &mut WriterCounter::new();
+  let tell = wr.tell_frac();
+  cw.write_txfm_split(wr, bo, bsize, tx_bo, tx_size, true);
+  let cost = wr.tell_frac() - tell;
+  let mut split_rd = get_lambda(fi) * (cost as f64) / ((1 << OD_BITRES) as f64);
+  tx_partition.set_split(root, node, true);
+  let sub = tx_size.split();
+  for (i, (x, y)) in tx_size.split_offsets().into_iter().enumerate() {
+    let sub_bo = BlockOffset { x: tx_bo.x + x, y: tx_bo.y + y };
+    split_rd += rdo_tx_split(
+      fi, fs, cw, mode, bsize, bo, pred, &sub_bo, sub, depth + 1, tx_partition, root, i + 1
+    );
+  }
+  if split_rd < rd {
+    return split_rd;
+  }
+
+  // Code the transform whole again for the contexts of the next ones
+  cw.rollback(&cw_checkpoint);
+  tx_partition.set_split(root, node, false);
+  rdo_tx_leaf(fi, fs, cw, mode, bsize, bo, pred, tx_bo, tx_size, can_split)
+}
 
-  (tx_size, tx_type)
+// Codes an unsplit transform of an inter block and returns its RD cost
+fn rdo_tx_leaf(
+  fi: &FrameInvariants, fs: &mut FrameState, cw: &mut ContextWriter,
+  mode: PredictionMode, bsize: BlockSize, bo: &BlockOffset, pred: &[u16],
+  tx_bo: &BlockOffset, tx_size: TxSize, can_split: bool
+) -> f64 {
+  let po = tx_bo.plane_offset(&fs.input.planes[0].cfg);
+  if !fi.use_tx_domain_distortion {
+    // Restore the prediction under the reconstruction of a previous try
+    let rec = &mut fs.rec.planes[0];
+    let stride = rec.cfg.stride;
+    let x0 = (tx_bo.x - bo.x) << MI_SIZE_LOG2;
+    let y0 = (tx_bo.y - bo.y) << MI_SIZE_LOG2;
+    let mut slice = rec.mut_slice(&po);
+    let data = slice.as_mut_slice();
+    for r in 0..tx_size.height() {
+      let src = &pred[(y0 + r) * bsize.width() + x0..];
+      data[r * stride..r * stride + tx_size.width()]
+        .copy_from_slice(&src[..tx_size.width()]);
+    }
+  }
+
+  let wr: &mut dyn Writer = &mut WriterCounter::new();
+  let tell = wr.tell_frac();
+  if can_split {
+    cw.write_txfm_split(wr, bo, bsize, tx_bo, tx_size, false);
+  }
+  cw.bc.set_tx_size(tx_bo, tx_size.block_size(), tx_size);
+
+  let qidx = get_qidx(fi, fs, cw, bo);
+  fs.qc.update(qidx, tx_size, false, fi.sequence.bit_depth, fi.dc_delta_q[0], 0);
+  let (_, tx_dist) = encode_tx_block(
+    fi, fs, cw, wr, 0, tx_bo, mode, tx_size, TxType::DCT_DCT, bsize, &po, false, &[0i16; 2], 0,
+    None, true
+  );
+  let cost = wr.tell_frac() - tell;
+
+  if fi.use_tx_domain_distortion {
+    compute_tx_rd_cost(
+      fi, fs, tx_size.width(), tx_size.height(), false, tx_bo, cost, tx_dist, false, true
+    )
+  } else {
+    compute_rd_cost(fi, fs, tx_size.width(), tx_size.height(), false, tx_bo, cost, true)
+  }
 }
 
 struct EncodingSettings {
@@ -335,11 +506,17 @@ struct EncodingSettings {
   mode_chroma: PredictionMode,
   cfl_params: CFLParams,
   skip: bool,
+  skip_mode: bool,
   rd: f64,
   ref_frames: [usize; 2],
   mvs: [MotionVector; 2],
+  motion_mode: MotionMode,
+  interp_filter: [FilterMode; 2],
+  compound: CompoundParams,
+  palette: PaletteParams,
   tx_size: TxSize,
-  tx_type: TxType
+  tx_type: TxType,
+  tx_partition: TxPartition
 }
 
 impl Default for EncodingSettings {
@@ -349,11 +526,17 @@ impl Default for EncodingSettings {
       mode_chroma: PredictionMode::DC_PRED,
       cfl_params: CFLParams::new(),
       skip: false,
+      skip_mode: false,
       rd: std::f64::MAX,
       ref_frames: [INTRA_FRAME, NONE_FRAME],
       mvs: [MotionVector { row: 0, col: 0 }; 2],
+      motion_mode: MotionMode::SIMPLE_TRANSLATION,
+      interp_filter: [FilterMode::REGULAR; 2],
+      compound: CompoundParams::new(),
+      palette: PaletteParams::new(),
       tx_size: TxSize::TX_4X4,
-      tx_type: TxType::DCT_DCT
+      tx_type: TxType::DCT_DCT,
+      tx_partition: TxPartition::default()
     }
   }
 }
@@ -370,6 +553,8 @@ pub fn rdo_mode_decision(fi: &FrameInvariants, fs: &mut FrameState,
 
   let PlaneConfig { xdec, ydec, .. } = fs.input.planes[1].cfg;
   let is_chroma_block = has_chroma(bo, bsize, xdec, ydec);
+  // Palettes are only searched for blocks with chroma of at least 4x4
+  let palette_search = cw.palette_allowed(fi, bsize) && w >= 8 && h >= 8;
 
   let cw_checkpoint = cw.checkpoint();
 
@@ -407,7 +592,7 @@ pub fn rdo_mode_decision(fi: &FrameInvariants, fs: &mut FrameState,
     mode_contexts.push(cw.find_mvrefs(bo, ref_frames, &mut mv_stack, bsize, fi, false));
 
     if fi.frame_type == FrameType::INTER {
-      let mut pmv = [MotionVector{ row: 0, col: 0 }; 2];
+      let mut pmv = [global_mv(fi, ref_frames[0], bsize, bo); 2];
       if mv_stack.len() > 0 { pmv[0] = mv_stack[0].this_mv; }
       if mv_stack.len() > 1 { pmv[1] = mv_stack[1].this_mv; }
       let cmv = pmvs[ref_slot_set[i] as usize].unwrap();
@@ -422,7 +607,8 @@ pub fn rdo_mode_decision(fi: &FrameInvariants, fs: &mut FrameState,
       if mv_stack.len() >= 1 {
         mode_set.push((PredictionMode::NEAR0MV, i));
       }
-      if mv_stack.len() >= 2 {
+      if mv_stack.len() >= 2
+        || global_warp(fi, ref_frames[0], bsize.width(), bsize.height()).is_some() {
         mode_set.push((PredictionMode::GLOBALMV, i));
       }
       let include_near_mvs = fi.config.speed_settings.include_near_mvs;
@@ -465,10 +651,12 @@ pub fn rdo_mode_decision(fi: &FrameInvariants, fs: &mut FrameState,
   }
 
   let luma_rdo = |luma_mode: PredictionMode, fs: &mut FrameState, cw: &mut ContextWriter, best: &mut EncodingSettings,
-    mvs: [MotionVector; 2], ref_frames: [usize; 2], mode_set_chroma: &[PredictionMode], luma_mode_is_intra: bool,
-    mode_context: usize, mv_stack: &Vec<CandidateMV>| {
-    let (tx_size, mut tx_type) = rdo_tx_size_type(
-        fi, fs, cw, bsize, bo, luma_mode, ref_frames, mvs, false,
+    mvs: [MotionVector; 2], ref_frames: [usize; 2], motion_mode: MotionMode, interp_filter: [FilterMode; 2],
+    compound: CompoundParams, mode_set_chroma: &[PredictionMode], luma_mode_is_intra: bool, mode_context: usize,
+    mv_stack: &Vec<CandidateMV>, skip_mode: bool, palette: PaletteParams| {
+    let (tx_size, mut tx_partition, mut tx_type) = rdo_tx_size_type(
+        fi, fs, cw, bsize, bo, luma_mode, ref_frames, mvs, motion_mode, interp_filter, compound,
+        palette, false,
     );
 
     // Find the best chroma prediction mode for the current luma prediction mode
@@ -477,13 +665,17 @@ pub fn rdo_mode_decision(fi: &FrameInvariants, fs: &mut FrameState,
         let wr: &mut dyn Writer = &mut WriterCounter::new();
         let tell = wr.tell_frac();
 
-        if skip { tx_type = TxType::DCT_DCT; };
+        if skip {
+          tx_type = TxType::DCT_DCT;
+          tx_partition = TxPartition::default();
+        };
 
-        if bsize >= BlockSize::BLOCK_8X8 && bsize.is_sqr() {
+        // Blocks of extended partitions code no partition of their own
+        if bsize >= BlockSize::BLOCK_8X8 && bsize.is_sqr() && !cw.bc.at(bo).partition.is_ext() {
           cw.write_partition(wr, bo, PartitionType::PARTITION_NONE, bsize);
         }
 
-        encode_block_a(&fi.sequence, fs, cw, wr, bsize, bo, skip);
+        encode_block_a(fi, fs, cw, wr, bsize, bo, skip, skip_mode);
         let tx_dist =
         encode_block_b(
           fi,
@@ -494,12 +686,18 @@ pub fn rdo_mode_decision(fi: &FrameInvariants, fs: &mut FrameState,
           chroma_mode,
           ref_frames,
           mvs,
+          motion_mode,
+          interp_filter,
+          compound,
+          palette,
           bsize,
           bo,
           skip,
+          skip_mode,
           CFLParams::new(),
           tx_size,
           tx_type,
+          tx_partition,
           mode_context,
           mv_stack,
           !needs_rec
@@ -538,20 +736,30 @@ pub fn rdo_mode_decision(fi: &FrameInvariants, fs: &mut FrameState,
           best.mode_chroma = chroma_mode;
           best.ref_frames = ref_frames;
           best.mvs = mvs;
+          best.motion_mode = motion_mode;
+          best.interp_filter = interp_filter;
+          best.compound = compound;
+          best.palette = palette;
           best.skip = skip;
+          best.skip_mode = skip_mode;
           best.tx_size = tx_size;
           best.tx_type = tx_type;
+          best.tx_partition = tx_partition;
         }
 
         cw.rollback(&cw_checkpoint);
       });
     };
 
-    chroma_rdo(false);
-    // Don't skip when using intra modes
-    if !luma_mode_is_intra {
+    if skip_mode {
         chroma_rdo(true);
-    };
+    } else {
+        chroma_rdo(false);
+        // Don't skip when using intra modes
+        if !luma_mode_is_intra {
+            chroma_rdo(true);
+        };
+    }
   };
 
   if fi.frame_type != FrameType::INTER {
@@ -559,36 +767,185 @@ pub fn rdo_mode_decision(fi: &FrameInvariants, fs: &mut FrameState,
   }
 
   mode_set.iter().for_each(|&(luma_mode, i)| {
+    let global_mvs = [
+      global_mv(fi, ref_frames_set[i][0], bsize, bo),
+      global_mv(fi, ref_frames_set[i][1], bsize, bo)
+    ];
     let mvs = match luma_mode {
       PredictionMode::NEWMV | PredictionMode::NEW_NEWMV => mvs_from_me[i],
       PredictionMode::NEARESTMV | PredictionMode::NEAREST_NEARESTMV => if mv_stacks[i].len() > 0 {
         [mv_stacks[i][0].this_mv, mv_stacks[i][0].comp_mv]
       } else {
-        [MotionVector { row: 0, col: 0 }; 2]
+        global_mvs
       },
       PredictionMode::NEAR0MV => if mv_stacks[i].len() > 1 {
         [mv_stacks[i][1].this_mv, mv_stacks[i][1].comp_mv]
       } else {
-        [MotionVector { row: 0, col: 0 }; 2]
+        global_mvs
       },
       PredictionMode::NEAR1MV | PredictionMode::NEAR2MV =>
           [mv_stacks[i][luma_mode as usize - PredictionMode::NEAR0MV as usize + 1].this_mv,
           mv_stacks[i][luma_mode as usize - PredictionMode::NEAR0MV as usize + 1].comp_mv],
       PredictionMode::NEAREST_NEWMV => [mv_stacks[i][0].this_mv, mvs_from_me[i][1]],
       PredictionMode::NEW_NEARESTMV => [mvs_from_me[i][0], mv_stacks[i][0].comp_mv],
-      _ => [MotionVector { row: 0, col: 0 }; 2]
+      _ => global_mvs
     };
     let mode_set_chroma = vec![luma_mode];
 
-    luma_rdo(luma_mode, fs, cw, &mut best, mvs, ref_frames_set[i], &mode_set_chroma, false,
-             mode_contexts[i], &mv_stacks[i]);
+    let interp_filter = fi.default_interp_filter();
+
+    luma_rdo(luma_mode, fs, cw, &mut best, mvs, ref_frames_set[i], MotionMode::SIMPLE_TRANSLATION,
+             interp_filter, CompoundParams::new(), &mode_set_chroma, false, mode_contexts[i], &mv_stacks[i], false,
+             PaletteParams::new());
+
+    if fi.config.speed_settings.obmc &&
+      cw.motion_mode_switchable(fi, bo, bsize, luma_mode, ref_frames_set[i]) {
+      luma_rdo(luma_mode, fs, cw, &mut best, mvs, ref_frames_set[i], MotionMode::OBMC_CAUSAL,
+               interp_filter, CompoundParams::new(), &mode_set_chroma, false, mode_contexts[i], &mv_stacks[i], false,
+               PaletteParams::new());
+    }
+
+    if cw.warped_causal_allowed(fi, bo, bsize, luma_mode, ref_frames_set[i], mvs[0]) {
+      let samples = cw.bc.find_warp_samples(bo, bsize, fi.sequence.sb_size(), ref_frames_set[i][0], mvs[0]);
+      if local_warp(bo, bsize, mvs[0], &samples).is_some() {
+        luma_rdo(luma_mode, fs, cw, &mut best, mvs, ref_frames_set[i], MotionMode::WARPED_CAUSAL,
+                 interp_filter, CompoundParams::new(), &mode_set_chroma, false, mode_contexts[i], &mv_stacks[i], false,
+                 PaletteParams::new());
+      }
+    }
   });
 
+  // Search the interpolation filters of the best inter mode only
+  if !best.mode_luma.is_intra() && fi.is_filter_switchable &&
+    cw.interp_filter_coded(fi, bsize, best.mode_luma, best.ref_frames, best.motion_mode,
+                           best.skip_mode) {
+    let i = ref_frames_set.iter().position(|&r| r == best.ref_frames).unwrap();
+    let (luma_mode, mvs, motion_mode) = (best.mode_luma, best.mvs, best.motion_mode);
+    let mode_set_chroma = vec![luma_mode];
+    let filters = [FilterMode::REGULAR, FilterMode::SMOOTH, FilterMode::SHARP];
+    for &filter_y in filters.iter() {
+      for &filter_x in filters.iter() {
+        if (filter_x != filter_y && !fi.sequence.enable_dual_filter) ||
+          (filter_x == FilterMode::REGULAR && filter_y == FilterMode::REGULAR) {
+          continue;
+        }
+        luma_rdo(luma_mode, fs, cw, &mut best, mvs, ref_frames_set[i], motion_mode,
+                 [filter_y, filter_x], CompoundParams::new(), &mode_set_chroma, false,
+                 mode_contexts[i], &mv_stacks[i], false,
+                 PaletteParams::new());
+      }
+    }
+  }
+
+  // Search the weighted and masked compound predictions of the best
+  // compound mode
+  if !best.mode_luma.is_intra() && cw.compound_type_switchable(fi, best.ref_frames) {
+    let i = ref_frames_set.iter().position(|&r| r == best.ref_frames).unwrap();
+    let (luma_mode, ref_frames, mvs, interp_filter) =
+      (best.mode_luma, best.ref_frames, best.mvs, best.interp_filter);
+    let mode_set_chroma = vec![luma_mode];
+    let mut compounds = Vec::new();
+    if fi.sequence.enable_jnt_comp {
+      compounds.push(CompoundParams {
+        compound_type: CompoundType::COMPOUND_DISTANCE,
+        ..CompoundParams::new()
+      });
+    }
+    if fi.sequence.enable_masked_compound {
+      if bsize.wedge_allowed() {
+        let (wedge_index, wedge_sign) =
+          rdo_wedge_selection(fi, fs, bsize, bo, luma_mode, ref_frames, mvs, interp_filter);
+        compounds.push(CompoundParams {
+          compound_type: CompoundType::COMPOUND_WEDGE,
+          wedge_index,
+          wedge_sign,
+          ..CompoundParams::new()
+        });
+      }
+      for &mask_type in [false, true].iter() {
+        compounds.push(CompoundParams {
+          compound_type: CompoundType::COMPOUND_DIFFWTD,
+          mask_type,
+          ..CompoundParams::new()
+        });
+      }
+    }
+    for &compound in compounds.iter() {
+      luma_rdo(luma_mode, fs, cw, &mut best, mvs, ref_frames, MotionMode::SIMPLE_TRANSLATION,
+               interp_filter, compound, &mode_set_chroma, false, mode_contexts[i], &mv_stacks[i], false,
+               PaletteParams::new());
+    }
+  }
+
+  // Search the inter-intra predictions of the best single reference mode
+  if !best.mode_luma.is_intra() && best.motion_mode == MotionMode::SIMPLE_TRANSLATION &&
+    cw.interintra_allowed(fi, bsize, best.ref_frames) {
+    let i = ref_frames_set.iter().position(|&r| r == best.ref_frames).unwrap();
+    let (luma_mode, ref_frames, mvs, interp_filter) =
+      (best.mode_luma, best.ref_frames, best.mvs, best.interp_filter);
+    let mode_set_chroma = vec![luma_mode];
+    let interintra_modes = [
+      InterIntraMode::II_DC_PRED,
+      InterIntraMode::II_V_PRED,
+      InterIntraMode::II_H_PRED,
+      InterIntraMode::II_SMOOTH_PRED
+    ];
+    for &interintra_mode in interintra_modes.iter() {
+      luma_rdo(luma_mode, fs, cw, &mut best, mvs, ref_frames, MotionMode::SIMPLE_TRANSLATION,
+               interp_filter, CompoundParams::new_interintra(interintra_mode, None),
+               &mode_set_chroma, false, mode_contexts[i], &mv_stacks[i], false,
+               PaletteParams::new());
+    }
+    // Wedges are tried with the intra mode of the best smooth blend
+    let interintra_mode = if best.compound.interintra {
+      best.compound.interintra_mode
+    } else {
+      InterIntraMode::II_DC_PRED
+    };
+    let wedge_index = rdo_interintra_wedge_selection(
+      fi, fs, bsize, bo, luma_mode, ref_frames, mvs, interp_filter, interintra_mode
+    );
+    luma_rdo(luma_mode, fs, cw, &mut best, mvs, ref_frames, MotionMode::SIMPLE_TRANSLATION,
+             interp_filter, CompoundParams::new_interintra(interintra_mode, Some(wedge_index)),
+             &mode_set_chroma, false, mode_contexts[i], &mv_stacks[i], false,
+             PaletteParams::new());
+  }
+
+  // Skip mode: compound prediction from the nearest candidate of the skip
+  // mode references, without residual
+  if cw.skip_mode_allowed(fi, bsize) {
+    let ref_frames = fi.skip_mode_frames;
+    let mut mv_stack: Vec<CandidateMV> = Vec::new();
+    let mode_context = cw.find_mvrefs(bo, ref_frames, &mut mv_stack, bsize, fi, true);
+    let mvs = [mv_stack[0].this_mv, mv_stack[0].comp_mv];
+    let luma_mode = PredictionMode::NEAREST_NEARESTMV;
+    luma_rdo(luma_mode, fs, cw, &mut best, mvs, ref_frames, MotionMode::SIMPLE_TRANSLATION,
+             fi.default_interp_filter(), CompoundParams::new(), &[luma_mode], false,
+             mode_context, &mv_stack, true,
+             PaletteParams::new());
+  }
+
+  // IntraBC: copy an exact match from the already coded part of the frame
+  if fi.allow_intrabc {
+    let ref_frames = [INTRA_FRAME, NONE_FRAME];
+    let mut mv_stack: Vec<CandidateMV> = Vec::new();
+    cw.find_mvrefs(bo, ref_frames, &mut mv_stack, bsize, fi, false);
+    if let Some(dv) = intrabc_search(fi, fs, bo, bsize, ref_dv(fi, bo, &mv_stack)) {
+      let luma_mode = PredictionMode::NEWMV;
+      luma_rdo(luma_mode, fs, cw, &mut best, [dv, MotionVector { row: 0, col: 0 }], ref_frames,
+               MotionMode::SIMPLE_TRANSLATION, [FilterMode::BILINEAR; 2], CompoundParams::new(),
+               &[luma_mode], false, 0, &mv_stack, false,
+               PaletteParams::new());
+    }
+  }
+
   if !best.skip {
     let tx_size = bsize.tx_size();
 
     // Reduce number of prediction modes at higher speed levels
-    let num_modes_rdo = if (fi.frame_type == FrameType::KEY
+    let num_modes_rdo = if fi.allow_screen_content_tools != 0 {
+      RAV1E_INTRA_MODES_SCREEN.len()
+    } else if (fi.frame_type == FrameType::KEY
       && fi.config.speed_settings.prediction_modes >= PredictionModesSetting::ComplexKeyframes)
       || (fi.frame_type == FrameType::INTER && fi.config.speed_settings.prediction_modes >= PredictionModesSetting::ComplexAll)
     {
@@ -597,12 +954,16 @@ pub fn rdo_mode_decision(fi: &FrameInvariants, fs: &mut FrameState,
       3
     };
 
-    let intra_mode_set = RAV1E_INTRA_MODES;
+    let intra_mode_set = if fi.allow_screen_content_tools != 0 {
+      RAV1E_INTRA_MODES_SCREEN
+    } else {
+      RAV1E_INTRA_MODES
+    };
     let mut sads = {
       let edge_buf = {
         let rec = &mut fs.rec.planes[0];
         let po = bo.plane_offset(&rec.cfg);
-        get_intra_edges(&rec.slice(&po), tx_size, fi.sequence.bit_depth, 0, fi.w_in_b, fi.h_in_b, None)
+        get_intra_edges(&rec.slice(&po), tx_size, fi.sequence.bit_depth, 0, fi.w_in_b, fi.h_in_b, fi.sequence.sb_size(), bsize, cw.bc.at(bo).partition, None)
       };
       intra_mode_set.iter().map(|&luma_mode| {
         let rec = &mut fs.rec.planes[0];
@@ -641,9 +1002,21 @@ pub fn rdo_mode_decision(fi: &FrameInvariants, fs: &mut FrameState,
       if is_chroma_block && luma_mode != PredictionMode::DC_PRED {
         mode_set_chroma.push(PredictionMode::DC_PRED);
       }
-      luma_rdo(luma_mode, fs, cw, &mut best, mvs, ref_frames, &mode_set_chroma, true,
-               0, &Vec::new());
+      luma_rdo(luma_mode, fs, cw, &mut best, mvs, ref_frames, MotionMode::SIMPLE_TRANSLATION,
+               fi.default_interp_filter(), CompoundParams::new(), &mode_set_chroma, true, 0,
+               &Vec::new(), false,
+               PaletteParams::new());
     });
+
+    if palette_search {
+      if let Some(palette) = luma_palette(fi, fs, bo, bsize) {
+        let luma_mode = PredictionMode::DC_PRED;
+        luma_rdo(luma_mode, fs, cw, &mut best, [MotionVector { row: 0, col: 0 }; 2],
+                 [INTRA_FRAME, NONE_FRAME], MotionMode::SIMPLE_TRANSLATION,
+                 fi.default_interp_filter(), CompoundParams::new(), &[luma_mode], true, 0,
+                 &Vec::new(), false, palette);
+      }
+    }
   }
 
   if best.mode_luma.is_intra() && is_chroma_block && bsize.cfl_allowed() {
@@ -663,6 +1036,7 @@ pub fn rdo_mode_decision(fi: &FrameInvariants, fs: &mut FrameState,
       best.tx_type,
       false,
       CFLParams::new(),
+      best.palette,
       true,
       false
     );
@@ -671,7 +1045,7 @@ pub fn rdo_mode_decision(fi: &FrameInvariants, fs: &mut FrameState,
       let mut wr: &mut dyn Writer = &mut WriterCounter::new();
       let tell = wr.tell_frac();
 
-      encode_block_a(&fi.sequence, fs, cw, wr, bsize, bo, best.skip);
+      encode_block_a(fi, fs, cw, wr, bsize, bo, best.skip, best.skip_mode);
       encode_block_b(
         fi,
         fs,
@@ -681,12 +1055,18 @@ pub fn rdo_mode_decision(fi: &FrameInvariants, fs: &mut FrameState,
         chroma_mode,
         best.ref_frames,
         best.mvs,
+        best.motion_mode,
+        best.interp_filter,
+        best.compound,
+        best.palette,
         bsize,
         bo,
         best.skip,
+        best.skip_mode,
         cfl,
         best.tx_size,
         best.tx_type,
+        best.tx_partition,
         0,
         &Vec::new(),
         false // For CFL, luma should be always reconstructed.
@@ -717,7 +1097,56 @@ pub fn rdo_mode_decision(fi: &FrameInvariants, fs: &mut FrameState,
     }
   }
 
+  if palette_search && best.mode_luma.is_intra() && is_chroma_block {
+    if let Some(palette) = chroma_palette(fi, fs, bo, bsize, best.palette) {
+      let chroma_mode = PredictionMode::DC_PRED;
+      let cw_checkpoint = cw.checkpoint();
+      let wr: &mut dyn Writer = &mut WriterCounter::new();
+      let tell = wr.tell_frac();
+
+      encode_block_a(fi, fs, cw, wr, bsize, bo, best.skip, best.skip_mode);
+      encode_block_b(
+        fi,
+        fs,
+        cw,
+        wr,
+        best.mode_luma,
+        chroma_mode,
+        best.ref_frames,
+        best.mvs,
+        best.motion_mode,
+        best.interp_filter,
+        best.compound,
+        palette,
+        bsize,
+        bo,
+        best.skip,
+        best.skip_mode,
+        CFLParams::new(),
+        best.tx_size,
+        best.tx_type,
+        best.tx_partition,
+        0,
+        &Vec::new(),
+        false
+      );
+
+      let cost = wr.tell_frac() - tell;
+      let rd = compute_rd_cost(fi, fs, w, h, is_chroma_block, bo, cost, false);
+
+      if rd < best.rd {
+        best.rd = rd;
+        best.mode_chroma = chroma_mode;
+        best.cfl_params = CFLParams::new();
+        best.palette = palette;
+      }
+
+      cw.rollback(&cw_checkpoint);
+    }
+  }
+
   cw.bc.set_mode(bo, bsize, best.mode_luma);
+  cw.bc.set_palette(bo, bsize, best.palette);
   cw.bc.set_ref_frames(bo, bsize, best.ref_frames);
   cw.bc.set_motion_vectors(bo, bsize, best.mvs);
 
@@ -734,14 +1163,115 @@ pub fn rdo_mode_decision(fi: &FrameInvariants, fs: &mut FrameState,
       pred_cfl_params: best.cfl_params,
       ref_frames: best.ref_frames,
       mvs: best.mvs,
+      motion_mode: best.motion_mode,
+      interp_filter: best.interp_filter,
+      compound: best.compound,
+      palette: best.palette,
       rd_cost: best.rd,
       skip: best.skip,
+      skip_mode: best.skip_mode,
       tx_size: best.tx_size,
       tx_type: best.tx_type,
+      tx_partition: best.tx_partition,
     }]
   }
 }
 
+// Picks the wedge whose blend of the two luma predictions of a compound
+// block is closest to the source
+fn rdo_wedge_selection(
+  fi: &FrameInvariants, fs: &FrameState, bsize: BlockSize, bo: &BlockOffset,
+  luma_mode: PredictionMode, ref_frames: [usize; 2], mvs: [MotionVector; 2],
+  interp_filter: [FilterMode; 2]
+) -> (usize, bool) {
+  let w = bsize.width();
+  let h = bsize.height();
+  let po = bo.plane_offset(&fs.input.planes[0].cfg);
+  let single_mode = if luma_mode == PredictionMode::GLOBAL_GLOBALMV {
+    PredictionMode::GLOBALMV
+  } else {
+    PredictionMode::NEWMV
+  };
+  let preds: Vec<Plane> = (0..2).map(|i| {
+    let mut pred = Plane::new(w, h, 0, 0, 0, 0);
+    single_mode.predict_inter(
+      fi, 0, &po, &mut pred.mut_slice(&PlaneOffset { x: 0, y: 0 }), w, h,
+      [ref_frames[i], NONE_FRAME], [mvs[i], MotionVector { row: 0, col: 0 }],
+      interp_filter, CompoundParams::new(), &mut [], None
+    );
+    pred
+  }).collect();
+
+  let src = &fs.input.planes[0];
+  let mut best = (0, false);
+  let mut best_sse = std::u64::MAX;
+  for wedge_index in 0..16 {
+    for &wedge_sign in [false, true].iter() {
+      let mask = get_wedge_mask(bsize, wedge_sign, wedge_index);
+      let mut sse = 0;
+      for y in 0..h {
+        for x in 0..w {
+          let m = mask[y * w + x] as i32;
+          let pred = (preds[0].p(x, y) as i32 * m
+            + preds[1].p(x, y) as i32 * (64 - m) + 32) >> 6;
+          let diff = src.p(po.x as usize + x, po.y as usize + y) as i32 - pred;
+          sse += (diff * diff) as u64;
+        }
+      }
+      if sse < best_sse {
+        best_sse = sse;
+        best = (wedge_index, wedge_sign);
+      }
+    }
+  }
+  best
+}
+
+// Picks the wedge whose blend of the luma inter and intra predictions of an
+// inter-intra block is closest to the source
+fn rdo_interintra_wedge_selection(
+  fi: &FrameInvariants, fs: &mut FrameState, bsize: BlockSize, bo: &BlockOffset,
+  luma_mode: PredictionMode, ref_frames: [usize; 2], mvs: [MotionVector; 2],
+  interp_filter: [FilterMode; 2], interintra_mode: InterIntraMode
+) -> usize {
+  let w = bsize.width();
+  let h = bsize.height();
+  let po = bo.plane_offset(&fs.input.planes[0].cfg);
+  let mut inter = Plane::new(w, h, 0, 0, 0, 0);
+  luma_mode.predict_inter(
+    fi, 0, &po, &mut inter.mut_slice(&PlaneOffset { x: 0, y: 0 }), w, h,
+    ref_frames, mvs, interp_filter, CompoundParams::new(), &mut [], None
+  );
+
+  // The intra prediction is made in place, like the final one
+  let rec = &mut fs.rec.planes[0];
+  let tx_size = bsize.tx_size();
+  let mode = interintra_mode.intra_mode();
+  let edge_buf = get_intra_edges(
+    &rec.slice(&po), tx_size, fi.sequence.bit_depth, 0, fi.w_in_b, fi.h_in_b,
+    fi.sequence.sb_size(), bsize, PartitionType::PARTITION_NONE, Some(mode)
+  );
+  mode.predict_intra(
+    &mut rec.mut_slice(&po), tx_size, fi.sequence.bit_depth, &[0i16; 2], 0, &edge_buf
+  );
+
+  let src = &fs.input.planes[0];
+  (0..16).min_by_key(|&wedge_index| {
+    let mask = get_wedge_mask(bsize, false, wedge_index);
+    let mut sse = 0;
+    for y in 0..h {
+      for x in 0..w {
+        let m = mask[y * w + x] as i32;
+        let (px, py) = (po.x as usize + x, po.y as usize + y);
+        let pred = (rec.p(px, py) as i32 * m + inter.p(x, y) as i32 * (64 - m) + 32) >> 6;
+        let diff = src.p(px, py) as i32 - pred;
+        sse += (diff * diff) as u64;
+      }
+    }
+    sse
+  }).unwrap()
+}
+
 pub fn rdo_cfl_alpha(
   fs: &mut FrameState, bo: &BlockOffset, bsize: BlockSize, bit_depth: usize,
   chroma_sampling: ChromaSampling) -> Option<CFLParams> {
@@ -754,9 +1284,11 @@ pub fn rdo_cfl_alpha(
       let rec = &mut fs.rec.planes[p];
       let input = &fs.input.planes[p];
       let po = bo.plane_offset(&fs.input.planes[p].cfg);
+      let PlaneConfig { xdec, ydec, .. } = input.cfg;
+      let plane_bsize = get_plane_block_size(bsize, xdec, ydec);
       (-16i16..17i16)
         .min_by_key(|&alpha| {
-          let edge_buf = get_intra_edges(&rec.slice(&po), uv_tx_size, bit_depth, p, 0, 0, Some(PredictionMode::UV_CFL_PRED));
+          let edge_buf = get_intra_edges(&rec.slice(&po), uv_tx_size, bit_depth, p, 0, 0, BlockSize::BLOCK_64X64, plane_bsize, PartitionType::PARTITION_NONE, Some(PredictionMode::UV_CFL_PRED));
           PredictionMode::UV_CFL_PRED.predict_intra(
             &mut rec.mut_slice(&po),
             uv_tx_size,
@@ -784,8 +1316,10 @@ pub fn rdo_cfl_alpha(
 // RDO-based transform type decision
 pub fn rdo_tx_type_decision(
   fi: &FrameInvariants, fs: &mut FrameState, cw: &mut ContextWriter,
-  mode: PredictionMode, ref_frames: [usize; 2], mvs: [MotionVector; 2], bsize: BlockSize, bo: &BlockOffset, tx_size: TxSize,
-  tx_set: TxSet) -> TxType {
+  mode: PredictionMode, ref_frames: [usize; 2], mvs: [MotionVector; 2], motion_mode: MotionMode,
+  interp_filter: [FilterMode; 2], compound: CompoundParams, palette: PaletteParams,
+  bsize: BlockSize, bo: &BlockOffset, tx_size: TxSize, tx_partition: TxPartition, tx_set: TxSet
+) -> (TxType, f64) {
   let mut best_type = TxType::DCT_DCT;
   let mut best_rd = std::f64::MAX;
 
@@ -807,19 +1341,20 @@ pub fn rdo_tx_type_decision(
     }
 
     if is_inter {
-      motion_compensate(fi, fs, cw, mode, ref_frames, mvs, bsize, bo, true);
+      motion_compensate(fi, fs, cw, mode, ref_frames, mvs, motion_mode, interp_filter, compound, bsize, bo,
+                        true);
     }
 
     let mut wr: &mut dyn Writer = &mut WriterCounter::new();
     let tell = wr.tell_frac();
     let tx_dist = if is_inter {
       write_tx_tree(
-        fi, fs, cw, wr, mode, bo, bsize, tx_size, tx_type, false, true, true
+        fi, fs, cw, wr, mode, bo, bsize, tx_size, tx_type, tx_partition, false, true, true
       )
     }  else {
       let cfl = CFLParams::new(); // Unused
       write_tx_blocks(
-        fi, fs, cw, wr, mode, mode, bo, bsize, tx_size, tx_type, false, cfl, true, true
+        fi, fs, cw, wr, mode, mode, bo, bsize, tx_size, tx_type, false, cfl, palette, true, true
       )
     };
 
@@ -859,7 +1394,7 @@ pub fn rdo_tx_type_decision(
 
   assert!(best_rd >= 0_f64);
 
-  best_type
+  (best_type, best_rd)
 }
 
 pub fn get_sub_partitions<'a>(four_partitions: &[&'a BlockOffset; 4],
@@ -911,10 +1446,64 @@ pub fn get_sub_partitions_with_border_check<'a>(four_partitions: &[&'a BlockOffs
   partitions
 }
 
+// Index into the subsampled ME results of a superblock: 0 for the whole
+// superblock, 1 to 4 for its quadrants in raster order
+pub fn get_pmv_idx(fi: &FrameInvariants, bsize: BlockSize, bo: &BlockOffset) -> usize {
+  let half_sb_log2 = fi.sequence.sb_size_log2() - 1;
+  if bsize.width_log2() > half_sb_log2 || bsize.height_log2() > half_sb_log2 {
+    0
+  } else {
+    let half_sb_mi = 1 << (half_sb_log2 - MI_SIZE_LOG2);
+    ((bo.x & half_sb_mi) != 0) as usize + 2 * ((bo.y & half_sb_mi) != 0) as usize + 1
+  }
+}
+
+// Whether an extended `partition` of a `bsize` block is searched
+pub fn ext_partition_allowed(
+  fi: &FrameInvariants, fs: &FrameState, bsize: BlockSize, partition: PartitionType
+) -> bool {
+  let partition_types = fi.config.speed_settings.partition_types;
+  let enabled = match partition {
+    PARTITION_HORZ_4 | PARTITION_VERT_4 => partition_types >= PartitionTypesSetting::FourWay,
+    _ => partition_types >= PartitionTypesSetting::Extended
+  };
+  let PlaneConfig { xdec, ydec, .. } = fs.input.planes[1].cfg;
+
+  // Only the 4:1 blocks share chroma blocks with their neighbours
+  enabled && bsize.is_sqr() && bsize >= BlockSize::BLOCK_16X16
+    && bsize.subsize(partition) != BlockSize::BLOCK_INVALID
+    && bsize.subsize(PARTITION_SPLIT) >= fi.min_partition_size
+    && partition.sub_blocks(&BlockOffset { x: 0, y: 0 }, bsize).iter().all(|&(_, subsize)| {
+      get_plane_block_size(subsize, xdec, ydec) != BlockSize::BLOCK_INVALID
+    })
+}
+
+// Mode decisions for the blocks of an extended partition, which are not
+// partitioned further. The blocks are coded as they are decided.
+pub fn rdo_ext_partition(
+  fi: &FrameInvariants, fs: &mut FrameState,
+  cw: &mut ContextWriter, w: &mut dyn Writer,
+  bsize: BlockSize, bo: &BlockOffset, partition: PartitionType,
+  pmvs: &[[Option<MotionVector>; REF_FRAMES]; 5]
+) -> Vec<RDOPartitionOutput> {
+  partition.sub_blocks(bo, bsize).into_iter().map(|(sub_bo, subsize)| {
+    let pmv_idx = get_pmv_idx(fi, subsize, &sub_bo);
+
+    cw.bc.set_partition(&sub_bo, subsize, partition);
+    let mode_decision =
+      rdo_mode_decision(fi, fs, cw, subsize, &sub_bo, &pmvs[pmv_idx], true)
+        .part_modes[0]
+        .clone();
+
+    encode_block_with_modes(fi, fs, cw, w, subsize, &sub_bo, &mode_decision);
+    mode_decision
+  }).collect()
+}
+
 // RDO-based single level partitioning decision
 pub fn rdo_partition_decision(
   fi: &FrameInvariants, fs: &mut FrameState,
-  cw: &mut ContextWriter, w_pre_cdef: &mut dyn Writer, w_post_cdef: &mut dyn Writer,
+  cw: &mut ContextWriter, w: &mut dyn Writer,
   bsize: BlockSize, bo: &BlockOffset,
   cached_block: &RDOOutput, pmvs: &[[Option<MotionVector>; REF_FRAMES]; 5],
   partition_types: &Vec<PartitionType>,
@@ -934,18 +1523,11 @@ pub fn rdo_partition_decision(
 
     match partition {
       PartitionType::PARTITION_NONE => {
-        if bsize > BlockSize::BLOCK_64X64 {
-          continue;
-        }
-
-        let pmv_idx = if bsize > BlockSize::BLOCK_32X32 {
-          0
-        } else {
-          ((bo.x & 32) >> 5) + ((bo.y & 32) >> 4) + 1
-        };
+        let pmv_idx = get_pmv_idx(fi, bsize, bo);
 
         let spmvs = &pmvs[pmv_idx];
 
+        cw.bc.set_partition(bo, bsize, partition);
         let mode_decision = rdo_mode_decision(fi, fs, cw, bsize, bo, spmvs, false).part_modes[0].clone();
         child_modes.push(mode_decision);
       }
@@ -973,19 +1555,13 @@ pub fn rdo_partition_decision(
         let partitions = get_sub_partitions_with_border_check(&four_partitions, partition, fi, subsize);
 
         let pmv_idxs = partitions.iter().map(|&offset| {
-          if subsize.greater_than(BlockSize::BLOCK_32X32) {
-              0
-          } else {
-              ((offset.x & 32) >> 5) + ((offset.y & 32) >> 4) + 1
-          }
+          get_pmv_idx(fi, subsize, offset)
         }).collect::<Vec<_>>();
 
         let cw_checkpoint = cw.checkpoint();
-        let w_pre_checkpoint = w_pre_cdef.checkpoint();
-        let w_post_checkpoint = w_post_cdef.checkpoint();
+        let w_checkpoint = w.checkpoint();
 
         if bsize >= BlockSize::BLOCK_8X8 {
-          let w: &mut dyn Writer = if cw.bc.cdef_coded {w_post_cdef} else {w_pre_cdef};
           let tell = w.tell_frac();
           cw.write_partition(w, bo, partition, bsize);
           cost = (w.tell_frac() - tell) as f64 * get_lambda(fi)/ ((1 << OD_BITRES) as f64);
@@ -995,6 +1571,7 @@ pub fn rdo_partition_decision(
           partitions
             .iter().zip(pmv_idxs)
             .map(|(&offset, pmv_idx)| {
+              cw.bc.set_partition(offset, subsize, partition);
               let mode_decision =
               rdo_mode_decision(fi, fs, cw, subsize, &offset,
                 &pmvs[pmv_idx], true)
@@ -1002,18 +1579,34 @@ pub fn rdo_partition_decision(
                 .clone();
 
                 if subsize >= BlockSize::BLOCK_8X8 && subsize.is_sqr() {
-                  let w: &mut dyn Writer = if cw.bc.cdef_coded {w_post_cdef} else {w_pre_cdef};
                   cw.write_partition(w, offset, PartitionType::PARTITION_NONE, subsize);
                 }
 
-                encode_block_with_modes(fi, fs, cw, w_pre_cdef, w_post_cdef, subsize,
+                encode_block_with_modes(fi, fs, cw, w, subsize,
                                     offset, &mode_decision);
                 mode_decision
             }).collect::<Vec<_>>()
         );
         cw.rollback(&cw_checkpoint);
-        w_pre_cdef.rollback(&w_pre_checkpoint);
-        w_post_cdef.rollback(&w_post_checkpoint);
+        w.rollback(&w_checkpoint);
+      }
+      PARTITION_HORZ_A |
+      PARTITION_HORZ_B |
+      PARTITION_VERT_A |
+      PARTITION_VERT_B |
+      PARTITION_HORZ_4 |
+      PARTITION_VERT_4 => {
+        let cw_checkpoint = cw.checkpoint();
+        let w_checkpoint = w.checkpoint();
+
+        let tell = w.tell_frac();
+        cw.write_partition(w, bo, partition, bsize);
+        cost = (w.tell_frac() - tell) as f64 * get_lambda(fi)/ ((1 << OD_BITRES) as f64);
+
+        child_modes.extend(rdo_ext_partition(fi, fs, cw, w, bsize, bo, partition, pmvs));
+
+        cw.rollback(&cw_checkpoint);
+        w.rollback(&w_checkpoint);
       }
       _ => {
         assert!(false);
@@ -1038,119 +1631,264 @@ pub fn rdo_partition_decision(
   }
 }
 
-pub fn rdo_cdef_decision(sbo: &SuperBlockOffset, fi: &FrameInvariants,
-                         fs: &FrameState, cw: &mut ContextWriter) -> u8 {
-    // FIXME: 128x128 SB support will break this, we need FilterBlockOffset etc.
-    // Construct a single-superblock-sized frame to test-filter into
-    let sbo_0 = SuperBlockOffset { x: 0, y: 0 };
-    let bc = &mut cw.bc;
-    let mut cdef_output = Frame {
-        planes: [
-            Plane::new(64 >> fs.rec.planes[0].cfg.xdec, 64 >> fs.rec.planes[0].cfg.ydec,
-                       fs.rec.planes[0].cfg.xdec, fs.rec.planes[0].cfg.ydec, 0, 0),
-            Plane::new(64 >> fs.rec.planes[1].cfg.xdec, 64 >> fs.rec.planes[1].cfg.ydec,
-                       fs.rec.planes[1].cfg.xdec, fs.rec.planes[1].cfg.ydec, 0, 0),
-            Plane::new(64 >> fs.rec.planes[2].cfg.xdec, 64 >> fs.rec.planes[2].cfg.ydec,
-                       fs.rec.planes[2].cfg.xdec, fs.rec.planes[2].cfg.ydec, 0, 0),
-        ]
-    };
-    // Construct a padded input
-    let mut rec_input = Frame {
-        planes: [
-            Plane::new((64 >> fs.rec.planes[0].cfg.xdec)+4, (64 >> fs.rec.planes[0].cfg.ydec)+4,
-                       fs.rec.planes[0].cfg.xdec, fs.rec.planes[0].cfg.ydec, 0, 0),
-            Plane::new((64 >> fs.rec.planes[1].cfg.xdec)+4, (64 >> fs.rec.planes[1].cfg.ydec)+4,
-                       fs.rec.planes[1].cfg.xdec, fs.rec.planes[1].cfg.ydec, 0, 0),
-            Plane::new((64 >> fs.rec.planes[2].cfg.xdec)+4, (64 >> fs.rec.planes[2].cfg.ydec)+4,
-                       fs.rec.planes[2].cfg.xdec, fs.rec.planes[2].cfg.ydec, 0, 0),
-        ]
-    };
-    // Copy reconstructed data into padded input
-    for p in 0..3 {
-        let xdec = fs.rec.planes[p].cfg.xdec;
-        let ydec = fs.rec.planes[p].cfg.ydec;
-        let h = fi.padded_h as isize >> ydec;
-        let w = fi.padded_w as isize >> xdec;
-        let offset = sbo.plane_offset(&fs.rec.planes[p].cfg);
-        for y in 0..(64>>ydec)+4 {
-            let mut rec_slice = rec_input.planes[p].mut_slice(&PlaneOffset {x:0, y:y});
-            let mut rec_row = rec_slice.as_mut_slice();
-            if offset.y+y < 2 || offset.y+y >= h+2 {
-                // above or below the frame, fill with flag
-                for x in 0..(64>>xdec)+4 { rec_row[x] = CDEF_VERY_LARGE; }
-            } else {
-                let mut in_slice = fs.rec.planes[p].slice(&PlaneOffset {x:0, y:offset.y+y-2});
-                let mut in_row = in_slice.as_slice();
-                // are we guaranteed to be all in frame this row?
-                if offset.x < 2 || offset.x+(64>>xdec)+2 >= w {
-                    // No; do it the hard way.  off left or right edge, fill with flag.
-                    for x in 0..(64>>xdec)+4 {
-                        if offset.x+x >= 2 && offset.x+x < w+2 {
-                            rec_row[x as usize] = in_row[(offset.x+x-2) as usize]
-                        } else {
-                            rec_row[x as usize] = CDEF_VERY_LARGE;
+/// Distortion of a group of filter blocks sharing a cdef_idx, after
+/// filtering with each luma and chroma strength
+struct CdefGroupStats {
+    y: [u64; CDEF_STRENGTHS],
+    uv: [u64; CDEF_STRENGTHS]
+}
+
+// First stage of the CDEF search: the distortion of every group for all
+// the strengths with the given damping. The groups lie in the rows of
+// filter blocks starting at `fby`, of which `strips` are filled by
+// `cdef_fill_strip` and `dirs` are the directions.
+fn cdef_group_stats(fi: &FrameInvariants, input: &Frame, strips: &[Frame], fby: usize,
+                    dirs: &[CdefDirections], bc: &BlockContext,
+                    groups: &[Vec<FilterBlockOffset>], strengths: &[usize],
+                    damping: u8) -> Vec<CdefGroupStats> {
+    let fb_width = (fi.padded_w + 63) / 64;
+    let bit_depth = fi.sequence.bit_depth;
+    let mut fb_groups = vec![None; fb_width * strips.len()];
+    for (g, fbos) in groups.iter().enumerate() {
+        for fbo in fbos {
+            fb_groups[(fbo.y - fby) * fb_width + fbo.x] = Some(g);
+        }
+    }
+
+    // The filter blocks are test-filtered a row at a time, from a strip
+    // into another one
+    let mut cdef_output = cdef_strip(fi, input);
+    let mut stats: Vec<CdefGroupStats> = groups.iter().map(|_| {
+        CdefGroupStats { y: [0; CDEF_STRENGTHS], uv: [0; CDEF_STRENGTHS] }
+    }).collect();
+    for (i, strip) in strips.iter().enumerate() {
+        for fbx in 0..fb_width {
+            let g = match fb_groups[i * fb_width + fbx] {
+                Some(g) => g,
+                None => continue
+            };
+            let fbo = FilterBlockOffset { x: fbx, y: fby + i };
+            let strip_fbo = FilterBlockOffset { x: fbx, y: 0 };
+            let cdef_dirs = &dirs[i * fb_width + fbx];
+            for p in 0..3 {
+                for &strength in strengths {
+                    cdef_filter_superblock_plane(strip, &mut cdef_output, bc, &strip_fbo, &fbo,
+                                                 &strip_fbo, p, strength as u8, damping,
+                                                 bit_depth, cdef_dirs);
+                    // Rate is constant, compute just distortion
+                    let in_plane = &input.planes[p];
+                    let out_plane = &cdef_output.planes[p];
+                    let xdec = in_plane.cfg.xdec;
+                    let ydec = in_plane.cfg.ydec;
+                    let mut dist = 0;
+                    for by in 0..8 {
+                        for bx in 0..8 {
+                            if cdef_block_skipped(bc, &fbo, bx, by) {
+                                continue;
+                            }
+                            let po = fbo.block_offset(bx<<1, by<<1).plane_offset(&in_plane.cfg);
+                            let out_po = strip_fbo.block_offset(bx<<1, by<<1).plane_offset(&out_plane.cfg);
+                            let in_slice = in_plane.slice(&po);
+                            let out_slice = out_plane.slice(&out_po);
+                            if p == 0 {
+                                dist += cdef_dist_wxh_8x8(&in_slice, &out_slice, bit_depth);
+                            } else {
+                                let sse = sse_wxh(&in_slice, &out_slice, 8>>xdec, 8>>ydec);
+                                dist += weight_plane_dist(fi, p, sse);
+                            }
                         }
                     }
-                }  else  {
-                    // Yes, do it the easy way: just copy
-                    rec_row[0..(64>>xdec)+4].copy_from_slice(&in_row[(offset.x-2) as usize..(offset.x+(64>>xdec)+2) as usize]);
+                    if p == 0 {
+                        stats[g].y[strength] += dist;
+                    } else {
+                        stats[g].uv[strength] += dist;
+                    }
                 }
             }
         }
     }
+    stats
+}
 
-    // RDO comparisons
-    let mut best_index: u8 = 0;
-    let mut best_err: u64 = 0;
-    let cdef_dirs = cdef_analyze_superblock(&mut rec_input, bc, &sbo_0, &sbo, fi.sequence.bit_depth);
-    for cdef_index in 0..(1<<fi.cdef_bits) {
-        //for p in 0..3 {
-        //    for i in 0..cdef_output.planes[p].data.len() { cdef_output.planes[p].data[i] = CDEF_VERY_LARGE; }
-        //}
-        // TODO: Don't repeat find_direction over and over; split filter_superblock to run it separately
-        cdef_filter_superblock(fi, &mut rec_input, &mut cdef_output,
-                               bc, &sbo_0, &sbo, cdef_index, &cdef_dirs);
-
-        // Rate is constant, compute just distortion
-        // Computation is block by block, paying attention to skip flag
-
-        // Each direction block is 8x8 in y, potentially smaller if subsampled in chroma
-        // We're dealing only with in-frmae and unpadded planes now
-        let mut err:u64 = 0;
-        for by in 0..8 {
-            for bx in 0..8 {
-                let bo = sbo.block_offset(bx<<1, by<<1);
-                if bo.x < bc.cols && bo.y < bc.rows {
-                    let skip = bc.at(&bo).skip;
-                    if !skip {
-                        for p in 0..3 {
-                            let mut in_plane = &fs.input.planes[p];
-                            let in_po = sbo.block_offset(bx<<1, by<<1).plane_offset(&in_plane.cfg);
-                            let in_slice = in_plane.slice(&in_po);
-
-                            let mut out_plane = &mut cdef_output.planes[p];
-                            let out_po = sbo_0.block_offset(bx<<1, by<<1).plane_offset(&out_plane.cfg);
-                            let out_slice = &out_plane.slice(&out_po);
-
-                            let xdec = in_plane.cfg.xdec;
-                            let ydec = in_plane.cfg.ydec;
-
-                            if p==0 {
-                                err += cdef_dist_wxh_8x8(&in_slice, &out_slice, fi.sequence.bit_depth);
-                            } else {
-                                err += sse_wxh(&in_slice, &out_slice, 8>>xdec, 8>>ydec);
-                            }
-                        }
+// Second stage of the CDEF search: the strength pairs of each cdef_bits
+// and their total distortion. The pairs of each set are those of the set
+// for one fewer bit, followed by pairs added greedily and then refined one
+// luma or chroma strength at a time, so that the sets for fewer bits are
+// prefixes and more bits never do worse.
+fn cdef_pick_strengths(stats: &[CdefGroupStats],
+                       strengths: &[usize]) -> Vec<(Vec<(usize, usize)>, u64)> {
+    let dist = |g: &CdefGroupStats, pair: (usize, usize)| g.y[pair.0] + g.uv[pair.1];
+    let total = |best: &[u64], pair: (usize, usize)| -> u64 {
+        stats.iter().zip(best).map(|(g, &b)| cmp::min(b, dist(g, pair))).sum()
+    };
+    // Distortion of each group with its best pair of the set but `skip`
+    let best_of = |set: &[(usize, usize)], skip: Option<usize>| -> Vec<u64> {
+        stats.iter().map(|g| {
+            set.iter().enumerate().filter(|&(j, _)| Some(j) != skip)
+                .map(|(_, &pair)| dist(g, pair)).min().unwrap_or(std::u64::MAX)
+        }).collect()
+    };
+
+    let mut set = Vec::with_capacity(CDEF_MAX_STRENGTH_PAIRS);
+    (0..=CDEF_MAX_BITS).map(|bits| {
+        let start = set.len();
+        while set.len() < 1 << bits {
+            let best = best_of(&set, None);
+            let mut best_pair = (0, 0);
+            let mut best_total = std::u64::MAX;
+            for &y in strengths {
+                for &uv in strengths {
+                    let t = total(&best, (y, uv));
+                    if t < best_total {
+                        best_total = t;
+                        best_pair = (y, uv);
                     }
                 }
             }
+            set.push(best_pair);
+        }
+        for _ in 0..4 {
+            let mut changed = false;
+            for k in start..set.len() {
+                let others = best_of(&set, Some(k));
+                let y = *strengths.iter().min_by_key(|&&y| total(&others, (y, set[k].1))).unwrap();
+                let uv = *strengths.iter().min_by_key(|&&uv| total(&others, (y, uv))).unwrap();
+                if total(&others, (y, uv)) < total(&others, set[k]) {
+                    set[k] = (y, uv);
+                    changed = true;
+                }
+            }
+            if !changed {
+                break;
+            }
         }
+        let d = best_of(&set, None).iter().sum();
+        (set.clone(), d)
+    }).collect()
+}
+
+/// Picks the CDEF damping, cdef_bits and strengths of the frame jointly
+/// by RD cost from the distortion of each group of filter blocks sharing
+/// a cdef_idx, in the first rows with any, and returns the cdef_idx of
+/// each group. The groups lie in the rows of filter blocks starting at
+/// `fby`, of which `strips` are filled by `cdef_fill_strip`.
+pub fn rdo_cdef_frame(fi: &mut FrameInvariants, input: &Frame, strips: &[Frame], fby: usize,
+                      dirs: &[CdefDirections], bc: &BlockContext,
+                      groups: &[Vec<FilterBlockOffset>]) -> Vec<u8> {
+    if groups.is_empty() {
+        fi.cdef_bits = 0;
+        fi.cdef_y_strengths = [0; 8];
+        fi.cdef_uv_strengths = [0; 8];
+        return Vec::new();
+    }
 
-        if cdef_index == 0 || err < best_err {
-            best_err = err;
-            best_index = cdef_index;
+    let lambda = get_lambda(fi);
+    let all_strengths: Vec<usize> = (0..CDEF_STRENGTHS).collect();
+    let (strengths, dampings) = if fi.config.speed_settings.fast_cdef {
+        // Stronger damping at lower quality
+        let damping = 3 + (fi.base_q_idx >> 6);
+        (&CDEF_FAST_STRENGTHS[..], damping..damping + 1)
+    } else {
+        (&all_strengths[..], CDEF_MIN_DAMPING..CDEF_MAX_DAMPING + 1)
+    };
+    let mut best_cost = std::f64::MAX;
+    let mut best = (0, 0, Vec::new());
+    let mut best_stats = Vec::new();
+    for damping in dampings {
+        let stats = cdef_group_stats(fi, input, strips, fby, dirs, bc, groups, strengths,
+                                     damping);
+        let mut improved = false;
+        for (bits, (set, dist)) in cdef_pick_strengths(&stats, strengths).into_iter().enumerate() {
+            // cdef_idx of each group and the strengths in the frame header
+            let rate = bits * groups.len() + set.len() * 2 * CDEF_STRENGTH_BITS;
+            let cost = dist as f64 + lambda * rate as f64;
+            if cost < best_cost {
+                best_cost = cost;
+                best = (damping, bits, set);
+                improved = true;
+            }
+        }
+        if improved {
+            best_stats = stats;
         }
+    }
 
+    let (damping, bits, set) = best;
+    fi.cdef_damping = damping;
+    fi.cdef_bits = bits as u8;
+    fi.cdef_y_strengths = [0; 8];
+    fi.cdef_uv_strengths = [0; 8];
+    for (i, &(y, uv)) in set.iter().enumerate() {
+        fi.cdef_y_strengths[i] = y as u8;
+        fi.cdef_uv_strengths[i] = uv as u8;
     }
-    best_index
+    cdef_pick_indices(fi, &best_stats)
+}
+
+/// Picks the cdef_idx of each group of filter blocks in the rows starting
+/// at `fby` among the strengths of the frame, as `rdo_cdef_frame`
+pub fn rdo_cdef_rows(fi: &FrameInvariants, input: &Frame, strips: &[Frame], fby: usize,
+                     dirs: &[CdefDirections], bc: &BlockContext,
+                     groups: &[Vec<FilterBlockOffset>]) -> Vec<u8> {
+    let set_len = 1 << fi.cdef_bits;
+    let mut strengths: Vec<usize> = fi.cdef_y_strengths[..set_len].iter()
+        .chain(&fi.cdef_uv_strengths[..set_len]).map(|&s| s as usize).collect();
+    strengths.sort();
+    strengths.dedup();
+    let stats = cdef_group_stats(fi, input, strips, fby, dirs, bc, groups, &strengths,
+                                 fi.cdef_damping);
+    cdef_pick_indices(fi, &stats)
+}
+
+// The cdef_idx of least distortion of each group
+fn cdef_pick_indices(fi: &FrameInvariants, stats: &[CdefGroupStats]) -> Vec<u8> {
+    let set_len = 1 << fi.cdef_bits;
+    stats.iter().map(|g| {
+        (0..set_len).min_by_key(|&k| {
+            g.y[fi.cdef_y_strengths[k] as usize] + g.uv[fi.cdef_uv_strengths[k] as usize]
+        }).unwrap() as u8
+    }).collect()
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use rand::{ChaChaRng, Rng, SeedableRng};
+
+  #[test]
+  fn cdef_strength_sets() {
+    let mut ra = ChaChaRng::from_seed([0; 32]);
+    let strengths: Vec<usize> = (0..CDEF_STRENGTHS).collect();
+    for &num_groups in &[1, 5, 40] {
+      let stats: Vec<CdefGroupStats> = (0..num_groups).map(|_| {
+        let mut g = CdefGroupStats { y: [0; CDEF_STRENGTHS], uv: [0; CDEF_STRENGTHS] };
+        for s in 0..CDEF_STRENGTHS {
+          g.y[s] = ra.gen_range(1000, 2000);
+          g.uv[s] = ra.gen_range(100, 400);
+        }
+        g
+      }).collect();
+      let sets = cdef_pick_strengths(&stats, &strengths);
+      assert_eq!(sets.len(), CDEF_MAX_BITS + 1);
+      for (bits, &(ref set, dist)) in sets.iter().enumerate() {
+        assert_eq!(set.len(), 1 << bits);
+        // The distortion is that of the best pair of the set for each group
+        let expected: u64 = stats.iter().map(|g| {
+          set.iter().map(|&(y, uv)| g.y[y] + g.uv[uv]).min().unwrap()
+        }).sum();
+        assert_eq!(dist, expected);
+        if bits > 0 {
+          let (ref prev_set, prev_dist) = sets[bits - 1];
+          assert_eq!(&set[..prev_set.len()], &prev_set[..]);
+          assert!(dist <= prev_dist);
+        }
+      }
+      // A single group is filtered with its best strengths whatever the bits
+      if num_groups == 1 {
+        let best = (0..CDEF_STRENGTHS).map(|s| stats[0].y[s]).min().unwrap()
+          + (0..CDEF_STRENGTHS).map(|s| stats[0].uv[s]).min().unwrap();
+        assert!(sets.iter().all(|&(_, dist)| dist == best));
+      }
+    }
+  }
 }
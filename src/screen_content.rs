@@ -0,0 +1,139 @@
+// Copyright (c) 2019, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+use plane::*;
+
+/// Side of the square blocks whose colours are counted
+const ANALYSIS_BLOCK_SIZE: usize = 16;
+/// Blocks with up to this many distinct luma values look like text or
+/// graphics. Blocks with more are counted together in the last bin.
+const SCREEN_MAX_BLOCK_COLORS: usize = 4;
+/// Smallest difference between neighbouring samples, in 8-bit units, that
+/// counts as a sharp edge
+const SHARP_EDGE_THRESHOLD: u16 = 32;
+
+/// Luma statistics that tell screen content, such as text, user interfaces
+/// and graphics, from camera captured content. Screen content has large
+/// areas of few colours separated by sharp edges, where noise and lens blur
+/// give camera content many colours and gradual transitions.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct ScreenContentStats {
+  /// Number of blocks by their count of distinct luma values, with the
+  /// blocks above `SCREEN_MAX_BLOCK_COLORS` in the last bin
+  pub color_counts: [usize; SCREEN_MAX_BLOCK_COLORS + 1],
+  /// Neighbouring sample pairs differing by at least `SHARP_EDGE_THRESHOLD`
+  pub sharp_edges: usize,
+  /// Neighbouring sample pairs with a smaller, non-zero difference
+  pub smooth_edges: usize
+}
+
+impl ScreenContentStats {
+  /// Analyses the complete blocks in the top left `width` x `height`
+  /// samples of `plane`.
+  pub fn new(
+    plane: &Plane, width: usize, height: usize, bit_depth: usize
+  ) -> ScreenContentStats {
+    let mut stats = ScreenContentStats::default();
+    let sharp = SHARP_EDGE_THRESHOLD << (bit_depth - 8);
+    let stride = plane.cfg.stride;
+    let data = plane.data_origin();
+
+    for by in 0..height / ANALYSIS_BLOCK_SIZE {
+      for bx in 0..width / ANALYSIS_BLOCK_SIZE {
+        let (x0, y0) = (bx * ANALYSIS_BLOCK_SIZE, by * ANALYSIS_BLOCK_SIZE);
+        let mut colors = [0u16; SCREEN_MAX_BLOCK_COLORS + 1];
+        let mut n = 0;
+        for y in y0..y0 + ANALYSIS_BLOCK_SIZE {
+          let row = &data[y * stride..];
+          for x in x0..x0 + ANALYSIS_BLOCK_SIZE {
+            let v = row[x];
+            if n <= SCREEN_MAX_BLOCK_COLORS && !colors[..n].contains(&v) {
+              if n < colors.len() {
+                colors[n] = v;
+              }
+              n += 1;
+            }
+            // Differences to the right and bottom neighbours inside the frame
+            let mut edge = |d: u16| {
+              if d >= sharp {
+                stats.sharp_edges += 1;
+              } else if d > 0 {
+                stats.smooth_edges += 1;
+              }
+            };
+            if x + 1 < width {
+              edge((row[x + 1] as i32 - v as i32).abs() as u16);
+            }
+            if y + 1 < height {
+              edge((row[x + stride] as i32 - v as i32).abs() as u16);
+            }
+          }
+        }
+        stats.color_counts[n.min(SCREEN_MAX_BLOCK_COLORS + 1) - 1] += 1;
+      }
+    }
+    stats
+  }
+
+  /// Whether the screen content tools are worth enabling: at least a tenth
+  /// of the blocks have a few colours but are not flat, and sharp edges are
+  /// not outnumbered by gradual ones.
+  pub fn is_screen_content(&self) -> bool {
+    let blocks: usize = self.color_counts.iter().sum();
+    let few_colors: usize =
+      self.color_counts[1..SCREEN_MAX_BLOCK_COLORS].iter().sum();
+    few_colors * 10 > blocks && self.sharp_edges * 2 > self.smooth_edges
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn plane_from_fn<F: Fn(usize, usize) -> u16>(
+    width: usize, height: usize, f: F
+  ) -> Plane {
+    let mut plane = Plane::new(width, height, 0, 0, 0, 0);
+    let stride = plane.cfg.stride;
+    {
+      let data = plane.data_origin_mut();
+      for y in 0..height {
+        for x in 0..width {
+          data[y * stride + x] = f(x, y);
+        }
+      }
+    }
+    plane
+  }
+
+  #[test]
+  fn screen_content_detection() {
+    // Two-colour glyphs on a flat background
+    let text = plane_from_fn(64, 64, |x, y| {
+      if (x % 8 < 2 || y % 16 == 3) && x % 16 < 12 { 20 } else { 235 }
+    });
+    let stats = ScreenContentStats::new(&text, 64, 64, 8);
+    assert_eq!(stats.color_counts, [0, 16, 0, 0, 0]);
+    assert_eq!(stats.smooth_edges, 0);
+    assert!(stats.is_screen_content());
+
+    // Gradient with noise
+    let natural = plane_from_fn(64, 64, |x, y| {
+      (60 + x + 2 * y + (x * 7 + y * 13) % 5) as u16
+    });
+    let stats = ScreenContentStats::new(&natural, 64, 64, 8);
+    assert_eq!(stats.color_counts, [0, 0, 0, 0, 16]);
+    assert_eq!(stats.sharp_edges, 0);
+    assert!(!stats.is_screen_content());
+
+    // The same content at a higher bit depth
+    let text_hbd = plane_from_fn(64, 64, |x, y| text.p(x, y) << 2);
+    assert!(ScreenContentStats::new(&text_hbd, 64, 64, 10).is_screen_content());
+  }
+}
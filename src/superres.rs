@@ -0,0 +1,146 @@
+// Copyright (c) 2018, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+use encoder::Frame;
+use encoder::FrameInvariants;
+use plane::{Plane, PlaneConfig};
+use util::*;
+
+use std::cmp;
+
+pub const SUPERRES_NUM: u8 = 8;
+pub const SUPERRES_DENOM_MIN: u8 = 9;
+pub const SUPERRES_DENOM_BITS: u32 = 3;
+pub const SUPERRES_FILTER_BITS: usize = 6;
+pub const SUPERRES_FILTER_TAPS: usize = 8;
+pub const SUPERRES_FILTER_OFFSET: isize = 3;
+pub const SUPERRES_SCALE_BITS: usize = 14;
+pub const SUPERRES_SCALE_MASK: i64 = (1 << SUPERRES_SCALE_BITS) - 1;
+pub const SUPERRES_EXTRA_BITS: usize = SUPERRES_SCALE_BITS - SUPERRES_FILTER_BITS;
+/// Precision of the upscaling filter taps.
+const FILTER_BITS: usize = 7;
+
+/// Base quantizer index above which the automatic mode starts coding frames
+/// at a reduced width.
+pub const SUPERRES_AUTO_QTHRESH: u8 = 192;
+
+const UPSCALE_FILTER: [[i32; SUPERRES_FILTER_TAPS]; 1 << SUPERRES_FILTER_BITS] = [
+  [0, 0, 0, 128, 0, 0, 0, 0], [0, 0, -1, 128, 2, -1, 0, 0],
+  [0, 1, -3, 127, 4, -2, 1, 0], [0, 1, -4, 127, 6, -3, 1, 0],
+  [0, 2, -6, 126, 8, -3, 1, 0], [0, 2, -7, 125, 11, -4, 1, 0],
+  [-1, 2, -8, 125, 13, -5, 2, 0], [-1, 3, -9, 124, 15, -6, 2, 0],
+  [-1, 3, -10, 123, 18, -6, 2, -1], [-1, 3, -11, 122, 20, -7, 3, -1],
+  [-1, 4, -12, 121, 22, -8, 3, -1], [-1, 4, -13, 120, 25, -9, 3, -1],
+  [-1, 4, -14, 118, 28, -9, 3, -1], [-1, 4, -15, 117, 30, -10, 4, -1],
+  [-1, 5, -16, 116, 32, -11, 4, -1], [-1, 5, -16, 114, 35, -12, 4, -1],
+  [-1, 5, -17, 112, 38, -12, 4, -1], [-1, 5, -18, 111, 40, -13, 5, -1],
+  [-1, 5, -18, 109, 43, -14, 5, -1], [-1, 6, -19, 107, 45, -14, 5, -1],
+  [-1, 6, -19, 105, 48, -15, 5, -1], [-1, 6, -19, 103, 51, -16, 5, -1],
+  [-1, 6, -20, 101, 53, -16, 6, -1], [-1, 6, -20, 99, 56, -17, 6, -1],
+  [-1, 6, -20, 97, 58, -17, 6, -1], [-1, 6, -20, 95, 61, -18, 6, -1],
+  [-2, 7, -20, 93, 64, -18, 6, -2], [-2, 7, -20, 91, 66, -19, 6, -1],
+  [-2, 7, -20, 88, 69, -19, 6, -1], [-2, 7, -20, 86, 71, -19, 6, -1],
+  [-2, 7, -20, 84, 74, -20, 7, -2], [-2, 7, -20, 81, 76, -20, 7, -1],
+  [-2, 7, -20, 79, 79, -20, 7, -2], [-1, 7, -20, 76, 81, -20, 7, -2],
+  [-2, 7, -20, 74, 84, -20, 7, -2], [-1, 6, -19, 71, 86, -20, 7, -2],
+  [-1, 6, -19, 69, 88, -20, 7, -2], [-1, 6, -19, 66, 91, -20, 7, -2],
+  [-2, 6, -18, 64, 93, -20, 7, -2], [-1, 6, -18, 61, 95, -20, 6, -1],
+  [-1, 6, -17, 58, 97, -20, 6, -1], [-1, 6, -17, 56, 99, -20, 6, -1],
+  [-1, 6, -16, 53, 101, -20, 6, -1], [-1, 5, -16, 51, 103, -19, 6, -1],
+  [-1, 5, -15, 48, 105, -19, 6, -1], [-1, 5, -14, 45, 107, -19, 6, -1],
+  [-1, 5, -14, 43, 109, -18, 5, -1], [-1, 5, -13, 40, 111, -18, 5, -1],
+  [-1, 4, -12, 38, 112, -17, 5, -1], [-1, 4, -12, 35, 114, -16, 5, -1],
+  [-1, 4, -11, 32, 116, -16, 5, -1], [-1, 4, -10, 30, 117, -15, 4, -1],
+  [-1, 3, -9, 28, 118, -14, 4, -1], [-1, 3, -9, 25, 120, -13, 4, -1],
+  [-1, 3, -8, 22, 121, -12, 4, -1], [-1, 3, -7, 20, 122, -11, 3, -1],
+  [-1, 2, -6, 18, 123, -10, 3, -1], [0, 2, -6, 15, 124, -9, 3, -1],
+  [0, 2, -5, 13, 125, -8, 2, -1], [0, 1, -4, 11, 125, -7, 2, 0],
+  [0, 1, -3, 8, 126, -6, 2, 0], [0, 1, -3, 6, 127, -4, 1, 0],
+  [0, 1, -2, 4, 127, -3, 1, 0], [0, 0, -1, 2, 128, -1, 0, 0]
+];
+
+/// Coded frame width for a frame upscaled to `upscaled_width` with
+/// denominator `denom`.
+pub fn superres_coded_width(upscaled_width: usize, denom: u8) -> usize {
+  let denom = denom as usize;
+  let width = (upscaled_width * SUPERRES_NUM as usize + denom / 2) / denom;
+  cmp::max(width, cmp::min(16, upscaled_width))
+}
+
+/// Normative horizontal upscaling of rows of a reconstructed plane (spec
+/// 7.16), from row `src_y` of `src` to row `dst_y` of `dst`.
+fn upscale_plane(
+  src: &Plane, dst: &mut Plane, downscaled_w: usize, upscaled_w: usize,
+  mi_w: usize, src_y: usize, dst_y: usize, rows: usize, bit_depth: usize
+) {
+  let downscaled_w = downscaled_w as i64;
+  let upscaled_w = upscaled_w as i64;
+  let step =
+    ((downscaled_w << SUPERRES_SCALE_BITS) + upscaled_w / 2) / upscaled_w;
+  let err = upscaled_w * step - (downscaled_w << SUPERRES_SCALE_BITS);
+  let initial_subpel = ((-((upscaled_w - downscaled_w)
+    << (SUPERRES_SCALE_BITS - 1)) + upscaled_w / 2) / upscaled_w
+    + (1 << (SUPERRES_EXTRA_BITS - 1)) - err / 2) & SUPERRES_SCALE_MASK;
+  let max_x = (mi_w * 4) as isize - 1;
+  let max_val = (1 << bit_depth) - 1;
+
+  let dst_stride = dst.cfg.stride;
+  let dst_data = dst.data_origin_mut();
+  for y in 0..rows {
+    for x in 0..upscaled_w as usize {
+      let src_x = -(1 << SUPERRES_SCALE_BITS) + initial_subpel + x as i64 * step;
+      let src_x_px = (src_x >> SUPERRES_SCALE_BITS) as isize;
+      let src_x_subpel =
+        ((src_x & SUPERRES_SCALE_MASK) >> SUPERRES_EXTRA_BITS) as usize;
+      let filter = &UPSCALE_FILTER[src_x_subpel];
+      let mut sum = 0;
+      for k in 0..SUPERRES_FILTER_TAPS {
+        let sample_x =
+          clamp(src_x_px + k as isize - SUPERRES_FILTER_OFFSET, 0, max_x) as usize;
+        sum += src.p(sample_x, src_y + y) as i32 * filter[k];
+      }
+      dst_data[(dst_y + y) * dst_stride + x] =
+        clamp(round_shift(sum, FILTER_BITS), 0, max_val) as u16;
+    }
+  }
+}
+
+/// Allocates the frame a frame coded at `fi.width` is upscaled into.
+pub fn upscaled_frame(fi: &FrameInvariants) -> Frame {
+  Frame::new(
+    fi.upscaled_width.align_power_of_two(3), fi.padded_h,
+    fi.sequence.chroma_sampling
+  )
+}
+
+/// Upscales `rows` rows of a plane of a frame coded at `fi.width`, from
+/// row `src_y` of `src` to row `dst_y` of `dst`.
+pub fn upscale_rows(
+  fi: &FrameInvariants, src: &Plane, dst: &mut Plane, src_y: usize,
+  dst_y: usize, rows: usize
+) {
+  let xdec = src.cfg.xdec;
+  upscale_plane(
+    src, dst, (fi.width + xdec) >> xdec, (fi.upscaled_width + xdec) >> xdec,
+    fi.w_in_b >> xdec, src_y, dst_y, rows, fi.sequence.bit_depth
+  );
+}
+
+/// Upscales filter block row `fby` of a frame coded at `fi.width` into
+/// `dst`, allocated by `upscaled_frame`.
+pub fn upscale_frame_rows(
+  fi: &FrameInvariants, src: &Frame, dst: &mut Frame, fby: usize
+) {
+  for p in 0..3 {
+    let ydec = src.planes[p].cfg.ydec;
+    let y = fby * 64 >> ydec;
+    let rows = cmp::min((fby + 1) * 64, fi.padded_h) >> ydec;
+    upscale_rows(fi, &src.planes[p], &mut dst.planes[p], y, y, rows - y);
+  }
+}
@@ -230,3 +230,13 @@ pub fn msb(x: i32) -> i32 {
 pub fn round_shift(value: i32, bit: usize) -> i32 {
   (value + (1 << bit >> 1)) >> bit
 }
+
+/// Rounds a signed value to `n` fewer bits of precision, symmetrically
+/// about zero (spec Round2Signed).
+pub fn round2signed(x: i64, n: usize) -> i64 {
+  if x >= 0 {
+    (x + (1 << n >> 1)) >> n
+  } else {
+    -((-x + (1 << n >> 1)) >> n)
+  }
+}
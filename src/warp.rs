@@ -0,0 +1,366 @@
+// Copyright (c) 2019, The rav1e contributors. All rights reserved
+//
+// This source code is subject to the terms of the BSD 2 Clause License and
+// the Alliance for Open Media Patent License 1.0. If the BSD 2 Clause License
+// was not distributed with this source code in the LICENSE file, you can
+// obtain it at www.aomedia.org/license/software. If the Alliance for Open
+// Media Patent License 1.0 was not distributed with this source code in the
+// PATENTS file, you can obtain it at www.aomedia.org/license/patent.
+
+use context::{BlockOffset, MI_SIZE};
+use encoder::FrameInvariants;
+use partition::*;
+use util::*;
+
+pub const WARPEDMODEL_PREC_BITS: usize = 16;
+pub const WARP_PARAM_REDUCE_BITS: usize = 6;
+const DIV_LUT_BITS: usize = 8;
+const DIV_LUT_PREC_BITS: usize = 14;
+const DIV_LUT_NUM: usize = 257;
+
+const LS_MV_MAX: i64 = 256;
+const WARPEDMODEL_TRANS_CLAMP: i64 = 1 << 23;
+const WARPEDMODEL_NONDIAGAFFINE_CLAMP: i64 = 1 << 13;
+
+const GM_ABS_ALPHA_BITS: u32 = 12;
+const GM_ALPHA_PREC_BITS: u32 = 15;
+const GM_ABS_TRANS_ONLY_BITS: u32 = 9;
+const GM_TRANS_ONLY_PREC_BITS: u32 = 3;
+const GM_ABS_TRANS_BITS: u32 = 12;
+const GM_TRANS_PREC_BITS: u32 = 6;
+
+/// Maximum number of neighbouring blocks a local warp model is fitted to.
+pub const LEAST_SQUARES_SAMPLES_MAX: usize = 8;
+
+/// Center of a neighbouring block and the position it is predicted from,
+/// as [row, col, ref row, ref col] in 1/8 pel.
+pub type WarpSample = [i32; 4];
+
+/// Parameters of the identity warp model.
+pub const DEFAULT_WARP_PARAMS: [i32; 6] = [
+  0, 0, 1 << WARPEDMODEL_PREC_BITS, 0, 0, 1 << WARPEDMODEL_PREC_BITS
+];
+
+const DIV_LUT: [i32; DIV_LUT_NUM] = [
+  16384, 16320, 16257, 16194, 16132, 16070, 16009, 15948, 15888, 15828,
+  15768, 15709, 15650, 15592, 15534, 15477, 15420, 15364, 15308, 15252,
+  15197, 15142, 15087, 15033, 14980, 14926, 14873, 14821, 14769, 14717,
+  14665, 14614, 14564, 14513, 14463, 14413, 14364, 14315, 14266, 14218,
+  14170, 14122, 14075, 14028, 13981, 13935, 13888, 13843, 13797, 13752,
+  13707, 13662, 13618, 13574, 13530, 13487, 13443, 13400, 13358, 13315,
+  13273, 13231, 13190, 13148, 13107, 13066, 13026, 12985, 12945, 12906,
+  12866, 12827, 12788, 12749, 12710, 12672, 12633, 12596, 12558, 12520,
+  12483, 12446, 12409, 12373, 12336, 12300, 12264, 12228, 12193, 12157,
+  12122, 12087, 12053, 12018, 11984, 11950, 11916, 11882, 11848, 11815,
+  11782, 11749, 11716, 11683, 11651, 11619, 11586, 11555, 11523, 11491,
+  11460, 11429, 11398, 11367, 11336, 11305, 11275, 11245, 11215, 11185,
+  11155, 11125, 11096, 11067, 11038, 11009, 10980, 10951, 10923, 10894,
+  10866, 10838, 10810, 10782, 10755, 10727, 10700, 10673, 10645, 10618,
+  10592, 10565, 10538, 10512, 10486, 10460, 10434, 10408, 10382, 10356,
+  10331, 10305, 10280, 10255, 10230, 10205, 10180, 10156, 10131, 10107,
+  10082, 10058, 10034, 10010, 9986, 9963, 9939, 9916, 9892, 9869,
+  9846, 9823, 9800, 9777, 9754, 9732, 9709, 9687, 9664, 9642,
+  9620, 9598, 9576, 9554, 9533, 9511, 9489, 9468, 9447, 9425,
+  9404, 9383, 9362, 9341, 9321, 9300, 9279, 9259, 9239, 9218,
+  9198, 9178, 9158, 9138, 9118, 9098, 9079, 9059, 9039, 9020,
+  9001, 8981, 8962, 8943, 8924, 8905, 8886, 8867, 8849, 8830,
+  8812, 8793, 8775, 8756, 8738, 8720, 8702, 8684, 8666, 8648,
+  8630, 8613, 8595, 8577, 8560, 8542, 8525, 8508, 8490, 8473,
+  8456, 8439, 8422, 8405, 8389, 8372, 8355, 8339, 8322, 8306,
+  8289, 8273, 8257, 8240, 8224, 8208, 8192
+];
+
+/// Approximates 1 / d as a multiplier and a shift (spec 7.11.3.7).
+fn resolve_divisor(d: i64) -> (usize, i64) {
+  let n = 63 - d.abs().leading_zeros() as usize;
+  let e = d.abs() - (1 << n);
+  let f = if n > DIV_LUT_BITS {
+    round2signed(e, n - DIV_LUT_BITS)
+  } else {
+    e << (DIV_LUT_BITS - n)
+  };
+  let div_factor = DIV_LUT[f as usize] as i64;
+  (n + DIV_LUT_PREC_BITS, if d < 0 { -div_factor } else { div_factor })
+}
+
+/// An affine warp model along with the shear parameters the warp filter
+/// derives from it.
+#[derive(Copy, Clone, Debug)]
+pub struct WarpParams {
+  pub mat: [i32; 6],
+  pub alpha: i32,
+  pub beta: i32,
+  pub gamma: i32,
+  pub delta: i32
+}
+
+impl WarpParams {
+  /// Sets up the shear parameters of a model (spec 7.11.3.6), returning
+  /// `None` for models the warp filter cannot apply.
+  pub fn new(mat: &[i32; 6]) -> Option<WarpParams> {
+    if mat[2] <= 0 {
+      return None;
+    }
+    let clamp16 = |x: i64| clamp(x, -32768, 32767);
+    let reduce = |x: i64| {
+      (round2signed(x, WARP_PARAM_REDUCE_BITS) << WARP_PARAM_REDUCE_BITS)
+        as i32
+    };
+    let (div_shift, div_factor) = resolve_divisor(mat[2] as i64);
+    let v = (mat[4] as i64) << WARPEDMODEL_PREC_BITS;
+    let w = mat[3] as i64 * mat[4] as i64;
+    let alpha = reduce(clamp16(mat[2] as i64 - (1 << WARPEDMODEL_PREC_BITS)));
+    let beta = reduce(clamp16(mat[3] as i64));
+    let gamma = reduce(clamp16(round2signed(v * div_factor, div_shift)));
+    let delta = reduce(clamp16(
+      mat[5] as i64
+        - round2signed(w * div_factor, div_shift)
+        - (1 << WARPEDMODEL_PREC_BITS)
+    ));
+    if 4 * alpha.abs() + 7 * beta.abs() >= 1 << WARPEDMODEL_PREC_BITS
+      || 4 * gamma.abs() + 4 * delta.abs() >= 1 << WARPEDMODEL_PREC_BITS
+    {
+      return None;
+    }
+    Some(WarpParams { mat: *mat, alpha, beta, gamma, delta })
+  }
+}
+
+/// Fits the local warp model of a `WARPED_CAUSAL` block to the motion of
+/// its neighbours (spec 7.11.3.8), returning `None` for models the decoder
+/// falls back to translation for.
+pub fn local_warp(
+  bo: &BlockOffset, bsize: BlockSize, mv: MotionVector, samples: &[WarpSample]
+) -> Option<WarpParams> {
+  let ls_product = |a: i64, b: i64| ((a * b) >> 2) + (a + b);
+
+  let mid_y = (bo.y * MI_SIZE + bsize.height() / 2) as i64 - 1;
+  let mid_x = (bo.x * MI_SIZE + bsize.width() / 2) as i64 - 1;
+  let (suy, sux) = (mid_y * 8, mid_x * 8);
+  let (duy, dux) = (suy + mv.row as i64, sux + mv.col as i64);
+
+  let mut a = [[0i64; 2]; 2];
+  let mut bx = [0i64; 2];
+  let mut by = [0i64; 2];
+  for sample in samples {
+    let sy = sample[0] as i64 - suy;
+    let sx = sample[1] as i64 - sux;
+    let dy = sample[2] as i64 - duy;
+    let dx = sample[3] as i64 - dux;
+    if (sx - dx).abs() < LS_MV_MAX && (sy - dy).abs() < LS_MV_MAX {
+      a[0][0] += ls_product(sx, sx) + 8;
+      a[0][1] += ls_product(sx, sy) + 4;
+      a[1][1] += ls_product(sy, sy) + 8;
+      bx[0] += ls_product(sx, dx) + 8;
+      bx[1] += ls_product(sy, dx) + 4;
+      by[0] += ls_product(sx, dy) + 4;
+      by[1] += ls_product(sy, dy) + 8;
+    }
+  }
+
+  let det = a[0][0] * a[1][1] - a[0][1] * a[0][1];
+  if det == 0 {
+    return None;
+  }
+  let (shift, mut div_factor) = resolve_divisor(det);
+  let mut div_shift = shift as isize - WARPEDMODEL_PREC_BITS as isize;
+  if div_shift < 0 {
+    div_factor <<= -div_shift;
+    div_shift = 0;
+  }
+  let solve = |v: i64, lo: i64, hi: i64| {
+    clamp(round2signed(v * div_factor, div_shift as usize), lo, hi) as i32
+  };
+  let diag = |v: i64| solve(
+    v,
+    (1 << WARPEDMODEL_PREC_BITS) - WARPEDMODEL_NONDIAGAFFINE_CLAMP + 1,
+    (1 << WARPEDMODEL_PREC_BITS) + WARPEDMODEL_NONDIAGAFFINE_CLAMP - 1
+  );
+  let nondiag = |v: i64| solve(
+    v,
+    -WARPEDMODEL_NONDIAGAFFINE_CLAMP + 1,
+    WARPEDMODEL_NONDIAGAFFINE_CLAMP - 1
+  );
+
+  let mut mat = [0; 6];
+  mat[2] = diag(a[1][1] * bx[0] - a[0][1] * bx[1]);
+  mat[3] = nondiag(a[0][0] * bx[1] - a[0][1] * bx[0]);
+  mat[4] = nondiag(a[1][1] * by[0] - a[0][1] * by[1]);
+  mat[5] = diag(a[0][0] * by[1] - a[0][1] * by[0]);
+
+  let vx = mv.col as i64 * (1 << (WARPEDMODEL_PREC_BITS - 3))
+    - (mid_x * (mat[2] as i64 - (1 << WARPEDMODEL_PREC_BITS))
+      + mid_y * mat[3] as i64);
+  let vy = mv.row as i64 * (1 << (WARPEDMODEL_PREC_BITS - 3))
+    - (mid_x * mat[4] as i64
+      + mid_y * (mat[5] as i64 - (1 << WARPEDMODEL_PREC_BITS)));
+  mat[0] = clamp(vx, -WARPEDMODEL_TRANS_CLAMP, WARPEDMODEL_TRANS_CLAMP - 1) as i32;
+  mat[1] = clamp(vy, -WARPEDMODEL_TRANS_CLAMP, WARPEDMODEL_TRANS_CLAMP - 1) as i32;
+
+  WarpParams::new(&mat)
+}
+
+/// Whether `ref_frame` was coded at a different size than the current
+/// frame, in which case it is never warped.
+pub fn ref_is_scaled(fi: &FrameInvariants, ref_frame: usize) -> bool {
+  const REF_SCALE_SHIFT: usize = 14;
+  match fi.rec_buffer.frames[fi.ref_frames[ref_frame - LAST_FRAME] as usize] {
+    Some(ref rec) => {
+      let x_scale = ((rec.width << REF_SCALE_SHIFT) + fi.width / 2) / fi.width;
+      let y_scale = ((rec.height << REF_SCALE_SHIFT) + fi.height / 2) / fi.height;
+      x_scale != 1 << REF_SCALE_SHIFT || y_scale != 1 << REF_SCALE_SHIFT
+    }
+    None => false
+  }
+}
+
+/// Returns the range and the precision, in bits, with which global motion
+/// parameter `idx` of a model is coded.
+pub fn gm_param_bits(
+  mode: GlobalMVMode, idx: usize, allow_high_precision_mv: bool
+) -> (u32, u32) {
+  if idx >= 2 {
+    (GM_ABS_ALPHA_BITS, GM_ALPHA_PREC_BITS)
+  } else if mode == GlobalMVMode::TRANSLATION {
+    let hp = allow_high_precision_mv as u32;
+    (GM_ABS_TRANS_ONLY_BITS - 1 + hp, GM_TRANS_ONLY_PREC_BITS - 1 + hp)
+  } else {
+    (GM_ABS_TRANS_BITS, GM_TRANS_PREC_BITS)
+  }
+}
+
+/// Converts global motion parameter `idx` to the units it is coded in,
+/// centered on the identity model.
+pub fn gm_param_to_coded(
+  param: i32, mode: GlobalMVMode, idx: usize, allow_high_precision_mv: bool
+) -> i32 {
+  let (_, prec_bits) = gm_param_bits(mode, idx, allow_high_precision_mv);
+  let sub = if idx % 3 == 2 { 1 << prec_bits } else { 0 };
+  (param >> (WARPEDMODEL_PREC_BITS as u32 - prec_bits)) - sub
+}
+
+/// Inverse of `gm_param_to_coded`.
+pub fn gm_param_from_coded(
+  coded: i32, mode: GlobalMVMode, idx: usize, allow_high_precision_mv: bool
+) -> i32 {
+  let (_, prec_bits) = gm_param_bits(mode, idx, allow_high_precision_mv);
+  let round = if idx % 3 == 2 { 1 << WARPEDMODEL_PREC_BITS } else { 0 };
+  (coded << (WARPEDMODEL_PREC_BITS as u32 - prec_bits)) + round
+}
+
+pub fn lower_mv_precision(fi: &FrameInvariants, mv: i64) -> i16 {
+  if fi.allow_high_precision_mv {
+    mv as i16
+  } else if fi.force_integer_mv != 0 {
+    let a = ((mv.abs() + 3) >> 3) << 3;
+    (if mv > 0 { a } else { -a }) as i16
+  } else if mv & 1 != 0 {
+    (if mv > 0 { mv - 1 } else { mv + 1 }) as i16
+  } else {
+    mv as i16
+  }
+}
+
+/// Motion vector of a block predicted with the global motion of
+/// `ref_frame` (spec 7.10.2.1).
+pub fn global_mv(
+  fi: &FrameInvariants, ref_frame: usize, bsize: BlockSize, bo: &BlockOffset
+) -> MotionVector {
+  if ref_frame < LAST_FRAME || ref_frame > ALTREF_FRAME {
+    return MotionVector { row: 0, col: 0 };
+  }
+  let mat = &fi.gm_params[ref_frame];
+  let (row, col) = match fi.globalmv_transformation_type[ref_frame] {
+    GlobalMVMode::IDENTITY => (0, 0),
+    // The spec takes the vertical component from the first parameter here
+    GlobalMVMode::TRANSLATION => (
+      (mat[0] >> (WARPEDMODEL_PREC_BITS - 3)) as i64,
+      (mat[1] >> (WARPEDMODEL_PREC_BITS - 3)) as i64
+    ),
+    _ => {
+      let x = (bo.x * MI_SIZE + bsize.width() / 2) as i64 - 1;
+      let y = (bo.y * MI_SIZE + bsize.height() / 2) as i64 - 1;
+      let xc = (mat[2] as i64 - (1 << WARPEDMODEL_PREC_BITS)) * x
+        + mat[3] as i64 * y
+        + mat[0] as i64;
+      let yc = mat[4] as i64 * x
+        + (mat[5] as i64 - (1 << WARPEDMODEL_PREC_BITS)) * y
+        + mat[1] as i64;
+      if fi.allow_high_precision_mv {
+        (
+          round2signed(yc, WARPEDMODEL_PREC_BITS - 3),
+          round2signed(xc, WARPEDMODEL_PREC_BITS - 3)
+        )
+      } else {
+        (
+          round2signed(yc, WARPEDMODEL_PREC_BITS - 2) * 2,
+          round2signed(xc, WARPEDMODEL_PREC_BITS - 2) * 2
+        )
+      }
+    }
+  };
+  MotionVector {
+    row: lower_mv_precision(fi, row),
+    col: lower_mv_precision(fi, col)
+  }
+}
+
+/// Warp model applied to a block's `GLOBALMV` prediction from `ref_frame`,
+/// for a plane block of the given dimensions. Blocks without one are
+/// translated by their global motion vector instead.
+pub fn global_warp(
+  fi: &FrameInvariants, ref_frame: usize, width: usize, height: usize
+) -> Option<WarpParams> {
+  if width < 8
+    || height < 8
+    || fi.force_integer_mv != 0
+    || fi.globalmv_transformation_type[ref_frame] <= GlobalMVMode::TRANSLATION
+  {
+    return None;
+  }
+  WarpParams::new(&fi.gm_params[ref_frame])
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn gm_param_coding_round_trip() {
+    let mat = [274432, -101376, 64822, -250, 270, 66866];
+    for &mode in &[GlobalMVMode::ROTZOOM, GlobalMVMode::AFFINE] {
+      for i in 0..6 {
+        let coded = gm_param_to_coded(mat[i], mode, i, true);
+        assert_eq!(gm_param_from_coded(coded, mode, i, true), mat[i]);
+      }
+    }
+    assert_eq!(gm_param_to_coded(DEFAULT_WARP_PARAMS[2], GlobalMVMode::AFFINE, 2, true), 0);
+  }
+
+  #[test]
+  fn warp_params_shear() {
+    let identity = WarpParams::new(&DEFAULT_WARP_PARAMS).unwrap();
+    assert_eq!((identity.alpha, identity.beta, identity.gamma, identity.delta), (0, 0, 0, 0));
+    let zoom = WarpParams::new(&[0, 0, 64822, -250, 250, 64822]).unwrap();
+    assert_eq!((zoom.alpha, zoom.beta), (-704, -256));
+    // Shears beyond the reach of the warp filter are rejected
+    assert!(WarpParams::new(&[0, 0, 98304, 0, 0, 98304]).is_none());
+    assert!(WarpParams::new(&[0, 0, 0, 0, 0, 65536]).is_none());
+  }
+
+  #[test]
+  fn local_warp_fit() {
+    let bo = BlockOffset { x: 4, y: 4 };
+    let bsize = BlockSize::BLOCK_16X16;
+    let mv = MotionVector { row: 8, col: 16 };
+    let sample = |row: i32, col: i32, zoom: i32| {
+      [row * 8, col * 8, row * 8 + mv.row as i32 + (row - 23) * zoom,
+        col * 8 + mv.col as i32 + (col - 23) * zoom]
+    };
+    // Neighbours above and to the left moving like the block
+    let translation = local_warp(&bo, bsize, mv, &[sample(11, 23, 0), sample(23, 11, 0)]).unwrap();
+    assert!(translation.mat[2..].iter().zip(&DEFAULT_WARP_PARAMS[2..]).all(|(&a, &b)| (a - b).abs() < 128));
+    let zoom = local_warp(&bo, bsize, mv, &[sample(11, 23, 1), sample(23, 11, 1)]).unwrap();
+    assert!(zoom.mat[2] > translation.mat[2] + 4096 && zoom.mat[5] > translation.mat[5] + 4096);
+  }
+}